@@ -0,0 +1,325 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Append-only audit log of API calls changing the daemon, e.g. mount, umount and takeover.
+//!
+//! Clients of the API socket are identified by their credentials when connections are
+//! accepted, see `server::ApiServer`. Connections of TLS clients are relayed by nydusd itself,
+//! from a socket bound to an abstract address telling which TLS client it's relayed for.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use micro_http::{Method, Request, Response};
+use nix::libc;
+use nix::sys::socket::{getsockopt, sockopt};
+
+/// Error messages of failed calls are cut to this length.
+const MAX_ERROR_LEN: usize = 1024;
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<File>> = Mutex::new(None);
+    static ref TLS_CLIENTS: Mutex<HashMap<u64, AuditPeer>> = Mutex::new(HashMap::new());
+}
+
+static TLS_CLIENT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Who sent an API request.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditPeer {
+    /// Client of the API socket.
+    Unix { pid: i32, uid: u32, gid: u32 },
+    /// Client authenticated by its certificate, see `tls::start_tls_thread()`.
+    Tls { subject: String, address: String },
+}
+
+/// A mutating API call and its outcome, one JSON line in the audit log.
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    timestamp_secs: u64,
+    method: String,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<&'a AuditPeer>,
+    status: u16,
+    success: bool,
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Record mutating API calls to `path`, which is created with mode 0600 if missing.
+pub fn open(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?;
+    *AUDIT_LOG.lock().unwrap() = Some(file);
+    info!("API calls audited to {:?}", path);
+
+    Ok(())
+}
+
+/// Who is on the other side of `stream`, a connection accepted from the API socket.
+pub(crate) fn peer_of(stream: &UnixStream) -> Option<AuditPeer> {
+    let cred = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)
+        .map_err(|e| warn!("failed to get credentials of API client, {}", e))
+        .ok()?;
+    if cred.pid() as u32 == std::process::id() {
+        if let Some(peer) = peer_name(stream)
+            .as_deref()
+            .and_then(tls_client_id)
+            .and_then(|id| TLS_CLIENTS.lock().unwrap().get(&id).cloned())
+        {
+            return Some(peer);
+        }
+    }
+
+    Some(AuditPeer::Unix {
+        pid: cred.pid(),
+        uid: cred.uid(),
+        gid: cred.gid(),
+    })
+}
+
+/// Abstract address of the peer of `stream`.
+fn peer_name(stream: &UnixStream) -> Option<Vec<u8>> {
+    // nix doesn't handle addresses of unix sockets in getpeername() well.
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    // Safe because the buffer is large enough for a unix socket address.
+    let ret = unsafe {
+        libc::getpeername(
+            stream.as_raw_fd(),
+            &mut addr as *mut libc::sockaddr_un as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    let path_offset = std::mem::size_of::<libc::sa_family_t>();
+    if ret != 0 || (len as usize) <= path_offset + 1 || addr.sun_path[0] != 0 {
+        return None;
+    }
+
+    Some(
+        addr.sun_path[1..len as usize - path_offset]
+            .iter()
+            .map(|c| *c as u8)
+            .collect(),
+    )
+}
+
+/// Unix socket address of `path`, which is abstract if it starts with a NUL byte.
+fn unix_addr(path: &[u8]) -> Result<(libc::sockaddr_un, libc::socklen_t)> {
+    // nix doesn't handle addresses of unix sockets well, see `peer_name()`.
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    if path.len() >= addr.sun_path.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "socket path too long"));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+
+    Ok((addr, len as libc::socklen_t))
+}
+
+/// Abstract address a relay for the TLS client `id` is bound to.
+fn tls_client_name(id: u64) -> Vec<u8> {
+    format!("nydus-api-tls/{}/{}", std::process::id(), id).into_bytes()
+}
+
+fn tls_client_id(name: &[u8]) -> Option<u64> {
+    let prefix = tls_client_name(0);
+    let prefix = &prefix[..prefix.len() - 1];
+    if !name.starts_with(prefix) {
+        return None;
+    }
+    std::str::from_utf8(&name[prefix.len()..])
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Record `request` from `peer` if it changes the daemon, `response` tells its outcome.
+pub(crate) fn record(
+    request: &Request,
+    response: &Response,
+    peer: Option<&AuditPeer>,
+    begin: SystemTime,
+) {
+    if matches!(request.method(), Method::Get) {
+        return;
+    }
+    let mut log = AUDIT_LOG.lock().unwrap();
+    let file = match log.as_mut() {
+        Some(f) => f,
+        None => return,
+    };
+
+    let status = std::str::from_utf8(response.status().raw())
+        .ok()
+        .and_then(|s| s.parse::<u16>().ok())
+        .unwrap_or_default();
+    let success = (200..400).contains(&status);
+    let error = if success {
+        None
+    } else {
+        response.body().map(|b| {
+            let mut msg = String::from_utf8_lossy(b.raw()).to_string();
+            msg.truncate(MAX_ERROR_LEN);
+            msg
+        })
+    };
+    let entry = AuditEntry {
+        timestamp_secs: begin
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        method: format!("{:?}", request.method()).to_uppercase(),
+        path: request.uri().get_abs_path(),
+        peer,
+        status,
+        success,
+        elapsed_ms: begin.elapsed().map(|d| d.as_millis() as u64).unwrap_or(0),
+        error,
+    };
+
+    write_entry(file, &entry).unwrap_or_else(|e| error!("failed to write audit log, {}", e));
+}
+
+fn write_entry(file: &mut File, entry: &AuditEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    // One write per entry, so that entries aren't interleaved even if the file is shared.
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+/// A TLS client being connected, whose requests are attributed to it until dropped.
+pub(crate) struct TlsClientGuard(u64);
+
+impl TlsClientGuard {
+    pub(crate) fn new(subject: String, address: String) -> Self {
+        let id = TLS_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        TLS_CLIENTS
+            .lock()
+            .unwrap()
+            .insert(id, AuditPeer::Tls { subject, address });
+        TlsClientGuard(id)
+    }
+
+    /// Connect to the API socket `api_sock` on behalf of the client.
+    pub(crate) fn connect(&self, api_sock: &Path) -> Result<UnixStream> {
+        // Safe because it doesn't touch memory.
+        let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        // Safe because the fd was just created and is owned by nobody else.
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+        let mut name = vec![0u8];
+        name.extend_from_slice(&tls_client_name(self.0));
+        let (addr, len) = unix_addr(&name)?;
+        // Safe because `addr` is a valid unix socket address of `len` bytes.
+        if unsafe { libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let (addr, len) = unix_addr(api_sock.as_os_str().as_bytes())?;
+        // Safe because `addr` is a valid unix socket address of `len` bytes.
+        if unsafe { libc::connect(fd, &addr as *const _ as *const libc::sockaddr, len) } != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(stream)
+    }
+}
+
+impl Drop for TlsClientGuard {
+    fn drop(&mut self) {
+        TLS_CLIENTS.lock().unwrap().remove(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_write_entry() {
+        let tmp = TempFile::new().unwrap();
+        let mut file = OpenOptions::new().append(true).open(tmp.as_path()).unwrap();
+        let peers = [
+            AuditPeer::Unix {
+                pid: 100,
+                uid: 0,
+                gid: 0,
+            },
+            AuditPeer::Tls {
+                subject: "CN=ops".to_string(),
+                address: "10.0.0.1:40000".to_string(),
+            },
+        ];
+        for (peer, status) in peers.iter().zip(&[204, 400]) {
+            let entry = AuditEntry {
+                timestamp_secs: 1602835200,
+                method: "PUT".to_string(),
+                path: "/api/v1/daemon/exit",
+                peer: Some(peer),
+                status: *status,
+                success: *status == 204,
+                elapsed_ms: 1,
+                error: None,
+            };
+            write_entry(&mut file, &entry).unwrap();
+        }
+
+        let mut content = String::new();
+        File::open(tmp.as_path())
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["peer"]["unix"]["pid"], 100);
+        assert_eq!(first["success"], true);
+        assert!(first.get("error").is_none());
+        let second: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second["peer"]["tls"]["subject"], "CN=ops");
+        assert_eq!(second["status"], 400);
+    }
+
+    #[test]
+    fn test_tls_client_guard() {
+        {
+            let _g = TlsClientGuard::new("CN=test".to_string(), "127.0.0.1:1".to_string());
+            assert!(TLS_CLIENTS
+                .lock()
+                .unwrap()
+                .values()
+                .any(|p| matches!(p, AuditPeer::Tls { subject, .. } if subject == "CN=test")));
+        }
+        assert!(!TLS_CLIENTS
+            .lock()
+            .unwrap()
+            .values()
+            .any(|p| matches!(p, AuditPeer::Tls { subject, .. } if subject == "CN=test")));
+    }
+}
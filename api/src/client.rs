@@ -0,0 +1,112 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A minimal client of the API server, for nydus tools and nydusd itself during live upgrade.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Status code and body of an API response.
+pub struct ApiResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl ApiResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Send a request to the API server listening on `sock`, `path` includes the query string.
+///
+/// Reading the response times out after `timeout`, if any.
+pub fn api_call(
+    sock: &Path,
+    method: &str,
+    path: &str,
+    body: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<ApiResponse> {
+    let mut stream = UnixStream::connect(sock)?;
+    stream.set_read_timeout(timeout)?;
+
+    let mut req = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+    if let Some(body) = body {
+        req.push_str("Content-Type: application/json\r\n");
+        req.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        req.push_str(body);
+    } else {
+        req.push_str("\r\n");
+    }
+    stream.write_all(req.as_bytes())?;
+
+    let mut resp = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut head: Option<(usize, usize)> = None;
+    loop {
+        if let Some((head_len, body_len)) = head {
+            if resp.len() >= head_len + body_len {
+                break;
+            }
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "API connection closed",
+            ));
+        }
+        resp.extend_from_slice(&buf[..n]);
+        if head.is_none() {
+            head = parse_head(&resp);
+        }
+    }
+
+    let (head_len, body_len) = head.unwrap();
+    let head = String::from_utf8_lossy(&resp[..head_len]);
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid API response"))?;
+    let body = String::from_utf8_lossy(&resp[head_len..head_len + body_len]).to_string();
+
+    Ok(ApiResponse { status, body })
+}
+
+/// Get length of response head and body, if the whole head is received.
+fn parse_head(resp: &[u8]) -> Option<(usize, usize)> {
+    let end = resp.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let head = String::from_utf8_lossy(&resp[..end]);
+    let body_len = head
+        .lines()
+        .filter_map(|l| {
+            let mut kv = l.splitn(2, ':');
+            Some((kv.next()?, kv.next()?))
+        })
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    Some((end, body_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_head() {
+        assert_eq!(parse_head(b"HTTP/1.1 200 OK\r\nContent-Len"), None);
+        assert_eq!(
+            parse_head(b"HTTP/1.1 204 No Content\r\nServer: Nydus API\r\n\r\n"),
+            Some((46, 0))
+        );
+        let resp = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}";
+        assert_eq!(parse_head(resp), Some((resp.len() - 2, 2)));
+    }
+}
@@ -5,27 +5,54 @@
 
 use std::collections::HashMap;
 use std::io::Result;
-use std::path::PathBuf;
+use std::path::Path;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use std::os::unix::io::AsRawFd;
 
 use http::uri::Uri;
 use url::Url;
 
-use micro_http::{HttpServer, MediaType, Request, Response, StatusCode};
+use micro_http::{MediaType, Request, Response, StatusCode};
+use nydus_utils::metrics;
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::audit;
 use crate::http_endpoint::{
-    error_response, ApiError, ApiRequest, ApiResponse, EventsHandler, ExitHandler, FsBackendInfo,
-    HttpError, HttpResult, InfoHandler, MetricsBackendHandler, MetricsBlobcacheHandler,
-    MetricsFilesHandler, MetricsHandler, MetricsInflightHandler, MetricsPatternHandler,
-    MountHandler, SendFuseFdHandler, TakeoverHandler,
+    versioned_error_response, ApiError, ApiRequest, ApiResponse, BlobcacheHandler,
+    EventStreamHandler, EventsHandler, ExitHandler, FsBackendInfo, HttpError, HttpResult,
+    InflightHandler, InfoHandler, LogLevelHandler, MetricsBackendHandler, MetricsBlobcacheHandler,
+    MetricsFilesHandler, MetricsFsHandler, MetricsHandler, MetricsInflightHandler,
+    MetricsMemoryHandler, MetricsPatternHandler, MountActionHandler, MountHandler, MountsHandler,
+    OpenApiHandler, ProfileHandler, RestartHandler, SaveStateHandler, SendFuseFdHandler,
+    TakeoverHandler, ValidateHandler,
 };
+use crate::profile::{self, ProfileFormat, ProfileSession};
+use crate::server::{ApiServer, ServerRequest};
 
 const HTTP_ROOT: &str = "/api/v1";
+const HTTP_ROOT_V2: &str = "/api/v2";
+
+/// Version of the HTTP API, telling how errors are reported to clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiVersion {
+    /// Errors come with an `UNDEFINED` code, kept for compatibility.
+    V1,
+    /// Errors come with a code telling the kind of error, see `openapi/nydus-api-v2.yaml`.
+    V2,
+}
+
+impl ApiVersion {
+    pub(crate) fn from_path(path: &str) -> Self {
+        if path.starts_with(HTTP_ROOT_V2) {
+            ApiVersion::V2
+        } else {
+            ApiVersion::V1
+        }
+    }
+}
 
 /// An HTTP endpoint handler interface
 pub trait EndpointHandler: Sync + Send {
@@ -39,17 +66,38 @@ pub trait EndpointHandler: Sync + Send {
         req: &Request,
         kicker: &dyn Fn(ApiRequest) -> ApiResponse,
     ) -> HttpResult;
+
+    /// Media type of successful responses.
+    fn media_type(&self, _req: &Request) -> MediaType {
+        MediaType::ApplicationJson
+    }
 }
 
 /// An HTTP routes structure.
 pub struct HttpRoutes {
-    /// routes is a hash table mapping endpoint URIs to their endpoint handlers.
+    /// routes is a hash table mapping endpoint URIs to their endpoint handlers. URIs ending
+    /// with '/' match all paths under them, passing the rest as a path parameter.
     pub routes: HashMap<String, Box<dyn EndpointHandler + Sync + Send>>,
 }
 
+impl HttpRoutes {
+    fn find(&self, path: &str) -> Option<&(dyn EndpointHandler + Sync + Send)> {
+        self.routes
+            .get(path)
+            .or_else(|| {
+                self.routes
+                    .iter()
+                    .filter(|(k, _)| k.ends_with('/') && path.starts_with(k.as_str()))
+                    .max_by_key(|(k, _)| k.len())
+                    .map(|(_, v)| v)
+            })
+            .map(|v| v.as_ref())
+    }
+}
+
 macro_rules! endpoint {
-    ($path:expr) => {
-        format!("{}{}", HTTP_ROOT, $path)
+    ($root:expr, $path:expr) => {
+        format!("{}{}", $root, $path)
     };
 }
 
@@ -60,19 +108,35 @@ lazy_static! {
             routes: HashMap::new(),
         };
 
-        r.routes.insert(endpoint!("/daemon"), Box::new(InfoHandler{}));
-        r.routes.insert(endpoint!("/daemon/events"), Box::new(EventsHandler{}));
-        r.routes.insert(endpoint!("/daemon/backend"), Box::new(FsBackendInfo{}));
-        r.routes.insert(endpoint!("/daemon/exit"), Box::new(ExitHandler{}));
-        r.routes.insert(endpoint!("/daemon/fuse/sendfd"), Box::new(SendFuseFdHandler{}));
-        r.routes.insert(endpoint!("/daemon/fuse/takeover"), Box::new(TakeoverHandler{}));
-        r.routes.insert(endpoint!("/mount"), Box::new(MountHandler{}));
-        r.routes.insert(endpoint!("/metrics"), Box::new(MetricsHandler{}));
-        r.routes.insert(endpoint!("/metrics/files"), Box::new(MetricsFilesHandler{}));
-        r.routes.insert(endpoint!("/metrics/pattern"), Box::new(MetricsPatternHandler{}));
-        r.routes.insert(endpoint!("/metrics/backend"), Box::new(MetricsBackendHandler{}));
-        r.routes.insert(endpoint!("/metrics/blobcache"), Box::new(MetricsBlobcacheHandler{}));
-        r.routes.insert(endpoint!("/metrics/inflight"), Box::new(MetricsInflightHandler{}));
+        // v2 serves the same endpoints as v1, only errors are reported differently.
+        for root in &[HTTP_ROOT, HTTP_ROOT_V2] {
+            r.routes.insert(endpoint!(root, "/daemon"), Box::new(InfoHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/events"), Box::new(EventsHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/events/stream"), Box::new(EventStreamHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/backend"), Box::new(FsBackendInfo{}));
+            r.routes.insert(endpoint!(root, "/daemon/inflight"), Box::new(InflightHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/log-level"), Box::new(LogLevelHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/profile"), Box::new(ProfileHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/exit"), Box::new(ExitHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/fuse/sendfd"), Box::new(SendFuseFdHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/fuse/takeover"), Box::new(TakeoverHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/restart"), Box::new(RestartHandler{}));
+            r.routes.insert(endpoint!(root, "/daemon/save"), Box::new(SaveStateHandler{}));
+            r.routes.insert(endpoint!(root, "/mount"), Box::new(MountHandler{}));
+            r.routes.insert(endpoint!(root, "/mounts"), Box::new(MountsHandler{}));
+            r.routes.insert(endpoint!(root, "/validate"), Box::new(ValidateHandler{}));
+            r.routes.insert(endpoint!(root, "/mounts/"), Box::new(MountActionHandler{}));
+            r.routes.insert(endpoint!(root, "/blobcache"), Box::new(BlobcacheHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics"), Box::new(MetricsHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics/files"), Box::new(MetricsFilesHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics/pattern"), Box::new(MetricsPatternHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics/backend"), Box::new(MetricsBackendHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics/blobcache"), Box::new(MetricsBlobcacheHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics/inflight"), Box::new(MetricsInflightHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics/memory"), Box::new(MetricsMemoryHandler{}));
+            r.routes.insert(endpoint!(root, "/metrics/fs/"), Box::new(MetricsFsHandler{}));
+        }
+        r.routes.insert(endpoint!(HTTP_ROOT_V2, "/openapi"), Box::new(OpenApiHandler{}));
         r
     };
 }
@@ -122,24 +186,38 @@ fn handle_http_request(
 
     // Micro http should ensure that req path is legal.
     let uri_parsed = request.uri().get_abs_path().parse::<Uri>();
+    let mut media_type = MediaType::ApplicationJson;
 
     let mut response = match uri_parsed {
-        Ok(uri) => match HTTP_ROUTES.routes.get(uri.path()) {
-            Some(route) => route
-                .handle_request(&request, &|r| {
-                    kick_api_server(api_notifier, to_api, from_api, r)
-                })
-                .unwrap_or_else(|err| error_response(err, StatusCode::BadRequest)),
-            None => error_response(HttpError::NoRoute, StatusCode::NotFound),
-        },
+        Ok(uri) => {
+            let version = ApiVersion::from_path(uri.path());
+            match HTTP_ROUTES.find(uri.path()) {
+                Some(route) => {
+                    media_type = route.media_type(request);
+                    route
+                        .handle_request(&request, &|r| {
+                            kick_api_server(api_notifier, to_api, from_api, r)
+                        })
+                        .unwrap_or_else(|err| {
+                            media_type = MediaType::ApplicationJson;
+                            versioned_error_response(version, err, StatusCode::BadRequest)
+                        })
+                }
+                None => versioned_error_response(version, HttpError::NoRoute, StatusCode::NotFound),
+            }
+        }
         Err(e) => {
             error!("URI can't be parsed, {}", e);
-            error_response(HttpError::BadRequest, StatusCode::BadRequest)
+            versioned_error_response(
+                ApiVersion::V1,
+                HttpError::BadRequest,
+                StatusCode::BadRequest,
+            )
         }
     };
 
     response.set_server("Nydus API");
-    response.set_content_type(MediaType::ApplicationJson);
+    response.set_content_type(media_type);
 
     trace_api_end(&response, request.method(), begin_time);
 
@@ -170,8 +248,80 @@ pub fn extract_query_part(req: &Request, key: &str) -> Option<String> {
     v
 }
 
+/// Get the rest of request path following `prefix`, which is relative to API root, e.g.
+/// `images/busybox` of `/api/v1/metrics/fs/images/busybox` with prefix `/metrics/fs/`.
+pub fn extract_path_param(req: &Request, prefix: &str) -> Option<String> {
+    let uri = req.uri().get_abs_path().parse::<Uri>().ok()?;
+    let path = uri.path();
+    let rest = path
+        .strip_prefix(HTTP_ROOT_V2)
+        .or_else(|| path.strip_prefix(HTTP_ROOT))?;
+
+    rest.strip_prefix(prefix).map(|p| p.to_string())
+}
+
+/// Parse `since` and `timeout` of a request polling daemon events, `None` if `req` doesn't
+/// poll events or doesn't ask to wait for them.
+fn event_poll_params(req: &Request) -> Option<(u64, Duration)> {
+    let uri = req.uri().get_abs_path().parse::<Uri>().ok()?;
+    let path = uri.path();
+    let is_event_poll = [HTTP_ROOT, HTTP_ROOT_V2]
+        .iter()
+        .any(|root| path == endpoint!(root, "/daemon/events/stream"));
+    if !is_event_poll || !matches!(req.method(), micro_http::Method::Get) {
+        return None;
+    }
+
+    let since = match extract_query_part(req, "since") {
+        Some(s) => s.parse::<u64>().ok()?,
+        None => 0,
+    };
+    let timeout = extract_query_part(req, "timeout")?.parse::<u64>().ok()?;
+    if timeout == 0 {
+        return None;
+    }
+
+    Some((
+        since,
+        Duration::from_secs(std::cmp::min(timeout, EVENT_POLL_TIMEOUT_MAX)),
+    ))
+}
+
+/// Parse seconds and format of a request to profile the daemon, `None` if `req` isn't one or
+/// it's invalid, then the handler reports why.
+fn profile_params(req: &Request) -> Option<(Duration, ProfileFormat)> {
+    let uri = req.uri().get_abs_path().parse::<Uri>().ok()?;
+    let path = uri.path();
+    let is_profile = [HTTP_ROOT, HTTP_ROOT_V2]
+        .iter()
+        .any(|root| path == endpoint!(root, "/daemon/profile"));
+    if !is_profile || !matches!(req.method(), micro_http::Method::Get) {
+        return None;
+    }
+
+    profile::parse_params(req).ok()
+}
+
+/// An event poll request held by the HTTP server until new events come or it times out.
+struct PendingEventPoll {
+    request: ServerRequest,
+    since: u64,
+    deadline: Instant,
+}
+
+/// A profile request held by the HTTP server while the daemon is being profiled.
+struct PendingProfile {
+    request: ServerRequest,
+    session: ProfileSession,
+    deadline: Instant,
+}
+
 const EVENT_UNIX_SOCKET: u64 = 1;
 const EVENT_HTTP_DIE: u64 = 2;
+const EVENT_NEW_EVENTS: u64 = 3;
+
+/// Max seconds an event poll request can be held.
+const EVENT_POLL_TIMEOUT_MAX: u64 = 60;
 
 /// Start a HTTP server parsing http requests and send to nydus API server a concrete
 /// request to operate nydus or fetch working status.
@@ -190,16 +340,13 @@ pub fn start_http_thread(
 ) -> Result<thread::JoinHandle<Result<()>>> {
     // Try to remove existed unix domain socket
     std::fs::remove_file(path).unwrap_or_default();
-    let socket_path = PathBuf::from(path);
+    let mut server = ApiServer::bind(Path::new(path))?;
 
     let thread = thread::Builder::new()
         .name("http-server".to_string())
         .spawn(move || {
             let epoll_fd = epoll::create(true).unwrap();
 
-            let mut server = HttpServer::new(socket_path).unwrap();
-            // Must start the server successfully or just die by panic
-            server.start_server().unwrap();
             epoll::ctl(
                 epoll_fd,
                 epoll::ControlOptions::EPOLL_CTL_ADD,
@@ -214,12 +361,43 @@ pub fn start_http_thread(
                 epoll::Event::new(epoll::Events::EPOLLIN, EVENT_HTTP_DIE),
             )?;
 
+            // Event poll requests are held here rather than in handlers, so that they don't
+            // block other requests.
+            let event_notifier = metrics::EVENT_LOG.lock().unwrap().notifier();
+            if let Some(n) = event_notifier.as_ref() {
+                epoll::ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    n.as_raw_fd(),
+                    epoll::Event::new(epoll::Events::EPOLLIN, EVENT_NEW_EVENTS),
+                )?;
+            }
+            let mut event_polls: Vec<PendingEventPoll> = Vec::new();
+            let mut profiles: Vec<PendingProfile> = Vec::new();
+
             let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 100];
 
             info!("http server started");
 
+            let respond = |server: &mut ApiServer, server_request: ServerRequest| {
+                let begin = SystemTime::now();
+                let request = &server_request.request;
+                let response = handle_http_request(request, &api_notifier, &to_api, &from_api);
+                audit::record(request, &response, server_request.peer.as_ref(), begin);
+                server.respond(server_request, response);
+            };
+
             'wait: loop {
-                let num = epoll::wait(epoll_fd, -1, events.as_mut_slice()).map_err(|e| {
+                // Wake up in time for the earliest deadline of held requests.
+                let timeout = event_polls
+                    .iter()
+                    .map(|p| p.deadline)
+                    .chain(profiles.iter().map(|p| p.deadline))
+                    .min()
+                    .map_or(-1, |d| {
+                        d.saturating_duration_since(Instant::now()).as_millis() as i32 + 1
+                    });
+                let num = epoll::wait(epoll_fd, timeout, events.as_mut_slice()).map_err(|e| {
                     error!("Wait event error. {:?}", e);
                     e
                 })?;
@@ -229,19 +407,34 @@ pub fn start_http_thread(
                         EVENT_UNIX_SOCKET => match server.requests() {
                             Ok(request_vec) => {
                                 for server_request in request_vec {
-                                    // Ignore error when sending response
-                                    server
-                                        .respond(server_request.process(|request| {
-                                            handle_http_request(
-                                                request,
-                                                &api_notifier,
-                                                &to_api,
-                                                &from_api,
-                                            )
-                                        }))
-                                        .unwrap_or_else(|e| {
-                                            error!("HTTP server error on response: {}", e)
-                                        });
+                                    if let Some((since, timeout)) =
+                                        event_poll_params(&server_request.request)
+                                    {
+                                        if metrics::last_event_seq() <= since {
+                                            event_polls.push(PendingEventPoll {
+                                                request: server_request,
+                                                since,
+                                                deadline: Instant::now() + timeout,
+                                            });
+                                            continue;
+                                        }
+                                    }
+                                    if let Some((duration, format)) =
+                                        profile_params(&server_request.request)
+                                    {
+                                        match ProfileSession::start(format) {
+                                            Ok(session) => {
+                                                profiles.push(PendingProfile {
+                                                    request: server_request,
+                                                    session,
+                                                    deadline: Instant::now() + duration,
+                                                });
+                                                continue;
+                                            }
+                                            Err(e) => profile::set_outcome(Err(e)),
+                                        }
+                                    }
+                                    respond(&mut server, server_request);
                                 }
                             }
                             Err(e) => {
@@ -252,11 +445,91 @@ pub fn start_http_thread(
                             }
                         },
                         EVENT_HTTP_DIE => break 'wait Ok(()),
+                        EVENT_NEW_EVENTS => {
+                            // Reset the counter, new events are checked below.
+                            if let Some(n) = event_notifier.as_ref() {
+                                n.read().unwrap_or_default();
+                            }
+                        }
                         _ => error!("Invalid event"),
                     }
                 }
+
+                if !event_polls.is_empty() {
+                    let last_seq = metrics::last_event_seq();
+                    let now = Instant::now();
+                    let (ready, pending): (Vec<_>, Vec<_>) = event_polls
+                        .drain(..)
+                        .partition(|p| p.since < last_seq || p.deadline <= now);
+                    event_polls = pending;
+                    for p in ready {
+                        respond(&mut server, p.request);
+                    }
+                }
+
+                if !profiles.is_empty() {
+                    let now = Instant::now();
+                    let (ready, pending): (Vec<_>, Vec<_>) =
+                        profiles.drain(..).partition(|p| p.deadline <= now);
+                    profiles = pending;
+                    for p in ready {
+                        profile::set_outcome(p.session.finish());
+                        respond(&mut server, p.request);
+                    }
+                }
             }
         })?;
 
     Ok(thread)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_version() {
+        assert_eq!(ApiVersion::from_path("/api/v1/daemon"), ApiVersion::V1);
+        assert_eq!(ApiVersion::from_path("/api/v2/daemon"), ApiVersion::V2);
+        assert_eq!(ApiVersion::from_path("/daemon"), ApiVersion::V1);
+    }
+
+    #[test]
+    fn test_http_routes() {
+        for path in &[
+            "/daemon",
+            "/mount",
+            "/blobcache",
+            "/metrics",
+            "/metrics/inflight",
+            "/metrics/memory",
+        ] {
+            assert!(HTTP_ROUTES.routes.contains_key(&endpoint!(HTTP_ROOT, path)));
+            assert!(HTTP_ROUTES
+                .routes
+                .contains_key(&endpoint!(HTTP_ROOT_V2, path)));
+        }
+        assert!(!HTTP_ROUTES
+            .routes
+            .contains_key(&endpoint!(HTTP_ROOT, "/openapi")));
+        assert!(HTTP_ROUTES
+            .routes
+            .contains_key(&endpoint!(HTTP_ROOT_V2, "/openapi")));
+    }
+
+    #[test]
+    fn test_find_route() {
+        assert!(HTTP_ROUTES.find("/api/v1/metrics").is_some());
+        assert!(HTTP_ROUTES.find("/api/v1/metrics/fs/").is_some());
+        assert!(HTTP_ROUTES
+            .find("/api/v2/metrics/fs/images/busybox")
+            .is_some());
+        assert!(HTTP_ROUTES.find("/api/v1/metrics/fs").is_none());
+        assert!(HTTP_ROUTES
+            .find("/api/v1/mounts/images/busybox/prefetch")
+            .is_some());
+        assert!(HTTP_ROUTES.find("/api/v1/daemon/unknown").is_none());
+        assert!(HTTP_ROUTES.find("/api/v2/daemon/events/stream").is_some());
+        assert!(HTTP_ROUTES.find("/api/v1/daemon/profile").is_some());
+    }
+}
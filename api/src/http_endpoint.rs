@@ -8,16 +8,18 @@
 //! to export running metrics. So it will be easier to wrap different crates' Error
 //! into.
 
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::io;
 use std::sync::mpsc::{RecvError, SendError};
 
-use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use micro_http::{Body, MediaType, Method, Request, Response, StatusCode, Version};
 
-use serde::Deserialize;
+use serde::{de::Error as DeError, Deserialize, Deserializer};
 use serde_json::Error as SerdeError;
 
-use crate::http::{extract_query_part, EndpointHandler};
+use crate::http::{extract_path_param, extract_query_part, ApiVersion, EndpointHandler};
+use crate::profile::{self, ProfileError};
 
 use nydus_utils::metrics::IoStatsError;
 
@@ -33,6 +35,9 @@ pub enum DaemonErrorKind {
     Channel,
     Serde(SerdeError),
     UnexpectedEvent(String),
+    InvalidArguments(String),
+    NotFound,
+    Busy(String),
     Other(String),
 }
 
@@ -71,6 +76,20 @@ pub enum ApiResponsePayload {
     DaemonInfo(String),
     Events(String),
     FsBackendInfo(String),
+    /// Result of probing storage backend of a mount.
+    BackendHealth(String),
+    /// Summarized metadata of a rafs mount.
+    Metadata(String),
+    /// Live state of all mounted filesystem backends.
+    Mounts(String),
+    /// Blob files cached by all rafs mounts.
+    CachedBlobs(String),
+    /// Number of chunks imported into blobcache of each blob.
+    ImportedBlobs(String),
+    /// Which fields of a daemon configuration patch are applied.
+    DaemonConfPatched(String),
+    /// Log level of the daemon and its modules.
+    LogLevel(String),
     /// Nydus filesystem global metrics
     FsGlobalMetrics(String),
     /// Nydus filesystem per-file metrics
@@ -79,6 +98,15 @@ pub enum ApiResponsePayload {
     BackendMetrics(String),
     BlobcacheMetrics(String),
     InflightMetrics(String),
+    /// Outstanding fuse requests and backend reads of each mount.
+    MountsInflight(String),
+    /// Memory taken by subsystems of each mount.
+    MemoryUsage(String),
+    /// Summary of daemon state saved for failover.
+    SavedState(String),
+    /// Results of checking a mount command without mounting.
+    Validation(String),
+    FsMetrics(String),
 }
 
 /// This is the response sent by the API server through the mpsc channel.
@@ -89,22 +117,62 @@ pub type HttpResult = std::result::Result<Response, HttpError>;
 pub enum ApiRequest {
     DaemonInfo,
     Events,
+    /// Daemon events following the given sequence number.
+    EventStream(u64),
     Mount((String, ApiMountCmd)),
     Remount((String, ApiMountCmd)),
     Umount(String),
+    /// Check a mount command end to end without mounting.
+    Validate(ApiMountCmd),
+    BulkMount(Vec<ApiBulkMountCmd>),
     ConfigureDaemon(DaemonConf),
+    PatchDaemonConf(DaemonConfPatch),
+    GetLogLevel,
+    /// Change log level of the daemon, globally and per module.
+    SetLogLevel(ApiLogLevel),
     ExportGlobalMetrics(Option<String>),
     ExportFilesMetrics(Option<String>, bool),
     ExportAccessPatterns(Option<String>),
+    /// Files read since the mount, optionally as a prefetch list of image builder.
+    ExportMountAccessPattern((String, bool)),
+    /// Mountpoint, number of files, and whether to rank by reads rather than bytes.
+    ExportMountHotFiles((String, usize, bool)),
     ExportBackendMetrics(Option<String>),
     ExportBlobcacheMetrics(Option<String>),
     ExportInflightMetrics,
+    ExportMountsInflight,
+    ExportMemoryUsage,
+    ExportFsMetrics(String),
     ExportFsBackendInfo(String),
+    /// Probe storage backend of a mount.
+    ProbeBackend(String),
+    /// Dump metadata of a mount, with records of the given path within it.
+    DumpMetadata((String, Option<String>)),
+    ExportMounts,
+    PrefetchFiles((String, PrefetchFiles)),
+    ExportCachedBlobs,
+    /// Purge cached blobs of a mountpoint, or a single blob, or both.
+    PurgeCachedBlobs(Option<String>, Option<String>),
+    /// Import downloaded blob files into blobcache of a mountpoint.
+    ImportBlobs(ApiImportBlobsCmd),
     SendFuseFd,
+    /// Persist daemon state for failover right away.
+    SaveState,
     Takeover,
+    /// Live upgrade to a new binary, driven by the daemon itself.
+    Restart(ApiRestartCmd),
     Exit,
 }
 
+/// New Nydusd binary to run for live upgrade, and its arguments. Arguments of the running
+/// daemon plus `--upgrade` are used if `args` is absent.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiRestartCmd {
+    pub binary: String,
+    #[serde(default)]
+    pub args: Option<Vec<String>>,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct ApiMountCmd {
     pub source: String,
@@ -115,6 +183,54 @@ pub struct ApiMountCmd {
     pub prefetch_files: Option<Vec<String>>,
 }
 
+/// A mount command of `POST /mounts`, which mounts a batch of filesystems at once.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiBulkMountCmd {
+    pub mountpoint: String,
+    #[serde(flatten)]
+    pub cmd: ApiMountCmd,
+}
+
+/// Files to prefetch for a mounted filesystem, "all" or a list of absolute paths within it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrefetchFiles {
+    All,
+    Paths(Vec<String>),
+}
+
+impl<'de> Deserialize<'de> for PrefetchFiles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Files {
+            Keyword(String),
+            Paths(Vec<String>),
+        }
+
+        match Files::deserialize(deserializer)? {
+            Files::Keyword(k) if k == "all" => Ok(PrefetchFiles::All),
+            Files::Keyword(k) => Err(D::Error::custom(format!(
+                "expect \"all\" or a list of paths, got \"{}\"",
+                k
+            ))),
+            Files::Paths(p) => Ok(PrefetchFiles::Paths(p)),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiPrefetchCmd {
+    pub files: PrefetchFiles,
+}
+
+/// Blob files downloaded by others to fill blobcache of `mountpoint` with, `path` is either
+/// a blob file named by its blob id or a directory of them.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ApiImportBlobsCmd {
+    pub mountpoint: String,
+    pub path: String,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct ApiUmountCmd {
     pub mountpoint: String,
@@ -129,6 +245,41 @@ pub struct DaemonConf {
     pub log_level: String,
 }
 
+/// Log level of the daemon, with overrides for modules named by module path, such as
+/// `storage::backend`, which also apply to their submodules.
+#[derive(Clone, Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiLogLevel {
+    pub level: String,
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+/// Daemon settings to update at runtime, absent fields are left untouched.
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfPatch {
+    pub log_level: Option<String>,
+    /// Prefetch bandwidth limit of all rafs mounts in bytes per second, 0 means unlimited.
+    pub prefetch_bandwidth_rate: Option<u32>,
+    /// Storage backend settings, which are kept in the mount configuration saved for restart
+    /// and take effect once a new daemon takes over, as backends are created from it.
+    pub backend_timeout: Option<u64>,
+    pub backend_connect_timeout: Option<u64>,
+    pub backend_retry_limit: Option<u8>,
+    pub backend_auth: Option<String>,
+    pub backend_registry_token: Option<String>,
+}
+
+/// Response to a daemon configuration patch.
+#[derive(Debug, Default, Serialize)]
+pub struct DaemonConfPatchResult {
+    /// Fields which have taken effect.
+    pub applied: Vec<String>,
+    /// Fields which are accepted but take effect only after restart.
+    pub restart_required: Vec<String>,
+}
+
 /// Errors associated with Nydus management
 #[derive(Debug)]
 pub enum HttpError {
@@ -152,7 +303,16 @@ pub enum HttpError {
     BlobcacheMetrics(ApiError),
     BackendMetrics(ApiError),
     FsBackendInfo(ApiError),
+    BackendHealth(ApiError),
+    Metadata(ApiError),
     InflightMetrics(ApiError),
+    MountsInflight(ApiError),
+    MemoryUsage(ApiError),
+    Mounts(ApiError),
+    FsMetrics(ApiError),
+    Blobcache(ApiError),
+    Prefetch(ApiError),
+    Profile(ProfileError),
 }
 
 fn success_response(body: Option<String>) -> Response {
@@ -186,15 +346,97 @@ pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     response
 }
 
+/// Generate an error response in the format of API `version`.
+pub fn versioned_error_response(
+    version: ApiVersion,
+    error: HttpError,
+    status: StatusCode,
+) -> Response {
+    match version {
+        ApiVersion::V1 => error_response(error, status),
+        ApiVersion::V2 => {
+            let mut response = Response::new(Version::Http11, status);
+            let err_msg = ErrorMessage {
+                code: error_code(&error).to_string(),
+                message: format!("{:?}", error),
+            };
+            response.set_body(Body::new(serde_json::to_string(&err_msg).unwrap()));
+            response
+        }
+    }
+}
+
+fn error_code(e: &HttpError) -> &'static str {
+    match e {
+        HttpError::NoRoute => "NO_ROUTE",
+        HttpError::BadRequest => "BAD_REQUEST",
+        HttpError::QueryString(_) => "INVALID_QUERY",
+        HttpError::SerdeJsonDeserialize(_) | HttpError::ParseBody(_) => "INVALID_BODY",
+        HttpError::SerdeJsonSerialize(_) => "INTERNAL_ERROR",
+        HttpError::Info(e)
+        | HttpError::Events(e)
+        | HttpError::Mount(e)
+        | HttpError::GlobalMetrics(e)
+        | HttpError::FsFilesMetrics(e)
+        | HttpError::Pattern(e)
+        | HttpError::Configure(e)
+        | HttpError::Upgrade(e)
+        | HttpError::BlobcacheMetrics(e)
+        | HttpError::BackendMetrics(e)
+        | HttpError::FsBackendInfo(e)
+        | HttpError::BackendHealth(e)
+        | HttpError::Metadata(e)
+        | HttpError::InflightMetrics(e)
+        | HttpError::MountsInflight(e)
+        | HttpError::MemoryUsage(e)
+        | HttpError::Mounts(e)
+        | HttpError::FsMetrics(e)
+        | HttpError::Blobcache(e)
+        | HttpError::Prefetch(e) => api_error_code(e),
+        HttpError::Profile(e) => match e {
+            ProfileError::Unsupported => "UNSUPPORTED",
+            ProfileError::Busy => "INVALID_STATE",
+            ProfileError::Report(_) => "INTERNAL_ERROR",
+        },
+    }
+}
+
+// Keep in line with translate_status_code().
+fn api_error_code(e: &ApiError) -> &'static str {
+    match e {
+        ApiError::DaemonAbnormal(kind) | ApiError::MountFailure(kind) => match kind {
+            DaemonErrorKind::NotReady => "NOT_READY",
+            DaemonErrorKind::Unsupported => "UNSUPPORTED",
+            DaemonErrorKind::UnexpectedEvent(_) => "INVALID_STATE",
+            DaemonErrorKind::InvalidArguments(_) => "INVALID_ARGUMENTS",
+            DaemonErrorKind::NotFound => "NOT_FOUND",
+            DaemonErrorKind::Busy(_) => "BUSY",
+            _ => match e {
+                ApiError::MountFailure(_) => "MOUNT_FAILURE",
+                _ => "INTERNAL_ERROR",
+            },
+        },
+        ApiError::Metrics(MetricsErrorKind::Stats(IoStatsError::NoCounter)) => "NOT_FOUND",
+        ApiError::Metrics(MetricsErrorKind::Stats(IoStatsError::Disabled)) => "UNSUPPORTED",
+        _ => "INTERNAL_ERROR",
+    }
+}
+
 fn translate_status_code(e: &ApiError) -> StatusCode {
     match e {
         ApiError::DaemonAbnormal(kind) | ApiError::MountFailure(kind) => match kind {
             DaemonErrorKind::NotReady => StatusCode::ServiceUnavailable,
             DaemonErrorKind::Unsupported => StatusCode::NotImplemented,
             DaemonErrorKind::UnexpectedEvent(_) => StatusCode::BadRequest,
+            DaemonErrorKind::InvalidArguments(_) => StatusCode::BadRequest,
+            DaemonErrorKind::NotFound => StatusCode::NotFound,
+            DaemonErrorKind::Busy(_) => StatusCode::ServiceUnavailable,
             _ => StatusCode::InternalServerError,
         },
         ApiError::Metrics(MetricsErrorKind::Stats(IoStatsError::NoCounter)) => StatusCode::NotFound,
+        ApiError::Metrics(MetricsErrorKind::Stats(IoStatsError::Disabled)) => {
+            StatusCode::NotImplemented
+        }
         _ => StatusCode::InternalServerError,
     }
 }
@@ -203,13 +445,19 @@ fn translate_status_code(e: &ApiError) -> StatusCode {
 // a `error_response` is generated whose status code is 4XX or 5XX. With error response,
 // it still returns Ok(error_response) to http request handling framework, which means
 // nydusd api server receives the request and try handle it, even the request can't be fulfilled.
-fn convert_to_response<O: FnOnce(ApiError) -> HttpError>(api_resp: ApiResponse, op: O) -> Response {
+fn convert_to_response<O: FnOnce(ApiError) -> HttpError>(
+    req: &Request,
+    api_resp: ApiResponse,
+    op: O,
+) -> Response {
     match api_resp {
         Ok(r) => {
             use ApiResponsePayload::*;
             match r {
                 Empty => success_response(None),
                 DaemonInfo(d) => success_response(Some(d)),
+                DaemonConfPatched(d) => success_response(Some(d)),
+                LogLevel(d) => success_response(Some(d)),
                 Events(d) => success_response(Some(d)),
                 FsFilesMetrics(d) => success_response(Some(d)),
                 FsGlobalMetrics(d) => success_response(Some(d)),
@@ -217,12 +465,23 @@ fn convert_to_response<O: FnOnce(ApiError) -> HttpError>(api_resp: ApiResponse,
                 BackendMetrics(d) => success_response(Some(d)),
                 BlobcacheMetrics(d) => success_response(Some(d)),
                 FsBackendInfo(d) => success_response(Some(d)),
+                BackendHealth(d) => success_response(Some(d)),
+                Metadata(d) => success_response(Some(d)),
+                Mounts(d) => success_response(Some(d)),
+                CachedBlobs(d) => success_response(Some(d)),
+                ImportedBlobs(d) => success_response(Some(d)),
                 InflightMetrics(d) => success_response(Some(d)),
+                MountsInflight(d) => success_response(Some(d)),
+                MemoryUsage(d) => success_response(Some(d)),
+                SavedState(d) => success_response(Some(d)),
+                Validation(d) => success_response(Some(d)),
+                FsMetrics(d) => success_response(Some(d)),
             }
         }
         Err(e) => {
             let sc = translate_status_code(&e);
-            error_response(op(e), sc)
+            let version = ApiVersion::from_path(req.uri().get_abs_path());
+            versioned_error_response(version, op(e), sc)
         }
     }
 }
@@ -237,12 +496,17 @@ impl EndpointHandler for InfoHandler {
         match (req.method(), req.body.as_ref()) {
             (Method::Get, None) => {
                 let r = kicker(ApiRequest::DaemonInfo);
-                Ok(convert_to_response(r, HttpError::Info))
+                Ok(convert_to_response(req, r, HttpError::Info))
             }
             (Method::Put, Some(body)) => {
                 let conf = parse_body(body)?;
                 let r = kicker(ApiRequest::ConfigureDaemon(conf));
-                Ok(convert_to_response(r, HttpError::Configure))
+                Ok(convert_to_response(req, r, HttpError::Configure))
+            }
+            (Method::Patch, Some(body)) => {
+                let patch = parse_body(body)?;
+                let r = kicker(ApiRequest::PatchDaemonConf(patch));
+                Ok(convert_to_response(req, r, HttpError::Configure))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -259,7 +523,38 @@ impl EndpointHandler for EventsHandler {
         match (req.method(), req.body.as_ref()) {
             (Method::Get, None) => {
                 let r = kicker(ApiRequest::Events);
-                Ok(convert_to_response(r, HttpError::Events))
+                Ok(convert_to_response(req, r, HttpError::Events))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+/// Poll daemon events following sequence number `since`. When there is no such event yet,
+/// the HTTP server holds the request for at most `timeout` seconds before it is handled.
+pub struct EventStreamHandler {}
+impl EndpointHandler for EventStreamHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let since = match extract_query_part(req, "since") {
+                    Some(s) => s.parse::<u64>().map_err(|_| {
+                        HttpError::QueryString("'since' should be a sequence number".to_string())
+                    })?,
+                    None => 0,
+                };
+                // Waiting for events is up to the HTTP server, see `start_http_thread()`.
+                if let Some(t) = extract_query_part(req, "timeout") {
+                    t.parse::<u64>().map_err(|_| {
+                        HttpError::QueryString("'timeout' should be in unit of seconds".to_string())
+                    })?;
+                }
+                let r = kicker(ApiRequest::EventStream(since));
+                Ok(convert_to_response(req, r, HttpError::Events))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -280,16 +575,201 @@ impl EndpointHandler for MountHandler {
             (Method::Post, Some(body)) => {
                 let cmd = parse_body(body)?;
                 let r = kicker(ApiRequest::Mount((mountpoint, cmd)));
-                Ok(convert_to_response(r, HttpError::Mount))
+                Ok(convert_to_response(req, r, HttpError::Mount))
             }
             (Method::Put, Some(body)) => {
                 let cmd = parse_body(body)?;
                 let r = kicker(ApiRequest::Remount((mountpoint, cmd)));
-                Ok(convert_to_response(r, HttpError::Mount))
+                Ok(convert_to_response(req, r, HttpError::Mount))
             }
             (Method::Delete, None) => {
                 let r = kicker(ApiRequest::Umount(mountpoint));
-                Ok(convert_to_response(r, HttpError::Mount))
+                Ok(convert_to_response(req, r, HttpError::Mount))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct ValidateHandler {}
+impl EndpointHandler for ValidateHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Post, Some(body)) => {
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::Validate(cmd));
+                Ok(convert_to_response(req, r, HttpError::Mount))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct MountsHandler {}
+impl EndpointHandler for MountsHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ExportMounts);
+                Ok(convert_to_response(req, r, HttpError::Mounts))
+            }
+            (Method::Post, Some(body)) => {
+                let cmds = parse_body(body)?;
+                let r = kicker(ApiRequest::BulkMount(cmds));
+                Ok(convert_to_response(req, r, HttpError::Mount))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+/// Actions on a mounted filesystem, which may take more than one path segment.
+const MOUNT_ACTIONS: &[&str] = &[
+    "prefetch",
+    "access-pattern",
+    "hot-files",
+    "backend/health",
+    "metadata",
+];
+
+/// Split `{mountpoint}/{action}` into mountpoint and action, an unknown action is taken
+/// from the last path segment.
+fn split_mount_action(path: &str) -> (String, String) {
+    let path = format!("/{}", path.trim_end_matches('/'));
+    let action = MOUNT_ACTIONS
+        .iter()
+        .find(|a| path.ends_with(&format!("/{}", a)))
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| path.rsplit('/').next().unwrap_or_default().to_string());
+    let mountpoint = &path[..path.len() - action.len() - 1];
+    let mountpoint = if mountpoint.is_empty() {
+        "/"
+    } else {
+        mountpoint
+    };
+
+    (mountpoint.to_string(), action)
+}
+
+/// Split path of `/mounts/{mountpoint}/{action}` into mountpoint and action.
+fn parse_mount_action(req: &Request) -> Option<(String, String)> {
+    extract_path_param(req, "/mounts/").map(|p| split_mount_action(&p))
+}
+
+/// Number of files in the hot files report if not specified.
+const DEFAULT_HOT_FILES: usize = 20;
+
+/// Whether the access pattern is asked for as a prefetch list, rather than in JSON.
+fn is_prefetch_list(req: &Request) -> bool {
+    extract_query_part(req, "format").as_deref() == Some("prefetch-list")
+}
+
+/// Handle actions on a mounted filesystem, `/mounts/{mountpoint}/{action}`.
+pub struct MountActionHandler {}
+impl EndpointHandler for MountActionHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        let (mountpoint, action) = parse_mount_action(req).ok_or(HttpError::BadRequest)?;
+
+        match (req.method(), action.as_str(), req.body.as_ref()) {
+            (Method::Post, "prefetch", Some(body)) => {
+                let cmd: ApiPrefetchCmd = parse_body(body)?;
+                let r = kicker(ApiRequest::PrefetchFiles((mountpoint, cmd.files)));
+                Ok(convert_to_response(req, r, HttpError::Prefetch))
+            }
+            (_, "prefetch", _) => Err(HttpError::BadRequest),
+            (Method::Get, "access-pattern", None) => {
+                let r = kicker(ApiRequest::ExportMountAccessPattern((
+                    mountpoint,
+                    is_prefetch_list(req),
+                )));
+                Ok(convert_to_response(req, r, HttpError::Pattern))
+            }
+            (_, "access-pattern", _) => Err(HttpError::BadRequest),
+            (Method::Get, "hot-files", None) => {
+                let top = match extract_query_part(req, "top") {
+                    Some(t) => t.parse::<usize>().map_err(|_| {
+                        HttpError::QueryString(format!("invalid number of files '{}'", t))
+                    })?,
+                    None => DEFAULT_HOT_FILES,
+                };
+                let by_reads = match extract_query_part(req, "by").as_deref() {
+                    None | Some("bytes") => false,
+                    Some("reads") => true,
+                    Some(o) => {
+                        return Err(HttpError::QueryString(format!(
+                            "'by' should be 'bytes' or 'reads', got '{}'",
+                            o
+                        )))
+                    }
+                };
+                let r = kicker(ApiRequest::ExportMountHotFiles((mountpoint, top, by_reads)));
+                Ok(convert_to_response(req, r, HttpError::Pattern))
+            }
+            (_, "hot-files", _) => Err(HttpError::BadRequest),
+            (Method::Get, "backend/health", None) => {
+                let r = kicker(ApiRequest::ProbeBackend(mountpoint));
+                Ok(convert_to_response(req, r, HttpError::BackendHealth))
+            }
+            (_, "backend/health", _) => Err(HttpError::BadRequest),
+            (Method::Get, "metadata", None) => {
+                let path = extract_query_part(req, "path");
+                let r = kicker(ApiRequest::DumpMetadata((mountpoint, path)));
+                Ok(convert_to_response(req, r, HttpError::Metadata))
+            }
+            (_, "metadata", _) => Err(HttpError::BadRequest),
+            _ => Err(HttpError::NoRoute),
+        }
+    }
+
+    fn media_type(&self, req: &Request) -> MediaType {
+        match parse_mount_action(req) {
+            Some((_, action)) if action == "access-pattern" && is_prefetch_list(req) => {
+                MediaType::PlainText
+            }
+            _ => MediaType::ApplicationJson,
+        }
+    }
+}
+
+pub struct BlobcacheHandler {}
+impl EndpointHandler for BlobcacheHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ExportCachedBlobs);
+                Ok(convert_to_response(req, r, HttpError::Blobcache))
+            }
+            (Method::Delete, None) => {
+                let mountpoint = extract_query_part(req, "mountpoint");
+                let blob_id = extract_query_part(req, "blob_id");
+                if mountpoint.is_none() && blob_id.is_none() {
+                    return Err(HttpError::QueryString(
+                        "'mountpoint' or 'blob_id' should be specified in query string".to_string(),
+                    ));
+                }
+                let r = kicker(ApiRequest::PurgeCachedBlobs(mountpoint, blob_id));
+                Ok(convert_to_response(req, r, HttpError::Blobcache))
+            }
+            (Method::Put, Some(body)) => {
+                let cmd: ApiImportBlobsCmd = parse_body(body)?;
+                let r = kicker(ApiRequest::ImportBlobs(cmd));
+                Ok(convert_to_response(req, r, HttpError::Blobcache))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -307,7 +787,7 @@ impl EndpointHandler for MetricsHandler {
             (Method::Get, None) => {
                 let id = extract_query_part(req, "id");
                 let r = kicker(ApiRequest::ExportGlobalMetrics(id));
-                Ok(convert_to_response(r, HttpError::GlobalMetrics))
+                Ok(convert_to_response(req, r, HttpError::GlobalMetrics))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -327,7 +807,7 @@ impl EndpointHandler for MetricsFilesHandler {
                 let latest_read_files = extract_query_part(req, "latest")
                     .map_or(false, |b| b.parse::<bool>().unwrap_or(false));
                 let r = kicker(ApiRequest::ExportFilesMetrics(id, latest_read_files));
-                Ok(convert_to_response(r, HttpError::FsFilesMetrics))
+                Ok(convert_to_response(req, r, HttpError::FsFilesMetrics))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -345,7 +825,7 @@ impl EndpointHandler for MetricsPatternHandler {
             (Method::Get, None) => {
                 let id = extract_query_part(req, "id");
                 let r = kicker(ApiRequest::ExportAccessPatterns(id));
-                Ok(convert_to_response(r, HttpError::Pattern))
+                Ok(convert_to_response(req, r, HttpError::Pattern))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -363,7 +843,7 @@ impl EndpointHandler for MetricsBackendHandler {
             (Method::Get, None) => {
                 let id = extract_query_part(req, "id");
                 let r = kicker(ApiRequest::ExportBackendMetrics(id));
-                Ok(convert_to_response(r, HttpError::BackendMetrics))
+                Ok(convert_to_response(req, r, HttpError::BackendMetrics))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -381,7 +861,7 @@ impl EndpointHandler for MetricsBlobcacheHandler {
             (Method::Get, None) => {
                 let id = extract_query_part(req, "id");
                 let r = kicker(ApiRequest::ExportBlobcacheMetrics(id));
-                Ok(convert_to_response(r, HttpError::BlobcacheMetrics))
+                Ok(convert_to_response(req, r, HttpError::BlobcacheMetrics))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -398,7 +878,61 @@ impl EndpointHandler for MetricsInflightHandler {
         match (req.method(), req.body.as_ref()) {
             (Method::Get, None) => {
                 let r = kicker(ApiRequest::ExportInflightMetrics);
-                Ok(convert_to_response(r, HttpError::InflightMetrics))
+                Ok(convert_to_response(req, r, HttpError::InflightMetrics))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct MetricsMemoryHandler {}
+impl EndpointHandler for MetricsMemoryHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ExportMemoryUsage);
+                Ok(convert_to_response(req, r, HttpError::MemoryUsage))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct InflightHandler {}
+impl EndpointHandler for InflightHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::ExportMountsInflight);
+                Ok(convert_to_response(req, r, HttpError::MountsInflight))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct MetricsFsHandler {}
+impl EndpointHandler for MetricsFsHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let mountpoint = extract_path_param(req, "/metrics/fs/")
+                    .map(|p| format!("/{}", p.trim_end_matches('/')))
+                    .ok_or(HttpError::BadRequest)?;
+                let r = kicker(ApiRequest::ExportFsMetrics(mountpoint));
+                Ok(convert_to_response(req, r, HttpError::FsMetrics))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -415,7 +949,7 @@ impl EndpointHandler for SendFuseFdHandler {
         match (req.method(), req.body.as_ref()) {
             (Method::Put, None) => {
                 let r = kicker(ApiRequest::SendFuseFd);
-                Ok(convert_to_response(r, HttpError::Upgrade))
+                Ok(convert_to_response(req, r, HttpError::Upgrade))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -432,7 +966,64 @@ impl EndpointHandler for TakeoverHandler {
         match (req.method(), req.body.as_ref()) {
             (Method::Put, None) => {
                 let r = kicker(ApiRequest::Takeover);
-                Ok(convert_to_response(r, HttpError::Upgrade))
+                Ok(convert_to_response(req, r, HttpError::Upgrade))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct LogLevelHandler {}
+impl EndpointHandler for LogLevelHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                let r = kicker(ApiRequest::GetLogLevel);
+                Ok(convert_to_response(req, r, HttpError::Configure))
+            }
+            (Method::Put, Some(body)) => {
+                let level = parse_body(body)?;
+                let r = kicker(ApiRequest::SetLogLevel(level));
+                Ok(convert_to_response(req, r, HttpError::Configure))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct SaveStateHandler {}
+impl EndpointHandler for SaveStateHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, None) => {
+                let r = kicker(ApiRequest::SaveState);
+                Ok(convert_to_response(req, r, HttpError::Upgrade))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct RestartHandler {}
+impl EndpointHandler for RestartHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Put, Some(body)) => {
+                let cmd = parse_body(body)?;
+                let r = kicker(ApiRequest::Restart(cmd));
+                Ok(convert_to_response(req, r, HttpError::Upgrade))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -449,7 +1040,7 @@ impl EndpointHandler for ExitHandler {
         match (req.method(), req.body.as_ref()) {
             (Method::Put, None) => {
                 let r = kicker(ApiRequest::Exit);
-                Ok(convert_to_response(r, HttpError::Upgrade))
+                Ok(convert_to_response(req, r, HttpError::Upgrade))
             }
             _ => Err(HttpError::BadRequest),
         }
@@ -472,9 +1063,303 @@ impl EndpointHandler for FsBackendInfo {
                     )
                 })?;
                 let r = kicker(ApiRequest::ExportFsBackendInfo(mountpoint));
-                Ok(convert_to_response(r, HttpError::FsBackendInfo))
+                Ok(convert_to_response(req, r, HttpError::FsBackendInfo))
+            }
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+}
+
+pub struct ProfileHandler {}
+impl EndpointHandler for ProfileHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        _kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => {
+                profile::parse_params(req)?;
+                // Profiles are taken by the HTTP server, see `start_http_thread()`.
+                match profile::take_outcome() {
+                    Some(Ok(data)) => {
+                        let mut r = Response::new(Version::Http11, StatusCode::OK);
+                        r.set_body(Body::new(data));
+                        Ok(r)
+                    }
+                    Some(Err(e)) => Err(HttpError::Profile(e)),
+                    None => Err(HttpError::Profile(ProfileError::Report(
+                        "profile is not taken".to_string(),
+                    ))),
+                }
             }
             _ => Err(HttpError::BadRequest),
         }
     }
+
+    fn media_type(&self, _req: &Request) -> MediaType {
+        MediaType::PlainText
+    }
+}
+
+const OPENAPI_V2: &str = include_str!("../openapi/nydus-api-v2.yaml");
+
+pub struct OpenApiHandler {}
+impl EndpointHandler for OpenApiHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        _kicker: &dyn Fn(ApiRequest) -> ApiResponse,
+    ) -> HttpResult {
+        match (req.method(), req.body.as_ref()) {
+            (Method::Get, None) => Ok(success_response(Some(OPENAPI_V2.to_string()))),
+            _ => Err(HttpError::BadRequest),
+        }
+    }
+
+    fn media_type(&self, _req: &Request) -> MediaType {
+        MediaType::PlainText
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_daemon_conf_patch() {
+        let patch: DaemonConfPatch =
+            serde_json::from_str(r#"{"log_level": "debug", "prefetch_bandwidth_rate": 0}"#)
+                .unwrap();
+        assert_eq!(patch.log_level, Some("debug".to_string()));
+        assert_eq!(patch.prefetch_bandwidth_rate, Some(0));
+        assert!(patch.backend_timeout.is_none());
+
+        assert!(serde_json::from_str::<DaemonConfPatch>(r#"{"thread_num": 4}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_log_level() {
+        let level: ApiLogLevel = serde_json::from_str(r#"{"level": "info"}"#).unwrap();
+        assert_eq!(level.level, "info");
+        assert!(level.modules.is_empty());
+        let level: ApiLogLevel =
+            serde_json::from_str(r#"{"level": "warn", "modules": {"storage::backend": "trace"}}"#)
+                .unwrap();
+        assert_eq!(level.modules["storage::backend"], "trace");
+
+        assert!(serde_json::from_str::<ApiLogLevel>(r#"{"modules": {}}"#).is_err());
+        assert!(serde_json::from_str::<ApiLogLevel>(r#"{"level": "info", "rafs": 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_bulk_mount() {
+        let cmds: Vec<ApiBulkMountCmd> = serde_json::from_str(
+            r#"[{"mountpoint": "/a", "source": "/a.boot", "fs_type": "rafs", "config": "{}"},
+                {"mountpoint": "/b", "source": "/b.boot", "config": "{}", "prefetch_files": ["/bin"]}]"#,
+        )
+        .unwrap();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(cmds[0].mountpoint, "/a");
+        assert_eq!(cmds[0].cmd.fs_type, "rafs");
+        assert_eq!(cmds[1].cmd.prefetch_files, Some(vec!["/bin".to_string()]));
+
+        assert!(serde_json::from_str::<Vec<ApiBulkMountCmd>>(
+            r#"[{"source": "/a.boot", "config": "{}"}]"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_prefetch_files() {
+        let cmd: ApiPrefetchCmd = serde_json::from_str(r#"{"files": "all"}"#).unwrap();
+        assert_eq!(cmd.files, PrefetchFiles::All);
+        let cmd: ApiPrefetchCmd =
+            serde_json::from_str(r#"{"files": ["/usr/bin", "/etc/hosts"]}"#).unwrap();
+        assert_eq!(
+            cmd.files,
+            PrefetchFiles::Paths(vec!["/usr/bin".to_string(), "/etc/hosts".to_string()])
+        );
+
+        assert!(serde_json::from_str::<ApiPrefetchCmd>(r#"{"files": "none"}"#).is_err());
+        assert!(serde_json::from_str::<ApiPrefetchCmd>(r#"{"files": 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_split_mount_action() {
+        let split = |mp: &str, action: &str| (mp.to_string(), action.to_string());
+        assert_eq!(
+            split_mount_action("images/busybox/prefetch"),
+            split("/images/busybox", "prefetch")
+        );
+        assert_eq!(split_mount_action("prefetch"), split("/", "prefetch"));
+        assert_eq!(
+            split_mount_action("sub/backend/health/"),
+            split("/sub", "backend/health")
+        );
+        assert_eq!(
+            split_mount_action("backend/health"),
+            split("/", "backend/health")
+        );
+        assert_eq!(
+            split_mount_action("images/busybox/metadata"),
+            split("/images/busybox", "metadata")
+        );
+        assert_eq!(
+            split_mount_action("images/busybox/hot-files"),
+            split("/images/busybox", "hot-files")
+        );
+        assert_eq!(split_mount_action("sub/unknown"), split("/sub", "unknown"));
+        assert_eq!(split_mount_action(""), split("/", ""));
+    }
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(error_code(&HttpError::NoRoute), "NO_ROUTE");
+        assert_eq!(
+            error_code(&HttpError::QueryString("mountpoint".to_string())),
+            "INVALID_QUERY"
+        );
+        assert_eq!(
+            error_code(&HttpError::Mount(ApiError::MountFailure(
+                DaemonErrorKind::NotReady
+            ))),
+            "NOT_READY"
+        );
+        assert_eq!(
+            error_code(&HttpError::Mount(ApiError::MountFailure(
+                DaemonErrorKind::Other("busy".to_string())
+            ))),
+            "MOUNT_FAILURE"
+        );
+        assert_eq!(
+            error_code(&HttpError::FsFilesMetrics(ApiError::Metrics(
+                MetricsErrorKind::Stats(IoStatsError::NoCounter)
+            ))),
+            "NOT_FOUND"
+        );
+        assert_eq!(
+            error_code(&HttpError::Pattern(ApiError::Metrics(
+                MetricsErrorKind::Stats(IoStatsError::Disabled)
+            ))),
+            "UNSUPPORTED"
+        );
+        assert_eq!(
+            error_code(&HttpError::Blobcache(ApiError::DaemonAbnormal(
+                DaemonErrorKind::NotFound
+            ))),
+            "NOT_FOUND"
+        );
+        assert_eq!(
+            error_code(&HttpError::Blobcache(ApiError::DaemonAbnormal(
+                DaemonErrorKind::Busy("blob".to_string())
+            ))),
+            "BUSY"
+        );
+        assert_eq!(
+            error_code(&HttpError::Info(ApiError::ResponsePayloadType)),
+            "INTERNAL_ERROR"
+        );
+    }
+
+    /// Check schema objects of the OpenAPI doc `doc` recursively, `at` tells where it is.
+    fn check_schema(doc: &serde_json::Value, schema: &serde_json::Value, at: &str) {
+        const KEYWORDS: &[&str] = &[
+            "$ref",
+            "type",
+            "format",
+            "description",
+            "properties",
+            "required",
+            "items",
+            "oneOf",
+            "anyOf",
+            "allOf",
+            "enum",
+            "additionalProperties",
+            "example",
+            "default",
+            "nullable",
+            "minimum",
+            "maximum",
+        ];
+        const TYPES: &[&str] = &["string", "integer", "number", "boolean", "array", "object"];
+
+        let schema = schema
+            .as_object()
+            .unwrap_or_else(|| panic!("{}: schema is not an object", at));
+        for (key, value) in schema {
+            assert!(KEYWORDS.contains(&key.as_str()), "{}: unknown {}", at, key);
+            match key.as_str() {
+                "$ref" => {
+                    let target = value.as_str().unwrap().trim_start_matches('#');
+                    assert!(doc.pointer(target).is_some(), "{}: no {}", at, target);
+                }
+                "type" => assert!(
+                    TYPES.contains(&value.as_str().unwrap_or_default()),
+                    "{}: invalid type {}",
+                    at,
+                    value
+                ),
+                "properties" => {
+                    for (name, s) in value.as_object().unwrap() {
+                        check_schema(doc, s, &format!("{}.{}", at, name));
+                    }
+                }
+                "items" => check_schema(doc, value, at),
+                "additionalProperties" if value.is_object() => check_schema(doc, value, at),
+                "oneOf" | "anyOf" | "allOf" => {
+                    for s in value.as_array().unwrap() {
+                        check_schema(doc, s, at);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_openapi_doc() {
+        const PARAMETER_KEYS: &[&str] = &["name", "in", "description", "required", "schema"];
+
+        let doc: serde_json::Value = serde_yaml::from_str(OPENAPI_V2).unwrap();
+        for (name, schema) in doc["components"]["schemas"].as_object().unwrap() {
+            check_schema(&doc, schema, name);
+        }
+        for (path, ops) in doc["paths"].as_object().unwrap() {
+            // Path items may have a summary and a description besides operations.
+            for (method, op) in
+                ops.as_object().unwrap().iter().filter(|(m, _)| {
+                    ["get", "put", "post", "patch", "delete"].contains(&m.as_str())
+                })
+            {
+                let at = format!("{} {}", method, path);
+                for param in op["parameters"].as_array().unwrap_or(&Vec::new()) {
+                    for key in param.as_object().unwrap().keys() {
+                        assert!(PARAMETER_KEYS.contains(&key.as_str()), "{}: {}", at, key);
+                    }
+                    assert!(
+                        param["name"].is_string() && param["in"].is_string(),
+                        "{}",
+                        at
+                    );
+                    check_schema(&doc, &param["schema"], &at);
+                }
+                if let Some(content) = op["requestBody"]["content"].as_object() {
+                    for media in content.values() {
+                        check_schema(&doc, &media["schema"], &at);
+                    }
+                }
+                for (status, resp) in op["responses"].as_object().unwrap() {
+                    let at = format!("{} {}", at, status);
+                    assert!(resp["description"].is_string(), "{}: no description", at);
+                    if let Some(content) = resp["content"].as_object() {
+                        for media in content.values() {
+                            check_schema(&doc, &media["schema"], &at);
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
@@ -13,5 +13,12 @@ extern crate vmm_sys_util;
 extern crate lazy_static;
 extern crate url;
 
+pub mod audit;
+pub mod client;
 pub mod http;
 pub mod http_endpoint;
+pub mod profile;
+pub mod prometheus;
+pub mod push;
+pub mod server;
+pub mod tls;
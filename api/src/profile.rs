@@ -0,0 +1,166 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! On-demand CPU profiling of the daemon through `GET /daemon/profile`.
+//!
+//! The profiler samples all threads with `SIGPROF` for the requested seconds, meanwhile the
+//! request is held by the HTTP server like event polls, so other API requests are served as
+//! usual. Only one profile can be taken at a time. Profiling needs nydusd built with feature
+//! `profile`.
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use micro_http::Request;
+
+use crate::http::extract_query_part;
+use crate::http_endpoint::HttpError;
+
+/// Seconds to profile if not given by the request.
+const DEFAULT_PROFILE_SECONDS: u64 = 10;
+/// Max seconds a profile request can be held.
+pub(crate) const MAX_PROFILE_SECONDS: u64 = 60;
+/// Samples per second, not a multiple of common timer frequencies to avoid lockstep sampling.
+#[cfg(feature = "profile")]
+const SAMPLE_FREQUENCY: i32 = 99;
+
+thread_local! {
+    // Outcome of the profile being responded by the HTTP server thread.
+    static OUTCOME: RefCell<Option<Result<Vec<u8>, ProfileError>>> = RefCell::new(None);
+}
+
+#[derive(Debug)]
+pub enum ProfileError {
+    /// nydusd is built without feature `profile`.
+    Unsupported,
+    /// Another profile is being taken.
+    Busy,
+    /// Failed to build or encode the profile.
+    Report(String),
+}
+
+/// Output format of a profile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProfileFormat {
+    /// Protobuf consumed by `go tool pprof`.
+    Pprof,
+    /// Flame graph in SVG.
+    Flamegraph,
+}
+
+/// Get seconds to profile and output format from query string of `req`.
+pub(crate) fn parse_params(req: &Request) -> Result<(Duration, ProfileFormat), HttpError> {
+    let seconds = match extract_query_part(req, "seconds") {
+        Some(s) => match s.parse::<u64>() {
+            Ok(n) if n > 0 && n <= MAX_PROFILE_SECONDS => n,
+            _ => {
+                return Err(HttpError::QueryString(format!(
+                    "'seconds' should be between 1 and {}",
+                    MAX_PROFILE_SECONDS
+                )))
+            }
+        },
+        None => DEFAULT_PROFILE_SECONDS,
+    };
+    let format = match extract_query_part(req, "format").as_deref() {
+        None | Some("pprof") => ProfileFormat::Pprof,
+        Some("flamegraph") => ProfileFormat::Flamegraph,
+        Some(_) => {
+            return Err(HttpError::QueryString(
+                "'format' should be 'pprof' or 'flamegraph'".to_string(),
+            ))
+        }
+    };
+
+    Ok((Duration::from_secs(seconds), format))
+}
+
+/// A profile being taken, until it's finished.
+pub(crate) struct ProfileSession {
+    format: ProfileFormat,
+    #[cfg(feature = "profile")]
+    guard: pprof::ProfilerGuard<'static>,
+}
+
+impl ProfileSession {
+    #[cfg(feature = "profile")]
+    pub(crate) fn start(format: ProfileFormat) -> Result<Self, ProfileError> {
+        // pprof fails to start only if it's running already.
+        let guard = pprof::ProfilerGuard::new(SAMPLE_FREQUENCY).map_err(|e| {
+            warn!("failed to start profiler, {}", e);
+            ProfileError::Busy
+        })?;
+        info!("CPU profiling started");
+
+        Ok(ProfileSession { format, guard })
+    }
+
+    #[cfg(not(feature = "profile"))]
+    pub(crate) fn start(_format: ProfileFormat) -> Result<Self, ProfileError> {
+        Err(ProfileError::Unsupported)
+    }
+
+    /// Stop sampling and encode the profile.
+    #[cfg(feature = "profile")]
+    pub(crate) fn finish(self) -> Result<Vec<u8>, ProfileError> {
+        let report = self
+            .guard
+            .report()
+            .build()
+            .map_err(|e| ProfileError::Report(e.to_string()))?;
+        let mut body = Vec::new();
+        match self.format {
+            ProfileFormat::Pprof => {
+                let profile = report
+                    .pprof()
+                    .map_err(|e| ProfileError::Report(e.to_string()))?;
+                prost::Message::encode(&profile, &mut body)
+                    .map_err(|e| ProfileError::Report(e.to_string()))?;
+            }
+            ProfileFormat::Flamegraph => report
+                .flamegraph(&mut body)
+                .map_err(|e| ProfileError::Report(e.to_string()))?,
+        }
+        info!("CPU profiling finished, {} bytes", body.len());
+
+        Ok(body)
+    }
+
+    #[cfg(not(feature = "profile"))]
+    pub(crate) fn finish(self) -> Result<Vec<u8>, ProfileError> {
+        let _ = self.format;
+        Err(ProfileError::Unsupported)
+    }
+}
+
+/// Hand the outcome of a profile to `ProfileHandler`, which responds it in the same thread.
+pub(crate) fn set_outcome(outcome: Result<Vec<u8>, ProfileError>) {
+    OUTCOME.with(|o| *o.borrow_mut() = Some(outcome));
+}
+
+pub(crate) fn take_outcome() -> Option<Result<Vec<u8>, ProfileError>> {
+    OUTCOME.with(|o| o.borrow_mut().take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome() {
+        assert!(take_outcome().is_none());
+        set_outcome(Err(ProfileError::Busy));
+        assert!(matches!(take_outcome(), Some(Err(ProfileError::Busy))));
+        assert!(take_outcome().is_none());
+    }
+
+    #[cfg(not(feature = "profile"))]
+    #[test]
+    fn test_unsupported() {
+        assert!(matches!(
+            ProfileSession::start(ProfileFormat::Pprof),
+            Err(ProfileError::Unsupported)
+        ));
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serve metrics in Prometheus text format over plain HTTP, to be scraped without a sidecar
+//! translating the JSON API.
+//!
+//! Only `GET /metrics` is served and nothing can be changed through the listener, so it's
+//! not authenticated like the admin API over TLS.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Time allowed for a scraper to send its request or to receive the response.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests of scrapers are tiny, anything longer is rejected.
+const MAX_REQUEST_SIZE: usize = 8192;
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Listen on `address`, e.g. `0.0.0.0:9100`, and respond scrapes with metrics from `render`.
+///
+/// Scrapes are served one by one in the listener thread, which runs until the process exits.
+pub fn start_prometheus_thread<F>(
+    address: &str,
+    render: F,
+) -> Result<thread::JoinHandle<Result<()>>>
+where
+    F: Fn() -> String + Send + 'static,
+{
+    let listener = TcpListener::bind(address)?;
+
+    thread::Builder::new()
+        .name("prometheus".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("failed to accept scrape connection, {}", e);
+                        continue;
+                    }
+                };
+                let peer = stream.peer_addr().ok();
+                serve_scrape(stream, &render)
+                    .unwrap_or_else(|e| warn!("scrape from {:?} failed, {}", peer, e));
+            }
+            Ok(())
+        })
+}
+
+fn serve_scrape<F: Fn() -> String>(mut stream: TcpStream, render: &F) -> Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        if req.len() > MAX_REQUEST_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "request is too long"));
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        req.extend_from_slice(&buf[..n]);
+    }
+
+    let (status, body) = match request_target(&req) {
+        Some(("GET", "/metrics")) => ("200 OK", render()),
+        Some(("GET", _)) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+
+    stream.write_all(resp.as_bytes())
+}
+
+/// Get method and path of a request, with the query string stripped.
+fn request_target(req: &[u8]) -> Option<(&str, &str)> {
+    let line = std::str::from_utf8(req).ok()?.lines().next()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?;
+    let path = parts.next()?.splitn(2, '?').next()?;
+
+    Some((method, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_target() {
+        assert_eq!(
+            request_target(b"GET /metrics HTTP/1.1\r\nHost: node\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(
+            request_target(b"GET /metrics?name[]=x HTTP/1.1\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(
+            request_target(b"POST /metrics HTTP/1.1\r\n\r\n"),
+            Some(("POST", "/metrics"))
+        );
+        assert_eq!(request_target(b"\r\n\r\n"), None);
+        assert_eq!(request_target(&[0xff, 0xfe]), None);
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Push metrics to statsd or a Prometheus pushgateway periodically, for short-lived daemons
+//! which can't be scraped.
+//!
+//! Metrics are rendered in Prometheus text format, like they're served by the prometheus
+//! listener, and sent as is to a pushgateway. For statsd they're translated to gauges, with
+//! label values joined to metric names and histogram buckets left out.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Time allowed to connect to a pushgateway and to send metrics to it.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Keep statsd packets under a common MTU, so that they are not fragmented.
+const MAX_STATSD_PACKET: usize = 1432;
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+fn default_job() -> String {
+    "nydusd".to_string()
+}
+
+fn default_prefix() -> String {
+    "nydus".to_string()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PushKind {
+    Statsd,
+    Pushgateway,
+}
+
+/// `metrics_push` of the daemon configuration.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MetricsPushConfig {
+    #[serde(rename = "type")]
+    pub kind: PushKind,
+    /// `host:port` of statsd or the pushgateway.
+    pub address: String,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Job grouping metrics in the pushgateway.
+    #[serde(default = "default_job")]
+    pub job: String,
+    /// Instance grouping metrics in the pushgateway, the host name if not given.
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Prefix of statsd metric names.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+/// Pushes metrics in background until stopped.
+pub struct MetricsPusher {
+    // set to stop the pusher, which wakes up on the condition variable
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl MetricsPusher {
+    /// Push metrics from `render` every `interval_secs` of `config`.
+    pub fn start<F>(config: MetricsPushConfig, render: F) -> Result<Self>
+    where
+        F: Fn() -> String + Send + 'static,
+    {
+        if config.interval_secs == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "metrics push interval must be positive",
+            ));
+        }
+        let sink = Sink::new(&config)?;
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let stopped = stop.clone();
+        let interval = Duration::from_secs(config.interval_secs);
+
+        let thread = thread::Builder::new()
+            .name("metrics-push".to_string())
+            .spawn(move || {
+                let mut failing = false;
+                loop {
+                    let (lock, cvar) = &*stopped;
+                    let done = *cvar
+                        .wait_timeout_while(lock.lock().unwrap(), interval, |done| !*done)
+                        .unwrap()
+                        .0;
+                    // Metrics are pushed once more when stopped, to have the final values.
+                    match sink.push(&render()) {
+                        Ok(_) if failing => {
+                            info!("metrics pushed to {} again", config.address);
+                            failing = false;
+                        }
+                        Ok(_) => {}
+                        // Only the first failure is logged, the endpoint may be away for long.
+                        Err(e) if !failing => {
+                            warn!("failed to push metrics to {}, {}", config.address, e);
+                            failing = true;
+                        }
+                        Err(_) => {}
+                    }
+                    if done {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(MetricsPusher { stop, thread })
+    }
+
+    /// Push metrics for the last time and wait for the pusher to exit.
+    pub fn stop(self) {
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+        if self.thread.join().is_err() {
+            error!("metrics pusher panicked");
+        }
+    }
+}
+
+enum Sink {
+    Statsd { sock: UdpSocket, prefix: String },
+    Pushgateway { address: String, path: String },
+}
+
+impl Sink {
+    fn new(config: &MetricsPushConfig) -> Result<Self> {
+        match config.kind {
+            PushKind::Statsd => {
+                let sock = UdpSocket::bind("0.0.0.0:0")?;
+                sock.connect(&config.address)?;
+                Ok(Sink::Statsd {
+                    sock,
+                    prefix: config.prefix.clone(),
+                })
+            }
+            PushKind::Pushgateway => {
+                let instance = match config.instance.as_ref() {
+                    Some(i) => i.clone(),
+                    None => hostname()?,
+                };
+                for v in &[&config.job, &instance] {
+                    if v.is_empty() || v.contains('/') {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid pushgateway grouping {:?}", v),
+                        ));
+                    }
+                }
+                Ok(Sink::Pushgateway {
+                    address: config.address.clone(),
+                    path: format!("/metrics/job/{}/instance/{}", config.job, instance),
+                })
+            }
+        }
+    }
+
+    fn push(&self, text: &str) -> Result<()> {
+        match self {
+            Sink::Statsd { sock, prefix } => {
+                for packet in statsd_packets(text, prefix) {
+                    sock.send(packet.as_bytes())?;
+                }
+                Ok(())
+            }
+            Sink::Pushgateway { address, path } => push_gateway(address, path, text),
+        }
+    }
+}
+
+fn hostname() -> Result<String> {
+    let mut buf = [0u8; 256];
+    let name = nix::unistd::gethostname(&mut buf)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("failed to get hostname, {}", e)))?;
+    Ok(name.to_string_lossy().to_string())
+}
+
+/// Replace metrics of the group at `path` with `text`.
+fn push_gateway(address: &str, path: &str, text: &str) -> Result<()> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address resolved"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let req = format!(
+        "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        address,
+        CONTENT_TYPE,
+        text.len(),
+        text
+    );
+    stream.write_all(req.as_bytes())?;
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp)?;
+    let status = resp.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("pushgateway responded {:?}", resp.lines().next()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Keep characters allowed in statsd metric names, others are replaced by `_`.
+fn statsd_name(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Label values of a sample like `name{a="x",b="y"}`, in order.
+fn label_values(labels: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut chars = labels.chars();
+    while let Some(c) = chars.next() {
+        if c != '"' {
+            continue;
+        }
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => value.extend(chars.next()),
+                '"' => break,
+                c => value.push(c),
+            }
+        }
+        values.push(value);
+    }
+    values
+}
+
+/// Translate samples of Prometheus text to statsd gauges, batched into packets.
+fn statsd_packets(text: &str, prefix: &str) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (sample, value) = match line.rfind(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => continue,
+        };
+        let (name, labels) = match sample.find('{') {
+            Some(i) => (&sample[..i], &sample[i..]),
+            None => (sample, ""),
+        };
+        if name.ends_with("_bucket") {
+            continue;
+        }
+
+        let mut metric = format!("{}.{}", prefix, statsd_name(name));
+        for v in label_values(labels) {
+            metric.push('.');
+            metric.push_str(&statsd_name(&v));
+        }
+        let gauge = format!("{}:{}|g", metric, value);
+
+        if !packet.is_empty() && packet.len() + 1 + gauge.len() > MAX_STATSD_PACKET {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&gauge);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statsd_packets() {
+        let text = "# HELP nydusd_mounts Filesystems mounted by the daemon.\n\
+                    # TYPE nydusd_mounts gauge\n\
+                    nydusd_mounts 2\n\
+                    nydusd_state{state=\"RUNNING\"} 1\n\
+                    nydus_fop_total{mountpoint=\"/sub\",fop=\"read\"} 42\n\
+                    nydus_read_latency_seconds_bucket{mountpoint=\"/sub\",le=\"0.001\"} 3\n\
+                    nydus_read_latency_seconds_sum{mountpoint=\"/a \\\"b\\\"\"} 0.5\n";
+        let packets = statsd_packets(text, "nydus");
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0].lines().collect::<Vec<_>>(),
+            vec![
+                "nydus.nydusd_mounts:2|g",
+                "nydus.nydusd_state.RUNNING:1|g",
+                "nydus.nydus_fop_total._sub.read:42|g",
+                "nydus.nydus_read_latency_seconds_sum._a__b_:0.5|g",
+            ]
+        );
+
+        let text = (0..200)
+            .map(|i| format!("metric_{} {}\n", i, i))
+            .collect::<String>();
+        let packets = statsd_packets(&text, "nydus");
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|p| p.len() <= MAX_STATSD_PACKET));
+        assert_eq!(
+            packets.iter().map(|p| p.lines().count()).sum::<usize>(),
+            200
+        );
+    }
+
+    #[test]
+    fn test_push_config() {
+        let config: MetricsPushConfig =
+            serde_json::from_str(r#"{"type": "pushgateway", "address": "localhost:9091"}"#)
+                .unwrap();
+        assert_eq!(config.kind, PushKind::Pushgateway);
+        assert_eq!(config.interval_secs, 10);
+        assert_eq!(config.job, "nydusd");
+
+        let mut bad = config.clone();
+        bad.instance = Some("a/b".to_string());
+        assert!(Sink::new(&bad).is_err());
+        bad.instance = None;
+        bad.interval_secs = 0;
+        assert!(MetricsPusher::start(bad, String::new).is_err());
+    }
+
+    #[test]
+    fn test_push_statsd() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = MetricsPushConfig {
+            kind: PushKind::Statsd,
+            address: server.local_addr().unwrap().to_string(),
+            interval_secs: 3600,
+            job: default_job(),
+            instance: None,
+            prefix: "test".to_string(),
+        };
+        let pusher = MetricsPusher::start(config, || "nydusd_mounts 1\n".to_string()).unwrap();
+        pusher.stop();
+
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"test.nydusd_mounts:1|g");
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Connections of the API socket.
+//!
+//! micro_http doesn't tell which connection a request comes from, so connections are accepted
+//! and read here, only requests are parsed by micro_http. Who is on the other side is captured
+//! when a connection is accepted, and comes along with each request read from it.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use micro_http::{Request, Response, StatusCode, Version};
+
+use crate::audit::{self, AuditPeer};
+
+/// Concurrent connections allowed, others are closed right after accepted.
+const MAX_CONNECTIONS: usize = 64;
+/// Requests larger than this are refused, and the connection is closed.
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+/// Time allowed for a client to take a response.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const EVENT_LISTENER: u64 = 0;
+
+/// A request and who sent it.
+pub struct ServerRequest {
+    pub request: Request,
+    /// Credentials of the client, or `None` if they can't be told.
+    pub peer: Option<AuditPeer>,
+    connection: u64,
+}
+
+struct Connection {
+    stream: UnixStream,
+    peer: Option<AuditPeer>,
+    /// Bytes read but not yet parsed into a request.
+    buf: Vec<u8>,
+}
+
+/// Serve HTTP requests on a unix domain socket.
+pub struct ApiServer {
+    listener: UnixListener,
+    epoll_fd: RawFd,
+    connections: HashMap<u64, Connection>,
+    next_connection: u64,
+}
+
+impl ApiServer {
+    pub fn bind(path: &Path) -> Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let server = ApiServer {
+            epoll_fd: epoll::create(true)?,
+            listener,
+            connections: HashMap::new(),
+            next_connection: EVENT_LISTENER + 1,
+        };
+        server.watch(server.listener.as_raw_fd(), EVENT_LISTENER)?;
+
+        Ok(server)
+    }
+
+    /// An epoll fd which gets readable when there are connections or requests pending.
+    pub fn epoll_fd(&self) -> RawFd {
+        self.epoll_fd
+    }
+
+    /// Accept pending connections and read requests pending, without blocking.
+    pub fn requests(&mut self) -> Result<Vec<ServerRequest>> {
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 32];
+        let num = epoll::wait(self.epoll_fd, 0, events.as_mut_slice())?;
+        let mut requests = Vec::new();
+
+        for event in &events[..num] {
+            match event.data {
+                EVENT_LISTENER => self.accept(),
+                id => self.read(id, &mut requests),
+            }
+        }
+
+        Ok(requests)
+    }
+
+    /// Send `response` to the client of `request`, nothing is sent if it's gone.
+    pub fn respond(&mut self, request: ServerRequest, response: Response) {
+        let id = request.connection;
+        if let Some(conn) = self.connections.get_mut(&id) {
+            if let Err(e) = response.write_all(&mut conn.stream) {
+                warn!("failed to respond API client {:?}, {:?}", conn.peer, e);
+                self.close(id);
+            }
+        }
+    }
+
+    fn watch(&self, fd: RawFd, data: u64) -> Result<()> {
+        epoll::ctl(
+            self.epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, data),
+        )
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("failed to accept API connection, {}", e);
+                    return;
+                }
+            };
+            if self.connections.len() >= MAX_CONNECTIONS {
+                warn!("too many API connections, dropping one");
+                continue;
+            }
+
+            // Reads only happen when the connection is readable, so they don't block.
+            let id = self.next_connection;
+            let setup = stream
+                .set_write_timeout(Some(WRITE_TIMEOUT))
+                .and_then(|_| self.watch(stream.as_raw_fd(), id));
+            if let Err(e) = setup {
+                warn!("failed to set up API connection, {}", e);
+                continue;
+            }
+            self.next_connection += 1;
+            self.connections.insert(
+                id,
+                Connection {
+                    peer: audit::peer_of(&stream),
+                    stream,
+                    buf: Vec::new(),
+                },
+            );
+        }
+    }
+
+    fn read(&mut self, id: u64, requests: &mut Vec<ServerRequest>) {
+        let conn = match self.connections.get_mut(&id) {
+            Some(c) => c,
+            None => return,
+        };
+
+        let mut chunk = [0u8; 4096];
+        let mut closed = match conn.stream.read(&mut chunk) {
+            Ok(0) => true,
+            Ok(n) => {
+                conn.buf.extend_from_slice(&chunk[..n]);
+                false
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => false,
+            Err(e) => {
+                warn!("failed to read API connection, {}", e);
+                true
+            }
+        };
+
+        // Clients may send several requests without waiting for responses.
+        loop {
+            let parsed = match request_size(&conn.buf) {
+                Ok(Some(size)) => {
+                    let parsed = Request::try_from(&conn.buf[..size]);
+                    conn.buf.drain(..size);
+                    parsed.map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}", e)))
+                }
+                Ok(None) => break,
+                Err(e) => Err(e),
+            };
+            match parsed {
+                Ok(request) => requests.push(ServerRequest {
+                    request,
+                    peer: conn.peer.clone(),
+                    connection: id,
+                }),
+                Err(e) => {
+                    warn!("invalid API request from {:?}, {}", conn.peer, e);
+                    Response::new(Version::Http11, StatusCode::BadRequest)
+                        .write_all(&mut conn.stream)
+                        .unwrap_or_else(|e| warn!("failed to respond API client, {:?}", e));
+                    closed = true;
+                    break;
+                }
+            }
+        }
+
+        if closed {
+            self.close(id);
+        }
+    }
+
+    fn close(&mut self, id: u64) {
+        // Closing the stream removes it from the epoll set too.
+        self.connections.remove(&id);
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.epoll_fd);
+    }
+}
+
+/// Size of the first request in `buf`, `None` if it's not complete yet.
+fn request_size(buf: &[u8]) -> Result<Option<usize>> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let head_end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if buf.len() > MAX_REQUEST_SIZE => return Err(invalid("request too large")),
+        None => return Ok(None),
+    };
+
+    let head =
+        std::str::from_utf8(&buf[..head_end]).map_err(|_| invalid("invalid request header"))?;
+    let mut body_size = 0;
+    for line in head.lines().skip(1) {
+        let colon = match line.find(':') {
+            Some(c) => c,
+            None => continue,
+        };
+        let (name, value) = (line[..colon].trim(), line[colon + 1..].trim());
+        if name.eq_ignore_ascii_case("Content-Length") {
+            body_size = value
+                .parse::<usize>()
+                .map_err(|_| invalid("invalid content length"))?;
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            return Err(invalid("transfer encoding is not supported"));
+        }
+    }
+
+    let size = head_end + body_size;
+    if size > MAX_REQUEST_SIZE {
+        Err(invalid("request too large"))
+    } else if size > buf.len() {
+        Ok(None)
+    } else {
+        Ok(Some(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use micro_http::{Body, Method};
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::audit::TlsClientGuard;
+
+    #[test]
+    fn test_request_size() {
+        assert_eq!(
+            request_size(b"GET /api/v1/daemon HTTP/1.1\r\n").unwrap(),
+            None
+        );
+        let get = b"GET /api/v1/daemon HTTP/1.1\r\n\r\n";
+        assert_eq!(request_size(get).unwrap(), Some(get.len()));
+
+        let put = b"PUT /api/v1/daemon/exit HTTP/1.1\r\ncontent-length: 2\r\n\r\n{}";
+        assert_eq!(request_size(&put[..put.len() - 1]).unwrap(), None);
+        assert_eq!(request_size(put).unwrap(), Some(put.len()));
+        let mut two = put.to_vec();
+        two.extend_from_slice(get);
+        assert_eq!(request_size(&two).unwrap(), Some(put.len()));
+
+        assert!(request_size(b"PUT / HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_err());
+        assert!(request_size(b"PUT / HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n").is_err());
+        assert!(request_size(b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_request_peer() {
+        let dir = TempDir::new().unwrap();
+        let sock = dir.as_path().join("api.sock");
+        let mut server = ApiServer::bind(&sock).unwrap();
+
+        // A request sent in pieces comes in one piece, along with its sender.
+        let mut client = UnixStream::connect(&sock).unwrap();
+        assert!(server.requests().unwrap().is_empty());
+        client
+            .write_all(b"PUT /api/v1/daemon/exit HTTP/1.1\r\nContent-Length: 2\r\n\r\n{")
+            .unwrap();
+        assert!(server.requests().unwrap().is_empty());
+        client.write_all(b"}").unwrap();
+        let mut requests = server.requests().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = requests.pop().unwrap();
+        assert_eq!(request.request.method(), Method::Put);
+        assert_eq!(request.request.body, Some(Body::new(b"{}".to_vec())));
+        assert!(matches!(
+            request.peer,
+            Some(AuditPeer::Unix { pid, .. }) if pid as u32 == std::process::id()
+        ));
+
+        let mut response = Response::new(Version::Http11, StatusCode::NoContent);
+        response.set_body(Body::new(b"done".to_vec()));
+        server.respond(request, response);
+        let mut buf = [0u8; 64];
+        let n = client.read(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 204"));
+        assert!(buf[..n].ends_with(b"done"));
+
+        // Connections relayed for a TLS client are attributed to the client.
+        let guard = TlsClientGuard::new("CN=ops".to_string(), "10.0.0.1:40000".to_string());
+        let mut relay = guard.connect(&sock).unwrap();
+        assert!(server.requests().unwrap().is_empty());
+        relay
+            .write_all(b"POST /api/v1/mount HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut requests = server.requests().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = requests.pop().unwrap();
+        assert_eq!(
+            request.peer,
+            Some(AuditPeer::Tls {
+                subject: "CN=ops".to_string(),
+                address: "10.0.0.1:40000".to_string(),
+            })
+        );
+
+        // Garbage is refused and the connection is closed.
+        client.write_all(b"\x00\x01\r\n\r\n").unwrap();
+        assert!(server.requests().unwrap().is_empty());
+        let n = client.read(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 400"));
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+}
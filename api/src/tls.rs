@@ -0,0 +1,223 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serve the admin API on a TCP address over TLS, for management planes off the node.
+//!
+//! The API server only serves unix domain sockets, so TLS connections are terminated here and
+//! relayed to the API socket. Clients must present a certificate signed by the configured CA.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::X509Ref;
+
+use crate::audit::TlsClientGuard;
+
+/// Concurrent TLS connections allowed, others are closed right after accepted.
+const MAX_CONNECTIONS: usize = 16;
+/// Time allowed for a client to finish TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait on one side of a connection before checking the other side.
+const RELAY_INTERVAL: Duration = Duration::from_millis(20);
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct TlsListenerConfig {
+    /// Address to listen on, e.g. `0.0.0.0:8443`.
+    pub address: String,
+    /// PEM certificate chain of the server.
+    pub cert: PathBuf,
+    /// PEM private key of the server.
+    pub key: PathBuf,
+    /// PEM CA certificates to verify client certificates.
+    pub client_ca: PathBuf,
+}
+
+fn new_acceptor(config: &TlsListenerConfig) -> Result<SslAcceptor> {
+    let ssl_err =
+        |e: ErrorStack| Error::new(ErrorKind::Other, format!("invalid TLS setting, {}", e));
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(ssl_err)?;
+
+    builder
+        .set_certificate_chain_file(&config.cert)
+        .map_err(ssl_err)?;
+    builder
+        .set_private_key_file(&config.key, SslFiletype::PEM)
+        .map_err(ssl_err)?;
+    builder.check_private_key().map_err(ssl_err)?;
+    builder.set_ca_file(&config.client_ca).map_err(ssl_err)?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+
+    Ok(builder.build())
+}
+
+/// Listen on `config.address` and relay requests of authenticated clients to the API server
+/// listening on `api_sock`.
+///
+/// Setup errors are returned right away, except that the address is in use. The listener
+/// thread runs until the process exits.
+pub fn start_tls_thread(
+    config: TlsListenerConfig,
+    api_sock: &str,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    let acceptor = Arc::new(new_acceptor(&config)?);
+    // The address is still held by the old daemon during live upgrade, keep trying in the
+    // listener thread then.
+    let mut listener = match TcpListener::bind(&config.address) {
+        Ok(l) => Some(l),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            warn!("{} is in use, retry later", config.address);
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    let api_sock = PathBuf::from(api_sock);
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    thread::Builder::new()
+        .name("api-tls".to_string())
+        .spawn(move || {
+            let listener = loop {
+                match listener.take() {
+                    Some(l) => break l,
+                    None => {
+                        thread::sleep(BIND_RETRY_INTERVAL);
+                        listener =
+                            TcpListener::bind(&config.address).map(Some).or_else(|e| {
+                                match e.kind() {
+                                    ErrorKind::AddrInUse => Ok(None),
+                                    _ => Err(e),
+                                }
+                            })?;
+                    }
+                }
+            };
+
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("failed to accept TLS connection, {}", e);
+                        continue;
+                    }
+                };
+                if connections.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::AcqRel);
+                    warn!("too many TLS connections, dropping one");
+                    continue;
+                }
+
+                let acceptor = acceptor.clone();
+                let api_sock = api_sock.clone();
+                let connections = connections.clone();
+                thread::Builder::new()
+                    .name("api-tls-conn".to_string())
+                    .spawn(move || {
+                        let peer = stream.peer_addr().ok();
+                        serve_connection(&acceptor, stream, &api_sock)
+                            .unwrap_or_else(|e| warn!("TLS connection {:?} failed, {}", peer, e));
+                        connections.fetch_sub(1, Ordering::AcqRel);
+                    })
+                    .map(|_| ())
+                    .unwrap_or_else(|e| {
+                        error!("failed to spawn TLS connection thread, {}", e);
+                    });
+            }
+            Ok(())
+        })
+}
+
+fn serve_connection(acceptor: &SslAcceptor, stream: TcpStream, api_sock: &Path) -> Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut tls = acceptor
+        .accept(stream)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("handshake failed, {}", e)))?;
+    // Requests relayed for the client are attributed to it in the audit log.
+    let client = TlsClientGuard::new(
+        tls.ssl()
+            .peer_certificate()
+            .map(|c| cert_subject(&c))
+            .unwrap_or_default(),
+        tls.get_ref()
+            .peer_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default(),
+    );
+    let mut api = client.connect(api_sock)?;
+
+    tls.get_ref().set_read_timeout(Some(RELAY_INTERVAL))?;
+    api.set_read_timeout(Some(RELAY_INTERVAL))?;
+    relay(&mut tls, &mut api)
+}
+
+/// Subject of a certificate, e.g. `CN=ops,O=example`.
+fn cert_subject(cert: &X509Ref) -> String {
+    cert.subject_name()
+        .entries()
+        .filter_map(|e| {
+            let name = e.object().nid().short_name().ok()?;
+            let value = e.data().as_utf8().ok()?;
+            Some(format!("{}={}", name, value))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Copy data between the client and the API server until either side closes.
+///
+/// SslStream can't be shared by two threads, so both sides are polled in turn with short
+/// read timeouts.
+fn relay(tls: &mut SslStream<TcpStream>, api: &mut UnixStream) -> Result<()> {
+    let mut buf = vec![0u8; 16384];
+
+    loop {
+        match tls.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => api.write_all(&buf[..n])?,
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+        match api.read(&mut buf) {
+            Ok(0) => {
+                // The client may have gone already, nothing to do if it fails.
+                let _ = tls.shutdown();
+                return Ok(());
+            }
+            Ok(n) => {
+                tls.write_all(&buf[..n])?;
+                tls.flush()?;
+            }
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_timeout(e: &Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_tls_setting() {
+        let config = TlsListenerConfig {
+            address: "127.0.0.1:0".to_string(),
+            cert: PathBuf::from("/no/such/cert.pem"),
+            key: PathBuf::from("/no/such/key.pem"),
+            client_ca: PathBuf::from("/no/such/ca.pem"),
+        };
+        assert!(new_acceptor(&config).is_err());
+        assert!(start_tls_thread(config, "/no/such/api.sock").is_err());
+    }
+}
@@ -7,30 +7,46 @@
 //! RAFS: a readonly FUSE file system designed for Cloud Native.
 
 use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
-use std::ffi::{CStr, OsStr};
+use std::ffi::{CStr, OsStr, OsString};
 use std::fmt;
 use std::io::Result;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use nix::unistd::{getegid, geteuid};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
 use fuse_rs::abi::linux_abi::Attr;
 use fuse_rs::api::filesystem::*;
 use fuse_rs::api::BackendFileSystem;
 
-use crate::metadata::{Inode, RafsInode, RafsSuper, RAFS_DEFAULT_BLOCK_SIZE};
+use crate::metadata::layout::XattrValue;
+use crate::metadata::{Inode, RafsInode, RafsSuper, RafsSuperInodes, RAFS_DEFAULT_BLOCK_SIZE};
+use crate::signature::{self, SignatureConfig, SignatureStatus};
+use crate::trace::AccessTrace;
 use crate::*;
+use nydus_utils::digest::RafsDigest;
 use nydus_utils::metrics::{self, FopRecorder, StatsFop::*};
-use storage::device::BlobPrefetchControl;
+use storage::cache::scheduler::IoPriority;
+use storage::crypt::{KeyConfig, KeyProvider, KmsConfig};
+use storage::device::{BlobPrefetchControl, RafsBio, RafsBioDesc, RafsChunkInfo, RafsReadVec};
+use storage::utils::hash_table_bytes;
 use storage::*;
-use storage::{cache::PrefetchWorker, device};
+use storage::{
+    backend::{BackendProbe, BlobBackend},
+    cache::{CacheMemoryUsage, CachedBlob, PrefetchWorker},
+    device,
+};
 
 /// Type of RAFS fuse handle.
 pub type Handle = u64;
@@ -40,8 +56,52 @@ pub const RAFS_DEFAULT_ATTR_TIMEOUT: u64 = 1 << 32;
 /// Rafs default entry timeout value.
 pub const RAFS_DEFAULT_ENTRY_TIMEOUT: u64 = RAFS_DEFAULT_ATTR_TIMEOUT;
 
+/// Max inode number of a rafs instance.
+///
+/// Rafs inode numbers are assigned by the image builder and stored in bootstrap, so they are
+/// identical whenever the same bootstrap gets mounted. The vfs multiplexing pseudo mounts
+/// takes the highest 8 bits of 64-bit inode numbers to tell filesystems apart, which must not
+/// be clobbered by rafs itself.
+pub const RAFS_MAX_INO: u64 = (1 << 56) - 1;
+
+/// Max number of cached negative lookup results, the cache gets reset once it's full.
+const NEGATIVE_CACHE_CAPACITY: usize = 4096;
+/// Atime older than this gets updated on access in relatime mode.
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// A mount is considered failing reads for this long since a read fails.
+const READ_FAILING_PERIOD: Duration = Duration::from_secs(60);
+/// Max number of inodes whose updated atime is kept in memory, the least recently accessed
+/// half of a shard is forgotten once it's full.
+const ATIMES_CAPACITY: usize = 65536;
+/// Max number of files whose chunks read are tracked for file digest verification, the least
+/// recently read half of a shard is forgotten once it's full.
+const FILE_DIGESTS_PENDING_CAPACITY: usize = 65536;
+/// Max number of inodes following the one being read to look for neighboring chunks.
+const AMPLIFY_IO_MAX_INODES: u64 = 256;
+// max number of files whose reads are tracked for merging
+const SEQUENTIAL_READS_CAPACITY: usize = 4096;
+/// Smallest readahead window, the default readahead of the kernel.
+const READAHEAD_MIN_SIZE: u64 = 0x20000;
+/// Max number of readahead fetches queued for the worker, more are dropped.
+const READAHEAD_QUEUE_DEPTH: usize = 64;
+/// Max number of threads prefetching files requested after mounted.
+const ONDEMAND_PREFETCH_THREADS: usize = 2;
+/// Max number of files queued for prefetch after mounted, requests beyond it are refused.
+const ONDEMAND_PREFETCH_QUEUE_DEPTH: usize = 4096;
+/// Max number of chunk buffers of a vectored read, `writev()` takes at most 1024 buffers
+/// including the one of FUSE reply header.
+const READ_VECTORED_MAX_BUFS: usize = 1023;
+
 const DOT: &str = ".";
 const DOTDOT: &str = "..";
+const OVERLAYFS_WHITEOUT_OPAQUE: &[u8] = b"trusted.overlay.opaque";
+/// Virtual xattr reporting file digest verification state, one of `pending`, `verified` and
+/// `failed`.
+const NYDUS_XATTR_FILE_DIGEST: &[u8] = b"user.nydus.file_digest";
+/// Virtual xattr listing ids of blobs backing a file, one per line.
+const NYDUS_XATTR_BLOB_ID: &[u8] = b"user.nydus.blob_id";
+/// Virtual xattr listing chunks of a file, one per line.
+const NYDUS_XATTR_CHUNKS: &[u8] = b"user.nydus.chunks";
 
 fn default_threads_count() -> usize {
     8
@@ -70,6 +130,40 @@ pub struct FsPrefetchControl {
     bandwidth_rate: u32,
 }
 
+/// Rules about which extended attribute namespaces are served to readers.
+///
+/// Some hardened container runtimes reject images leaking `trusted.*` xattrs, so they can be
+/// hidden entirely or from unprivileged readers only, while `allow` keeps specific ones like
+/// `security.capability` visible regardless of other rules.
+#[derive(Clone, Default, Deserialize)]
+pub struct XattrFilter {
+    /// Xattr name prefixes never served, e.g. "trusted." or "security.".
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Xattr name prefixes always served, they take precedence over any other rule.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Hide `trusted.*` xattrs from non-root readers, like what local filesystems do.
+    #[serde(default)]
+    pub hide_trusted_from_unprivileged: bool,
+}
+
+impl XattrFilter {
+    const TRUSTED_PREFIX: &'static [u8] = b"trusted.";
+
+    /// Check whether xattr `name` can be served to a reader with user id `uid`.
+    pub fn allows(&self, name: &[u8], uid: u32) -> bool {
+        if self.allow.iter().any(|p| name.starts_with(p.as_bytes())) {
+            return true;
+        }
+        if self.hide_trusted_from_unprivileged && uid != 0 && name.starts_with(Self::TRUSTED_PREFIX)
+        {
+            return false;
+        }
+        !self.deny.iter().any(|p| name.starts_with(p.as_bytes()))
+    }
+}
+
 /// Not everything can be safely exported from configuration.
 /// We trim the unneeded info from here.
 #[macro_export]
@@ -82,6 +176,158 @@ macro_rules! trim_backend_config {
     };
 }
 
+/// Id presented in place of ids which can't be mapped, same as the kernel's overflow id.
+const OVERFLOW_ID: u32 = 65534;
+
+/// A range of ids in image mapped to ids presented to users, like a line of
+/// `/proc/<pid>/uid_map`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct IdMapRange {
+    /// First id of the range in image.
+    pub inside: u32,
+    /// First id of the range presented to users.
+    pub outside: u32,
+    pub count: u32,
+}
+
+/// Uid/gid mapping of a mount, similar to idmapped mounts.
+///
+/// It allows presenting a root owned image as owned by an unprivileged user for rootless
+/// container runtimes, e.g. mapping uid 0 to 1000, or shifting the whole range of ids.
+/// Ids not covered by any range are presented as they are.
+///
+/// Ranges must not run past the end of u32 on either side, they're rejected on mount.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct IdMapping {
+    #[serde(default)]
+    pub uid: Vec<IdMapRange>,
+    #[serde(default)]
+    pub gid: Vec<IdMapRange>,
+}
+
+impl IdMapping {
+    fn map(ranges: &[IdMapRange], id: u32) -> u32 {
+        ranges
+            .iter()
+            .find(|r| id >= r.inside && id - r.inside < r.count)
+            .map(|r| r.outside.checked_add(id - r.inside).unwrap_or(OVERFLOW_ID))
+            .unwrap_or(id)
+    }
+
+    fn validate_ranges(kind: &str, ranges: &[IdMapRange]) -> RafsResult<()> {
+        for r in ranges {
+            let last = r.count.saturating_sub(1);
+            if r.inside.checked_add(last).is_none() || r.outside.checked_add(last).is_none() {
+                return Err(RafsError::Configure(format!(
+                    "{} mapping {:?} overflows",
+                    kind, r
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that no range runs past the largest id.
+    pub fn validate(&self) -> RafsResult<()> {
+        Self::validate_ranges("uid", &self.uid)?;
+        Self::validate_ranges("gid", &self.gid)
+    }
+
+    /// Get uid presented to users of uid `uid` in image.
+    pub fn map_uid(&self, uid: u32) -> u32 {
+        Self::map(&self.uid, uid)
+    }
+
+    /// Get gid presented to users of gid `gid` in image.
+    pub fn map_gid(&self, gid: u32) -> u32 {
+        Self::map(&self.gid, gid)
+    }
+}
+
+/// How access time of inodes gets updated.
+///
+/// Rafs is readonly so atime can't be persisted, it's either derived from the image or kept
+/// in memory of the mount.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AtimeMode {
+    /// Atime is never updated and always equals to the time of mount.
+    NoAtime,
+    /// Atime is updated on access if it's not later than mtime or ctime, or older than a day.
+    RelAtime,
+    /// Atime is updated on every access.
+    StrictAtime,
+}
+
+impl Default for AtimeMode {
+    fn default() -> Self {
+        AtimeMode::NoAtime
+    }
+}
+
+/// Progress of prefetching hinted files and blob ranges after mount.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrefetchStatus {
+    Disabled,
+    Running,
+    Done,
+}
+
+/// Approximate bytes of memory taken by a rafs instance, as per subsystem.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RafsMemoryUsage {
+    /// Bootstrap mapped in direct mode, or inodes loaded in cached mode.
+    pub metadata_bytes: usize,
+    /// Chunk infos loaded in cached mode.
+    pub chunk_info_bytes: usize,
+    /// Negative lookup results, file digest states and digests pinned to enforce integrity.
+    pub inode_cache_bytes: usize,
+    #[serde(flatten)]
+    pub cache: CacheMemoryUsage,
+    pub total_bytes: usize,
+}
+
+/// Number of chunks imported into blobcache from a downloaded blob file.
+#[derive(Clone, Debug, Serialize)]
+pub struct ImportedBlob {
+    pub blob_id: String,
+    pub path: PathBuf,
+    pub chunks: usize,
+}
+
+/// Result of a check done by `Rafs::validate()`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RafsCheck {
+    /// What's checked, `config`, `bootstrap`, `prefetch_files` or `backend`.
+    pub name: String,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+/// Whether a rafs can be mounted, with results of checks done in order. Checks after a failed
+/// one are skipped.
+#[derive(Debug, Default, Serialize)]
+pub struct RafsValidation {
+    pub valid: bool,
+    pub checks: Vec<RafsCheck>,
+    /// Result of probing storage backend by the first blob of the image.
+    pub backend_probe: Option<BackendProbe>,
+}
+
+impl RafsValidation {
+    fn check(&mut self, name: &str, error: Option<String>) -> bool {
+        let passed = error.is_none();
+        self.checks.push(RafsCheck {
+            name: name.to_string(),
+            passed,
+            error,
+        });
+        self.valid = self.checks.iter().all(|c| c.passed);
+        passed
+    }
+}
+
 /// Rafs storage backend configuration information.
 #[derive(Clone, Default, Deserialize)]
 pub struct RafsConfig {
@@ -89,6 +335,14 @@ pub struct RafsConfig {
     pub mode: String,
     #[serde(default)]
     pub digest_validate: bool,
+    /// Verify digest of the whole file once all of its chunks have been read.
+    #[serde(default)]
+    pub digest_validate_file: bool,
+    /// Verify the digest tree from the root down as directories are accessed, and only return
+    /// data of chunks proven to belong to the file being read, validated against chunk digests
+    /// on every read from cache.
+    #[serde(default)]
+    pub enforce_integrity: bool,
     #[serde(default)]
     pub iostats_files: bool,
     #[serde(default)]
@@ -96,39 +350,923 @@ pub struct RafsConfig {
     #[serde(default)]
     pub enable_xattr: bool,
     #[serde(default)]
+    pub xattr_filter: XattrFilter,
+    #[serde(default)]
+    pub id_mapping: IdMapping,
+    #[serde(default)]
+    pub atime: AtimeMode,
+    /// Hide overlayfs whiteouts and opaque xattrs when the bootstrap is not used as an
+    /// overlayfs lower layer.
+    #[serde(default)]
+    pub flatten_whiteouts: bool,
+    /// Max number of inodes kept in memory in cached mode, others are reloaded from bootstrap
+    /// on demand. 0 means keeping all of them.
+    #[serde(default)]
+    pub max_cached_inodes: usize,
+    /// Max number of chunk infos kept in memory in cached mode, others are reloaded from
+    /// bootstrap on demand. 0 means keeping all of them.
+    #[serde(default)]
+    pub max_cached_chunks: usize,
+    /// Fetch neighboring chunks in blob along with small reads until this many bytes in a
+    /// single backend request. 0 disables it.
+    #[serde(default)]
+    pub amplify_io: u32,
+    /// Fetch chunks ahead of a read adjacent to the previous one of the same file, until this
+    /// many bytes in a single backend request. 0 disables it.
+    #[serde(default)]
+    pub read_merging_size: u32,
+    /// Max size of the window fetched in background ahead of sequential reads of a file
+    /// handle. The window grows as the handle keeps reading sequentially and shrinks on random
+    /// reads. 0 disables it.
+    #[serde(default)]
+    pub readahead_size: u32,
+    #[serde(default)]
     pub access_pattern: bool,
     #[serde(default)]
     pub latest_read_files: bool,
+    /// Record every read to this file, to be replayed by `nydus-replay`.
+    #[serde(default)]
+    pub access_trace: Option<PathBuf>,
+    /// Refuse to mount bootstraps without a valid signature.
+    #[serde(default)]
+    pub signature: Option<SignatureConfig>,
+    /// Key of encrypted blobs, in a file or the kernel keyring.
+    #[serde(default)]
+    pub encryption_key: Option<KeyConfig>,
+    /// KMS to fetch keys of encrypted blobs from, instead of `encryption_key`.
+    #[serde(default)]
+    pub encryption: Option<KmsConfig>,
+}
+
+impl FromStr for RafsConfig {
+    type Err = RafsError;
+
+    fn from_str(s: &str) -> RafsResult<RafsConfig> {
+        serde_json::from_str(s).map_err(RafsError::ParseConfig)
+    }
 }
 
-impl FromStr for RafsConfig {
-    type Err = RafsError;
+impl RafsConfig {
+    pub fn new() -> RafsConfig {
+        RafsConfig {
+            ..Default::default()
+        }
+    }
+
+    pub fn from_file(path: &str) -> RafsResult<RafsConfig> {
+        let file = File::open(path).map_err(RafsError::LoadConfig)?;
+        serde_json::from_reader::<File, RafsConfig>(file).map_err(RafsError::ParseConfig)
+    }
+
+    /// Set up the keys to decrypt blobs with, None if not configured.
+    pub fn key_provider(&self) -> RafsResult<Option<Arc<dyn KeyProvider>>> {
+        let provider = match (self.encryption.clone(), self.encryption_key.clone()) {
+            (Some(_), Some(_)) => {
+                return Err(RafsError::Configure(
+                    "only one of `encryption` and `encryption_key` can be configured".to_string(),
+                ))
+            }
+            (Some(kms), None) => factory::new_key_provider(kms),
+            (None, Some(key)) => factory::new_local_key(key),
+            (None, None) => return Ok(None),
+        };
+        provider
+            .map(Some)
+            .map_err(|e| RafsError::Configure(format!("failed to load blob key, {}", e)))
+    }
+}
+
+impl fmt::Display for RafsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mode={} digest_validate={} iostats_files={} latest_read_files={}",
+            self.mode, self.digest_validate, self.iostats_files, self.latest_read_files
+        )
+    }
+}
+
+/// Number of shards of maps indexed by inode or handle, must be a power of 2.
+const MAP_SHARDS: usize = 32;
+
+/// Hash map indexed by inode or handle numbers, split into shards protected by their own locks.
+///
+/// Parallel metadata scans like `find` or `ls -R` hit per-inode states from many threads at the
+/// same time, sharding by the number, which is mostly sequential, spreads them over locks.
+struct ShardedMap<V> {
+    shards: Vec<RwLock<HashMap<u64, V>>>,
+}
+
+impl<V> Default for ShardedMap<V> {
+    fn default() -> Self {
+        ShardedMap {
+            shards: (0..MAP_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+        }
+    }
+}
+
+impl<V> ShardedMap<V> {
+    fn shard(&self, key: u64) -> &RwLock<HashMap<u64, V>> {
+        &self.shards[key as usize & (MAP_SHARDS - 1)]
+    }
+
+    /// Lock the shard holding `key` for read.
+    fn read(&self, key: u64) -> RwLockReadGuard<HashMap<u64, V>> {
+        self.shard(key).read().unwrap()
+    }
+
+    /// Lock the shard holding `key` for write.
+    fn write(&self, key: u64) -> RwLockWriteGuard<HashMap<u64, V>> {
+        self.shard(key).write().unwrap()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.read().unwrap().is_empty())
+    }
+
+    fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    /// Approximate bytes of memory taken by the map, `value_bytes` tells heap memory owned by
+    /// a value.
+    fn memory_usage<F: Fn(&V) -> usize>(&self, value_bytes: F) -> usize {
+        self.shards
+            .iter()
+            .map(|s| {
+                let shard = s.read().unwrap();
+                hash_table_bytes::<(u64, V)>(shard.capacity())
+                    + shard.values().map(|v| value_bytes(v)).sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+/// Cache of names known to be absent from directories.
+///
+/// Probing nonexistent paths, e.g. library search paths, is very common. The kernel caches
+/// negative dentries too, but they could be dropped under memory pressure or bypassed by
+/// different mountpoints, so remember the result to avoid walking metadata again.
+/// Entries expire after `entry_timeout` like positive entries.
+#[derive(Default)]
+struct NegativeCache {
+    entries: ShardedMap<HashMap<OsString, Option<Instant>>>,
+    count: AtomicUsize,
+}
+
+impl NegativeCache {
+    fn contains(&self, parent: Inode, name: &OsStr) -> bool {
+        let entries = self.entries.read(parent);
+        match entries.get(&parent).and_then(|names| names.get(name)) {
+            Some(Some(expire)) => Instant::now() < *expire,
+            // Never expires.
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    fn insert(&self, parent: Inode, name: &OsStr, timeout: Duration) {
+        if self.count.load(Ordering::Relaxed) >= NEGATIVE_CACHE_CAPACITY {
+            self.clear();
+        }
+        let mut entries = self.entries.write(parent);
+        let expire = Instant::now().checked_add(timeout);
+        if entries
+            .entry(parent)
+            .or_insert_with(HashMap::new)
+            .insert(name.to_os_string(), expire)
+            .is_none()
+        {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn clear(&self) {
+        self.entries.clear();
+        self.count.store(0, Ordering::Relaxed);
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.entries.memory_usage(|names| {
+            hash_table_bytes::<(OsString, Option<Instant>)>(names.capacity())
+                + names.keys().map(|n| n.len()).sum::<usize>()
+        })
+    }
+}
+
+enum FileDigestState {
+    Verified,
+    Failed,
+}
+
+/// Chunks read so far of a file not verified yet.
+struct PendingDigest {
+    // file offsets of chunks read
+    chunks: HashSet<u64>,
+    last_read: Instant,
+}
+
+/// Whole file digest verification.
+///
+/// Chunk data is validated against chunk digests when read from storage, and the file digest
+/// is calculated over all chunk digests of the file. So verifying the file digest once every
+/// chunk of the file has been read proves the file content matches the one built into image.
+///
+/// Chunks read of at most `FILE_DIGESTS_PENDING_CAPACITY` files are tracked, a file forgotten
+/// gets verified only once all of its chunks are read again.
+#[derive(Default)]
+struct FileDigests {
+    states: ShardedMap<FileDigestState>,
+    pending: ShardedMap<PendingDigest>,
+}
+
+impl FileDigests {
+    fn memory_usage(&self) -> usize {
+        self.states.memory_usage(|_| 0)
+            + self
+                .pending
+                .memory_usage(|p| hash_table_bytes::<u64>(p.chunks.capacity()))
+    }
+
+    fn check(&self, ino: Inode) -> Result<()> {
+        match self.states.read(ino).get(&ino) {
+            Some(FileDigestState::Failed) => Err(eio!("file digest mismatch")),
+            _ => Ok(()),
+        }
+    }
+
+    fn state(&self, ino: Inode) -> &'static str {
+        match self.states.read(ino).get(&ino) {
+            Some(FileDigestState::Verified) => "verified",
+            Some(FileDigestState::Failed) => "failed",
+            _ => "pending",
+        }
+    }
+
+    /// Record chunks successfully read from `inode`, verifying the file digest when the
+    /// last one comes in.
+    fn record(
+        &self,
+        sb: &RafsSuper,
+        inode: &Arc<dyn RafsInode>,
+        chunks: impl Iterator<Item = u64>,
+    ) -> Result<()> {
+        let ino = inode.ino();
+        let mut states = self.states.write(ino);
+        match states.get(&ino) {
+            Some(FileDigestState::Verified) => return Ok(()),
+            Some(FileDigestState::Failed) => return Err(eio!("file digest mismatch")),
+            None => {}
+        }
+        if !self.track(ino, chunks, inode.get_child_count() as usize) {
+            return Ok(());
+        }
+
+        if sb
+            .inodes
+            .digest_validate(inode.clone(), false, sb.meta.get_digester())?
+        {
+            states.insert(ino, FileDigestState::Verified);
+            Ok(())
+        } else {
+            states.insert(ino, FileDigestState::Failed);
+            Err(eio!("file digest mismatch"))
+        }
+    }
+
+    /// Add `chunks` to those read of file `ino`, return true and stop tracking it once all of
+    /// its `count` chunks are read.
+    fn track(&self, ino: Inode, chunks: impl Iterator<Item = u64>, count: usize) -> bool {
+        let mut pending = self.pending.write(ino);
+        if pending.len() >= FILE_DIGESTS_PENDING_CAPACITY / MAP_SHARDS
+            && !pending.contains_key(&ino)
+        {
+            let mut times: Vec<Instant> = pending.values().map(|p| p.last_read).collect();
+            times.sort_unstable();
+            let median = times[times.len() / 2];
+            pending.retain(|_, p| p.last_read > median);
+        }
+
+        let now = Instant::now();
+        let file = pending.entry(ino).or_insert_with(|| PendingDigest {
+            chunks: HashSet::new(),
+            last_read: now,
+        });
+        file.chunks.extend(chunks);
+        file.last_read = now;
+        if file.chunks.len() < count {
+            return false;
+        }
+        pending.remove(&ino);
+        true
+    }
+
+    fn clear(&self) {
+        self.states.clear();
+        self.pending.clear();
+    }
+}
+
+enum Integrity {
+    // digest pinned by the verified parent directory, or the root digest
+    Pinned(RafsDigest),
+    // directory whose children have their digests pinned
+    Verified,
+    // chunk digests of a regular file proven to make up its pinned digest
+    Proven(HashSet<RafsDigest>),
+}
+
+/// Chunk digests anchored to the root of the digest tree.
+///
+/// The digest of a directory covers digests of its children, and the digest of a regular file
+/// covers digests of its chunks, like a Merkle tree. Only the root digest is pinned on load, a
+/// directory is verified against its pinned digest on the first lookup in it, or when a file
+/// under it is read, pinning digests of its children in turn. Chunk digests of a file are
+/// proven against its pinned digest on the first read, after that only chunks of the proven
+/// ones may be read, whose data is in turn validated against the digest on every read from
+/// cache or backend.
+#[derive(Default)]
+struct IntegrityTree {
+    entries: ShardedMap<Integrity>,
+}
+
+impl IntegrityTree {
+    fn memory_usage(&self) -> usize {
+        self.entries.memory_usage(|e| match e {
+            Integrity::Proven(chunks) => hash_table_bytes::<RafsDigest>(chunks.capacity()),
+            _ => 0,
+        })
+    }
+
+    /// Pin the root digest of `sb`, forgetting digests pinned from the previous bootstrap.
+    fn load(&self, sb: &RafsSuper) -> Result<()> {
+        let root = sb.get_inode(ROOT_ID, false)?;
+        self.pin_root(root.get_digest());
+        Ok(())
+    }
+
+    fn pin_root(&self, digest: RafsDigest) {
+        self.entries.clear();
+        self.entries
+            .write(ROOT_ID)
+            .insert(ROOT_ID, Integrity::Pinned(digest));
+        info!("digest tree pinned, root digest {}", digest);
+    }
+
+    /// Digest pinned for `ino`, if it's pinned but not verified or proven yet.
+    fn pinned(&self, ino: Inode) -> Option<RafsDigest> {
+        match self.entries.read(ino).get(&ino) {
+            Some(Integrity::Pinned(digest)) => Some(*digest),
+            _ => None,
+        }
+    }
+
+    /// Verify directory `ino`, and its ancestors not verified yet from the top down.
+    fn verify_dir(&self, sb: &RafsSuper, ino: Inode) -> Result<()> {
+        let mut dirs = Vec::new();
+        let mut cur = ino;
+        loop {
+            let known = match self.entries.read(cur).get(&cur) {
+                Some(Integrity::Verified) => break,
+                Some(Integrity::Pinned(_)) => true,
+                Some(Integrity::Proven(_)) => return Err(enotdir!()),
+                None => false,
+            };
+            dirs.push(cur);
+            if known {
+                break;
+            }
+            // The root is pinned, so more dirs than inodes means a loop of parents.
+            if cur == ROOT_ID || dirs.len() as u64 > sb.get_max_ino() {
+                return Err(eio!(format!("inode {} is not in digest tree", ino)));
+            }
+            cur = sb.get_inode(cur, false)?.parent();
+        }
+
+        for ino in dirs.into_iter().rev() {
+            let expected = match self.pinned(ino) {
+                Some(digest) => digest,
+                // Verified by another lookup in the meantime.
+                None if matches!(self.entries.read(ino).get(&ino), Some(Integrity::Verified)) => {
+                    continue
+                }
+                None => return Err(eio!(format!("inode {} is not in digest tree", ino))),
+            };
+            let dir = sb.get_inode(ino, false)?;
+            if !dir.is_dir() {
+                return Err(enotdir!());
+            }
+            if dir.get_digest() != expected
+                || !sb
+                    .inodes
+                    .digest_validate(dir.clone(), false, sb.meta.get_digester())?
+            {
+                return Err(eio!(format!("digest mismatch of inode {}", ino)));
+            }
+            for idx in 0..dir.get_child_count() {
+                let child = dir.get_child_by_index(idx as u64)?;
+                self.entries
+                    .write(child.ino())
+                    .entry(child.ino())
+                    .or_insert_with(|| Integrity::Pinned(child.get_digest()));
+            }
+            self.entries.write(ino).insert(ino, Integrity::Verified);
+        }
+
+        Ok(())
+    }
+
+    /// Check that chunks of `bios` belong to `inode`, verifying directories above it first
+    /// if it's not pinned yet.
+    fn check(&self, sb: &RafsSuper, inode: &Arc<dyn RafsInode>, bios: &[RafsBio]) -> Result<()> {
+        let ino = inode.ino();
+        if let Some(Integrity::Proven(chunks)) = self.entries.read(ino).get(&ino) {
+            return Self::check_bios(ino, chunks, bios);
+        }
+
+        let expected = match self.pinned(ino) {
+            Some(digest) => digest,
+            None => {
+                self.verify_dir(sb, inode.parent())?;
+                match self.pinned(ino) {
+                    Some(digest) => digest,
+                    None => return self.check_proven(inode, bios),
+                }
+            }
+        };
+        let mut chunks = HashSet::new();
+        for idx in 0..inode.get_child_count() {
+            chunks.insert(*inode.get_chunk_info(idx)?.block_id());
+        }
+        if inode.get_digest() != expected
+            || !sb
+                .inodes
+                .digest_validate(inode.clone(), false, sb.meta.get_digester())?
+        {
+            return Err(eio!("file digest mismatch"));
+        }
+        let r = Self::check_bios(ino, &chunks, bios);
+        self.entries
+            .write(ino)
+            .insert(ino, Integrity::Proven(chunks));
+        r
+    }
+
+    /// Check `bios` of `inode` proven by another reader in the meantime.
+    fn check_proven(&self, inode: &Arc<dyn RafsInode>, bios: &[RafsBio]) -> Result<()> {
+        let ino = inode.ino();
+        match self.entries.read(ino).get(&ino) {
+            Some(Integrity::Proven(chunks)) => Self::check_bios(ino, chunks, bios),
+            _ => Err(eio!(format!("inode {} is not in digest tree", ino))),
+        }
+    }
+
+    fn check_bios(ino: Inode, chunks: &HashSet<RafsDigest>, bios: &[RafsBio]) -> Result<()> {
+        for bio in bios {
+            if !chunks.contains(bio.chunkinfo.block_id()) {
+                error!(
+                    "chunk {} not proven to be part of inode {}",
+                    bio.chunkinfo.block_id(),
+                    ino
+                );
+                return Err(eio!("chunk not in digest tree"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct ReadStream {
+    // end of the last read, None until the file is read
+    next: Option<u64>,
+    // chunks up to this file offset have been fetched ahead
+    fetched: u64,
+    // file range being fetched ahead
+    fetching: Option<(u64, u64)>,
+}
+
+/// Merging of adjacent reads of the same file into one backend request.
+///
+/// The kernel splits a sequential read of a file into small requests, e.g. 128KB with the
+/// default readahead, which are served by several threads at the same time. Each of them would
+/// fetch missing chunks on its own, issuing small backend requests of the same chunks. Once a
+/// read starts where the previous one ends, chunks ahead of it are fetched in a single request,
+/// and reads within the range being fetched wait for it instead of fetching again.
+struct SequentialReads {
+    merging_size: u64,
+    streams: Mutex<HashMap<Inode, ReadStream>>,
+    fetched: Condvar,
+}
+
+impl SequentialReads {
+    fn new(merging_size: u64) -> Self {
+        SequentialReads {
+            merging_size,
+            streams: Mutex::new(HashMap::new()),
+            fetched: Condvar::new(),
+        }
+    }
+
+    /// Account a read of `ino` in `[offset, end)` of a file of `size` bytes. Returns the file
+    /// range to fetch ahead, the caller must call `fetch_done()` once it's fetched.
+    fn start_read(&self, ino: Inode, offset: u64, end: u64, size: u64) -> Option<(u64, u64)> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.len() >= SEQUENTIAL_READS_CAPACITY && !streams.contains_key(&ino) {
+            streams.clear();
+        }
+        loop {
+            let waiting = match streams.get(&ino).and_then(|s| s.fetching) {
+                Some((start, stop)) => offset < stop && end > start,
+                None => false,
+            };
+            if !waiting {
+                break;
+            }
+            streams = self.fetched.wait(streams).unwrap();
+        }
+
+        let stream = streams.entry(ino).or_insert_with(ReadStream::default);
+        let sequential = stream.next == Some(offset);
+        stream.next = Some(end);
+        if !sequential {
+            // Chunks fetched ahead may have been evicted by the time the file is read again.
+            stream.fetched = 0;
+            return None;
+        }
+        if end <= stream.fetched {
+            return None;
+        }
+
+        let start = std::cmp::max(offset, stream.fetched);
+        let stop = std::cmp::min(
+            std::cmp::max(end, offset.saturating_add(self.merging_size)),
+            size,
+        );
+        if start >= stop {
+            return None;
+        }
+        stream.fetched = stop;
+        stream.fetching = Some((start, stop));
+        Some((start, stop))
+    }
+
+    /// Wake up reads waiting for the fetch ahead of `ino`.
+    fn fetch_done(&self, ino: Inode) {
+        if let Some(stream) = self.streams.lock().unwrap().get_mut(&ino) {
+            stream.fetching = None;
+        }
+        self.fetched.notify_all();
+    }
+
+    fn clear(&self) {
+        self.streams.lock().unwrap().clear();
+        self.fetched.notify_all();
+    }
+}
+
+#[derive(Default)]
+struct ReadaheadWindow {
+    // where the next read is expected to start
+    next: u64,
+    // current size of the window
+    size: u64,
+    // chunks up to this file offset have been fetched ahead
+    ahead: u64,
+}
+
+impl ReadaheadWindow {
+    /// Account a read in `[offset, end)` of a file of `file_size` bytes, returns the file range
+    /// to fetch ahead.
+    fn advance(
+        &mut self,
+        offset: u64,
+        end: u64,
+        file_size: u64,
+        max_size: u64,
+    ) -> Option<(u64, u64)> {
+        if offset == self.next {
+            self.size = std::cmp::min(std::cmp::max(self.size * 2, READAHEAD_MIN_SIZE), max_size);
+        } else {
+            // Fetching ahead of random reads wastes backend bandwidth, and chunks ahead of the
+            // previous position are of no use to the new one.
+            self.size /= 4;
+            if self.size < READAHEAD_MIN_SIZE {
+                self.size = 0;
+            }
+            self.ahead = 0;
+        }
+        self.next = end;
+        // Fetch the next window once half of the last one is consumed, so that data is ready
+        // by the time the reader gets there.
+        if self.size == 0 || self.ahead >= end.saturating_add(self.size / 2) {
+            return None;
+        }
+
+        let start = std::cmp::max(self.ahead, end);
+        let stop = std::cmp::min(end.saturating_add(self.size), file_size);
+        if start >= stop {
+            return None;
+        }
+        self.ahead = stop;
+        Some((start, stop))
+    }
+}
+
+type ReadaheadJob = Box<dyn FnOnce() + Send>;
+
+/// Adaptive readahead of file handles.
+///
+/// Unlike `SequentialReads`, which fetches ahead in the thread serving a read, chunks ahead of
+/// the reader are fetched by a worker in background, so reads don't wait for the readahead.
+struct Readahead {
+    max_size: u64,
+    windows: ShardedMap<ReadaheadWindow>,
+    sender: Mutex<SyncSender<ReadaheadJob>>,
+}
+
+impl Readahead {
+    fn new(max_size: u64) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<ReadaheadJob>(READAHEAD_QUEUE_DEPTH);
+        // The worker exits once the sender is dropped along with the filesystem.
+        std::thread::Builder::new()
+            .name("readahead".to_string())
+            .spawn(move || {
+                while let Ok(job) = receiver.recv() {
+                    job();
+                }
+            })?;
+
+        Ok(Readahead {
+            max_size,
+            windows: ShardedMap::default(),
+            sender: Mutex::new(sender),
+        })
+    }
+
+    /// Account a read of `handle` in `[offset, end)`, returns the file range to fetch ahead.
+    fn start_read(&self, handle: u64, offset: u64, end: u64, file_size: u64) -> Option<(u64, u64)> {
+        self.windows
+            .write(handle)
+            .entry(handle)
+            .or_insert_with(ReadaheadWindow::default)
+            .advance(offset, end, file_size, self.max_size)
+    }
+
+    fn submit(&self, job: ReadaheadJob) {
+        if let Err(TrySendError::Full(_)) = self.sender.lock().unwrap().try_send(job) {
+            debug!("readahead queue is full, drop readahead");
+        }
+    }
+
+    fn release(&self, handle: u64) {
+        self.windows.write(handle).remove(&handle);
+    }
+}
+
+/// Files of a prefetch request, along with the bootstrap they're looked up in.
+struct OndemandPrefetchJob {
+    sb: Arc<RafsSuper>,
+    device: device::RafsDevice,
+    inodes: Vec<Inode>,
+}
+
+#[derive(Default)]
+struct OndemandPrefetchState {
+    jobs: VecDeque<OndemandPrefetchJob>,
+    // files queued or being prefetched, by address of their bootstrap and inode number
+    pending: HashSet<(usize, Inode)>,
+    threads: usize,
+}
+
+/// Prefetch of files requested after mounted.
+///
+/// Requests are queued for at most `ONDEMAND_PREFETCH_THREADS` workers, which exit once the
+/// queue is drained. Files already queued or being prefetched are skipped.
+#[derive(Default)]
+struct OndemandPrefetch {
+    state: Mutex<OndemandPrefetchState>,
+}
+
+impl OndemandPrefetch {
+    fn key(sb: &Arc<RafsSuper>, ino: Inode) -> (usize, Inode) {
+        (Arc::as_ptr(sb) as usize, ino)
+    }
+
+    /// Queue `inodes` of `sb` to be fetched into cache of `device`, fails with EBUSY if there
+    /// are too many files queued.
+    fn submit(
+        self: &Arc<Self>,
+        sb: Arc<RafsSuper>,
+        device: device::RafsDevice,
+        mut inodes: Vec<Inode>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut seen = HashSet::new();
+        inodes.retain(|ino| seen.insert(*ino) && !state.pending.contains(&Self::key(&sb, *ino)));
+        if inodes.is_empty() {
+            return Ok(());
+        }
+        if state.pending.len() + inodes.len() > ONDEMAND_PREFETCH_QUEUE_DEPTH {
+            return Err(ebusy!(format!(
+                "{} files are queued for prefetch already",
+                state.pending.len()
+            )));
+        }
+
+        for ino in inodes.iter() {
+            state.pending.insert(Self::key(&sb, *ino));
+        }
+        state
+            .jobs
+            .push_back(OndemandPrefetchJob { sb, device, inodes });
+        if state.threads < ONDEMAND_PREFETCH_THREADS {
+            let prefetch = self.clone();
+            match std::thread::Builder::new()
+                .name("ondemand_prefetch".to_string())
+                .spawn(move || prefetch.work())
+            {
+                Ok(_) => state.threads += 1,
+                Err(e) if state.threads == 0 => {
+                    let job = state.jobs.pop_back().unwrap();
+                    for ino in job.inodes.iter() {
+                        state.pending.remove(&Self::key(&job.sb, *ino));
+                    }
+                    return Err(e);
+                }
+                // Left to the running workers.
+                Err(e) => warn!("failed to start prefetch worker, {}", e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut state = self.state.lock().unwrap();
+                match state.jobs.pop_front() {
+                    Some(job) => job,
+                    None => {
+                        state.threads -= 1;
+                        return;
+                    }
+                }
+            };
 
-    fn from_str(s: &str) -> RafsResult<RafsConfig> {
-        serde_json::from_str(s).map_err(RafsError::ParseConfig)
+            job.sb
+                .prefetch_inodes(&job.inodes, &|desc| {
+                    job.device
+                        .fetch_all(desc, RAFS_DEFAULT_BLOCK_SIZE, IoPriority::Prefetch)
+                        .unwrap_or_else(|e| {
+                            warn!("Prefetch error, {:?}", e);
+                            0
+                        });
+                    desc.bi_vec.clear();
+                    desc.bi_size = 0;
+                })
+                .unwrap_or_else(|e| warn!("Failed in prefetching files, {:?}", e));
+
+            let mut state = self.state.lock().unwrap();
+            for ino in job.inodes.iter() {
+                state.pending.remove(&Self::key(&job.sb, *ino));
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.state.lock().unwrap().threads > 0
     }
 }
 
-impl RafsConfig {
-    pub fn new() -> RafsConfig {
-        RafsConfig {
-            ..Default::default()
+/// Inode pinned by an open file handle.
+///
+/// The handle keeps reading from the bootstrap it was opened on, even after the filesystem
+/// has been switched to a new bootstrap, until it gets released.
+struct PinnedInode(Arc<dyn RafsInode>);
+
+/// Inode numbers handed out to the kernel mapped to those of the bootstrap mounted.
+///
+/// Inode numbers are assigned by the image builder, so those of the same file may differ
+/// between bootstraps. Files found at the same path of the new bootstrap with the same type
+/// and digest keep their numbers on switching bootstrap, other files get numbers never handed
+/// out before. The kernel then never sees data change under an inode number, and page cache of
+/// files is always kept.
+#[derive(Default)]
+struct InodeMap {
+    // kernel inode number to bootstrap inode number
+    to_sb: HashMap<Inode, Inode>,
+    // bootstrap inode number to kernel inode number
+    to_fs: HashMap<Inode, Inode>,
+}
+
+/// A bootstrap mounted with everything derived from it, switched as a whole on update.
+#[derive(Default)]
+struct MountedSuper {
+    sb: Arc<RafsSuper>,
+    // none until the bootstrap is switched for the first time, numbers are the same then
+    inodes: Option<InodeMap>,
+    // next inode number never handed out to the kernel
+    next_ino: Inode,
+    // total blocks in 512-byte units, calculated on first statfs
+    fs_blocks: Mutex<Option<u64>>,
+}
+
+impl MountedSuper {
+    fn new(sb: RafsSuper) -> Self {
+        MountedSuper {
+            next_ino: sb.get_max_ino() + 1,
+            sb: Arc::new(sb),
+            inodes: None,
+            fs_blocks: Mutex::new(None),
         }
     }
 
-    pub fn from_file(path: &str) -> RafsResult<RafsConfig> {
-        let file = File::open(path).map_err(RafsError::LoadConfig)?;
-        serde_json::from_reader::<File, RafsConfig>(file).map_err(RafsError::ParseConfig)
+    /// Bootstrap inode number of kernel inode number `ino`.
+    fn sb_ino(&self, ino: Inode) -> Result<Inode> {
+        match self.inodes.as_ref() {
+            None => Ok(ino),
+            // Files gone with the previous bootstrap.
+            Some(map) => map.to_sb.get(&ino).copied().ok_or_else(|| enoent!()),
+        }
     }
-}
 
-impl fmt::Display for RafsConfig {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "mode={} digest_validate={} iostats_files={} latest_read_files={}",
-            self.mode, self.digest_validate, self.iostats_files, self.latest_read_files
-        )
+    /// Kernel inode number of bootstrap inode number `ino`.
+    fn fs_ino(&self, ino: Inode) -> Inode {
+        match self.inodes.as_ref() {
+            None => ino,
+            Some(map) => map.to_fs.get(&ino).copied().unwrap_or(ino),
+        }
+    }
+
+    fn get_inode(&self, ino: Inode, digest_validate: bool) -> Result<Arc<dyn RafsInode>> {
+        self.sb.get_inode(self.sb_ino(ino)?, digest_validate)
+    }
+
+    /// Mount `sb` in place of this one, keeping inode numbers of files unchanged.
+    fn switch_to(&self, sb: RafsSuper) -> Result<Self> {
+        let mut map = InodeMap::default();
+        let mut next_ino = std::cmp::max(self.next_ino, sb.get_max_ino() + 1);
+        map.to_sb.insert(ROOT_ID, ROOT_ID);
+        map.to_fs.insert(ROOT_ID, ROOT_ID);
+
+        let mut dirs = vec![(
+            sb.get_inode(ROOT_ID, false)?,
+            Some(self.sb.get_inode(ROOT_ID, false)?),
+        )];
+        while let Some((dir, old_dir)) = dirs.pop() {
+            for idx in 0..dir.get_child_count() {
+                let child = dir.get_child_by_index(idx as Inode)?;
+                let old = old_dir
+                    .as_ref()
+                    .and_then(|d| d.get_child_by_name(&child.name()).ok())
+                    .filter(|o| {
+                        o.get_attr().mode & libc::S_IFMT == child.get_attr().mode & libc::S_IFMT
+                    });
+                if child.is_dir() {
+                    dirs.push((child.clone(), old.clone()));
+                }
+                // Hardlinks of a file seen already.
+                if map.to_fs.contains_key(&child.ino()) {
+                    continue;
+                }
+                let kept = old
+                    .filter(|o| {
+                        o.is_dir()
+                            || (o.get_digest() == child.get_digest() && o.size() == child.size())
+                    })
+                    .map(|o| self.fs_ino(o.ino()))
+                    .filter(|ino| !map.to_sb.contains_key(ino));
+                let ino = match kept {
+                    Some(ino) => ino,
+                    None => {
+                        next_ino += 1;
+                        next_ino - 1
+                    }
+                };
+                map.to_sb.insert(ino, child.ino());
+                map.to_fs.insert(child.ino(), ino);
+            }
+        }
+        if next_ino - 1 > RAFS_MAX_INO {
+            return Err(einval!(format!(
+                "inode number {} exceeds {}",
+                next_ino - 1,
+                RAFS_MAX_INO
+            )));
+        }
+
+        Ok(MountedSuper {
+            sb: Arc::new(sb),
+            inodes: Some(map),
+            next_ino,
+            fs_blocks: Mutex::new(None),
+        })
     }
 }
 
@@ -136,12 +1274,32 @@ impl fmt::Display for RafsConfig {
 pub struct Rafs {
     id: String,
     device: device::RafsDevice,
-    pub sb: Arc<RafsSuper>,
+    mounted: ArcSwap<MountedSuper>,
     digest_validate: bool,
+    file_digests: Option<FileDigests>,
+    integrity: Option<IntegrityTree>,
     fs_prefetch: bool,
+    prefetch_done: Arc<AtomicBool>,
+    // prefetch triggered after mounted
+    ondemand_prefetch: Arc<OndemandPrefetch>,
+    amplify_io: u64,
+    sequential_reads: Option<SequentialReads>,
+    readahead: Option<Readahead>,
     initialized: bool,
     xattr_enabled: bool,
+    xattr_filter: XattrFilter,
+    id_mapping: IdMapping,
+    atime_mode: AtimeMode,
+    // in-memory atime of accessed inodes, since unix epoch
+    atimes: ShardedMap<Duration>,
+    flatten_whiteouts: bool,
+    negative_cache: NegativeCache,
+    handles: ShardedMap<PinnedInode>,
+    next_handle: AtomicU64,
     ios: Arc<metrics::GlobalIOStats>,
+    access_trace: Option<AccessTrace>,
+    // signature verified of the bootstrap mounted
+    signature: Mutex<Option<SignatureStatus>>,
     // static inode attributes
     i_uid: u32,
     i_gid: u32,
@@ -168,13 +1326,25 @@ impl TryFrom<&RafsConfig> for PrefetchWorker {
 
 impl Rafs {
     pub fn new(conf: RafsConfig, id: &str, r: &mut RafsIoReader) -> RafsResult<Self> {
+        let begin = Instant::now();
         let mut device_conf = conf.device.clone();
 
-        device_conf.cache.cache_validate = conf.digest_validate;
+        device_conf.cache.cache_validate =
+            conf.digest_validate || conf.digest_validate_file || conf.enforce_integrity;
         device_conf.cache.prefetch_worker = TryFrom::try_from(&conf)?;
+        device_conf.cache.key_provider = conf.key_provider()?;
+        conf.id_mapping.validate()?;
 
         let mut sb = RafsSuper::new(&conf).map_err(RafsError::FillSuperblock)?;
         sb.load(r).map_err(RafsError::FillSuperblock)?;
+        Self::validate_max_ino(&sb)?;
+        let integrity = if conf.enforce_integrity {
+            let tree = IntegrityTree::default();
+            tree.load(&sb).map_err(RafsError::FillSuperblock)?;
+            Some(tree)
+        } else {
+            None
+        };
 
         let rafs = Rafs {
             id: id.to_string(),
@@ -185,12 +1355,48 @@ impl Rafs {
                 id,
             )
             .map_err(RafsError::CreateDevice)?,
-            sb: Arc::new(sb),
+            mounted: ArcSwap::new(Arc::new(MountedSuper::new(sb))),
             initialized: false,
             ios: metrics::new(id),
             digest_validate: conf.digest_validate,
+            file_digests: if conf.digest_validate_file {
+                Some(FileDigests::default())
+            } else {
+                None
+            },
+            integrity,
             fs_prefetch: conf.fs_prefetch.enable,
+            prefetch_done: Arc::new(AtomicBool::new(false)),
+            ondemand_prefetch: Arc::new(OndemandPrefetch::default()),
+            amplify_io: conf.amplify_io as u64,
+            sequential_reads: if conf.read_merging_size > 0 {
+                Some(SequentialReads::new(conf.read_merging_size as u64))
+            } else {
+                None
+            },
+            readahead: if conf.readahead_size > 0 {
+                Some(Readahead::new(conf.readahead_size as u64).map_err(|e| {
+                    RafsError::Configure(format!("failed to start readahead worker, {}", e))
+                })?)
+            } else {
+                None
+            },
             xattr_enabled: conf.enable_xattr,
+            xattr_filter: conf.xattr_filter.clone(),
+            id_mapping: conf.id_mapping.clone(),
+            atime_mode: conf.atime,
+            atimes: ShardedMap::default(),
+            flatten_whiteouts: conf.flatten_whiteouts,
+            negative_cache: NegativeCache::default(),
+            signature: Mutex::new(None),
+            handles: ShardedMap::default(),
+            next_handle: AtomicU64::new(1),
+            access_trace: match conf.access_trace.as_ref() {
+                Some(path) => Some(AccessTrace::create(path).map_err(|e| {
+                    RafsError::Configure(format!("failed to create access trace, {}", e))
+                })?),
+                None => None,
+            },
             i_uid: geteuid().into(),
             i_gid: getegid().into(),
             i_time: SystemTime::now()
@@ -199,6 +1405,7 @@ impl Rafs {
                 .as_secs(),
         };
 
+        rafs.ios.set_mount_time(begin);
         rafs.ios.toggle_files_recording(conf.iostats_files);
         rafs.ios.toggle_access_pattern(conf.access_pattern);
         rafs.ios
@@ -207,7 +1414,148 @@ impl Rafs {
         Ok(rafs)
     }
 
-    /// update backend meta and blob file.
+    /// Check whether a rafs can be mounted from `bootstrap` with `config`, without mounting
+    /// it: parse configuration, load bootstrap, look up `prefetch_files` and probe storage
+    /// backend with credentials in configuration.
+    pub fn validate(
+        config: &str,
+        bootstrap: &str,
+        prefetch_files: Option<&[PathBuf]>,
+    ) -> RafsValidation {
+        let mut v = RafsValidation::default();
+
+        let conf = match RafsConfig::from_str(config) {
+            Ok(conf) => conf,
+            Err(e) => {
+                v.check("config", Some(format!("invalid configuration, {:?}", e)));
+                return v;
+            }
+        };
+        let backend_conf = conf.device.backend.clone();
+        let backend_type = backend_conf.backend_type.clone();
+        let error = conf.id_mapping.validate().err().map(|e| format!("{:?}", e));
+        if !v.check("config", error) {
+            return v;
+        }
+
+        let sb = RafsIoRead::from_file(bootstrap)
+            .map_err(|e| format!("failed to open bootstrap, {:?}", e))
+            .and_then(|mut r| {
+                let mut sb = RafsSuper::new(&conf).map_err(|e| e.to_string())?;
+                sb.load(&mut r)
+                    .map_err(|e| format!("invalid bootstrap, {}", e))?;
+                Self::validate_max_ino(&sb).map_err(|e| format!("{:?}", e))?;
+                Ok(sb)
+            });
+        let sb = match sb {
+            Ok(sb) => sb,
+            Err(e) => {
+                v.check("bootstrap", Some(e));
+                return v;
+            }
+        };
+        v.check("bootstrap", None);
+
+        if let Some(config) = conf.signature.as_ref() {
+            let error = RafsIoRead::from_file(bootstrap)
+                .map_err(|e| format!("failed to open bootstrap, {:?}", e))
+                .and_then(|mut r| {
+                    signature::verify_bootstrap(Path::new(bootstrap), &mut r, config)
+                        .map_err(|e| e.to_string())
+                })
+                .err();
+            if !v.check("signature", error) {
+                return v;
+            }
+        }
+
+        if let Some(files) = prefetch_files {
+            let missing = files
+                .iter()
+                .filter(|f| sb.ino_from_path(f).is_err())
+                .collect::<Vec<_>>();
+            let error = if missing.is_empty() {
+                None
+            } else {
+                Some(format!("{:?} not found in the image", missing))
+            };
+            if !v.check("prefetch_files", error) {
+                return v;
+            }
+        }
+
+        // Backend metrics are registered by id, don't collide with that of any mount.
+        let id = format!("validate:{}", bootstrap);
+        let backend = match factory::new_backend(backend_conf, &id) {
+            Ok(b) => b,
+            Err(e) => {
+                v.check(
+                    "backend",
+                    Some(format!("invalid {} backend, {}", backend_type, e)),
+                );
+                return v;
+            }
+        };
+        let error = match sb.inodes.get_blobs().first() {
+            Some(blob) => {
+                let probe = backend.probe(&blob.blob_id);
+                let error = if probe.auth_valid == Some(false) {
+                    Some("credentials are rejected by backend".to_string())
+                } else if !probe.reachable {
+                    Some(format!(
+                        "backend is unreachable, {}",
+                        probe.error.as_deref().unwrap_or_default()
+                    ))
+                } else {
+                    None
+                };
+                v.backend_probe = Some(probe);
+                error
+            }
+            None => None,
+        };
+        backend.release();
+        v.check("backend", error);
+
+        v
+    }
+
+    /// Check the signature of `bootstrap` read from `r` if `conf` requires one, before it's
+    /// loaded from `r` by [`Rafs::new`] or [`Rafs::update`]. The result is to be recorded by
+    /// [`Rafs::set_signature`] once loaded.
+    pub fn verify_signature(
+        conf: &RafsConfig,
+        bootstrap: &str,
+        r: &mut RafsIoReader,
+    ) -> RafsResult<Option<SignatureStatus>> {
+        conf.signature
+            .as_ref()
+            .map(|config| {
+                signature::verify_bootstrap(Path::new(bootstrap), r, config)
+                    .map_err(RafsError::VerifySignature)
+            })
+            .transpose()
+    }
+
+    pub fn set_signature(&self, status: Option<SignatureStatus>) {
+        *self.signature.lock().unwrap() = status;
+    }
+
+    /// Signature verified of the bootstrap mounted, none if not required.
+    pub fn signature(&self) -> Option<SignatureStatus> {
+        self.signature.lock().unwrap().clone()
+    }
+
+    /// Superblock of the bootstrap mounted.
+    pub fn sb(&self) -> Arc<RafsSuper> {
+        self.mounted.load().sb.clone()
+    }
+
+    /// Switch to a new bootstrap and storage device set up with `conf`.
+    ///
+    /// Both are set up completely before switching, nothing is changed on failure. Open files
+    /// keep reading from the previous bootstrap until released, while lookups see the new one.
+    /// Files unchanged keep their inode numbers.
     pub fn update(&self, r: &mut RafsIoReader, conf: RafsConfig) -> RafsResult<()> {
         info!("update");
         if !self.initialized {
@@ -215,30 +1563,60 @@ impl Rafs {
             return Err(RafsError::Uninitialized);
         }
 
-        // step 1: update sb.
-        // No lock is needed thanks to ArcSwap.
-        self.sb.update(r).map_err(|e| {
-            error!("update failed due to {:?}", e);
-            e
-        })?;
-
+        // step 1: load the new bootstrap.
+        let mut sb = RafsSuper::new(&conf).map_err(RafsError::FillSuperblock)?;
+        sb.load(r).map_err(RafsError::FillSuperblock)?;
+        Self::validate_max_ino(&sb)?;
+        let old = self.mounted.load();
+        // The storage device is set up with features of the bootstrap.
+        if sb.meta.flags != old.sb.meta.flags || sb.meta.block_size != old.sb.meta.block_size {
+            return Err(RafsError::SwapBackend(einval!(format!(
+                "incompatible bootstrap, features {} block size {}, expect {} {}",
+                sb.meta.flags, sb.meta.block_size, old.sb.meta.flags, old.sb.meta.block_size
+            ))));
+        }
+        let root_digest = sb
+            .get_inode(ROOT_ID, false)
+            .map_err(RafsError::FillSuperblock)?
+            .get_digest();
+        let mounted = old.switch_to(sb).map_err(RafsError::FillSuperblock)?;
         info!("update sb is successful");
 
+        // step 2: switch to a new device, the current one is kept if it fails.
         let mut device_conf = conf.device.clone();
-        device_conf.cache.cache_validate = conf.digest_validate;
+        device_conf.cache.cache_validate =
+            conf.digest_validate || conf.digest_validate_file || conf.enforce_integrity;
         device_conf.cache.prefetch_worker = TryFrom::try_from(&conf)?;
-
-        // step 2: update device (only localfs is supported)
+        device_conf.cache.key_provider = conf.key_provider()?;
         self.device
             .update(
                 device_conf,
-                self.sb.meta.get_compressor(),
-                self.sb.meta.get_digester(),
+                mounted.sb.meta.get_compressor(),
+                mounted.sb.meta.get_digester(),
                 self.id.as_str(),
             )
             .map_err(RafsError::SwapBackend)?;
         info!("update device is successful");
 
+        // step 3: switch to the new bootstrap, nothing fails from now on.
+        self.mounted.store(Arc::new(mounted));
+        // Names absent from the old bootstrap may show up in the new one.
+        self.negative_cache.clear();
+        self.atimes.clear();
+        if let Some(digests) = self.file_digests.as_ref() {
+            digests.clear();
+        }
+        if let Some(tree) = self.integrity.as_ref() {
+            // Directories are verified again from the new root on demand.
+            tree.pin_root(root_digest);
+        }
+        if let Some(reads) = self.sequential_reads.as_ref() {
+            reads.clear();
+        }
+        if let Some(trace) = self.access_trace.as_ref() {
+            trace.reset_files();
+        }
+
         Ok(())
     }
 
@@ -254,7 +1632,7 @@ impl Rafs {
 
         // Without too much layout concern, just prefetch a certain range from backend.
         let prefetch_vec = self
-            .sb
+            .sb()
             .inodes
             .get_blobs()
             .iter()
@@ -271,8 +1649,10 @@ impl Rafs {
 
         // Device should be ready before any prefetch.
         if self.fs_prefetch {
-            let sb = self.sb.clone();
+            let sb = self.sb();
             let device = self.device.clone();
+            let prefetch_done = self.prefetch_done.clone();
+            let ios = self.ios.clone();
 
             let _ = std::thread::spawn(move || {
                 let mut reader = r;
@@ -308,6 +1688,8 @@ impl Rafs {
                 device
                     .stop_prefetch()
                     .unwrap_or_else(|_| error!("Failed in stopping prefetch workers"));
+                prefetch_done.store(true, Ordering::Release);
+                ios.mark_prefetch_done();
             });
         }
 
@@ -315,26 +1697,607 @@ impl Rafs {
         Ok(())
     }
 
+    /// Prefetch `files` in background, or the whole filesystem if it's None, for files to
+    /// prefetch which are only known after mounted.
+    ///
+    /// Data is fetched into blobcache directly, since prefetch workers may have exited once
+    /// prefetch on import is done, within the prefetch bandwidth limit. Fails with ENOSYS
+    /// without blobcache, with ENOENT if any of `files` doesn't exist, and with EBUSY if too
+    /// many files are waiting to be prefetched.
+    pub fn prefetch_files(&self, files: Option<&[PathBuf]>) -> Result<()> {
+        // Prefetched data has nowhere to stay without blobcache.
+        self.device.cached_blobs()?;
+
+        let sb = self.sb();
+        let inodes = match files {
+            Some(files) => files
+                .iter()
+                .map(|f| sb.ino_from_path(f))
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![ROOT_ID],
+        };
+
+        self.ondemand_prefetch
+            .submit(sb, self.device.clone(), inodes)
+    }
+
+    /// Get progress of prefetch started on import or on demand.
+    pub fn prefetch_status(&self) -> PrefetchStatus {
+        if self.ondemand_prefetch.is_running() {
+            PrefetchStatus::Running
+        } else if !self.fs_prefetch {
+            PrefetchStatus::Disabled
+        } else if self.prefetch_done.load(Ordering::Acquire) {
+            PrefetchStatus::Done
+        } else {
+            PrefetchStatus::Running
+        }
+    }
+
+    /// Change prefetch bandwidth limit in bytes per second, 0 means unlimited. It lasts until
+    /// the next remount, which takes the limit from the new configuration.
+    pub fn set_prefetch_bandwidth(&self, bandwidth_rate: u32) -> Result<()> {
+        self.device.set_prefetch_bandwidth(bandwidth_rate)
+    }
+
+    /// List blob files cached in the blobcache work dir of this instance, fails with ENOSYS
+    /// without blobcache.
+    pub fn cached_blobs(&self) -> Result<Vec<CachedBlob>> {
+        self.device.cached_blobs()
+    }
+
+    /// Get approximate memory usage of the instance, cached mode metadata takes a while to
+    /// walk through for huge images.
+    pub fn memory_usage(&self) -> RafsMemoryUsage {
+        let meta = self.sb().inodes.memory_usage();
+        let inode_cache_bytes = self.negative_cache.memory_usage()
+            + self.file_digests.as_ref().map_or(0, |d| d.memory_usage())
+            + self.integrity.as_ref().map_or(0, |t| t.memory_usage());
+        let cache = self.device.memory_usage();
+        let total_bytes = meta.metadata_bytes
+            + meta.chunk_info_bytes
+            + inode_cache_bytes
+            + cache.chunk_map_bytes
+            + cache.inflight_buffer_bytes
+            + cache.prefetch_queue_bytes;
+
+        RafsMemoryUsage {
+            metadata_bytes: meta.metadata_bytes,
+            chunk_info_bytes: meta.chunk_info_bytes,
+            inode_cache_bytes,
+            cache,
+            total_bytes,
+        }
+    }
+
+    /// Drop cached data of blob `blob_id` in the blobcache work dir, or of all blobs used by
+    /// this instance if it's None, returns the number of purged blobs. The data is fetched
+    /// from backend again on demand. Fails with EBUSY if other processes use a blob.
+    pub fn purge_blobs(&self, blob_id: Option<&str>) -> Result<usize> {
+        self.device.purge_blobs(blob_id)
+    }
+
+    /// Fill blobcache with blob files downloaded by others, so that their data is not fetched
+    /// from backend again.
+    ///
+    /// `src` is either a blob file named by its blob id, or a directory of such files, in
+    /// which files not belonging to this image are ignored. Fails with ENOENT if no blob of
+    /// this image is found, and with EINVAL if any chunk doesn't match its digest.
+    pub fn import_blobs(&self, src: &Path) -> Result<Vec<ImportedBlob>> {
+        let sb = self.sb();
+        let blobs = sb.inodes.get_blobs();
+        let sources = if src.is_dir() {
+            blobs
+                .iter()
+                .map(|b| (b.clone(), src.join(&b.blob_id)))
+                .filter(|(_, p)| p.is_file())
+                .collect::<Vec<_>>()
+        } else {
+            let name = src.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            blobs
+                .iter()
+                .find(|b| b.blob_id == name)
+                .map(|b| vec![(b.clone(), src.to_path_buf())])
+                .unwrap_or_default()
+        };
+        if sources.is_empty() {
+            return Err(enoent!(format!("no blob of the image in {:?}", src)));
+        }
+
+        let blobs = sources.iter().map(|(b, _)| b.clone()).collect::<Vec<_>>();
+        let blobs_chunks = sb.get_blobs_chunks(&blobs)?;
+
+        let mut imported = Vec::with_capacity(sources.len());
+        for ((blob, path), bios) in sources.into_iter().zip(blobs_chunks) {
+            // Read the blob file sequentially.
+            let mut desc = RafsBioDesc::new();
+            desc.bi_vec = bios;
+            let chunks = self.device.import_blob(&desc, &path)?;
+            info!(
+                "imported {} chunks of blob {} from {:?}",
+                chunks, blob.blob_id, path
+            );
+            imported.push(ImportedBlob {
+                blob_id: blob.blob_id.clone(),
+                path,
+                chunks,
+            });
+        }
+
+        Ok(imported)
+    }
+
+    /// Probe storage backend by the first blob of the image, without reading any data.
+    pub fn probe_backend(&self) -> Result<BackendProbe> {
+        let blob = self
+            .sb()
+            .inodes
+            .get_blobs()
+            .into_iter()
+            .next()
+            .ok_or_else(|| enoent!("no blob to probe"))?;
+
+        Ok(self.device.probe_backend(&blob.blob_id))
+    }
+
+    /// Check whether reads have failed lately, which mostly come from storage backend errors.
+    pub fn read_failing(&self) -> bool {
+        self.ios
+            .last_read_error()
+            .map_or(false, |at| at.elapsed() < READ_FAILING_PERIOD)
+    }
+
+    /// Read like `FileSystem::read()`, but splice data into the pipe `pipe` straight from cache
+    /// files, calling `prepare` with the size of data before that. Returns None with nothing
+    /// written to the pipe if data can't be spliced, then it should be read as usual.
+    pub fn splice_read(
+        &self,
+        ino: u64,
+        handle: u64,
+        size: u32,
+        offset: u64,
+        pipe: RawFd,
+        prepare: &mut dyn FnMut(usize) -> Result<()>,
+    ) -> Result<Option<usize>> {
+        // Chunks have to be read through cache to verify the whole file digest, or to be
+        // validated against chunk digests.
+        if self.file_digests.is_some() || self.integrity.is_some() {
+            return Ok(None);
+        }
+        let pinned = self.handles.read(handle).get(&handle).map(|h| h.0.clone());
+        let inode = match pinned {
+            Some(inode) => inode,
+            None => self.mounted.load().get_inode(ino, false)?,
+        };
+        if offset >= inode.size() {
+            return Ok(None);
+        }
+        let desc = inode.alloc_bio_desc(offset, size as usize)?;
+
+        // Only recorded once it's known to be spliced, otherwise it's read again as usual.
+        let mut recorder = None;
+        let start = self.ios.latency_start();
+        let r = self.device.splice_to(&desc, pipe, &mut |len| {
+            recorder = Some(FopRecorder::settle(Read, ino, &self.ios));
+            prepare(len)
+        })?;
+        let (r, mut recorder) = match (r, recorder) {
+            (Some(r), Some(recorder)) => (r, recorder),
+            _ => return Ok(None),
+        };
+        self.ios.latency_end(&start, Read);
+
+        // Keep fetching ahead for sequential reads, which are spliced once data gets cached.
+        if let Some(reads) = self.sequential_reads.as_ref() {
+            self.merge_read(reads, &inode, offset, size);
+        }
+        if let Some(readahead) = self.readahead.as_ref() {
+            self.readahead(readahead, handle, &inode, offset, size);
+        }
+        if let Some(trace) = self.access_trace.as_ref() {
+            trace
+                .record(&self.sb(), inode.ino(), offset, size)
+                .unwrap_or_else(|e| warn!("failed to trace read of inode {}: {}", ino, e));
+        }
+        self.touch_atime(ino)?;
+        recorder.mark_success(r);
+        Ok(Some(r))
+    }
+
+    /// Read like `FileSystem::read()`, but return data in chunk buffers to be replied without
+    /// copying them into one buffer. Returns None if the read is within a chunk or too large
+    /// for a reply of `READ_VECTORED_MAX_BUFS` buffers, then it should be read as usual.
+    pub fn read_vectored(
+        &self,
+        ino: u64,
+        handle: u64,
+        size: u32,
+        offset: u64,
+    ) -> Result<Option<RafsReadVec>> {
+        let pinned = self.handles.read(handle).get(&handle).map(|h| h.0.clone());
+        let inode = match pinned {
+            Some(inode) => inode,
+            None => self.mounted.load().get_inode(ino, false)?,
+        };
+        if offset >= inode.size() {
+            return Ok(None);
+        }
+        let desc = inode.alloc_bio_desc(offset, size as usize)?;
+        if desc.bi_vec.len() < 2 || desc.bi_vec.len() > READ_VECTORED_MAX_BUFS {
+            return Ok(None);
+        }
+
+        let mut recorder = FopRecorder::settle(Read, ino, &self.ios);
+        let mut data = None;
+        let r = self.read_inode(&inode, handle, desc, offset, size, |desc| {
+            let v = self.device.read_vectored(desc)?;
+            let len = v.len();
+            data = Some(v);
+            Ok(len)
+        })?;
+        self.touch_atime(ino)?;
+        recorder.mark_success(r);
+        Ok(data)
+    }
+
     /// umount a previously mounted rafs virtual path
     pub fn destroy(&mut self) -> Result<()> {
         info! {"Destroy rafs"}
 
+        if let Some(trace) = self.access_trace.as_ref() {
+            trace
+                .flush()
+                .unwrap_or_else(|e| warn!("failed to flush access trace: {}", e));
+        }
+
         if self.initialized {
-            Arc::get_mut(&mut self.sb)
+            let mut mounted = self.mounted.swap(Arc::new(MountedSuper::default()));
+            Arc::get_mut(&mut mounted)
+                .and_then(|m| Arc::get_mut(&mut m.sb))
                 .expect("Superblock is no longer used")
                 .destroy();
             self.device.close()?;
             self.initialized = false;
         }
 
-        Ok(())
+        Ok(())
+    }
+
+    fn validate_max_ino(sb: &RafsSuper) -> RafsResult<()> {
+        let max_ino = sb.get_max_ino();
+        if max_ino > RAFS_MAX_INO {
+            return Err(RafsError::FillSuperblock(einval!(format!(
+                "max inode number {} exceeds {}",
+                max_ino, RAFS_MAX_INO
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Sum up blocks of all inodes, hardlinked files are counted only once.
+    fn count_blocks(sb: &RafsSuper) -> Result<u64> {
+        let mut blocks = 0;
+        let mut hardlinks = HashSet::new();
+        let mut dirs = vec![sb.get_inode(ROOT_ID, false)?];
+
+        while let Some(dir) = dirs.pop() {
+            blocks += dir.get_attr().blocks;
+            for idx in 0..dir.get_child_count() {
+                let child = dir.get_child_by_index(idx as Inode)?;
+                if child.is_dir() {
+                    dirs.push(child);
+                } else if !child.is_hardlink() || hardlinks.insert(child.ino()) {
+                    blocks += child.get_attr().blocks;
+                }
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    fn xattr_supported(&self) -> bool {
+        self.xattr_enabled || self.mounted.load().sb.meta.has_xattr()
+    }
+
+    /// An overlayfs whiteout is a character device with 0/0 device number.
+    fn is_hidden_whiteout(&self, inode: &dyn RafsInode) -> bool {
+        self.flatten_whiteouts
+            && inode.get_attr().mode & libc::S_IFMT == libc::S_IFCHR
+            && inode.rdev() == 0
+    }
+
+    fn is_hidden_xattr(&self, name: &[u8], uid: u32) -> bool {
+        (self.flatten_whiteouts && name == OVERLAYFS_WHITEOUT_OPAQUE)
+            || !self.xattr_filter.allows(name, uid)
+    }
+
+    /// Read `desc` of `inode` with `read`, fetching ahead and keeping track of the read as
+    /// configured.
+    fn read_inode<F>(
+        &self,
+        inode: &Arc<dyn RafsInode>,
+        handle: u64,
+        desc: RafsBioDesc,
+        offset: u64,
+        size: u32,
+        read: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(RafsBioDesc) -> Result<usize>,
+    {
+        let sb = self.sb();
+        if let Some(tree) = self.integrity.as_ref() {
+            tree.check(&sb, inode, &desc.bi_vec)?;
+        }
+        if let Some(digests) = self.file_digests.as_ref() {
+            digests.check(inode.ino())?;
+        }
+        let mut fetched = match self.sequential_reads.as_ref() {
+            Some(reads) => self.merge_read(reads, inode, offset, size),
+            None => false,
+        };
+        if self.amplify_io > 0 && !fetched {
+            fetched = self.amplify_read(inode, &desc).unwrap_or_else(|e| {
+                warn!("failed to amplify read of inode {}: {}", inode.ino(), e);
+                0
+            }) > 0;
+        }
+        if !fetched && desc.bi_vec.len() > 1 {
+            self.fetch_chunks(inode, &desc);
+        }
+        if let Some(readahead) = self.readahead.as_ref() {
+            self.readahead(readahead, handle, inode, offset, size);
+        }
+        let chunks = self.file_digests.as_ref().map(|_| {
+            desc.bi_vec
+                .iter()
+                .map(|bio| bio.chunkinfo.file_offset())
+                .collect::<Vec<_>>()
+        });
+        let start = self.ios.latency_start();
+        let r = read(desc);
+        self.ios.latency_end(&start, Read);
+        let r = r?;
+        if let (Some(digests), Some(chunks)) = (self.file_digests.as_ref(), chunks) {
+            digests.record(&sb, inode, chunks.into_iter())?;
+        }
+        if let Some(trace) = self.access_trace.as_ref() {
+            trace
+                .record(&sb, inode.ino(), offset, size)
+                .unwrap_or_else(|e| warn!("failed to trace read of inode {}: {}", inode.ino(), e));
+        }
+        Ok(r)
+    }
+
+    /// Fetch chunks following the ones about to be read into cache in one backend request,
+    /// until `amplify_io` bytes in total.
+    ///
+    /// The builder dumps chunks of files in the order of inode numbers, so chunks of small files
+    /// next to each other are continuous in blob. Deduplicated chunks are skipped, and it stops
+    /// at the first gap in blob. Nothing is done if the read hits cache.
+    fn amplify_read(&self, inode: &Arc<dyn RafsInode>, desc: &RafsBioDesc) -> Result<usize> {
+        let mut bios: Vec<RafsBio> = desc
+            .bi_vec
+            .iter()
+            .filter(|bio| !bio.chunkinfo.is_hole())
+            .cloned()
+            .collect();
+        let last = match bios.last() {
+            Some(last) => last.clone(),
+            None => return Ok(0),
+        };
+        // Don't walk inodes for chunks to fetch unless the read goes to backend.
+        if !self.device.need_fetch(&bios[0])? {
+            return Ok(0);
+        }
+        let blob_index = last.chunkinfo.blob_index();
+        let mut size: u64 = bios
+            .iter()
+            .map(|bio| bio.chunkinfo.compress_size() as u64)
+            .sum();
+        if size >= self.amplify_io {
+            return Ok(0);
+        }
+        let wanted = bios.len();
+        let mut end = last.chunkinfo.compress_offset() + last.chunkinfo.compress_size() as u64;
+        // Only chunks after the read range of the file being read count.
+        let mut skip_until = Some(last.chunkinfo.file_offset());
+        let sb = self.sb();
+        let max_ino = std::cmp::min(
+            sb.get_max_ino(),
+            inode.ino().saturating_add(AMPLIFY_IO_MAX_INODES),
+        );
+        let mut current = inode.clone();
+
+        'walk: loop {
+            if current.is_reg() {
+                for idx in 0..current.get_child_count() {
+                    let chunk = current.get_chunk_info(idx)?;
+                    if skip_until.map_or(false, |offset| chunk.file_offset() <= offset)
+                        || chunk.is_hole()
+                        || chunk.blob_index() != blob_index
+                        || chunk.compress_offset() < end
+                    {
+                        continue;
+                    }
+                    if chunk.compress_offset() > end || size >= self.amplify_io {
+                        break 'walk;
+                    }
+                    size += chunk.compress_size() as u64;
+                    end += chunk.compress_size() as u64;
+                    bios.push(RafsBio::new(
+                        chunk,
+                        last.blob.clone(),
+                        0,
+                        0,
+                        current.get_blocksize(),
+                    ));
+                }
+            }
+            skip_until = None;
+            let ino = current.ino() + 1;
+            if ino > max_ino {
+                break;
+            }
+            current = sb.get_inode(ino, false)?;
+        }
+
+        if bios.len() == wanted {
+            return Ok(0);
+        }
+        let mut amplified = RafsBioDesc::new();
+        amplified.bi_vec = bios;
+        self.device.fetch(&amplified, IoPriority::OnDemand)
+    }
+
+    /// Fetch chunks of a read spanning more than one chunk in as few backend requests as
+    /// possible, rather than one after another as they get copied out. Large fuse requests
+    /// span many chunks of small files.
+    fn fetch_chunks(&self, inode: &Arc<dyn RafsInode>, desc: &RafsBioDesc) {
+        let mut chunks = RafsBioDesc::new();
+        chunks.bi_vec = desc
+            .bi_vec
+            .iter()
+            .filter(|bio| !bio.chunkinfo.is_hole())
+            .cloned()
+            .collect();
+        let size = chunks
+            .bi_vec
+            .iter()
+            .map(|bio| bio.chunkinfo.compress_size() as u64)
+            .sum();
+        // Chunks failing here are fetched again as they get read.
+        if let Err(e) = self.device.fetch_all(&chunks, size, IoPriority::OnDemand) {
+            warn!("failed to fetch chunks of inode {}: {}", inode.ino(), e);
+        }
+    }
+
+    /// Fetch chunks ahead of a read adjacent to the previous one of `inode` in one backend
+    /// request, returns whether it's fetched.
+    fn merge_read(
+        &self,
+        reads: &SequentialReads,
+        inode: &Arc<dyn RafsInode>,
+        offset: u64,
+        size: u32,
+    ) -> bool {
+        let end = std::cmp::min(offset.saturating_add(size as u64), inode.size());
+        let (start, stop) = match reads.start_read(inode.ino(), offset, end, inode.size()) {
+            Some(range) => range,
+            None => return false,
+        };
+        let r = inode
+            .alloc_bio_desc(start, (stop - start) as usize)
+            .and_then(|mut desc| {
+                desc.bi_vec.retain(|bio| !bio.chunkinfo.is_hole());
+                self.device
+                    .fetch_all(&desc, reads.merging_size, IoPriority::OnDemand)
+            });
+        reads.fetch_done(inode.ino());
+
+        match r {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("failed to merge read of inode {}: {}", inode.ino(), e);
+                false
+            }
+        }
+    }
+
+    /// Fetch chunks ahead of a read of `handle` in background as its readahead window goes.
+    fn readahead(
+        &self,
+        readahead: &Readahead,
+        handle: u64,
+        inode: &Arc<dyn RafsInode>,
+        offset: u64,
+        size: u32,
+    ) {
+        let end = std::cmp::min(offset.saturating_add(size as u64), inode.size());
+        let (start, stop) = match readahead.start_read(handle, offset, end, inode.size()) {
+            Some(range) => range,
+            None => return,
+        };
+        let mut desc = match inode.alloc_bio_desc(start, (stop - start) as usize) {
+            Ok(desc) => desc,
+            Err(e) => {
+                warn!("failed to read ahead of inode {}: {}", inode.ino(), e);
+                return;
+            }
+        };
+        desc.bi_vec.retain(|bio| !bio.chunkinfo.is_hole());
+        if desc.bi_vec.is_empty() {
+            return;
+        }
+
+        let device = self.device.clone();
+        let ino = inode.ino();
+        readahead.submit(Box::new(move || {
+            device
+                .fetch_all(&desc, RAFS_DEFAULT_BLOCK_SIZE, IoPriority::Background)
+                .unwrap_or_else(|e| {
+                    warn!("failed to read ahead of inode {}: {}", ino, e);
+                    0
+                });
+        }));
     }
 
-    fn xattr_supported(&self) -> bool {
-        self.xattr_enabled || self.sb.meta.has_xattr()
+    /// Get xattrs synthesized from rafs metadata of regular files, which are not stored in
+    /// bootstrap and not listed by listxattr.
+    fn get_virtual_xattr(&self, inode: &dyn RafsInode, name: &[u8]) -> Result<Option<XattrValue>> {
+        if !inode.is_reg() {
+            return Ok(None);
+        }
+
+        if name == NYDUS_XATTR_FILE_DIGEST {
+            Ok(self
+                .file_digests
+                .as_ref()
+                .map(|digests| digests.state(inode.ino()).as_bytes().to_vec()))
+        } else if name == NYDUS_XATTR_BLOB_ID {
+            let mut blobs: Vec<u32> = Vec::new();
+            for idx in 0..inode.get_child_count() {
+                let blob_index = inode.get_chunk_info(idx)?.blob_index();
+                if !blobs.contains(&blob_index) {
+                    blobs.push(blob_index);
+                }
+            }
+            let mut value = String::new();
+            for blob_index in blobs {
+                value += &inode.get_blob_by_index(blob_index)?.blob_id;
+                value.push('\n');
+            }
+            Ok(Some(value.into_bytes()))
+        } else if name == NYDUS_XATTR_CHUNKS {
+            // file_offset blob_id compress_offset compress_size decompress_size digest
+            let mut value = String::new();
+            for idx in 0..inode.get_child_count() {
+                let chunk = inode.get_chunk_info(idx)?;
+                let blob = inode.get_blob_by_index(chunk.blob_index())?;
+                value += &format!(
+                    "{} {} {} {} {} {}\n",
+                    chunk.file_offset(),
+                    blob.blob_id,
+                    chunk.compress_offset(),
+                    chunk.compress_size(),
+                    chunk.decompress_size(),
+                    chunk.block_id()
+                );
+            }
+            Ok(Some(value.into_bytes()))
+        } else {
+            Ok(None)
+        }
     }
 
-    fn do_readdir<F>(&self, ino: Inode, size: u32, offset: u64, mut add_entry: F) -> Result<()>
+    fn do_readdir<F>(
+        &self,
+        m: &MountedSuper,
+        ino: Inode,
+        size: u32,
+        offset: u64,
+        mut add_entry: F,
+    ) -> Result<()>
     where
         F: FnMut(DirEntry) -> Result<usize>,
     {
@@ -342,10 +2305,11 @@ impl Rafs {
             return Ok(());
         }
 
-        let parent = self.sb.get_inode(ino, self.digest_validate)?;
+        let parent = m.get_inode(ino, self.digest_validate)?;
         if !parent.is_dir() {
             return Err(enotdir!());
         }
+        self.touch_atime(ino)?;
 
         let mut cur_offset = offset;
         // offset 0 and 1 is for "." and ".." respectively.
@@ -362,7 +2326,7 @@ impl Rafs {
             let parent = if ino == ROOT_ID {
                 ROOT_ID
             } else {
-                parent.parent()
+                m.fs_ino(parent.parent())
             };
             cur_offset += 1;
             add_entry(DirEntry {
@@ -378,21 +2342,25 @@ impl Rafs {
             let child = parent.get_child_by_index(idx)?;
 
             cur_offset += 1;
+            if self.is_hidden_whiteout(child.as_ref()) {
+                idx += 1;
+                continue;
+            }
             match add_entry(DirEntry {
-                ino: child.ino(),
+                ino: m.fs_ino(child.ino()),
                 offset: cur_offset,
                 type_: 0,
                 name: child.name().as_bytes(),
             }) {
                 Ok(0) => {
                     self.ios
-                        .new_file_counter(child.ino(), |i| self.sb.path_from_ino(i).unwrap());
+                        .new_file_counter(child.ino(), |i| m.sb.path_from_ino(i).unwrap());
                     break;
                 }
                 Ok(_) => {
                     idx += 1;
                     self.ios
-                        .new_file_counter(child.ino(), |i| self.sb.path_from_ino(i).unwrap())
+                        .new_file_counter(child.ino(), |i| m.sb.path_from_ino(i).unwrap())
                 } // TODO: should we check `size` here?
                 Err(r) => return Err(r),
             }
@@ -402,6 +2370,7 @@ impl Rafs {
     }
 
     fn negative_entry(&self) -> Entry {
+        let meta = self.mounted.load().sb.meta;
         Entry {
             attr: Attr {
                 ..Default::default()
@@ -409,38 +2378,105 @@ impl Rafs {
             .into(),
             inode: 0,
             generation: 0,
-            attr_timeout: self.sb.meta.attr_timeout,
-            entry_timeout: self.sb.meta.entry_timeout,
+            attr_timeout: meta.attr_timeout,
+            entry_timeout: meta.entry_timeout,
         }
     }
 
     fn get_inode_attr(&self, ino: u64) -> Result<Attr> {
-        let inode = self.sb.get_inode(ino, false)?;
+        let m = self.mounted.load();
+        let inode = m.get_inode(ino, false)?;
         let mut attr = inode.get_attr();
+        attr.ino = ino;
         // override uid/gid if there is no explicit inode uid/gid
-        if !self.sb.meta.explicit_uidgid() {
+        if !m.sb.meta.explicit_uidgid() {
             attr.uid = self.i_uid;
             attr.gid = self.i_gid;
+        } else {
+            attr.uid = self.id_mapping.map_uid(attr.uid);
+            attr.gid = self.id_mapping.map_gid(attr.gid);
         }
 
+        // inodes carry no atime, and carry mtime and ctime only if built with them
         attr.atime = self.i_time;
-        attr.ctime = self.i_time;
-        attr.mtime = self.i_time;
+        if !m.sb.meta.has_mtime() {
+            attr.mtime = self.i_time;
+            attr.ctime = self.i_time;
+        }
+
+        if self.atime_mode != AtimeMode::NoAtime {
+            if let Some(atime) = self.atimes.read(ino).get(&ino) {
+                attr.atime = atime.as_secs();
+                attr.atimensec = atime.subsec_nanos();
+            }
+        }
 
         Ok(attr)
     }
 
-    fn get_inode_entry(&self, inode: Arc<dyn RafsInode>) -> Entry {
+    /// Update in-memory atime of inode `ino` on access as per the atime mode.
+    fn touch_atime(&self, ino: Inode) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        match self.atime_mode {
+            AtimeMode::NoAtime => return Ok(()),
+            AtimeMode::RelAtime => {
+                let attr = match self.get_inode_attr(ino) {
+                    Ok(attr) => attr,
+                    // Files gone with the previous bootstrap, still read by open handles.
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                let atime = Duration::new(attr.atime, attr.atimensec);
+                if atime > Duration::new(attr.mtime, attr.mtimensec)
+                    && atime > Duration::new(attr.ctime, attr.ctimensec)
+                    && now.checked_sub(atime).unwrap_or_default() < RELATIME_INTERVAL
+                {
+                    return Ok(());
+                }
+            }
+            AtimeMode::StrictAtime => {}
+        }
+
+        let mut atimes = self.atimes.write(ino);
+        if atimes.len() >= ATIMES_CAPACITY / MAP_SHARDS && !atimes.contains_key(&ino) {
+            // Forgotten atime falls back to the time of mount.
+            let mut times: Vec<Duration> = atimes.values().copied().collect();
+            times.sort_unstable();
+            let median = times[times.len() / 2];
+            atimes.retain(|_, atime| *atime > median);
+        }
+        atimes.insert(ino, now);
+
+        Ok(())
+    }
+
+    fn get_inode_entry(&self, m: &MountedSuper, inode: Arc<dyn RafsInode>) -> Entry {
         let mut entry = inode.get_entry();
+        entry.inode = m.fs_ino(entry.inode);
+        entry.attr.st_ino = entry.inode;
         // override uid/gid if there is no explicit inode uid/gid
-        if !self.sb.meta.explicit_uidgid() {
+        if !m.sb.meta.explicit_uidgid() {
             entry.attr.st_uid = self.i_uid;
             entry.attr.st_gid = self.i_gid;
+        } else {
+            entry.attr.st_uid = self.id_mapping.map_uid(entry.attr.st_uid);
+            entry.attr.st_gid = self.id_mapping.map_gid(entry.attr.st_gid);
         }
 
         entry.attr.st_atime = self.i_time as i64;
-        entry.attr.st_ctime = self.i_time as i64;
-        entry.attr.st_mtime = self.i_time as i64;
+        if !m.sb.meta.has_mtime() {
+            entry.attr.st_mtime = self.i_time as i64;
+            entry.attr.st_ctime = self.i_time as i64;
+        }
+
+        if self.atime_mode != AtimeMode::NoAtime {
+            if let Some(atime) = self.atimes.read(entry.inode).get(&entry.inode) {
+                entry.attr.st_atime = atime.as_secs() as i64;
+                entry.attr.st_atime_nsec = atime.subsec_nanos() as i64;
+            }
+        }
 
         entry
     }
@@ -448,11 +2484,12 @@ impl Rafs {
 
 impl BackendFileSystem for Rafs {
     fn mount(&self) -> Result<(Entry, u64)> {
-        let root_inode = self.sb.get_inode(ROOT_ID, self.digest_validate)?;
+        let m = self.mounted.load();
+        let root_inode = m.get_inode(ROOT_ID, self.digest_validate)?;
         self.ios
-            .new_file_counter(root_inode.ino(), |i| self.sb.path_from_ino(i).unwrap());
-        let entry = self.get_inode_entry(root_inode);
-        Ok((entry, self.sb.get_max_ino()))
+            .new_file_counter(root_inode.ino(), |i| m.sb.path_from_ino(i).unwrap());
+        let entry = self.get_inode_entry(&m, root_inode);
+        Ok((entry, m.sb.get_max_ino()))
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -465,6 +2502,9 @@ impl FileSystem for Rafs {
     type Handle = Handle;
 
     fn init(&self, _opts: FsOptions) -> Result<FsOptions> {
+        // POSIX_LOCKS and FLOCK_LOCKS are never requested by rafs, which has nothing to lock on
+        // a readonly filesystem. Whether the kernel forwards fcntl and flock locks to the daemon
+        // is up to the fuse session, see `--file-locks` of nydusd.
         Ok(
             // These fuse features are supported by rafs by default.
             FsOptions::ASYNC_READ
@@ -486,31 +2526,40 @@ impl FileSystem for Rafs {
     fn lookup(&self, _ctx: Context, ino: u64, name: &CStr) -> Result<Entry> {
         let mut rec = FopRecorder::settle(Lookup, ino, &self.ios);
         let target = OsStr::from_bytes(name.to_bytes());
-        let parent = self.sb.get_inode(ino, self.digest_validate)?;
+        let m = self.mounted.load();
+        let parent = m.get_inode(ino, self.digest_validate)?;
         if !parent.is_dir() {
             return Err(enotdir!());
         }
+        if let Some(tree) = self.integrity.as_ref() {
+            tree.verify_dir(&m.sb, parent.ino())?;
+        }
 
         rec.mark_success(0);
         if target == DOT || (ino == ROOT_ID && target == DOTDOT) {
-            let mut entry = self.get_inode_entry(parent);
-            entry.inode = ino;
-            Ok(entry)
+            Ok(self.get_inode_entry(&m, parent))
         } else if target == DOTDOT {
-            Ok(self
-                .sb
+            Ok(m.sb
                 .get_inode(parent.parent(), self.digest_validate)
-                .map(|i| self.get_inode_entry(i))
+                .map(|i| self.get_inode_entry(&m, i))
                 .unwrap_or_else(|_| self.negative_entry()))
+        } else if self.negative_cache.contains(ino, target) {
+            Ok(self.negative_entry())
         } else {
             Ok(parent
                 .get_child_by_name(target)
+                .ok()
+                .filter(|i| !self.is_hidden_whiteout(i.as_ref()))
                 .map(|i| {
                     self.ios
-                        .new_file_counter(i.ino(), |i| self.sb.path_from_ino(i).unwrap());
-                    self.get_inode_entry(i)
+                        .new_file_counter(i.ino(), |i| m.sb.path_from_ino(i).unwrap());
+                    self.get_inode_entry(&m, i)
                 })
-                .unwrap_or_else(|_| self.negative_entry()))
+                .unwrap_or_else(|| {
+                    self.negative_cache
+                        .insert(ino, target, m.sb.meta.entry_timeout);
+                    self.negative_entry()
+                }))
         }
     }
 
@@ -533,12 +2582,12 @@ impl FileSystem for Rafs {
             recorder.mark_success(0);
             r
         })?;
-        Ok((attr.into(), self.sb.meta.attr_timeout))
+        Ok((attr.into(), self.mounted.load().sb.meta.attr_timeout))
     }
 
     fn readlink(&self, _ctx: Context, ino: u64) -> Result<Vec<u8>> {
         let mut rec = FopRecorder::settle(Readlink, ino, &self.ios);
-        let inode = self.sb.get_inode(ino, self.digest_validate)?;
+        let inode = self.mounted.load().get_inode(ino, self.digest_validate)?;
         Ok(inode
             .get_symlink()
             .map(|r| {
@@ -549,12 +2598,26 @@ impl FileSystem for Rafs {
             .to_vec())
     }
 
+    fn open(&self, _ctx: Context, ino: u64, _flags: u32) -> Result<(Option<u64>, OpenOptions)> {
+        let inode = self.mounted.load().get_inode(ino, false)?;
+        if inode.is_dir() {
+            return Err(std::io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles
+            .write(handle)
+            .insert(handle, PinnedInode(inode));
+
+        // Data under an inode number never changes, even across switching bootstrap.
+        Ok((Some(handle), OpenOptions::KEEP_CACHE))
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn read(
         &self,
         _ctx: Context,
         ino: u64,
-        _handle: u64,
+        handle: u64,
         w: &mut dyn ZeroCopyWriter,
         size: u32,
         offset: u64,
@@ -562,19 +2625,22 @@ impl FileSystem for Rafs {
         _flags: u32,
     ) -> Result<usize> {
         let mut recorder = FopRecorder::settle(Read, ino, &self.ios);
-        let inode = self.sb.get_inode(ino, false)?;
+        let pinned = self.handles.read(handle).get(&handle).map(|h| h.0.clone());
+        let inode = match pinned {
+            Some(inode) => inode,
+            None => self.mounted.load().get_inode(ino, false)?,
+        };
         if offset >= inode.size() {
             recorder.mark_success(0);
             return Ok(0);
         }
         let desc = inode.alloc_bio_desc(offset, size as usize)?;
-        let start = self.ios.latency_start();
-        let r = self.device.read_to(w, desc).map(|r| {
-            recorder.mark_success(r);
-            r
-        });
-        self.ios.latency_end(&start, Read);
-        r
+        let r = self.read_inode(&inode, handle, desc, offset, size, |desc| {
+            self.device.read_to(w, desc)
+        })?;
+        self.touch_atime(ino)?;
+        recorder.mark_success(r);
+        Ok(r)
     }
 
     fn release(
@@ -582,11 +2648,15 @@ impl FileSystem for Rafs {
         _ctx: Context,
         _inode: u64,
         _flags: u32,
-        _handle: u64,
+        handle: u64,
         _flush: bool,
         _flock_release: bool,
         _lock_owner: Option<u64>,
     ) -> Result<()> {
+        self.handles.write(handle).remove(&handle);
+        if let Some(readahead) = self.readahead.as_ref() {
+            readahead.release(handle);
+        }
         Ok(())
     }
 
@@ -598,13 +2668,30 @@ impl FileSystem for Rafs {
         // filesystem doesn't implement this method.
         st.f_namemax = 255;
         st.f_bsize = 512;
-        st.f_fsid = self.sb.meta.magic as u64;
-        st.f_files = self.sb.meta.inodes_count;
+        st.f_frsize = 512;
+        let m = self.mounted.load();
+        st.f_fsid = m.sb.meta.magic as u64;
+        st.f_files = m.sb.meta.inodes_count;
+
+        // Walking the whole inode tree is expensive, but the result never changes until the
+        // bootstrap gets updated. The walk is done without the lock held so that concurrent
+        // statfs calls don't queue up behind it, racing ones just count the same number.
+        let cached = *m.fs_blocks.lock().unwrap();
+        let fs_blocks = match cached {
+            Some(blocks) => blocks,
+            None => {
+                let blocks = Self::count_blocks(&m.sb)?;
+                *m.fs_blocks.lock().unwrap() = Some(blocks);
+                blocks
+            }
+        };
+        // Free blocks and inodes are left zero as nothing can be written to rafs.
+        st.f_blocks = fs_blocks;
 
         Ok(st)
     }
 
-    fn getxattr(&self, _ctx: Context, inode: u64, name: &CStr, size: u32) -> Result<GetxattrReply> {
+    fn getxattr(&self, ctx: Context, inode: u64, name: &CStr, size: u32) -> Result<GetxattrReply> {
         let mut recorder = FopRecorder::settle(Getxattr, inode, &self.ios);
 
         if !self.xattr_supported() {
@@ -612,9 +2699,15 @@ impl FileSystem for Rafs {
         }
 
         let name = OsStr::from_bytes(name.to_bytes());
-        let inode = self.sb.get_inode(inode, false)?;
+        let inode = self.mounted.load().get_inode(inode, false)?;
 
-        let value = inode.get_xattr(name)?;
+        let value = if self.is_hidden_xattr(name.as_bytes(), ctx.uid) {
+            None
+        } else if let Some(value) = self.get_virtual_xattr(inode.as_ref(), name.as_bytes())? {
+            Some(value)
+        } else {
+            inode.get_xattr(name)?
+        };
         let r = match value {
             Some(value) => match size {
                 0 => Ok(GetxattrReply::Count((value.len() + 1) as u32)),
@@ -636,18 +2729,21 @@ impl FileSystem for Rafs {
         })
     }
 
-    fn listxattr(&self, _ctx: Context, inode: u64, size: u32) -> Result<ListxattrReply> {
+    fn listxattr(&self, ctx: Context, inode: u64, size: u32) -> Result<ListxattrReply> {
         let mut rec = FopRecorder::settle(Listxattr, inode, &self.ios);
         if !self.xattr_supported() {
             return Err(std::io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
-        let inode = self.sb.get_inode(inode, false)?;
+        let inode = self.mounted.load().get_inode(inode, false)?;
 
         let mut count = 0;
         let mut buf = Vec::new();
 
         for mut name in inode.get_xattrs()? {
+            if self.is_hidden_xattr(&name, ctx.uid) {
+                continue;
+            }
             count += name.len() + 1;
             if size != 0 {
                 buf.append(&mut name);
@@ -674,10 +2770,11 @@ impl FileSystem for Rafs {
         add_entry: &mut dyn FnMut(DirEntry) -> Result<usize>,
     ) -> Result<()> {
         let mut rec = FopRecorder::settle(Readdir, inode, &self.ios);
-        self.do_readdir(inode, size, offset, add_entry).map(|r| {
-            rec.mark_success(0);
-            r
-        })
+        self.do_readdir(&self.mounted.load(), inode, size, offset, add_entry)
+            .map(|r| {
+                rec.mark_success(0);
+                r
+            })
     }
 
     fn readdirplus(
@@ -690,9 +2787,10 @@ impl FileSystem for Rafs {
         add_entry: &mut dyn FnMut(DirEntry, Entry) -> Result<usize>,
     ) -> Result<()> {
         let mut rec = FopRecorder::settle(Readdirplus, ino, &self.ios);
-        self.do_readdir(ino, size, offset, |dir_entry| {
-            let inode = self.sb.get_inode(dir_entry.ino, self.digest_validate)?;
-            add_entry(dir_entry, self.get_inode_entry(inode))
+        let m = self.mounted.load();
+        self.do_readdir(&m, ino, size, offset, |dir_entry| {
+            let inode = m.get_inode(dir_entry.ino, self.digest_validate)?;
+            add_entry(dir_entry, self.get_inode_entry(&m, inode))
         })
         .map(|r| {
             rec.mark_success(0);
@@ -751,9 +2849,9 @@ impl FileSystem for Rafs {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use vmm_sys_util::tempdir::TempDir;
 
-    fn new_rafs_backend() -> Box<Rafs> {
-        let config = r#"
+    const BACKEND_CONFIG: &str = r#"
         {
             "device": {
               "backend": {
@@ -778,12 +2876,18 @@ mod tests {
               "bandwidth_rate": 10485760
             }
           }"#;
+
+    fn texture_bootstrap() -> PathBuf {
         let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
         let mut source_path = PathBuf::from(root_dir);
         source_path.push("../tests/texture/bootstrap/image_v2.boot");
+        source_path
+    }
+
+    fn mount_rafs_backend(bootstrap: &Path) -> Box<Rafs> {
         let mountpoint = "/mnt";
-        let rafs_config = RafsConfig::from_str(config).unwrap();
-        let bootstrapfile = source_path.to_str().unwrap();
+        let rafs_config = RafsConfig::from_str(BACKEND_CONFIG).unwrap();
+        let bootstrapfile = bootstrap.to_str().unwrap();
         let mut bootstrap = RafsIoRead::from_file(bootstrapfile).unwrap();
         let mut rafs = Rafs::new(rafs_config, mountpoint, &mut bootstrap).unwrap();
         rafs.import(bootstrap, Some(vec![std::path::PathBuf::new()]))
@@ -791,6 +2895,10 @@ mod tests {
         Box::new(rafs)
     }
 
+    fn new_rafs_backend() -> Box<Rafs> {
+        mount_rafs_backend(&texture_bootstrap())
+    }
+
     #[test]
     fn it_should_create_new_rafs_fs() {
         let rafs = new_rafs_backend();
@@ -800,6 +2908,123 @@ mod tests {
         assert_eq!(attr.uid, 0);
     }
 
+    #[test]
+    fn it_should_validate_mount() {
+        let config = r#"
+        {
+            "device": {
+              "backend": {
+                "type": "localfs",
+                "config": {
+                  "dir": "/no/such/dir"
+                }
+              }
+            },
+            "mode": "direct"
+          }"#;
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let mut source_path = PathBuf::from(root_dir);
+        source_path.push("../tests/texture/bootstrap/image_v2.boot");
+        let bootstrap = source_path.to_str().unwrap();
+
+        let v = Rafs::validate("{", bootstrap, None);
+        assert!(!v.valid);
+        assert_eq!(v.checks.len(), 1);
+        assert_eq!(v.checks[0].name, "config");
+
+        let v = Rafs::validate(config, "/no/such/bootstrap", None);
+        assert!(!v.valid);
+        assert_eq!(v.checks[1].name, "bootstrap");
+        assert!(v.checks[1].error.is_some());
+
+        let files = vec![PathBuf::from("/no/such/file")];
+        let v = Rafs::validate(config, bootstrap, Some(&files));
+        assert!(!v.valid);
+        assert!(v.checks[1].passed);
+        assert_eq!(v.checks[2].name, "prefetch_files");
+        assert!(!v.checks[2].passed);
+
+        // Blobs of the image are not found in the backend directory.
+        let files = vec![PathBuf::from("/")];
+        let v = Rafs::validate(config, bootstrap, Some(&files));
+        assert!(v.checks[2].passed);
+        assert_eq!(v.checks.last().unwrap().name, "backend");
+        assert_eq!(v.valid, v.checks.last().unwrap().passed);
+    }
+
+    #[test]
+    fn it_should_check_chunks_against_digest_tree() {
+        let rafs = new_rafs_backend();
+        let digester = rafs.sb().meta.get_digester();
+        let mut files = Vec::new();
+        rafs.sb()
+            .get_inode(ROOT_ID, false)
+            .unwrap()
+            .collect_descendants_inodes(&mut files)
+            .unwrap();
+        let mut files = files
+            .into_iter()
+            .filter(|f| f.is_reg() && f.get_child_count() > 0);
+        let file = files.find(|f| f.get_attr().nlink == 1).unwrap();
+        let bios = file.alloc_bio_desc(0, file.size() as usize).unwrap().bi_vec;
+        let other = files
+            .find(|f| {
+                let chunk = f.get_chunk_info(0).unwrap();
+                bios.iter()
+                    .all(|b| b.chunkinfo.block_id() != chunk.block_id())
+            })
+            .unwrap();
+        let other_bios = other.alloc_bio_desc(0, 1).unwrap().bi_vec;
+
+        let tree = IntegrityTree::default();
+        // Nothing can be read before the root is pinned.
+        assert!(tree.check(&rafs.sb(), &file, &bios).is_err());
+
+        tree.load(&rafs.sb()).unwrap();
+        tree.check(&rafs.sb(), &file, &bios).unwrap();
+        tree.check(&rafs.sb(), &file, &bios[..1]).unwrap();
+        // Chunks of other files are not part of it.
+        assert!(tree.check(&rafs.sb(), &file, &other_bios).is_err());
+        // Only directories above the file are verified.
+        let verified: usize = tree
+            .entries
+            .shards
+            .iter()
+            .map(|s| {
+                let shard = s.read().unwrap();
+                shard
+                    .values()
+                    .filter(|e| matches!(e, Integrity::Verified))
+                    .count()
+            })
+            .sum();
+        let path = rafs.sb().path_from_ino(file.ino()).unwrap();
+        assert_eq!(verified, path.components().count() - 1);
+
+        let bogus = RafsDigest::from_buf(b"bogus", digester);
+        tree.verify_dir(&rafs.sb(), other.parent()).unwrap();
+        tree.entries
+            .write(other.ino())
+            .insert(other.ino(), Integrity::Pinned(bogus));
+        assert!(tree.check(&rafs.sb(), &other, &other_bios).is_err());
+
+        // Directories are verified against digests pinned from their parents.
+        tree.load(&rafs.sb()).unwrap();
+        tree.entries
+            .write(ROOT_ID)
+            .insert(ROOT_ID, Integrity::Pinned(bogus));
+        assert!(tree.verify_dir(&rafs.sb(), ROOT_ID).is_err());
+        assert!(tree.check(&rafs.sb(), &other, &other_bios).is_err());
+    }
+
+    #[test]
+    fn it_should_leave_locks_to_kernel() {
+        let rafs = new_rafs_backend();
+        let opts = rafs.init(FsOptions::all()).unwrap();
+        assert!(!opts.contains(FsOptions::POSIX_LOCKS));
+        assert!(!opts.contains(FsOptions::FLOCK_LOCKS));
+    }
+
     #[test]
     fn it_should_access() {
         let rafs = new_rafs_backend();
@@ -842,6 +3067,9 @@ mod tests {
             Ok(statfs) => {
                 assert_eq!(statfs.f_files, 43082);
                 assert_eq!(statfs.f_bsize, 512);
+                assert_eq!(statfs.f_frsize, 512);
+                assert!(statfs.f_blocks > 0);
+                assert_eq!(statfs.f_bfree, 0);
                 assert_eq!(statfs.f_namemax, 255);
                 assert_eq!(statfs.f_fsid, 1380009555);
                 assert_eq!(statfs.f_ffree, 0);
@@ -850,12 +3078,535 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_should_filter_xattr() {
+        let filter = XattrFilter {
+            deny: vec!["security.".to_string()],
+            allow: vec!["security.capability".to_string()],
+            hide_trusted_from_unprivileged: true,
+        };
+        assert!(filter.allows(b"user.foo", 1000));
+        assert!(filter.allows(b"trusted.overlay.opaque", 0));
+        assert!(!filter.allows(b"trusted.overlay.opaque", 1000));
+        assert!(!filter.allows(b"security.selinux", 0));
+        assert!(filter.allows(b"security.capability", 1000));
+        assert!(XattrFilter::default().allows(b"trusted.foo", 1000));
+    }
+
+    #[test]
+    fn it_should_map_ids() {
+        let mapping: IdMapping = serde_json::from_str(
+            r#"{
+                "uid": [{"inside": 0, "outside": 1000, "count": 1},
+                        {"inside": 1, "outside": 100000, "count": 65535}],
+                "gid": [{"inside": 0, "outside": 1000, "count": 1}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(mapping.map_uid(0), 1000);
+        assert_eq!(mapping.map_uid(1), 100000);
+        assert_eq!(mapping.map_uid(65535), 165534);
+        // Ids out of any range are kept.
+        assert_eq!(mapping.map_uid(65536), 65536);
+        assert_eq!(mapping.map_gid(0), 1000);
+        assert_eq!(mapping.map_gid(1), 1);
+        assert_eq!(IdMapping::default().map_uid(0), 0);
+        assert!(mapping.validate().is_ok());
+
+        // Ranges running past the largest id are refused.
+        let mapping: IdMapping =
+            serde_json::from_str(r#"{"uid": [{"inside": 0, "outside": 4294967295, "count": 2}]}"#)
+                .unwrap();
+        assert!(mapping.validate().is_err());
+        assert_eq!(mapping.map_uid(1), OVERFLOW_ID);
+        let mapping: IdMapping =
+            serde_json::from_str(r#"{"gid": [{"inside": 4294967295, "outside": 0, "count": 2}]}"#)
+                .unwrap();
+        assert!(mapping.validate().is_err());
+        let mapping: IdMapping =
+            serde_json::from_str(r#"{"gid": [{"inside": 4294967295, "outside": 0, "count": 1}]}"#)
+                .unwrap();
+        assert!(mapping.validate().is_ok());
+    }
+
+    #[test]
+    fn it_should_update_atime() {
+        let mut rafs = new_rafs_backend();
+        let attr = rafs.get_inode_attr(ROOT_ID).unwrap();
+        rafs.touch_atime(ROOT_ID).unwrap();
+        assert_eq!(rafs.get_inode_attr(ROOT_ID).unwrap().atime, attr.atime);
+        assert!(rafs.atimes.is_empty());
+
+        // Atime not later than mtime gets updated.
+        rafs.atime_mode = AtimeMode::RelAtime;
+        rafs.touch_atime(ROOT_ID).unwrap();
+        let updated = rafs.get_inode_attr(ROOT_ID).unwrap();
+        assert!(
+            Duration::new(updated.atime, updated.atimensec)
+                > Duration::new(attr.atime, attr.atimensec)
+        );
+        rafs.touch_atime(ROOT_ID).unwrap();
+        assert_eq!(
+            rafs.get_inode_attr(ROOT_ID).unwrap().atimensec,
+            updated.atimensec
+        );
+
+        rafs.atime_mode = AtimeMode::StrictAtime;
+        std::thread::sleep(Duration::from_millis(10));
+        rafs.touch_atime(ROOT_ID).unwrap();
+        let touched = rafs.get_inode_attr(ROOT_ID).unwrap();
+        assert!(
+            Duration::new(touched.atime, touched.atimensec)
+                > Duration::new(updated.atime, updated.atimensec)
+        );
+        assert_eq!(touched.mtime, attr.mtime);
+    }
+
+    #[test]
+    fn it_should_bound_atimes() {
+        let mut rafs = new_rafs_backend();
+        rafs.atime_mode = AtimeMode::StrictAtime;
+        // Inodes falling into the same shard.
+        let shard_capacity = ATIMES_CAPACITY / MAP_SHARDS;
+        let inos: Vec<u64> = (1..=shard_capacity as u64 + 1)
+            .map(|i| i * MAP_SHARDS as u64)
+            .collect();
+        for ino in inos.iter() {
+            rafs.touch_atime(*ino).unwrap();
+        }
+
+        let atimes = rafs.atimes.read(inos[0]);
+        assert!(atimes.len() <= shard_capacity / 2 + 1);
+        assert!(!atimes.contains_key(&inos[0]));
+        assert!(atimes.contains_key(inos.last().unwrap()));
+    }
+
     #[test]
     fn it_should_enable_xattr() {
         let rafs = new_rafs_backend();
         assert_eq!(rafs.xattr_supported(), true);
     }
 
+    #[test]
+    fn it_should_keep_stable_inode_numbers() {
+        let ctx = Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let list_root = |rafs: &Rafs| {
+            let mut entries = Vec::new();
+            rafs.do_readdir(&rafs.mounted.load(), ROOT_ID, 4096, 2, |e| {
+                entries.push((e.name.to_vec(), e.ino));
+                Ok(1)
+            })
+            .unwrap();
+            entries
+        };
+
+        let rafs = new_rafs_backend();
+        let entries = list_root(rafs.as_ref());
+        assert!(!entries.is_empty());
+        for (name, ino) in entries.iter() {
+            let entry = rafs
+                .lookup(ctx, ROOT_ID, &std::ffi::CString::new(name.clone()).unwrap())
+                .unwrap();
+            let (st, _) = rafs.getattr(ctx, *ino, None).unwrap();
+            assert_eq!(entry.inode, *ino);
+            assert_eq!(st.st_ino, *ino);
+        }
+
+        // Mounting the same bootstrap again yields the very same inode numbers.
+        let remounted = new_rafs_backend();
+        assert_eq!(list_root(remounted.as_ref()), entries);
+    }
+
+    #[test]
+    fn it_should_pin_inode_by_handle() {
+        let rafs = new_rafs_backend();
+        let ctx = Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let ino = (ROOT_ID..=rafs.sb().get_max_ino())
+            .find(|ino| {
+                rafs.sb()
+                    .get_inode(*ino, false)
+                    .map(|i| i.is_reg())
+                    .unwrap_or(false)
+            })
+            .unwrap();
+
+        assert_eq!(
+            rafs.open(ctx, ROOT_ID, 0).unwrap_err().raw_os_error(),
+            Some(libc::EISDIR)
+        );
+        let (handle, opts) = rafs.open(ctx, ino, 0).unwrap();
+        let handle = handle.unwrap();
+        assert_eq!(opts, OpenOptions::KEEP_CACHE);
+        assert_eq!(rafs.handles.read(handle).get(&handle).unwrap().0.ino(), ino);
+        rafs.release(ctx, ino, 0, handle, false, false, None)
+            .unwrap();
+        assert!(rafs.handles.read(handle).get(&handle).is_none());
+    }
+
+    #[test]
+    fn it_should_read_old_bootstrap_by_handle_after_update() {
+        let dir = TempDir::new().unwrap();
+        let old = dir.as_path().join("old.boot");
+        let new = dir.as_path().join("new.boot");
+        std::fs::copy(texture_bootstrap(), &old).unwrap();
+        std::fs::copy(texture_bootstrap(), &new).unwrap();
+        let rafs = mount_rafs_backend(&old);
+        let ctx = Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let inode = (ROOT_ID..=rafs.sb().get_max_ino())
+            .filter_map(|ino| rafs.sb().get_inode(ino, false).ok())
+            .find(|inode| inode.is_reg() && inode.get_child_count() > 0)
+            .unwrap();
+        let (handle, _) = rafs.open(ctx, inode.ino(), 0).unwrap();
+        let handle = handle.unwrap();
+
+        let mut r = RafsIoRead::from_file(new.to_str().unwrap()).unwrap();
+        rafs.update(&mut r, RafsConfig::from_str(BACKEND_CONFIG).unwrap())
+            .unwrap();
+        // The old mapping stays valid as long as the handle is open.
+        drop(r);
+        std::fs::remove_file(&old).unwrap();
+
+        let pinned = rafs.handles.read(handle).get(&handle).unwrap().0.clone();
+        assert_eq!(pinned.name(), inode.name());
+        assert_eq!(pinned.size(), inode.size());
+        assert_eq!(
+            pinned.get_chunk_info(0).unwrap().block_id(),
+            inode.get_chunk_info(0).unwrap().block_id()
+        );
+        drop(inode);
+        let desc = pinned.alloc_bio_desc(0, pinned.size() as usize).unwrap();
+        assert!(!desc.bi_vec.is_empty());
+
+        // Unchanged files keep their inode numbers and thus their page cache.
+        let (_, opts) = rafs.open(ctx, pinned.ino(), 0).unwrap();
+        assert_eq!(opts, OpenOptions::KEEP_CACHE);
+        rafs.release(ctx, pinned.ino(), 0, handle, false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_current_bootstrap_if_update_fails() {
+        let rafs = new_rafs_backend();
+        let ctx = Context {
+            gid: 0,
+            pid: 1,
+            uid: 0,
+        };
+        let list_root = |rafs: &Rafs| {
+            let mut entries = Vec::new();
+            rafs.do_readdir(&rafs.mounted.load(), ROOT_ID, 4096, 0, |e| {
+                entries.push((e.name.to_vec(), e.ino));
+                Ok(1)
+            })
+            .unwrap();
+            entries
+        };
+        let before = rafs.sb();
+        let entries = list_root(rafs.as_ref());
+
+        // A truncated bootstrap.
+        let dir = TempDir::new().unwrap();
+        let broken = dir.as_path().join("broken.boot");
+        let data = std::fs::read(texture_bootstrap()).unwrap();
+        std::fs::write(&broken, &data[..data.len() / 2]).unwrap();
+        let mut r = RafsIoRead::from_file(broken.to_str().unwrap()).unwrap();
+        assert!(rafs
+            .update(&mut r, RafsConfig::from_str(BACKEND_CONFIG).unwrap())
+            .is_err());
+        assert!(Arc::ptr_eq(&rafs.sb(), &before));
+
+        // A storage backend which can't be set up.
+        let mut r = RafsIoRead::from_file(texture_bootstrap().to_str().unwrap()).unwrap();
+        let bogus = BACKEND_CONFIG.replace(r#""type": "oss""#, r#""type": "bogus""#);
+        assert!(rafs
+            .update(&mut r, RafsConfig::from_str(&bogus).unwrap())
+            .is_err());
+        assert!(Arc::ptr_eq(&rafs.sb(), &before));
+        assert_eq!(list_root(rafs.as_ref()), entries);
+        for (name, ino) in entries.iter() {
+            let entry = rafs
+                .lookup(ctx, ROOT_ID, &std::ffi::CString::new(name.clone()).unwrap())
+                .unwrap();
+            assert_eq!(entry.inode, *ino);
+        }
+
+        // Switching succeeds afterwards and keeps inode numbers of all files.
+        let mut r = RafsIoRead::from_file(texture_bootstrap().to_str().unwrap()).unwrap();
+        rafs.update(&mut r, RafsConfig::from_str(BACKEND_CONFIG).unwrap())
+            .unwrap();
+        assert!(!Arc::ptr_eq(&rafs.sb(), &before));
+        assert_eq!(list_root(rafs.as_ref()), entries);
+        let st = rafs.statfs(ctx, ROOT_ID).unwrap();
+        assert_eq!(st.f_files, rafs.sb().meta.inodes_count);
+    }
+
+    #[test]
+    fn it_should_verify_file_digest() {
+        let rafs = new_rafs_backend();
+        let inode = (ROOT_ID..=rafs.sb().get_max_ino())
+            .filter_map(|ino| rafs.sb().get_inode(ino, false).ok())
+            .find(|inode| inode.is_reg() && inode.get_child_count() > 0)
+            .unwrap();
+        let chunks = (0..inode.get_child_count())
+            .map(|idx| inode.get_chunk_info(idx).unwrap().file_offset())
+            .collect::<Vec<_>>();
+
+        let digests = FileDigests::default();
+        assert_eq!(digests.state(inode.ino()), "pending");
+        let last = chunks.len() - 1;
+        digests
+            .record(&rafs.sb(), &inode, chunks[..last].iter().cloned())
+            .unwrap();
+        assert_eq!(digests.state(inode.ino()), "pending");
+        // Chunks read again are counted only once.
+        digests
+            .record(&rafs.sb(), &inode, chunks[..last].iter().cloned())
+            .unwrap();
+        assert_eq!(digests.state(inode.ino()), "pending");
+        digests
+            .record(&rafs.sb(), &inode, chunks[last..].iter().cloned())
+            .unwrap();
+        assert_eq!(digests.state(inode.ino()), "verified");
+        assert!(digests.check(inode.ino()).is_ok());
+
+        digests.clear();
+        assert_eq!(digests.state(inode.ino()), "pending");
+    }
+
+    #[test]
+    fn it_should_bound_pending_file_digests() {
+        let digests = FileDigests::default();
+        // Files falling into the same shard, each having one of its two chunks read.
+        let shard_capacity = FILE_DIGESTS_PENDING_CAPACITY / MAP_SHARDS;
+        let inos: Vec<u64> = (1..=shard_capacity as u64 + 1)
+            .map(|i| i * MAP_SHARDS as u64)
+            .collect();
+        for ino in inos.iter() {
+            assert!(!digests.track(*ino, std::iter::once(0), 2));
+        }
+
+        {
+            let pending = digests.pending.read(inos[0]);
+            assert!(pending.len() <= shard_capacity / 2 + 1);
+            assert!(!pending.contains_key(&inos[0]));
+            assert!(pending.contains_key(inos.last().unwrap()));
+        }
+        // A forgotten file has to be read all over again.
+        assert!(!digests.track(inos[0], std::iter::once(4096), 2));
+        assert!(digests.track(inos[0], std::iter::once(0), 2));
+        assert!(!digests.pending.read(inos[0]).contains_key(&inos[0]));
+    }
+
+    #[test]
+    fn it_should_prefetch_inodes() {
+        let rafs = new_rafs_backend();
+        let inode = (ROOT_ID..=rafs.sb().get_max_ino())
+            .filter_map(|ino| rafs.sb().get_inode(ino, false).ok())
+            .find(|inode| inode.is_reg() && inode.get_child_count() > 0)
+            .unwrap();
+
+        let fetched = Mutex::new(0);
+        rafs.sb()
+            .prefetch_inodes(&[inode.ino()], &|desc| {
+                *fetched.lock().unwrap() += desc.bi_size;
+                desc.bi_vec.clear();
+                desc.bi_size = 0;
+            })
+            .unwrap();
+        assert_eq!(*fetched.lock().unwrap(), inode.size() as usize);
+    }
+
+    #[test]
+    fn it_should_get_blobs_chunks() {
+        let rafs = new_rafs_backend();
+        let sb = rafs.sb();
+        let blobs = sb.inodes.get_blobs();
+
+        // Same chunks as those reachable from the directory tree.
+        let walked = Mutex::new(HashSet::new());
+        sb.prefetch_inodes(&[ROOT_ID], &|desc| {
+            for bio in desc.bi_vec.drain(..) {
+                let chunk = &bio.chunkinfo;
+                walked
+                    .lock()
+                    .unwrap()
+                    .insert((chunk.blob_index(), chunk.compress_offset()));
+            }
+            desc.bi_size = 0;
+        })
+        .unwrap();
+        let chunks = sb.get_blobs_chunks(&blobs).unwrap();
+        assert_eq!(chunks.len(), blobs.len());
+        let listed = chunks
+            .iter()
+            .flatten()
+            .map(|b| (b.blob.blob_index, b.chunkinfo.compress_offset()))
+            .collect::<Vec<_>>();
+        assert!(!listed.is_empty());
+        assert_eq!(
+            listed.iter().cloned().collect::<HashSet<_>>(),
+            walked.into_inner().unwrap()
+        );
+        assert_eq!(listed.len(), listed.iter().collect::<HashSet<_>>().len());
+        for c in chunks.iter() {
+            assert!(c
+                .windows(2)
+                .all(|w| w[0].chunkinfo.compress_offset() < w[1].chunkinfo.compress_offset()));
+        }
+        assert!(sb.get_blobs_chunks(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_should_not_prefetch_without_blobcache() {
+        let rafs = new_rafs_backend();
+        let e = rafs.prefetch_files(None).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSYS));
+        assert!(!rafs.ondemand_prefetch.is_running());
+    }
+
+    #[test]
+    fn it_should_queue_ondemand_prefetch() {
+        let rafs = new_rafs_backend();
+        let sb = rafs.sb();
+        let prefetch = Arc::new(OndemandPrefetch::default());
+        let submit = |inodes: Vec<Inode>| prefetch.submit(sb.clone(), rafs.device.clone(), inodes);
+        // Requests stay queued with all workers taken.
+        prefetch.state.lock().unwrap().threads = ONDEMAND_PREFETCH_THREADS;
+
+        submit(vec![ROOT_ID, 2, ROOT_ID]).unwrap();
+        submit(vec![2, 3]).unwrap();
+        submit(vec![3]).unwrap();
+        {
+            let state = prefetch.state.lock().unwrap();
+            let jobs = state
+                .jobs
+                .iter()
+                .map(|j| j.inodes.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(jobs, vec![vec![ROOT_ID, 2], vec![3]]);
+            assert_eq!(state.pending.len(), 3);
+        }
+
+        let many = (10..10 + ONDEMAND_PREFETCH_QUEUE_DEPTH as Inode).collect::<Vec<_>>();
+        let e = submit(many).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EBUSY));
+        assert_eq!(prefetch.state.lock().unwrap().pending.len(), 3);
+
+        // A worker takes all queued requests and exits.
+        prefetch.state.lock().unwrap().threads -= 1;
+        submit(vec![4]).unwrap();
+        loop {
+            let state = prefetch.state.lock().unwrap();
+            if state.threads < ONDEMAND_PREFETCH_THREADS {
+                assert!(state.jobs.is_empty());
+                assert!(state.pending.is_empty());
+                break;
+            }
+            drop(state);
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn it_should_get_virtual_xattr() {
+        let rafs = new_rafs_backend();
+        let inode = (ROOT_ID..=rafs.sb().get_max_ino())
+            .filter_map(|ino| rafs.sb().get_inode(ino, false).ok())
+            .find(|inode| inode.is_reg() && inode.get_child_count() > 0)
+            .unwrap();
+
+        let chunks = rafs
+            .get_virtual_xattr(inode.as_ref(), NYDUS_XATTR_CHUNKS)
+            .unwrap()
+            .unwrap();
+        let chunks = String::from_utf8(chunks).unwrap();
+        assert_eq!(chunks.lines().count(), inode.get_child_count() as usize);
+
+        let blobs = rafs
+            .get_virtual_xattr(inode.as_ref(), NYDUS_XATTR_BLOB_ID)
+            .unwrap()
+            .unwrap();
+        let blobs = String::from_utf8(blobs).unwrap();
+        assert!(blobs.lines().count() > 0);
+        for blob_id in blobs.lines() {
+            assert!(chunks.contains(blob_id));
+        }
+
+        // File digest state is only available when file digest validation is enabled.
+        assert!(rafs
+            .get_virtual_xattr(inode.as_ref(), NYDUS_XATTR_FILE_DIGEST)
+            .unwrap()
+            .is_none());
+        let root = rafs.sb().get_inode(ROOT_ID, false).unwrap();
+        assert!(rafs
+            .get_virtual_xattr(root.as_ref(), NYDUS_XATTR_CHUNKS)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn it_should_shard_map() {
+        let map: ShardedMap<u64> = ShardedMap::default();
+        assert!(map.is_empty());
+        for key in 0..(MAP_SHARDS * 2) as u64 {
+            map.write(key).insert(key, key * 2);
+        }
+        for key in 0..(MAP_SHARDS * 2) as u64 {
+            assert_eq!(map.read(key).get(&key), Some(&(key * 2)));
+            // Each shard holds two keys.
+            assert_eq!(map.read(key).len(), 2);
+        }
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn it_should_cache_negative_entry() {
+        let cache = NegativeCache::default();
+        let name = OsStr::new("libfoo.so");
+
+        assert!(!cache.contains(1, name));
+        cache.insert(1, name, Duration::from_secs(3600));
+        assert!(cache.contains(1, name));
+        assert!(!cache.contains(2, name));
+
+        // Timeouts too large to be represented never expire.
+        cache.insert(2, name, Duration::from_secs(u64::MAX));
+        assert!(cache.contains(2, name));
+
+        cache.insert(3, name, Duration::from_secs(0));
+        assert!(!cache.contains(3, name));
+
+        cache.clear();
+        assert!(!cache.contains(1, name));
+
+        for idx in 0..NEGATIVE_CACHE_CAPACITY + 1 {
+            cache.insert(idx as Inode, name, Duration::from_secs(3600));
+        }
+        assert_eq!(cache.count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn it_should_account_negative_cache_memory() {
+        let cache = NegativeCache::default();
+        let empty = cache.memory_usage();
+
+        cache.insert(1, OsStr::new("libfoo.so"), Duration::from_secs(3600));
+        assert!(cache.memory_usage() > empty);
+    }
+
     #[test]
     fn it_should_lookup_entry() {
         let rafs = new_rafs_backend();
@@ -874,4 +3625,96 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn it_should_merge_sequential_reads() {
+        let reads = SequentialReads::new(0x100000);
+        let size = 0x180000;
+
+        // The first read of a file isn't known to be sequential.
+        assert_eq!(reads.start_read(1, 0, 0x20000, size), None);
+        assert_eq!(
+            reads.start_read(1, 0x20000, 0x40000, size),
+            Some((0x20000, 0x120000))
+        );
+        // Reads within chunks fetched ahead go to cache.
+        reads.fetch_done(1);
+        assert_eq!(reads.start_read(1, 0x40000, 0x60000, size), None);
+        assert_eq!(reads.start_read(1, 0x100000, 0x120000, size), None);
+        // Fetch the rest of the file once reads go beyond fetched chunks.
+        assert_eq!(
+            reads.start_read(1, 0x120000, 0x140000, size),
+            Some((0x120000, size))
+        );
+        reads.fetch_done(1);
+
+        // Random reads are not merged, and reset the fetched range.
+        assert_eq!(reads.start_read(1, 0, 0x20000, size), None);
+        assert_eq!(
+            reads.start_read(1, 0x20000, 0x40000, size),
+            Some((0x20000, 0x120000))
+        );
+        reads.fetch_done(1);
+
+        // Files are tracked separately.
+        assert_eq!(reads.start_read(2, 0x20000, 0x40000, size), None);
+        reads.clear();
+        assert_eq!(reads.start_read(1, 0x40000, 0x60000, size), None);
+    }
+
+    #[test]
+    fn it_should_adapt_readahead_window() {
+        let max = 0x100000;
+        let size = 0x1000000;
+        let mut window = ReadaheadWindow::default();
+
+        // Reading from the start of a file is sequential, the window doubles as it goes.
+        assert_eq!(
+            window.advance(0, 0x20000, size, max),
+            Some((0x20000, 0x40000))
+        );
+        assert_eq!(
+            window.advance(0x20000, 0x40000, size, max),
+            Some((0x40000, 0x80000))
+        );
+        assert_eq!(
+            window.advance(0x40000, 0x60000, size, max),
+            Some((0x80000, 0xe0000))
+        );
+        assert_eq!(
+            window.advance(0x60000, 0x80000, size, max),
+            Some((0xe0000, 0x180000))
+        );
+        assert_eq!(window.size, max);
+        // Not fetched again until half of the window is consumed.
+        assert_eq!(window.advance(0x80000, 0xa0000, size, max), None);
+        assert_eq!(
+            window.advance(0xa0000, 0x110000, size, max),
+            Some((0x180000, 0x210000))
+        );
+
+        // Random reads shrink the window until it's closed.
+        assert_eq!(
+            window.advance(0x800000, 0x820000, size, max),
+            Some((0x820000, 0x860000))
+        );
+        assert_eq!(window.size, max / 4);
+        assert_eq!(
+            window.advance(0x820000, 0x840000, size, max),
+            Some((0x860000, 0x8c0000))
+        );
+        assert_eq!(
+            window.advance(0x10000, 0x20000, size, max),
+            Some((0x20000, 0x40000))
+        );
+        assert_eq!(window.advance(0x400000, 0x410000, size, max), None);
+        assert_eq!(window.size, 0);
+
+        // Never beyond end of file.
+        assert_eq!(
+            window.advance(0x410000, 0x420000, 0x430000, max),
+            Some((0x420000, 0x430000))
+        );
+        assert_eq!(window.advance(0x420000, 0x430000, 0x430000, max), None);
+    }
 }
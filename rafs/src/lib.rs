@@ -3,6 +3,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
 //! A readonly filesystem with separated bootstrap and data, to support on-demand loading.
+//!
+//! Besides serving as a FUSE filesystem through [`fs::Rafs`], images can be consumed as a
+//! library through [`reader::RafsReader`], which opens a bootstrap, resolves paths and reads
+//! file data from blobs. [`metadata::RafsSuper`] and [`metadata::RafsInode`] give access to
+//! image metadata, while the on-disk layout in [`metadata::layout`] is subject to change.
 
 #[macro_use]
 extern crate log;
@@ -20,6 +25,9 @@ use crate::metadata::layout::{align_to_rafs, RAFS_ALIGNMENT};
 
 pub mod fs;
 pub mod metadata;
+pub mod reader;
+pub mod signature;
+pub mod trace;
 #[macro_use]
 extern crate storage;
 
@@ -36,6 +44,7 @@ pub enum RafsError {
     CreateDevice(Error),
     Prefetch(String),
     Configure(String),
+    VerifySignature(Error),
 }
 
 pub type RafsResult<T> = std::result::Result<T, RafsError>;
@@ -7,12 +7,19 @@
 //!
 //! All file system bootstrap will be loaded, validated and cached into memory when loading the
 //! file system. And currently the cache layer only supports readonly file systems.
+//!
+//! Chunk information takes most of the memory for huge images, so it can optionally be loaded
+//! from bootstrap on demand, with the number of chunks kept in memory bounded by LRU. So can
+//! inodes, which are then found through the inode table of bootstrap.
 
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
+use std::fs::File;
 use std::io::{ErrorKind, Read, Result};
 use std::mem::size_of;
-use std::sync::Arc;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
 
 use fuse_rs::abi::linux_abi;
 use fuse_rs::api::filesystem::Entry;
@@ -22,20 +29,41 @@ use crate::metadata::*;
 use crate::RafsIoReader;
 
 use nydus_utils::{digest::RafsDigest, ByteSize};
+use storage::utils::hash_table_bytes;
+
+/// Max number of shards of inodes and chunk infos loaded on demand.
+const LRU_SHARDS: usize = 16;
+/// Min weight bound of a shard, smaller caches are split into fewer shards.
+const LRU_SHARD_MIN_LIMIT: usize = 64;
 
 pub struct CachedInodes {
     s_blob: Arc<OndiskBlobTable>,
     s_meta: Arc<RafsSuperMeta>,
     s_inodes: BTreeMap<Inode, Arc<CachedInode>>,
+    s_loader: Option<Arc<CachedInodeLoader>>,
+    s_chunks: Option<Arc<CachedChunks>>,
+    max_inodes: usize,
+    max_chunks: usize,
     digest_validate: bool,
 }
 
 impl CachedInodes {
-    pub fn new(meta: RafsSuperMeta, digest_validate: bool) -> Self {
+    /// `max_inodes` and `max_chunks` bound number of inodes and chunk infos kept in memory,
+    /// 0 means loading all of them.
+    pub fn new(
+        meta: RafsSuperMeta,
+        digest_validate: bool,
+        max_inodes: usize,
+        max_chunks: usize,
+    ) -> Self {
         CachedInodes {
             s_blob: Arc::new(OndiskBlobTable::new()),
             s_inodes: BTreeMap::new(),
             s_meta: Arc::new(meta),
+            s_loader: None,
+            s_chunks: None,
+            max_inodes,
+            max_chunks,
             digest_validate,
         }
     }
@@ -51,6 +79,7 @@ impl CachedInodes {
                 break;
             }
             let mut inode = CachedInode::new(self.s_blob.clone(), self.s_meta.clone());
+            inode.i_chunks = self.s_chunks.clone();
             match inode.load(&self.s_meta, r) {
                 Ok(_) => {
                     entries += 1;
@@ -100,7 +129,7 @@ impl CachedInodes {
     fn hash_inode(&mut self, inode: Arc<CachedInode>) -> Result<Arc<CachedInode>> {
         if inode.is_hardlink() {
             if let Some(i) = self.s_inodes.get(&inode.i_ino) {
-                if !i.i_data.is_empty() {
+                if i.has_chunk_info() {
                     return Ok(inode);
                 }
             }
@@ -151,6 +180,30 @@ impl RafsSuperInodes for CachedInodes {
 
         self.s_blob = Arc::new(blob_table);
 
+        if self.max_chunks > 0 {
+            // Keep a private handle of bootstrap to reload chunk infos on demand.
+            let file = dup_bootstrap(r)?;
+            self.s_chunks = Some(Arc::new(CachedChunks::new(file, self.max_chunks)));
+        }
+
+        if self.max_inodes > 0 {
+            // Inodes are found by the inode table and loaded on demand, and validated on
+            // demand as direct mode does, instead of walking through all of them here.
+            r.seek(SeekFrom::Start(self.s_meta.inode_table_offset))?;
+            let mut inode_table = OndiskInodeTable::new(self.s_meta.inode_table_entries as usize);
+            inode_table.load(r)?;
+            let reader = Box::new(dup_bootstrap(r)?) as RafsIoReader;
+            self.s_loader = Some(Arc::new(CachedInodeLoader {
+                reader: Mutex::new(reader),
+                inode_table,
+                blob_table: self.s_blob.clone(),
+                meta: self.s_meta.clone(),
+                chunks: self.s_chunks.clone(),
+                inodes: ShardedLru::new(self.max_inodes),
+            }));
+            return Ok(());
+        }
+
         // Load all inodes started from first inode offset.
         r.seek(SeekFrom::Start(inode_offset as u64))?;
         self.load_all_inodes(r)?;
@@ -168,25 +221,62 @@ impl RafsSuperInodes for CachedInodes {
 
     fn destroy(&mut self) {
         self.s_inodes.clear();
+        self.s_loader = None;
     }
 
-    fn get_inode(&self, ino: Inode, _digest_validate: bool) -> Result<Arc<dyn RafsInode>> {
+    fn get_inode(&self, ino: Inode, digest_validate: bool) -> Result<Arc<dyn RafsInode>> {
+        if let Some(loader) = self.s_loader.as_ref() {
+            let inode = loader.get(ino)? as Arc<dyn RafsInode>;
+            let digester = self.s_meta.get_digester();
+            if digest_validate && !self.digest_validate(inode.clone(), false, digester)? {
+                return Err(einval!("invalid inode digest"));
+            }
+            return Ok(inode);
+        }
+
         self.s_inodes
             .get(&ino)
             .map_or(Err(enoent!()), |i| Ok(i.clone()))
     }
 
     fn get_max_ino(&self) -> u64 {
-        self.s_inodes.len() as u64
+        match self.s_loader.as_ref() {
+            Some(loader) => loader.inode_table.len() as u64,
+            None => self.s_inodes.len() as u64,
+        }
     }
 
     fn get_blob_table(&self) -> Arc<OndiskBlobTable> {
         self.s_blob.clone()
     }
 
-    fn update(&self, _r: &mut RafsIoReader) -> RafsResult<()> {
+    fn update(&self, _r: &mut RafsIoReader, _meta: &RafsSuperMeta) -> RafsResult<()> {
         Err(RafsError::Unsupported)
     }
+
+    /// Walk through all inodes, which takes a while for huge images.
+    fn memory_usage(&self) -> RafsMetaMemoryUsage {
+        let mut usage = self
+            .s_loader
+            .as_ref()
+            .map_or_else(RafsMetaMemoryUsage::default, |l| l.memory_usage());
+        usage.metadata_bytes += self.s_inodes.len() * size_of::<(Inode, Arc<CachedInode>)>();
+        usage.chunk_info_bytes += self.s_chunks.as_ref().map_or(0, |c| c.memory_usage());
+        for inode in self.s_inodes.values() {
+            usage.metadata_bytes += inode.metadata_bytes();
+            usage.chunk_info_bytes += chunk_infos_bytes(inode.i_data.len());
+        }
+
+        usage
+    }
+}
+
+/// Approximate bytes of memory taken by `count` chunk infos of a file.
+fn chunk_infos_bytes(count: usize) -> usize {
+    count
+        * (size_of::<Arc<CachedChunkInfo>>()
+            + size_of::<CachedChunkInfo>()
+            + size_of::<RafsDigest>())
 }
 
 #[derive(Default, Clone, Debug)]
@@ -208,15 +298,40 @@ pub struct CachedInode {
     // extra info need cache
     i_blksize: u32,
     i_rdev: u32,
+    i_mtime_nsec: u32,
+    i_mtime: u64,
+    i_ctime_nsec: u32,
+    i_ctime: u64,
     i_target: OsString, // for symbol link
     i_xattr: HashMap<OsString, Vec<u8>>,
     i_data: Vec<Arc<CachedChunkInfo>>,
+    // bootstrap offset of chunk infos loaded on demand, 0 if they are kept in `i_data`
+    i_chunk_offset: u64,
+    i_chunks: Option<Arc<CachedChunks>>,
+    // children are loaded on demand by the loader if inodes are bounded, otherwise kept here
+    i_loader: Weak<CachedInodeLoader>,
     i_child: Vec<Arc<CachedInode>>,
     i_blob_table: Arc<OndiskBlobTable>,
     i_meta: Arc<RafsSuperMeta>,
 }
 
 impl CachedInode {
+    /// Approximate bytes of memory taken by the inode, chunk infos excluded.
+    fn metadata_bytes(&self) -> usize {
+        let xattrs = self
+            .i_xattr
+            .iter()
+            .map(|(k, v)| k.len() + v.len())
+            .sum::<usize>()
+            + hash_table_bytes::<(OsString, Vec<u8>)>(self.i_xattr.capacity());
+
+        size_of::<CachedInode>()
+            + self.i_name.len()
+            + self.i_target.len()
+            + xattrs
+            + self.i_child.capacity() * size_of::<Arc<CachedInode>>()
+    }
+
     pub fn new(blob_table: Arc<OndiskBlobTable>, meta: Arc<RafsSuperMeta>) -> Self {
         CachedInode {
             i_blob_table: blob_table,
@@ -260,7 +375,12 @@ impl CachedInode {
     }
 
     fn load_chunk_info(&mut self, r: &mut RafsIoReader) -> Result<()> {
-        if self.is_reg() && self.i_child_cnt > 0 {
+        if self.is_reg() && self.i_child_cnt > 0 && self.i_chunks.is_some() {
+            // Just remember where chunk infos are and skip them.
+            self.i_chunk_offset = r.seek(SeekFrom::Current(0))?;
+            let size = self.i_child_cnt as i64 * size_of::<OndiskChunkInfo>() as i64;
+            r.seek(SeekFrom::Current(size))?;
+        } else if self.is_reg() && self.i_child_cnt > 0 {
             let mut chunk = OndiskChunkInfo::new();
             for _i in 0..self.i_child_cnt {
                 chunk.load(r)?;
@@ -303,6 +423,30 @@ impl CachedInode {
         self.i_child_idx = inode.i_child_index;
         self.i_child_cnt = inode.i_child_count;
         self.i_rdev = inode.i_rdev;
+        self.i_mtime_nsec = inode.i_mtime_nsec;
+        self.i_mtime = inode.i_mtime;
+        let (ctime, ctime_nsec) = inode.ctime();
+        self.i_ctime = ctime;
+        self.i_ctime_nsec = ctime_nsec;
+    }
+
+    fn has_chunk_info(&self) -> bool {
+        !self.i_data.is_empty() || self.i_chunk_offset != 0
+    }
+
+    /// Child of index `idx`, loaded on demand if inodes are bounded.
+    fn child(&self, idx: u32) -> Result<Arc<CachedInode>> {
+        if idx >= self.i_child_cnt {
+            return Err(enoent!("invalid child index"));
+        }
+        match self.i_loader.upgrade() {
+            Some(loader) => loader.get(self.i_child_idx as u64 + idx as u64),
+            None => self
+                .i_child
+                .get(idx as usize)
+                .cloned()
+                .ok_or_else(|| enoent!("invalid child index")),
+        }
     }
 
     fn add_child(&mut self, child: Arc<CachedInode>) {
@@ -336,16 +480,34 @@ impl RafsInode for CachedInode {
     }
 
     fn get_child_by_name(&self, name: &OsStr) -> Result<Arc<dyn RafsInode>> {
-        let idx = self
-            .i_child
-            .binary_search_by(|c| c.i_name.as_os_str().cmp(name))
-            .map_err(|_| enoent!())?;
-        Ok(self.i_child[idx].clone())
+        if self.i_loader.upgrade().is_none() {
+            let idx = self
+                .i_child
+                .binary_search_by(|c| c.i_name.as_os_str().cmp(name))
+                .map_err(|_| enoent!())?;
+            return Ok(self.i_child[idx].clone());
+        }
+
+        // Children are sorted by name in bootstrap.
+        let (mut first, mut last) = (0, self.i_child_cnt);
+        while first < last {
+            let pivot = first + (last - first) / 2;
+            let child = self.child(pivot)?;
+            match child.i_name.as_os_str().cmp(name) {
+                std::cmp::Ordering::Equal => return Ok(child),
+                std::cmp::Ordering::Less => first = pivot + 1,
+                std::cmp::Ordering::Greater => last = pivot,
+            }
+        }
+        Err(enoent!())
     }
 
     #[inline]
     fn get_child_by_index(&self, index: Inode) -> Result<Arc<dyn RafsInode>> {
-        Ok(self.i_child[index as usize].clone())
+        if index >= self.i_child_cnt as u64 {
+            return Err(enoent!("invalid child index"));
+        }
+        Ok(self.child(index as u32)?)
     }
 
     #[inline]
@@ -365,6 +527,15 @@ impl RafsInode for CachedInode {
 
     #[inline]
     fn get_chunk_info(&self, idx: u32) -> Result<Arc<dyn RafsChunkInfo>> {
+        if self.i_chunk_offset != 0 {
+            if let Some(chunks) = self.i_chunks.as_ref() {
+                let data = chunks.get(self.i_ino, self.i_chunk_offset, self.i_child_cnt)?;
+                return data
+                    .get(idx as usize)
+                    .map(|c| c.clone() as Arc<dyn RafsChunkInfo>)
+                    .ok_or_else(|| einval!("invalid chunk index"));
+            }
+        }
         Ok(self.i_data[idx as usize].clone())
     }
 
@@ -394,6 +565,10 @@ impl RafsInode for CachedInode {
             nlink: self.i_nlink as u32,
             blksize: RAFS_INODE_BLOCKSIZE,
             rdev: self.i_rdev,
+            mtime: self.i_mtime,
+            mtimensec: self.i_mtime_nsec,
+            ctime: self.i_ctime,
+            ctimensec: self.i_ctime_nsec,
             ..Default::default()
         }
     }
@@ -449,15 +624,21 @@ impl RafsInode for CachedInode {
 
         let mut child_dirs: Vec<Arc<dyn RafsInode>> = Vec::new();
 
-        for child_inode in &self.i_child {
+        let children = match self.i_loader.upgrade() {
+            Some(_) => (0..self.i_child_cnt)
+                .map(|idx| self.child(idx))
+                .collect::<Result<Vec<_>>>()?,
+            None => self.i_child.clone(),
+        };
+        for child_inode in children {
             if child_inode.is_dir() {
                 trace!("Got dir {:?}", child_inode.name());
-                child_dirs.push(child_inode.clone());
+                child_dirs.push(child_inode);
             } else {
                 if child_inode.is_empty_size() {
                     continue;
                 }
-                descendants.push(child_inode.clone());
+                descendants.push(child_inode);
             }
         }
 
@@ -474,7 +655,7 @@ impl RafsInode for CachedInode {
         } else {
             0
         };
-        Ok(OndiskInode {
+        let mut inode = OndiskInode {
             i_digest: self.i_digest,
             i_parent: self.i_parent,
             i_ino: self.i_ino,
@@ -491,8 +672,13 @@ impl RafsInode for CachedInode {
             i_name_size: self.i_name.len() as u16,
             i_symlink_size,
             i_rdev: self.i_rdev,
-            i_reserved: [0; 20],
-        })
+            i_mtime_nsec: self.i_mtime_nsec,
+            i_mtime: self.i_mtime,
+            i_ctime: 0,
+        };
+        inode.set_ctime(self.i_ctime, self.i_ctime_nsec);
+
+        Ok(inode)
     }
 
     impl_getter!(ino, i_ino, u64);
@@ -501,6 +687,209 @@ impl RafsInode for CachedInode {
     impl_getter!(rdev, i_rdev, u32);
 }
 
+/// Values keyed by inode number, with the total weight of them bounded by evicting the least
+/// recently used ones.
+#[derive(Debug)]
+struct Lru<V> {
+    tick: u64,
+    weight: usize,
+    entries: HashMap<Inode, (u64, usize, V)>,
+    /// Access tick to inode number, the first one is the least recently used.
+    lru: BTreeMap<u64, Inode>,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new() -> Self {
+        Lru {
+            tick: 0,
+            weight: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, ino: Inode) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&ino)?;
+        self.lru.remove(&entry.0);
+        self.lru.insert(tick, ino);
+        entry.0 = tick;
+        Some(entry.2.clone())
+    }
+
+    /// Insert `value` of `weight`, evicting least recently used ones until the total weight is
+    /// no more than `limit`, but always keep the one just inserted.
+    fn insert(&mut self, ino: Inode, value: V, weight: usize, limit: usize) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((old_tick, old_weight, _)) = self.entries.insert(ino, (tick, weight, value)) {
+            self.lru.remove(&old_tick);
+            self.weight -= old_weight;
+        }
+        self.lru.insert(tick, ino);
+        self.weight += weight;
+
+        while self.weight > limit {
+            let (oldest_tick, oldest_ino) = match self.lru.iter().next() {
+                Some((t, i)) if *i != ino => (*t, *i),
+                _ => break,
+            };
+            self.lru.remove(&oldest_tick);
+            if let Some((_, weight, _)) = self.entries.remove(&oldest_ino) {
+                self.weight -= weight;
+            }
+        }
+    }
+
+    fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|(_, _, v)| v)
+    }
+
+    /// Approximate bytes of memory taken by the bookkeeping, values excluded.
+    fn memory_usage(&self) -> usize {
+        hash_table_bytes::<(Inode, (u64, usize, V))>(self.entries.capacity())
+            + self.lru.len() * size_of::<(u64, Inode)>()
+    }
+}
+
+/// `Lru` split into shards by inode number, each bounded by its share of the total weight, so
+/// that lookups of different inodes from many threads don't contend on a single lock.
+#[derive(Debug)]
+struct ShardedLru<V> {
+    shards: Vec<Mutex<Lru<V>>>,
+    // weight bound of each shard
+    limit: usize,
+}
+
+impl<V: Clone> ShardedLru<V> {
+    fn new(limit: usize) -> Self {
+        let count = std::cmp::max(1, std::cmp::min(limit / LRU_SHARD_MIN_LIMIT, LRU_SHARDS));
+        ShardedLru {
+            shards: (0..count).map(|_| Mutex::new(Lru::new())).collect(),
+            limit: limit / count,
+        }
+    }
+
+    fn shard(&self, ino: Inode) -> MutexGuard<Lru<V>> {
+        self.shards[ino as usize % self.shards.len()]
+            .lock()
+            .unwrap()
+    }
+
+    fn get(&self, ino: Inode) -> Option<V> {
+        self.shard(ino).get(ino)
+    }
+
+    fn insert(&self, ino: Inode, value: V, weight: usize) {
+        self.shard(ino).insert(ino, value, weight, self.limit)
+    }
+
+    /// Sum up `f` of all shards, each locked in turn.
+    fn sum<F: Fn(&Lru<V>) -> usize>(&self, f: F) -> usize {
+        self.shards.iter().map(|s| f(&s.lock().unwrap())).sum()
+    }
+}
+
+/// Dup the file descriptor of bootstrap, to read from it on demand with a private handle.
+fn dup_bootstrap(r: &RafsIoReader) -> Result<File> {
+    let fd = unsafe { libc::dup(r.as_raw_fd()) };
+    if fd < 0 {
+        return Err(last_error!("failed to dup bootstrap file fd"));
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Inodes loaded from bootstrap on demand, with the number of them kept in memory bounded by
+/// LRU. Inodes hold a weak reference to it to load their children.
+pub struct CachedInodeLoader {
+    reader: Mutex<RafsIoReader>,
+    inode_table: OndiskInodeTable,
+    blob_table: Arc<OndiskBlobTable>,
+    meta: Arc<RafsSuperMeta>,
+    chunks: Option<Arc<CachedChunks>>,
+    inodes: ShardedLru<Arc<CachedInode>>,
+}
+
+impl CachedInodeLoader {
+    /// Get inode `ino`, which is the index of it in the inode table. Hardlinks take their own
+    /// entries in the inode table, but carry the inode number of the first one.
+    fn get(self: &Arc<Self>, ino: Inode) -> Result<Arc<CachedInode>> {
+        if let Some(inode) = self.inodes.get(ino) {
+            return Ok(inode);
+        }
+
+        let offset = self.inode_table.get(ino)?;
+        let mut inode = CachedInode::new(self.blob_table.clone(), self.meta.clone());
+        inode.i_chunks = self.chunks.clone();
+        inode.i_loader = Arc::downgrade(self);
+        {
+            let mut r = self.reader.lock().unwrap();
+            r.seek(SeekFrom::Start(offset as u64))?;
+            inode.load(&self.meta, &mut *r)?;
+        }
+        let inode = Arc::new(inode);
+        self.inodes.insert(ino, inode.clone(), 1);
+        Ok(inode)
+    }
+
+    fn memory_usage(&self) -> RafsMetaMemoryUsage {
+        RafsMetaMemoryUsage {
+            metadata_bytes: self.inode_table.size()
+                + self.inodes.sum(|lru| {
+                    lru.memory_usage() + lru.values().map(|i| i.metadata_bytes()).sum::<usize>()
+                }),
+            chunk_info_bytes: self.inodes.sum(|lru| {
+                lru.values()
+                    .map(|i| chunk_infos_bytes(i.i_data.len()))
+                    .sum()
+            }),
+        }
+    }
+}
+
+/// Chunk infos of regular files loaded from bootstrap on demand.
+#[derive(Debug)]
+pub struct CachedChunks {
+    file: File,
+    state: ShardedLru<Arc<Vec<Arc<CachedChunkInfo>>>>,
+}
+
+impl CachedChunks {
+    fn new(file: File, limit: usize) -> Self {
+        CachedChunks {
+            file,
+            state: ShardedLru::new(limit),
+        }
+    }
+
+    /// Get all `count` chunk infos of inode `ino` stored at bootstrap `offset`.
+    fn get(&self, ino: Inode, offset: u64, count: u32) -> Result<Arc<Vec<Arc<CachedChunkInfo>>>> {
+        if let Some(data) = self.state.get(ino) {
+            return Ok(data);
+        }
+
+        // Read without the shard locked, racing readers of the same inode just load it twice.
+        let mut buf = vec![0u8; count as usize * size_of::<OndiskChunkInfo>()];
+        self.file.read_exact_at(&mut buf, offset)?;
+        let mut data = Vec::with_capacity(count as usize);
+        let mut chunk = OndiskChunkInfo::new();
+        for raw in buf.chunks_exact(size_of::<OndiskChunkInfo>()) {
+            chunk.as_mut().copy_from_slice(raw);
+            data.push(Arc::new(CachedChunkInfo::from(&chunk)));
+        }
+        let data = Arc::new(data);
+        self.state.insert(ino, data.clone(), count as usize);
+
+        Ok(data)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.state
+            .sum(|lru| chunk_infos_bytes(lru.weight) + lru.memory_usage())
+    }
+}
+
 /// Cached information about an Rafs Data Chunk.
 #[derive(Clone, Default, Debug)]
 pub struct CachedChunkInfo {
@@ -583,20 +972,27 @@ impl From<&OndiskChunkInfo> for CachedChunkInfo {
 
 #[cfg(test)]
 mod cached_tests {
-    use crate::metadata::cached::CachedInode;
+    use crate::metadata::cached::{
+        chunk_infos_bytes, CachedChunks, CachedInode, ShardedLru, LRU_SHARDS, LRU_SHARD_MIN_LIMIT,
+    };
     use crate::metadata::layout::{
         OndiskBlobTable, OndiskChunkInfo, OndiskInode, OndiskInodeWrapper, XAttrs,
     };
-    use crate::metadata::{align_to_rafs, RafsInode, RafsStore, RafsSuperMeta};
+    use crate::metadata::{
+        align_to_rafs, RafsChunkInfo, RafsInode, RafsMode, RafsStore, RafsSuper, RafsSuperMeta,
+        RAFS_ROOT_INODE,
+    };
     use crate::{RafsIoReader, RafsIoWriter};
     use nydus_utils::ByteSize;
     use std::cmp;
     use std::ffi::{OsStr, OsString};
-    use std::fs::OpenOptions;
+    use std::fs::{File, OpenOptions};
     use std::io::Seek;
     use std::io::SeekFrom::Start;
     use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
     use std::sync::Arc;
+    use vmm_sys_util::tempfile::TempFile;
 
     #[test]
     fn test_load_inode() {
@@ -741,7 +1137,7 @@ mod cached_tests {
         let mut blob_table = Arc::new(OndiskBlobTable::new());
         Arc::get_mut(&mut blob_table)
             .unwrap()
-            .add(String::from("123333"), 0, 0, 0, 0);
+            .add(String::from("123333"), 0, 0, 0, 0, 0);
         let mut cached_inode = CachedInode::new(blob_table, meta.clone());
         cached_inode.load(&meta, &mut reader).unwrap();
         let desc1 = cached_inode.alloc_bio_desc(0, 100).unwrap();
@@ -768,4 +1164,138 @@ mod cached_tests {
         drop(f);
         std::fs::remove_file("/tmp/buf_3").unwrap();
     }
+
+    #[test]
+    fn test_load_chunks_on_demand() {
+        let tmp_file = TempFile::new().unwrap();
+        let f = tmp_file.as_file();
+        let mut writer = Box::new(f.try_clone().unwrap()) as RafsIoWriter;
+        for idx in 0..3 {
+            let mut chunk = OndiskChunkInfo::new();
+            chunk.index = idx;
+            chunk.compress_offset = idx as u64 * 4096;
+            chunk.store(&mut writer).unwrap();
+        }
+
+        let chunk_size = std::mem::size_of::<OndiskChunkInfo>() as u64;
+        let chunks = CachedChunks::new(f.try_clone().unwrap(), 2);
+        assert_eq!(chunks.memory_usage(), 0);
+        let data = chunks.get(1, 0, 2).unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[1].index(), 1);
+        assert_eq!(data[1].compress_offset(), 4096);
+        let usage = chunks.memory_usage();
+        assert!(usage >= chunk_infos_bytes(2));
+
+        // Loading chunks of inode 2 exceeds the limit, so chunks of inode 1 get evicted.
+        let data = chunks.get(2, 2 * chunk_size, 1).unwrap();
+        assert_eq!(data[0].index(), 2);
+        {
+            let state = chunks.state.shard(1);
+            assert_eq!(state.weight, 1);
+            assert!(!state.entries.contains_key(&1));
+        }
+        assert!(chunks.memory_usage() < usage);
+        // And reloaded on demand.
+        assert_eq!(chunks.get(1, 0, 2).unwrap()[0].index(), 0);
+    }
+
+    #[test]
+    fn test_sharded_lru() {
+        // Small caches are kept in a single shard, so that they're bounded exactly.
+        assert_eq!(ShardedLru::<u64>::new(2).shards.len(), 1);
+
+        let limit = LRU_SHARD_MIN_LIMIT * LRU_SHARDS * 2;
+        let lru = ShardedLru::new(limit);
+        assert_eq!(lru.shards.len(), LRU_SHARDS);
+        for ino in 0..limit as u64 * 2 {
+            lru.insert(ino, ino, 1);
+        }
+        assert_eq!(lru.sum(|l| l.weight), limit);
+        assert_eq!(lru.get(0), None);
+        assert_eq!(lru.get(limit as u64 * 2 - 1), Some(limit as u64 * 2 - 1));
+    }
+
+    /// Paths, attributes and chunks of all inodes under `inode`, by walking through the tree.
+    fn walk(inode: Arc<dyn RafsInode>, path: PathBuf, files: &mut Vec<String>) {
+        let chunks = if inode.is_reg() {
+            (0..inode.get_child_count())
+                .map(|idx| inode.get_chunk_info(idx).unwrap().compress_offset())
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+        let attr = inode.get_attr();
+        files.push(format!(
+            "{:?} ino {} mode {:o} size {} chunks {:?}",
+            path, attr.ino, attr.mode, attr.size, chunks
+        ));
+        if inode.is_dir() {
+            for idx in 0..inode.get_child_count() {
+                let child = inode.get_child_by_index(idx as u64).unwrap();
+                let name = child.name();
+                let found = inode.get_child_by_name(&name).unwrap();
+                assert_eq!(found.ino(), child.ino());
+                walk(child, path.join(name), files);
+            }
+            assert!(inode.get_child_by_name(OsStr::new("no-such-file")).is_err());
+        }
+    }
+
+    fn load_texture(max_inodes: usize, max_chunks: usize) -> RafsSuper {
+        let mut path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+        path.push("../tests/texture/bootstrap/image_v2.boot");
+        let mut r = Box::new(File::open(path).unwrap()) as RafsIoReader;
+        let mut rs = RafsSuper {
+            mode: RafsMode::Cached,
+            max_cached_inodes: max_inodes,
+            max_cached_chunks: max_chunks,
+            ..Default::default()
+        };
+        rs.load(&mut r).unwrap();
+        rs
+    }
+
+    #[test]
+    fn test_load_inodes_on_demand() {
+        let rs = load_texture(0, 0);
+        let mut expected = Vec::new();
+        walk(
+            rs.get_inode(RAFS_ROOT_INODE, false).unwrap(),
+            PathBuf::from("/"),
+            &mut expected,
+        );
+        assert!(expected.len() > 4);
+
+        let bounded = load_texture(2, 0);
+        assert_eq!(bounded.inodes.memory_usage().chunk_info_bytes, 0);
+        let mut files = Vec::new();
+        walk(
+            bounded.get_inode(RAFS_ROOT_INODE, false).unwrap(),
+            PathBuf::from("/"),
+            &mut files,
+        );
+        assert_eq!(files, expected);
+        assert!(bounded.get_inode(RAFS_ROOT_INODE, true).is_ok());
+
+        // Only the most recently used inodes are kept, along with their chunk infos.
+        let usage = bounded.inodes.memory_usage();
+        let (max_usage, chunk_usage) = {
+            let rs = load_texture(0, 0);
+            let usage = rs.inodes.memory_usage();
+            (usage.metadata_bytes, usage.chunk_info_bytes)
+        };
+        assert!(usage.metadata_bytes > 0 && usage.metadata_bytes < max_usage);
+        assert!(usage.chunk_info_bytes <= chunk_usage);
+
+        // Chunk infos are bounded on their own too.
+        let bounded = load_texture(2, 1);
+        let mut files = Vec::new();
+        walk(
+            bounded.get_inode(RAFS_ROOT_INODE, false).unwrap(),
+            PathBuf::from("/"),
+            &mut files,
+        );
+        assert_eq!(files, expected);
+    }
 }
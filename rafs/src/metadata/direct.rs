@@ -17,6 +17,11 @@
 /// The bootstrap file may be provided by untrusted parties, so we must ensure strong validations
 /// before making use of any bootstrap, especially we are using them in memory-mapped mode. The
 /// rule is to call validate() after creating any data structure from the on-disk bootstrap.
+///
+/// # Lazy Loading
+/// Only the superblock, the inode table, the blob table and the root directory are read when
+/// mounting. Other inodes are faulted in from the mapping as they get looked up, children of a
+/// directory at once on the first lookup in it, so subtrees never accessed are never read.
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::Result;
@@ -24,9 +29,10 @@ use std::mem::{size_of, ManuallyDrop};
 use std::ops::Deref;
 use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
 use std::slice;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
 
-use arc_swap::{ArcSwap, Guard};
+use arc_swap::ArcSwap;
 
 use crate::metadata::layout::*;
 use crate::metadata::*;
@@ -34,6 +40,9 @@ use storage::utils::readahead;
 
 use nydus_utils::digest::RafsDigest;
 
+/// Most bytes of a directory's children to read ahead on the first lookup in it.
+const DIR_READAHEAD_MAX_SIZE: u64 = 0x40_0000;
+
 /// Impl get accessor for inode object.
 macro_rules! impl_inode_getter {
     ($G: ident, $F: ident, $U: ty) => {
@@ -72,6 +81,34 @@ macro_rules! impl_chunkinfo_getter {
     };
 }
 
+/// A bit for each inode in the inode table.
+#[derive(Default)]
+struct InodeBitmap {
+    bits: Vec<AtomicU8>,
+}
+
+impl InodeBitmap {
+    fn new(entries: usize) -> Self {
+        InodeBitmap {
+            bits: (0..(entries + 7) / 8).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    fn is_set(&self, ino: Inode) -> bool {
+        let idx = ino.wrapping_sub(1) as usize;
+        self.bits
+            .get(idx >> 3)
+            .map_or(false, |b| b.load(Ordering::Acquire) & (1 << (idx & 7)) != 0)
+    }
+
+    fn set(&self, ino: Inode) {
+        let idx = ino.wrapping_sub(1) as usize;
+        if let Some(b) = self.bits.get(idx >> 3) {
+            b.fetch_or(1 << (idx & 7), Ordering::AcqRel);
+        }
+    }
+}
+
 /// The underlying struct to maintain memory mapped bootstrap for a file system.
 ///
 /// Only the DirectMappingState may store raw pointers.
@@ -89,6 +126,10 @@ struct DirectMappingState {
     fd: RawFd,
     mmapped_inode_table: bool,
     digest_validate: bool,
+    // inodes passed validate()
+    validated: Arc<InodeBitmap>,
+    // directories whose children have been read ahead
+    children_read: Arc<InodeBitmap>,
 }
 
 impl DirectMappingState {
@@ -103,6 +144,8 @@ impl DirectMappingState {
             size: 0,
             mmapped_inode_table: false,
             digest_validate,
+            validated: Arc::new(InodeBitmap::default()),
+            children_read: Arc::new(InodeBitmap::default()),
         }
     }
 
@@ -134,6 +177,53 @@ impl DirectMappingState {
 
         Ok(())
     }
+
+    /// Read ahead the children of directory `dir` on the first lookup in it, they are laid out
+    /// next to each other in the bootstrap and would be faulted in one page at a time otherwise.
+    fn read_children(&self, dir: &OndiskInode) {
+        if dir.i_child_count == 0 || self.children_read.is_set(dir.i_ino) {
+            return;
+        }
+        self.children_read.set(dir.i_ino);
+
+        let first = dir.i_child_index as u64;
+        let last = first + dir.i_child_count as u64 - 1;
+        if let (Ok(start), Ok(end)) = (self.inode_table.get(first), self.inode_table.get(last)) {
+            let start = start as u64;
+            let end = std::cmp::min(
+                end as u64 + (size_of::<OndiskInode>() + RAFS_MAX_NAME) as u64,
+                self.size as u64,
+            );
+            if start < end {
+                readahead(
+                    self.fd,
+                    start,
+                    std::cmp::min(end, start + DIR_READAHEAD_MAX_SIZE),
+                );
+            }
+        }
+    }
+
+    /// Bytes of the bootstrap faulted in so far.
+    fn resident_size(&self) -> usize {
+        if self.base.is_null() {
+            return 0;
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut pages = vec![0u8; (self.size + page_size - 1) / page_size];
+        if unsafe {
+            libc::mincore(
+                self.base as *mut libc::c_void,
+                self.size,
+                pages.as_mut_ptr(),
+            )
+        } != 0
+        {
+            return self.size;
+        }
+
+        pages.iter().filter(|p| *p & 1 != 0).count() * page_size
+    }
 }
 
 impl Drop for DirectMappingState {
@@ -157,15 +247,15 @@ impl Drop for DirectMappingState {
     }
 }
 
+// Safe to Send/Sync because the mapped bootstrap is readonly and only unmapped on drop.
+unsafe impl Send for DirectMappingState {}
+unsafe impl Sync for DirectMappingState {}
+
 #[derive(Clone)]
 pub struct DirectMapping {
     state: ArcSwap<DirectMappingState>,
 }
 
-// Safe to Send/Sync because the underlying data structures are readonly
-unsafe impl Send for DirectMapping {}
-unsafe impl Sync for DirectMapping {}
-
 impl DirectMapping {
     pub fn new(meta: &RafsSuperMeta, digest_validate: bool) -> Self {
         let state = DirectMappingState::new(meta, digest_validate);
@@ -175,27 +265,32 @@ impl DirectMapping {
         }
     }
 
+    /// Get inode `ino` of the bootstrap mapped by `state`.
+    ///
+    /// The inode holds `state`, so it keeps reading from the same bootstrap after switching to
+    /// a new one, which is only unmapped when the last inode of it gets dropped.
     #[inline]
     fn get_inode_wrapper(
-        &self,
+        state: &Arc<DirectMappingState>,
         ino: Inode,
-        state: &DirectMappingState,
     ) -> Result<OndiskInodeWrapper> {
         let offset = state.inode_table.get(ino)? as usize;
         let _inode = state.cast_to_ref::<OndiskInode>(state.base, offset)?;
         let wrapper = OndiskInodeWrapper {
-            mapping: self.clone(),
+            state: state.clone(),
             offset,
         };
 
-        // TODO: use bitmap to record validation result.
-        wrapper.validate()?;
+        if !state.validated.is_set(ino) {
+            wrapper.validate()?;
+            state.validated.set(ino);
+        }
 
         Ok(wrapper)
     }
 
     #[allow(clippy::cast_ptr_alignment)]
-    fn update_state(&self, r: &mut RafsIoReader) -> Result<()> {
+    fn update_state(&self, r: &mut RafsIoReader, meta: &RafsSuperMeta) -> Result<()> {
         let old_state = self.state.load();
 
         // Validate file size
@@ -215,8 +310,8 @@ impl DirectMapping {
         }
 
         // Validate inode table layout
-        let inode_table_start = old_state.meta.inode_table_offset;
-        let inode_table_size = old_state.meta.inode_table_entries as u64 * size_of::<u32>() as u64;
+        let inode_table_start = meta.inode_table_offset;
+        let inode_table_size = meta.inode_table_entries as u64 * size_of::<u32>() as u64;
         let inode_table_end = inode_table_start
             .checked_add(inode_table_size)
             .ok_or_else(|| ebadf!("invalid inode table size"))?;
@@ -229,8 +324,8 @@ impl DirectMapping {
         }
 
         // Validate blob table layout
-        let blob_table_start = old_state.meta.blob_table_offset;
-        let blob_table_size = old_state.meta.blob_table_size as u64;
+        let blob_table_start = meta.blob_table_offset;
+        let blob_table_size = meta.blob_table_size as u64;
         let blob_table_end = blob_table_start
             .checked_add(blob_table_size)
             .ok_or_else(|| ebadf!("invalid blob table size"))?;
@@ -243,7 +338,7 @@ impl DirectMapping {
         }
 
         // Validate extended blob table layout
-        let extended_blob_table_offset = old_state.meta.extended_blob_table_offset;
+        let extended_blob_table_offset = meta.extended_blob_table_offset;
         if extended_blob_table_offset > 0
             && ((extended_blob_table_offset as u64) < blob_table_start
                 || extended_blob_table_offset as u64 >= len)
@@ -251,8 +346,8 @@ impl DirectMapping {
             return Err(ebadf!("invalid extended blob table"));
         }
 
-        // Prefetch the bootstrap file
-        readahead(fd, 0, len);
+        // Only read ahead the inode table, which every lookup goes through.
+        readahead(fd, inode_table_start, inode_table_end);
 
         // Mmap the bootstrap file into current process for direct access
         let base = unsafe {
@@ -271,12 +366,15 @@ impl DirectMapping {
         if base.is_null() {
             return Err(ebadf!("failed to mmap bootstrap"));
         }
+        // Inodes are faulted in as they get looked up, don't read around them.
+        if unsafe { libc::madvise(base as *mut libc::c_void, size, libc::MADV_RANDOM) } != 0 {
+            debug!("failed to advise on bootstrap mapping: {}", last_error!());
+        }
         // Safe because the mmap area should covered the range [start, end)
         let end = unsafe { base.add(size) };
 
         // Load blob table. Safe because we have validated the blob table layout.
         let mut blob_table = OndiskBlobTable::new();
-        let meta = &old_state.meta;
 
         // Load extended blob table if the bootstrap including
         // extended blob table.
@@ -297,8 +395,8 @@ impl DirectMapping {
             OndiskInodeTable {
                 data: Vec::from_raw_parts(
                     base.add(inode_table_start as usize) as *const u32 as *mut u32,
-                    old_state.meta.inode_table_entries as usize,
-                    old_state.meta.inode_table_entries as usize,
+                    meta.inode_table_entries as usize,
+                    meta.inode_table_entries as usize,
                 ),
             }
         };
@@ -306,7 +404,7 @@ impl DirectMapping {
         let digest_validate = old_state.digest_validate;
 
         let state = DirectMappingState {
-            meta: old_state.meta,
+            meta: *meta,
             inode_table: ManuallyDrop::new(inode_table),
             blob_table: Arc::new(blob_table),
             fd: file.into_raw_fd(),
@@ -315,8 +413,14 @@ impl DirectMapping {
             size,
             mmapped_inode_table: true,
             digest_validate,
+            validated: Arc::new(InodeBitmap::new(meta.inode_table_entries as usize)),
+            children_read: Arc::new(InodeBitmap::new(meta.inode_table_entries as usize)),
         };
 
+        // Read in the root directory before use, other inodes are loaded on demand.
+        let root = state.inode_table.get(RAFS_ROOT_INODE)? as usize;
+        state.read_children(state.cast_to_ref::<OndiskInode>(base, root)?);
+
         // Swap new and old DirectMappingState object, the old object will be destroyed when the
         // reference count reaches zero.
         self.state.store(Arc::new(state));
@@ -327,7 +431,8 @@ impl DirectMapping {
 
 impl RafsSuperInodes for DirectMapping {
     fn load(&mut self, r: &mut RafsIoReader) -> Result<()> {
-        self.update_state(r)
+        let meta = self.state.load().meta;
+        self.update_state(r, &meta)
     }
 
     fn destroy(&mut self) {
@@ -338,8 +443,8 @@ impl RafsSuperInodes for DirectMapping {
     /// Find inode offset by ino from inode table and mmap to OndiskInode.
     #[inline]
     fn get_inode(&self, ino: Inode, digest_validate: bool) -> Result<Arc<dyn RafsInode>> {
-        let state = self.state.load();
-        let wrapper = self.get_inode_wrapper(ino, state.deref())?;
+        let state = self.state.load_full();
+        let wrapper = Self::get_inode_wrapper(&state, ino)?;
         let inode = Arc::new(wrapper) as Arc<dyn RafsInode>;
 
         // Validate inode digest tree
@@ -362,20 +467,28 @@ impl RafsSuperInodes for DirectMapping {
         state.blob_table.clone()
     }
 
-    fn update(&self, r: &mut RafsIoReader) -> RafsResult<()> {
-        self.update_state(r).map_err(RafsError::SwapBackend)
+    fn update(&self, r: &mut RafsIoReader, meta: &RafsSuperMeta) -> RafsResult<()> {
+        self.update_state(r, meta).map_err(RafsError::SwapBackend)
+    }
+
+    /// The bootstrap faulted in so far, which is shared with page cache.
+    fn memory_usage(&self) -> RafsMetaMemoryUsage {
+        RafsMetaMemoryUsage {
+            metadata_bytes: self.state.load().resident_size(),
+            chunk_info_bytes: 0,
+        }
     }
 }
 
 pub struct OndiskInodeWrapper {
-    pub mapping: DirectMapping,
-    pub offset: usize,
+    state: Arc<DirectMappingState>,
+    offset: usize,
 }
 
 impl OndiskInodeWrapper {
     #[inline]
-    fn state(&self) -> Guard<Arc<DirectMappingState>> {
-        self.mapping.state.load()
+    fn state(&self) -> &Arc<DirectMappingState> {
+        &self.state
     }
 
     #[allow(clippy::cast_ptr_alignment)]
@@ -535,6 +648,7 @@ impl RafsInode for OndiskInodeWrapper {
         if inode.i_child_count == 0 {
             return Err(enoent!());
         }
+        state.read_children(inode);
 
         let mut last = (inode.i_child_count - 1) as i32;
 
@@ -543,9 +657,10 @@ impl RafsInode for OndiskInodeWrapper {
         while first <= last {
             let pivot = first + ((last - first) >> 1);
 
-            let wrapper = self
-                .mapping
-                .get_inode_wrapper((inode.i_child_index as i32 + pivot) as u64, state.deref())?;
+            let wrapper = DirectMapping::get_inode_wrapper(
+                state,
+                (inode.i_child_index as i32 + pivot) as u64,
+            )?;
             let target = wrapper.name_ref(state.deref());
 
             if target == name {
@@ -578,8 +693,12 @@ impl RafsInode for OndiskInodeWrapper {
         if idx >= child_count {
             return Err(enoent!("invalid child index"));
         }
+        state.read_children(inode);
 
-        self.mapping.get_inode(idx + child_index, false)
+        Ok(Arc::new(DirectMapping::get_inode_wrapper(
+            state,
+            idx + child_index,
+        )?))
     }
 
     /// Get chunk information with index `idx`
@@ -605,7 +724,7 @@ impl RafsInode for OndiskInodeWrapper {
         offset += size_of::<OndiskChunkInfo>() * idx as usize;
 
         let chunk = state.cast_to_ref::<OndiskChunkInfo>(state.base, offset)?;
-        let wrapper = OndiskChunkInfoWrapper::new(chunk, self.mapping.clone(), offset);
+        let wrapper = OndiskChunkInfoWrapper::new(chunk, state.clone(), offset);
 
         Ok(Arc::new(wrapper))
     }
@@ -631,6 +750,7 @@ impl RafsInode for OndiskInodeWrapper {
     fn get_attr(&self) -> Attr {
         let state = self.state();
         let inode = self.inode(state.deref());
+        let (ctime, ctimensec) = inode.ctime();
 
         Attr {
             ino: inode.i_ino,
@@ -642,6 +762,10 @@ impl RafsInode for OndiskInodeWrapper {
             gid: inode.i_gid,
             blksize: RAFS_INODE_BLOCKSIZE,
             rdev: inode.i_rdev,
+            mtime: inode.i_mtime,
+            mtimensec: inode.i_mtime_nsec,
+            ctime,
+            ctimensec,
             ..Default::default()
         }
     }
@@ -680,7 +804,7 @@ impl RafsInode for OndiskInodeWrapper {
 
     #[inline]
     fn get_blocksize(&self) -> u32 {
-        self.mapping.state.load().meta.block_size
+        self.state.meta.block_size
     }
 
     // TODO: Do prefetch insides this while walking the entire file system
@@ -699,7 +823,8 @@ impl RafsInode for OndiskInodeWrapper {
         let mut child_dirs: Vec<Arc<dyn RafsInode>> = Vec::new();
 
         for idx in child_index..(child_index + child_count) {
-            let child_inode = self.mapping.get_inode(idx, false).unwrap();
+            let child_inode: Arc<dyn RafsInode> =
+                Arc::new(DirectMapping::get_inode_wrapper(state, idx)?);
             if child_inode.is_dir() {
                 trace!("Got dir {:?}", child_inode.name());
                 child_dirs.push(child_inode);
@@ -736,28 +861,25 @@ impl RafsInode for OndiskInodeWrapper {
 }
 
 pub struct OndiskChunkInfoWrapper {
-    mapping: DirectMapping,
+    state: Arc<DirectMappingState>,
     offset: usize,
     digest: RafsDigest,
 }
 
-unsafe impl Send for OndiskChunkInfoWrapper {}
-unsafe impl Sync for OndiskChunkInfoWrapper {}
-
 // This is *direct* metadata mode in-memory chunk info object.
 impl OndiskChunkInfoWrapper {
     #[inline]
-    fn new(chunk: &OndiskChunkInfo, mapping: DirectMapping, offset: usize) -> Self {
+    fn new(chunk: &OndiskChunkInfo, state: Arc<DirectMappingState>, offset: usize) -> Self {
         Self {
-            mapping,
+            state,
             offset,
             digest: chunk.block_id,
         }
     }
 
     #[inline]
-    fn state(&self) -> Guard<Arc<DirectMappingState>> {
-        self.mapping.state.load()
+    fn state(&self) -> &Arc<DirectMappingState> {
+        &self.state
     }
 
     /// Dereference the underlying OndiskChunkInfo object.
@@ -802,3 +924,33 @@ impl RafsChunkInfo for OndiskChunkInfoWrapper {
     impl_chunkinfo_getter!(file_offset, u64);
     impl_chunkinfo_getter!(flags, RafsChunkFlags);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inode_bitmap() {
+        let bitmap = InodeBitmap::new(17);
+        assert_eq!(bitmap.bits.len(), 3);
+        for ino in 1..=17 {
+            assert!(!bitmap.is_set(ino));
+        }
+
+        bitmap.set(1);
+        bitmap.set(9);
+        bitmap.set(17);
+        assert!(bitmap.is_set(1));
+        assert!(!bitmap.is_set(2));
+        assert!(bitmap.is_set(9));
+        assert!(!bitmap.is_set(10));
+        assert!(bitmap.is_set(17));
+
+        // Out of the inode table.
+        bitmap.set(0);
+        bitmap.set(100);
+        assert!(!bitmap.is_set(0));
+        assert!(!bitmap.is_set(100));
+        assert!(!InodeBitmap::default().is_set(1));
+    }
+}
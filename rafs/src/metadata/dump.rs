@@ -0,0 +1,192 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Summarized view of RAFS metadata, to debug a mounted image without its bootstrap file.
+
+use std::io::Result;
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::{RafsInode, RafsSuper, RafsSuperMeta};
+
+#[derive(Serialize)]
+pub struct RafsBlobDump {
+    pub blob_index: u32,
+    pub blob_id: String,
+    pub chunk_count: u32,
+    pub readahead_offset: u32,
+    pub readahead_size: u32,
+    pub blob_cache_size: u64,
+}
+
+#[derive(Serialize)]
+pub struct RafsChunkDump {
+    pub index: u32,
+    pub blob_index: u32,
+    pub block_id: String,
+    pub compress_offset: u64,
+    pub compress_size: u32,
+    pub decompress_offset: u64,
+    pub decompress_size: u32,
+    pub file_offset: u64,
+    pub compressed: bool,
+    pub hole: bool,
+}
+
+#[derive(Serialize)]
+pub struct RafsInodeDump {
+    pub path: String,
+    pub ino: u64,
+    pub parent: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub mtime: u64,
+    pub digest: String,
+    /// Number of children for directories, or number of chunks for regular files.
+    pub child_count: u32,
+    pub symlink: Option<String>,
+    pub xattrs: Vec<String>,
+    /// Chunk records of regular files.
+    pub chunks: Vec<RafsChunkDump>,
+}
+
+#[derive(Serialize)]
+pub struct RafsSuperDump {
+    pub superblock: RafsSuperMeta,
+    pub blobs: Vec<RafsBlobDump>,
+    /// Inode of the path asked for, if any.
+    pub inode: Option<RafsInodeDump>,
+}
+
+impl RafsSuper {
+    /// Dump superblock fields and blob table, and records of the inode at `path` if given.
+    pub fn dump(&self, path: Option<&Path>) -> Result<RafsSuperDump> {
+        let blobs = self
+            .inodes
+            .get_blobs()
+            .iter()
+            .map(|b| RafsBlobDump {
+                blob_index: b.blob_index,
+                blob_id: b.blob_id.clone(),
+                chunk_count: b.chunk_count,
+                readahead_offset: b.readahead_offset,
+                readahead_size: b.readahead_size,
+                blob_cache_size: b.blob_cache_size,
+            })
+            .collect();
+        let inode = match path {
+            Some(p) => {
+                let inode = self.get_inode(self.ino_from_path(p)?, false)?;
+                Some(dump_inode(p, inode.as_ref())?)
+            }
+            None => None,
+        };
+
+        Ok(RafsSuperDump {
+            superblock: self.meta,
+            blobs,
+            inode,
+        })
+    }
+}
+
+fn dump_inode(path: &Path, inode: &dyn RafsInode) -> Result<RafsInodeDump> {
+    let attr = inode.get_attr();
+    let symlink = if inode.is_symlink() {
+        Some(inode.get_symlink()?.to_string_lossy().to_string())
+    } else {
+        None
+    };
+    let xattrs = inode
+        .get_xattrs()?
+        .iter()
+        .map(|n| String::from_utf8_lossy(n).to_string())
+        .collect();
+
+    let mut chunks = Vec::new();
+    if inode.is_reg() {
+        for idx in 0..inode.get_child_count() {
+            let c = inode.get_chunk_info(idx)?;
+            chunks.push(RafsChunkDump {
+                index: c.index(),
+                blob_index: c.blob_index(),
+                block_id: c.block_id().to_string(),
+                compress_offset: c.compress_offset(),
+                compress_size: c.compress_size(),
+                decompress_offset: c.decompress_offset(),
+                decompress_size: c.decompress_size(),
+                file_offset: c.file_offset(),
+                compressed: c.is_compressed(),
+                hole: c.is_hole(),
+            });
+        }
+    }
+
+    Ok(RafsInodeDump {
+        path: path.to_string_lossy().to_string(),
+        ino: inode.ino(),
+        parent: inode.parent(),
+        mode: attr.mode,
+        uid: attr.uid,
+        gid: attr.gid,
+        nlink: attr.nlink,
+        size: inode.size(),
+        mtime: attr.mtime,
+        digest: inode.get_digest().to_string(),
+        child_count: inode.get_child_count(),
+        symlink,
+        xattrs,
+        chunks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use fuse_rs::api::filesystem::ROOT_ID;
+
+    use crate::fs::RafsConfig;
+    use crate::RafsIoRead;
+
+    #[test]
+    fn test_dump() {
+        let config = r#"
+        {
+            "device": {
+              "backend": {
+                "type": "localfs",
+                "config": {
+                  "dir": "/tmp"
+                }
+              }
+            },
+            "mode": "direct",
+            "digest_validate": false
+          }"#;
+        let conf = RafsConfig::from_str(config).unwrap();
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let mut path = PathBuf::from(root_dir);
+        path.push("../tests/texture/bootstrap/image_v2.boot");
+        let mut r = RafsIoRead::from_file(path.to_str().unwrap()).unwrap();
+        let mut sb = RafsSuper::new(&conf).unwrap();
+        sb.load(&mut r).unwrap();
+
+        let dump = sb.dump(None).unwrap();
+        assert_eq!(dump.superblock.magic, sb.meta.magic);
+        assert!(dump.inode.is_none());
+
+        let dump = sb.dump(Some(Path::new("/"))).unwrap();
+        let root = dump.inode.unwrap();
+        assert_eq!(root.ino, ROOT_ID);
+        assert!(root.chunks.is_empty());
+        assert!(sb.dump(Some(Path::new("/no/such/file"))).is_err());
+    }
+}
@@ -19,17 +19,18 @@ pub const EXTENDED_BLOB_TABLE_ENTRY_SIZE: usize = 32;
 pub struct ExtendedBlobTableEntry {
     /// Number of chunks in a blob file.
     pub chunk_count: u32,
-    pub reserved1: [u8; 4],
+    /// Version of the key chunks are encrypted with, 0 if not encrypted.
+    pub key_version: u32,
     /// The expected decompress size of blob cache file.
     pub blob_cache_size: u64,
     pub reserved2: [u8; EXTENDED_BLOB_TABLE_ENTRY_SIZE - 16],
 }
 
 impl ExtendedBlobTableEntry {
-    pub fn new(chunk_count: u32, blob_cache_size: u64) -> Self {
+    pub fn new(chunk_count: u32, blob_cache_size: u64, key_version: u32) -> Self {
         Self {
             chunk_count,
-            reserved1: [0; 4],
+            key_version,
             blob_cache_size,
             reserved2: [0; EXTENDED_BLOB_TABLE_ENTRY_SIZE - 16],
         }
@@ -60,10 +61,11 @@ impl ExtendedBlobTable {
         self.entries.len()
     }
 
-    pub fn add(&mut self, chunk_count: u32, blob_cache_size: u64) {
+    pub fn add(&mut self, chunk_count: u32, blob_cache_size: u64, key_version: u32) {
         self.entries.push(Arc::new(ExtendedBlobTableEntry::new(
             chunk_count,
             blob_cache_size,
+            key_version,
         )));
     }
 
@@ -98,13 +100,11 @@ impl RafsStore for ExtendedBlobTable {
             .enumerate()
             .try_for_each::<_, Result<()>>(|(_idx, entry)| {
                 w.write_all(&u32::to_le_bytes(entry.chunk_count))?;
-                w.write_all(&entry.reserved1)?;
+                w.write_all(&u32::to_le_bytes(entry.key_version))?;
                 w.write_all(&u64::to_le_bytes(entry.blob_cache_size))?;
                 w.write_all(&entry.reserved2)?;
-                size += size_of::<u32>()
-                    + entry.reserved1.len()
-                    + size_of::<u64>()
-                    + entry.reserved2.len();
+                size +=
+                    size_of::<u32>() + size_of::<u32>() + size_of::<u64>() + entry.reserved2.len();
                 Ok(())
             })?;
 
@@ -134,7 +134,7 @@ mod tests {
         // Create extended blob table
         let mut table = ExtendedBlobTable::new();
         for i in 0..5 {
-            table.add(i * 3, 100);
+            table.add(i * 3, 100, i % 2);
         }
 
         // Store extended blob table
@@ -159,7 +159,7 @@ mod tests {
         // Check expected blob table
         for i in 0..5 {
             assert_eq!(table.get(i).unwrap().chunk_count, i * 3);
-            assert_eq!(table.get(i).unwrap().reserved1, [0u8; 4]);
+            assert_eq!(table.get(i).unwrap().key_version, i % 2);
             assert_eq!(table.get(i).unwrap().blob_cache_size, 100);
             assert_eq!(table.get(i).unwrap().reserved2, [0u8; 16]);
         }
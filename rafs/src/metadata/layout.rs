@@ -57,6 +57,8 @@ pub const RAFS_SUPER_VERSION_V5: u32 = 0x500;
 pub const RAFS_SUPER_MIN_VERSION: u32 = RAFS_SUPER_VERSION_V4;
 pub const RAFS_ALIGNMENT: usize = 8;
 pub const RAFS_ROOT_INODE: u64 = 1;
+/// Bits of nanoseconds in `OndiskInode::i_ctime`.
+const CTIME_NSEC_BITS: u32 = 30;
 
 macro_rules! impl_bootstrap_converter {
     ($T: ty) => {
@@ -180,6 +182,10 @@ bitflags! {
         const HAS_XATTR = 0x0000_0020;
         // Data chunks are compressed with gzip
         const COMPRESS_GZIP = 0x0000_0040;
+        /// Inodes carry mtime and ctime with nanosecond precision.
+        /// If unset, nydusd reports its start time as timestamps of all
+        /// inodes at runtime.
+        const HAS_MTIME = 0x0000_0080;
     }
 }
 
@@ -317,6 +323,10 @@ impl OndiskSuperBlock {
         self.s_flags |= RafsSuperFlags::HAS_XATTR.bits();
     }
 
+    pub fn set_has_mtime(&mut self) {
+        self.s_flags |= RafsSuperFlags::HAS_MTIME.bits();
+    }
+
     impl_pub_getter_setter!(magic, set_magic, s_magic, u32);
     impl_pub_getter_setter!(version, set_version, s_fs_version, u32);
     impl_pub_getter_setter!(sb_size, set_sb_size, s_sb_size, u32);
@@ -570,6 +580,7 @@ impl OndiskBlobTable {
         readahead_size: u32,
         chunk_count: u32,
         blob_cache_size: u64,
+        key_version: u32,
     ) -> u32 {
         let blob_index = self.entries.len() as u32;
         self.entries.push(Arc::new(RafsBlobEntry {
@@ -579,8 +590,9 @@ impl OndiskBlobTable {
             readahead_size,
             chunk_count,
             blob_cache_size,
+            key_version,
         }));
-        self.extended.add(chunk_count, blob_cache_size);
+        self.extended.add(chunk_count, blob_cache_size, key_version);
         blob_index
     }
 
@@ -632,7 +644,7 @@ impl OndiskBlobTable {
             let index = self.entries.len();
 
             // For compatibility concern, blob table might not associate with extended blob table.
-            let (chunk_count, blob_cache_size) = if !self.extended.entries.is_empty() {
+            let (chunk_count, blob_cache_size, key_version) = if !self.extended.entries.is_empty() {
                 // chge: Though below can hardly happen and we can do nothing meeting
                 // this possibly due to bootstrap corruption, someone like this kind of check, make them happy.
                 if index > self.extended.entries.len() - 1 {
@@ -645,9 +657,10 @@ impl OndiskBlobTable {
                 (
                     self.extended.entries[index].chunk_count,
                     self.extended.entries[index].blob_cache_size,
+                    self.extended.entries[index].key_version,
                 )
             } else {
-                (0, 0)
+                (0, 0, 0)
             };
 
             self.entries.push(Arc::new(RafsBlobEntry {
@@ -657,6 +670,7 @@ impl OndiskBlobTable {
                 readahead_offset,
                 readahead_size,
                 blob_cache_size,
+                key_version,
             }));
 
             if unsafe { align_to_rafs(frame.offset_from(begin_ptr) as usize) } as u32
@@ -749,8 +763,14 @@ pub struct OndiskInode {
     /// symlink path size, [char; i_symlink_size]
     pub i_symlink_size: u16, // 104
     //// inode device block number, ignored for non-special files
-    pub i_rdev: u32,          // 108
-    pub i_reserved: [u8; 20], // 128
+    pub i_rdev: u32, // 108
+    /// nanoseconds part of mtime, valid only if `RafsSuperFlags::HAS_MTIME` is set
+    pub i_mtime_nsec: u32, // 112
+    /// seconds part of mtime, valid only if `RafsSuperFlags::HAS_MTIME` is set
+    pub i_mtime: u64, // 120
+    /// ctime, seconds in the high 34 bits and nanoseconds in the low 30 bits, valid only if
+    /// `RafsSuperFlags::HAS_MTIME` is set
+    pub i_ctime: u64, // 128
 }
 
 bitflags! {
@@ -787,6 +807,20 @@ impl OndiskInode {
         self.i_symlink_size = symlink_len as u16;
     }
 
+    /// Seconds and nanoseconds of ctime.
+    #[inline]
+    pub fn ctime(&self) -> (u64, u32) {
+        (
+            self.i_ctime >> CTIME_NSEC_BITS,
+            (self.i_ctime & ((1 << CTIME_NSEC_BITS) - 1)) as u32,
+        )
+    }
+
+    #[inline]
+    pub fn set_ctime(&mut self, secs: u64, nsec: u32) {
+        self.i_ctime = secs << CTIME_NSEC_BITS | nsec as u64;
+    }
+
     #[inline]
     pub fn size(&self) -> usize {
         size_of::<Self>()
@@ -1129,7 +1163,7 @@ pub fn parse_xattr_value(data: &[u8], size: usize, name: &OsStr) -> Result<Optio
 
 #[cfg(test)]
 pub mod tests {
-    use super::OndiskBlobTable;
+    use super::{OndiskBlobTable, OndiskInode, OndiskSuperBlock, RafsSuperFlags};
     use crate::RafsIoReader;
     use nydus_utils::setup_logging;
     use std::fs::OpenOptions;
@@ -1146,6 +1180,24 @@ pub mod tests {
         ::std::slice::from_raw_parts((p as *const T) as *const u8, ::std::mem::size_of::<T>())
     }
 
+    #[test]
+    fn test_inode_mtime_layout() {
+        // mtime and ctime live in the previously reserved area, inode size must not change.
+        assert_eq!(std::mem::size_of::<OndiskInode>(), 128);
+
+        let mut inode = OndiskInode::new();
+        inode.set_ctime(1_600_000_000, 999_999_999);
+        assert_eq!(inode.ctime(), (1_600_000_000, 999_999_999));
+        // Seconds fit in 34 bits until year 2514.
+        inode.set_ctime((1 << 34) - 1, 1);
+        assert_eq!(inode.ctime(), ((1 << 34) - 1, 1));
+
+        let mut sb = OndiskSuperBlock::new();
+        assert!(!RafsSuperFlags::from_bits_truncate(sb.flags()).contains(RafsSuperFlags::HAS_MTIME));
+        sb.set_has_mtime();
+        assert!(RafsSuperFlags::from_bits_truncate(sb.flags()).contains(RafsSuperFlags::HAS_MTIME));
+    }
+
     #[test]
     fn test_load_blob_table() {
         setup_logging(None, log::LevelFilter::Info).unwrap();
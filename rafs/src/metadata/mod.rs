@@ -40,6 +40,7 @@ use nydus_utils::digest::{self, RafsDigest};
 
 pub mod cached;
 pub mod direct;
+pub mod dump;
 pub mod extended;
 pub mod layout;
 
@@ -108,6 +109,9 @@ impl RafsSuperMeta {
     pub fn has_xattr(&self) -> bool {
         self.flags.contains(RafsSuperFlags::HAS_XATTR)
     }
+    pub fn has_mtime(&self) -> bool {
+        self.flags.contains(RafsSuperFlags::HAS_MTIME)
+    }
 }
 
 #[derive(Clone)]
@@ -141,6 +145,10 @@ impl fmt::Display for RafsMode {
 pub struct RafsSuper {
     pub mode: RafsMode,
     pub digest_validate: bool,
+    /// Max number of inodes kept in memory in cached mode, 0 means unlimited.
+    pub max_cached_inodes: usize,
+    /// Max number of chunk infos kept in memory in cached mode, 0 means unlimited.
+    pub max_cached_chunks: usize,
     pub meta: RafsSuperMeta,
     pub inodes: Arc<dyn RafsSuperInodes + Sync + Send>,
 }
@@ -150,6 +158,8 @@ impl Default for RafsSuper {
         Self {
             mode: RafsMode::Direct,
             digest_validate: false,
+            max_cached_inodes: 0,
+            max_cached_chunks: 0,
             meta: RafsSuperMeta {
                 magic: 0,
                 version: 0,
@@ -195,6 +205,8 @@ impl RafsSuper {
         }
 
         rs.digest_validate = digest_validate;
+        rs.max_cached_inodes = conf.max_cached_inodes;
+        rs.max_cached_chunks = conf.max_cached_chunks;
 
         Ok(rs)
     }
@@ -205,47 +217,68 @@ impl RafsSuper {
             .destroy();
     }
 
+    /// Switch to a new bootstrap atomically.
+    ///
+    /// Inodes already handed out keep referring to the old bootstrap until dropped, while new
+    /// lookups see the new one. Features of the new bootstrap must match the current one, since
+    /// they are used to setup the storage device.
     pub fn update(&self, r: &mut RafsIoReader) -> RafsResult<()> {
         let mut sb = OndiskSuperBlock::new();
 
         r.read_exact(sb.as_mut()).map_err(RafsError::ReadMetadata)?;
-        self.inodes.update(r)
-    }
+        sb.validate().map_err(RafsError::ReadMetadata)?;
+        let meta = self.parse_meta(&sb).map_err(RafsError::ReadMetadata)?;
+        if meta.flags != self.meta.flags || meta.block_size != self.meta.block_size {
+            return Err(RafsError::SwapBackend(einval!(format!(
+                "incompatible bootstrap, features {} block size {}, expect {} {}",
+                meta.flags, meta.block_size, self.meta.flags, self.meta.block_size
+            ))));
+        }
 
-    /// Load RAFS super block and optionally cache inodes.
-    pub fn load(&mut self, r: &mut RafsIoReader) -> Result<()> {
-        let mut sb = OndiskSuperBlock::new();
+        self.inodes.update(r, &meta)
+    }
 
-        r.read_exact(sb.as_mut())?;
-        sb.validate()?;
+    fn parse_meta(&self, sb: &OndiskSuperBlock) -> Result<RafsSuperMeta> {
+        let mut meta = self.meta;
 
-        self.meta.magic = sb.magic();
-        self.meta.version = sb.version();
-        self.meta.sb_size = sb.sb_size();
-        self.meta.block_size = sb.block_size();
-        self.meta.flags = RafsSuperFlags::from_bits(sb.flags())
+        meta.magic = sb.magic();
+        meta.version = sb.version();
+        meta.sb_size = sb.sb_size();
+        meta.block_size = sb.block_size();
+        meta.flags = RafsSuperFlags::from_bits(sb.flags())
             .ok_or_else(|| einval!(format!("invalid super flags {:x}", sb.flags())))?;
-        self.meta.prefetch_table_offset = sb.prefetch_table_offset();
-        self.meta.prefetch_table_entries = sb.prefetch_table_entries();
-
-        info!("rafs superblock features: {}", self.meta.flags);
+        meta.prefetch_table_offset = sb.prefetch_table_offset();
+        meta.prefetch_table_entries = sb.prefetch_table_entries();
 
-        match self.meta.version {
+        match meta.version {
             RAFS_SUPER_VERSION_V4 => {
-                self.meta.inodes_count = std::u64::MAX;
+                meta.inodes_count = std::u64::MAX;
             }
             RAFS_SUPER_VERSION_V5 => {
-                self.meta.inodes_count = sb.inodes_count();
-                self.meta.inode_table_entries = sb.inode_table_entries();
-                self.meta.inode_table_offset = sb.inode_table_offset();
-                self.meta.blob_table_offset = sb.blob_table_offset();
-                self.meta.blob_table_size = sb.blob_table_size();
-                self.meta.extended_blob_table_offset = sb.extended_blob_table_offset();
-                self.meta.extended_blob_table_entries = sb.extended_blob_table_entries();
+                meta.inodes_count = sb.inodes_count();
+                meta.inode_table_entries = sb.inode_table_entries();
+                meta.inode_table_offset = sb.inode_table_offset();
+                meta.blob_table_offset = sb.blob_table_offset();
+                meta.blob_table_size = sb.blob_table_size();
+                meta.extended_blob_table_offset = sb.extended_blob_table_offset();
+                meta.extended_blob_table_entries = sb.extended_blob_table_entries();
             }
             _ => return Err(ebadf!("invalid superblock version number")),
         }
 
+        Ok(meta)
+    }
+
+    /// Load RAFS super block and optionally cache inodes.
+    pub fn load(&mut self, r: &mut RafsIoReader) -> Result<()> {
+        let mut sb = OndiskSuperBlock::new();
+
+        r.read_exact(sb.as_mut())?;
+        sb.validate()?;
+
+        self.meta = self.parse_meta(&sb)?;
+        info!("rafs superblock features: {}", self.meta.flags);
+
         match sb.version() {
             RAFS_SUPER_VERSION_V4 => {
                 // TODO: Support Rafs v4
@@ -258,7 +291,12 @@ impl RafsSuper {
                     self.inodes = Arc::new(inodes);
                 }
                 RafsMode::Cached => {
-                    let mut inodes = CachedInodes::new(self.meta, self.digest_validate);
+                    let mut inodes = CachedInodes::new(
+                        self.meta,
+                        self.digest_validate,
+                        self.max_cached_inodes,
+                        self.max_cached_chunks,
+                    );
                     inodes.load(r)?;
                     self.inodes = Arc::new(inodes);
                 }
@@ -374,7 +412,67 @@ impl RafsSuper {
         Ok(())
     }
 
-    pub(crate) fn path_from_ino(&self, ino: Inode) -> Result<PathBuf> {
+    /// Pass data of `inodes` to `fetcher` in batches, descendants of directories included.
+    pub fn prefetch_inodes(
+        &self,
+        inodes: &[Inode],
+        fetcher: &dyn Fn(&mut RafsBioDesc),
+    ) -> Result<()> {
+        let mut hardlinks: HashSet<u64> = HashSet::new();
+        let mut head_desc = RafsBioDesc::new();
+
+        for ino in inodes {
+            self.build_prefetch_desc(*ino, &mut head_desc, &mut hardlinks, fetcher)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get chunks of each of `blobs` ordered by offset in blob, chunks shared by files are
+    /// listed only once. The inode table is scanned instead of walking the directory tree, as
+    /// chunks are only reachable from inodes.
+    pub fn get_blobs_chunks(&self, blobs: &[Arc<RafsBlobEntry>]) -> Result<Vec<Vec<RafsBio>>> {
+        let indexes = blobs
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (b.blob_index, i))
+            .collect::<HashMap<_, _>>();
+        let mut chunks = vec![Vec::new(); blobs.len()];
+        let mut seen = HashSet::new();
+
+        for ino in ROOT_ID..=self.get_max_ino() {
+            let inode = self.get_inode(ino, false)?;
+            if !inode.is_reg() {
+                continue;
+            }
+            for idx in 0..inode.get_child_count() {
+                let chunk = inode.get_chunk_info(idx)?;
+                let i = match indexes.get(&chunk.blob_index()) {
+                    Some(i) if !chunk.is_hole() => *i,
+                    _ => continue,
+                };
+                if !seen.insert((chunk.blob_index(), chunk.compress_offset())) {
+                    continue;
+                }
+                let size = chunk.decompress_size() as usize;
+                chunks[i].push(RafsBio::new(
+                    chunk,
+                    blobs[i].clone(),
+                    0,
+                    size,
+                    inode.get_blocksize(),
+                ));
+            }
+        }
+        for c in chunks.iter_mut() {
+            c.sort_by_key(|b| b.chunkinfo.compress_offset());
+        }
+
+        Ok(chunks)
+    }
+
+    /// Get absolute path of an inode by walking up to the root inode.
+    pub fn path_from_ino(&self, ino: Inode) -> Result<PathBuf> {
         if ino == ROOT_ID {
             return Ok(self.get_inode(ino, false)?.name().into());
         }
@@ -398,7 +496,8 @@ impl RafsSuper {
         Ok(path)
     }
 
-    pub(crate) fn ino_from_path(&self, f: &Path) -> Result<u64> {
+    /// Resolve an absolute path to inode number, without following symlinks.
+    pub fn ino_from_path(&self, f: &Path) -> Result<u64> {
         if f == Path::new("/") {
             return Ok(ROOT_ID);
         }
@@ -517,6 +616,15 @@ impl RafsSuper {
     }
 }
 
+/// Approximate bytes of memory taken by metadata of a file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RafsMetaMemoryUsage {
+    /// Bootstrap mapped in direct mode, or inodes loaded in cached mode.
+    pub metadata_bytes: usize,
+    /// Chunk infos loaded in cached mode, which are part of the mapping in direct mode.
+    pub chunk_info_bytes: usize,
+}
+
 /// Trait to manage all inodes of a file system.
 pub trait RafsSuperInodes {
     fn load(&mut self, r: &mut RafsIoReader) -> Result<()>;
@@ -533,7 +641,12 @@ pub trait RafsSuperInodes {
 
     fn get_blob_table(&self) -> Arc<OndiskBlobTable>;
 
-    fn update(&self, r: &mut RafsIoReader) -> RafsResult<()>;
+    /// Switch to a new bootstrap described by `meta`.
+    fn update(&self, r: &mut RafsIoReader, meta: &RafsSuperMeta) -> RafsResult<()>;
+
+    fn memory_usage(&self) -> RafsMetaMemoryUsage {
+        RafsMetaMemoryUsage::default()
+    }
 
     /// Validate child, chunk and symlink digest on inode tree.
     /// The chunk data digest for regular file will only validate on fs read.
@@ -587,7 +700,7 @@ pub trait RafsSuperInodes {
 }
 
 /// Trait to access Rafs Inode Information.
-pub trait RafsInode {
+pub trait RafsInode: Send + Sync {
     /// Validate the object for safety.
     /// The object may be transmuted from a raw buffer read from an external file, so the caller
     /// must validate it before accessing any fields of the object.
@@ -852,6 +965,7 @@ mod tests {
                     blob_id: String::from("blobid"),
                     blob_index: 0,
                     blob_cache_size: 0,
+                    key_version: 0,
                 }),
             );
             assert_eq!(*result, res);
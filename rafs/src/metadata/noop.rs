@@ -44,7 +44,7 @@ impl RafsSuperInodes for NoopInodes {
         unimplemented!()
     }
 
-    fn update(&self, _r: &mut RafsIoReader) -> RafsResult<()> {
+    fn update(&self, _r: &mut RafsIoReader, _meta: &RafsSuperMeta) -> RafsResult<()> {
         unimplemented!()
     }
 }
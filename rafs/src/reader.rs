@@ -0,0 +1,265 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read rafs images without mounting them.
+//!
+//! `RafsReader` glues a loaded bootstrap and a storage device together, so that tools like image
+//! scanners can open a bootstrap, resolve paths and read file data directly, instead of running
+//! nydusd and going through a FUSE mountpoint.
+//!
+//! ```ignore
+//! let conf = RafsConfig::from_file("/path/to/config.json")?;
+//! let reader = RafsReader::open(conf, "scanner", Path::new("/path/to/bootstrap"))?;
+//! let data = reader.read_file(Path::new("/etc/os-release"))?;
+//! ```
+
+use std::convert::TryFrom;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::fs::RafsConfig;
+use crate::metadata::{Inode, RafsInode, RafsSuper};
+use crate::*;
+use storage::device::RafsDevice;
+
+/// Readonly access to files of a rafs image, out of a FUSE context.
+pub struct RafsReader {
+    sb: RafsSuper,
+    device: RafsDevice,
+}
+
+impl RafsReader {
+    /// Load the bootstrap at `bootstrap` and setup the storage device described by `conf`.
+    ///
+    /// `id` tells metrics and cache of different readers apart, just like the mountpoint of
+    /// a rafs instance.
+    pub fn open(conf: RafsConfig, id: &str, bootstrap: &Path) -> RafsResult<Self> {
+        let path = bootstrap
+            .to_str()
+            .ok_or_else(|| RafsError::ReadMetadata(einval!("invalid bootstrap path")))?;
+        let mut r = RafsIoRead::from_file(path)?;
+
+        let mut sb = RafsSuper::new(&conf).map_err(RafsError::FillSuperblock)?;
+        sb.load(&mut r).map_err(RafsError::FillSuperblock)?;
+
+        let mut device_conf = conf.device.clone();
+        device_conf.cache.cache_validate =
+            conf.digest_validate || conf.digest_validate_file || conf.enforce_integrity;
+        device_conf.cache.prefetch_worker = TryFrom::try_from(&conf)?;
+        // Readers fetch data on demand only, prefetching is up to the caller.
+        device_conf.cache.prefetch_worker.enable = false;
+        device_conf.cache.key_provider = conf.key_provider()?;
+
+        let device = RafsDevice::new(
+            device_conf,
+            sb.meta.get_compressor(),
+            sb.meta.get_digester(),
+            id,
+        )
+        .map_err(RafsError::CreateDevice)?;
+        device.init(&[]).map_err(RafsError::CreateDevice)?;
+
+        Ok(RafsReader { sb, device })
+    }
+
+    /// Get the loaded bootstrap, to access image metadata and walk the inode tree.
+    pub fn super_block(&self) -> &RafsSuper {
+        &self.sb
+    }
+
+    /// Get inode of an absolute path within the image.
+    pub fn lookup(&self, path: &Path) -> Result<Arc<dyn RafsInode>> {
+        let ino = self.sb.ino_from_path(path)?;
+        self.sb.get_inode(ino, self.sb.digest_validate)
+    }
+
+    /// Get absolute path of an inode within the image.
+    pub fn path(&self, ino: Inode) -> Result<PathBuf> {
+        self.sb.path_from_ino(ino)
+    }
+
+    /// Read data of a regular file at `offset` into `buf`, returns number of bytes read, which
+    /// is less than the buffer size at end of file, or if the storage returns less data than
+    /// asked for, in which case the rest could be read again from where it stopped.
+    pub fn read_at(&self, inode: &dyn RafsInode, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if inode.is_dir() {
+            return Err(std::io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        if !inode.is_reg() {
+            return Err(einval!("not a regular file"));
+        }
+        if offset >= inode.size() {
+            return Ok(0);
+        }
+
+        let size = std::cmp::min(buf.len() as u64, inode.size() - offset) as usize;
+        let desc = inode.alloc_bio_desc(offset, size)?;
+
+        self.device.read_to_buf(&mut buf[..size], desc)
+    }
+
+    /// Read the whole content of a regular file.
+    pub fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let inode = self.lookup(path)?;
+        let mut buf = vec![0u8; inode.size() as usize];
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            let n = self.read_at(inode.as_ref(), &mut buf[offset..], offset as u64)?;
+            if n == 0 {
+                return Err(eio!("unexpected end of file"));
+            }
+            offset += n;
+        }
+
+        Ok(buf)
+    }
+}
+
+impl Drop for RafsReader {
+    fn drop(&mut self) {
+        self.device
+            .close()
+            .unwrap_or_else(|e| warn!("failed to close device: {:?}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use fuse_rs::api::filesystem::ROOT_ID;
+    use sha2::{Digest, Sha256};
+
+    fn new_reader() -> RafsReader {
+        let config = r#"
+        {
+            "device": {
+              "backend": {
+                "type": "oss",
+                "config": {
+                  "endpoint": "test",
+                  "access_key_id": "test",
+                  "access_key_secret": "test",
+                  "bucket_name": "antsys-nydus",
+                  "object_prefix":"nydus_v2/",
+                  "scheme": "http"
+                }
+              }
+            },
+            "mode": "direct",
+            "digest_validate": false
+          }"#;
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let mut source_path = PathBuf::from(root_dir);
+        source_path.push("../tests/texture/bootstrap/image_v2.boot");
+        let conf = RafsConfig::from_str(config).unwrap();
+
+        RafsReader::open(conf, "reader", &source_path).unwrap()
+    }
+
+    #[test]
+    fn it_should_resolve_path() {
+        let reader = new_reader();
+        let root = reader.lookup(Path::new("/")).unwrap();
+        assert_eq!(root.ino(), ROOT_ID);
+        assert!(reader.lookup(Path::new("relative")).is_err());
+        assert!(reader.lookup(Path::new("/no/such/file")).is_err());
+
+        let sb = reader.super_block();
+        for ino in ROOT_ID..=sb.get_max_ino() {
+            let inode = sb.get_inode(ino, false).unwrap();
+            let path = reader.path(ino).unwrap();
+            assert_eq!(reader.lookup(&path).unwrap().ino(), inode.ino());
+        }
+    }
+
+    /// Open a bootstrap of `tests/texture/repeatable`, with data in blob files of the localfs
+    /// backend.
+    fn new_localfs_reader(bootstrap: &str) -> RafsReader {
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let texture = PathBuf::from(root_dir).join("../tests/texture/repeatable");
+        let config = format!(
+            r#"
+        {{
+            "device": {{
+              "backend": {{
+                "type": "localfs",
+                "config": {{
+                  "dir": {:?}
+                }}
+              }}
+            }},
+            "mode": "direct",
+            "digest_validate": true
+          }}"#,
+            texture.join("blobs")
+        );
+        let conf = RafsConfig::from_str(&config).unwrap();
+
+        RafsReader::open(conf, bootstrap, &texture.join(bootstrap)).unwrap()
+    }
+
+    #[test]
+    fn it_should_read_file_data() {
+        let dir = "/normal-file-test/busybox/1f777dbdd68d1c4d554bc0d20e027bfef9ed2dfe2d8d5f029e958552a0e12475";
+        // Files spanning multiple chunks, with holes, and of a single small chunk.
+        let files = [
+            (
+                format!("{}/layer.tar", dir),
+                "0b97b1c81a3200e9eeb87f17a5d25a50791a16fa08fc41eb94ad15f26516ccea",
+            ),
+            (
+                "/normal-file-test/holefiles/head-hole-1".to_string(),
+                "97cc2c7a60468144b316c68ee9b9ac964f40928434011ab7ab6c4615647beac0",
+            ),
+            (
+                "/normal-file-test/holefiles/tail-hole-5000".to_string(),
+                "be3e04470c34613736b8fae43c4ca0385468c78bc8b0d2bb564f00c52205f857",
+            ),
+            (
+                format!("{}/VERSION", dir),
+                "d0ff5974b6aa52cf562bea5921840c032a860a91a3512f7fe8f768f6bbe005f6",
+            ),
+        ];
+
+        for bootstrap in &[
+            "sha256-nocompress-repeatable",
+            "blake3-lz4_block-non_repeatable",
+        ] {
+            let reader = new_localfs_reader(bootstrap);
+            for (path, digest) in files.iter() {
+                let data = reader.read_file(Path::new(path)).unwrap();
+                assert_eq!(&format!("{:x}", Sha256::digest(&data)), digest);
+
+                // Reads not aligned to chunks return the same data.
+                let inode = reader.lookup(Path::new(path)).unwrap();
+                let mut buf = vec![0u8; 300_000];
+                let mut offset = 0;
+                loop {
+                    let n = reader
+                        .read_at(inode.as_ref(), &mut buf, offset as u64)
+                        .unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    assert_eq!(&buf[..n], &data[offset..offset + n]);
+                    offset += n;
+                }
+                assert_eq!(offset, data.len());
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_not_read_dir() {
+        let reader = new_reader();
+        let root = reader.lookup(Path::new("/")).unwrap();
+        let mut buf = [0u8; 16];
+        let e = reader.read_at(root.as_ref(), &mut buf, 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EISDIR));
+    }
+}
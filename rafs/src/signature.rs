@@ -0,0 +1,1106 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detached signatures of bootstraps, so that a rafs is only mounted from trusted metadata.
+//!
+//! The signature covers the sha256 digest of the whole bootstrap, which in turn covers chunk
+//! digests of file data, so verifying it along with `digest_validate` ties data read from
+//! blobs to the signer as well. Keys are PEM encoded RSA, EC or Ed25519 keys; a signature is
+//! verified with the public key or an X.509 certificate holding it. The signature is saved as
+//! JSON next to the bootstrap, at `<bootstrap>.sig` by default:
+//!
+//! ```text
+//! {"digest": "sha256:<hex>", "signature": "<hex>"}
+//! ```
+//!
+//! Signatures made by other tools over the bootstrap file are verified as well, as per
+//! `format` in [`SignatureConfig`]:
+//! - `cosign`: the base64 signature written by `cosign sign-blob`, or the bundle written with
+//!   `--bundle`. A keyless bundle carries a short-lived certificate issued by Fulcio to an OIDC
+//!   identity, along with the Rekor log entry proving that the signature was made while the
+//!   certificate was valid.
+//! - `notation`: the JWS envelope written by `notation sign`, whose payload targets the
+//!   bootstrap by digest, with the certificate chain of the signer.
+//!
+//! Signers are trusted by their public keys, or by identities in certificates chaining up to
+//! `trust_roots`.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, PKeyRef, Private, Public};
+use openssl::rsa::Padding;
+use openssl::sign::{RsaPssSaltlen, Signer, Verifier};
+use openssl::x509::{X509Ref, X509VerifyResult, X509};
+use serde::{Deserialize, Serialize};
+use sha2::digest::Digest;
+use sha2::Sha256;
+
+use crate::RafsIoReader;
+
+/// Suffix of the signature file next to a bootstrap.
+pub const SIGNATURE_SUFFIX: &str = "sig";
+
+/// DER encoded OID 1.3.6.1.4.1.57264.1.1, extension of the OIDC issuer in Fulcio certificates.
+const FULCIO_ISSUER_OID: &[u8] = &[
+    0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xbf, 0x30, 0x01, 0x01,
+];
+const MAX_CHAIN_DEPTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureFormat {
+    /// Made by `nydus-image sign`.
+    Nydus,
+    Cosign,
+    Notation,
+}
+
+impl Default for SignatureFormat {
+    fn default() -> Self {
+        SignatureFormat::Nydus
+    }
+}
+
+/// Refuse to mount a bootstrap without a valid signature.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SignatureConfig {
+    #[serde(default)]
+    pub format: SignatureFormat,
+    /// PEM public key or X.509 certificate of the signer.
+    #[serde(default)]
+    pub public_key: Option<PathBuf>,
+    /// More signers, a signature made by any of them is accepted.
+    #[serde(default)]
+    pub public_keys: Vec<PathBuf>,
+    /// Signers trusted by certificates issued to them rather than by keys, for `cosign` and
+    /// `notation` only.
+    #[serde(default)]
+    pub identities: Vec<SignerIdentity>,
+    /// PEM bundle of CA certificates which certificates of `identities` chain up to.
+    #[serde(default)]
+    pub trust_roots: Option<PathBuf>,
+    /// PEM public key of the Rekor log, required by keyless `cosign` signatures.
+    #[serde(default)]
+    pub rekor_public_key: Option<PathBuf>,
+    /// Signature file of the bootstrap, `<bootstrap>.sig` if not given.
+    #[serde(default)]
+    pub signature: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct SignerIdentity {
+    /// Email or URI in subject alternative names of the certificate for `cosign`, or
+    /// attributes of the certificate subject like `CN=builder, O=example` for `notation`.
+    pub subject: String,
+    /// OIDC issuer recorded in the Fulcio certificate, any if not given. `cosign` only.
+    #[serde(default)]
+    pub issuer: Option<String>,
+}
+
+impl fmt::Display for SignerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.issuer.as_ref() {
+            Some(issuer) => write!(f, "{} ({})", self.subject, issuer),
+            None => write!(f, "{}", self.subject),
+        }
+    }
+}
+
+/// Signature verified of a mounted bootstrap.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SignatureStatus {
+    pub format: SignatureFormat,
+    /// Digest of the bootstrap, e.g. `sha256:<hex>`.
+    pub digest: String,
+    /// Public key file or identity of the signer.
+    pub signer: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BootstrapSignature {
+    /// Digest of the bootstrap, e.g. `sha256:<hex>`.
+    pub digest: String,
+    /// Signature over `digest` in hex.
+    pub signature: String,
+}
+
+impl BootstrapSignature {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = read_file(path, "signature")?;
+        serde_json::from_slice(&data)
+            .map_err(|e| einval!(format!("invalid signature {:?}, {}", path, e)))
+    }
+
+    /// Sign `digest` with the PEM private key `key`.
+    pub fn sign(digest: &str, key: &[u8]) -> Result<Self> {
+        let key = PKey::private_key_from_pem(key).map_err(|e| ssl_err("invalid private key", e))?;
+        let signature = sign_data(&key, digest.as_bytes()).map_err(|e| ssl_err("sign", e))?;
+
+        Ok(BootstrapSignature {
+            digest: digest.to_string(),
+            signature: to_hex(&signature),
+        })
+    }
+
+    /// Check that the signature is made over `digest` by the owner of `key`, a PEM public key
+    /// or certificate.
+    pub fn verify(&self, digest: &str, key: &[u8]) -> Result<()> {
+        self.check_digest(digest)?;
+        let key = public_key_from_pem(key)?;
+        if !self.made_by(&key)? {
+            return Err(einval!("signature is not made by the given key".to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn check_digest(&self, digest: &str) -> Result<()> {
+        if self.digest != digest {
+            return Err(einval!(format!(
+                "bootstrap digest {} doesn't match the signed one {}",
+                digest, self.digest
+            )));
+        }
+        Ok(())
+    }
+
+    fn made_by(&self, key: &PKeyRef<Public>) -> Result<bool> {
+        let signature = from_hex(&self.signature)
+            .ok_or_else(|| einval!("signature is not a hex string".to_string()))?;
+        verify_data(key, self.digest.as_bytes(), &signature).map_err(|e| ssl_err("verify", e))
+    }
+}
+
+/// Path of the signature file next to `bootstrap`.
+pub fn signature_path(bootstrap: &Path) -> PathBuf {
+    let mut name = bootstrap.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIGNATURE_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Digest of the bootstrap read from `r`, e.g. `sha256:<hex>`.
+pub fn bootstrap_digest<R: Read>(mut r: R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 0x10000];
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// Verify the bootstrap at `bootstrap` as per `config`.
+///
+/// The digest is taken from `r` rather than the path, so that the bootstrap loaded afterwards
+/// from `r` is the one verified even if the file is replaced meanwhile. `r` is rewound.
+pub fn verify_bootstrap(
+    bootstrap: &Path,
+    r: &mut RafsIoReader,
+    config: &SignatureConfig,
+) -> Result<SignatureStatus> {
+    let keys = config
+        .public_key
+        .iter()
+        .chain(config.public_keys.iter())
+        .map(|path| {
+            let key = public_key_from_pem(&read_file(path, "public key")?)?;
+            Ok((path.display().to_string(), key))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if keys.is_empty() && config.identities.is_empty() {
+        return Err(einval!(
+            "no public key or identity to verify signature with".to_string()
+        ));
+    }
+    let path = config
+        .signature
+        .clone()
+        .unwrap_or_else(|| signature_path(bootstrap));
+
+    r.seek(SeekFrom::Start(0))?;
+    let digest = bootstrap_digest(&mut *r)?;
+    let signer = match config.format {
+        SignatureFormat::Nydus => {
+            let signature = BootstrapSignature::from_file(&path)?;
+            signature.check_digest(&digest)?;
+            find_signer(&keys, |key| signature.made_by(key))
+                .ok_or_else(|| einval!("signature is not made by any trusted key".to_string()))?
+        }
+        SignatureFormat::Cosign => verify_cosign(r, &digest, &path, &keys, config)?,
+        SignatureFormat::Notation => verify_notation(&digest, &path, &keys, config)?,
+    };
+    r.seek(SeekFrom::Start(0))?;
+    info!(
+        "bootstrap {:?} is signed by {} with {}",
+        bootstrap, signer, digest
+    );
+
+    Ok(SignatureStatus {
+        format: config.format,
+        digest,
+        signer,
+    })
+}
+
+/// Bundle written by `cosign sign-blob --bundle`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CosignBundle {
+    base64_signature: String,
+    /// Base64 of the PEM certificate of a keyless signature.
+    #[serde(default)]
+    cert: Option<String>,
+    #[serde(default)]
+    rekor_bundle: Option<RekorBundle>,
+}
+
+#[derive(Deserialize)]
+struct RekorBundle {
+    #[serde(rename = "SignedEntryTimestamp")]
+    signed_entry_timestamp: String,
+    #[serde(rename = "Payload")]
+    payload: RekorPayload,
+}
+
+/// Fields are in the order of canonical JSON, which the signed entry timestamp is made over.
+#[derive(Deserialize, Serialize)]
+struct RekorPayload {
+    body: String,
+    #[serde(rename = "integratedTime")]
+    integrated_time: i64,
+    #[serde(rename = "logID")]
+    log_id: String,
+    #[serde(rename = "logIndex")]
+    log_index: u64,
+}
+
+fn verify_cosign(
+    r: &mut RafsIoReader,
+    digest: &str,
+    path: &Path,
+    keys: &[(String, PKey<Public>)],
+    config: &SignatureConfig,
+) -> Result<String> {
+    let data = read_file(path, "signature")?;
+    let bundle = serde_json::from_slice(&data).unwrap_or_else(|_| CosignBundle {
+        base64_signature: String::from_utf8_lossy(&data).trim().to_string(),
+        cert: None,
+        rekor_bundle: None,
+    });
+    let signature = decode_base64(&bundle.base64_signature, base64::STANDARD, "signature")?;
+
+    if let Some(signer) = find_signer(keys, |key| verify_reader(key, r, &signature)) {
+        return Ok(signer);
+    }
+    let cert = match bundle.cert.as_ref() {
+        Some(cert) if !config.identities.is_empty() => cert,
+        _ => {
+            return Err(einval!(
+                "signature is not made by any trusted key".to_string()
+            ))
+        }
+    };
+    let cert = X509::from_pem(&decode_base64(cert, base64::STANDARD, "certificate")?)
+        .map_err(|e| ssl_err("invalid certificate in signature", e))?;
+    let identity = config
+        .identities
+        .iter()
+        .find(|id| fulcio_identity_matches(&cert, id))
+        .ok_or_else(|| {
+            einval!("certificate of signature is not issued to any trusted identity".to_string())
+        })?;
+    // Fulcio certificates expire in minutes, they're checked at the time the signature is
+    // logged rather than now.
+    let rekor = bundle
+        .rekor_bundle
+        .as_ref()
+        .ok_or_else(|| einval!("no transparency log entry in keyless signature".to_string()))?;
+    let logged_time = verify_rekor_entry(config, rekor, digest, &bundle.base64_signature)?;
+    verify_chain(&cert, &[], config, Some(logged_time))?;
+
+    let key = cert
+        .public_key()
+        .map_err(|e| ssl_err("invalid certificate in signature", e))?;
+    if !verify_reader(&key, r, &signature)? {
+        return Err(einval!(
+            "signature is not made by its certificate".to_string()
+        ));
+    }
+
+    Ok(identity.to_string())
+}
+
+/// Check that `rekor` logs `signature` over the bootstrap of `digest`, returns the time it's
+/// logged in seconds since unix epoch.
+fn verify_rekor_entry(
+    config: &SignatureConfig,
+    rekor: &RekorBundle,
+    digest: &str,
+    signature: &str,
+) -> Result<i64> {
+    let path = config
+        .rekor_public_key
+        .as_ref()
+        .ok_or_else(|| einval!("rekor_public_key is required by keyless signatures".to_string()))?;
+    let key = public_key_from_pem(&read_file(path, "Rekor public key")?)?;
+    let payload = serde_json::to_vec(&rekor.payload).map_err(|e| einval!(e))?;
+    let timestamp = decode_base64(
+        &rekor.signed_entry_timestamp,
+        base64::STANDARD,
+        "signed entry timestamp",
+    )?;
+    if !verify_data(&key, &payload, &timestamp).map_err(|e| ssl_err("verify log entry", e))? {
+        return Err(einval!("log entry is not signed by Rekor".to_string()));
+    }
+
+    let body = decode_base64(&rekor.payload.body, base64::STANDARD, "log entry")?;
+    let entry: serde_json::Value =
+        serde_json::from_slice(&body).map_err(|e| einval!(format!("invalid log entry, {}", e)))?;
+    let hash = entry["spec"]["data"]["hash"]["value"]
+        .as_str()
+        .map(|h| format!("sha256:{}", h));
+    if hash.as_deref() != Some(digest)
+        || entry["spec"]["signature"]["content"].as_str() != Some(signature)
+    {
+        return Err(einval!(
+            "log entry is not of the signature of bootstrap".to_string()
+        ));
+    }
+
+    Ok(rekor.payload.integrated_time)
+}
+
+fn fulcio_identity_matches(cert: &X509Ref, identity: &SignerIdentity) -> bool {
+    let subject = identity.subject.as_str();
+    let issued_to = cert.subject_alt_names().map_or(false, |names| {
+        names
+            .iter()
+            .any(|n| n.email() == Some(subject) || n.uri() == Some(subject))
+    });
+    issued_to
+        && identity
+            .issuer
+            .as_ref()
+            .map_or(true, |issuer| fulcio_issuer(cert).as_ref() == Some(issuer))
+}
+
+/// OIDC issuer in a Fulcio certificate, stored as raw bytes in an extension which openssl
+/// doesn't know about, so it's looked up in DER of the certificate.
+fn fulcio_issuer(cert: &X509Ref) -> Option<String> {
+    let der = cert.to_der().ok()?;
+    let pos = der
+        .windows(FULCIO_ISSUER_OID.len())
+        .position(|w| w == FULCIO_ISSUER_OID)?;
+    let mut value = &der[pos + FULCIO_ISSUER_OID.len()..];
+    // Skip the critical flag, a BOOLEAN, if any.
+    if value.first() == Some(&0x01) {
+        value = value.get(3..)?;
+    }
+    // The value is an OCTET STRING, short enough to have its length in a single byte.
+    if value.first() != Some(&0x04) || *value.get(1)? >= 0x80 {
+        return None;
+    }
+    let len = value[1] as usize;
+    value
+        .get(2..2 + len)
+        .map(|v| String::from_utf8_lossy(v).to_string())
+}
+
+/// Envelope written by `notation sign`, in JWS JSON serialization.
+#[derive(Deserialize)]
+struct JwsEnvelope {
+    payload: String,
+    protected: String,
+    header: JwsHeader,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct JwsHeader {
+    /// Base64 DER certificates, the signer's first.
+    x5c: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JwsProtectedHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NotationPayload {
+    target_artifact: TargetArtifact,
+}
+
+#[derive(Deserialize)]
+struct TargetArtifact {
+    digest: String,
+}
+
+fn verify_notation(
+    digest: &str,
+    path: &Path,
+    keys: &[(String, PKey<Public>)],
+    config: &SignatureConfig,
+) -> Result<String> {
+    let envelope: JwsEnvelope = serde_json::from_slice(&read_file(path, "signature")?)
+        .map_err(|e| einval!(format!("invalid signature {:?}, {}", path, e)))?;
+    let protected: JwsProtectedHeader = serde_json::from_slice(&decode_base64(
+        &envelope.protected,
+        base64::URL_SAFE_NO_PAD,
+        "protected header",
+    )?)
+    .map_err(|e| einval!(format!("invalid protected header, {}", e)))?;
+    let payload: NotationPayload = serde_json::from_slice(&decode_base64(
+        &envelope.payload,
+        base64::URL_SAFE_NO_PAD,
+        "payload",
+    )?)
+    .map_err(|e| einval!(format!("invalid payload, {}", e)))?;
+    if payload.target_artifact.digest != digest {
+        return Err(einval!(format!(
+            "bootstrap digest {} doesn't match the signed one {}",
+            digest, payload.target_artifact.digest
+        )));
+    }
+
+    let certs = envelope
+        .header
+        .x5c
+        .iter()
+        .map(|c| {
+            X509::from_der(&decode_base64(c, base64::STANDARD, "certificate")?)
+                .map_err(|e| ssl_err("invalid certificate in signature", e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let leaf = certs
+        .first()
+        .ok_or_else(|| einval!("no certificate in signature".to_string()))?;
+    let key = leaf
+        .public_key()
+        .map_err(|e| ssl_err("invalid certificate in signature", e))?;
+    let signed = format!("{}.{}", envelope.protected, envelope.payload);
+    let signature = decode_base64(&envelope.signature, base64::URL_SAFE_NO_PAD, "signature")?;
+    if !verify_jws(&protected.alg, &key, signed.as_bytes(), &signature)? {
+        return Err(einval!(
+            "signature is not made by its certificate".to_string()
+        ));
+    }
+
+    if let Some((name, _)) = keys.iter().find(|(_, k)| k.public_eq(&key)) {
+        return Ok(name.clone());
+    }
+    let identity = config
+        .identities
+        .iter()
+        .find(|id| subject_matches(leaf, &id.subject))
+        .ok_or_else(|| {
+            einval!("signature is not made by any trusted key or identity".to_string())
+        })?;
+    verify_chain(leaf, &certs[1..], config, None)?;
+
+    Ok(identity.to_string())
+}
+
+fn verify_jws(alg: &str, key: &PKeyRef<Public>, data: &[u8], signature: &[u8]) -> Result<bool> {
+    let (md, pss) = match alg {
+        "PS256" => (MessageDigest::sha256(), true),
+        "PS384" => (MessageDigest::sha384(), true),
+        "PS512" => (MessageDigest::sha512(), true),
+        "ES256" => (MessageDigest::sha256(), false),
+        "ES384" => (MessageDigest::sha384(), false),
+        "ES512" => (MessageDigest::sha512(), false),
+        _ => return Err(einval!(format!("unsupported signature algorithm {}", alg))),
+    };
+
+    let verify = || -> std::result::Result<bool, ErrorStack> {
+        let mut verifier = Verifier::new(md, key)?;
+        if pss {
+            verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+            verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+            verifier.update(data)?;
+            return verifier.verify(signature);
+        }
+        verifier.update(data)?;
+        // JWS has ECDSA signatures as r || s rather than DER.
+        let (r, s) = signature.split_at(signature.len() / 2);
+        let signature =
+            EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?;
+        verifier.verify(&signature.to_der()?)
+    };
+    verify().map_err(|e| ssl_err("verify", e))
+}
+
+/// Whether the subject of `cert` has all attributes in `subject`, like `CN=builder, O=example`.
+fn subject_matches(cert: &X509Ref, subject: &str) -> bool {
+    let entries = cert
+        .subject_name()
+        .entries()
+        .filter_map(|e| {
+            let name = e.object().nid().short_name().ok()?;
+            let value = e.data().as_utf8().ok()?;
+            Some((name, value.to_string()))
+        })
+        .collect::<Vec<_>>();
+    subject.split(',').all(|attr| {
+        let mut kv = attr.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => entries
+                .iter()
+                .any(|(name, value)| *name == k.trim() && value == v.trim()),
+            _ => false,
+        }
+    })
+}
+
+/// Check that `cert` chains up to a certificate in `trust_roots` of `config`, through
+/// `intermediates`, and that certificates along the way are valid at `time` in seconds since
+/// unix epoch, or now.
+fn verify_chain(
+    cert: &X509Ref,
+    intermediates: &[X509],
+    config: &SignatureConfig,
+    time: Option<i64>,
+) -> Result<()> {
+    let path = config
+        .trust_roots
+        .as_ref()
+        .ok_or_else(|| einval!("trust_roots is required to verify certificates".to_string()))?;
+    let roots = X509::stack_from_pem(&read_file(path, "trust roots")?)
+        .map_err(|e| ssl_err("invalid trust roots", e))?;
+    let time = match time {
+        Some(t) => Asn1Time::from_unix(t as libc::time_t),
+        None => Asn1Time::days_from_now(0),
+    }
+    .map_err(|e| ssl_err("invalid time", e))?;
+
+    let mut cert = cert.to_owned();
+    // Bounded in case certificates cross-sign each other.
+    for _ in 0..MAX_CHAIN_DEPTH {
+        if !valid_at(&cert, &time)? {
+            return Err(einval!(
+                "certificate of signer is expired or not yet valid".to_string()
+            ));
+        }
+        if roots.iter().any(|root| issued_by(&cert, root)) {
+            return Ok(());
+        }
+        cert = intermediates
+            .iter()
+            .find(|c| issued_by(&cert, c))
+            .cloned()
+            .ok_or_else(|| einval!("certificate is not issued by any trusted root".to_string()))?;
+    }
+
+    Err(einval!("certificate chain is too long".to_string()))
+}
+
+fn issued_by(cert: &X509Ref, issuer: &X509Ref) -> bool {
+    issuer.issued(cert) == X509VerifyResult::OK
+        && issuer
+            .public_key()
+            .and_then(|key| cert.verify(&key))
+            .unwrap_or(false)
+}
+
+fn valid_at(cert: &X509Ref, time: &Asn1TimeRef) -> Result<bool> {
+    let started = cert
+        .not_before()
+        .compare(time)
+        .map_err(|e| ssl_err("invalid certificate", e))?;
+    let ended = cert
+        .not_after()
+        .compare(time)
+        .map_err(|e| ssl_err("invalid certificate", e))?;
+    Ok(started != Ordering::Greater && ended != Ordering::Less)
+}
+
+/// Name of the first of `keys` the signature is made by, as told by `made_by`.
+fn find_signer<F>(keys: &[(String, PKey<Public>)], mut made_by: F) -> Option<String>
+where
+    F: FnMut(&PKeyRef<Public>) -> Result<bool>,
+{
+    for (name, key) in keys.iter() {
+        match made_by(key) {
+            Ok(true) => return Some(name.clone()),
+            Ok(false) => {}
+            // e.g. the signature is made by another type of key
+            Err(e) => debug!("signature is not made by {}, {}", name, e),
+        }
+    }
+    None
+}
+
+/// Verify `signature` over the whole bootstrap read from `r`, which is rewound first.
+fn verify_reader(key: &PKeyRef<Public>, r: &mut RafsIoReader, signature: &[u8]) -> Result<bool> {
+    r.seek(SeekFrom::Start(0))?;
+    // Ed25519 can't be fed in pieces.
+    if key.id() == Id::ED25519 {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        return verify_data(key, &data, signature).map_err(|e| ssl_err("verify", e));
+    }
+
+    let mut verifier =
+        Verifier::new(MessageDigest::sha256(), key).map_err(|e| ssl_err("verify", e))?;
+    let mut buf = vec![0u8; 0x10000];
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        verifier
+            .update(&buf[..n])
+            .map_err(|e| ssl_err("verify", e))?;
+    }
+    verifier.verify(signature).map_err(|e| ssl_err("verify", e))
+}
+
+fn read_file(path: &Path, what: &str) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("failed to read {} {:?}, {}", what, path, e),
+        )
+    })
+}
+
+fn decode_base64(s: &str, config: base64::Config, what: &str) -> Result<Vec<u8>> {
+    base64::decode_config(s, config).map_err(|e| einval!(format!("invalid {}, {}", what, e)))
+}
+
+fn ssl_err(what: &str, e: ErrorStack) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}, {}", what, e))
+}
+
+fn public_key_from_pem(pem: &[u8]) -> Result<PKey<Public>> {
+    if let Ok(cert) = X509::from_pem(pem) {
+        return cert
+            .public_key()
+            .map_err(|e| ssl_err("invalid certificate", e));
+    }
+    PKey::public_key_from_pem(pem).map_err(|e| ssl_err("invalid public key or certificate", e))
+}
+
+// Ed25519 hashes the message itself, others sign the sha256 digest of it.
+fn sign_data(key: &PKeyRef<Private>, data: &[u8]) -> std::result::Result<Vec<u8>, ErrorStack> {
+    if key.id() == Id::ED25519 {
+        Signer::new_without_digest(key)?.sign_oneshot_to_vec(data)
+    } else {
+        let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+        signer.update(data)?;
+        signer.sign_to_vec()
+    }
+}
+
+fn verify_data(
+    key: &PKeyRef<Public>,
+    data: &[u8],
+    signature: &[u8],
+) -> std::result::Result<bool, ErrorStack> {
+    if key.id() == Id::ED25519 {
+        Verifier::new_without_digest(key)?.verify_oneshot(signature, data)
+    } else {
+        let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+        verifier.update(data)?;
+        verifier.verify(signature)
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Integer;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509Extension, X509NameBuilder};
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use vmm_sys_util::tempdir::TempDir;
+
+    const BOOTSTRAP: &[u8] = b"bootstrap";
+    const ISSUER: &str = "https://accounts.example.com";
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn write_file(dir: &TempDir, name: &str, data: &[u8]) -> PathBuf {
+        let path = dir.as_path().join(name);
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    fn open_bootstrap(path: &Path) -> RafsIoReader {
+        Box::new(File::open(path).unwrap())
+    }
+
+    /// Certificate of `key` with subject `O=example, CN=<cn>`, self-signed if `issuer` is none.
+    fn make_cert(
+        cn: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        email: Option<&str>,
+        days: u32,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "example").unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap();
+        builder
+            .set_serial_number(&Asn1Integer::from_bn(&serial).unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        match issuer {
+            Some((cert, _)) => builder.set_issuer_name(cert.subject_name()).unwrap(),
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                builder.append_extension(ca).unwrap();
+            }
+        }
+        if let Some(email) = email {
+            let san = SubjectAlternativeName::new()
+                .email(email)
+                .build(&builder.x509v3_context(issuer.map(|i| i.0.as_ref()), None))
+                .unwrap();
+            builder.append_extension(san).unwrap();
+            let value = ISSUER
+                .bytes()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(":");
+            let ext = X509Extension::new(
+                None,
+                None,
+                "1.3.6.1.4.1.57264.1.1",
+                &format!("DER:{}", value),
+            )
+            .unwrap();
+            builder.append_extension(ext).unwrap();
+        }
+        builder
+            .sign(issuer.map_or(key, |i| i.1), MessageDigest::sha256())
+            .unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let digest = bootstrap_digest(Cursor::new(b"bootstrap")).unwrap();
+        assert!(digest.starts_with("sha256:"));
+        assert_eq!(digest.len(), 7 + 64);
+
+        for key in &[ec_key(), PKey::generate_ed25519().unwrap()] {
+            let private = key.private_key_to_pem_pkcs8().unwrap();
+            let public = key.public_key_to_pem().unwrap();
+            let sig = BootstrapSignature::sign(&digest, &private).unwrap();
+
+            sig.verify(&digest, &public).unwrap();
+            let other = bootstrap_digest(Cursor::new(b"tampered")).unwrap();
+            assert!(sig.verify(&other, &public).is_err());
+
+            let mut forged = BootstrapSignature {
+                digest: other.clone(),
+                signature: sig.signature.clone(),
+            };
+            assert!(forged.verify(&other, &public).is_err());
+            forged.signature = "xyz".to_string();
+            assert!(forged.verify(&other, &public).is_err());
+
+            let stranger = ec_key().public_key_to_pem().unwrap();
+            assert!(sig.verify(&digest, &stranger).is_err());
+        }
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0, 0xab, 0x10]));
+        assert_eq!(from_hex("0ab"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(
+            signature_path(Path::new("/images/bootstrap")),
+            PathBuf::from("/images/bootstrap.sig")
+        );
+    }
+
+    #[test]
+    fn test_verify_with_any_key() {
+        let dir = TempDir::new().unwrap();
+        let bootstrap = write_file(&dir, "bootstrap", BOOTSTRAP);
+        let digest = bootstrap_digest(Cursor::new(BOOTSTRAP)).unwrap();
+        let key = ec_key();
+        let sig =
+            BootstrapSignature::sign(&digest, &key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        write_file(&dir, "bootstrap.sig", &serde_json::to_vec(&sig).unwrap());
+        let stranger = write_file(&dir, "stranger.pem", &ec_key().public_key_to_pem().unwrap());
+        let signer = write_file(&dir, "signer.pem", &key.public_key_to_pem().unwrap());
+
+        let mut config = SignatureConfig {
+            public_key: Some(stranger.clone()),
+            ..Default::default()
+        };
+        let mut r = open_bootstrap(&bootstrap);
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+
+        config.public_keys = vec![signer.clone()];
+        let status = verify_bootstrap(&bootstrap, &mut r, &config).unwrap();
+        assert_eq!(
+            status,
+            SignatureStatus {
+                format: SignatureFormat::Nydus,
+                digest,
+                signer: signer.display().to_string(),
+            }
+        );
+        assert_eq!(r.seek(SeekFrom::Current(0)).unwrap(), 0);
+
+        // Signatures of nydus-image are verified with keys only.
+        config.public_key = None;
+        config.public_keys.clear();
+        config.identities = vec![SignerIdentity {
+            subject: "O=example".to_string(),
+            issuer: None,
+        }];
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+    }
+
+    #[test]
+    fn test_verify_cosign_with_key() {
+        let dir = TempDir::new().unwrap();
+        let bootstrap = write_file(&dir, "bootstrap", BOOTSTRAP);
+        let key = ec_key();
+        let signature = base64::encode(&sign_data(&key, BOOTSTRAP).unwrap());
+        write_file(&dir, "bootstrap.sig", format!("{}\n", signature).as_bytes());
+        let signer = write_file(&dir, "cosign.pub", &key.public_key_to_pem().unwrap());
+
+        let config = SignatureConfig {
+            format: SignatureFormat::Cosign,
+            public_keys: vec![signer],
+            ..Default::default()
+        };
+        let mut r = open_bootstrap(&bootstrap);
+        let status = verify_bootstrap(&bootstrap, &mut r, &config).unwrap();
+        assert_eq!(status.format, SignatureFormat::Cosign);
+
+        let tampered = write_file(&dir, "tampered", b"tampered");
+        let mut r = open_bootstrap(&tampered);
+        let config = SignatureConfig {
+            signature: Some(dir.as_path().join("bootstrap.sig")),
+            ..config
+        };
+        assert!(verify_bootstrap(&tampered, &mut r, &config).is_err());
+    }
+
+    #[test]
+    fn test_verify_cosign_keyless() {
+        let dir = TempDir::new().unwrap();
+        let bootstrap = write_file(&dir, "bootstrap", BOOTSTRAP);
+        let digest = bootstrap_digest(Cursor::new(BOOTSTRAP)).unwrap();
+        let ca_key = ec_key();
+        let ca = make_cert("fulcio", &ca_key, None, None, 10);
+        let key = ec_key();
+        let cert = make_cert(
+            "sigstore",
+            &key,
+            Some((&ca, &ca_key)),
+            Some("dev@example.com"),
+            1,
+        );
+        assert_eq!(fulcio_issuer(&cert), Some(ISSUER.to_string()));
+        let rekor_key = ec_key();
+        let signature = base64::encode(&sign_data(&key, BOOTSTRAP).unwrap());
+
+        let make_bundle = |integrated_time: i64| {
+            let body = serde_json::json!({
+                "apiVersion": "0.0.1",
+                "kind": "hashedrekord",
+                "spec": {
+                    "data": {"hash": {"algorithm": "sha256", "value": &digest[7..]}},
+                    "signature": {"content": &signature},
+                },
+            });
+            let payload = RekorPayload {
+                body: base64::encode(&serde_json::to_vec(&body).unwrap()),
+                integrated_time,
+                log_id: "c0d23d6a".to_string(),
+                log_index: 1,
+            };
+            let timestamp = sign_data(&rekor_key, &serde_json::to_vec(&payload).unwrap()).unwrap();
+            let bundle = serde_json::json!({
+                "base64Signature": &signature,
+                "cert": base64::encode(&cert.to_pem().unwrap()),
+                "rekorBundle": {
+                    "SignedEntryTimestamp": base64::encode(&timestamp),
+                    "Payload": payload,
+                },
+            });
+            write_file(&dir, "bootstrap.sig", &serde_json::to_vec(&bundle).unwrap());
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        make_bundle(now);
+
+        let mut config = SignatureConfig {
+            format: SignatureFormat::Cosign,
+            identities: vec![SignerIdentity {
+                subject: "dev@example.com".to_string(),
+                issuer: Some(ISSUER.to_string()),
+            }],
+            trust_roots: Some(write_file(&dir, "roots.pem", &ca.to_pem().unwrap())),
+            ..Default::default()
+        };
+        let mut r = open_bootstrap(&bootstrap);
+        // Rekor public key is missing.
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+
+        config.rekor_public_key = Some(write_file(
+            &dir,
+            "rekor.pem",
+            &rekor_key.public_key_to_pem().unwrap(),
+        ));
+        let status = verify_bootstrap(&bootstrap, &mut r, &config).unwrap();
+        assert_eq!(status.signer, format!("dev@example.com ({})", ISSUER));
+
+        config.identities[0].issuer = Some("https://other.example.com".to_string());
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+        config.identities[0].issuer = None;
+        verify_bootstrap(&bootstrap, &mut r, &config).unwrap();
+
+        // Logged after the certificate expired.
+        make_bundle(now + 3 * 86400);
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+    }
+
+    #[test]
+    fn test_verify_notation() {
+        let dir = TempDir::new().unwrap();
+        let bootstrap = write_file(&dir, "bootstrap", BOOTSTRAP);
+        let digest = bootstrap_digest(Cursor::new(BOOTSTRAP)).unwrap();
+        let ca_key = ec_key();
+        let ca = make_cert("notary", &ca_key, None, None, 10);
+        let key = ec_key();
+        let cert = make_cert("builder", &key, Some((&ca, &ca_key)), None, 1);
+
+        let make_envelope = |digest: &str| {
+            let encode = |v: serde_json::Value| {
+                base64::encode_config(&serde_json::to_vec(&v).unwrap(), base64::URL_SAFE_NO_PAD)
+            };
+            let protected = encode(serde_json::json!({
+                "alg": "ES256",
+                "cty": "application/vnd.cncf.notary.payload.v1+json",
+            }));
+            let payload = encode(serde_json::json!({
+                "targetArtifact": {
+                    "mediaType": "application/octet-stream",
+                    "digest": digest,
+                    "size": BOOTSTRAP.len(),
+                },
+            }));
+            let der = sign_data(&key, format!("{}.{}", protected, payload).as_bytes()).unwrap();
+            let sig = EcdsaSig::from_der(&der).unwrap();
+            let mut signature = Vec::new();
+            for n in &[sig.r(), sig.s()] {
+                let bytes = n.to_vec();
+                signature.extend(vec![0u8; 32 - bytes.len()]);
+                signature.extend(bytes);
+            }
+            let envelope = serde_json::json!({
+                "payload": payload,
+                "protected": protected,
+                "header": {
+                    "x5c": [
+                        base64::encode(&cert.to_der().unwrap()),
+                        base64::encode(&ca.to_der().unwrap()),
+                    ],
+                },
+                "signature": base64::encode_config(&signature, base64::URL_SAFE_NO_PAD),
+            });
+            write_file(
+                &dir,
+                "bootstrap.sig",
+                &serde_json::to_vec(&envelope).unwrap(),
+            );
+        };
+        make_envelope(&digest);
+
+        let mut config = SignatureConfig {
+            format: SignatureFormat::Notation,
+            identities: vec![SignerIdentity {
+                subject: "CN=builder, O=example".to_string(),
+                issuer: None,
+            }],
+            trust_roots: Some(write_file(&dir, "roots.pem", &ca.to_pem().unwrap())),
+            ..Default::default()
+        };
+        let mut r = open_bootstrap(&bootstrap);
+        let status = verify_bootstrap(&bootstrap, &mut r, &config).unwrap();
+        assert_eq!(status.signer, "CN=builder, O=example");
+
+        config.identities[0].subject = "CN=someone, O=example".to_string();
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+
+        // Trusted by key, without any root.
+        config.trust_roots = None;
+        config.public_key = Some(write_file(&dir, "cert.pem", &cert.to_pem().unwrap()));
+        verify_bootstrap(&bootstrap, &mut r, &config).unwrap();
+
+        // Not issued by the trusted root.
+        let other_key = ec_key();
+        let other = make_cert("other", &other_key, None, None, 10);
+        config.public_key = None;
+        config.identities[0].subject = "CN=builder".to_string();
+        config.trust_roots = Some(write_file(&dir, "other.pem", &other.to_pem().unwrap()));
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+
+        let tampered = bootstrap_digest(Cursor::new(b"tampered")).unwrap();
+        make_envelope(&tampered);
+        config.public_key = Some(dir.as_path().join("cert.pem"));
+        assert!(verify_bootstrap(&bootstrap, &mut r, &config).is_err());
+    }
+}
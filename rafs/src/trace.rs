@@ -0,0 +1,291 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Record reads of a rafs to a trace file, to be replayed against other configurations.
+//!
+//! A trace starts with a header, followed by entries each led by a one byte tag. A path entry
+//! names a file the first time it's read, following read entries refer to the file by its
+//! number. All integers are little endian.
+//!
+//! ```text
+//! header: magic "NYDUSTRC", version u32, reserved u32
+//! path:   tag 1, file u32, length u16, path bytes
+//! read:   tag 2, file u32, offset u64, size u32, microseconds since trace started u64
+//! ```
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metadata::{Inode, RafsSuper};
+
+const TRACE_MAGIC: &[u8; 8] = b"NYDUSTRC";
+const TRACE_VERSION: u32 = 1;
+const TAG_PATH: u8 = 1;
+const TAG_READ: u8 = 2;
+/// Buffered entries are flushed at most this late, so that a trace can be taken while reads
+/// are still going on.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A read recorded in a trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRead {
+    /// Absolute path within the image.
+    pub path: PathBuf,
+    pub offset: u64,
+    pub size: u32,
+    /// Microseconds since the trace started.
+    pub timestamp_us: u64,
+}
+
+struct TraceState {
+    writer: BufWriter<File>,
+    // number of traced files by inode
+    files: HashMap<Inode, u32>,
+    flushed_at: Instant,
+}
+
+/// Appends reads of a rafs to a trace file.
+pub struct AccessTrace {
+    start: Instant,
+    state: Mutex<TraceState>,
+}
+
+impl AccessTrace {
+    /// Create a trace at `path`, an existing file is truncated.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(TRACE_MAGIC)?;
+        writer.write_all(&TRACE_VERSION.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.flush()?;
+        info!("reads traced to {:?}", path);
+
+        Ok(AccessTrace {
+            start: Instant::now(),
+            state: Mutex::new(TraceState {
+                writer,
+                files: HashMap::new(),
+                flushed_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Record a read of `size` bytes at `offset` of inode `ino`.
+    pub fn record(&self, sb: &RafsSuper, ino: Inode, offset: u64, size: u32) -> Result<()> {
+        let timestamp_us = self.start.elapsed().as_micros() as u64;
+        let mut state = self.state.lock().unwrap();
+        let file = match state.files.get(&ino) {
+            Some(f) => *f,
+            None => {
+                let path = sb.path_from_ino(ino)?;
+                let bytes = path.as_os_str().as_bytes();
+                if bytes.len() > u16::MAX as usize {
+                    return Err(einval!("path too long to be traced"));
+                }
+                let file = state.files.len() as u32;
+                let mut entry = Vec::with_capacity(7 + bytes.len());
+                entry.push(TAG_PATH);
+                entry.extend_from_slice(&file.to_le_bytes());
+                entry.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+                entry.extend_from_slice(bytes);
+                state.writer.write_all(&entry)?;
+                state.files.insert(ino, file);
+                file
+            }
+        };
+
+        let mut entry = [0u8; 25];
+        entry[0] = TAG_READ;
+        entry[1..5].copy_from_slice(&file.to_le_bytes());
+        entry[5..13].copy_from_slice(&offset.to_le_bytes());
+        entry[13..17].copy_from_slice(&size.to_le_bytes());
+        entry[17..25].copy_from_slice(&timestamp_us.to_le_bytes());
+        state.writer.write_all(&entry)?;
+
+        if state.flushed_at.elapsed() >= FLUSH_INTERVAL {
+            state.writer.flush()?;
+            state.flushed_at = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Forget inode numbers, which may refer to other files once the bootstrap is switched.
+    /// Files are numbered again when they're read next time.
+    pub fn reset_files(&self) {
+        self.state.lock().unwrap().files.clear();
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.state.lock().unwrap().writer.flush()
+    }
+}
+
+/// Iterates over reads of a trace file in the order they were recorded.
+pub struct TraceReader<R: Read> {
+    reader: R,
+    files: HashMap<u32, PathBuf>,
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != TRACE_MAGIC {
+            return Err(einval!("not a rafs access trace"));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != TRACE_VERSION {
+            return Err(einval!(format!("unsupported trace version {}", version)));
+        }
+
+        Ok(TraceReader {
+            reader,
+            files: HashMap::new(),
+        })
+    }
+
+    fn next_read(&mut self) -> Result<Option<TraceRead>> {
+        loop {
+            let mut tag = [0u8; 1];
+            match self.reader.read_exact(&mut tag) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            match tag[0] {
+                TAG_PATH => {
+                    let mut head = [0u8; 6];
+                    self.reader.read_exact(&mut head)?;
+                    let file = u32::from_le_bytes(head[..4].try_into().unwrap());
+                    let len = u16::from_le_bytes(head[4..].try_into().unwrap());
+                    let mut path = vec![0u8; len as usize];
+                    self.reader.read_exact(&mut path)?;
+                    self.files
+                        .insert(file, PathBuf::from(OsString::from_vec(path)));
+                }
+                TAG_READ => {
+                    let mut entry = [0u8; 24];
+                    self.reader.read_exact(&mut entry)?;
+                    let file = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                    let path = self
+                        .files
+                        .get(&file)
+                        .ok_or_else(|| einval!(format!("unknown file {} in trace", file)))?;
+                    return Ok(Some(TraceRead {
+                        path: path.clone(),
+                        offset: u64::from_le_bytes(entry[4..12].try_into().unwrap()),
+                        size: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+                        timestamp_us: u64::from_le_bytes(entry[16..].try_into().unwrap()),
+                    }));
+                }
+                t => return Err(einval!(format!("invalid trace entry tag {}", t))),
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRead>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_read().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::fs::RafsConfig;
+    use crate::RafsIoRead;
+
+    fn load_super() -> RafsSuper {
+        let config = r#"
+        {
+            "device": {
+              "backend": {
+                "type": "localfs",
+                "config": {
+                  "dir": "/tmp"
+                }
+              }
+            },
+            "mode": "direct",
+            "digest_validate": false
+          }"#;
+        let root_dir = &std::env::var("CARGO_MANIFEST_DIR").expect("$CARGO_MANIFEST_DIR");
+        let mut source_path = PathBuf::from(root_dir);
+        source_path.push("../tests/texture/bootstrap/image_v2.boot");
+        let conf = RafsConfig::from_str(config).unwrap();
+        let mut sb = RafsSuper::new(&conf).unwrap();
+        let mut r = RafsIoRead::from_file(source_path.to_str().unwrap()).unwrap();
+        sb.load(&mut r).unwrap();
+        sb
+    }
+
+    #[test]
+    fn test_trace_roundtrip() {
+        let sb = load_super();
+        let tmp = TempFile::new().unwrap();
+        let trace = AccessTrace::create(tmp.as_path()).unwrap();
+        let ino = sb.get_max_ino();
+        trace.record(&sb, ino, 0, 4096).unwrap();
+        trace.record(&sb, ino, 4096, 1024).unwrap();
+        trace.record(&sb, 1, 0, 16).unwrap();
+        trace.flush().unwrap();
+
+        let reads = TraceReader::open(tmp.as_path())
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(reads.len(), 3);
+        assert_eq!(reads[0].path, sb.path_from_ino(ino).unwrap());
+        assert_eq!(reads[1].path, reads[0].path);
+        assert_eq!((reads[1].offset, reads[1].size), (4096, 1024));
+        assert_eq!(reads[2].path, sb.path_from_ino(1).unwrap());
+        assert!(reads[0].timestamp_us <= reads[2].timestamp_us);
+
+        // Path entries are written once per file.
+        let len = std::fs::metadata(tmp.as_path()).unwrap().len() as usize;
+        let paths = reads[0].path.as_os_str().len() + reads[2].path.as_os_str().len();
+        assert_eq!(len, 16 + 3 * 25 + 2 * 7 + paths);
+    }
+
+    #[test]
+    fn test_invalid_trace() {
+        assert!(TraceReader::new(&b"NYDUSTR"[..]).is_err());
+        let mut data = TRACE_MAGIC.to_vec();
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        assert!(TraceReader::new(&data[..]).is_err());
+
+        data[8] = 1;
+        data.push(TAG_READ);
+        data.extend_from_slice(&[0u8; 24]);
+        let mut reader = TraceReader::new(&data[..]).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+}
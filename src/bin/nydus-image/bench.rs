@@ -0,0 +1,468 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Benchmark reading a RAFS image with a rafs configuration, to evaluate backend and cache
+//! settings without mounting the image.
+//!
+//! The workload is either reads recorded in an access trace or a synthetic one. The same reads
+//! are sent in multiple rounds, so the first round shows how fast data is fetched from the
+//! backend, unless it's cached already, and later rounds how fast the cache serves it. Besides
+//! latencies of reads, requests to the backend and hits of the blobcache are counted per round
+//! from their metrics.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use nydus_utils::metrics::{self, MountIoStats};
+use rafs::fs::RafsConfig;
+use rafs::metadata::layout::RAFS_ROOT_INODE;
+use rafs::metadata::{RafsInode, RafsSuper};
+use rafs::reader::RafsReader;
+use rafs::trace::TraceReader;
+
+/// Id of the image opened, which metrics of its backend and cache are registered with.
+const BENCH_ID: &str = "bench";
+
+pub enum Workload {
+    /// Reads recorded by `"access_trace"` of a rafs, sent back to back regardless of timing.
+    Trace(PathBuf),
+    /// All regular files read from start to end in path order.
+    Sequential,
+    /// Reads of random files at random offsets aligned to the block size. They are chosen by a
+    /// pseudo random generator seeded with `seed`, so runs with the same seed are comparable.
+    Random { reads: u64, seed: u64 },
+}
+
+pub struct BenchOptions {
+    pub workload: Workload,
+    /// Size of synthetic reads.
+    pub block_size: u32,
+    pub threads: usize,
+    pub rounds: u32,
+}
+
+/// A read of `size` bytes at `offset` of the file at `path`.
+#[derive(Debug, PartialEq)]
+struct ReadOp {
+    path: PathBuf,
+    offset: u64,
+    size: u32,
+}
+
+/// Statistics of a round, with those of the backend and the blobcache counted in it.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct RoundReport {
+    /// Counted from 1.
+    pub round: u32,
+    pub reads: u64,
+    pub errors: u64,
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+    /// Bytes read per second.
+    pub throughput: u64,
+    pub latency_avg_us: u64,
+    pub latency_p50_us: u64,
+    pub latency_p99_us: u64,
+    pub latency_max_us: u64,
+    /// Reads to the blobcache and those served by it, absent without blobcache.
+    pub cache_reads: Option<u64>,
+    pub cache_hits: Option<u64>,
+    /// Requests to the backend, bytes fetched and average latency of them.
+    pub backend_reads: Option<u64>,
+    pub backend_read_bytes: Option<u64>,
+    pub backend_latency_avg_us: Option<u64>,
+}
+
+impl RoundReport {
+    fn new(round: u32, mut stats: WorkerStats, elapsed_us: u64) -> Self {
+        let latencies = &mut stats.latencies;
+        latencies.sort_unstable();
+        let percentile = |p: usize| {
+            if latencies.is_empty() {
+                0
+            } else {
+                latencies[(latencies.len() - 1) * p / 100]
+            }
+        };
+        let total: u64 = latencies.iter().sum();
+
+        RoundReport {
+            round,
+            reads: latencies.len() as u64,
+            errors: stats.errors,
+            bytes: stats.bytes,
+            elapsed_ms: elapsed_us / 1000,
+            throughput: (stats.bytes as u128 * 1_000_000 / std::cmp::max(elapsed_us, 1) as u128)
+                as u64,
+            latency_avg_us: total.checked_div(latencies.len() as u64).unwrap_or(0),
+            latency_p50_us: percentile(50),
+            latency_p99_us: percentile(99),
+            latency_max_us: latencies.last().copied().unwrap_or(0),
+            ..Default::default()
+        }
+    }
+
+    /// Set statistics of the blobcache and the backend as changed from `before` to `after`.
+    fn set_tiers(&mut self, before: &MountIoStats, after: &MountIoStats) {
+        let delta =
+            |b: Option<usize>, a: Option<usize>| a.map(|a| a.saturating_sub(b.unwrap_or(0)) as u64);
+        self.cache_reads = delta(before.cache_reads, after.cache_reads);
+        self.cache_hits = delta(before.cache_hits, after.cache_hits);
+        self.backend_reads = delta(before.backend_reads, after.backend_reads);
+        self.backend_read_bytes = delta(before.backend_read_bytes, after.backend_read_bytes);
+        let latency = delta(
+            before.backend_read_latency_us,
+            after.backend_read_latency_us,
+        );
+        self.backend_latency_avg_us = match (latency, self.backend_reads) {
+            (Some(l), Some(n)) => l.checked_div(n),
+            _ => None,
+        };
+    }
+}
+
+/// What a worker has done in a round.
+#[derive(Default)]
+struct WorkerStats {
+    /// Latencies of successful reads in microseconds.
+    latencies: Vec<u64>,
+    errors: u64,
+    bytes: u64,
+}
+
+/// Pseudo random numbers by xorshift64*, good enough to pick reads.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero.
+        Rng(if seed == 0 { 1 } else { seed })
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+pub struct Bench {
+    reader: Arc<RafsReader>,
+    options: BenchOptions,
+}
+
+impl Bench {
+    /// Open the image of `bootstrap` with its storage device set up as `config` tells.
+    pub fn new(bootstrap: &Path, config: RafsConfig, mut options: BenchOptions) -> Result<Self> {
+        let reader = RafsReader::open(config, BENCH_ID, bootstrap)
+            .map_err(|e| anyhow!("failed to open image {:?}, {:?}", bootstrap, e))?;
+        options.threads = std::cmp::max(options.threads, 1);
+        options.block_size = std::cmp::max(options.block_size, 1);
+
+        Ok(Bench {
+            reader: Arc::new(reader),
+            options,
+        })
+    }
+
+    /// Send reads of the workload in all rounds, one after another.
+    pub fn run(&self) -> Result<Vec<RoundReport>> {
+        let ops = match &self.options.workload {
+            Workload::Trace(path) => trace_reads(path)?,
+            workload => {
+                let files =
+                    regular_files(self.reader.super_block()).context("failed to walk inodes")?;
+                synthetic_reads(&files, workload, self.options.block_size)
+            }
+        };
+        if ops.is_empty() {
+            bail!("no read in workload");
+        }
+        info!("{} reads in workload", ops.len());
+
+        let ops = Arc::new(ops);
+        let mut reports = Vec::with_capacity(self.options.rounds as usize);
+        for round in 1..=self.options.rounds {
+            let report = self.run_round(round, &ops)?;
+            info!(
+                "round {} done in {}ms, {} errors",
+                round, report.elapsed_ms, report.errors
+            );
+            reports.push(report);
+        }
+
+        Ok(reports)
+    }
+
+    fn run_round(&self, round: u32, ops: &Arc<Vec<ReadOp>>) -> Result<RoundReport> {
+        let before = metrics::mount_io_stats(BENCH_ID);
+        let next = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+
+        let workers = (0..self.options.threads)
+            .map(|_| {
+                let reader = self.reader.clone();
+                let ops = ops.clone();
+                let next = next.clone();
+                thread::spawn(move || read_worker(&reader, &ops, &next))
+            })
+            .collect::<Vec<_>>();
+        let mut stats = WorkerStats::default();
+        for worker in workers {
+            let s = worker
+                .join()
+                .map_err(|_| anyhow!("bench worker panicked"))?;
+            stats.latencies.extend(s.latencies);
+            stats.errors += s.errors;
+            stats.bytes += s.bytes;
+        }
+
+        let mut report = RoundReport::new(round, stats, start.elapsed().as_micros() as u64);
+        report.set_tiers(&before, &metrics::mount_io_stats(BENCH_ID));
+        Ok(report)
+    }
+}
+
+/// Take reads from `ops` in order until they are all taken, by multiple workers.
+fn read_worker(reader: &RafsReader, ops: &[ReadOp], next: &AtomicUsize) -> WorkerStats {
+    let mut stats = WorkerStats::default();
+    let mut inodes: HashMap<&Path, Arc<dyn RafsInode>> = HashMap::new();
+    let mut buf = Vec::new();
+
+    loop {
+        let idx = next.fetch_add(1, Ordering::Relaxed);
+        if idx >= ops.len() {
+            break;
+        }
+        let op = &ops[idx];
+        let inode = match inodes.entry(op.path.as_path()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => match reader.lookup(&op.path) {
+                Ok(inode) => e.insert(inode),
+                Err(err) => {
+                    warn!("failed to look up {:?}, {}", op.path, err);
+                    stats.errors += 1;
+                    continue;
+                }
+            },
+        };
+
+        buf.resize(op.size as usize, 0);
+        let begin = Instant::now();
+        match reader.read_at(inode.as_ref(), &mut buf, op.offset) {
+            Ok(n) => {
+                stats.latencies.push(begin.elapsed().as_micros() as u64);
+                stats.bytes += n as u64;
+            }
+            Err(err) => {
+                warn!("failed to read {:?} at {}, {}", op.path, op.offset, err);
+                stats.errors += 1;
+            }
+        }
+    }
+
+    stats
+}
+
+fn trace_reads(path: &Path) -> Result<Vec<ReadOp>> {
+    let reads = TraceReader::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let mut ops = Vec::new();
+    for r in reads {
+        let r = r.with_context(|| format!("invalid trace {:?}", path))?;
+        ops.push(ReadOp {
+            path: r.path,
+            offset: r.offset,
+            size: r.size,
+        });
+    }
+    Ok(ops)
+}
+
+/// Paths and sizes of non-empty regular files of an image, in path order.
+fn regular_files(rs: &RafsSuper) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(rs.get_inode(RAFS_ROOT_INODE, false)?, PathBuf::from("/"))];
+
+    while let Some((dir, dir_path)) = dirs.pop() {
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            let path = dir_path.join(child.name());
+            if child.is_dir() {
+                dirs.push((child, path));
+            } else if child.is_reg() && child.size() > 0 {
+                files.push((path, child.size()));
+            }
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+fn synthetic_reads(files: &[(PathBuf, u64)], workload: &Workload, block_size: u32) -> Vec<ReadOp> {
+    let block_size = block_size as u64;
+    let op = |path: &Path, size: u64, offset: u64| ReadOp {
+        path: path.to_path_buf(),
+        offset,
+        size: std::cmp::min(block_size, size - offset) as u32,
+    };
+
+    match workload {
+        Workload::Trace(_) => Vec::new(),
+        Workload::Sequential => files
+            .iter()
+            .flat_map(|(path, size)| {
+                (0..(size + block_size - 1) / block_size)
+                    .map(move |block| op(path, *size, block * block_size))
+            })
+            .collect(),
+        Workload::Random { reads, seed } => {
+            if files.is_empty() {
+                return Vec::new();
+            }
+            let mut rng = Rng::new(*seed);
+            (0..*reads)
+                .map(|_| {
+                    let (path, size) = &files[(rng.next_u64() % files.len() as u64) as usize];
+                    let blocks = (size + block_size - 1) / block_size;
+                    op(path, *size, rng.next_u64() % blocks * block_size)
+                })
+                .collect()
+        }
+    }
+}
+
+/// Print a summary of each round, with its cache and backend lines if any.
+pub fn print<W: Write>(reports: &[RoundReport], mut w: W) -> Result<()> {
+    for r in reports {
+        writeln!(
+            w,
+            "round {}: {} reads, {} errors, {} bytes in {}ms, {} bytes/s, latency avg {}us p50 {}us p99 {}us max {}us",
+            r.round,
+            r.reads,
+            r.errors,
+            r.bytes,
+            r.elapsed_ms,
+            r.throughput,
+            r.latency_avg_us,
+            r.latency_p50_us,
+            r.latency_p99_us,
+            r.latency_max_us
+        )?;
+        if let (Some(reads), Some(hits)) = (r.cache_reads, r.cache_hits) {
+            writeln!(w, "  cache: {} reads, {} hits", reads, hits)?;
+        }
+        if let (Some(reads), Some(bytes)) = (r.backend_reads, r.backend_read_bytes) {
+            writeln!(
+                w,
+                "  backend: {} reads, {} bytes, latency avg {}us",
+                reads,
+                bytes,
+                r.backend_latency_avg_us.unwrap_or(0)
+            )?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(path: &str, offset: u64, size: u32) -> ReadOp {
+        ReadOp {
+            path: PathBuf::from(path),
+            offset,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_synthetic_reads() {
+        let files = vec![
+            (PathBuf::from("/a"), 10u64),
+            (PathBuf::from("/b"), 4),
+            (PathBuf::from("/c"), 8),
+        ];
+
+        let ops = synthetic_reads(&files, &Workload::Sequential, 4);
+        assert_eq!(
+            ops,
+            vec![
+                op("/a", 0, 4),
+                op("/a", 4, 4),
+                op("/a", 8, 2),
+                op("/b", 0, 4),
+                op("/c", 0, 4),
+                op("/c", 4, 4),
+            ]
+        );
+
+        let random = Workload::Random {
+            reads: 100,
+            seed: 7,
+        };
+        let ops = synthetic_reads(&files, &random, 4);
+        assert_eq!(ops.len(), 100);
+        for o in &ops {
+            let size = files.iter().find(|(p, _)| *p == o.path).unwrap().1;
+            assert_eq!(o.offset % 4, 0);
+            assert!(o.size > 0 && o.offset + o.size as u64 <= size);
+        }
+        // Same seed, same reads.
+        assert_eq!(ops, synthetic_reads(&files, &random, 4));
+        assert!(synthetic_reads(&[], &random, 4).is_empty());
+    }
+
+    #[test]
+    fn test_round_report() {
+        let stats = WorkerStats {
+            latencies: (1..=100).rev().collect(),
+            errors: 1,
+            bytes: 4096,
+        };
+        let mut r = RoundReport::new(2, stats, 2_000_000);
+        assert_eq!((r.round, r.reads, r.errors), (2, 100, 1));
+        assert_eq!((r.elapsed_ms, r.throughput), (2000, 2048));
+        assert_eq!(r.latency_avg_us, 50);
+        assert_eq!(r.latency_p50_us, 50);
+        assert_eq!(r.latency_p99_us, 99);
+        assert_eq!(r.latency_max_us, 100);
+
+        let before = MountIoStats {
+            backend_reads: Some(10),
+            backend_read_bytes: Some(1000),
+            backend_read_latency_us: Some(500),
+            ..Default::default()
+        };
+        let after = MountIoStats {
+            backend_reads: Some(14),
+            backend_read_bytes: Some(5000),
+            backend_read_latency_us: Some(900),
+            ..Default::default()
+        };
+        r.set_tiers(&before, &after);
+        assert_eq!(r.cache_reads, None);
+        assert_eq!(r.backend_reads, Some(4));
+        assert_eq!(r.backend_read_bytes, Some(4000));
+        assert_eq!(r.backend_latency_avg_us, Some(100));
+
+        let mut out = Vec::new();
+        print(&[r], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("round 2: 100 reads, 1 errors, 4096 bytes in 2000ms"));
+        assert!(out.ends_with("  backend: 4 reads, 4000 bytes, latency avg 100us\n"));
+    }
+}
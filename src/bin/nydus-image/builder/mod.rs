@@ -4,6 +4,7 @@
 
 pub mod directory;
 pub mod stargz;
+pub mod tar;
 
 use anyhow::Result;
 
@@ -495,7 +495,9 @@ impl StargzIndexTreeBuilder {
             i_name_size: name_size,
             i_symlink_size: symlink_size,
             i_rdev: entry.rdev(),
-            i_reserved: [0; 20],
+            i_mtime_nsec: 0,
+            i_mtime: 0,
+            i_ctime: 0,
         };
 
         Ok(Node {
@@ -0,0 +1,1127 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Build an image from a tar archive read as a stream, e.g. an OCI layer read from stdin while
+//! it's being pulled.
+//!
+//! Nothing is extracted to disk. Entries are taken in the order of the archive, and data of
+//! regular files is chunked and written to the blob as soon as it's read, so the archive is
+//! read once from start to end. Gzip compressed archives are decompressed on the fly. Ustar
+//! headers, GNU long names and PAX records are understood, including xattrs as
+//! `SCHILY.xattr.*` records and sparse files in PAX format 1.0 as written by `unpack`.
+//! Directories missing from the archive are added with mode 0755, and a later entry of a path
+//! replaces the earlier one.
+//!
+//! When appending, chunks go to the end of the blob of the last entry in the blob table of the
+//! parent bootstrap instead of a new blob, so that layers converted one after another can share
+//! a single blob.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use flate2::bufread::MultiGzDecoder;
+use nix::sys::stat::makedev;
+use sha2::{Digest, Sha256};
+
+use nydus_utils::digest::RafsDigest;
+use nydus_utils::{div_round_up, try_round_up_4k, ByteSize};
+use rafs::metadata::extended::blob_table::ExtendedBlobTableEntry;
+use rafs::metadata::layout::*;
+use rafs::metadata::{Inode, RafsBlobEntry, RafsMode, RafsSuper};
+
+use crate::builder::Builder;
+use crate::core::blob::{BlobBufferWriter, BlobStorage};
+use crate::core::bootstrap::Bootstrap;
+use crate::core::context::{BuildContext, BUF_WRITER_CAPACITY};
+use crate::core::node::*;
+use crate::core::tree::Tree;
+
+/// Source path meaning stdin.
+const STDIN: &str = "-";
+const BLOCK_SIZE: u64 = 512;
+/// PAX records and GNU long names larger than this are taken as garbage.
+const MAX_META_SIZE: u64 = 16 << 20;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// PAX records of xattrs as written by star and GNU tar.
+const XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+const TYPE_REG: u8 = b'0';
+const TYPE_REG_OLD: u8 = b'\0';
+const TYPE_HARDLINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_CHAR: u8 = b'3';
+const TYPE_BLOCK: u8 = b'4';
+const TYPE_DIR: u8 = b'5';
+const TYPE_FIFO: u8 = b'6';
+const TYPE_CONTIGUOUS: u8 = b'7';
+const TYPE_PAX: u8 = b'x';
+const TYPE_PAX_GLOBAL: u8 = b'g';
+const TYPE_GNU_LONGNAME: u8 = b'L';
+const TYPE_GNU_LONGLINK: u8 = b'K';
+const TYPE_GNU_SPARSE: u8 = b'S';
+
+/// Metadata of a tar entry.
+#[derive(Debug, Default, PartialEq)]
+struct Entry {
+    /// Absolute path in the image.
+    path: PathBuf,
+    kind: u8,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    mtime_nsec: u32,
+    /// From PAX records, the same as mtime if not there.
+    ctime: u64,
+    ctime_nsec: u32,
+    /// Size of the file, which is larger than its data in the archive for sparse files.
+    size: u64,
+    /// Target of a symlink as is, or of a hardlink.
+    link: Vec<u8>,
+    rdev: u64,
+    xattrs: Vec<(OsString, Vec<u8>)>,
+    /// Data starts with a sparse map, in PAX format 1.0.
+    sparse: bool,
+}
+
+/// Reads entries of a tar stream one by one, data of the current entry is read through `Read`.
+struct TarReader<R: Read> {
+    reader: R,
+    /// Data of the current entry not read yet.
+    remaining: u64,
+    /// Padding following data of the current entry.
+    padding: u64,
+}
+
+impl<R: Read> TarReader<R> {
+    fn new(reader: R) -> Self {
+        TarReader {
+            reader,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    /// The next entry, or None at the end of the archive. Data of the current entry left
+    /// unread is skipped.
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let mut pax = HashMap::new();
+        let mut long_name = None;
+        let mut long_link = None;
+
+        loop {
+            self.skip_data()?;
+            let mut header = [0u8; BLOCK_SIZE as usize];
+            if !self.read_header(&mut header)? || header.iter().all(|b| *b == 0) {
+                return Ok(None);
+            }
+            verify_checksum(&header)?;
+
+            let kind = header[156];
+            let size = parse_number(&header[124..136]).context("invalid entry size")?;
+            self.set_data_size(size);
+            match kind {
+                TYPE_PAX => parse_pax(&self.read_meta()?, &mut pax)?,
+                // Global records are defaults for the archive, which don't matter to images.
+                TYPE_PAX_GLOBAL => {}
+                TYPE_GNU_LONGNAME => long_name = Some(trim_nul(&self.read_meta()?).to_vec()),
+                TYPE_GNU_LONGLINK => long_link = Some(trim_nul(&self.read_meta()?).to_vec()),
+                TYPE_GNU_SPARSE => bail!("old GNU sparse files are not supported"),
+                _ => {
+                    return self
+                        .parse_entry(&header, pax, long_name, long_link)
+                        .map(Some)
+                }
+            }
+        }
+    }
+
+    fn parse_entry(
+        &mut self,
+        header: &[u8; BLOCK_SIZE as usize],
+        pax: HashMap<Vec<u8>, Vec<u8>>,
+        long_name: Option<Vec<u8>>,
+        long_link: Option<Vec<u8>>,
+    ) -> Result<Entry> {
+        let kind = header[156];
+        let mut path = long_name.unwrap_or_else(|| {
+            let name = trim_nul(&header[0..100]);
+            let prefix = trim_nul(&header[345..500]);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                [prefix, b"/", name].concat()
+            } else {
+                name.to_vec()
+            }
+        });
+        let mut link = long_link.unwrap_or_else(|| trim_nul(&header[157..257]).to_vec());
+        let mut uid = parse_number(&header[108..116]).context("invalid uid")?;
+        let mut gid = parse_number(&header[116..124]).context("invalid gid")?;
+        let mut mtime = (parse_number(&header[136..148]).context("invalid mtime")?, 0);
+        let mut ctime = None;
+        let mut size = self.remaining;
+        let mut sparse = false;
+        let mut xattrs = Vec::new();
+
+        for (key, value) in pax.iter() {
+            let number = || -> Result<u64> {
+                let text = String::from_utf8_lossy(value);
+                // Timestamps may have fractions of seconds.
+                let integer = text.split('.').next().unwrap_or_default();
+                integer
+                    .parse()
+                    .with_context(|| format!("invalid PAX record {:?}", key))
+            };
+            let time = || -> Result<(u64, u32)> {
+                parse_pax_time(&String::from_utf8_lossy(value))
+                    .with_context(|| format!("invalid PAX record {:?}", key))
+            };
+            match key.as_slice() {
+                b"path" => path = value.clone(),
+                b"linkpath" => link = value.clone(),
+                b"uid" => uid = number()?,
+                b"gid" => gid = number()?,
+                b"mtime" => mtime = time()?,
+                b"ctime" => ctime = Some(time()?),
+                b"size" => {
+                    size = number()?;
+                    self.set_data_size(size);
+                }
+                b"GNU.sparse.major" => sparse = value.as_slice() == b"1",
+                b"GNU.sparse.map" | b"GNU.sparse.numblocks" => {
+                    bail!("sparse files in PAX format 0.x are not supported")
+                }
+                _ => {
+                    if key.starts_with(XATTR_PREFIX) {
+                        let name = OsStr::from_bytes(&key[XATTR_PREFIX.len()..]);
+                        xattrs.push((name.to_os_string(), value.clone()));
+                    }
+                }
+            }
+        }
+        if sparse {
+            if let Some(name) = pax.get(&b"GNU.sparse.name"[..]) {
+                path = name.clone();
+            }
+            size = pax
+                .get(&b"GNU.sparse.realsize"[..])
+                .and_then(|s| std::str::from_utf8(s).ok())
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| anyhow!("invalid real size of sparse file"))?;
+        }
+
+        let rdev = if kind == TYPE_CHAR || kind == TYPE_BLOCK {
+            makedev(
+                parse_number(&header[329..337]).context("invalid device major")?,
+                parse_number(&header[337..345]).context("invalid device minor")?,
+            )
+        } else {
+            0
+        };
+
+        Ok(Entry {
+            path: normalize_path(&path)?,
+            kind,
+            mode: parse_number(&header[100..108]).context("invalid mode")? as u32 & 0o7777,
+            uid: uid as u32,
+            gid: gid as u32,
+            mtime: mtime.0,
+            mtime_nsec: mtime.1,
+            ctime: ctime.unwrap_or(mtime).0,
+            ctime_nsec: ctime.unwrap_or(mtime).1,
+            size,
+            link,
+            rdev,
+            xattrs,
+            sparse,
+        })
+    }
+
+    /// Read a header block, returns false if the stream ends before it.
+    fn read_header(&mut self, header: &mut [u8]) -> Result<bool> {
+        let mut read = 0;
+        while read < header.len() {
+            match self.reader.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => bail!("unexpected end of tar archive"),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e).context("failed to read tar archive"),
+            }
+        }
+        Ok(true)
+    }
+
+    fn set_data_size(&mut self, size: u64) {
+        self.remaining = size;
+        self.padding = padding(size);
+    }
+
+    /// Read the whole data of a metadata entry.
+    fn read_meta(&mut self) -> Result<Vec<u8>> {
+        if self.remaining > MAX_META_SIZE {
+            bail!(
+                "tar metadata entry of {} bytes is too large",
+                self.remaining
+            );
+        }
+        let mut data = vec![0u8; self.remaining as usize];
+        self.read_exact(&mut data)
+            .context("failed to read tar metadata entry")?;
+        Ok(data)
+    }
+
+    fn skip_data(&mut self) -> Result<()> {
+        let size = self.remaining + self.padding;
+        let skipped = io::copy(&mut (&mut self.reader).take(size), &mut io::sink())
+            .context("failed to read tar archive")?;
+        if skipped != size {
+            bail!("unexpected end of tar archive");
+        }
+        self.remaining = 0;
+        self.padding = 0;
+        Ok(())
+    }
+
+    /// Read the sparse map leading data of a sparse file of `size` bytes, as `(offset, length)`
+    /// of data regions in order.
+    fn read_sparse_map(&mut self, size: u64) -> Result<Vec<(u64, u64)>> {
+        let mut map_size = 0u64;
+        let mut read_number = || -> Result<u64> {
+            let mut digits = Vec::new();
+            let mut c = [0u8];
+            loop {
+                self.read_exact(&mut c)?;
+                map_size += 1;
+                match c[0] {
+                    b'\n' => break,
+                    b'0'..=b'9' if digits.len() < 20 => digits.push(c[0]),
+                    _ => bail!("invalid sparse map"),
+                }
+            }
+            Ok(std::str::from_utf8(&digits)?.parse()?)
+        };
+
+        let count = read_number()?;
+        let mut regions = Vec::new();
+        let mut end = 0;
+        for _ in 0..count {
+            let offset = read_number()?;
+            let len = read_number()?;
+            if offset < end || offset.checked_add(len).map_or(true, |e| e > size) {
+                bail!("invalid sparse map");
+            }
+            end = offset + len;
+            regions.push((offset, len));
+        }
+
+        let mut pad = vec![0u8; padding(map_size) as usize];
+        self.read_exact(&mut pad)?;
+
+        Ok(regions)
+    }
+}
+
+impl<R: Read> Read for TarReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        let n = self.reader.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of tar archive",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads a sparse file of `size` bytes, with data `regions` read from `data` and zeros in
+/// holes between them.
+struct SparseReader<'a, R: Read> {
+    data: &'a mut R,
+    regions: Vec<(u64, u64)>,
+    size: u64,
+    pos: u64,
+    region: usize,
+}
+
+impl<'a, R: Read> SparseReader<'a, R> {
+    fn new(data: &'a mut R, regions: Vec<(u64, u64)>, size: u64) -> Self {
+        SparseReader {
+            data,
+            regions,
+            size,
+            pos: 0,
+            region: 0,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for SparseReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some((offset, len)) = self.regions.get(self.region) {
+            if offset + len > self.pos {
+                break;
+            }
+            self.region += 1;
+        }
+
+        let left = self.size.saturating_sub(self.pos);
+        let n = match self.regions.get(self.region) {
+            Some(&(offset, len)) if offset <= self.pos => {
+                let n = std::cmp::min(left, offset + len - self.pos);
+                let n = std::cmp::min(n, buf.len() as u64) as usize;
+                self.data.read_exact(&mut buf[..n])?;
+                n
+            }
+            region => {
+                let hole_end = region.map(|r| r.0).unwrap_or(self.size);
+                let n = std::cmp::min(left, hole_end - self.pos);
+                let n = std::cmp::min(n, buf.len() as u64) as usize;
+                for b in buf[..n].iter_mut() {
+                    *b = 0;
+                }
+                n
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+fn padding(size: u64) -> u64 {
+    (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+fn trim_nul(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+/// Parse a numeric header field, octal text or base-256 for large values as GNU tar writes.
+/// Parse seconds and nanoseconds of a PAX timestamp, which may have fractions of seconds.
+fn parse_pax_time(text: &str) -> Result<(u64, u32)> {
+    let (secs, fraction) = match text.find('.') {
+        Some(dot) => (&text[..dot], &text[dot + 1..]),
+        None => (text, ""),
+    };
+    if !fraction.bytes().all(|c| c.is_ascii_digit()) {
+        bail!("invalid fraction of seconds");
+    }
+    // Digits beyond nanoseconds are dropped.
+    let digits = &fraction[..fraction.len().min(9)];
+    let nsec = if digits.is_empty() {
+        0
+    } else {
+        digits.parse::<u32>()? * 10u32.pow(9 - digits.len() as u32)
+    };
+
+    Ok((secs.parse()?, nsec))
+}
+
+fn parse_number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        if field[0] == 0xff {
+            bail!("negative number");
+        }
+        let mut value = (field[0] & 0x7f) as u64;
+        for b in &field[1..] {
+            value = value
+                .checked_mul(256)
+                .and_then(|v| v.checked_add(*b as u64))
+                .ok_or_else(|| anyhow!("number out of range"))?;
+        }
+        return Ok(value);
+    }
+
+    let text = field
+        .iter()
+        .skip_while(|c| **c == b' ')
+        .take_while(|c| **c != 0 && **c != b' ')
+        .cloned()
+        .collect::<Vec<_>>();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    let text = std::str::from_utf8(&text)?;
+    u64::from_str_radix(text, 8).with_context(|| format!("invalid octal number {:?}", text))
+}
+
+fn verify_checksum(header: &[u8; BLOCK_SIZE as usize]) -> Result<()> {
+    let expected = parse_number(&header[148..156]).context("invalid header checksum")?;
+    let mut unsigned = 0u64;
+    // Some old tars sum bytes as signed.
+    let mut signed = 0i64;
+    for (idx, b) in header.iter().enumerate() {
+        let b = if (148..156).contains(&idx) { b' ' } else { *b };
+        unsigned += b as u64;
+        signed += b as i8 as i64;
+    }
+    if expected != unsigned && expected as i64 != signed {
+        bail!("invalid tar header checksum, not a tar archive?");
+    }
+    Ok(())
+}
+
+/// Parse PAX records `"<length> <key>=<value>\n"` into `records`, a record with an empty
+/// value removes the key.
+fn parse_pax(data: &[u8], records: &mut HashMap<Vec<u8>, Vec<u8>>) -> Result<()> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|c| *c == b' ')
+            .ok_or_else(|| anyhow!("invalid PAX record"))?;
+        let len: usize = std::str::from_utf8(&rest[..space])?
+            .parse()
+            .context("invalid PAX record length")?;
+        if len <= space + 1 || len > rest.len() || rest[len - 1] != b'\n' {
+            bail!("invalid PAX record");
+        }
+        let record = &rest[space + 1..len - 1];
+        let eq = record
+            .iter()
+            .position(|c| *c == b'=')
+            .ok_or_else(|| anyhow!("invalid PAX record"))?;
+        let (key, value) = (&record[..eq], &record[eq + 1..]);
+        if value.is_empty() {
+            records.remove(key);
+        } else {
+            records.insert(key.to_vec(), value.to_vec());
+        }
+        rest = &rest[len..];
+    }
+    Ok(())
+}
+
+/// Absolute path of an entry in the image. `..` is refused, so that no entry gets out of root.
+fn normalize_path(path: &[u8]) -> Result<PathBuf> {
+    let mut normalized = PathBuf::from("/");
+    for comp in Path::new(OsStr::from_bytes(path)).components() {
+        match comp {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => bail!("path {:?} is out of root", OsStr::from_bytes(path)),
+            _ => {}
+        }
+    }
+    Ok(normalized)
+}
+
+/// Open the tar archive at `path`, or stdin for "-", decompressing it if it's gzip compressed.
+fn open_source(path: &Path) -> Result<Box<dyn Read>> {
+    let reader: Box<dyn Read> = if path == Path::new(STDIN) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path).with_context(|| format!("failed to open {:?}", path))?)
+    };
+    let mut reader = BufReader::with_capacity(BUF_WRITER_CAPACITY, reader);
+    let gzip = reader
+        .fill_buf()
+        .context("failed to read tar archive")?
+        .starts_with(&GZIP_MAGIC);
+
+    Ok(if gzip {
+        Box::new(MultiGzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// Where chunks of the layer go in the blob.
+struct BlobCursor {
+    index: u32,
+    compress_offset: u64,
+    decompress_offset: u64,
+    cache_size: u64,
+    /// Bytes written to the blob by this build.
+    size: usize,
+    hash: Sha256,
+}
+
+pub struct TarBuilder {
+    blob_stor: BlobStorage,
+    append: bool,
+}
+
+impl TarBuilder {
+    pub fn new(blob_stor: BlobStorage, append: bool) -> Self {
+        Self { blob_stor, append }
+    }
+
+    /// Blob table of the parent bootstrap, with chunks of lower layers added to the chunk
+    /// cache for dedup. Chunks are dumped while reading the archive, so this is needed before
+    /// the tree gets applied to the parent bootstrap, which loads it again.
+    fn load_parent(ctx: &mut BuildContext) -> Result<Option<OndiskBlobTable>> {
+        let r = match ctx.f_parent_bootstrap.as_mut() {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let mut rs = RafsSuper {
+            mode: RafsMode::Direct,
+            digest_validate: true,
+            ..Default::default()
+        };
+        rs.load(r)
+            .context("failed to load superblock from bootstrap")?;
+        Tree::from_bootstrap(&rs, Some(&mut ctx.chunk_cache))
+            .context("failed to build tree from bootstrap")?;
+        r.seek(SeekFrom::Start(0))?;
+
+        Ok(Some(rs.inodes.get_blob_table().as_ref().clone()))
+    }
+
+    fn open_blob(
+        &self,
+        ctx: &mut BuildContext,
+        parent: Option<&OndiskBlobTable>,
+    ) -> Result<(BlobBufferWriter, BlobCursor)> {
+        let mut cursor = BlobCursor {
+            index: parent.map(|t| t.entries.len() as u32).unwrap_or_default(),
+            compress_offset: 0,
+            decompress_offset: 0,
+            cache_size: 0,
+            size: 0,
+            hash: Sha256::new(),
+        };
+        if !self.append {
+            return Ok((BlobBufferWriter::new(self.blob_stor.clone())?, cursor));
+        }
+
+        let path = match &self.blob_stor {
+            BlobStorage::SingleFile(p) => p,
+            BlobStorage::BlobsDir(_) => bail!("only a blob file given by --blob can be appended"),
+        };
+        let table = parent.ok_or_else(|| anyhow!("appending needs a parent bootstrap"))?;
+        let entry = table
+            .entries
+            .last()
+            .ok_or_else(|| anyhow!("parent bootstrap has no blob to append to"))?;
+        if table.extended.entries.len() != table.entries.len() {
+            bail!("parent bootstrap has no extended blob table, upgrade it by `upgrade-bootstrap`");
+        }
+        if !ctx.blob_id.is_empty() && ctx.blob_id != entry.blob_id {
+            bail!(
+                "blob id {} differs from {} of the blob appended to",
+                ctx.blob_id,
+                entry.blob_id
+            );
+        }
+        // A blob is encrypted with a single key version throughout.
+        if ctx.key_version() != entry.key_version {
+            bail!(
+                "blob appended to is encrypted with key version {}, not {}",
+                entry.key_version,
+                ctx.key_version()
+            );
+        }
+        ctx.blob_id = entry.blob_id.clone();
+        ctx.chunk_count_map
+            .set_count(entry.blob_index, entry.chunk_count);
+
+        let (writer, size) = BlobBufferWriter::append(path)?;
+        cursor.index = entry.blob_index;
+        cursor.compress_offset = size;
+        cursor.cache_size = entry.blob_cache_size;
+        cursor.decompress_offset = if ctx.aligned_chunk {
+            try_round_up_4k(entry.blob_cache_size)
+                .ok_or_else(|| anyhow!("invalid blob cache size"))?
+        } else {
+            entry.blob_cache_size
+        };
+
+        Ok((writer, cursor))
+    }
+
+    /// Build node tree from entries of the archive, dumping data of regular files to blob.
+    fn build_tree(
+        &self,
+        ctx: &mut BuildContext,
+        tar: &mut TarReader<Box<dyn Read>>,
+        writer: &mut BlobBufferWriter,
+        cursor: &mut BlobCursor,
+    ) -> Result<Tree> {
+        let layered = ctx.f_parent_bootstrap.is_some();
+        let cipher = ctx.cipher();
+        let mut root = None;
+        let mut nodes: Vec<Node> = Vec::new();
+        // Index of the latest node of each path in `nodes`.
+        let mut paths: HashMap<PathBuf, usize> = HashMap::new();
+
+        while let Some(entry) = tar.next_entry()? {
+            let mut node = match entry.kind {
+                TYPE_HARDLINK => {
+                    let target = normalize_path(&entry.link)?;
+                    let idx = paths.get(&target).ok_or_else(|| {
+                        anyhow!("target {:?} of hardlink {:?} not found", target, entry.path)
+                    })?;
+                    let mut node = nodes[*idx].clone();
+                    if node.is_dir() {
+                        bail!("hardlink {:?} to directory {:?}", entry.path, target);
+                    }
+                    node.path = entry.path.clone();
+                    node.inode.set_name_size(node.name().byte_size());
+                    node
+                }
+                TYPE_REG | TYPE_REG_OLD | TYPE_CONTIGUOUS | TYPE_SYMLINK | TYPE_CHAR
+                | TYPE_BLOCK | TYPE_DIR | TYPE_FIFO => {
+                    let ino = nodes.len() as Inode + 2;
+                    new_node(&entry, ino, ctx.explicit_uidgid)
+                        .with_context(|| format!("failed to create node {:?}", entry.path))?
+                }
+                kind => {
+                    warn!(
+                        "{:?} of unsupported type {:?} is skipped",
+                        entry.path, kind as char
+                    );
+                    continue;
+                }
+            };
+
+            if entry.path == Path::new("/") {
+                if !node.is_dir() {
+                    bail!("root of the archive is not a directory");
+                }
+                root = Some(node);
+                continue;
+            }
+
+            // As per OCI spec, whiteout files should only be present in layers.
+            if node.whiteout_type(&ctx.whiteout_spec).is_some()
+                && !node.is_overlayfs_opaque(&ctx.whiteout_spec)
+                && !layered
+            {
+                continue;
+            }
+
+            if entry.kind != TYPE_HARDLINK {
+                let mut sparse;
+                let data: Option<&mut dyn Read> = if !node.is_reg() {
+                    None
+                } else if entry.sparse {
+                    let regions = tar
+                        .read_sparse_map(entry.size)
+                        .with_context(|| format!("failed to read sparse file {:?}", entry.path))?;
+                    sparse = SparseReader::new(&mut *tar, regions, entry.size);
+                    Some(&mut sparse)
+                } else {
+                    Some(&mut *tar)
+                };
+                cursor.size += node
+                    .dump_blob_from(
+                        data,
+                        writer,
+                        &mut cursor.hash,
+                        &mut cursor.compress_offset,
+                        &mut cursor.decompress_offset,
+                        &mut cursor.cache_size,
+                        &mut ctx.chunk_cache,
+                        &mut ctx.chunk_count_map,
+                        ctx.compressor,
+                        ctx.digester,
+                        cursor.index,
+                        ctx.aligned_chunk,
+                        cipher.as_deref(),
+                    )
+                    .with_context(|| format!("failed to dump file {:?}", entry.path))?;
+            }
+
+            // Parent directories not in the archive so far.
+            let mut lost_dirs = entry
+                .path
+                .ancestors()
+                .skip(1)
+                .take_while(|p| *p != Path::new("/") && !paths.contains_key(*p))
+                .map(Path::to_path_buf)
+                .collect::<Vec<_>>();
+            while let Some(dir) = lost_dirs.pop() {
+                let ino = nodes.len() as Inode + 2;
+                paths.insert(dir.clone(), nodes.len());
+                nodes.push(new_dir(dir, ino, ctx.explicit_uidgid));
+            }
+
+            paths.insert(entry.path, nodes.len());
+            nodes.push(node);
+        }
+
+        let root = root.unwrap_or_else(|| new_dir(PathBuf::from("/"), 1, ctx.explicit_uidgid));
+        let mut tree = Tree::new(root);
+        for node in nodes.iter() {
+            tree.apply(node, false, &ctx.whiteout_spec)?;
+        }
+
+        Ok(tree)
+    }
+}
+
+/// Node of `entry`, numbered `ino` so that hardlinks to it can be told by the number.
+fn new_node(entry: &Entry, ino: Inode, explicit_uidgid: bool) -> Result<Node> {
+    let file_type = match entry.kind {
+        TYPE_SYMLINK => libc::S_IFLNK,
+        TYPE_CHAR => libc::S_IFCHR,
+        TYPE_BLOCK => libc::S_IFBLK,
+        TYPE_DIR => libc::S_IFDIR,
+        TYPE_FIFO => libc::S_IFIFO,
+        _ => libc::S_IFREG,
+    };
+
+    let mut flags = RafsInodeFlags::default();
+    let mut size = 0;
+    let mut symlink = None;
+    let mut symlink_size = 0;
+    if file_type == libc::S_IFLNK {
+        flags |= RafsInodeFlags::SYMLINK;
+        let link = OsStr::from_bytes(&entry.link).to_os_string();
+        symlink_size = link.byte_size() as u16;
+        size = symlink_size as u64;
+        symlink = Some(link);
+    } else if file_type == libc::S_IFREG {
+        size = entry.size;
+    }
+
+    let mut xattrs = XAttrs::new();
+    for (name, value) in entry.xattrs.iter() {
+        xattrs.add(name.clone(), value.clone());
+    }
+    if !xattrs.is_empty() {
+        flags |= RafsInodeFlags::XATTR;
+    }
+
+    let (uid, gid, mtime, mtime_nsec) = if explicit_uidgid {
+        (entry.uid, entry.gid, entry.mtime, entry.mtime_nsec)
+    } else {
+        (0, 0, 0, 0)
+    };
+    let mut inode = OndiskInode {
+        i_digest: RafsDigest::default(),
+        i_parent: 0,
+        i_ino: ino,
+        i_projid: 0,
+        i_uid: uid,
+        i_gid: gid,
+        i_mode: file_type | entry.mode,
+        i_size: size,
+        i_nlink: 1,
+        i_blocks: div_round_up(size + xattrs.aligned_size() as u64, 512),
+        i_flags: flags,
+        i_child_index: 0,
+        i_child_count: 0,
+        i_name_size: 0,
+        i_symlink_size: symlink_size,
+        i_rdev: entry.rdev as u32,
+        i_mtime_nsec: mtime_nsec,
+        i_mtime: mtime,
+        i_ctime: 0,
+    };
+    if explicit_uidgid {
+        inode.set_ctime(entry.ctime, entry.ctime_nsec);
+    }
+
+    let mut node = Node {
+        index: 0,
+        real_ino: ino,
+        dev: u64::MAX,
+        rdev: entry.rdev,
+        overlay: Overlay::UpperAddition,
+        explicit_uidgid,
+        source: PathBuf::from("/"),
+        path: entry.path.clone(),
+        inode,
+        chunks: Vec::new(),
+        symlink,
+        xattrs,
+    };
+    node.inode.set_name_size(node.name().byte_size());
+    node.inode.i_child_count = node.chunk_count() as u32;
+
+    Ok(node)
+}
+
+/// Node of a directory missing from the archive.
+fn new_dir(path: PathBuf, ino: Inode, explicit_uidgid: bool) -> Node {
+    let entry = Entry {
+        path,
+        kind: TYPE_DIR,
+        mode: 0o755,
+        ..Default::default()
+    };
+    // Safe to unwrap because a directory entry without xattrs can't fail.
+    new_node(&entry, ino, explicit_uidgid).unwrap()
+}
+
+impl Builder for TarBuilder {
+    fn build(&mut self, mut ctx: &mut BuildContext) -> Result<(Vec<String>, usize)> {
+        let mut bootstrap = Bootstrap::new()?;
+        let parent = Self::load_parent(&mut ctx)?;
+        let (mut writer, mut cursor) = self.open_blob(&mut ctx, parent.as_ref())?;
+        let mut tar = TarReader::new(open_source(&ctx.source_path)?);
+
+        // Build tree from source, dumping blob along the way
+        let mut tree = timing_tracer!(
+            { self.build_tree(&mut ctx, &mut tar, &mut writer, &mut cursor) },
+            "load_from_tar"
+        )
+        .context("failed to build tree from tar archive")?;
+
+        // Build bootstrap from source
+        if ctx.f_parent_bootstrap.is_some() {
+            bootstrap.build(&mut ctx, &mut tree);
+            // Apply to parent bootstrap for layered build
+            let mut tree = bootstrap.apply(&mut ctx)?;
+            timing_tracer!({ bootstrap.build(&mut ctx, &mut tree) }, "build_bootstrap");
+        } else {
+            bootstrap.build(&mut ctx, &mut tree);
+        }
+
+        let blob_size = cursor.size;
+        let dumped_size = if self.append {
+            // Update the entry of the blob appended to, rather than adding one.
+            let idx = cursor.index as usize;
+            let chunk_count = *ctx.chunk_count_map.count(cursor.index).unwrap_or(&0);
+            let entry = RafsBlobEntry {
+                chunk_count,
+                blob_cache_size: cursor.cache_size,
+                ..ctx.blob_table.entries[idx].as_ref().clone()
+            };
+            let key_version = entry.key_version;
+            ctx.blob_table.entries[idx] = Arc::new(entry);
+            ctx.blob_table.extended.entries[idx] = Arc::new(ExtendedBlobTableEntry::new(
+                chunk_count,
+                cursor.cache_size,
+                key_version,
+            ));
+            0
+        } else {
+            blob_size
+        };
+
+        // Dump bootstrap file
+        let (blob_ids, _) =
+            bootstrap.dump(&mut ctx, cursor.hash, dumped_size, 0, cursor.cache_size)?;
+        let blob_id = if blob_size > 0 || self.append {
+            Some(ctx.blob_id.as_str())
+        } else {
+            None
+        };
+        writer.release(blob_id)?;
+
+        Ok((blob_ids, blob_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ustar header of `kind` with `size` bytes of data.
+    fn header(path: &str, kind: u8, size: u64, link: &str) -> Vec<u8> {
+        let mut h = vec![0u8; BLOCK_SIZE as usize];
+        h[..path.len()].copy_from_slice(path.as_bytes());
+        h[100..107].copy_from_slice(b"0000644");
+        h[108..115].copy_from_slice(b"0001750");
+        h[116..123].copy_from_slice(b"0001750");
+        h[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        h[136..147].copy_from_slice(b"00000001750");
+        h[156] = kind;
+        h[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        h[148..156].copy_from_slice(b"        ");
+        let sum: u64 = h.iter().map(|b| *b as u64).sum();
+        h[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        h
+    }
+
+    fn append(tar: &mut Vec<u8>, path: &str, kind: u8, data: &[u8], link: &str) {
+        tar.extend(header(path, kind, data.len() as u64, link));
+        tar.extend_from_slice(data);
+        tar.resize(tar.len() + padding(data.len() as u64) as usize, 0);
+    }
+
+    fn pax(records: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (key, value) in records {
+            let rest = key.len() + value.len() + 3;
+            let mut len = rest + 1;
+            while len.to_string().len() + rest > len {
+                len += 1;
+            }
+            data.extend(format!("{} {}={}\n", len, key, value).into_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number(b"0000644\0").unwrap(), 0o644);
+        assert_eq!(parse_number(b"  755 \0\0").unwrap(), 0o755);
+        assert_eq!(parse_number(b"\0\0\0\0").unwrap(), 0);
+        assert_eq!(parse_number(&[0x80, 0, 0, 1, 0]).unwrap(), 256);
+        assert!(parse_number(&[0xff, 0xff]).is_err());
+        assert!(parse_number(b"0009\0").is_err());
+    }
+
+    #[test]
+    fn test_parse_pax_time() {
+        assert_eq!(parse_pax_time("1600000000").unwrap(), (1600000000, 0));
+        assert_eq!(
+            parse_pax_time("1600000000.5").unwrap(),
+            (1600000000, 500_000_000)
+        );
+        assert_eq!(parse_pax_time("1.0000000019").unwrap(), (1, 1));
+        assert!(parse_pax_time("-1.5").is_err());
+        assert!(parse_pax_time("1.5e3").is_err());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(b"./").unwrap(), PathBuf::from("/"));
+        assert_eq!(
+            normalize_path(b"./usr/bin/").unwrap(),
+            PathBuf::from("/usr/bin")
+        );
+        assert_eq!(
+            normalize_path(b"/etc//hosts").unwrap(),
+            PathBuf::from("/etc/hosts")
+        );
+        assert!(normalize_path(b"../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_tar_reader() {
+        let mut tar = Vec::new();
+        append(&mut tar, "./", TYPE_DIR, b"", "");
+        append(&mut tar, "./bin/sh", TYPE_REG, b"#!/bin/sh\n", "");
+        append(&mut tar, "./bin/bash", TYPE_HARDLINK, b"", "./bin/sh");
+        let records = pax(&[
+            ("path", "./a/very/long/path/name"),
+            ("uid", "100000"),
+            ("mtime", "1600000000.5"),
+            ("ctime", "1600000001.00000025"),
+            ("SCHILY.xattr.user.foo", "bar"),
+        ]);
+        append(&mut tar, "./PaxHeaders/name", TYPE_PAX, &records, "");
+        append(&mut tar, "./name", TYPE_SYMLINK, b"", "../target");
+        let records = pax(&[
+            ("GNU.sparse.major", "1"),
+            ("GNU.sparse.minor", "0"),
+            ("GNU.sparse.name", "./sparse"),
+            ("GNU.sparse.realsize", "10000"),
+        ]);
+        append(&mut tar, "./PaxHeaders/sparse", TYPE_PAX, &records, "");
+        let mut data = b"2\n10\n3\n9000\n2\n".to_vec();
+        data.resize(BLOCK_SIZE as usize, 0);
+        data.extend_from_slice(b"abcde");
+        append(&mut tar, "./GNUSparseFile.0/sparse", TYPE_REG, &data, "");
+        tar.resize(tar.len() + 2 * BLOCK_SIZE as usize, 0);
+
+        let mut r = TarReader::new(tar.as_slice());
+        let root = r.next_entry().unwrap().unwrap();
+        assert_eq!(root.path, PathBuf::from("/"));
+        assert_eq!(root.kind, TYPE_DIR);
+        assert_eq!(root.mode, 0o644);
+        assert_eq!((root.uid, root.gid, root.mtime), (1000, 1000, 1000));
+        assert_eq!((root.ctime, root.ctime_nsec), (1000, 0));
+
+        let sh = r.next_entry().unwrap().unwrap();
+        assert_eq!(sh.path, PathBuf::from("/bin/sh"));
+        assert_eq!(sh.size, 10);
+        let mut data = Vec::new();
+        r.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"#!/bin/sh\n");
+
+        // Data of an entry is skipped if it's not read.
+        let bash = r.next_entry().unwrap().unwrap();
+        assert_eq!(bash.kind, TYPE_HARDLINK);
+        assert_eq!(bash.link, b"./bin/sh");
+
+        let link = r.next_entry().unwrap().unwrap();
+        assert_eq!(link.path, PathBuf::from("/a/very/long/path/name"));
+        assert_eq!(link.link, b"../target");
+        assert_eq!((link.uid, link.mtime), (100000, 1600000000));
+        assert_eq!(link.mtime_nsec, 500_000_000);
+        assert_eq!((link.ctime, link.ctime_nsec), (1600000001, 250));
+        assert_eq!(
+            link.xattrs,
+            vec![(OsString::from("user.foo"), b"bar".to_vec())]
+        );
+
+        let sparse = r.next_entry().unwrap().unwrap();
+        assert_eq!(sparse.path, PathBuf::from("/sparse"));
+        assert!(sparse.sparse);
+        assert_eq!(sparse.size, 10000);
+        let regions = r.read_sparse_map(sparse.size).unwrap();
+        assert_eq!(regions, vec![(10, 3), (9000, 2)]);
+        let mut data = Vec::new();
+        SparseReader::new(&mut r, regions, sparse.size)
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut expected = vec![0u8; 10000];
+        expected[10..13].copy_from_slice(b"abc");
+        expected[9000..9002].copy_from_slice(b"de");
+        assert_eq!(data, expected);
+
+        assert!(r.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_tar() {
+        let mut tar = header("./file", TYPE_REG, 100, "");
+        tar[0] = b'x';
+        assert!(TarReader::new(tar.as_slice()).next_entry().is_err());
+
+        // Truncated data.
+        let mut tar = header("./file", TYPE_REG, 100, "");
+        tar.extend_from_slice(&[0u8; 10]);
+        let mut r = TarReader::new(tar.as_slice());
+        r.next_entry().unwrap().unwrap();
+        assert!(r.next_entry().is_err());
+
+        // No end of archive blocks.
+        assert!(TarReader::new(&b""[..]).next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_new_node() {
+        let entry = Entry {
+            path: PathBuf::from("/usr/lib/libc.so"),
+            kind: TYPE_REG,
+            mode: 0o755,
+            uid: 1,
+            gid: 2,
+            mtime: 3,
+            mtime_nsec: 4,
+            ctime: 5,
+            ctime_nsec: 6,
+            size: 0x250000,
+            ..Default::default()
+        };
+        let node = new_node(&entry, 5, true).unwrap();
+        assert!(node.is_reg());
+        assert_eq!(node.inode.i_mode, libc::S_IFREG | 0o755);
+        assert_eq!(node.inode.i_child_count, 3);
+        assert_eq!((node.inode.i_uid, node.inode.i_gid), (1, 2));
+        assert_eq!((node.inode.i_mtime, node.inode.i_mtime_nsec), (3, 4));
+        assert_eq!(node.inode.ctime(), (5, 6));
+        assert_eq!(node.name(), OsStr::new("libc.so"));
+        assert_eq!(node.rootfs(), PathBuf::from("/usr/lib/libc.so"));
+
+        let node = new_node(&entry, 5, false).unwrap();
+        assert_eq!((node.inode.i_uid, node.inode.i_mtime), (0, 0));
+        assert_eq!(node.inode.ctime(), (0, 0));
+
+        let dir = new_dir(PathBuf::from("/usr"), 6, true);
+        assert!(dir.is_dir());
+        assert_eq!(dir.inode.i_mode, libc::S_IFDIR | 0o755);
+    }
+}
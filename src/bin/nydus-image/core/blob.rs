@@ -72,11 +72,29 @@ impl BlobBufferWriter {
         }
     }
 
+    /// Append to the blob file at `path`, returns the writer and the size of the blob so far.
+    pub fn append(path: &Path) -> Result<(Self, u64)> {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open blob {:?} to append", path))?;
+        let size = file.metadata()?.len();
+        let writer = Self {
+            file: BufWriter::with_capacity(BUF_WRITER_CAPACITY, file),
+            parent_dir: None,
+            blob_stor: BlobStorage::SingleFile(path.to_path_buf()),
+            _tmp_file: None,
+        };
+        Ok((writer, size))
+    }
+
     pub fn write_all(&mut self, buf: &[u8]) -> Result<()> {
         self.file.write_all(buf).map_err(|e| anyhow!(e))
     }
 
-    fn release(self, new_name: Option<&str>) -> Result<()> {
+    /// Flush the blob and name it `new_name`, or remove it if `new_name` is None as nothing is
+    /// written to it.
+    pub fn release(self, new_name: Option<&str>) -> Result<()> {
         let mut f = self.file.into_inner()?;
         f.flush()?;
 
@@ -162,6 +180,7 @@ impl Blob {
         let mut compress_offset = 0u64;
         let mut decompress_offset = 0u64;
         let mut blob_hash = Sha256::new();
+        let cipher = ctx.cipher();
 
         match ctx.source_type {
             SourceType::Directory => {
@@ -187,6 +206,7 @@ impl Blob {
                                 blob_index,
                                 // TODO: Introduce build context to enclose the sparse states?
                                 ctx.aligned_chunk,
+                                cipher.as_deref(),
                             )
                             .context("failed to dump readahead blob chunks")?;
                     }
@@ -220,11 +240,14 @@ impl Blob {
                                 ctx.digester,
                                 blob_index,
                                 ctx.aligned_chunk,
+                                cipher.as_deref(),
                             )
                             .context("failed to dump remaining blob chunks")?;
                     }
                 }
             }
+            // Chunks are dumped as the tar stream is read.
+            SourceType::Tar => {}
             SourceType::StargzIndex => {
                 // Set blob index and inode digest for upper nodes
                 for node in &mut ctx.nodes {
@@ -194,6 +194,11 @@ impl Bootstrap {
         rs.load(ctx.f_parent_bootstrap.as_mut().unwrap())
             .context("failed to load superblock from bootstrap")?;
 
+        // Inodes of lower layer built without mtime would show up with epoch timestamps.
+        if !rs.meta.has_mtime() {
+            ctx.has_mtime = false;
+        }
+
         let lower_compressor = rs.meta.get_compressor();
         if ctx.compressor != lower_compressor {
             bail!(
@@ -260,6 +265,7 @@ impl Bootstrap {
                 u32::try_from(blob_readahead_size)?,
                 *ctx.chunk_count_map.count(blob_index).unwrap_or(&0),
                 blob_cache_size,
+                ctx.key_version(),
             );
         }
 
@@ -307,6 +313,9 @@ impl Bootstrap {
         if ctx.explicit_uidgid {
             super_block.set_explicit_uidgid();
         }
+        if ctx.has_mtime {
+            super_block.set_has_mtime();
+        }
         if ctx.source_type == SourceType::StargzIndex {
             super_block.set_block_size(STARGZ_DEFAULT_BLOCK_SIZE);
         }
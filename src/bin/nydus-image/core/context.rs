@@ -7,6 +7,7 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::{Error, Result};
 
@@ -15,6 +16,7 @@ use rafs::metadata::Inode;
 use rafs::{RafsIoRead, RafsIoWrite};
 // FIXME: Must image tool depend on storage backend?
 use storage::compress;
+use storage::crypt::BlobCipher;
 
 use nydus_utils::digest::{self, RafsDigest};
 
@@ -28,6 +30,7 @@ pub const BUF_WRITER_CAPACITY: usize = 2 << 17;
 pub enum SourceType {
     Directory,
     StargzIndex,
+    Tar,
 }
 
 impl FromStr for SourceType {
@@ -36,17 +39,19 @@ impl FromStr for SourceType {
         match s {
             "directory" => Ok(Self::Directory),
             "stargz_index" => Ok(Self::StargzIndex),
+            "tar" => Ok(Self::Tar),
             _ => Err(anyhow!("invalid source type")),
         }
     }
 }
 
 pub struct BuildContext {
-    /// Source type: Directory | StargzIndex
+    /// Source type: Directory | StargzIndex | Tar
     pub source_type: SourceType,
     /// Source path, for different source type:
     /// Directory: should be a directory path
     /// StargzIndex: should be a stargz index json file path
+    /// Tar: should be a tar archive path, or "-" for stdin
     pub source_path: PathBuf,
     /// Blob id (user specified or sha256(blob)).
     pub blob_id: String,
@@ -60,6 +65,8 @@ pub struct BuildContext {
     pub digester: digest::Algorithm,
     /// Save host uid gid in each inode.
     pub explicit_uidgid: bool,
+    /// Save mtime and ctime in each inode, cleared if any source of inodes doesn't carry it.
+    pub has_mtime: bool,
     /// whiteout spec: overlayfs or oci
     pub whiteout_spec: WhiteoutSpec,
     /// Cache node index for hardlinks, HashMap<(real_inode, dev), Vec<index>>.
@@ -78,4 +85,18 @@ pub struct BuildContext {
     /// to image tool thus to align chunks in blob with 4k size.
     pub aligned_chunk: bool,
     pub prefetch: Prefetch,
+    /// Version of the key to encrypt chunks of the blob with and the key.
+    pub encryption: Option<(u32, Arc<BlobCipher>)>,
+}
+
+impl BuildContext {
+    /// Key to encrypt chunks of the blob with, if it's to be encrypted.
+    pub fn cipher(&self) -> Option<Arc<BlobCipher>> {
+        self.encryption.as_ref().map(|(_, cipher)| cipher.clone())
+    }
+
+    /// Version of the key the blob is encrypted with, 0 if not encrypted.
+    pub fn key_version(&self) -> u32 {
+        self.encryption.as_ref().map_or(0, |(version, _)| *version)
+    }
 }
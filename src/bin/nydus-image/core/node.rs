@@ -33,6 +33,8 @@ use super::blob::BlobBufferWriter;
 use rafs::metadata::layout::*;
 use rafs::metadata::*;
 use storage::compress;
+use storage::crypt::BlobCipher;
+use storage::utils::is_zero;
 
 const ROOT_PATH_NAME: &[u8] = &[b'/'];
 
@@ -127,6 +129,11 @@ impl ChunkCountMap {
         }
     }
 
+    /// Go on allocating indexes after `count` chunks already in a blob, e.g. one appended to.
+    pub fn set_count(&mut self, blob_index: u32, count: u32) {
+        self.chunks.insert(blob_index, count);
+    }
+
     /// Get the number of counts in a blob by the index of blob table.
     pub fn count(&self, blob_index: u32) -> Option<&u32> {
         self.chunks.get(&blob_index)
@@ -257,6 +264,50 @@ impl Node {
         digester: digest::Algorithm,
         blob_index: u32,
         aligned_chunk: bool,
+        cipher: Option<&BlobCipher>,
+    ) -> Result<usize> {
+        let mut file = None;
+        if self.is_reg() {
+            file = Some(
+                File::open(&self.path)
+                    .with_context(|| format!("failed to open node file {:?}", self.path))?,
+            );
+        }
+        self.dump_blob_from(
+            file.as_mut().map(|f| f as &mut dyn Read),
+            blob_writer,
+            blob_hash,
+            compress_offset,
+            decompress_offset,
+            blob_cache_size,
+            chunk_cache,
+            chunk_count_map,
+            compressor,
+            digester,
+            blob_index,
+            aligned_chunk,
+            cipher,
+        )
+    }
+
+    /// Dump file data read from `data` to blob, which is only read for regular files.
+    /// Chunks are encrypted after compressed if `cipher` is given.
+    #[allow(clippy::too_many_arguments)]
+    pub fn dump_blob_from(
+        &mut self,
+        data: Option<&mut dyn Read>,
+        blob_writer: &mut BlobBufferWriter,
+        blob_hash: &mut Sha256,
+        compress_offset: &mut u64,
+        decompress_offset: &mut u64,
+        blob_cache_size: &mut u64,
+        chunk_cache: &mut HashMap<RafsDigest, OndiskChunkInfo>,
+        chunk_count_map: &mut ChunkCountMap,
+        compressor: compress::Algorithm,
+        digester: digest::Algorithm,
+        blob_index: u32,
+        aligned_chunk: bool,
+        cipher: Option<&BlobCipher>,
     ) -> Result<usize> {
         if self.is_dir() {
             return Ok(0);
@@ -274,8 +325,7 @@ impl Node {
         let file_size = self.inode.i_size;
         let mut blob_size = 0usize;
         let mut inode_hasher = RafsDigest::hasher(digester);
-        let mut file = File::open(&self.path)
-            .with_context(|| format!("failed to open node file {:?}", self.path))?;
+        let file = data.ok_or_else(|| anyhow!("no data of node file {:?}", self.path))?;
 
         for i in 0..self.inode.i_child_count {
             // Init chunk info
@@ -297,12 +347,25 @@ impl Node {
                 .with_context(|| format!("failed to read node file {:?}", self.path))?;
 
             // Calculate chunk digest
-            // TODO: check for hole chunks. One possible way is to always save
-            // a global hole chunk and check for digest duplication
             chunk.block_id = RafsDigest::from_buf(chunk_data.as_slice(), digester);
             // Calculate inode digest
             inode_hasher.digest_update(chunk.block_id.as_ref());
 
+            // All-zero chunks are declared as hole chunks, which take no space in blob
+            // and are served by nydusd without any IO.
+            if is_zero(&chunk_data) {
+                chunk.flags |= RafsChunkFlags::HOLECHUNK;
+                chunk.blob_index = blob_index;
+                chunk.file_offset = file_offset;
+                chunk.compress_offset = *compress_offset;
+                chunk.decompress_offset = *decompress_offset;
+                chunk.decompress_size = chunk_size as u32;
+                self.chunks.push(chunk);
+                trace!("\t\tbuilding hole chunk: {}", chunk);
+                event_tracer!("hole_chunks", +1);
+                continue;
+            }
+
             // Deduplicate chunk if we found a same one from chunk cache
             if let Some(cached_chunk) = chunk_cache.get(&chunk.block_id) {
                 // hole cached_chunk can have zero decompress size
@@ -332,10 +395,21 @@ impl Node {
             // Compress chunk data
             let (compressed, is_compressed) = compress::compress(&chunk_data, compressor)
                 .with_context(|| format!("failed to compress node file {:?}", self.path))?;
-            let compressed_size = compressed.len();
             if is_compressed {
                 chunk.flags |= RafsChunkFlags::COMPRESSED;
             }
+            let encrypted;
+            let compressed = match cipher {
+                Some(cipher) => {
+                    encrypted = cipher
+                        .encrypt(&compressed)
+                        .with_context(|| format!("failed to encrypt node file {:?}", self.path))?;
+                    chunk.flags |= RafsChunkFlags::ENCRYPTED;
+                    encrypted.as_slice()
+                }
+                None => compressed.as_ref(),
+            };
+            let compressed_size = compressed.len();
 
             chunk.blob_index = blob_index;
             chunk.file_offset = file_offset;
@@ -359,13 +433,13 @@ impl Node {
             *decompress_offset += aligned_chunk_size;
 
             // Calculate blob hash
-            blob_hash.update(&compressed);
+            blob_hash.update(compressed);
 
             // Dump compressed chunk data to blob
             event_tracer!("blob_decompressed_size", +chunk_size);
             event_tracer!("blob_compressed_size", +compressed_size);
             blob_writer
-                .write_all(&compressed)
+                .write_all(compressed)
                 .context("failed to write blob")?;
 
             // Cache chunk digest info
@@ -427,6 +501,11 @@ impl Node {
         if self.explicit_uidgid {
             self.inode.i_uid = meta.st_uid();
             self.inode.i_gid = meta.st_gid();
+            // Timestamps break reproducible build as well as uid/gid.
+            self.inode.i_mtime = meta.st_mtime() as u64;
+            self.inode.i_mtime_nsec = meta.st_mtime_nsec() as u32;
+            self.inode
+                .set_ctime(meta.st_ctime() as u64, meta.st_ctime_nsec() as u32);
         }
         self.inode.i_projid = 0;
         self.inode.i_size = meta.st_size();
@@ -626,3 +705,73 @@ impl Node {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::blob::BlobStorage;
+    use crate::trace::{EventTracerClass, TraceClass};
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_dump_hole_chunks() {
+        register_tracer!(TraceClass::Event, EventTracerClass);
+        let dir = TempDir::new().unwrap();
+        let file_path = dir.as_path().join("file");
+        let block_size = RAFS_DEFAULT_BLOCK_SIZE as usize;
+        let mut data = vec![b'a'; block_size];
+        data.resize(block_size * 2 + 0x1000, 0);
+        fs::write(&file_path, &data).unwrap();
+
+        let mut node = Node::new(
+            dir.as_path().to_path_buf(),
+            file_path,
+            Overlay::UpperAddition,
+            false,
+        )
+        .unwrap();
+        assert_eq!(node.inode.i_child_count, 3);
+
+        let mut blob_writer =
+            BlobBufferWriter::new(BlobStorage::SingleFile(dir.as_path().join("blob"))).unwrap();
+        let mut compress_offset = 0;
+        let mut decompress_offset = 0;
+        let mut blob_cache_size = 0;
+        let mut chunk_cache = HashMap::new();
+        let mut chunk_count_map = ChunkCountMap::default();
+        let blob_size = node
+            .dump_blob(
+                &mut blob_writer,
+                &mut Sha256::new(),
+                &mut compress_offset,
+                &mut decompress_offset,
+                &mut blob_cache_size,
+                &mut chunk_cache,
+                &mut chunk_count_map,
+                compress::Algorithm::None,
+                digest::Algorithm::Blake3,
+                0,
+                false,
+                None,
+            )
+            .unwrap();
+
+        // Only the first chunk takes space in blob and gets a chunk map index.
+        assert_eq!(blob_size, block_size);
+        assert_eq!(compress_offset, block_size as u64);
+        assert_eq!(decompress_offset, block_size as u64);
+        assert_eq!(chunk_count_map.alloc_index(0).unwrap(), 1);
+        assert_eq!(chunk_cache.len(), 1);
+
+        assert_eq!(node.chunks.len(), 3);
+        assert!(!node.chunks[0].flags.contains(RafsChunkFlags::HOLECHUNK));
+        for (i, chunk) in node.chunks[1..].iter().enumerate() {
+            assert!(chunk.flags.contains(RafsChunkFlags::HOLECHUNK));
+            assert_eq!(chunk.compress_size, 0);
+            assert_eq!(chunk.decompress_offset, block_size as u64);
+            assert_eq!(chunk.file_offset, (i + 1) as u64 * block_size as u64);
+        }
+        assert_eq!(node.chunks[1].decompress_size, block_size as u32);
+        assert_eq!(node.chunks[2].decompress_size, 0x1000);
+    }
+}
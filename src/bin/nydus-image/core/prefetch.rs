@@ -100,6 +100,21 @@ impl Prefetch {
         })
     }
 
+    /// Prefetch `files` by `Fs` policy, e.g. those in the prefetch table of an existing
+    /// bootstrap, rather than files listed from stdin.
+    pub fn with_files(files: Vec<PathBuf>) -> Self {
+        let policy = if files.is_empty() {
+            PrefetchPolicy::None
+        } else {
+            PrefetchPolicy::Fs
+        };
+        Self {
+            policy,
+            hint_readahead_files: files.into_iter().map(|f| (f, None)).collect(),
+            readahead_files: BTreeMap::new(),
+        }
+    }
+
     pub fn insert_if_need(&mut self, node: &Node) {
         let path = &node.rootfs();
         let inode = node.inode.i_ino;
@@ -13,6 +13,7 @@
 use anyhow::Result;
 
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -100,6 +101,9 @@ impl<'a> MetadataTreeBuilder<'a> {
             }
             children.push(child);
         }
+        // Children of bootstrap are sorted by name, but don't rely on bootstraps built by
+        // other tools.
+        children.sort_by(|a, b| a.node.name().cmp(b.node.name()));
 
         Ok(children)
     }
@@ -163,6 +167,13 @@ impl Tree {
         }
     }
 
+    /// Binary search child by name, children are always kept sorted by name so that
+    /// applying layers onto huge directories doesn't end up with linear scans.
+    fn search_child(&self, name: &OsStr) -> std::result::Result<usize, usize> {
+        self.children
+            .binary_search_by(|child| child.node.name().cmp(name))
+    }
+
     pub fn iterate<F>(&self, cb: &F) -> Result<()>
     where
         F: Fn(&Node) -> bool,
@@ -230,12 +241,8 @@ impl Tree {
 
         // Don't search if path recursive depth out of target path
         if depth < target_paths_len {
-            // TODO: Search child by binary search
-            for child in self.children.iter_mut() {
-                // Skip if path component name not match
-                if target_paths[depth] != child.node.name() {
-                    continue;
-                }
+            if let Ok(idx) = self.search_child(&target_paths[depth]) {
+                let child = &mut self.children[idx];
                 // Modifications: Replace the node
                 if depth == target_paths_len - 1 {
                     let mut node = target.clone();
@@ -260,10 +267,16 @@ impl Tree {
         if depth == target_paths_len - 1 && target_paths[depth - 1] == self.node.name() {
             let mut node = target.clone();
             node.overlay = Overlay::UpperAddition;
-            self.children.push(Tree {
-                node,
-                children: Vec::new(),
-            });
+            let idx = match self.search_child(node.name()) {
+                Ok(idx) | Err(idx) => idx,
+            };
+            self.children.insert(
+                idx,
+                Tree {
+                    node,
+                    children: Vec::new(),
+                },
+            );
             return Ok(true);
         }
 
@@ -352,3 +365,80 @@ impl Tree {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{EventTracerClass, TraceClass};
+    use std::fs;
+    use std::path::Path;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn node(root: &Path, path: &str, overlay: Overlay) -> Node {
+        let path = root.join(path);
+        if path.extension().is_some() {
+            fs::write(&path, b"").unwrap();
+        } else {
+            fs::create_dir_all(&path).unwrap();
+        }
+        Node::new(root.to_path_buf(), path, overlay, false).unwrap()
+    }
+
+    fn names(tree: &Tree) -> Vec<&OsStr> {
+        tree.children.iter().map(|c| c.node.name()).collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        register_tracer!(TraceClass::Event, EventTracerClass);
+        let dir = TempDir::new().unwrap();
+        let root = dir.as_path();
+        let mut tree = Tree::new(node(root, "", Overlay::Lower));
+        for name in &["a.txt", "c", "e.txt"] {
+            tree.children
+                .push(Tree::new(node(root, name, Overlay::Lower)));
+        }
+        for name in &["c/x.txt", "c/z.txt"] {
+            tree.children[1]
+                .children
+                .push(Tree::new(node(root, name, Overlay::Lower)));
+        }
+
+        // Additions are inserted in place to keep children sorted by name.
+        for name in &["d.txt", "b.txt", "f.txt", "c/y.txt", "c/w.txt"] {
+            let target = node(root, name, Overlay::UpperAddition);
+            assert!(tree.apply(&target, true, &WhiteoutSpec::Oci).unwrap());
+        }
+        assert_eq!(
+            names(&tree),
+            vec!["a.txt", "b.txt", "c", "d.txt", "e.txt", "f.txt"]
+        );
+        assert_eq!(
+            names(&tree.children[2]),
+            vec!["w.txt", "x.txt", "y.txt", "z.txt"]
+        );
+        assert_eq!(tree.children[1].node.overlay, Overlay::UpperAddition);
+
+        // Modifications replace the node but keep its children.
+        let target = node(root, "c", Overlay::UpperAddition);
+        assert!(tree.apply(&target, true, &WhiteoutSpec::Oci).unwrap());
+        assert_eq!(tree.children[2].node.overlay, Overlay::UpperModification);
+        assert_eq!(tree.children[2].children.len(), 4);
+
+        // Removals drop the lower node, opaques drop all children of the directory.
+        let target = node(root, ".wh.e.txt", Overlay::UpperAddition);
+        assert!(tree.apply(&target, true, &WhiteoutSpec::Oci).unwrap());
+        assert_eq!(names(&tree), vec!["a.txt", "b.txt", "c", "d.txt", "f.txt"]);
+        let target = node(root, "c/.wh.x.txt", Overlay::UpperAddition);
+        assert!(tree.apply(&target, true, &WhiteoutSpec::Oci).unwrap());
+        assert_eq!(names(&tree.children[2]), vec!["w.txt", "y.txt", "z.txt"]);
+        let target = node(root, "c/.wh..wh..opq", Overlay::UpperAddition);
+        assert!(tree.apply(&target, true, &WhiteoutSpec::Oci).unwrap());
+        assert_eq!(tree.children[2].node.overlay, Overlay::UpperOpaque);
+        assert!(tree.children[2].children.is_empty());
+
+        // Whiteouts of missing files are not applied.
+        let target = node(root, ".wh.g.txt", Overlay::UpperAddition);
+        assert!(!tree.apply(&target, true, &WhiteoutSpec::Oci).unwrap());
+    }
+}
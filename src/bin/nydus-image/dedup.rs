@@ -0,0 +1,220 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Analyze chunks shared between images, to decide what to put in a chunk dictionary or a base
+//! image.
+//!
+//! Chunks are identified by their digests, regardless of the blobs holding them, since that's
+//! how the builder deduplicates chunks. A chunk is shared if more than one image has it, and
+//! unique to an image otherwise. Chunks repeated within an image are counted once for it.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use nydus_utils::digest::RafsDigest;
+use rafs::metadata::layout::RAFS_ROOT_INODE;
+use rafs::metadata::RafsSuper;
+
+use crate::inspect::load_super;
+
+/// Compressed and decompressed sizes of distinct chunks of an image, by chunk digest.
+type Chunks = HashMap<RafsDigest, (u32, u32)>;
+
+/// Number and sizes of some chunks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ChunkStat {
+    pub chunks: u64,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+}
+
+impl ChunkStat {
+    fn add(&mut self, compressed: u32, uncompressed: u32) {
+        self.chunks += 1;
+        self.compressed_size += compressed as u64;
+        self.uncompressed_size += uncompressed as u64;
+    }
+}
+
+/// Chunks of an image, and how many of them are shared with other images.
+#[derive(Debug, Serialize)]
+pub struct ImageStat {
+    pub bootstrap: String,
+    pub total: ChunkStat,
+    pub shared: ChunkStat,
+    pub unique: ChunkStat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DedupReport {
+    pub images: Vec<ImageStat>,
+    /// Chunks of all images, counted once for each image having them.
+    pub total: ChunkStat,
+    /// Distinct chunks of all images, what's left if all images are deduplicated together.
+    pub distinct: ChunkStat,
+    /// Distinct chunks had by more than one image.
+    pub shared: ChunkStat,
+}
+
+impl DedupReport {
+    pub fn print<W: Write>(&self, mut w: W) -> Result<()> {
+        let line = |w: &mut W, name: &str, total: &ChunkStat, shared: &ChunkStat| {
+            writeln!(
+                w,
+                "{}\t{}\t{}\t{}\t{}",
+                total.chunks, total.compressed_size, shared.chunks, shared.compressed_size, name
+            )
+        };
+        writeln!(w, "chunks\tsize\tshared\tshared_size\timage")?;
+        for image in &self.images {
+            line(&mut w, &image.bootstrap, &image.total, &image.shared)?;
+        }
+        line(&mut w, "total", &self.total, &self.shared)?;
+        writeln!(
+            w,
+            "{} distinct chunks of {} bytes, {} bytes saved by deduplicating images together",
+            self.distinct.chunks,
+            self.distinct.compressed_size,
+            self.total.compressed_size - self.distinct.compressed_size
+        )?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Count chunks shared between images of `bootstraps`.
+pub fn analyze(bootstraps: &[PathBuf]) -> Result<DedupReport> {
+    let mut images = Vec::with_capacity(bootstraps.len());
+    for bootstrap in bootstraps {
+        let rs = load_super(bootstrap)?;
+        let chunks = load_chunks(&rs)
+            .with_context(|| format!("failed to walk inodes of {:?}", bootstrap))?;
+        images.push((bootstrap.as_path(), chunks));
+    }
+    Ok(summarize(&images))
+}
+
+/// Distinct chunks of regular files of an image.
+fn load_chunks(rs: &RafsSuper) -> Result<Chunks> {
+    let mut chunks = HashMap::new();
+    let mut dirs = vec![rs.get_inode(RAFS_ROOT_INODE, false)?];
+
+    while let Some(dir) = dirs.pop() {
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            if child.is_dir() {
+                dirs.push(child);
+            } else if child.is_reg() {
+                for idx in 0..child.get_child_count() {
+                    let chunk = child.get_chunk_info(idx)?;
+                    chunks
+                        .entry(*chunk.block_id())
+                        .or_insert((chunk.compress_size(), chunk.decompress_size()));
+                }
+            }
+        }
+    }
+
+    Ok(chunks)
+}
+
+fn summarize(images: &[(&Path, Chunks)]) -> DedupReport {
+    // Number of images having a chunk, with its sizes in the first of them.
+    let mut owners: HashMap<&RafsDigest, (usize, u32, u32)> = HashMap::new();
+    for (_, chunks) in images {
+        for (digest, (compressed, uncompressed)) in chunks {
+            owners
+                .entry(digest)
+                .or_insert((0, *compressed, *uncompressed))
+                .0 += 1;
+        }
+    }
+
+    let mut report = DedupReport {
+        images: Vec::with_capacity(images.len()),
+        total: ChunkStat::default(),
+        distinct: ChunkStat::default(),
+        shared: ChunkStat::default(),
+    };
+    for (bootstrap, chunks) in images {
+        let mut image = ImageStat {
+            bootstrap: bootstrap.to_string_lossy().to_string(),
+            total: ChunkStat::default(),
+            shared: ChunkStat::default(),
+            unique: ChunkStat::default(),
+        };
+        for (digest, (compressed, uncompressed)) in chunks {
+            image.total.add(*compressed, *uncompressed);
+            report.total.add(*compressed, *uncompressed);
+            if owners[digest].0 > 1 {
+                image.shared.add(*compressed, *uncompressed);
+            } else {
+                image.unique.add(*compressed, *uncompressed);
+            }
+        }
+        report.images.push(image);
+    }
+    for (count, compressed, uncompressed) in owners.values() {
+        report.distinct.add(*compressed, *uncompressed);
+        if *count > 1 {
+            report.shared.add(*compressed, *uncompressed);
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nydus_utils::digest::Algorithm;
+
+    fn chunks(data: &[&[u8]]) -> Chunks {
+        data.iter()
+            .map(|d| {
+                let size = d.len() as u32;
+                (RafsDigest::from_buf(d, Algorithm::Sha256), (size, size * 2))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_summarize() {
+        let base = chunks(&[b"libc", b"bash"]);
+        let app1 = chunks(&[b"libc", b"bash", b"app-1"]);
+        let app2 = chunks(&[b"libc", b"app-22"]);
+        let images = vec![
+            (Path::new("base"), base),
+            (Path::new("app1"), app1),
+            (Path::new("app2"), app2),
+        ];
+
+        let report = summarize(&images);
+        let stat = |chunks, size| ChunkStat {
+            chunks,
+            compressed_size: size,
+            uncompressed_size: size * 2,
+        };
+        assert_eq!(report.images[0].shared, stat(2, 8));
+        assert_eq!(report.images[0].unique, stat(0, 0));
+        assert_eq!(report.images[1].total, stat(3, 13));
+        assert_eq!(report.images[1].unique, stat(1, 5));
+        assert_eq!(report.images[2].shared, stat(1, 4));
+        assert_eq!(report.total, stat(7, 31));
+        assert_eq!(report.distinct, stat(4, 19));
+        assert_eq!(report.shared, stat(2, 8));
+
+        let mut out = Vec::new();
+        report.print(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\n3\t13\t2\t8\tapp1\n"));
+        assert!(out.ends_with(
+            "4 distinct chunks of 19 bytes, 12 bytes saved by deduplicating images together\n"
+        ));
+    }
+}
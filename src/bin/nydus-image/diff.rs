@@ -0,0 +1,375 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compare two bootstraps, e.g. two releases of an image, to find out what an update costs.
+//!
+//! Files are matched by path. Besides added, removed and modified files, chunks of the new image
+//! which aren't in the old one are counted, their compressed size is what users running the old
+//! image have to download additionally when all files are read. A chunk is identified by its
+//! blob and offset in the blob rather than its digest, as data is fetched and cached per blob,
+//! so identical data moved into another blob still has to be downloaded.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use nydus_utils::digest::RafsDigest;
+use rafs::metadata::layout::{bytes_to_os_str, RAFS_ROOT_INODE};
+use rafs::metadata::RafsInode;
+
+use crate::inspect::load_super;
+
+/// `(blob id, compressed offset)` of a chunk.
+type ChunkKey = (String, u64);
+
+/// What's compared of a file.
+#[derive(Clone, Debug, Default)]
+struct Entry {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    rdev: u32,
+    digest: RafsDigest,
+    symlink: Option<OsString>,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+    /// Chunks of a regular file, with their compressed size.
+    chunks: Vec<(ChunkKey, u32)>,
+}
+
+impl Entry {
+    fn file_type(&self) -> &'static str {
+        match self.mode & libc::S_IFMT {
+            libc::S_IFREG => "file",
+            libc::S_IFDIR => "dir",
+            libc::S_IFLNK => "symlink",
+            libc::S_IFCHR => "char",
+            libc::S_IFBLK => "block",
+            libc::S_IFIFO => "fifo",
+            libc::S_IFSOCK => "socket",
+            _ => "unknown",
+        }
+    }
+
+    /// What's changed from `old`, mtime is left out as rebuilding an image changes it.
+    fn changes(&self, old: &Entry) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.mode & libc::S_IFMT != old.mode & libc::S_IFMT {
+            changes.push("type");
+        } else if self.mode != old.mode {
+            changes.push("mode");
+        }
+        if (self.uid, self.gid) != (old.uid, old.gid) {
+            changes.push("owner");
+        }
+        // Digests and sizes of directories depend on children, which are compared themselves.
+        if self.mode & libc::S_IFMT != libc::S_IFDIR {
+            if self.size != old.size {
+                changes.push("size");
+            }
+            if self.digest != old.digest || self.symlink != old.symlink || self.rdev != old.rdev {
+                changes.push("content");
+            }
+        }
+        if self.xattrs != old.xattrs {
+            changes.push("xattrs");
+        }
+        changes
+    }
+}
+
+/// A file added, removed or modified.
+#[derive(Debug, Serialize)]
+pub struct Change {
+    pub path: String,
+    pub file_type: &'static str,
+    /// What's modified, among type, mode, owner, size, content and xattrs.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<&'static str>,
+    /// Chunks of the file not in the old image, and their compressed size.
+    pub new_chunks: u64,
+    pub download_size: u64,
+}
+
+/// Differences between two images.
+#[derive(Debug, Default, Serialize)]
+pub struct DiffReport {
+    pub added: Vec<Change>,
+    pub removed: Vec<Change>,
+    pub modified: Vec<Change>,
+    pub added_blobs: Vec<String>,
+    pub removed_blobs: Vec<String>,
+    /// Distinct chunks of the new image not in the old one, and the other way around.
+    pub added_chunks: u64,
+    pub removed_chunks: u64,
+    /// Compressed size of added chunks, to be downloaded when all files are read.
+    pub download_size: u64,
+}
+
+impl DiffReport {
+    /// Print changes like `git diff --name-status`, a letter of the change and the path.
+    pub fn print<W: Write>(&self, mut w: W) -> Result<()> {
+        for c in &self.added {
+            writeln!(w, "A\t{}", c.path)?;
+        }
+        for c in &self.removed {
+            writeln!(w, "D\t{}", c.path)?;
+        }
+        for c in &self.modified {
+            writeln!(w, "M\t{}\t{}", c.path, c.changes.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare bootstrap `new` with `old`.
+pub fn diff(old: &Path, new: &Path) -> Result<DiffReport> {
+    let (old_entries, old_blobs) = load_entries(old)?;
+    let (new_entries, new_blobs) = load_entries(new)?;
+    Ok(compare(&old_entries, &old_blobs, &new_entries, &new_blobs))
+}
+
+/// Entries of all files in a bootstrap by path, and ids of its blobs.
+fn load_entries(path: &Path) -> Result<(BTreeMap<PathBuf, Entry>, Vec<String>)> {
+    let rs = load_super(path)?;
+
+    let blobs = rs
+        .inodes
+        .get_blob_table()
+        .entries
+        .iter()
+        .map(|e| e.blob_id.clone())
+        .collect::<Vec<_>>();
+
+    let mut entries = BTreeMap::new();
+    let mut dirs = vec![(rs.get_inode(RAFS_ROOT_INODE, false)?, PathBuf::from("/"))];
+    while let Some((dir, dir_path)) = dirs.pop() {
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            let path = dir_path.join(child.name());
+            let entry = new_entry(child.as_ref(), &blobs)
+                .with_context(|| format!("failed to load {:?}", path))?;
+            entries.insert(path.clone(), entry);
+            if child.is_dir() {
+                dirs.push((child, path));
+            }
+        }
+    }
+
+    Ok((entries, blobs))
+}
+
+fn new_entry(inode: &dyn RafsInode, blobs: &[String]) -> Result<Entry> {
+    let attr = inode.get_attr();
+    let mut entry = Entry {
+        mode: attr.mode,
+        uid: attr.uid,
+        gid: attr.gid,
+        size: attr.size,
+        rdev: inode.rdev(),
+        digest: inode.get_digest(),
+        ..Default::default()
+    };
+
+    if inode.is_symlink() {
+        entry.symlink = Some(inode.get_symlink()?);
+    }
+    if inode.has_xattr() {
+        for name in inode.get_xattrs()? {
+            let name = bytes_to_os_str(&name);
+            let value = inode.get_xattr(name)?.unwrap_or_default();
+            entry.xattrs.insert(name.to_os_string(), value);
+        }
+    }
+    if inode.is_reg() {
+        for idx in 0..inode.get_child_count() {
+            let chunk = inode.get_chunk_info(idx)?;
+            let blob_id = blobs
+                .get(chunk.blob_index() as usize)
+                .ok_or_else(|| anyhow!("invalid blob index {}", chunk.blob_index()))?;
+            entry.chunks.push((
+                (blob_id.clone(), chunk.compress_offset()),
+                chunk.compress_size(),
+            ));
+        }
+    }
+
+    Ok(entry)
+}
+
+fn compare(
+    old: &BTreeMap<PathBuf, Entry>,
+    old_blobs: &[String],
+    new: &BTreeMap<PathBuf, Entry>,
+    new_blobs: &[String],
+) -> DiffReport {
+    let old_chunks = chunk_sizes(old);
+    let new_chunks = chunk_sizes(new);
+    let mut report = DiffReport {
+        added_blobs: new_blobs
+            .iter()
+            .filter(|b| !old_blobs.contains(b))
+            .cloned()
+            .collect(),
+        removed_blobs: old_blobs
+            .iter()
+            .filter(|b| !new_blobs.contains(b))
+            .cloned()
+            .collect(),
+        ..Default::default()
+    };
+    for (key, size) in &new_chunks {
+        if !old_chunks.contains_key(key) {
+            report.added_chunks += 1;
+            report.download_size += *size as u64;
+        }
+    }
+    report.removed_chunks = old_chunks
+        .keys()
+        .filter(|k| !new_chunks.contains_key(*k))
+        .count() as u64;
+
+    let change = |path: &Path, entry: &Entry, changes: Vec<&'static str>| {
+        let mut change = Change {
+            path: path.to_string_lossy().to_string(),
+            file_type: entry.file_type(),
+            changes,
+            new_chunks: 0,
+            download_size: 0,
+        };
+        // A chunk repeated in the file is downloaded once.
+        let mut seen = HashSet::new();
+        for (key, size) in &entry.chunks {
+            if !old_chunks.contains_key(key) && seen.insert(key) {
+                change.new_chunks += 1;
+                change.download_size += *size as u64;
+            }
+        }
+        change
+    };
+
+    for (path, entry) in new {
+        match old.get(path) {
+            None => report.added.push(change(path, entry, Vec::new())),
+            Some(old_entry) => {
+                let changes = entry.changes(old_entry);
+                if !changes.is_empty() {
+                    report.modified.push(change(path, entry, changes));
+                }
+            }
+        }
+    }
+    for (path, entry) in old {
+        if !new.contains_key(path) {
+            report.removed.push(Change {
+                path: path.to_string_lossy().to_string(),
+                file_type: entry.file_type(),
+                changes: Vec::new(),
+                new_chunks: 0,
+                download_size: 0,
+            });
+        }
+    }
+
+    report
+}
+
+/// Compressed sizes of distinct chunks of all files.
+fn chunk_sizes(entries: &BTreeMap<PathBuf, Entry>) -> HashMap<ChunkKey, u32> {
+    entries
+        .values()
+        .flat_map(|e| e.chunks.iter().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nydus_utils::digest::Algorithm;
+
+    fn file(data: &[u8], chunks: &[(&str, u64, u32)]) -> Entry {
+        Entry {
+            mode: libc::S_IFREG | 0o644,
+            size: data.len() as u64,
+            digest: RafsDigest::from_buf(data, Algorithm::Blake3),
+            chunks: chunks
+                .iter()
+                .map(|(b, off, size)| ((b.to_string(), *off), *size))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let dir = Entry {
+            mode: libc::S_IFDIR | 0o755,
+            size: 4096,
+            ..Default::default()
+        };
+        let mut old = BTreeMap::new();
+        old.insert(PathBuf::from("/etc"), dir.clone());
+        old.insert(PathBuf::from("/etc/a"), file(b"a", &[("b1", 0, 10)]));
+        old.insert(PathBuf::from("/etc/b"), file(b"b", &[("b1", 10, 20)]));
+        old.insert(PathBuf::from("/etc/c"), file(b"c", &[("b1", 30, 30)]));
+
+        let mut new = BTreeMap::new();
+        let mut new_dir = dir;
+        new_dir.size = 8192;
+        new.insert(PathBuf::from("/etc"), new_dir);
+        new.insert(PathBuf::from("/etc/a"), file(b"a", &[("b1", 0, 10)]));
+        let mut b = file(b"b", &[("b1", 10, 20)]);
+        b.mode = libc::S_IFREG | 0o600;
+        b.xattrs.insert(OsString::from("user.k"), b"v".to_vec());
+        new.insert(PathBuf::from("/etc/b"), b);
+        new.insert(
+            PathBuf::from("/etc/d"),
+            file(b"dd", &[("b2", 0, 40), ("b2", 0, 40), ("b1", 30, 30)]),
+        );
+
+        let old_blobs = vec!["b0".to_string(), "b1".to_string()];
+        let new_blobs = vec!["b1".to_string(), "b2".to_string()];
+        let report = compare(&old, &old_blobs, &new, &new_blobs);
+        let paths = |changes: &[Change]| changes.iter().map(|c| c.path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths(&report.added), vec!["/etc/d"]);
+        assert_eq!(paths(&report.removed), vec!["/etc/c"]);
+        // Size of directories isn't compared.
+        assert_eq!(paths(&report.modified), vec!["/etc/b"]);
+        assert_eq!(report.modified[0].changes, vec!["mode", "xattrs"]);
+        assert_eq!(report.modified[0].new_chunks, 0);
+
+        // The chunk of removed `/etc/c` is reused by `/etc/d`.
+        assert_eq!(report.added[0].new_chunks, 1);
+        assert_eq!(report.added[0].download_size, 40);
+        assert_eq!(report.added_chunks, 1);
+        assert_eq!(report.removed_chunks, 0);
+        assert_eq!(report.download_size, 40);
+        assert_eq!(report.added_blobs, vec!["b2"]);
+        assert_eq!(report.removed_blobs, vec!["b0"]);
+
+        let mut out = Vec::new();
+        report.print(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "A\t/etc/d\nD\t/etc/c\nM\t/etc/b\tmode,xattrs\n"
+        );
+    }
+
+    #[test]
+    fn test_entry_changes() {
+        let old = file(b"a", &[("b1", 0, 10)]);
+        let mut new = file(b"b", &[("b1", 10, 10)]);
+        assert_eq!(new.changes(&old), vec!["content"]);
+
+        new.mode = libc::S_IFLNK | 0o777;
+        new.uid = 1000;
+        new.size = 0;
+        assert_eq!(new.changes(&old), vec!["type", "owner", "size", "content"]);
+        assert_eq!(new.file_type(), "symlink");
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Report sizes of directories of a RAFS image, like `du`, to find out what makes it big.
+//!
+//! Sizes are those of chunks in blobs rather than file sizes. A chunk shared by multiple files,
+//! e.g. deduplicated or hardlinked ones, is owned by the first of them in path order and only
+//! counted there, so sizes of all files sum up to the size of the blobs and nothing is counted
+//! twice. Holes take no space except for the one all-zero chunk they share.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use rafs::metadata::layout::RAFS_ROOT_INODE;
+use rafs::metadata::RafsSuper;
+
+use crate::inspect::load_super;
+
+/// `(blob index, compressed offset)` of a chunk.
+type ChunkKey = (u32, u64);
+
+/// A file or directory with its chunks, in path order.
+struct Node {
+    path: PathBuf,
+    is_dir: bool,
+    /// Chunks of a regular file, with compressed and decompressed sizes.
+    chunks: Vec<(ChunkKey, u32, u32)>,
+}
+
+/// Sizes of chunks owned by a file or all files under a directory.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Usage {
+    pub path: String,
+    pub is_dir: bool,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    /// Regular files owning at least one chunk, the file itself for a file.
+    pub files: u64,
+}
+
+/// Which entries to report and how.
+pub struct DuOptions {
+    /// Files are reported besides directories.
+    pub all: bool,
+    /// Entries deeper than this are summed up into their parents only, the root is of depth 0.
+    pub max_depth: Option<usize>,
+    /// Largest compressed size first, rather than path order.
+    pub sort: bool,
+}
+
+/// Sizes of directories, and files if asked, of the bootstrap at `path`.
+pub fn du(path: &Path, options: &DuOptions) -> Result<Vec<Usage>> {
+    let rs = load_super(path)?;
+    let nodes = load_nodes(&rs).context("failed to walk inodes")?;
+    Ok(summarize(&nodes, options))
+}
+
+/// Print `usages` as lines of compressed size, uncompressed size and path, tab separated.
+pub fn print<W: Write>(usages: &[Usage], mut w: W) -> Result<()> {
+    for u in usages {
+        writeln!(
+            w,
+            "{}\t{}\t{}",
+            u.compressed_size, u.uncompressed_size, u.path
+        )?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// All files and directories in path order, parents before children.
+fn load_nodes(rs: &RafsSuper) -> Result<Vec<Node>> {
+    let root = rs.get_inode(RAFS_ROOT_INODE, false)?;
+    let mut dirs = vec![(root, PathBuf::from("/"))];
+    let mut nodes = vec![Node {
+        path: PathBuf::from("/"),
+        is_dir: true,
+        chunks: Vec::new(),
+    }];
+
+    while let Some((dir, dir_path)) = dirs.pop() {
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            let path = dir_path.join(child.name());
+            let mut chunks = Vec::new();
+            if child.is_reg() {
+                for idx in 0..child.get_child_count() {
+                    let chunk = child.get_chunk_info(idx)?;
+                    chunks.push((
+                        (chunk.blob_index(), chunk.compress_offset()),
+                        chunk.compress_size(),
+                        chunk.decompress_size(),
+                    ));
+                }
+            }
+            nodes.push(Node {
+                path: path.clone(),
+                is_dir: child.is_dir(),
+                chunks,
+            });
+            if child.is_dir() {
+                dirs.push((child, path));
+            }
+        }
+    }
+    // Paths are compared by components, so a directory sorts right before its children.
+    nodes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(nodes)
+}
+
+fn summarize(nodes: &[Node], options: &DuOptions) -> Vec<Usage> {
+    let mut owned = HashSet::new();
+    let mut usages = Vec::with_capacity(nodes.len());
+    let mut dirs: HashMap<&Path, usize> = HashMap::new();
+
+    for node in nodes {
+        let mut usage = Usage {
+            path: node.path.to_string_lossy().to_string(),
+            is_dir: node.is_dir,
+            compressed_size: 0,
+            uncompressed_size: 0,
+            files: 0,
+        };
+        for (key, compressed, uncompressed) in &node.chunks {
+            if owned.insert(*key) {
+                usage.compressed_size += *compressed as u64;
+                usage.uncompressed_size += *uncompressed as u64;
+            }
+        }
+        if usage.compressed_size > 0 || usage.uncompressed_size > 0 {
+            usage.files = 1;
+        }
+
+        // Parents come before children in path order.
+        for parent in node.path.ancestors().skip(1) {
+            if let Some(idx) = dirs.get(parent) {
+                let dir: &mut Usage = &mut usages[*idx];
+                dir.compressed_size += usage.compressed_size;
+                dir.uncompressed_size += usage.uncompressed_size;
+                dir.files += usage.files;
+            }
+        }
+        if node.is_dir {
+            dirs.insert(&node.path, usages.len());
+        }
+        usages.push(usage);
+    }
+
+    let mut usages = nodes
+        .iter()
+        .zip(usages)
+        .filter(|(node, usage)| {
+            // The root has one component.
+            let depth = node.path.components().count() - 1;
+            (usage.is_dir || options.all) && options.max_depth.map_or(true, |max| depth <= max)
+        })
+        .map(|(_, usage)| usage)
+        .collect::<Vec<_>>();
+    if options.sort {
+        usages.sort_by(|a, b| b.compressed_size.cmp(&a.compressed_size));
+    }
+
+    usages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(path: &str) -> Node {
+        Node {
+            path: PathBuf::from(path),
+            is_dir: true,
+            chunks: Vec::new(),
+        }
+    }
+
+    fn file(path: &str, chunks: &[(u64, u32)]) -> Node {
+        Node {
+            path: PathBuf::from(path),
+            is_dir: false,
+            chunks: chunks
+                .iter()
+                .map(|(off, size)| ((0, *off), *size, *size * 2))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let nodes = vec![
+            dir("/"),
+            dir("/bin"),
+            file("/bin/a", &[(0, 100), (100, 50)]),
+            // Deduplicated with the first chunk of `a`.
+            file("/bin/b", &[(0, 100)]),
+            dir("/usr"),
+            dir("/usr/lib"),
+            file("/usr/lib/c.so", &[(150, 30), (150, 30)]),
+        ];
+        let mut options = DuOptions {
+            all: false,
+            max_depth: None,
+            sort: false,
+        };
+
+        let usages = summarize(&nodes, &options);
+        let sizes = usages
+            .iter()
+            .map(|u| (u.path.as_str(), u.compressed_size, u.files))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sizes,
+            vec![
+                ("/", 180, 2),
+                ("/bin", 150, 1),
+                ("/usr", 30, 1),
+                ("/usr/lib", 30, 1),
+            ]
+        );
+        assert_eq!(usages[0].uncompressed_size, 360);
+
+        options.all = true;
+        options.max_depth = Some(1);
+        options.sort = true;
+        let usages = summarize(&nodes, &options);
+        let paths = usages.iter().map(|u| u.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/", "/bin", "/usr"]);
+
+        options.max_depth = None;
+        let usages = summarize(&nodes, &options);
+        let b = usages.iter().find(|u| u.path == "/bin/b").unwrap();
+        assert_eq!((b.compressed_size, b.files), (0, 0));
+
+        let mut out = Vec::new();
+        print(&usages[..2], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "180\t360\t/\n150\t300\t/bin\n"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Export a RAFS image back into OCI image layers, gzip compressed tar archives.
+//!
+//! Each layer is described by the bootstrap built for it, i.e. the bootstraps built with
+//! `--parent-bootstrap` one upon another, from the lowest layer. The first layer has all files
+//! of its bootstrap. Any other layer has files added or modified from the bootstrap below, and
+//! whiteouts of files removed, so that applying the layers in order gives the same files as
+//! the bootstrap of the top layer. A removed directory is hidden by a single whiteout, opaque
+//! whiteouts are not written.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use sha2::digest::Digest;
+use sha2::Sha256;
+
+use storage::factory::BackendConfig;
+
+use crate::core::context::BUF_WRITER_CAPACITY;
+use crate::diff::{self, DiffReport};
+use crate::unpack::{collect_items, ensure_blobs, open_image, write_tar, Item};
+
+const OCI_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// A layer written, with digests to be referred to by an image manifest and config.
+#[derive(Debug, Serialize)]
+pub struct Layer {
+    pub path: String,
+    pub media_type: &'static str,
+    /// Digest of the compressed archive, as in the manifest.
+    pub digest: String,
+    /// Digest of the uncompressed archive, as in `rootfs.diff_ids` of the config.
+    pub diff_id: String,
+    pub size: u64,
+}
+
+/// Hashes and counts bytes written through it.
+struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    fn digest(&self) -> String {
+        format!("sha256:{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct Exporter {
+    /// Bootstraps of layers, the lowest first.
+    bootstraps: Vec<PathBuf>,
+    backend: BackendConfig,
+    output_dir: PathBuf,
+    /// Number of threads reading file data.
+    threads: usize,
+}
+
+impl Exporter {
+    pub fn new(
+        bootstraps: Vec<PathBuf>,
+        backend: BackendConfig,
+        output_dir: &Path,
+        threads: usize,
+    ) -> Self {
+        Exporter {
+            bootstraps,
+            backend,
+            output_dir: output_dir.to_path_buf(),
+            threads: std::cmp::max(threads, 1),
+        }
+    }
+
+    /// Write layers as `layer-<n>.tar.gz` in the output directory, numbered from 0 for the
+    /// lowest one.
+    pub fn export(&self) -> Result<Vec<Layer>> {
+        fs::create_dir_all(&self.output_dir)
+            .with_context(|| format!("failed to create {:?}", self.output_dir))?;
+
+        let mut layers = Vec::with_capacity(self.bootstraps.len());
+        for (idx, bootstrap) in self.bootstraps.iter().enumerate() {
+            let changes = match idx {
+                0 => None,
+                _ => Some(diff::diff(&self.bootstraps[idx - 1], bootstrap)?),
+            };
+            let path = self.output_dir.join(format!("layer-{}.tar.gz", idx));
+            let layer = self
+                .export_layer(bootstrap, changes.as_ref(), &path)
+                .with_context(|| format!("failed to export layer of {:?}", bootstrap))?;
+            info!(
+                "layer {} of {:?} exported to {:?}, {}",
+                idx, bootstrap, path, layer.digest
+            );
+            layers.push(layer);
+        }
+
+        Ok(layers)
+    }
+
+    /// Write files of `bootstrap` changed as `changes` tells, or all of them for the lowest
+    /// layer, to a gzip tar at `path`, which is removed if it fails half way.
+    fn export_layer(
+        &self,
+        bootstrap: &Path,
+        changes: Option<&DiffReport>,
+        path: &Path,
+    ) -> Result<Layer> {
+        let reader = open_image(bootstrap, &self.backend, "export", true)?;
+        ensure_blobs(&reader, &self.backend)?;
+        let reader = Arc::new(reader);
+
+        let mut items = Vec::new();
+        match changes {
+            None => items.extend(collect_items(&reader, &|_| true)?),
+            Some(changes) => {
+                let (whiteouts, selected) = layer_changes(changes);
+                // Whiteouts come before other entries, so that a removed path added back in
+                // the same layer isn't hidden by extractors applying entries in order.
+                items.extend(whiteouts.iter().map(|w| Item::empty_file(w)));
+                items.extend(collect_items(&reader, &|p| selected.contains(p))?);
+            }
+        }
+
+        let file = File::create(path).with_context(|| format!("failed to create {:?}", path))?;
+        let compressed = HashWriter::new(BufWriter::with_capacity(BUF_WRITER_CAPACITY, file));
+        let gz = GzEncoder::new(compressed, Compression::default());
+        let tar = HashWriter::new(gz);
+        write_tar(reader, items, self.threads, tar)
+            .and_then(|tar| {
+                let diff_id = tar.digest();
+                let compressed = tar.inner.finish()?;
+                let digest = compressed.digest();
+                let size = compressed.size;
+                compressed
+                    .inner
+                    .into_inner()
+                    .map_err(|e| anyhow!("failed to flush output, {}", e.error()))?
+                    .sync_all()?;
+                Ok(Layer {
+                    path: path.to_string_lossy().to_string(),
+                    media_type: OCI_LAYER_MEDIA_TYPE,
+                    digest,
+                    diff_id,
+                    size,
+                })
+            })
+            .map_err(|e| {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("failed to remove output {:?}, {}", path, e);
+                }
+                e
+            })
+    }
+}
+
+/// Whiteouts of a layer, and paths of files to put in it, both relative to the root.
+///
+/// Files added or modified are put in the layer. Files removed, or replaced by files of
+/// another type, get whiteouts, except for those under a removed directory whose whiteout
+/// hides them all.
+fn layer_changes(changes: &DiffReport) -> (Vec<PathBuf>, HashSet<PathBuf>) {
+    let relative = |path: &str| {
+        Path::new(path)
+            .strip_prefix("/")
+            .unwrap_or_else(|_| Path::new(path))
+            .to_path_buf()
+    };
+
+    let mut selected = HashSet::new();
+    let mut removed = HashSet::new();
+    for c in &changes.added {
+        selected.insert(relative(&c.path));
+    }
+    for c in &changes.modified {
+        selected.insert(relative(&c.path));
+        if c.changes.contains(&"type") {
+            removed.insert(relative(&c.path));
+        }
+    }
+    for c in &changes.removed {
+        removed.insert(relative(&c.path));
+    }
+
+    let mut whiteouts = removed
+        .iter()
+        .filter(|p| !p.ancestors().skip(1).any(|a| removed.contains(a)))
+        .filter_map(|p| {
+            let name = p.file_name()?;
+            let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+            whiteout.push(name);
+            Some(p.with_file_name(whiteout))
+        })
+        .collect::<Vec<_>>();
+    whiteouts.sort();
+
+    (whiteouts, selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::Change;
+
+    fn change(path: &str, changes: &[&'static str]) -> Change {
+        Change {
+            path: path.to_string(),
+            file_type: "file",
+            changes: changes.to_vec(),
+            new_chunks: 0,
+            download_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_layer_changes() {
+        let report = DiffReport {
+            added: vec![change("/etc/new", &[])],
+            removed: vec![
+                change("/opt", &[]),
+                change("/opt/app", &[]),
+                change("/opt/app/bin", &[]),
+                change("/etc/old", &[]),
+            ],
+            modified: vec![
+                change("/etc/passwd", &["content"]),
+                change("/usr/lib", &["type"]),
+            ],
+            ..Default::default()
+        };
+        let (whiteouts, selected) = layer_changes(&report);
+        assert_eq!(
+            whiteouts,
+            vec![
+                PathBuf::from(".wh.opt"),
+                PathBuf::from("etc/.wh.old"),
+                PathBuf::from("usr/.wh.lib"),
+            ]
+        );
+        let mut selected = selected.into_iter().collect::<Vec<_>>();
+        selected.sort();
+        assert_eq!(
+            selected,
+            vec![
+                PathBuf::from("etc/new"),
+                PathBuf::from("etc/passwd"),
+                PathBuf::from("usr/lib"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_writer() {
+        let mut w = HashWriter::new(Vec::new());
+        w.write_all(b"abc").unwrap();
+        assert_eq!(w.size, 3);
+        assert_eq!(
+            w.digest(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(w.inner, b"abc");
+    }
+}
@@ -0,0 +1,475 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Check a blobcache work dir and repair it, so that a suspect cache needn't be deleted whole.
+//!
+//! Each blob cached has a cache file named by its blob id, a chunk map file telling which of its
+//! chunks are ready, and maybe a side file of the hot tier, which is never reused. Blob ids may
+//! be anything, so files with a chunk map of their own or named by a blob id of the images are
+//! always taken as cache files, whatever they look like. Chunk maps left without their cache
+//! files and side files are removed. A chunk map nydusd would refuse or
+//! reset is removed along with its cache file, since nothing in the cache file can be trusted.
+//! Given bootstraps of images using the cache, every chunk marked ready is read back from the
+//! cache file and checked against its digest, and bad ones are marked not ready so that they're
+//! fetched again.
+//!
+//! The work dir must not be used by nydusd meanwhile. nydusd holds a shared lock of a cache file
+//! while using it, so all cache files are locked exclusively before anything is checked, and
+//! nothing is done if any of them is in use.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use serde::Serialize;
+
+use nydus_utils::digest::{self, RafsDigest};
+use rafs::metadata::layout::RAFS_ROOT_INODE;
+use rafs::metadata::RafsSuper;
+use storage::cache::chunkmap::indexed::{self, IndexedChunkMap};
+use storage::cache::chunkmap::ChunkMap;
+use storage::cache::hybrid::HOT_FILE_SUFFIX;
+use storage::compress;
+use storage::device::RafsChunkInfo;
+
+use crate::inspect::load_super;
+
+/// A chunk of an image, to check its data cached against.
+struct ImageChunk {
+    info: Arc<dyn RafsChunkInfo>,
+    compressor: compress::Algorithm,
+    digester: digest::Algorithm,
+}
+
+/// Chunks of a blob known from bootstraps, by chunk index.
+struct BlobChunks {
+    chunk_count: u32,
+    chunks: HashMap<u32, ImageChunk>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub path: String,
+    pub problem: String,
+    /// What's done about it, or would be done without a dry run.
+    pub action: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    /// Blob cache files found.
+    pub blobs: usize,
+    /// Blob cache files whose chunks are checked, i.e. those of blobs in the bootstraps.
+    pub blobs_checked: usize,
+    pub chunks_checked: u64,
+    pub problems: Vec<Problem>,
+    /// False for a dry run, where nothing is changed.
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn print<W: Write>(&self, mut w: W) -> Result<()> {
+        for p in &self.problems {
+            writeln!(w, "{}: {}, {}", p.path, p.problem, p.action)?;
+        }
+        writeln!(
+            w,
+            "{} blobs, {} checked with {} chunks, {} problems {}",
+            self.blobs,
+            self.blobs_checked,
+            self.chunks_checked,
+            self.problems.len(),
+            if self.repaired { "fixed" } else { "found" }
+        )?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+pub struct FsckOptions {
+    /// Chunks are cached compressed, as `cache_compressed` of the blobcache config tells.
+    pub compressed: bool,
+    /// Only report problems without fixing them.
+    pub dry_run: bool,
+}
+
+pub struct Fsck {
+    work_dir: PathBuf,
+    options: FsckOptions,
+    /// Chunks of blobs by blob id.
+    blobs: HashMap<String, BlobChunks>,
+}
+
+impl Fsck {
+    /// Check the blobcache work dir `work_dir`, with chunks of images of `bootstraps`.
+    pub fn new(work_dir: &Path, bootstraps: &[PathBuf], options: FsckOptions) -> Result<Self> {
+        let mut blobs = HashMap::new();
+        for bootstrap in bootstraps {
+            let rs = load_super(bootstrap)?;
+            load_chunks(&rs, &mut blobs)
+                .with_context(|| format!("failed to walk inodes of {:?}", bootstrap))?;
+        }
+
+        Ok(Fsck {
+            work_dir: work_dir.to_path_buf(),
+            options,
+            blobs,
+        })
+    }
+
+    pub fn check(&self) -> Result<FsckReport> {
+        let mut names = BTreeSet::new();
+        let entries = fs::read_dir(&self.work_dir)
+            .with_context(|| format!("failed to read work dir {:?}", self.work_dir))?;
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            match entry.file_name().into_string() {
+                Ok(name) => {
+                    names.insert(name);
+                }
+                Err(name) => warn!("unknown file {:?} in work dir, skipped", name),
+            }
+        }
+
+        let mut report = FsckReport {
+            repaired: !self.options.dry_run,
+            ..Default::default()
+        };
+        let chunk_map_suffix = format!(".{}", indexed::FILE_SUFFIX);
+        let has_chunk_map = |name: &str| names.contains(&format!("{}{}", name, chunk_map_suffix));
+        let is_blob = |name: &str| self.blobs.contains_key(name) || has_chunk_map(name);
+
+        // Locked until all is done, so that nydusd can't start using them meanwhile.
+        let mut locked = HashMap::new();
+        for name in names.iter().filter(|n| has_chunk_map(n)) {
+            locked.insert(name.as_str(), self.lock_blob(name)?);
+        }
+
+        for name in &names {
+            if is_blob(name) {
+                report.blobs += 1;
+                if let Some(file) = locked.get(name.as_str()) {
+                    self.check_blob(&mut report, name, file)
+                        .with_context(|| format!("failed to check blob {}", name))?;
+                }
+            } else if let Some(blob_id) = name.strip_suffix(&chunk_map_suffix) {
+                if !names.contains(blob_id) {
+                    self.remove(
+                        &mut report,
+                        &[name.as_str()],
+                        "chunk map without blob cache file",
+                    )?;
+                }
+            } else if is_hot_file(name) {
+                self.remove(&mut report, &[name.as_str()], "leftover hot tier file")?;
+            } else {
+                report.blobs += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Open blob cache file `blob_id` and lock it exclusively, fails if nydusd uses it.
+    fn lock_blob(&self, blob_id: &str) -> Result<File> {
+        let path = self.work_dir.join(blob_id);
+        let file = File::open(&path).with_context(|| format!("failed to open {:?}", path))?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(file),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                bail!(
+                    "{:?} is in use by nydusd, which must be stopped first",
+                    path
+                )
+            }
+            Err(e) => Err(anyhow!("failed to lock {:?}, {}", path, e)),
+        }
+    }
+
+    /// Check the chunk map of blob `blob_id`, and chunks it marks ready in the cache `file` if
+    /// the blob is known.
+    fn check_blob(&self, report: &mut FsckReport, blob_id: &str, file: &File) -> Result<()> {
+        let blob_path = self.work_dir.join(blob_id);
+        // The work dir is a UTF-8 string in the blobcache config.
+        let blob_path = blob_path
+            .to_str()
+            .ok_or_else(|| anyhow!("invalid path {:?}", blob_path))?;
+        let blob = self.blobs.get(blob_id).filter(|b| b.chunk_count > 0);
+
+        if let Some(fault) = IndexedChunkMap::check_file(blob_path, blob.map(|b| b.chunk_count))? {
+            let chunk_map = format!("{}.{}", blob_id, indexed::FILE_SUFFIX);
+            return self.remove(report, &[chunk_map.as_str(), blob_id], &fault.to_string());
+        }
+        let blob = match blob {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+
+        let mut bad = Vec::new();
+        for idx in IndexedChunkMap::ready_chunks(blob_path)? {
+            // Bitmaps are padded to bytes, and chunks of files not in the bootstraps are unknown.
+            if let Some(chunk) = blob.chunks.get(&idx) {
+                report.chunks_checked += 1;
+                if !check_chunk(file, chunk, self.options.compressed).unwrap_or(false) {
+                    bad.push(chunk);
+                }
+            }
+        }
+        report.blobs_checked += 1;
+        if bad.is_empty() {
+            return Ok(());
+        }
+
+        if !self.options.dry_run {
+            let chunk_map = IndexedChunkMap::new(blob_path, blob.chunk_count)?;
+            for chunk in &bad {
+                chunk_map.clear_ready(chunk.info.as_ref())?;
+            }
+        }
+        report.problems.push(Problem {
+            path: blob_path.to_string(),
+            problem: format!("{} chunks don't match their digests", bad.len()),
+            action: "marked not ready".to_string(),
+        });
+
+        Ok(())
+    }
+
+    /// Remove files `names` in the work dir for `problem`.
+    fn remove(&self, report: &mut FsckReport, names: &[&str], problem: &str) -> Result<()> {
+        if !self.options.dry_run {
+            for name in names {
+                let path = self.work_dir.join(name);
+                fs::remove_file(&path).with_context(|| format!("failed to remove {:?}", path))?;
+            }
+        }
+        report.problems.push(Problem {
+            path: self.work_dir.join(names[0]).to_string_lossy().to_string(),
+            problem: problem.to_string(),
+            action: match names.len() {
+                1 => "removed".to_string(),
+                _ => "removed with blob cache file".to_string(),
+            },
+        });
+        Ok(())
+    }
+}
+
+/// Whether `name` is of a hot tier side file, `$blob_id.$pid.$seq.hot`.
+fn is_hot_file(name: &str) -> bool {
+    let parts = name.rsplitn(4, '.').collect::<Vec<_>>();
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    parts.len() == 4
+        && parts[0] == HOT_FILE_SUFFIX
+        && is_number(parts[1])
+        && is_number(parts[2])
+        && !parts[3].is_empty()
+}
+
+/// Chunks of regular files of an image, added to `blobs`.
+fn load_chunks(rs: &RafsSuper, blobs: &mut HashMap<String, BlobChunks>) -> Result<()> {
+    let compressor = rs.meta.get_compressor();
+    let digester = rs.meta.get_digester();
+    let table = rs.inodes.get_blob_table();
+    let mut dirs = vec![rs.get_inode(RAFS_ROOT_INODE, false)?];
+
+    while let Some(dir) = dirs.pop() {
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            if child.is_dir() {
+                dirs.push(child);
+            } else if child.is_reg() {
+                for idx in 0..child.get_child_count() {
+                    let info = child.get_chunk_info(idx)?;
+                    // Encrypted chunks can't be checked without their keys.
+                    if info.is_encrypted() {
+                        continue;
+                    }
+                    let blob = table.get(info.blob_index())?;
+                    blobs
+                        .entry(blob.blob_id.clone())
+                        .or_insert_with(|| BlobChunks {
+                            chunk_count: blob.chunk_count,
+                            chunks: HashMap::new(),
+                        })
+                        .chunks
+                        .entry(info.index())
+                        .or_insert(ImageChunk {
+                            info,
+                            compressor,
+                            digester,
+                        });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether data of `chunk` in the blob cache `file` matches its digest. Chunks are cached at
+/// compressed offsets as they're in the blob if `compressed`, or decompressed otherwise.
+fn check_chunk(file: &File, chunk: &ImageChunk, compressed: bool) -> Result<bool> {
+    let info = &chunk.info;
+    let mut data = vec![0u8; info.decompress_size() as usize];
+    if !compressed {
+        file.read_exact_at(&mut data, info.decompress_offset())?;
+    } else if !info.is_compressed() {
+        file.read_exact_at(&mut data, info.compress_offset())?;
+    } else if chunk.compressor == compress::Algorithm::GZip {
+        // Compressed size isn't recorded for gzip chunks, decompress them as a stream.
+        let mut f = file.try_clone()?;
+        f.seek(SeekFrom::Start(info.compress_offset()))?;
+        compress::decompress(&[], Some(f), &mut data, chunk.compressor)?;
+    } else {
+        let mut raw = vec![0u8; info.compress_size() as usize];
+        file.read_exact_at(&mut raw, info.compress_offset())?;
+        compress::decompress(&raw, None, &mut data, chunk.compressor)?;
+    }
+    Ok(&RafsDigest::from_buf(&data, chunk.digester) == info.block_id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rafs::metadata::cached::CachedChunkInfo;
+    use rafs::metadata::layout::OndiskChunkInfo;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn chunk(index: u32, data: &[u8], decompress_offset: u64) -> ImageChunk {
+        let info = OndiskChunkInfo {
+            block_id: RafsDigest::from_buf(data, digest::Algorithm::Blake3),
+            index,
+            decompress_offset,
+            decompress_size: data.len() as u32,
+            compress_size: data.len() as u32,
+            ..Default::default()
+        };
+        ImageChunk {
+            info: Arc::new(CachedChunkInfo::from(&info)),
+            compressor: compress::Algorithm::None,
+            digester: digest::Algorithm::Blake3,
+        }
+    }
+
+    #[test]
+    fn test_fsck() {
+        let dir = TempDir::new().unwrap();
+        let work_dir = dir.as_path();
+        let blob_path = work_dir.join("blob-1");
+        let blob = blob_path.to_str().unwrap();
+
+        // Chunk 1 is corrupted in the cache file, chunk 2 isn't ready.
+        fs::write(&blob_path, b"good-0bad!-1good-2").unwrap();
+        let chunks = vec![
+            chunk(0, b"good-0", 0),
+            chunk(1, b"good-1", 6),
+            chunk(2, b"good-2", 12),
+        ];
+        let chunk_map = IndexedChunkMap::new(blob, 3).unwrap();
+        chunk_map.set_ready(chunks[0].info.as_ref()).unwrap();
+        chunk_map.set_ready(chunks[1].info.as_ref()).unwrap();
+        drop(chunk_map);
+        // Corrupted chunk map of another blob, an orphaned chunk map and a hot tier file.
+        fs::write(work_dir.join("blob-2"), b"data").unwrap();
+        fs::write(work_dir.join("blob-2.chunk_map"), vec![0u8; 4097]).unwrap();
+        fs::write(work_dir.join("blob-3.chunk_map"), vec![0u8; 4097]).unwrap();
+        fs::write(work_dir.join("blob-1.123.0.hot"), b"").unwrap();
+        // A blob whose id looks like a hot tier file.
+        let odd_blob = work_dir.join("blob-4.1.2.hot");
+        fs::write(&odd_blob, b"").unwrap();
+        drop(IndexedChunkMap::new(odd_blob.to_str().unwrap(), 1).unwrap());
+
+        let mut blobs = HashMap::new();
+        blobs.insert(
+            "blob-1".to_string(),
+            BlobChunks {
+                chunk_count: 3,
+                chunks: chunks.into_iter().map(|c| (c.info.index(), c)).collect(),
+            },
+        );
+        let mut fsck = Fsck {
+            work_dir: work_dir.to_path_buf(),
+            options: FsckOptions {
+                compressed: false,
+                dry_run: true,
+            },
+            blobs,
+        };
+
+        let report = fsck.check().unwrap();
+        assert_eq!(report.blobs, 3);
+        assert_eq!(report.blobs_checked, 1);
+        assert_eq!(report.chunks_checked, 2);
+        let problems = report
+            .problems
+            .iter()
+            .map(|p| {
+                (
+                    p.path.strip_prefix(work_dir.to_str().unwrap()).unwrap(),
+                    p.action.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                ("/blob-1", "marked not ready"),
+                ("/blob-1.123.0.hot", "removed"),
+                ("/blob-2.chunk_map", "removed with blob cache file"),
+                ("/blob-3.chunk_map", "removed"),
+            ]
+        );
+        assert!(work_dir.join("blob-1.123.0.hot").exists());
+
+        // Nothing is done while nydusd uses a blob cache file.
+        let used = File::open(&blob_path).unwrap();
+        flock(used.as_raw_fd(), FlockArg::LockShared).unwrap();
+        assert!(fsck.check().is_err());
+        drop(used);
+
+        fsck.options.dry_run = false;
+        let report = fsck.check().unwrap();
+        assert_eq!(report.problems.len(), 4);
+        let mut names = fs::read_dir(work_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "blob-1",
+                "blob-1.chunk_map",
+                "blob-4.1.2.hot",
+                "blob-4.1.2.hot.chunk_map"
+            ]
+        );
+        assert_eq!(IndexedChunkMap::ready_chunks(blob).unwrap(), vec![0]);
+
+        let report = fsck.check().unwrap();
+        assert!(report.problems.is_empty());
+        assert_eq!(report.chunks_checked, 1);
+    }
+
+    #[test]
+    fn test_is_hot_file() {
+        assert!(is_hot_file("blob-1.123.0.hot"));
+        assert!(is_hot_file("a.b.1.2.hot"));
+        assert!(!is_hot_file("blob-1.hot"));
+        assert!(!is_hot_file(".1.2.hot"));
+        assert!(!is_hot_file("blob-1.x.2.hot"));
+        assert!(!is_hot_file("blob-1.1.2.hot.chunk_map"));
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Generate a prefetch list from files read by runs of a container.
+//!
+//! Each run is given by the access pattern exported from nydusd, either as JSON or as a
+//! prefetch list, or by an access trace. Files are ordered by how early they're first read in
+//! a run relative to all files it reads, averaged over the runs reading them, so that files
+//! read early by every run come first. A file is listed once no matter how many runs read it.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use rafs::metadata::RafsSuper;
+use rafs::trace::TraceReader;
+
+/// Files read in a run, in the order they're first read.
+type Run = Vec<PathBuf>;
+
+/// An entry of the access pattern exported by nydusd as JSON.
+#[derive(Deserialize)]
+struct AccessPattern {
+    file_path: PathBuf,
+    /// In unit of seconds.
+    #[serde(default)]
+    first_access_time: u64,
+}
+
+pub struct GenOptions {
+    /// Files read by fewer runs are left out, e.g. those only read by chance.
+    pub min_runs: usize,
+    /// Files not in the image of this bootstrap are left out.
+    pub bootstrap: Option<PathBuf>,
+}
+
+/// The prefetch list of runs recorded in files `patterns`.
+pub fn generate(patterns: &[PathBuf], options: &GenOptions) -> Result<Vec<PathBuf>> {
+    let mut runs = Vec::with_capacity(patterns.len());
+    for path in patterns {
+        let data = fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+        let run = parse_run(&data).with_context(|| format!("invalid access pattern {:?}", path))?;
+        info!("{} files read in run {:?}", run.len(), path);
+        runs.push(run);
+    }
+
+    let mut files = merge(&runs, options.min_runs);
+    if let Some(bootstrap) = &options.bootstrap {
+        let rs = crate::inspect::load_super(bootstrap)?;
+        files = in_image(&rs, files);
+    }
+
+    Ok(files)
+}
+
+/// Write `files` line by line like the prefetch list given to `create`, or as a JSON array
+/// like `prefetch_files` of the mount API with `json`.
+pub fn print<W: Write>(files: &[PathBuf], json: bool, mut w: W) -> Result<()> {
+    if json {
+        serde_json::to_writer(&mut w, files)?;
+        writeln!(w)?;
+    } else {
+        for f in files {
+            writeln!(w, "{}", f.display())?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Parse a run from an access trace, a JSON access pattern or a prefetch list.
+fn parse_run(data: &[u8]) -> Result<Run> {
+    let mut paths = Vec::new();
+    if data.starts_with(b"NYDUSTRC") {
+        for read in TraceReader::new(data)? {
+            paths.push(read?.path);
+        }
+    } else if data.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'[') {
+        let mut patterns: Vec<AccessPattern> = serde_json::from_slice(data)?;
+        // Exported in the order of first access by mount, or in no order by id.
+        patterns.sort_by_key(|p| p.first_access_time);
+        paths.extend(patterns.into_iter().map(|p| p.file_path));
+    } else {
+        let list = std::str::from_utf8(data).context("invalid prefetch list")?;
+        for line in list.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if !line.starts_with('/') {
+                warn!("prefetch path {:?} must start with '/', skipped", line);
+                continue;
+            }
+            paths.push(PathBuf::from(line));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    paths.retain(|p| seen.insert(p.clone()));
+    Ok(paths)
+}
+
+fn merge(runs: &[Run], min_runs: usize) -> Vec<PathBuf> {
+    // Sum of positions of a file relative to lengths of runs, and the number of runs.
+    let mut files: HashMap<&Path, (f64, usize)> = HashMap::new();
+    for run in runs {
+        for (idx, path) in run.iter().enumerate() {
+            let file = files.entry(path.as_path()).or_insert((0.0, 0));
+            file.0 += idx as f64 / run.len() as f64;
+            file.1 += 1;
+        }
+    }
+
+    let mut files = files
+        .into_iter()
+        .filter(|(_, (_, count))| *count >= min_runs)
+        .map(|(path, (pos, count))| (pos / count as f64, path))
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| {
+        a.0.partial_cmp(&b.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.1.cmp(b.1))
+    });
+
+    files.into_iter().map(|(_, p)| p.to_path_buf()).collect()
+}
+
+/// Files of `files` found in the image.
+fn in_image(rs: &RafsSuper, files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|f| {
+            let found = rs.ino_from_path(f).is_ok();
+            if !found {
+                warn!("{:?} not found in image, skipped", f);
+            }
+            found
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn test_parse_run() {
+        let json = br#"[{"file_path":"/bin/sh","nr_read":1,"nr_bytes":10,"first_access_time":20},
+            {"file_path":"/etc/hosts","nr_read":2,"nr_bytes":10,"first_access_time":10}]"#;
+        assert_eq!(parse_run(json).unwrap(), paths(&["/etc/hosts", "/bin/sh"]));
+
+        let list = b"/bin/sh\n\n/etc/hosts\nrelative\n/bin/sh\n";
+        assert_eq!(parse_run(list).unwrap(), paths(&["/bin/sh", "/etc/hosts"]));
+
+        let mut trace = b"NYDUSTRC".to_vec();
+        trace.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        for (file, path) in &[(0u32, "/lib/libc.so"), (1u32, "/bin/sh")] {
+            trace.push(1);
+            trace.extend_from_slice(&file.to_le_bytes());
+            trace.extend_from_slice(&(path.len() as u16).to_le_bytes());
+            trace.extend_from_slice(path.as_bytes());
+        }
+        for file in &[1u32, 0, 1] {
+            trace.push(2);
+            trace.extend_from_slice(&file.to_le_bytes());
+            trace.extend_from_slice(&[0u8; 20]);
+        }
+        assert_eq!(
+            parse_run(&trace).unwrap(),
+            paths(&["/bin/sh", "/lib/libc.so"])
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let runs = vec![
+            paths(&["/bin/sh", "/lib/libc.so", "/etc/hosts", "/tmp/x"]),
+            paths(&["/lib/libc.so", "/bin/sh", "/etc/hosts"]),
+            paths(&["/bin/sh", "/etc/hosts"]),
+        ];
+        assert_eq!(
+            merge(&runs, 1),
+            paths(&["/bin/sh", "/lib/libc.so", "/etc/hosts", "/tmp/x"])
+        );
+        assert_eq!(merge(&runs, 3), paths(&["/bin/sh", "/etc/hosts"]));
+
+        let mut out = Vec::new();
+        print(&merge(&runs, 3), true, &mut out).unwrap();
+        assert_eq!(out, b"[\"/bin/sh\",\"/etc/hosts\"]\n");
+    }
+}
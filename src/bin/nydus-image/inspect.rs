@@ -0,0 +1,171 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! List directories and read files of a RAFS image without mounting it.
+//!
+//! Listing only needs the bootstrap, while reading a file fetches its chunks from a storage
+//! backend, the same way `unpack` does.
+
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use rafs::metadata::{RafsInode, RafsMode, RafsSuper};
+use rafs::RafsIoRead;
+use storage::factory::BackendConfig;
+
+use crate::unpack::{open_image, read_piece};
+
+/// Size of pieces a file is read and written in.
+const READ_BUFFER_SIZE: u64 = 0x10_0000;
+
+/// Load the bootstrap at `path` alone, for tools which don't read file data.
+pub fn load_super(path: &Path) -> Result<RafsSuper> {
+    let mut f: Box<dyn RafsIoRead> =
+        Box::new(File::open(path).with_context(|| format!("failed to open bootstrap {:?}", path))?);
+    let mut rs = RafsSuper {
+        mode: RafsMode::Direct,
+        digest_validate: false,
+        ..Default::default()
+    };
+    rs.load(&mut f)
+        .with_context(|| format!("failed to load bootstrap {:?}", path))?;
+    Ok(rs)
+}
+
+/// Permission string of `mode` like `ls -l`, e.g. `drwxr-xr-x`.
+fn mode_string(mode: u32) -> String {
+    let kind = match mode & libc::S_IFMT {
+        libc::S_IFDIR => b'd',
+        libc::S_IFLNK => b'l',
+        libc::S_IFCHR => b'c',
+        libc::S_IFBLK => b'b',
+        libc::S_IFIFO => b'p',
+        libc::S_IFSOCK => b's',
+        _ => b'-',
+    };
+    let mut s = vec![kind];
+    for (i, c) in b"rwxrwxrwx".iter().enumerate() {
+        s.push(if mode & (0o400 >> i) != 0 { *c } else { b'-' });
+    }
+    // Setuid, setgid and sticky bits replace execute bits.
+    for (bit, idx, set, unset) in &[
+        (libc::S_ISUID, 3, b's', b'S'),
+        (libc::S_ISGID, 6, b's', b'S'),
+        (libc::S_ISVTX, 9, b't', b'T'),
+    ] {
+        if mode & bit != 0 {
+            s[*idx] = if s[*idx] == b'x' { *set } else { *unset };
+        }
+    }
+    // Safe to unwrap because it's all ascii.
+    String::from_utf8(s).unwrap()
+}
+
+/// A line of `ls`, just the name or in the long format of `ls -ln`.
+fn list_line(inode: &dyn RafsInode, name: &str, long: bool) -> Result<String> {
+    if !long {
+        return Ok(name.to_string());
+    }
+    let attr = inode.get_attr();
+    let mut line = format!(
+        "{} {:>5} {:>5} {:>10} {}",
+        mode_string(attr.mode),
+        attr.uid,
+        attr.gid,
+        attr.size,
+        name
+    );
+    if inode.is_symlink() {
+        line.push_str(" -> ");
+        line.push_str(&inode.get_symlink()?.to_string_lossy());
+    }
+    Ok(line)
+}
+
+/// List children of the directory at `path` of the image, or the file itself, sorted by name.
+pub fn list<W: Write>(bootstrap: &Path, path: &Path, long: bool, mut w: W) -> Result<()> {
+    let rs = load_super(bootstrap)?;
+    let ino = rs
+        .ino_from_path(path)
+        .with_context(|| format!("{:?} not found in image", path))?;
+    let inode = rs.get_inode(ino, false)?;
+
+    if !inode.is_dir() {
+        let line = list_line(inode.as_ref(), &path.to_string_lossy(), long)?;
+        writeln!(w, "{}", line)?;
+        w.flush()?;
+        return Ok(());
+    }
+    for idx in 0..inode.get_child_count() {
+        let child = inode.get_child_by_index(idx as u64)?;
+        let line = list_line(child.as_ref(), &child.name().to_string_lossy(), long)?;
+        writeln!(w, "{}", line)?;
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+/// Write content of the regular file at `path` of the image to `w`, with chunks validated
+/// against their digests.
+pub fn cat<W: Write>(
+    bootstrap: &Path,
+    backend: &BackendConfig,
+    path: &Path,
+    mut w: W,
+) -> Result<()> {
+    let reader = open_image(bootstrap, backend, "cat", true)?;
+    let inode = reader
+        .lookup(path)
+        .with_context(|| format!("{:?} not found in image", path))?;
+    if !inode.is_reg() {
+        bail!("{:?} is not a regular file", path);
+    }
+
+    let mut offset = 0;
+    while offset < inode.size() {
+        let len = std::cmp::min(READ_BUFFER_SIZE, inode.size() - offset);
+        let data = read_piece(&reader, inode.as_ref(), offset, len)
+            .with_context(|| format!("failed to read {:?} at {}", path, offset))?;
+        match w.write_all(&data) {
+            Ok(_) => {}
+            // The reader of a pipe, e.g. `head`, has seen enough.
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        offset += len;
+    }
+    match w.flush() {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!(mode_string(libc::S_IFDIR | 0o755), "drwxr-xr-x");
+        assert_eq!(mode_string(libc::S_IFREG | 0o640), "-rw-r-----");
+        assert_eq!(mode_string(libc::S_IFLNK | 0o777), "lrwxrwxrwx");
+        assert_eq!(
+            mode_string(libc::S_IFREG | libc::S_ISUID | 0o755),
+            "-rwsr-xr-x"
+        );
+        assert_eq!(
+            mode_string(libc::S_IFDIR | libc::S_ISVTX | 0o777),
+            "drwxrwxrwt"
+        );
+        assert_eq!(
+            mode_string(libc::S_IFREG | libc::S_ISGID | 0o644),
+            "-rw-r-Sr--"
+        );
+        assert_eq!(mode_string(libc::S_IFCHR | 0o600), "crw-------");
+    }
+}
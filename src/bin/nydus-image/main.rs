@@ -12,9 +12,23 @@ extern crate nydus_utils;
 #[macro_use]
 mod trace;
 
+mod bench;
 mod builder;
 mod core;
+mod dedup;
+mod diff;
+mod du;
+mod export;
+mod fsck;
+mod gen_prefetch;
+mod inspect;
+mod mount;
+mod sign;
+mod stat;
+mod unpack;
+mod upgrade;
 mod validator;
+mod verifier;
 
 #[macro_use]
 extern crate log;
@@ -25,12 +39,12 @@ extern crate lazy_static;
 const BLOB_ID_MAXIMUM_LENGTH: usize = 1024;
 
 use anyhow::{bail, Context, Result};
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgGroup, SubCommand};
 
 use std::collections::HashMap;
 use std::fs::metadata;
-use std::fs::OpenOptions;
-use std::io::{self, BufWriter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter};
 use std::path::{Path, PathBuf};
 
 use nix::unistd::{getegid, geteuid};
@@ -38,6 +52,7 @@ use serde::Serialize;
 
 use crate::builder::directory::DirectoryBuilder;
 use crate::builder::stargz::StargzBuilder;
+use crate::builder::tar::TarBuilder;
 use crate::builder::Builder;
 
 use crate::core::blob::BlobStorage;
@@ -45,15 +60,21 @@ use crate::core::context::BuildContext;
 use crate::core::context::SourceType;
 use crate::core::context::BUF_WRITER_CAPACITY;
 use crate::core::node::{self, ChunkCountMap, WhiteoutSpec};
-use crate::core::prefetch::Prefetch;
+use crate::core::prefetch::{Prefetch, PrefetchPolicy};
 use crate::core::tree;
 
 use nydus_utils::{digest, setup_logging, BuildTimeInfo};
+use rafs::fs::RafsConfig;
 use rafs::metadata::layout::OndiskBlobTable;
 use rafs::RafsIoRead;
+use storage::cache::chunkmap::indexed::IndexedChunkMap;
 use storage::compress;
+use storage::crypt::{KmsClient, KmsConfig};
+use storage::factory::BackendConfig;
 use trace::{EventTracerClass, TimingTracerClass, TraceClass};
+use unpack::Unpacker;
 use validator::Validator;
+use verifier::Verifier;
 
 #[derive(Serialize, Default)]
 pub struct ResultOutput {
@@ -95,6 +116,33 @@ fn dump_result_output(matches: &clap::ArgMatches, blob_ids: Vec<String>) -> Resu
     Ok(())
 }
 
+/// Storage backend to read blobs from, given by `--blob-dir` or `--backend-type` with its
+/// config.
+fn backend_from_args(matches: &clap::ArgMatches) -> Result<BackendConfig> {
+    if let Some(dir) = matches.value_of("blob-dir") {
+        return Ok(unpack::blob_dir_backend(Path::new(dir)));
+    }
+    // Safe to unwrap because blob dir is required unless backend type is given, which
+    // requires either backend config or its file.
+    let backend_type = matches.value_of("backend-type").unwrap();
+    match matches.value_of("backend-config") {
+        Some(config) => BackendConfig::from_str(backend_type, config),
+        None => BackendConfig::from_file(
+            backend_type,
+            matches.value_of("backend-config-file").unwrap(),
+        ),
+    }
+}
+
+/// Number of worker threads given by `--thread-num`, the number of CPUs by default.
+fn threads_from_args(matches: &clap::ArgMatches) -> usize {
+    // Safe to unwrap because it's validated.
+    matches
+        .value_of("thread-num")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or_else(unpack::online_cpus)
+}
+
 fn main() -> Result<()> {
     let (bti_string, _) = BuildTimeInfo::dump(crate_version!());
 
@@ -108,141 +156,885 @@ fn main() -> Result<()> {
                 .about("dump image bootstrap and upload blob to storage backend")
                 .arg(
                     Arg::with_name("SOURCE")
-                        .help("source path")
+                        .help("source path, or \"-\" to read a tar archive from stdin")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("source-type")
+                        .long("source-type")
+                        .help("source type")
+                        .takes_value(true)
+                        .default_value("directory")
+                        .possible_values(&["directory", "stargz_index", "tar"])
+                )
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .required(true)
+                        .help("A path to bootstrap file which stores nydus image metadata portion")
+                        .takes_value(true),
+                ).arg(
+                    Arg::with_name("blob")
+                        .long("blob")
+                        .help("A path to blob file which stores nydus image data portion")
+                        .required_unless("backend-type")
+                        .required_unless("source-type")
+                        .required_unless("blob-dir")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("blob-id")
+                        .long("blob-id")
+                        .help("blob id (as object id in backend/oss)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compressor")
+                        .long("compressor")
+                        .help("how blob will be compressed: none, lz4_block (default)")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("lz4_block"),
+                )
+                .arg(
+                    Arg::with_name("digester")
+                        .long("digester")
+                        .help("how inode and blob chunk will be digested: blake3 (default), sha256")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("blake3"),
+                )
+                .arg(
+                    Arg::with_name("parent-bootstrap")
+                        .long("parent-bootstrap")
+                        .help("bootstrap file path of parent (optional)")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("append")
+                        .long("append")
+                        .help("Append data of a tar source to the blob of the last layer in parent bootstrap, given by --blob, instead of writing a new blob")
+                        .takes_value(false)
+                        .requires("parent-bootstrap")
+                        .requires("blob"),
+                )
+                .arg(
+                    Arg::with_name("prefetch-policy")
+                        .long("prefetch-policy")
+                        .help("Prefetch policy: fs(issued from Fs layer), blob(issued from backend/blob layer), none(no readahead is needed)")
+                        .takes_value(true)
+                        .required(false)
+                        .default_value("none"),
+                )
+                .arg(
+                    Arg::with_name("repeatable")
+                    .long("repeatable")
+                    .help("Produce environment independent image")
+                    .takes_value(false)
+                    .required(false),
+                )
+                .arg(
+                    Arg::with_name("inode-mtime")
+                    .long("inode-mtime")
+                    .help("Save mtime and ctime of files in inodes, images built with it can't be mounted by nydusd without mtime support")
+                    .takes_value(false)
+                    .required(false)
+                    .conflicts_with("repeatable"),
+                )
+                .arg(
+                    Arg::with_name("disable-check")
+                    .long("disable-check")
+                    .help("Disable to validate bootstrap file after building")
+                    .takes_value(false)
+                    .required(false)
+                )
+                .arg(
+                    Arg::with_name("whiteout-spec")
+                    .long("whiteout-spec")
+                    .help("decide which whiteout spec to follow: \"oci\" or \"overlayfs\"")
+                    .takes_value(true)
+                    .required(true)
+                    .possible_values(&["oci", "overlayfs"])
+                    .default_value("oci")
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for build result")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("aligned-chunk")
+                        .long("aligned-chunk")
+                        .help("Whether to align chunks into blobcache")
+                        .takes_value(false)
+                )
+                .arg(
+                    Arg::with_name("encryption-config")
+                        .long("encryption-config")
+                        .help("A path to KMS config file in JSON, to encrypt blob with the latest version of the key kept in KMS")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("blob-dir")
+                        .long("blob-dir")
+                        .help("A directory where blob files are saved named as their sha256 digest. It's very useful when multiple layers are built at the same time.")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("backend-type")
+                        .long("backend-type")
+                        .help("[deprecated!] Blob storage backend type, only support localfs for compatibility. Try use --blob instead.")
+                        .takes_value(true)
+                        .requires("backend-config")
+                        .possible_values(&["localfs"]),
+                )
+                .arg(
+                    Arg::with_name("backend-config")
+                        .long("backend-config")
+                        .help("[deprecated!] Blob storage backend config - JSON string, only support localfs for compatibility")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("validate image bootstrap")
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .help("bootstrap file path (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for check result")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("benchmark reading image with a rafs configuration, reporting latencies of reads and statistics of backend and cache")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .short("C")
+                        .help("rafs configuration file to read the image with, the same as nydusd's")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace")
+                        .long("trace")
+                        .help("replay reads of an access trace recorded by nydusd, rather than a synthetic workload")
+                        .takes_value(true)
+                        .conflicts_with("workload"),
+                )
+                .arg(
+                    Arg::with_name("workload")
+                        .long("workload")
+                        .help("synthetic workload, reading all files through or random blocks of random files, sequential by default")
+                        .takes_value(true)
+                        .possible_values(&["sequential", "random"]),
+                )
+                .arg(
+                    Arg::with_name("reads")
+                        .long("reads")
+                        .help("number of reads of random workload")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .validator(|v| {
+                            v.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| "reads must be a non-negative integer".to_string())
+                        }),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .help("seed of random workload, the same seed gives the same reads")
+                        .takes_value(true)
+                        .default_value("0")
+                        .validator(|v| {
+                            v.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| "seed must be a non-negative integer".to_string())
+                        }),
+                )
+                .arg(
+                    Arg::with_name("block-size")
+                        .long("block-size")
+                        .help("size of reads of synthetic workloads")
+                        .takes_value(true)
+                        .default_value("131072")
+                        .validator(|v| match v.parse::<u32>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("block size must be a positive integer".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("rounds")
+                        .long("rounds")
+                        .help("times to send the reads, the first round warms up cache for later ones")
+                        .takes_value(true)
+                        .default_value("2")
+                        .validator(|v| match v.parse::<u32>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("rounds must be a positive integer".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("thread-num")
+                        .long("thread-num")
+                        .help("number of threads sending reads concurrently, the number of CPUs by default")
+                        .takes_value(true)
+                        .validator(|v| match v.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("thread number must be a positive integer".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for bench result")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("dedup")
+                .about("count chunks shared between images and unique to each, by chunk digest")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file paths of the images")
+                        .required(true)
+                        .multiple(true)
+                        .min_values(2)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for dedup result")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("list files and chunks changed between two images, and the size to download for the update")
+                .arg(
+                    Arg::with_name("OLD_BOOTSTRAP")
+                        .help("bootstrap file path of the old image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("NEW_BOOTSTRAP")
+                        .help("bootstrap file path of the new image")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for diff result")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("du")
+                .about("report compressed and uncompressed sizes of directories of image, counting each chunk once")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .help("report files as well as directories")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("max-depth")
+                        .short("d")
+                        .long("max-depth")
+                        .help("report entries at most this deep, the root is of depth 0")
+                        .takes_value(true)
+                        .validator(|v| {
+                            v.parse::<usize>()
+                                .map(|_| ())
+                                .map_err(|_| "max depth must be a non-negative integer".to_string())
+                        }),
+                )
+                .arg(
+                    Arg::with_name("sort")
+                        .long("sort")
+                        .help("sort by compressed size, largest first")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for du result")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("export image into gzip compressed tar layers, reading data from blobs")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("format of layers to export")
+                        .takes_value(true)
+                        .default_value("oci")
+                        .possible_values(&["oci"]),
+                )
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .help("bootstrap file path of each layer, from the lowest one, i.e. the first built (required)")
+                        .required(true)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("blob-dir")
+                        .long("blob-dir")
+                        .help("A directory where blob files of the image are saved named as their sha256 digest")
+                        .required_unless("backend-type")
+                        .conflicts_with("backend-type")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("backend-type")
+                        .long("backend-type")
+                        .help("Storage backend to fetch blobs from during exporting")
+                        .takes_value(true)
+                        .requires("backend-config-source")
+                        .possible_values(&["localfs", "oss", "registry"]),
+                )
+                .arg(
+                    Arg::with_name("backend-config")
+                        .long("backend-config")
+                        .help("Storage backend config - JSON string, the same as `device.backend.config` of nydusd")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .arg(
+                    Arg::with_name("backend-config-file")
+                        .long("backend-config-file")
+                        .help("Storage backend config file, to keep credentials out of the command line")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .group(
+                    ArgGroup::with_name("backend-config-source")
+                        .args(&["backend-config", "backend-config-file"]),
+                )
+                .arg(
+                    Arg::with_name("output-dir")
+                        .long("output-dir")
+                        .help("directory to write layers to, as layer-<n>.tar.gz (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("thread-num")
+                        .long("thread-num")
+                        .help("number of threads reading and decompressing file data, the number of CPUs by default")
+                        .takes_value(true)
+                        .validator(|v| match v.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("thread number must be a positive integer".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for layers exported, with their digests and sizes")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("fsck-cache")
+                .about("check blobcache work dir of nydusd not running, removing broken files and marking bad chunks not ready")
+                .arg(
+                    Arg::with_name("work-dir")
+                        .long("work-dir")
+                        .help("blobcache work dir, `work_dir` of the cache config (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .help("bootstrap of an image using the cache, to check chunks cached against their digests, given once for each image")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("compressed")
+                        .long("compressed")
+                        .help("chunks are cached compressed, as `cache_compressed` of the cache config")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("only report problems, changing nothing")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for fsck result")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("export-chunk-map")
+                .about("export which chunks are cached in a blob cache file, to seed a copy of it on another node")
+                .arg(
+                    Arg::with_name("BLOB_CACHE")
+                        .help("blob cache file path, with its chunk map file next to it")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("O")
+                        .help("path of the exported chunk map (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import-chunk-map")
+                .about("import chunk map exported by `export-chunk-map` for a copied blob cache file")
+                .arg(
+                    Arg::with_name("BLOB_CACHE")
+                        .help("path of the copied blob cache file")
                         .required(true)
                         .index(1),
                 )
                 .arg(
-                    Arg::with_name("source-type")
-                        .long("source-type")
-                        .help("source type")
+                    Arg::with_name("input")
+                        .long("input")
+                        .short("I")
+                        .help("path of the exported chunk map (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("gen-prefetch")
+                .about("generate prefetch list from files read by runs of a container, in the order they're read")
+                .arg(
+                    Arg::with_name("ACCESS_PATTERN")
+                        .help("files read by a run, as access pattern exported from nydusd in JSON or as prefetch list, or as access trace")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .help("leave out files not in image of this bootstrap")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("min-runs")
+                        .long("min-runs")
+                        .help("leave out files read by fewer runs")
+                        .default_value("1")
+                        .takes_value(true)
+                        .validator(|v| match v.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("min runs must be a positive integer".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("write paths line by line for `create --prefetch-policy fs`, or as JSON array for the mount API")
+                        .default_value("list")
+                        .possible_values(&["list", "json"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("O")
+                        .help("path of prefetch list to write, stdout by default")
+                        .takes_value(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("list a directory or a file of image, without mounting it")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("absolute path within the image")
+                        .default_value("/")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("long")
+                        .short("l")
+                        .long("long")
+                        .help("list mode, owner and size like `ls -ln`")
+                        .takes_value(false),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("write content of a regular file of image to stdout, reading data from blobs without mounting it")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("absolute path within the image")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("blob-dir")
+                        .long("blob-dir")
+                        .help("A directory where blob files of the image are saved named as their sha256 digest")
+                        .required_unless("backend-type")
+                        .conflicts_with("backend-type")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("backend-type")
+                        .long("backend-type")
+                        .help("Storage backend to fetch blobs from")
+                        .takes_value(true)
+                        .requires("backend-config-source")
+                        .possible_values(&["localfs", "oss", "registry"]),
+                )
+                .arg(
+                    Arg::with_name("backend-config")
+                        .long("backend-config")
+                        .help("Storage backend config - JSON string, the same as `device.backend.config` of nydusd")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .arg(
+                    Arg::with_name("backend-config-file")
+                        .long("backend-config-file")
+                        .help("Storage backend config file, to keep credentials out of the command line")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .group(
+                    ArgGroup::with_name("backend-config-source")
+                        .args(&["backend-config", "backend-config-file"]),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("mount image in the foreground by nydusd for a quick look, with no config file needed")
+                .arg(
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .help("bootstrap file path (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("MOUNTPOINT")
+                        .help("directory to mount image at, created if missing")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("blob-dir")
+                        .long("blob-dir")
+                        .help("A directory where blob files of the image are saved named as their sha256 digest")
+                        .required_unless("backend-type")
+                        .conflicts_with("backend-type")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("backend-type")
+                        .long("backend-type")
+                        .help("Storage backend to fetch blobs from")
+                        .takes_value(true)
+                        .requires("backend-config-source")
+                        .possible_values(&["localfs", "oss", "registry"]),
+                )
+                .arg(
+                    Arg::with_name("backend-config")
+                        .long("backend-config")
+                        .help("Storage backend config - JSON string, the same as `device.backend.config` of nydusd")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .arg(
+                    Arg::with_name("backend-config-file")
+                        .long("backend-config-file")
+                        .help("Storage backend config file, to keep credentials out of the command line")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .group(
+                    ArgGroup::with_name("backend-config-source")
+                        .args(&["backend-config", "backend-config-file"]),
+                )
+                .arg(
+                    Arg::with_name("nydusd")
+                        .long("nydusd")
+                        .help("path of nydusd, the one next to nydus-image or in PATH by default")
+                        .takes_value(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("sign bootstrap with a private key, for nydusd to refuse images not signed")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .help("PEM private key to sign with, an RSA, EC or Ed25519 key (required)")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("O")
+                        .help("path of the signature to write, <BOOTSTRAP>.sig by default")
+                        .takes_value(true),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("stat")
+                .about("report sizes and dedup of image compared with the OCI layers it's converted from, and what lazy pulling saves")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image, the top layer for a layered image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .help("OCI layer the image is converted from, a tar archive gzip compressed or not, given once for each layer")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("prefetch-list")
+                        .long("prefetch-list")
+                        .help("file of paths prefetched at startup, one absolute path per line like the list given to `create`")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for stat report")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade-bootstrap")
+                .about("rewrite bootstrap in the latest layout in place, reusing its blobs")
+                .arg(
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("O")
+                        .help("write the upgraded bootstrap to this path rather than in place")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for upgrade result")
                         .takes_value(true)
-                        .default_value("directory")
-                        .possible_values(&["directory", "stargz_index"])
                 )
+        )
+        .subcommand(
+            SubCommand::with_name("unpack")
+                .about("unpack image into a tar archive, reading data from blobs in a directory")
                 .arg(
                     Arg::with_name("bootstrap")
                         .long("bootstrap")
+                        .help("bootstrap file path (required)")
                         .required(true)
-                        .help("A path to bootstrap file which stores nydus image metadata portion")
                         .takes_value(true),
-                ).arg(
-                    Arg::with_name("blob")
-                        .long("blob")
-                        .help("A path to blob file which stores nydus image data portion")
-                        .required_unless("backend-type")
-                        .required_unless("source-type")
-                        .required_unless("blob-dir")
-                        .takes_value(true)
                 )
                 .arg(
-                    Arg::with_name("blob-id")
-                        .long("blob-id")
-                        .help("blob id (as object id in backend/oss)")
+                    Arg::with_name("blob-dir")
+                        .long("blob-dir")
+                        .help("A directory where blob files of the image are saved named as their sha256 digest")
+                        .required_unless("backend-type")
+                        .conflicts_with("backend-type")
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("compressor")
-                        .long("compressor")
-                        .help("how blob will be compressed: none, lz4_block (default)")
-                        .takes_value(true)
-                        .required(false)
-                        .default_value("lz4_block"),
-                )
-                .arg(
-                    Arg::with_name("digester")
-                        .long("digester")
-                        .help("how inode and blob chunk will be digested: blake3 (default), sha256")
+                    Arg::with_name("backend-type")
+                        .long("backend-type")
+                        .help("Storage backend to fetch blobs from during unpacking")
                         .takes_value(true)
-                        .required(false)
-                        .default_value("blake3"),
+                        .requires("backend-config-source")
+                        .possible_values(&["localfs", "oss", "registry"]),
                 )
                 .arg(
-                    Arg::with_name("parent-bootstrap")
-                        .long("parent-bootstrap")
-                        .help("bootstrap file path of parent (optional)")
+                    Arg::with_name("backend-config")
+                        .long("backend-config")
+                        .help("Storage backend config - JSON string, the same as `device.backend.config` of nydusd")
                         .takes_value(true)
-                        .required(false),
+                        .requires("backend-type"),
                 )
                 .arg(
-                    Arg::with_name("prefetch-policy")
-                        .long("prefetch-policy")
-                        .help("Prefetch policy: fs(issued from Fs layer), blob(issued from backend/blob layer), none(no readahead is needed)")
+                    Arg::with_name("backend-config-file")
+                        .long("backend-config-file")
+                        .help("Storage backend config file, to keep credentials out of the command line")
                         .takes_value(true)
-                        .required(false)
-                        .default_value("none"),
-                )
-                .arg(
-                    Arg::with_name("repeatable")
-                    .long("repeatable")
-                    .help("Produce environment independent image")
-                    .takes_value(false)
-                    .required(false),
+                        .requires("backend-type"),
                 )
-                .arg(
-                    Arg::with_name("disable-check")
-                    .long("disable-check")
-                    .help("Disable to validate bootstrap file after building")
-                    .takes_value(false)
-                    .required(false)
+                .group(
+                    ArgGroup::with_name("backend-config-source")
+                        .args(&["backend-config", "backend-config-file"]),
                 )
                 .arg(
-                    Arg::with_name("whiteout-spec")
-                    .long("whiteout-spec")
-                    .help("decide which whiteout spec to follow: \"oci\" or \"overlayfs\"")
-                    .takes_value(true)
-                    .required(true)
-                    .possible_values(&["oci", "overlayfs"])
-                    .default_value("oci")
+                    Arg::with_name("output")
+                        .long("output")
+                        .help("path of the tar archive to write, or \"-\" to stream it to stdout (required)")
+                        .required(true)
+                        .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("output-json")
-                        .long("output-json")
-                        .help("JSON output path for build result")
+                    Arg::with_name("thread-num")
+                        .long("thread-num")
+                        .help("number of threads reading and decompressing file data, the number of CPUs by default")
                         .takes_value(true)
+                        .validator(|v| match v.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("thread number must be a positive integer".to_string()),
+                        }),
                 )
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("verify chunks and files of image against digests in bootstrap, reading data from blobs")
                 .arg(
-                    Arg::with_name("aligned-chunk")
-                        .long("aligned-chunk")
-                        .help("Whether to align chunks into blobcache")
-                        .takes_value(false)
+                    Arg::with_name("bootstrap")
+                        .long("bootstrap")
+                        .help("bootstrap file path (required)")
+                        .required(true)
+                        .takes_value(true),
                 )
                 .arg(
                     Arg::with_name("blob-dir")
                         .long("blob-dir")
-                        .help("A directory where blob files are saved named as their sha256 digest. It's very useful when multiple layers are built at the same time.")
-                        .takes_value(true)
+                        .help("A directory where blob files of the image are saved named as their sha256 digest")
+                        .required_unless("backend-type")
+                        .conflicts_with("backend-type")
+                        .takes_value(true),
                 )
                 .arg(
                     Arg::with_name("backend-type")
                         .long("backend-type")
-                        .help("[deprecated!] Blob storage backend type, only support localfs for compatibility. Try use --blob instead.")
+                        .help("Storage backend to fetch blobs from during verifying")
                         .takes_value(true)
-                        .requires("backend-config")
-                        .possible_values(&["localfs"]),
+                        .requires("backend-config-source")
+                        .possible_values(&["localfs", "oss", "registry"]),
                 )
                 .arg(
                     Arg::with_name("backend-config")
                         .long("backend-config")
-                        .help("[deprecated!] Blob storage backend config - JSON string, only support localfs for compatibility")
+                        .help("Storage backend config - JSON string, the same as `device.backend.config` of nydusd")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .arg(
+                    Arg::with_name("backend-config-file")
+                        .long("backend-config-file")
+                        .help("Storage backend config file, to keep credentials out of the command line")
+                        .takes_value(true)
+                        .requires("backend-type"),
+                )
+                .group(
+                    ArgGroup::with_name("backend-config-source")
+                        .args(&["backend-config", "backend-config-file"]),
+                )
+                .arg(
+                    Arg::with_name("thread-num")
+                        .long("thread-num")
+                        .help("number of threads reading and hashing chunks, the number of CPUs by default")
+                        .takes_value(true)
+                        .validator(|v| match v.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("thread number must be a positive integer".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("output-json")
+                        .long("output-json")
+                        .help("JSON output path for verify report, which is also printed to stdout")
                         .takes_value(true)
                 )
         )
         .subcommand(
-            SubCommand::with_name("check")
-                .about("validate image bootstrap")
+            SubCommand::with_name("verify-sig")
+                .about("verify signature of bootstrap made by `sign`")
                 .arg(
-                    Arg::with_name("bootstrap")
-                        .long("bootstrap")
-                        .help("bootstrap file path (required)")
+                    Arg::with_name("BOOTSTRAP")
+                        .help("bootstrap file path of the image")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("public-key")
+                        .long("public-key")
+                        .help("PEM public key or X.509 certificate of the signer (required)")
                         .required(true)
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("output-json")
-                        .long("output-json")
-                        .help("JSON output path for check result")
-                        .takes_value(true)
+                    Arg::with_name("signature")
+                        .long("signature")
+                        .help("path of the signature, <BOOTSTRAP>.sig by default")
+                        .takes_value(true),
                 )
         )
         .arg(
@@ -269,8 +1061,15 @@ fn main() -> Result<()> {
         let source_path = PathBuf::from(matches.value_of("SOURCE").unwrap());
         let source_type: SourceType = matches.value_of("source-type").unwrap().parse()?;
 
-        let source_file = metadata(&source_path)
-            .context(format!("failed to get source path {:?}", source_path))?;
+        let from_stdin = source_type == SourceType::Tar && source_path == Path::new("-");
+        let source_file = if from_stdin {
+            None
+        } else {
+            Some(
+                metadata(&source_path)
+                    .context(format!("failed to get source path {:?}", source_path))?,
+            )
+        };
 
         let mut blob_id = String::new();
         if let Some(p_blob_id) = matches.value_of("blob-id") {
@@ -283,15 +1082,28 @@ fn main() -> Result<()> {
         let mut compressor = matches.value_of("compressor").unwrap_or_default().parse()?;
         let mut digester = matches.value_of("digester").unwrap_or_default().parse()?;
         let repeatable = matches.is_present("repeatable");
+        let has_mtime = matches.is_present("inode-mtime");
+        if has_mtime && source_type == SourceType::StargzIndex {
+            bail!("--inode-mtime doesn't work with stargz index source");
+        }
+        let append = matches.is_present("append");
+        if append && source_type != SourceType::Tar {
+            bail!("--append only works with tar source");
+        }
 
         match source_type {
             SourceType::Directory => {
-                if !source_file.is_dir() {
+                if !source_file.map_or(false, |f| f.is_dir()) {
                     bail!("source {:?} must be a directory", source_path);
                 }
             }
+            SourceType::Tar => {
+                if !from_stdin && !source_file.map_or(false, |f| f.is_file()) {
+                    bail!("source {:?} must be a tar archive or \"-\"", source_path);
+                }
+            }
             SourceType::StargzIndex => {
-                if !source_file.is_file() {
+                if !source_file.map_or(false, |f| f.is_file()) {
                     bail!("source {:?} must be a JSON file", source_path);
                 }
                 if blob_id.trim() == "" {
@@ -308,12 +1120,30 @@ fn main() -> Result<()> {
             }
         }
 
+        let encryption = if let Some(path) = matches.value_of("encryption-config") {
+            // Gzip chunks are decompressed as streams, which can't be decrypted first.
+            if compressor == compress::Algorithm::GZip {
+                bail!("gzip compressed blobs can't be encrypted");
+            }
+            let file = File::open(path)
+                .with_context(|| format!("failed to open KMS config file {}", path))?;
+            let config: KmsConfig = serde_json::from_reader(file)
+                .with_context(|| format!("failed to parse KMS config file {}", path))?;
+            let (version, cipher) = KmsClient::new(config)?
+                .latest()
+                .context("failed to fetch blob key from KMS")?;
+            info!("Encrypt blob with key version {}", version);
+            Some((version, cipher))
+        } else {
+            None
+        };
+
         let bootstrap_path = Path::new(matches.value_of("bootstrap").unwrap());
 
         // Must specify a path to blob file.
         // For cli/binary interface compatibility sake, keep option `backend-config`, but
         // it only receives "localfs" backend type and it will be REMOVED in the future
-        let blob_stor = if source_type == SourceType::Directory {
+        let blob_stor = if source_type == SourceType::Directory || source_type == SourceType::Tar {
             Some(
                 if let Some(p) = matches
                     .value_of("blob")
@@ -358,10 +1188,19 @@ fn main() -> Result<()> {
             .unwrap_or_default()
             .parse()?;
 
-        let prefetch_policy = matches
+        let prefetch_policy: PrefetchPolicy = matches
             .value_of("prefetch-policy")
             .unwrap_or_default()
             .parse()?;
+        if source_type == SourceType::Tar {
+            if prefetch_policy == PrefetchPolicy::Blob {
+                bail!("prefetch policy blob is not supported with tar source, as blob is written while reading it");
+            }
+            // The prefetch list is read from stdin as well.
+            if from_stdin && prefetch_policy != PrefetchPolicy::None {
+                bail!("prefetch policy must be none when reading tar source from stdin");
+            }
+        }
         let prefetch = Prefetch::new(prefetch_policy)?;
 
         let aligned_chunk = matches.is_present("aligned-chunk");
@@ -403,6 +1242,7 @@ fn main() -> Result<()> {
             compressor,
             digester,
             explicit_uidgid: !repeatable,
+            has_mtime,
             whiteout_spec,
             aligned_chunk,
             prefetch,
@@ -413,6 +1253,7 @@ fn main() -> Result<()> {
             chunk_count_map: ChunkCountMap::default(),
             blob_table: OndiskBlobTable::new(),
             nodes: Vec::new(),
+            encryption,
         };
 
         let mut builder: Box<dyn Builder> = match source_type {
@@ -420,6 +1261,9 @@ fn main() -> Result<()> {
                 Box::new(DirectoryBuilder::new(blob_stor.as_ref().unwrap().clone()))
             }
             SourceType::StargzIndex => Box::new(StargzBuilder::new()),
+            SourceType::Tar => {
+                Box::new(TarBuilder::new(blob_stor.as_ref().unwrap().clone(), append))
+            }
         };
         let (blob_ids, blob_size) = timing_tracer!(
             { builder.build(&mut ctx).context("build failed") },
@@ -464,5 +1308,392 @@ fn main() -> Result<()> {
         dump_result_output(matches, blob_ids)?;
     }
 
+    if let Some(matches) = cmd.subcommand_matches("bench") {
+        // Safe to unwrap because they are required or have default values, and are validated.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let config = matches.value_of("config").unwrap();
+        let config = RafsConfig::from_file(config)
+            .map_err(|e| anyhow!("failed to load config {}, {:?}", config, e))?;
+        let workload = match matches.value_of("trace") {
+            Some(trace) => bench::Workload::Trace(PathBuf::from(trace)),
+            None if matches.value_of("workload") == Some("random") => bench::Workload::Random {
+                reads: matches.value_of("reads").unwrap().parse().unwrap(),
+                seed: matches.value_of("seed").unwrap().parse().unwrap(),
+            },
+            None => bench::Workload::Sequential,
+        };
+        let options = bench::BenchOptions {
+            workload,
+            block_size: matches.value_of("block-size").unwrap().parse().unwrap(),
+            threads: threads_from_args(matches),
+            rounds: matches.value_of("rounds").unwrap().parse().unwrap(),
+        };
+        let reports = bench::Bench::new(bootstrap_path, config, options)?.run()?;
+
+        let stdout = io::stdout();
+        bench::print(&reports, BufWriter::new(stdout.lock()))?;
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &reports).context("failed to write bench result")?;
+        }
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("dedup") {
+        // Safe to unwrap because it's required.
+        let bootstraps = matches
+            .values_of("BOOTSTRAP")
+            .unwrap()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let report = dedup::analyze(&bootstraps)?;
+
+        let stdout = io::stdout();
+        report.print(BufWriter::new(stdout.lock()))?;
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &report).context("failed to write dedup result")?;
+        }
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("diff") {
+        // Safe to unwrap because they are required.
+        let old = Path::new(matches.value_of("OLD_BOOTSTRAP").unwrap());
+        let new = Path::new(matches.value_of("NEW_BOOTSTRAP").unwrap());
+        let report = diff::diff(old, new)?;
+
+        report.print(io::stdout().lock())?;
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &report).context("failed to write diff result")?;
+        }
+
+        info!(
+            "{} added, {} removed, {} modified, {} new chunks of {} bytes to download",
+            report.added.len(),
+            report.removed.len(),
+            report.modified.len(),
+            report.added_chunks,
+            report.download_size
+        );
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("du") {
+        // Safe to unwrap because it's required.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let options = du::DuOptions {
+            all: matches.is_present("all"),
+            // Safe to unwrap because it's validated.
+            max_depth: matches.value_of("max-depth").map(|d| d.parse().unwrap()),
+            sort: matches.is_present("sort"),
+        };
+        let usages = du::du(bootstrap_path, &options)?;
+
+        let stdout = io::stdout();
+        du::print(&usages, BufWriter::new(stdout.lock()))?;
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &usages).context("failed to write du result")?;
+        }
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("export") {
+        // Safe to unwrap because they are required.
+        let bootstraps = matches
+            .values_of("bootstrap")
+            .unwrap()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let output_dir = Path::new(matches.value_of("output-dir").unwrap());
+        let backend = backend_from_args(matches)?;
+        let threads = threads_from_args(matches);
+
+        let layers = export::Exporter::new(bootstraps, backend, output_dir, threads).export()?;
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &layers).context("failed to write export result")?;
+        }
+
+        info!("{} layers exported to {:?}", layers.len(), output_dir);
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("fsck-cache") {
+        // Safe to unwrap because it's required.
+        let work_dir = Path::new(matches.value_of("work-dir").unwrap());
+        let bootstraps = matches
+            .values_of("bootstrap")
+            .map(|v| v.map(PathBuf::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let options = fsck::FsckOptions {
+            compressed: matches.is_present("compressed"),
+            dry_run: matches.is_present("dry-run"),
+        };
+        let report = fsck::Fsck::new(work_dir, &bootstraps, options)?
+            .check()
+            .with_context(|| format!("failed to check blobcache work dir {:?}", work_dir))?;
+
+        let stdout = io::stdout();
+        report.print(BufWriter::new(stdout.lock()))?;
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &report).context("failed to write fsck result")?;
+        }
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("export-chunk-map") {
+        // Safe to unwrap because they are required.
+        let blob_path = matches.value_of("BLOB_CACHE").unwrap();
+        let output = matches.value_of("output").unwrap();
+        let mut w = BufWriter::new(
+            OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(output)
+                .with_context(|| format!("{:?} can't be opened", output))?,
+        );
+        IndexedChunkMap::export_file(blob_path, &mut w)
+            .with_context(|| format!("failed to export chunk map of {:?}", blob_path))?;
+
+        info!("chunk map of {:?} exported to {:?}", blob_path, output);
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("import-chunk-map") {
+        // Safe to unwrap because they are required.
+        let blob_path = matches.value_of("BLOB_CACHE").unwrap();
+        let input = matches.value_of("input").unwrap();
+        let mut r = BufReader::new(
+            File::open(input).with_context(|| format!("{:?} can't be opened", input))?,
+        );
+        IndexedChunkMap::import_file(blob_path, &mut r)
+            .with_context(|| format!("failed to import chunk map into {:?}", blob_path))?;
+
+        info!("chunk map of {:?} imported from {:?}", blob_path, input);
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("gen-prefetch") {
+        // Safe to unwrap because they are required or have default values, and are validated.
+        let patterns = matches
+            .values_of("ACCESS_PATTERN")
+            .unwrap()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let options = gen_prefetch::GenOptions {
+            min_runs: matches.value_of("min-runs").unwrap().parse().unwrap(),
+            bootstrap: matches.value_of("bootstrap").map(PathBuf::from),
+        };
+        let json = matches.value_of("format") == Some("json");
+        let files = gen_prefetch::generate(&patterns, &options)
+            .context("failed to generate prefetch list")?;
+
+        match matches.value_of("output") {
+            Some(f) => {
+                let w = OpenOptions::new()
+                    .truncate(true)
+                    .create(true)
+                    .write(true)
+                    .open(f)
+                    .with_context(|| format!("{:?} can't be opened", f))?;
+                gen_prefetch::print(&files, json, BufWriter::new(w))?;
+            }
+            None => {
+                let stdout = io::stdout();
+                gen_prefetch::print(&files, json, BufWriter::new(stdout.lock()))?;
+            }
+        }
+        info!("{} files in prefetch list", files.len());
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("ls") {
+        // Safe to unwrap because they are required or have default values.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let path = Path::new(matches.value_of("PATH").unwrap());
+        let stdout = io::stdout();
+        inspect::list(
+            bootstrap_path,
+            path,
+            matches.is_present("long"),
+            BufWriter::new(stdout.lock()),
+        )?;
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("cat") {
+        // Safe to unwrap because they are required.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let path = Path::new(matches.value_of("PATH").unwrap());
+        let backend = backend_from_args(matches)?;
+        let stdout = io::stdout();
+        inspect::cat(
+            bootstrap_path,
+            &backend,
+            path,
+            BufWriter::with_capacity(BUF_WRITER_CAPACITY, stdout.lock()),
+        )?;
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("mount") {
+        // Safe to unwrap because they are required or have default values.
+        let bootstrap_path = Path::new(matches.value_of("bootstrap").unwrap());
+        let mountpoint = Path::new(matches.value_of("MOUNTPOINT").unwrap());
+        let log_level = cmd.value_of("log-level").unwrap();
+        let backend = backend_from_args(matches)?;
+        let nydusd = matches
+            .value_of("nydusd")
+            .map(PathBuf::from)
+            .unwrap_or_else(mount::default_nydusd);
+
+        mount::mount(bootstrap_path, &backend, mountpoint, &nydusd, log_level)
+            .with_context(|| format!("failed to mount bootstrap {:?}", bootstrap_path))?;
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("sign") {
+        // Safe to unwrap because they are required.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let key = Path::new(matches.value_of("key").unwrap());
+        let output = matches.value_of("output").map(Path::new);
+        let signature = sign::sign(bootstrap_path, key, output)
+            .with_context(|| format!("failed to sign bootstrap {:?}", bootstrap_path))?;
+
+        println!("signature written to {}", signature.display());
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("stat") {
+        // Safe to unwrap because it's required.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let source = matches
+            .values_of("source")
+            .map(|v| v.map(PathBuf::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let prefetch_list = matches.value_of("prefetch-list").map(Path::new);
+        let report = stat::stat(bootstrap_path, &source, prefetch_list)
+            .with_context(|| format!("failed to stat bootstrap {:?}", bootstrap_path))?;
+
+        let stdout = io::stdout();
+        report.print(BufWriter::new(stdout.lock()))?;
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &report).context("failed to write stat result")?;
+        }
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("upgrade-bootstrap") {
+        // Safe to unwrap because it's required.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let output = matches.value_of("output").map(Path::new);
+        let report = upgrade::upgrade(bootstrap_path, output)
+            .with_context(|| format!("failed to upgrade bootstrap {:?}", bootstrap_path))?;
+
+        if let Some(f) = matches.value_of("output-json") {
+            let w = OpenOptions::new()
+                .truncate(true)
+                .create(true)
+                .write(true)
+                .open(f)
+                .with_context(|| format!("{:?} can't be opened", f))?;
+            serde_json::to_writer(w, &report).context("failed to write upgrade result")?;
+        }
+        if report.upgraded {
+            println!(
+                "{} upgraded from version {}",
+                report.bootstrap, report.from_version
+            );
+        } else {
+            println!("{} is up to date", report.bootstrap);
+        }
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("unpack") {
+        // Safe to unwrap because they are required.
+        let bootstrap_path = Path::new(matches.value_of("bootstrap").unwrap());
+        let output = Path::new(matches.value_of("output").unwrap());
+        let backend = backend_from_args(matches)?;
+        let threads = threads_from_args(matches);
+
+        Unpacker::new(bootstrap_path, backend, output, threads)
+            .unpack()
+            .with_context(|| format!("failed to unpack bootstrap {:?}", bootstrap_path))?;
+
+        info!("image unpacked to {:?}", output);
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("verify") {
+        // Safe to unwrap because it's required.
+        let bootstrap_path = Path::new(matches.value_of("bootstrap").unwrap());
+        let backend = backend_from_args(matches)?;
+        let threads = threads_from_args(matches);
+
+        let report = Verifier::new(bootstrap_path, backend, threads)
+            .verify()
+            .with_context(|| format!("failed to verify bootstrap {:?}", bootstrap_path))?;
+
+        let json = serde_json::to_string_pretty(&report).context("failed to dump report")?;
+        println!("{}", json);
+        if let Some(f) = matches.value_of("output-json") {
+            std::fs::write(f, &json).with_context(|| format!("{:?} can't be written", f))?;
+        }
+
+        if !report.valid {
+            bail!(
+                "image is invalid, {} bad chunks, {} bad files, {} errors",
+                report.bad_chunks.len(),
+                report.bad_files.len(),
+                report.errors.len()
+            );
+        }
+        info!(
+            "image is valid, {} files, {} chunks of {} bytes verified",
+            report.files, report.chunks, report.bytes
+        );
+    }
+
+    if let Some(matches) = cmd.subcommand_matches("verify-sig") {
+        // Safe to unwrap because they are required.
+        let bootstrap_path = Path::new(matches.value_of("BOOTSTRAP").unwrap());
+        let public_key = Path::new(matches.value_of("public-key").unwrap());
+        let signature = matches.value_of("signature").map(Path::new);
+        let digest = sign::verify(bootstrap_path, public_key, signature).with_context(|| {
+            format!(
+                "failed to verify signature of bootstrap {:?}",
+                bootstrap_path
+            )
+        })?;
+
+        println!("signature is valid, bootstrap digest {}", digest);
+    }
+
     Ok(())
 }
@@ -0,0 +1,149 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mount an image in the foreground for a quick look inside, without writing a nydusd config.
+//!
+//! The FUSE server lives in nydusd, so nydusd is run as a child with a config generated for
+//! the backend given, reading blobs directly without cache. Ctrl-C and SIGTERM are passed on to
+//! nydusd, which unmounts the image and exits, and so does this command then.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::{Context, Result};
+use nix::sys::signal;
+use vmm_sys_util::tempdir::TempDir;
+
+use nydus_utils::signal::register_signal_handler;
+use storage::factory::BackendConfig;
+
+use crate::unpack::{ensure_blobs, open_image, rafs_config};
+
+/// Pid of the nydusd child to pass signals on to, 0 before it's spawned.
+static NYDUSD_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    let pid = NYDUSD_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        // nydusd handles both signals by unmounting, being signaled twice on Ctrl-C, by the
+        // terminal and from here, does no harm.
+        unsafe { libc::kill(pid, sig) };
+    }
+}
+
+/// `nydusd` next to this executable as they are installed together, or the one in `PATH`.
+pub fn default_nydusd() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("nydusd")))
+        .filter(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from("nydusd"))
+}
+
+/// Mount the image of `bootstrap` with blobs from `backend` at `mountpoint` by `nydusd`, and
+/// wait until it's unmounted.
+pub fn mount(
+    bootstrap: &Path,
+    backend: &BackendConfig,
+    mountpoint: &Path,
+    nydusd: &Path,
+    log_level: &str,
+) -> Result<()> {
+    // Fail early on a bad bootstrap or missing blobs, rather than on the first read of a file.
+    let reader = open_image(bootstrap, backend, "mount", false)?;
+    ensure_blobs(&reader, backend)?;
+    drop(reader);
+
+    let tmp = TempDir::new().map_err(|e| anyhow!("failed to create temp dir, {}", e))?;
+    let config = tmp.as_path().join("config.json");
+    fs::write(&config, serde_json::to_vec_pretty(&mount_config(backend))?)
+        .with_context(|| format!("failed to write nydusd config {:?}", config))?;
+    fs::create_dir_all(mountpoint)
+        .with_context(|| format!("failed to create mountpoint {:?}", mountpoint))?;
+
+    let mut child = Command::new(nydusd)
+        .args(nydusd_args(&config, bootstrap, mountpoint, log_level))
+        .spawn()
+        .with_context(|| format!("failed to run {:?}, pass its path by --nydusd", nydusd))?;
+    NYDUSD_PID.store(child.id() as i32, Ordering::SeqCst);
+    register_signal_handler(signal::SIGINT, forward_signal);
+    register_signal_handler(signal::SIGTERM, forward_signal);
+    info!(
+        "mounting image at {:?}, press Ctrl-C to unmount it",
+        mountpoint
+    );
+
+    let status = child.wait().context("failed to wait for nydusd")?;
+    if !status.success() {
+        bail!("nydusd exited with {}", status);
+    }
+    info!("image unmounted from {:?}", mountpoint);
+
+    Ok(())
+}
+
+/// Config of nydusd reading blobs from `backend`, with xattrs shown as in the source.
+fn mount_config(backend: &BackendConfig) -> serde_json::Value {
+    let mut config = rafs_config(backend, false);
+    config["enable_xattr"] = serde_json::Value::Bool(true);
+    config
+}
+
+fn nydusd_args(
+    config: &Path,
+    bootstrap: &Path,
+    mountpoint: &Path,
+    log_level: &str,
+) -> Vec<OsString> {
+    vec![
+        "--config".into(),
+        config.into(),
+        "--bootstrap".into(),
+        bootstrap.into(),
+        "--mountpoint".into(),
+        mountpoint.into(),
+        "--log-level".into(),
+        log_level.into(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpack::blob_dir_backend;
+    use rafs::fs::RafsConfig;
+
+    #[test]
+    fn test_mount_config() {
+        let backend = blob_dir_backend(Path::new("/blobs"));
+        let config: RafsConfig = serde_json::from_value(mount_config(&backend)).unwrap();
+        assert_eq!(config.mode, "direct");
+        assert!(config.enable_xattr);
+        assert!(!config.digest_validate);
+        assert_eq!(config.device.backend.backend_type, "localfs");
+
+        let args = nydusd_args(
+            Path::new("/tmp/config.json"),
+            Path::new("bootstrap"),
+            Path::new("/mnt"),
+            "info",
+        );
+        assert_eq!(
+            args,
+            vec![
+                "--config",
+                "/tmp/config.json",
+                "--bootstrap",
+                "bootstrap",
+                "--mountpoint",
+                "/mnt",
+                "--log-level",
+                "info"
+            ]
+        );
+    }
+}
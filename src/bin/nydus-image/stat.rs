@@ -0,0 +1,371 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Report how efficient a conversion is, comparing the RAFS image with the OCI layers it's
+//! converted from.
+//!
+//! The size of the image is that of the bootstrap plus distinct chunks in blobs, worked out
+//! from the bootstrap alone. Source layers are tar archives, gzip compressed or not. With a
+//! prefetch list, what's pulled before a container starts is estimated as the bootstrap and
+//! chunks of the listed files, everything else is only pulled when read.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use serde::Serialize;
+
+use rafs::metadata::layout::RAFS_ROOT_INODE;
+use rafs::metadata::RafsSuper;
+
+use crate::inspect::load_super;
+
+/// `(blob index, compressed offset)` of a chunk.
+type ChunkKey = (u32, u64);
+
+/// A regular file with its chunks, with compressed and decompressed sizes.
+struct Entry {
+    path: PathBuf,
+    ino: u64,
+    chunks: Vec<(ChunkKey, u32, u32)>,
+}
+
+/// Sizes of OCI layers an image is converted from.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct SourceStat {
+    pub layers: usize,
+    /// Sizes of layer files as pulled.
+    pub compressed_size: u64,
+    /// Sizes of tar archives in layers.
+    pub uncompressed_size: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct PrefetchStat {
+    /// Regular files under paths of the prefetch list.
+    pub files: u64,
+    /// Paths of the prefetch list with no regular file in the image.
+    pub missing: Vec<String>,
+    /// Compressed size of distinct chunks of prefetched files.
+    pub size: u64,
+    /// Bytes pulled before a container starts, the bootstrap and prefetched chunks.
+    pub startup_size: u64,
+    /// Bytes not pulled at startup, compared with the source layers if given, or the image.
+    pub saved_size: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct StatReport {
+    pub bootstrap: String,
+    pub bootstrap_size: u64,
+    pub blobs: usize,
+    /// Regular files, hardlinks counted once.
+    pub files: u64,
+    /// Distinct chunks in blobs.
+    pub chunks: u64,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    /// Compressed size of chunks of all files, as if no chunk were deduplicated.
+    pub referenced_size: u64,
+    pub dedup_saved_size: u64,
+    /// The bootstrap and blobs.
+    pub total_size: u64,
+    pub source: Option<SourceStat>,
+    /// Total size of the image minus compressed size of the source, negative if it's smaller.
+    pub size_delta: Option<i64>,
+    pub prefetch: Option<PrefetchStat>,
+}
+
+impl StatReport {
+    pub fn print<W: Write>(&self, mut w: W) -> Result<()> {
+        writeln!(w, "bootstrap: {} bytes", self.bootstrap_size)?;
+        writeln!(
+            w,
+            "blobs: {} blobs, {} files, {} chunks, {} bytes, {} bytes uncompressed",
+            self.blobs, self.files, self.chunks, self.compressed_size, self.uncompressed_size
+        )?;
+        writeln!(
+            w,
+            "dedup: {} bytes saved of {} bytes",
+            self.dedup_saved_size, self.referenced_size
+        )?;
+        if let Some(s) = &self.source {
+            writeln!(
+                w,
+                "source: {} layers, {} bytes, {} bytes uncompressed",
+                s.layers, s.compressed_size, s.uncompressed_size
+            )?;
+        }
+        if let Some(delta) = self.size_delta {
+            writeln!(w, "size delta: {:+} bytes", delta)?;
+        }
+        if let Some(p) = &self.prefetch {
+            writeln!(
+                w,
+                "prefetch: {} files, {} bytes, {} bytes pulled at startup, {} bytes saved",
+                p.files, p.size, p.startup_size, p.saved_size
+            )?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Compare the image of `bootstrap` with the OCI layers `source`, and estimate what's pulled
+/// at startup with files listed in `prefetch_list` prefetched.
+pub fn stat(
+    bootstrap: &Path,
+    source: &[PathBuf],
+    prefetch_list: Option<&Path>,
+) -> Result<StatReport> {
+    let rs = load_super(bootstrap)?;
+    let entries = load_entries(&rs).context("failed to walk inodes")?;
+    let bootstrap_size = fs::metadata(bootstrap)
+        .with_context(|| format!("failed to stat {:?}", bootstrap))?
+        .len();
+    let source = match source {
+        [] => None,
+        layers => Some(source_stat(layers)?),
+    };
+    let prefetch = prefetch_list.map(read_prefetch_list).transpose()?;
+
+    let mut report = summarize(&entries, bootstrap_size, source, prefetch.as_deref());
+    report.bootstrap = bootstrap.to_string_lossy().to_string();
+    report.blobs = rs.inodes.get_blob_table().entries.len();
+    Ok(report)
+}
+
+/// All regular files in path order.
+fn load_entries(rs: &RafsSuper) -> Result<Vec<Entry>> {
+    let mut dirs = vec![(rs.get_inode(RAFS_ROOT_INODE, false)?, PathBuf::from("/"))];
+    let mut entries = Vec::new();
+
+    while let Some((dir, dir_path)) = dirs.pop() {
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            let path = dir_path.join(child.name());
+            if child.is_dir() {
+                dirs.push((child, path));
+            } else if child.is_reg() {
+                let mut chunks = Vec::new();
+                for idx in 0..child.get_child_count() {
+                    let chunk = child.get_chunk_info(idx)?;
+                    chunks.push((
+                        (chunk.blob_index(), chunk.compress_offset()),
+                        chunk.compress_size(),
+                        chunk.decompress_size(),
+                    ));
+                }
+                entries.push(Entry {
+                    path,
+                    ino: child.ino(),
+                    chunks,
+                });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(entries)
+}
+
+fn source_stat(layers: &[PathBuf]) -> Result<SourceStat> {
+    let mut stat = SourceStat {
+        layers: layers.len(),
+        ..Default::default()
+    };
+    for layer in layers {
+        let (compressed, uncompressed) =
+            layer_size(layer).with_context(|| format!("failed to read layer {:?}", layer))?;
+        stat.compressed_size += compressed;
+        stat.uncompressed_size += uncompressed;
+    }
+    Ok(stat)
+}
+
+/// Size of the layer file and of the tar archive in it.
+fn layer_size(layer: &Path) -> Result<(u64, u64)> {
+    let size = fs::metadata(layer)?.len();
+    let mut magic = [0u8; 2];
+    File::open(layer)?.read_exact(&mut magic)?;
+    if magic != [0x1f, 0x8b] {
+        return Ok((size, size));
+    }
+    let mut tar = MultiGzDecoder::new(BufReader::new(File::open(layer)?));
+    Ok((size, io::copy(&mut tar, &mut io::sink())?))
+}
+
+/// Absolute paths line by line, the same as the prefetch list given to `create`.
+fn read_prefetch_list(path: &Path) -> Result<Vec<PathBuf>> {
+    let f = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
+    let mut paths = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.with_context(|| format!("failed to read {:?}", path))?;
+        let p = PathBuf::from(line.trim());
+        if p.as_os_str().is_empty() {
+            continue;
+        }
+        if !p.is_absolute() {
+            warn!("prefetch path {:?} must start with '/', skipped", p);
+            continue;
+        }
+        paths.push(p);
+    }
+    Ok(paths)
+}
+
+fn summarize(
+    entries: &[Entry],
+    bootstrap_size: u64,
+    source: Option<SourceStat>,
+    prefetch: Option<&[PathBuf]>,
+) -> StatReport {
+    let mut report = StatReport {
+        bootstrap_size,
+        ..Default::default()
+    };
+
+    let mut inodes = HashSet::new();
+    let mut chunks = HashMap::new();
+    for entry in entries {
+        if !inodes.insert(entry.ino) {
+            continue;
+        }
+        report.files += 1;
+        for (key, compressed, uncompressed) in &entry.chunks {
+            report.referenced_size += *compressed as u64;
+            chunks.insert(*key, (*compressed, *uncompressed));
+        }
+    }
+    for (compressed, uncompressed) in chunks.values() {
+        report.chunks += 1;
+        report.compressed_size += *compressed as u64;
+        report.uncompressed_size += *uncompressed as u64;
+    }
+    report.dedup_saved_size = report.referenced_size - report.compressed_size;
+    report.total_size = bootstrap_size + report.compressed_size;
+
+    report.size_delta = source
+        .as_ref()
+        .map(|s| report.total_size as i64 - s.compressed_size as i64);
+    let baseline = source
+        .as_ref()
+        .map_or(report.total_size, |s| s.compressed_size);
+    report.source = source;
+    report.prefetch = prefetch.map(|paths| prefetch_stat(entries, paths, bootstrap_size, baseline));
+
+    report
+}
+
+fn prefetch_stat(
+    entries: &[Entry],
+    paths: &[PathBuf],
+    bootstrap_size: u64,
+    baseline: u64,
+) -> PrefetchStat {
+    let mut stat = PrefetchStat::default();
+    let mut matched = HashSet::new();
+    let mut inodes = HashSet::new();
+    let mut chunks = HashSet::new();
+
+    for entry in entries {
+        // Component wise, so `/usr/lib` doesn't take in `/usr/lib64`.
+        let path = match paths.iter().find(|p| entry.path.starts_with(p)) {
+            Some(p) => p,
+            None => continue,
+        };
+        matched.insert(path);
+        if !inodes.insert(entry.ino) {
+            continue;
+        }
+        stat.files += 1;
+        for (key, compressed, _) in &entry.chunks {
+            if chunks.insert(*key) {
+                stat.size += *compressed as u64;
+            }
+        }
+    }
+    stat.missing = paths
+        .iter()
+        .filter(|p| !matched.contains(p))
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    stat.startup_size = bootstrap_size + stat.size;
+    stat.saved_size = baseline.saturating_sub(stat.startup_size);
+
+    stat
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, ino: u64, chunks: &[(u64, u32)]) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            ino,
+            chunks: chunks
+                .iter()
+                .map(|(off, size)| ((0, *off), *size, *size * 2))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let entries = vec![
+            file("/bin/app", 2, &[(0, 100), (100, 50)]),
+            // Deduplicated with the first chunk of `app`.
+            file("/bin/tool", 3, &[(0, 100)]),
+            // Hardlinked to `tool`.
+            file("/bin/tool-link", 3, &[(0, 100)]),
+            file("/usr/lib/libc.so", 4, &[(150, 30)]),
+            file("/usr/lib64/libm.so", 5, &[(180, 20)]),
+        ];
+        let source = SourceStat {
+            layers: 2,
+            compressed_size: 1000,
+            uncompressed_size: 3000,
+        };
+        let prefetch = vec![
+            PathBuf::from("/bin/tool"),
+            PathBuf::from("/usr/lib"),
+            PathBuf::from("/opt"),
+        ];
+
+        let report = summarize(&entries, 40, Some(source), Some(prefetch.as_slice()));
+        assert_eq!(report.files, 4);
+        assert_eq!(report.chunks, 4);
+        assert_eq!(report.compressed_size, 200);
+        assert_eq!(report.uncompressed_size, 400);
+        assert_eq!(report.referenced_size, 300);
+        assert_eq!(report.dedup_saved_size, 100);
+        assert_eq!(report.total_size, 240);
+        assert_eq!(report.size_delta, Some(-760));
+
+        let prefetch = report.prefetch.as_ref().unwrap();
+        assert_eq!(prefetch.files, 2);
+        assert_eq!(prefetch.missing, vec!["/opt".to_string()]);
+        assert_eq!(prefetch.size, 130);
+        assert_eq!(prefetch.startup_size, 170);
+        assert_eq!(prefetch.saved_size, 830);
+
+        let mut out = Vec::new();
+        report.print(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\nsize delta: -760 bytes\n"));
+        assert!(out.ends_with(
+            "prefetch: 2 files, 130 bytes, 170 bytes pulled at startup, 830 bytes saved\n"
+        ));
+
+        // Compared with the image itself without source layers.
+        let prefetch = vec![PathBuf::from("/usr/lib64/libm.so")];
+        let report = summarize(&entries, 40, None, Some(prefetch.as_slice()));
+        assert_eq!(report.size_delta, None);
+        assert_eq!(report.prefetch.unwrap().saved_size, 180);
+    }
+}
@@ -0,0 +1,954 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Unpack a RAFS image into a tar archive, with file data read from a storage backend.
+//!
+//! Blobs are resolved by their ids, i.e. sha-256 digests, either under a local directory, the
+//! same way `create --blob-dir` names them, or in a registry or OSS bucket, where they're
+//! fetched on demand. So an image built from multiple layers can be unpacked without
+//! downloading its blobs first. Entries are written in ustar format, with PAX extended headers
+//! for long names, large numbers and xattrs. Files having hole chunks are written as GNU sparse
+//! files of format 1.0, holes take no space in the archive and are restored when extracted.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Context, Result};
+use nix::sys::stat::{major, minor};
+
+use rafs::fs::RafsConfig;
+use rafs::metadata::layout::{bytes_to_os_str, RAFS_ROOT_INODE};
+use rafs::metadata::RafsInode;
+use rafs::reader::RafsReader;
+use storage::factory::BackendConfig;
+
+use crate::core::context::BUF_WRITER_CAPACITY;
+
+/// Output path meaning stdout.
+const STDOUT: &str = "-";
+const BLOCK_SIZE: usize = 512;
+/// Size of pieces file data is read in.
+const READ_BUFFER_SIZE: usize = 0x10_0000;
+/// Pieces read ahead of the one being written.
+const PENDING_PIECES: usize = 64;
+
+const TYPE_REG: u8 = b'0';
+const TYPE_HARDLINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_CHAR: u8 = b'3';
+const TYPE_BLOCK: u8 = b'4';
+const TYPE_DIR: u8 = b'5';
+const TYPE_FIFO: u8 = b'6';
+const TYPE_PAX: u8 = b'x';
+
+/// Metadata of a tar entry.
+struct Entry {
+    path: Vec<u8>,
+    kind: u8,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+    size: u64,
+    link: Vec<u8>,
+    rdev: u64,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Data regions as `(offset, length)` of a sparse file, whose `size` is the real size.
+    sparse: Option<Vec<(u64, u64)>>,
+}
+
+/// Writes tar entries in ustar format, falling back to PAX records for what ustar can't hold.
+struct TarWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn new(writer: W) -> Self {
+        TarWriter { writer }
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<()> {
+        let mut header = [0u8; BLOCK_SIZE];
+        let mut pax = Vec::new();
+        let mut path = entry.path.clone();
+        let mut size = entry.size;
+        let mut map = Vec::new();
+
+        if let Some(regions) = entry.sparse.as_ref() {
+            // The entry is named differently, so that tools not knowing sparse files don't
+            // extract the map as part of the file.
+            pax_record(&mut pax, b"GNU.sparse.major", b"1");
+            pax_record(&mut pax, b"GNU.sparse.minor", b"0");
+            pax_record(&mut pax, b"GNU.sparse.name", &entry.path);
+            pax_record(
+                &mut pax,
+                b"GNU.sparse.realsize",
+                size.to_string().as_bytes(),
+            );
+            path = sparse_path(&entry.path);
+            map = sparse_map(regions);
+            size = map.len() as u64 + regions.iter().map(|r| r.1).sum::<u64>();
+        }
+
+        if !set_bytes(&mut header[0..100], &path) {
+            pax_record(&mut pax, b"path", &path);
+        }
+        set_octal(&mut header[100..108], (entry.mode & 0o7777) as u64);
+        if !set_octal(&mut header[108..116], entry.uid) {
+            pax_record(&mut pax, b"uid", entry.uid.to_string().as_bytes());
+        }
+        if !set_octal(&mut header[116..124], entry.gid) {
+            pax_record(&mut pax, b"gid", entry.gid.to_string().as_bytes());
+        }
+        if !set_octal(&mut header[124..136], size) {
+            pax_record(&mut pax, b"size", size.to_string().as_bytes());
+        }
+        set_octal(&mut header[136..148], entry.mtime);
+        header[156] = entry.kind;
+        if !set_bytes(&mut header[157..257], &entry.link) {
+            pax_record(&mut pax, b"linkpath", &entry.link);
+        }
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        if entry.kind == TYPE_CHAR || entry.kind == TYPE_BLOCK {
+            set_octal(&mut header[329..337], major(entry.rdev));
+            set_octal(&mut header[337..345], minor(entry.rdev));
+        }
+        for (name, value) in &entry.xattrs {
+            let mut key = b"SCHILY.xattr.".to_vec();
+            key.extend_from_slice(name);
+            pax_record(&mut pax, &key, value);
+        }
+
+        if !pax.is_empty() {
+            let mut pax_header = [0u8; BLOCK_SIZE];
+            set_bytes(&mut pax_header[0..100], b"././@PaxHeader");
+            set_octal(&mut pax_header[100..108], 0o644);
+            set_octal(&mut pax_header[124..136], pax.len() as u64);
+            pax_header[156] = TYPE_PAX;
+            pax_header[257..263].copy_from_slice(b"ustar\0");
+            pax_header[263..265].copy_from_slice(b"00");
+            set_checksum(&mut pax_header);
+            self.writer.write_all(&pax_header)?;
+            self.writer.write_all(&pax)?;
+            self.pad(pax.len() as u64)?;
+        }
+
+        set_checksum(&mut header);
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&map)?;
+
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    /// Fill up the last block of `size` bytes of data.
+    fn pad(&mut self, size: u64) -> Result<()> {
+        let rem = (size % BLOCK_SIZE as u64) as usize;
+        if rem != 0 {
+            self.writer.write_all(&[0u8; BLOCK_SIZE][rem..])?;
+        }
+        Ok(())
+    }
+
+    /// End the archive with two zero blocks.
+    fn finish(mut self) -> Result<W> {
+        self.writer.write_all(&[0u8; BLOCK_SIZE * 2])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Copy `value` into a header field, returns false if it doesn't fit.
+fn set_bytes(field: &mut [u8], value: &[u8]) -> bool {
+    if value.len() > field.len() {
+        return false;
+    }
+    field[..value.len()].copy_from_slice(value);
+    true
+}
+
+/// Write `value` as a NUL terminated octal number, returns false if it doesn't fit.
+fn set_octal(field: &mut [u8], value: u64) -> bool {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() >= field.len() {
+        return false;
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    true
+}
+
+fn set_checksum(header: &mut [u8; BLOCK_SIZE]) {
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u64 = header.iter().map(|b| *b as u64).sum();
+    // Six digits, NUL and space.
+    let digits = format!("{:06o}\0 ", sum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+}
+
+/// Path of the entry of a sparse file, `dir/GNUSparseFile.0/name` for `dir/name`.
+fn sparse_path(path: &[u8]) -> Vec<u8> {
+    let path = Path::new(bytes_to_os_str(path));
+    let name = path.file_name().unwrap_or_default();
+    path.parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join("GNUSparseFile.0")
+        .join(name)
+        .as_os_str()
+        .as_bytes()
+        .to_vec()
+}
+
+/// Sparse map leading data of a sparse file, the number of regions followed by offset and
+/// length of each region, in decimal lines padded to blocks.
+fn sparse_map(regions: &[(u64, u64)]) -> Vec<u8> {
+    let mut map = format!("{}\n", regions.len());
+    for (offset, len) in regions {
+        map += &format!("{}\n{}\n", offset, len);
+    }
+    let mut map = map.into_bytes();
+    let rem = map.len() % BLOCK_SIZE;
+    if rem != 0 {
+        map.resize(map.len() + BLOCK_SIZE - rem, 0);
+    }
+    map
+}
+
+/// Data regions of a file of `size` bytes with `chunks` as `(offset, length)` and whether
+/// they're holes, or None if there's no hole. A file ending with a hole has an empty region
+/// at its end, to tell the real size.
+fn sparse_regions(size: u64, chunks: &[(u64, u64, bool)]) -> Option<Vec<(u64, u64)>> {
+    let mut regions: Vec<(u64, u64)> = Vec::new();
+    let mut data = chunks
+        .iter()
+        .filter(|c| !c.2 && c.1 > 0 && c.0 < size)
+        .map(|c| (c.0, std::cmp::min(c.0 + c.1, size)))
+        .collect::<Vec<_>>();
+    data.sort_unstable();
+
+    for (start, end) in data {
+        match regions.last_mut() {
+            Some(last) if start <= last.0 + last.1 => {
+                last.1 = std::cmp::max(last.0 + last.1, end) - last.0;
+            }
+            _ => regions.push((start, end - start)),
+        }
+    }
+
+    if size == 0 || (regions.len() == 1 && regions[0] == (0, size)) {
+        return None;
+    }
+    match regions.last() {
+        Some(last) if last.0 + last.1 == size => {}
+        _ => regions.push((size, 0)),
+    }
+
+    Some(regions)
+}
+
+/// Append a PAX record `"<length> <key>=<value>\n"`, where length counts the whole record.
+fn pax_record(pax: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len.to_string().len() + rest > len {
+        len += 1;
+    }
+    pax.extend_from_slice(len.to_string().as_bytes());
+    pax.push(b' ');
+    pax.extend_from_slice(key);
+    pax.push(b'=');
+    pax.extend_from_slice(value);
+    pax.push(b'\n');
+}
+
+/// Number of online CPUs, the default number of unpack threads.
+pub fn online_cpus() -> usize {
+    // Safe because it only queries system configuration.
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    std::cmp::max(n, 1) as usize
+}
+
+/// Backend reading blobs named by their digests under `dir`.
+pub fn blob_dir_backend(dir: &Path) -> BackendConfig {
+    BackendConfig {
+        backend_type: "localfs".to_string(),
+        backend_config: serde_json::json!({ "dir": dir }),
+    }
+}
+
+/// Local directory holding the blobs, if `backend` reads from one.
+fn blob_dir(backend: &BackendConfig) -> Option<PathBuf> {
+    if backend.backend_type != "localfs" {
+        return None;
+    }
+    backend
+        .backend_config
+        .get("dir")
+        .and_then(|d| d.as_str())
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Rafs config reading blobs from `backend` without cache, `digest_validate` tells whether
+/// chunk data read is checked against digests.
+pub fn rafs_config(backend: &BackendConfig, digest_validate: bool) -> serde_json::Value {
+    serde_json::json!({
+        "device": {
+            "backend": {
+                "type": backend.backend_type,
+                "config": backend.backend_config,
+            }
+        },
+        "mode": "direct",
+        "digest_validate": digest_validate,
+    })
+}
+
+/// Open the image of `bootstrap` with blobs from `backend`, see `rafs_config()` for
+/// `digest_validate`.
+pub fn open_image(
+    bootstrap: &Path,
+    backend: &BackendConfig,
+    id: &str,
+    digest_validate: bool,
+) -> Result<RafsReader> {
+    let config = rafs_config(backend, digest_validate);
+    let conf: RafsConfig = serde_json::from_value(config).context("failed to build rafs config")?;
+    let reader = RafsReader::open(conf, id, bootstrap)
+        .map_err(|e| anyhow!("failed to open image {:?}, {:?}", bootstrap, e))?;
+
+    let blobs = reader.super_block().inodes.get_blob_table();
+    for entry in &blobs.entries {
+        let id = &entry.blob_id;
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            bail!("invalid blob id {:?} in bootstrap", id);
+        }
+    }
+
+    Ok(reader)
+}
+
+/// Blobs of the image not found in the blob directory of `backend`. Blobs of remote backends
+/// are only known to be missing when they're read.
+pub fn missing_blobs(reader: &RafsReader, backend: &BackendConfig) -> Vec<String> {
+    let dir = match blob_dir(backend) {
+        Some(d) => d,
+        None => return Vec::new(),
+    };
+    reader
+        .super_block()
+        .inodes
+        .get_blob_table()
+        .entries
+        .iter()
+        .filter(|e| !dir.join(&e.blob_id).is_file())
+        .map(|e| e.blob_id.clone())
+        .collect()
+}
+
+/// Fail if any blob of the image is missing from the blob directory of `backend`, to find it
+/// out before writing anything rather than failing half way.
+pub fn ensure_blobs(reader: &RafsReader, backend: &BackendConfig) -> Result<()> {
+    if let Some(id) = missing_blobs(reader, backend).first() {
+        bail!("blob {} not found in {:?}", id, blob_dir(backend));
+    }
+    Ok(())
+}
+
+pub struct Unpacker {
+    bootstrap: PathBuf,
+    backend: BackendConfig,
+    output: PathBuf,
+    /// Number of threads reading file data.
+    threads: usize,
+}
+
+impl Unpacker {
+    pub fn new(bootstrap: &Path, backend: BackendConfig, output: &Path, threads: usize) -> Self {
+        Self {
+            bootstrap: bootstrap.to_path_buf(),
+            backend,
+            output: output.to_path_buf(),
+            threads: std::cmp::max(threads, 1),
+        }
+    }
+
+    fn open_image(&self) -> Result<RafsReader> {
+        // Chunks are checked against their digests, a corrupted blob fails unpacking rather
+        // than producing wrong data.
+        let reader = open_image(&self.bootstrap, &self.backend, "unpack", true)?;
+        ensure_blobs(&reader, &self.backend)?;
+
+        Ok(reader)
+    }
+
+    /// Write all files of the image to the output tar, or to stdout if the output is `-`.
+    /// An output file is removed if unpacking fails half way, e.g. when a backend is away.
+    pub fn unpack(&self) -> Result<()> {
+        if self.output == Path::new(STDOUT) {
+            // Safe because it only checks a file descriptor.
+            if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+                bail!("refuse to write tar to a terminal, redirect stdout or use --output");
+            }
+            let reader = Arc::new(self.open_image()?);
+            let stdout = io::stdout();
+            let writer = BufWriter::with_capacity(BUF_WRITER_CAPACITY, stdout.lock());
+            let items = collect_items(&reader, &|_| true)?;
+            write_tar(reader, items, self.threads, writer)?;
+            return Ok(());
+        }
+
+        let reader = Arc::new(self.open_image()?);
+        let file = File::create(&self.output)
+            .with_context(|| format!("failed to create output {:?}", self.output))?;
+        let writer = BufWriter::with_capacity(BUF_WRITER_CAPACITY, file);
+        collect_items(&reader, &|_| true)
+            .and_then(|items| write_tar(reader, items, self.threads, writer))
+            .and_then(|w| {
+                w.into_inner()
+                    .map_err(|e| anyhow!("failed to flush output, {}", e.error()))?
+                    .sync_all()?;
+                Ok(())
+            })
+            .map_err(|e| {
+                if let Err(e) = fs::remove_file(&self.output) {
+                    warn!("failed to remove output {:?}, {}", self.output, e);
+                }
+                e
+            })
+    }
+}
+
+/// Write `items` of the image in tar to `writer`.
+///
+/// File data is read and decompressed in pieces by worker threads, and written in order
+/// by the calling thread. A feeder thread hands out pieces to workers, at most
+/// `PENDING_PIECES` of them ahead of the one being written, to bound memory usage.
+pub fn write_tar<W: Write>(
+    reader: Arc<RafsReader>,
+    items: Vec<Item>,
+    threads: usize,
+    writer: W,
+) -> Result<W> {
+    let pieces = items
+        .iter()
+        .flat_map(|i| i.pieces.iter().map(move |p| (i.ino, p.0, p.1)))
+        .collect::<Vec<_>>();
+
+    let (job_tx, job_rx) = mpsc::sync_channel::<Job>(threads);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let mut workers = Vec::with_capacity(threads);
+    for i in 0..threads {
+        let reader = reader.clone();
+        let jobs = job_rx.clone();
+        workers.push(
+            thread::Builder::new()
+                .name(format!("unpack_worker_{}", i))
+                .spawn(move || read_pieces(&reader, &jobs))?,
+        );
+    }
+
+    let (order_tx, order_rx) = mpsc::sync_channel(PENDING_PIECES);
+    let feeder = thread::Builder::new()
+        .name("unpack_feeder".to_string())
+        .spawn(move || {
+            for (ino, offset, len) in pieces {
+                let (done, result) = mpsc::channel();
+                // The writer has given up if it's gone.
+                if order_tx.send(result).is_err() {
+                    break;
+                }
+                let job = Job {
+                    ino,
+                    offset,
+                    len,
+                    done,
+                };
+                if job_tx.send(job).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    let result = write_items(&items, &order_rx, writer);
+
+    // Stop the feeder if writing failed, workers exit once the feeder is gone.
+    drop(order_rx);
+    let mut panicked = feeder.join().is_err();
+    for worker in workers {
+        panicked |= worker.join().is_err();
+    }
+    if panicked && result.is_ok() {
+        bail!("unpack worker panicked");
+    }
+
+    result
+}
+
+/// Walk the inode tree for entries at paths chosen by `select` to write, in the order of the
+/// tar, parents before their children. Paths are relative to the root of the image.
+pub fn collect_items(reader: &RafsReader, select: &dyn Fn(&Path) -> bool) -> Result<Vec<Item>> {
+    let root = reader.super_block().get_inode(RAFS_ROOT_INODE, false)?;
+    // Paths of hardlinked inodes written, later links refer to them.
+    let mut links: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut dirs = vec![(root, PathBuf::new())];
+    let mut items = Vec::new();
+
+    while let Some((dir, dir_path)) = dirs.pop() {
+        let mut subdirs = Vec::new();
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            let path = dir_path.join(child.name());
+            if select(&path) {
+                if let Some(item) = new_item(child.as_ref(), &path, &mut links)
+                    .with_context(|| format!("failed to unpack {:?}", path))?
+                {
+                    items.push(item);
+                }
+            }
+            if child.is_dir() {
+                subdirs.push((child, path));
+            }
+        }
+        // Pushed in reverse, so that directories are visited in the order of names.
+        dirs.extend(subdirs.into_iter().rev());
+    }
+
+    Ok(items)
+}
+
+/// An entry to write, along with data pieces of a regular file.
+pub struct Item {
+    entry: Entry,
+    ino: u64,
+    /// `(offset, length)` of data pieces, each of at most `READ_BUFFER_SIZE` bytes.
+    pieces: Vec<(u64, u64)>,
+}
+
+impl Item {
+    /// An empty regular file at `path` not from the image, for markers like whiteouts.
+    pub fn empty_file(path: &Path) -> Self {
+        Item {
+            entry: Entry {
+                path: path.as_os_str().as_bytes().to_vec(),
+                kind: TYPE_REG,
+                mode: libc::S_IFREG | 0o644,
+                uid: 0,
+                gid: 0,
+                mtime: 0,
+                size: 0,
+                link: Vec::new(),
+                rdev: 0,
+                xattrs: Vec::new(),
+                sparse: None,
+            },
+            ino: 0,
+            pieces: Vec::new(),
+        }
+    }
+}
+
+/// A piece of file data to read, whose content is sent back through `done`.
+struct Job {
+    ino: u64,
+    offset: u64,
+    len: u64,
+    done: Sender<io::Result<Vec<u8>>>,
+}
+
+/// Build the entry of `inode` at `path`, None if it can't be put in tar.
+fn new_item(
+    inode: &dyn RafsInode,
+    path: &Path,
+    links: &mut HashMap<u64, Vec<u8>>,
+) -> Result<Option<Item>> {
+    let attr = inode.get_attr();
+    let entry = Entry {
+        path: path.as_os_str().as_bytes().to_vec(),
+        kind: TYPE_REG,
+        mode: attr.mode,
+        uid: attr.uid as u64,
+        gid: attr.gid as u64,
+        mtime: attr.mtime,
+        size: 0,
+        link: Vec::new(),
+        rdev: inode.rdev() as u64,
+        xattrs: Vec::new(),
+        sparse: None,
+    };
+    let mut item = Item {
+        entry,
+        ino: inode.ino(),
+        pieces: Vec::new(),
+    };
+    let entry = &mut item.entry;
+
+    // Later links of an inode refer to the first one, any file other than directories may
+    // be hardlinked.
+    if inode.is_hardlink() && !inode.is_dir() {
+        if let Some(target) = links.get(&inode.ino()) {
+            entry.kind = TYPE_HARDLINK;
+            entry.link = target.clone();
+            return Ok(Some(item));
+        }
+        links.insert(inode.ino(), entry.path.clone());
+    }
+
+    if inode.has_xattr() {
+        for name in inode.get_xattrs()? {
+            let value = inode.get_xattr(bytes_to_os_str(&name))?;
+            entry.xattrs.push((name, value.unwrap_or_default()));
+        }
+    }
+
+    match attr.mode & libc::S_IFMT {
+        libc::S_IFDIR => {
+            entry.kind = TYPE_DIR;
+            entry.path.push(b'/');
+        }
+        libc::S_IFLNK => {
+            entry.kind = TYPE_SYMLINK;
+            entry.link = inode.get_symlink()?.as_bytes().to_vec();
+        }
+        libc::S_IFCHR => entry.kind = TYPE_CHAR,
+        libc::S_IFBLK => entry.kind = TYPE_BLOCK,
+        libc::S_IFIFO => entry.kind = TYPE_FIFO,
+        libc::S_IFREG => {
+            entry.size = inode.size();
+            let mut chunks = Vec::new();
+            for idx in 0..inode.get_child_count() {
+                let chunk = inode.get_chunk_info(idx)?;
+                chunks.push((
+                    chunk.file_offset(),
+                    chunk.decompress_size() as u64,
+                    chunk.is_hole(),
+                ));
+            }
+            entry.sparse = sparse_regions(entry.size, &chunks);
+
+            let regions = entry
+                .sparse
+                .clone()
+                .unwrap_or_else(|| vec![(0, entry.size)]);
+            for (offset, len) in regions {
+                let end = offset + len;
+                let mut offset = offset;
+                while offset < end {
+                    let len = std::cmp::min(READ_BUFFER_SIZE as u64, end - offset);
+                    item.pieces.push((offset, len));
+                    offset += len;
+                }
+            }
+        }
+        _ => {
+            warn!("skip {:?} which can't be put in tar", path);
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(item))
+}
+
+/// Write entries of `items` with their data pieces, whose contents come from `order` in the
+/// same order.
+fn write_items<W: Write>(
+    items: &[Item],
+    order: &Receiver<Receiver<io::Result<Vec<u8>>>>,
+    writer: W,
+) -> Result<W> {
+    let mut tar = TarWriter::new(writer);
+
+    for item in items {
+        tar.append(&item.entry)?;
+        let mut written = 0;
+        for _ in &item.pieces {
+            let data = order
+                .recv()
+                .ok()
+                .and_then(|result| result.recv().ok())
+                .ok_or_else(|| anyhow!("unpack workers exited unexpectedly"))?
+                .with_context(|| {
+                    format!("failed to unpack {:?}", bytes_to_os_str(&item.entry.path))
+                })?;
+            tar.write_data(&data)?;
+            written += data.len() as u64;
+        }
+        tar.pad(written)?;
+    }
+
+    tar.finish()
+}
+
+/// Read pieces of file data for jobs until there's no more.
+fn read_pieces(reader: &RafsReader, jobs: &Mutex<Receiver<Job>>) {
+    // Pieces of a file are mostly handed out one after another, keep the inode for them.
+    let mut inode: Option<Arc<dyn RafsInode>> = None;
+
+    loop {
+        // The lock is only held while waiting for a job.
+        let job = jobs.lock().unwrap().recv();
+        let job = match job {
+            Ok(j) => j,
+            Err(_) => break,
+        };
+        if inode.as_ref().map(|i| i.ino()) != Some(job.ino) {
+            match reader.super_block().get_inode(job.ino, false) {
+                Ok(i) => inode = Some(i),
+                Err(e) => {
+                    let _ = job.done.send(Err(e));
+                    continue;
+                }
+            }
+        }
+        // Safe to unwrap because it's just set.
+        let result = read_piece(
+            reader,
+            inode.as_ref().unwrap().as_ref(),
+            job.offset,
+            job.len,
+        );
+        // The writer may have given up.
+        let _ = job.done.send(result);
+    }
+}
+
+/// Read exactly `len` bytes at `offset` of `inode`.
+pub fn read_piece(
+    reader: &RafsReader,
+    inode: &dyn RafsInode,
+    offset: u64,
+    len: u64,
+) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    let mut n = 0;
+    while n < buf.len() {
+        let r = reader.read_at(inode, &mut buf[n..], offset + n as u64)?;
+        if r == 0 {
+            return Err(eio!(format!(
+                "unexpected end of file at {}, size {}",
+                offset + n as u64,
+                inode.size()
+            )));
+        }
+        n += r;
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pax_record() {
+        let mut pax = Vec::new();
+        pax_record(&mut pax, b"path", b"a");
+        assert_eq!(pax, b"9 path=a\n");
+
+        // Length grows a digit when the record itself reaches 10 bytes.
+        let mut pax = Vec::new();
+        pax_record(&mut pax, b"path", b"ab");
+        assert_eq!(pax, b"11 path=ab\n");
+    }
+
+    #[test]
+    fn test_sparse_regions() {
+        // No hole.
+        let chunks = [(0, 4096, false), (4096, 100, false)];
+        assert_eq!(sparse_regions(4196, &chunks), None);
+        assert_eq!(sparse_regions(0, &[]), None);
+
+        // Holes in the middle and at the end, adjacent data chunks are merged.
+        let chunks = [
+            (0, 4096, false),
+            (4096, 4096, false),
+            (8192, 4096, true),
+            (12288, 4096, false),
+            (16384, 4096, true),
+        ];
+        assert_eq!(
+            sparse_regions(20480, &chunks),
+            Some(vec![(0, 8192), (12288, 4096), (20480, 0)])
+        );
+
+        // All zero, or holes not covered by any chunk.
+        assert_eq!(
+            sparse_regions(4096, &[(0, 4096, true)]),
+            Some(vec![(4096, 0)])
+        );
+        assert_eq!(
+            sparse_regions(8192, &[(4096, 4096, false)]),
+            Some(vec![(4096, 4096)])
+        );
+    }
+
+    #[test]
+    fn test_sparse_entry() {
+        let mut tar = TarWriter::new(Vec::new());
+        let entry = Entry {
+            path: b"dir/file".to_vec(),
+            kind: TYPE_REG,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 8192,
+            link: Vec::new(),
+            rdev: 0,
+            xattrs: Vec::new(),
+            sparse: Some(vec![(4096, 4096)]),
+        };
+        tar.append(&entry).unwrap();
+        let data = tar.finish().unwrap();
+
+        let records = String::from_utf8_lossy(&data[BLOCK_SIZE..BLOCK_SIZE * 2]);
+        assert!(records.contains(" GNU.sparse.name=dir/file\n"));
+        assert!(records.contains(" GNU.sparse.realsize=8192\n"));
+        let header = &data[BLOCK_SIZE * 2..BLOCK_SIZE * 3];
+        assert_eq!(&header[..25], b"dir/GNUSparseFile.0/file\0");
+        // The map block and data.
+        assert_eq!(
+            &header[124..136],
+            format!("{:011o}\0", 512 + 4096).as_bytes()
+        );
+        assert_eq!(
+            &data[BLOCK_SIZE * 3..BLOCK_SIZE * 3 + 12],
+            b"1\n4096\n4096\n"
+        );
+    }
+
+    #[test]
+    fn test_write_items() {
+        let file = |name: &str, size: u64, pieces: Vec<(u64, u64)>| Item {
+            entry: Entry {
+                path: name.as_bytes().to_vec(),
+                kind: TYPE_REG,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                mtime: 0,
+                size,
+                link: Vec::new(),
+                rdev: 0,
+                xattrs: Vec::new(),
+                sparse: None,
+            },
+            ino: 0,
+            pieces,
+        };
+        let items = vec![file("a", 3, vec![(0, 2), (2, 1)]), file("b", 0, vec![])];
+
+        // Pieces are read out of order but written in order.
+        let (order_tx, order_rx) = mpsc::sync_channel(2);
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
+        order_tx.send(rx1).unwrap();
+        order_tx.send(rx2).unwrap();
+        tx2.send(Ok(b"c".to_vec())).unwrap();
+        tx1.send(Ok(b"ab".to_vec())).unwrap();
+        let data = write_items(&items, &order_rx, Vec::new()).unwrap();
+        assert_eq!(data.len(), BLOCK_SIZE * 5);
+        assert_eq!(&data[BLOCK_SIZE..BLOCK_SIZE + 4], b"abc\0");
+        assert_eq!(&data[BLOCK_SIZE * 2..BLOCK_SIZE * 2 + 2], b"b\0");
+
+        let (order_tx, order_rx) = mpsc::sync_channel(1);
+        let (tx, rx) = mpsc::channel();
+        order_tx.send(rx).unwrap();
+        tx.send(Err(eio!())).unwrap();
+        let e = write_items(&items, &order_rx, Vec::new()).unwrap_err();
+        assert!(format!("{:#}", e).contains("failed to unpack \"a\""));
+
+        // Workers are gone.
+        drop(order_tx);
+        assert!(write_items(&items, &order_rx, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_blob_dir() {
+        let backend = blob_dir_backend(Path::new("/blobs"));
+        assert_eq!(blob_dir(&backend), Some(PathBuf::from("/blobs")));
+
+        let backend = BackendConfig::from_str("registry", r#"{"host": "localhost"}"#).unwrap();
+        assert_eq!(blob_dir(&backend), None);
+
+        // A single blob file rather than a directory.
+        let backend = BackendConfig::from_str("localfs", r#"{"blob_file": "/blob"}"#).unwrap();
+        assert_eq!(blob_dir(&backend), None);
+    }
+
+    #[test]
+    fn test_tar_entries() {
+        let mut tar = TarWriter::new(Vec::new());
+        let entry = Entry {
+            path: b"dir/".to_vec(),
+            kind: TYPE_DIR,
+            mode: 0o40755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 0,
+            link: Vec::new(),
+            rdev: 0,
+            xattrs: Vec::new(),
+            sparse: None,
+        };
+        tar.append(&entry).unwrap();
+        let data = tar.finish().unwrap();
+        assert_eq!(data.len(), BLOCK_SIZE * 3);
+        assert_eq!(&data[..4], b"dir/");
+        assert_eq!(&data[100..108], b"0000755\0");
+        assert_eq!(data[156], TYPE_DIR);
+        let sum: u64 = data[..BLOCK_SIZE]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u64
+                } else {
+                    *b as u64
+                }
+            })
+            .sum();
+        assert_eq!(&data[148..156], format!("{:06o}\0 ", sum).as_bytes());
+
+        let mut tar = TarWriter::new(Vec::new());
+        let entry = Entry {
+            path: vec![b'a'; 200],
+            kind: TYPE_REG,
+            mode: 0o100644,
+            uid: 1 << 30,
+            gid: 0,
+            mtime: 0,
+            size: 3,
+            link: Vec::new(),
+            rdev: 0,
+            xattrs: vec![(b"user.key".to_vec(), b"value".to_vec())],
+            sparse: None,
+        };
+        tar.append(&entry).unwrap();
+        tar.write_data(b"abc").unwrap();
+        tar.pad(3).unwrap();
+        let data = tar.finish().unwrap();
+        // PAX header and records, the entry header, data and the end.
+        assert_eq!(data.len(), BLOCK_SIZE * 6);
+        assert_eq!(data[156], TYPE_PAX);
+        let records = String::from_utf8_lossy(&data[BLOCK_SIZE..BLOCK_SIZE * 2]);
+        assert!(records.contains(&format!(" path={}\n", "a".repeat(200))));
+        assert!(records.contains(" uid=1073741824\n"));
+        assert!(records.contains(" SCHILY.xattr.user.key=value\n"));
+        assert_eq!(data[BLOCK_SIZE * 2 + 156], TYPE_REG);
+        assert_eq!(&data[BLOCK_SIZE * 3..BLOCK_SIZE * 3 + 3], b"abc");
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Upgrade a bootstrap to the layout the builder writes now, reusing the blobs it refers to.
+//!
+//! The bootstrap is loaded into a node tree, the same way a parent bootstrap is for a layered
+//! build, and dumped again without any blob data. So metadata added to the format since the
+//! bootstrap was built gets filled in, e.g. the extended blob table with chunk counts and
+//! blobcache sizes, which are worked out from chunks of the image. File data, the blob table
+//! and the prefetch table are kept, and mtime can't be restored for bootstraps built without
+//! it. The bootstrap is replaced atomically, and left alone if it's up to date already.
+//!
+//! RAFS v4 bootstraps can't be loaded by this version, so they have to be rebuilt instead.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::digest::Digest;
+use sha2::Sha256;
+
+use rafs::metadata::layout::*;
+use rafs::metadata::{RafsMode, RafsSuper, RAFS_DEFAULT_BLOCK_SIZE};
+use rafs::RafsIoReader;
+
+use crate::core::bootstrap::Bootstrap;
+use crate::core::context::{BuildContext, SourceType, BUF_WRITER_CAPACITY};
+use crate::core::node::{ChunkCountMap, WhiteoutSpec};
+use crate::core::prefetch::Prefetch;
+use crate::core::tree::Tree;
+use crate::validator::Validator;
+
+#[derive(Debug, Serialize)]
+pub struct UpgradeReport {
+    pub bootstrap: String,
+    /// Superblock version of the original bootstrap, e.g. `0x500`.
+    pub from_version: String,
+    /// False if the bootstrap is up to date and left untouched.
+    pub upgraded: bool,
+    /// The extended blob table was missing and got filled in.
+    pub extended_blob_table_filled: bool,
+    /// Entries of the prefetch table carried over.
+    pub prefetch_entries: usize,
+}
+
+/// Upgrade the bootstrap at `bootstrap` in place, or write the upgraded one to `output`.
+pub fn upgrade(bootstrap: &Path, output: Option<&Path>) -> Result<UpgradeReport> {
+    let version = superblock_version(bootstrap)?;
+    if version == RAFS_SUPER_VERSION_V4 {
+        bail!(
+            "RAFS v4 bootstrap {:?} can't be loaded by this version, rebuild the image instead",
+            bootstrap
+        );
+    }
+
+    let mut r: RafsIoReader =
+        Box::new(File::open(bootstrap).with_context(|| format!("failed to open {:?}", bootstrap))?);
+    let mut rs = RafsSuper {
+        mode: RafsMode::Direct,
+        digest_validate: true,
+        ..Default::default()
+    };
+    rs.load(&mut r)
+        .with_context(|| format!("failed to load bootstrap {:?}", bootstrap))?;
+    let prefetch_files = prefetch_files(&rs, &mut r)?;
+
+    let mut tree =
+        Tree::from_bootstrap(&rs, None).context("failed to build tree from bootstrap")?;
+    let mut blob_table = rs.inodes.get_blob_table().as_ref().clone();
+    let extended_blob_table_filled = blob_table.extended.entries.is_empty();
+    if extended_blob_table_filled {
+        // Chunks were numbered along with the extended blob table, number them now.
+        let mut chunks = Vec::new();
+        tree_chunks(&mut tree, &mut chunks);
+        let blobs = index_chunks(&mut chunks, blob_table.entries.len());
+        let mut table = OndiskBlobTable::new();
+        for (entry, (chunk_count, blob_cache_size)) in blob_table.entries.iter().zip(blobs) {
+            table.add(
+                entry.blob_id.clone(),
+                entry.readahead_offset,
+                entry.readahead_size,
+                chunk_count,
+                blob_cache_size,
+                entry.key_version,
+            );
+        }
+        blob_table = table;
+    }
+
+    // Written next to the output, so that it can be renamed over the output.
+    let output = output.unwrap_or(bootstrap);
+    let tmp = temp_path(output);
+    let prefetch_entries = prefetch_files.len();
+    let mut ctx = BuildContext {
+        source_type: SourceType::Directory,
+        source_path: PathBuf::from("/"),
+        // Nothing is written to blobs, so no blob gets added.
+        blob_id: String::new(),
+        f_bootstrap: Box::new(BufWriter::with_capacity(
+            BUF_WRITER_CAPACITY,
+            File::create(&tmp).with_context(|| format!("failed to create {:?}", tmp))?,
+        )),
+        f_parent_bootstrap: None,
+        compressor: rs.meta.get_compressor(),
+        digester: rs.meta.get_digester(),
+        explicit_uidgid: rs.meta.explicit_uidgid(),
+        has_mtime: rs.meta.has_mtime(),
+        whiteout_spec: WhiteoutSpec::Oci,
+        aligned_chunk: false,
+        prefetch: Prefetch::with_files(prefetch_files),
+        lower_inode_map: HashMap::new(),
+        upper_inode_map: HashMap::new(),
+        chunk_cache: HashMap::new(),
+        chunk_count_map: ChunkCountMap::default(),
+        blob_table,
+        nodes: Vec::new(),
+        encryption: None,
+    };
+
+    let result = dump(&mut ctx, &mut tree, &tmp, rs.meta.block_size).and_then(|_| {
+        if output == bootstrap && same_content(bootstrap, &tmp)? {
+            fs::remove_file(&tmp)?;
+            return Ok(false);
+        }
+        if let Ok(m) = fs::metadata(bootstrap) {
+            fs::set_permissions(&tmp, m.permissions())?;
+        }
+        fs::rename(&tmp, output).with_context(|| format!("failed to replace {:?}", output))?;
+        Ok(true)
+    });
+    let upgraded = result.map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e
+    })?;
+
+    Ok(UpgradeReport {
+        bootstrap: output.to_string_lossy().to_string(),
+        from_version: format!("{:#x}", version),
+        upgraded,
+        extended_blob_table_filled,
+        prefetch_entries,
+    })
+}
+
+fn superblock_version(bootstrap: &Path) -> Result<u32> {
+    let mut sb = OndiskSuperBlock::new();
+    File::open(bootstrap)
+        .and_then(|mut f| f.read_exact(sb.as_mut()))
+        .with_context(|| format!("failed to read superblock of {:?}", bootstrap))?;
+    if sb.magic() != RAFS_SUPER_MAGIC {
+        bail!("{:?} is not a RAFS bootstrap", bootstrap);
+    }
+    Ok(sb.version())
+}
+
+/// Paths of files and directories in the prefetch table.
+fn prefetch_files(rs: &RafsSuper, r: &mut RafsIoReader) -> Result<Vec<PathBuf>> {
+    let entries = rs.meta.prefetch_table_entries as usize;
+    if entries == 0 {
+        return Ok(Vec::new());
+    }
+    let mut table = PrefetchTable::new();
+    table
+        .load_prefetch_table_from(r, rs.meta.prefetch_table_offset, entries)
+        .map_err(|e| anyhow!("failed to load prefetch table, {:?}", e))?;
+
+    table
+        .inode_indexes
+        .iter()
+        // Index 0 is padding of the table.
+        .take_while(|ino| **ino != 0)
+        .map(|ino| {
+            rs.path_from_ino(*ino as u64)
+                .with_context(|| format!("invalid inode {} in prefetch table", ino))
+        })
+        .collect()
+}
+
+/// Chunks of all regular files under `tree`.
+fn tree_chunks<'a>(tree: &'a mut Tree, chunks: &mut Vec<&'a mut OndiskChunkInfo>) {
+    chunks.extend(tree.node.chunks.iter_mut());
+    for child in tree.children.iter_mut() {
+        tree_chunks(child, chunks);
+    }
+}
+
+/// Number chunks of each blob from 0 in order of their offsets, a chunk shared by files gets
+/// the same index. Returns chunk count and blobcache size of each of `blobs` blobs.
+fn index_chunks(chunks: &mut [&mut OndiskChunkInfo], blobs: usize) -> Vec<(u32, u64)> {
+    let mut offsets = vec![BTreeSet::new(); blobs];
+    let mut cache_sizes = vec![0u64; blobs];
+    for c in chunks.iter() {
+        if let Some(o) = offsets.get_mut(c.blob_index as usize) {
+            o.insert(c.compress_offset);
+            let end = c.decompress_offset + c.decompress_size as u64;
+            let size = &mut cache_sizes[c.blob_index as usize];
+            *size = std::cmp::max(*size, end);
+        }
+    }
+
+    let offsets = offsets
+        .into_iter()
+        .map(|o| o.into_iter().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    for c in chunks.iter_mut() {
+        if let Some(o) = offsets.get(c.blob_index as usize) {
+            // Safe to unwrap because all offsets are collected above.
+            c.index = o.binary_search(&c.compress_offset).unwrap() as u32;
+        }
+    }
+
+    offsets
+        .iter()
+        .zip(cache_sizes)
+        .map(|(o, size)| (o.len() as u32, size))
+        .collect()
+}
+
+fn dump(ctx: &mut BuildContext, tree: &mut Tree, path: &Path, block_size: u32) -> Result<()> {
+    let mut bootstrap = Bootstrap::new()?;
+    bootstrap.build(ctx, tree);
+    bootstrap.dump(ctx, Sha256::new(), 0, 0, 0)?;
+
+    // The builder sets block size as per the source type, keep the original one.
+    if block_size != RAFS_DEFAULT_BLOCK_SIZE as u32 {
+        let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut sb = OndiskSuperBlock::new();
+        f.read_exact(sb.as_mut())?;
+        sb.set_block_size(block_size);
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(sb.as_ref())?;
+        f.sync_all()?;
+    } else {
+        File::open(path)?.sync_all()?;
+    }
+
+    Validator::new(path)?
+        .check(false)
+        .context("failed to validate upgraded bootstrap")?;
+
+    Ok(())
+}
+
+fn temp_path(output: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(output.file_name().unwrap_or_default());
+    name.push(".upgrading");
+    output.with_file_name(name)
+}
+
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    Ok(fs::read(a)? == fs::read(b)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(blob_index: u32, compress_offset: u64, decompress_offset: u64) -> OndiskChunkInfo {
+        OndiskChunkInfo {
+            blob_index,
+            compress_offset,
+            decompress_offset,
+            decompress_size: 0x1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_index_chunks() {
+        let mut chunks = vec![
+            chunk(0, 300, 0x2000),
+            chunk(1, 0, 0),
+            chunk(0, 0, 0),
+            // Shared by two files.
+            chunk(0, 300, 0x2000),
+            chunk(0, 100, 0x1000),
+        ];
+        let mut refs = chunks.iter_mut().collect::<Vec<_>>();
+        let blobs = index_chunks(&mut refs, 3);
+
+        assert_eq!(blobs, vec![(3, 0x3000), (1, 0x1000), (0, 0)]);
+        let indexes = chunks.iter().map(|c| c.index).collect::<Vec<_>>();
+        assert_eq!(indexes, vec![2, 0, 0, 2, 1]);
+    }
+
+    #[test]
+    fn test_temp_path() {
+        assert_eq!(
+            temp_path(Path::new("/images/bootstrap")),
+            PathBuf::from("/images/.bootstrap.upgrading")
+        );
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verify file data of a RAFS image against the digests recorded in its bootstrap.
+//!
+//! `check` only validates the bootstrap itself. Here every chunk is read from its blob,
+//! decompressed and hashed again, and the digest of every regular file is recomputed from the
+//! chunks actually read, so that corrupted, truncated or mismatched blobs are found before an
+//! image is released. Chunks shared by multiple files are read once.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use nydus_utils::digest::RafsDigest;
+use rafs::metadata::layout::RAFS_ROOT_INODE;
+use rafs::reader::RafsReader;
+use storage::factory::BackendConfig;
+
+use crate::unpack::{missing_blobs, open_image, read_piece};
+
+/// A chunk whose data doesn't match its digest.
+#[derive(Debug, Serialize)]
+pub struct BadChunk {
+    pub path: String,
+    pub file_offset: u64,
+    pub blob_id: String,
+    pub compress_offset: u64,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// A regular file whose digest doesn't match the chunks read.
+#[derive(Debug, Serialize)]
+pub struct BadFile {
+    pub path: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// A file which couldn't be verified, e.g. its blob is missing or can't be read.
+#[derive(Debug, Serialize)]
+pub struct FileError {
+    pub path: String,
+    pub error: String,
+}
+
+/// Result of verifying an image, printed as JSON.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Whether the image passes all checks below.
+    pub valid: bool,
+    /// Whether digests of inodes in the bootstrap are consistent.
+    pub metadata_valid: bool,
+    pub blobs: Vec<String>,
+    pub missing_blobs: Vec<String>,
+    /// Regular files verified, hardlinks are counted once.
+    pub files: u64,
+    /// Distinct chunks read, and their decompressed size.
+    pub chunks: u64,
+    pub bytes: u64,
+    pub bad_chunks: Vec<BadChunk>,
+    pub bad_files: Vec<BadFile>,
+    pub errors: Vec<FileError>,
+}
+
+impl Report {
+    /// Sort findings by path, as files are verified concurrently, and decide validity.
+    fn finish(&mut self) {
+        self.bad_chunks
+            .sort_by(|a, b| (&a.path, a.file_offset).cmp(&(&b.path, b.file_offset)));
+        self.bad_files.sort_by(|a, b| a.path.cmp(&b.path));
+        self.errors.sort_by(|a, b| a.path.cmp(&b.path));
+        self.valid = self.metadata_valid
+            && self.missing_blobs.is_empty()
+            && self.bad_chunks.is_empty()
+            && self.bad_files.is_empty()
+            && self.errors.is_empty();
+    }
+}
+
+/// Outcome of verifying a regular file.
+#[derive(Default)]
+struct Checked {
+    chunks: u64,
+    bytes: u64,
+    bad_chunks: Vec<BadChunk>,
+    bad_file: Option<BadFile>,
+}
+
+/// State shared by verifying workers.
+struct Shared {
+    reader: RafsReader,
+    /// Blob indexes of missing blobs, chunks in them aren't read.
+    missing: HashSet<u32>,
+    /// Digests of chunks read, by blob index and compressed offset.
+    digests: Mutex<HashMap<(u32, u64), RafsDigest>>,
+}
+
+pub struct Verifier {
+    bootstrap: PathBuf,
+    backend: BackendConfig,
+    threads: usize,
+}
+
+impl Verifier {
+    pub fn new(bootstrap: &Path, backend: BackendConfig, threads: usize) -> Self {
+        Verifier {
+            bootstrap: bootstrap.to_path_buf(),
+            backend,
+            threads,
+        }
+    }
+
+    /// Verify inode digests in the bootstrap, then data of all regular files with worker
+    /// threads. Errors of single files are reported rather than returned.
+    pub fn verify(&self) -> Result<Report> {
+        // Chunks are hashed here, a mismatch is reported instead of failing the read.
+        let reader = open_image(&self.bootstrap, &self.backend, "verify", false)?;
+        let sb = reader.super_block();
+        let blobs = sb.inodes.get_blob_table();
+
+        let mut report = Report {
+            blobs: blobs.entries.iter().map(|e| e.blob_id.clone()).collect(),
+            missing_blobs: missing_blobs(&reader, &self.backend),
+            ..Default::default()
+        };
+        let missing = blobs
+            .entries
+            .iter()
+            .filter(|e| report.missing_blobs.contains(&e.blob_id))
+            .map(|e| e.blob_index)
+            .collect();
+
+        let root = sb.get_inode(RAFS_ROOT_INODE, false)?;
+        report.metadata_valid = sb
+            .inodes
+            .digest_validate(root, true, sb.meta.get_digester())
+            .context("failed to validate inode digests")?;
+
+        let files = collect_files(&reader)?;
+        report.files = files.len() as u64;
+
+        let (job_tx, job_rx) = mpsc::channel();
+        for file in files {
+            // Safe to unwrap because the receiver is alive.
+            job_tx.send(file).unwrap();
+        }
+        drop(job_tx);
+
+        let shared = Arc::new(Shared {
+            reader,
+            missing,
+            digests: Mutex::new(HashMap::new()),
+        });
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (result_tx, result_rx) = mpsc::channel();
+        let mut workers = Vec::with_capacity(self.threads);
+        for i in 0..self.threads {
+            let shared = shared.clone();
+            let jobs = job_rx.clone();
+            let results = result_tx.clone();
+            workers.push(
+                thread::Builder::new()
+                    .name(format!("verify_worker_{}", i))
+                    .spawn(move || verify_files(&shared, &jobs, &results))?,
+            );
+        }
+        // Results are all received once workers exit.
+        drop(result_tx);
+
+        for (path, result) in result_rx {
+            let path = path.to_string_lossy().to_string();
+            match result {
+                Ok(checked) => {
+                    report.chunks += checked.chunks;
+                    report.bytes += checked.bytes;
+                    report.bad_chunks.extend(checked.bad_chunks);
+                    report.bad_files.extend(checked.bad_file);
+                }
+                Err(e) => report.errors.push(FileError {
+                    path,
+                    error: format!("{:#}", e),
+                }),
+            }
+        }
+
+        let mut panicked = false;
+        for worker in workers {
+            panicked |= worker.join().is_err();
+        }
+        if panicked {
+            bail!("verify worker panicked");
+        }
+
+        report.finish();
+        Ok(report)
+    }
+}
+
+/// Inodes and paths of regular files, a hardlinked inode is verified through its first path.
+fn collect_files(reader: &RafsReader) -> Result<Vec<(u64, PathBuf)>> {
+    let root = reader.super_block().get_inode(RAFS_ROOT_INODE, false)?;
+    let mut seen = HashSet::new();
+    let mut dirs = vec![(root, PathBuf::from("/"))];
+    let mut files = Vec::new();
+
+    while let Some((dir, dir_path)) = dirs.pop() {
+        for idx in 0..dir.get_child_count() {
+            let child = dir.get_child_by_index(idx as u64)?;
+            let path = dir_path.join(child.name());
+            if child.is_dir() {
+                dirs.push((child, path));
+            } else if child.is_reg() && seen.insert(child.ino()) {
+                files.push((child.ino(), path));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Verify files for jobs until there's no more.
+fn verify_files(
+    shared: &Shared,
+    jobs: &Mutex<Receiver<(u64, PathBuf)>>,
+    results: &Sender<(PathBuf, Result<Checked>)>,
+) {
+    loop {
+        // The lock is only held while taking a job.
+        let job = jobs.lock().unwrap().recv();
+        let (ino, path) = match job {
+            Ok(j) => j,
+            Err(_) => break,
+        };
+        let result = verify_file(shared, ino, &path);
+        if results.send((path, result)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Hash each chunk of inode `ino` read from its blob, and the file from the chunk digests the
+/// same way the builder does.
+fn verify_file(shared: &Shared, ino: u64, path: &Path) -> Result<Checked> {
+    let sb = shared.reader.super_block();
+    let digester = sb.meta.get_digester();
+    let blobs = sb.inodes.get_blob_table();
+    let inode = sb.get_inode(ino, false)?;
+    let mut hasher = RafsDigest::hasher(digester);
+    let mut checked = Checked::default();
+
+    for idx in 0..inode.get_child_count() {
+        let chunk = inode.get_chunk_info(idx)?;
+        let blob_id = blobs
+            .entries
+            .get(chunk.blob_index() as usize)
+            .map(|e| e.blob_id.clone())
+            .ok_or_else(|| anyhow!("invalid blob index {}", chunk.blob_index()))?;
+        if shared.missing.contains(&chunk.blob_index()) {
+            bail!("blob {} not found", blob_id);
+        }
+
+        let key = (chunk.blob_index(), chunk.compress_offset());
+        let cached = shared.digests.lock().unwrap().get(&key).copied();
+        let actual = match cached {
+            Some(d) => d,
+            None => {
+                let data = read_piece(
+                    &shared.reader,
+                    inode.as_ref(),
+                    chunk.file_offset(),
+                    chunk.decompress_size() as u64,
+                )
+                .with_context(|| {
+                    format!(
+                        "failed to read chunk at {} of blob {}",
+                        chunk.compress_offset(),
+                        blob_id
+                    )
+                })?;
+                let digest = RafsDigest::from_buf(&data, digester);
+                checked.chunks += 1;
+                checked.bytes += data.len() as u64;
+                shared.digests.lock().unwrap().insert(key, digest);
+                digest
+            }
+        };
+
+        if &actual != chunk.block_id() {
+            checked.bad_chunks.push(BadChunk {
+                path: path.to_string_lossy().to_string(),
+                file_offset: chunk.file_offset(),
+                blob_id,
+                compress_offset: chunk.compress_offset(),
+                expected: chunk.block_id().to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        hasher.digest_update(actual.as_ref());
+    }
+
+    let actual = hasher.digest_finalize();
+    let expected = inode.get_digest();
+    if actual != expected {
+        checked.bad_file = Some(BadFile {
+            path: path.to_string_lossy().to_string(),
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+    }
+
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bad_chunk(path: &str, file_offset: u64) -> BadChunk {
+        BadChunk {
+            path: path.to_string(),
+            file_offset,
+            blob_id: "blob".to_string(),
+            compress_offset: 0,
+            expected: "00".to_string(),
+            actual: "11".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_report_finish() {
+        let mut report = Report {
+            metadata_valid: true,
+            files: 2,
+            ..Default::default()
+        };
+        report.finish();
+        assert!(report.valid);
+
+        report.bad_chunks = vec![
+            bad_chunk("/b", 0),
+            bad_chunk("/a", 0x10_0000),
+            bad_chunk("/a", 0),
+        ];
+        report.finish();
+        assert!(!report.valid);
+        let chunks = report
+            .bad_chunks
+            .iter()
+            .map(|c| (c.path.as_str(), c.file_offset))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec![("/a", 0), ("/a", 0x10_0000), ("/b", 0)]);
+
+        let mut report = Report {
+            metadata_valid: true,
+            missing_blobs: vec!["blob".to_string()],
+            ..Default::default()
+        };
+        report.finish();
+        assert!(!report.valid);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["valid"], false);
+        assert_eq!(json["missing_blobs"][0], "blob");
+        assert!(json["bad_files"].as_array().unwrap().is_empty());
+    }
+}
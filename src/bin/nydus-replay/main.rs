@@ -0,0 +1,271 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Replay reads recorded by `"access_trace"` of a rafs, to compare cache and backend settings
+//! under the same workload.
+
+#[macro_use(crate_authors, crate_version)]
+extern crate clap;
+#[macro_use]
+extern crate log;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::{App, Arg, ArgGroup};
+use serde::Serialize;
+
+use nydus_utils::setup_logging;
+use rafs::fs::RafsConfig;
+use rafs::metadata::RafsInode;
+use rafs::reader::RafsReader;
+use rafs::trace::{TraceRead, TraceReader};
+
+/// Where recorded reads are sent to.
+enum Target {
+    /// Files under the mountpoint of a running nydusd.
+    Mount {
+        root: PathBuf,
+        files: HashMap<PathBuf, File>,
+    },
+    /// An image opened with a rafs configuration, without mounting it.
+    Image {
+        reader: RafsReader,
+        inodes: HashMap<PathBuf, Arc<dyn RafsInode>>,
+    },
+}
+
+impl Target {
+    fn read(&mut self, r: &TraceRead, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Target::Mount { root, files } => {
+                if !files.contains_key(&r.path) {
+                    // Paths in trace are absolute within the image.
+                    let path = root.join(r.path.strip_prefix("/").unwrap_or(&r.path));
+                    let file =
+                        File::open(&path).with_context(|| format!("failed to open {:?}", path))?;
+                    files.insert(r.path.clone(), file);
+                }
+                let file = &files[&r.path];
+                Ok(file.read_at(buf, r.offset)?)
+            }
+            Target::Image { reader, inodes } => {
+                if !inodes.contains_key(&r.path) {
+                    let inode = reader
+                        .lookup(&r.path)
+                        .with_context(|| format!("failed to look up {:?}", r.path))?;
+                    inodes.insert(r.path.clone(), inode);
+                }
+                let inode = &inodes[&r.path];
+                Ok(reader.read_at(inode.as_ref(), buf, r.offset)?)
+            }
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+struct ReplaySummary {
+    reads: u64,
+    errors: u64,
+    bytes: u64,
+    elapsed_ms: u64,
+    latency_avg_us: u64,
+    latency_p50_us: u64,
+    latency_p99_us: u64,
+    latency_max_us: u64,
+}
+
+impl ReplaySummary {
+    fn new(mut latencies: Vec<u64>, errors: u64, bytes: u64, elapsed: Duration) -> Self {
+        latencies.sort_unstable();
+        let percentile = |p: usize| {
+            if latencies.is_empty() {
+                0
+            } else {
+                latencies[(latencies.len() - 1) * p / 100]
+            }
+        };
+        let total: u64 = latencies.iter().sum();
+
+        ReplaySummary {
+            reads: latencies.len() as u64,
+            errors,
+            bytes,
+            elapsed_ms: elapsed.as_millis() as u64,
+            latency_avg_us: total.checked_div(latencies.len() as u64).unwrap_or(0),
+            latency_p50_us: percentile(50),
+            latency_p99_us: percentile(99),
+            latency_max_us: latencies.last().copied().unwrap_or(0),
+        }
+    }
+}
+
+/// Send reads of `trace` to `target` one by one, waiting between them as they were recorded
+/// if `keep_timing` is set, otherwise back to back.
+fn replay(trace: &Path, target: &mut Target, keep_timing: bool) -> Result<ReplaySummary> {
+    let reads = TraceReader::open(trace).with_context(|| format!("failed to open {:?}", trace))?;
+    let mut buf = Vec::new();
+    let mut latencies = Vec::new();
+    let (mut errors, mut bytes) = (0, 0);
+    let start = Instant::now();
+
+    for r in reads {
+        let r = r.context("invalid trace")?;
+        if keep_timing {
+            let due = Duration::from_micros(r.timestamp_us);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        buf.resize(r.size as usize, 0);
+        let begin = Instant::now();
+        match target.read(&r, &mut buf) {
+            Ok(n) => {
+                latencies.push(begin.elapsed().as_micros() as u64);
+                bytes += n as u64;
+            }
+            Err(e) => {
+                warn!("failed to read {:?} at {}: {:#}", r.path, r.offset, e);
+                errors += 1;
+            }
+        }
+    }
+
+    Ok(ReplaySummary::new(
+        latencies,
+        errors,
+        bytes,
+        start.elapsed(),
+    ))
+}
+
+fn main() -> Result<()> {
+    let cmd = App::new("nydus-replay")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Replay reads recorded in a rafs access trace")
+        .arg(
+            Arg::with_name("trace")
+                .help("Access trace recorded by nydusd")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("mountpoint")
+                .long("mountpoint")
+                .short("M")
+                .help("Read files under the mountpoint of a running nydusd")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bootstrap")
+                .long("bootstrap")
+                .short("B")
+                .help(
+                    "Read the image of the bootstrap directly, with rafs configuration of --config",
+                )
+                .takes_value(true)
+                .requires("config"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("C")
+                .help("Rafs configuration file to read the image with")
+                .takes_value(true)
+                .requires("bootstrap"),
+        )
+        .group(
+            ArgGroup::with_name("target")
+                .args(&["mountpoint", "bootstrap"])
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("keep-timing")
+                .long("keep-timing")
+                .help("Wait between reads as they were recorded, rather than reading back to back"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print the summary in JSON"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .default_value("warn")
+                .help("Specify log level")
+                .possible_values(&["trace", "debug", "info", "warn", "error"])
+                .takes_value(true),
+        )
+        .get_matches();
+
+    // Safe to unwrap because it has default value and possible values are defined
+    setup_logging(None, cmd.value_of("log-level").unwrap().parse().unwrap())?;
+
+    let mut target = if let Some(root) = cmd.value_of("mountpoint") {
+        Target::Mount {
+            root: PathBuf::from(root),
+            files: HashMap::new(),
+        }
+    } else {
+        // Safe to unwrap because of the arg group and `requires`.
+        let config = cmd.value_of("config").unwrap();
+        let conf = RafsConfig::from_file(config)
+            .map_err(|e| anyhow::anyhow!("failed to load config {}, {:?}", config, e))?;
+        let bootstrap = Path::new(cmd.value_of("bootstrap").unwrap());
+        let reader = RafsReader::open(conf, "replay", bootstrap)
+            .map_err(|e| anyhow::anyhow!("failed to open image {:?}, {:?}", bootstrap, e))?;
+        Target::Image {
+            reader,
+            inodes: HashMap::new(),
+        }
+    };
+
+    // Safe to unwrap because it's required.
+    let trace = Path::new(cmd.value_of("trace").unwrap());
+    let summary = replay(trace, &mut target, cmd.is_present("keep-timing"))?;
+
+    if cmd.is_present("json") {
+        println!("{}", serde_json::to_string(&summary)?);
+    } else {
+        println!(
+            "reads {}, errors {}, bytes {}, elapsed {}ms",
+            summary.reads, summary.errors, summary.bytes, summary.elapsed_ms
+        );
+        println!(
+            "latency avg {}us, p50 {}us, p99 {}us, max {}us",
+            summary.latency_avg_us,
+            summary.latency_p50_us,
+            summary.latency_p99_us,
+            summary.latency_max_us
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let latencies = (1..=100).rev().collect::<Vec<u64>>();
+        let s = ReplaySummary::new(latencies, 2, 4096, Duration::from_millis(5));
+        assert_eq!(s.reads, 100);
+        assert_eq!(s.latency_avg_us, 50);
+        assert_eq!(s.latency_p50_us, 50);
+        assert_eq!(s.latency_p99_us, 99);
+        assert_eq!(s.latency_max_us, 100);
+
+        let s = ReplaySummary::new(Vec::new(), 1, 0, Duration::from_millis(1));
+        assert_eq!((s.reads, s.latency_p99_us, s.latency_avg_us), (0, 0, 0));
+    }
+}
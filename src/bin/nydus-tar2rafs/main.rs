@@ -0,0 +1,244 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Convert a tar stream read from stdin into a nydus blob and bootstrap, by feeding it to
+//! `nydus-image create --source-type tar -`.
+//!
+//! It works as a containerd stream processor: with `--passthrough` the layer is copied to
+//! stdout as it's read, so containerd goes on unpacking it while the nydus image of the layer
+//! is built along the way, and the layer is only read once from the registry.
+
+#[macro_use(crate_authors, crate_version)]
+extern crate clap;
+#[macro_use]
+extern crate log;
+
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use clap::{App, Arg, ArgGroup, ArgMatches};
+
+use nydus_utils::setup_logging;
+
+/// Options passed on to `nydus-image create` as they are, with a value or not.
+const VALUE_OPTIONS: &[&str] = &[
+    "bootstrap",
+    "blob",
+    "blob-dir",
+    "blob-id",
+    "parent-bootstrap",
+    "compressor",
+    "digester",
+    "whiteout-spec",
+];
+const FLAG_OPTIONS: &[&str] = &["append", "repeatable", "aligned-chunk"];
+
+/// `nydus-image` next to this binary, or the one in PATH.
+fn default_nydus_image() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("nydus-image")))
+        .filter(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from("nydus-image"))
+}
+
+fn create_args(cmd: &ArgMatches) -> Vec<OsString> {
+    // Safe to unwrap because it has default value.
+    let mut args: Vec<OsString> = vec![
+        "--log-level".into(),
+        cmd.value_of("log-level").unwrap().into(),
+        "create".into(),
+        "--source-type".into(),
+        "tar".into(),
+    ];
+    for name in VALUE_OPTIONS {
+        if let Some(value) = cmd.value_of_os(name) {
+            args.push(format!("--{}", name).into());
+            args.push(value.into());
+        }
+    }
+    for name in FLAG_OPTIONS {
+        if cmd.is_present(name) {
+            args.push(format!("--{}", name).into());
+        }
+    }
+    args.push("-".into());
+    args
+}
+
+/// Copy stdin to `converter`, and to stdout as well with `passthrough`.
+fn feed<W: Write>(converter: W, passthrough: bool) -> Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut input = stdin.lock();
+    let mut output = stdout.lock();
+    let mut converter = Some(converter);
+    let mut buf = vec![0u8; 0x100000];
+
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("failed to read tar stream"),
+        };
+        if passthrough {
+            output
+                .write_all(&buf[..n])
+                .context("failed to write tar stream to stdout")?;
+        }
+        if let Some(w) = converter.as_mut() {
+            if let Err(e) = w.write_all(&buf[..n]) {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    return Err(e).context("failed to feed tar stream to nydus-image");
+                }
+                // The converter quit early, its exit status tells why. Keep passing the
+                // stream through so that the consumer doesn't see a truncated layer.
+                warn!("nydus-image stopped reading tar stream");
+                converter = None;
+                if !passthrough {
+                    break;
+                }
+            }
+        }
+    }
+    output
+        .flush()
+        .context("failed to write tar stream to stdout")?;
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cmd = App::new("nydus-tar2rafs")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Convert a tar stream from stdin into nydus blob and bootstrap, e.g. as a containerd stream processor")
+        .arg(
+            Arg::with_name("bootstrap")
+                .long("bootstrap")
+                .help("Path of the bootstrap to write")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("blob")
+                .long("blob")
+                .help("Path of the blob to write, or to append to with --append")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("blob-dir")
+                .long("blob-dir")
+                .help("Directory to write the blob into, named by its sha256 digest")
+                .takes_value(true),
+        )
+        .group(
+            ArgGroup::with_name("blob-storage")
+                .args(&["blob", "blob-dir"])
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("blob-id")
+                .long("blob-id")
+                .help("Blob id, the sha256 digest of the blob by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("parent-bootstrap")
+                .long("parent-bootstrap")
+                .help("Bootstrap of the lower layers, to build the layer upon")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("append")
+                .long("append")
+                .help("Append data to the blob of the last layer in parent bootstrap")
+                .requires("parent-bootstrap")
+                .requires("blob"),
+        )
+        .arg(
+            Arg::with_name("compressor")
+                .long("compressor")
+                .help("How blob is compressed: none, lz4_block")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("digester")
+                .long("digester")
+                .help("How inodes and chunks are digested: blake3, sha256")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("whiteout-spec")
+                .long("whiteout-spec")
+                .help("Whiteout spec of the layer: oci, overlayfs")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("repeatable")
+                .long("repeatable")
+                .help("Produce environment independent image"),
+        )
+        .arg(
+            Arg::with_name("aligned-chunk")
+                .long("aligned-chunk")
+                .help("Align chunks into blobcache"),
+        )
+        .arg(
+            Arg::with_name("passthrough")
+                .long("passthrough")
+                .help("Copy the tar stream to stdout as it's read, as a stream processor does"),
+        )
+        .arg(
+            Arg::with_name("nydus-image")
+                .long("nydus-image")
+                .help("Path of nydus-image, the one next to this binary or in PATH by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .default_value("info")
+                .help("Specify log level")
+                .possible_values(&["trace", "debug", "info", "warn", "error"])
+                .takes_value(true),
+        )
+        .get_matches();
+
+    // Safe to unwrap because it has default value and possible values are defined
+    setup_logging(None, cmd.value_of("log-level").unwrap().parse().unwrap())?;
+
+    let nydus_image = cmd
+        .value_of("nydus-image")
+        .map(PathBuf::from)
+        .unwrap_or_else(default_nydus_image);
+    // Logs of nydus-image go to stderr, stdout is only for the stream passed through.
+    let mut child = Command::new(&nydus_image)
+        .args(create_args(&cmd))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| {
+            format!(
+                "failed to run {:?}, pass its path by --nydus-image",
+                nydus_image
+            )
+        })?;
+
+    // Safe to unwrap because stdin is piped.
+    let converter = child.stdin.take().unwrap();
+    let result = feed(converter, cmd.is_present("passthrough"));
+    let status = child.wait().context("failed to wait for nydus-image")?;
+    if !status.success() {
+        bail!("nydus-image exited with {}", status);
+    }
+    result?;
+    info!("tar stream converted");
+
+    Ok(())
+}
@@ -0,0 +1,589 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Command line client of the nydusd API.
+
+#[macro_use(crate_authors, crate_version)]
+extern crate clap;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+
+use nydus_api::client::api_call;
+
+const API_ROOT: &str = "/api/v2";
+
+struct Client {
+    sock: PathBuf,
+}
+
+impl Client {
+    /// Send a request and get the JSON response, `Value::Null` if the response has no body.
+    fn request(&self, method: &str, path: &str, body: Option<&Value>) -> Result<Value> {
+        let body = body.map(|b| b.to_string());
+        let path = format!("{}{}", API_ROOT, path);
+        let resp = api_call(&self.sock, method, &path, body.as_deref(), None)
+            .with_context(|| format!("failed to call nydusd API at {:?}", self.sock))?;
+
+        if !resp.is_success() {
+            let msg = serde_json::from_str::<Value>(&resp.body)
+                .ok()
+                .and_then(|v| v["message"].as_str().map(|m| m.to_string()))
+                .unwrap_or(resp.body);
+            bail!(
+                "{} {} failed with status {}: {}",
+                method,
+                path,
+                resp.status,
+                msg
+            );
+        }
+        if resp.body.is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_str(&resp.body).context("invalid API response")
+    }
+}
+
+/// Percent-encode a query value, slashes are kept for readability of mountpoints.
+fn encode_query(v: &str) -> String {
+    v.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Mountpoint as a path parameter, which has no leading slash.
+fn mount_path(mountpoint: &str) -> String {
+    mountpoint.trim_start_matches('/').to_string()
+}
+
+/// Render a JSON value within a table cell.
+fn cell(v: &Value) -> String {
+    match v {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(a) => a.iter().map(cell).collect::<Vec<_>>().join(","),
+        _ => v.to_string(),
+    }
+}
+
+/// Format rows as a table with aligned columns.
+fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (w, c) in widths.iter_mut().zip(row) {
+            *w = std::cmp::max(*w, c.len());
+        }
+    }
+
+    let line = |cols: Vec<&str>| {
+        cols.iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<width$}", c, width = w))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    let mut out = line(headers.to_vec());
+    for row in rows {
+        out.push('\n');
+        out.push_str(&line(row.iter().map(|c| c.as_str()).collect()));
+    }
+    out
+}
+
+/// Rows of `fields` of each object in a JSON array.
+fn object_rows(v: &Value, fields: &[&str]) -> Vec<Vec<String>> {
+    v.as_array()
+        .map(|items| {
+            items
+                .iter()
+                .map(|i| fields.iter().map(|f| cell(&i[*f])).collect())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Key/value rows of a JSON object, nested objects are flattened with dotted keys.
+fn kv_rows(prefix: &str, v: &Value, rows: &mut Vec<Vec<String>>) {
+    match v.as_object() {
+        Some(obj) => {
+            for (k, v) in obj {
+                let key = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", prefix, k)
+                };
+                kv_rows(&key, v, rows);
+            }
+        }
+        None => rows.push(vec![prefix.to_string(), cell(v)]),
+    }
+}
+
+fn print_kv(v: &Value) {
+    let mut rows = Vec::new();
+    kv_rows("", v, &mut rows);
+    println!("{}", table(&["KEY", "VALUE"], &rows));
+}
+
+fn print_list(v: &Value, headers: &[&str], fields: &[&str]) {
+    println!("{}", table(headers, &object_rows(v, fields)));
+}
+
+fn read_config(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("failed to read config {:?}", path))
+}
+
+fn mount(client: &Client, matches: &ArgMatches) -> Result<Value> {
+    // Safe to unwrap because they are required.
+    let mountpoint = matches.value_of("mountpoint").unwrap();
+    let config = read_config(Path::new(matches.value_of("config").unwrap()))?;
+    let mut cmd = json!({
+        "source": matches.value_of("source").unwrap(),
+        "fs_type": matches.value_of("type").unwrap(),
+        "config": config,
+    });
+    if let Some(files) = matches.values_of("prefetch-files") {
+        cmd["prefetch_files"] = json!(files.collect::<Vec<_>>());
+    }
+    if matches.is_present("dry-run") {
+        return client.request("POST", "/validate", Some(&cmd));
+    }
+    let method = if matches.is_present("remount") {
+        "PUT"
+    } else {
+        "POST"
+    };
+
+    client.request(
+        method,
+        &format!("/mount?mountpoint={}", encode_query(mountpoint)),
+        Some(&cmd),
+    )
+}
+
+/// Mount all file systems listed in `file` or none of them, each entry has the mountpoint
+/// and the arguments of `mount`, with `config` being path to the config file.
+fn mount_all(client: &Client, file: &Path) -> Result<Value> {
+    let list = fs::read_to_string(file).with_context(|| format!("failed to read {:?}", file))?;
+    let mut cmds: Vec<Value> =
+        serde_json::from_str(&list).with_context(|| format!("invalid mount list {:?}", file))?;
+    for cmd in cmds.iter_mut() {
+        let config = match cmd["config"].as_str() {
+            Some(c) => read_config(Path::new(c))?,
+            None => bail!("no config file for mount {}", cmd["mountpoint"]),
+        };
+        cmd["config"] = json!(config);
+        if cmd["fs_type"].is_null() {
+            cmd["fs_type"] = json!("rafs");
+        }
+    }
+
+    client.request("POST", "/mounts", Some(&json!(cmds)))
+}
+
+fn metrics(client: &Client, matches: &ArgMatches) -> Result<Value> {
+    let path = match matches.value_of("kind").unwrap() {
+        "global" => "/metrics",
+        "files" => "/metrics/files",
+        "pattern" => "/metrics/pattern",
+        "backend" => "/metrics/backend",
+        "blobcache" => "/metrics/blobcache",
+        "inflight" => "/metrics/inflight",
+        "memory" => "/metrics/memory",
+        k => bail!("unknown metrics {}", k),
+    };
+    let path = match matches.value_of("id") {
+        Some(id) => format!("{}?id={}", path, encode_query(id)),
+        None => path.to_string(),
+    };
+
+    client.request("GET", &path, None)
+}
+
+fn main() -> Result<()> {
+    let cmd = App::new("nydusctl")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Manage nydusd through its API")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("sock")
+                .long("sock")
+                .short("S")
+                .help("nydusd api socket path")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print raw JSON responses instead of tables")
+                .takes_value(false),
+        )
+        .subcommand(SubCommand::with_name("info").about("Show daemon information"))
+        .subcommand(SubCommand::with_name("list").about("List mounted file systems"))
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mount a file system, or update an existing mount with --remount")
+                .arg(
+                    Arg::with_name("mountpoint")
+                        .help("Mountpoint within nydusd, e.g. /sub")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .help("Bootstrap of rafs, or source directory of passthrough fs")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .help("Config file of the file system")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .help("File system type")
+                        .takes_value(true)
+                        .default_value("rafs")
+                        .possible_values(&["rafs", "passthrough_fs"]),
+                )
+                .arg(
+                    Arg::with_name("prefetch-files")
+                        .long("prefetch-files")
+                        .help("Files to prefetch after mounted")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("remount")
+                        .long("remount")
+                        .help("Update an existing mount")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Check the source and config without mounting")
+                        .takes_value(false)
+                        .conflicts_with("remount"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mount-all")
+                .about("Mount a list of file systems, none is mounted if any of them fails")
+                .arg(
+                    Arg::with_name("file")
+                        .help("JSON file of mounts, with mountpoint, source, config, fs_type and prefetch_files")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("umount")
+                .about("Umount a file system")
+                .arg(Arg::with_name("mountpoint").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("metrics")
+                .about("Show metrics")
+                .arg(
+                    Arg::with_name("kind")
+                        .help("Kind of metrics")
+                        .default_value("global")
+                        .possible_values(&[
+                            "global",
+                            "files",
+                            "pattern",
+                            "backend",
+                            "blobcache",
+                            "inflight",
+                            "memory",
+                        ]),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .long("id")
+                        .help("Mountpoint, or backend id, to show metrics of")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prefetch")
+                .about("Prefetch files of a mounted rafs, or all of them if none is given")
+                .arg(Arg::with_name("mountpoint").required(true))
+                .arg(Arg::with_name("files").multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("hot-files")
+                .about("Show files of a mounted rafs read most, which requires access pattern")
+                .arg(Arg::with_name("mountpoint").required(true))
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .help("Number of files to show")
+                        .takes_value(true)
+                        .default_value("20"),
+                )
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .help("Rank files by bytes read or by number of reads")
+                        .takes_value(true)
+                        .default_value("bytes")
+                        .possible_values(&["bytes", "reads"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manage blob cache")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").about("List cached blobs"))
+                .subcommand(
+                    SubCommand::with_name("purge")
+                        .about("Purge cached blobs of a mount, or a single blob, or both")
+                        .arg(
+                            Arg::with_name("mountpoint")
+                                .long("mountpoint")
+                                .takes_value(true)
+                                .required_unless("blob-id"),
+                        )
+                        .arg(Arg::with_name("blob-id").long("blob-id").takes_value(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Import downloaded blob files into blob cache of a mount")
+                        .arg(Arg::with_name("mountpoint").required(true))
+                        .arg(
+                            Arg::with_name("path")
+                                .help("A blob file named by its blob id, or a directory of them")
+                                .required(true),
+                        ),
+                ),
+        )
+        .get_matches();
+
+    // Safe to unwrap because it's required.
+    let client = Client {
+        sock: PathBuf::from(cmd.value_of("sock").unwrap()),
+    };
+    let json = cmd.is_present("json");
+
+    match cmd.subcommand() {
+        ("info", Some(_)) => {
+            let info = client.request("GET", "/daemon", None)?;
+            if json {
+                println!("{}", info);
+            } else {
+                print_kv(&info);
+            }
+        }
+        ("list", Some(_)) => {
+            let mounts = client.request("GET", "/mounts", None)?;
+            if json {
+                println!("{}", mounts);
+            } else {
+                print_list(
+                    &mounts,
+                    &[
+                        "MOUNTPOINT",
+                        "TYPE",
+                        "SOURCE",
+                        "PREFETCH",
+                        "HEALTH",
+                        "MOUNTED",
+                    ],
+                    &[
+                        "mountpoint",
+                        "backend_type",
+                        "source",
+                        "prefetch",
+                        "health",
+                        "mounted_time",
+                    ],
+                );
+            }
+        }
+        ("mount", Some(m)) => {
+            let report = mount(&client, m)?;
+            if m.is_present("dry-run") {
+                if json {
+                    println!("{}", report);
+                } else {
+                    print_list(
+                        &report["checks"],
+                        &["CHECK", "PASSED", "ERROR"],
+                        &["name", "passed", "error"],
+                    );
+                }
+                if report["valid"] != json!(true) {
+                    bail!("{} can't be mounted", m.value_of("source").unwrap());
+                }
+            }
+        }
+        ("mount-all", Some(m)) => {
+            mount_all(&client, Path::new(m.value_of("file").unwrap()))?;
+        }
+        ("umount", Some(m)) => {
+            let mountpoint = m.value_of("mountpoint").unwrap();
+            client.request(
+                "DELETE",
+                &format!("/mount?mountpoint={}", encode_query(mountpoint)),
+                None,
+            )?;
+        }
+        ("metrics", Some(m)) => {
+            let metrics = metrics(&client, m)?;
+            if json {
+                println!("{}", metrics);
+            } else if metrics.is_array() {
+                for item in metrics.as_array().unwrap() {
+                    print_kv(item);
+                    println!();
+                }
+            } else {
+                print_kv(&metrics);
+            }
+        }
+        ("prefetch", Some(m)) => {
+            let mountpoint = m.value_of("mountpoint").unwrap();
+            let files = match m.values_of("files") {
+                Some(files) => json!(files.collect::<Vec<_>>()),
+                None => json!("all"),
+            };
+            client.request(
+                "POST",
+                &format!("/mounts/{}/prefetch", mount_path(mountpoint)),
+                Some(&json!({ "files": files })),
+            )?;
+        }
+        ("hot-files", Some(m)) => {
+            let files = client.request(
+                "GET",
+                &format!(
+                    "/mounts/{}/hot-files?top={}&by={}",
+                    mount_path(m.value_of("mountpoint").unwrap()),
+                    encode_query(m.value_of("top").unwrap()),
+                    m.value_of("by").unwrap()
+                ),
+                None,
+            )?;
+            if json {
+                println!("{}", files);
+            } else {
+                print_list(
+                    &files,
+                    &["PATH", "READS", "BYTES"],
+                    &["path", "reads", "bytes"],
+                );
+            }
+        }
+        ("cache", Some(m)) => match m.subcommand() {
+            ("list", Some(_)) => {
+                let blobs = client.request("GET", "/blobcache", None)?;
+                if json {
+                    println!("{}", blobs);
+                } else {
+                    print_list(
+                        &blobs,
+                        &["BLOB_ID", "WORK_DIR", "DISK_USAGE", "REFS", "MOUNTPOINTS"],
+                        &["blob_id", "work_dir", "disk_usage", "refs", "mountpoints"],
+                    );
+                }
+            }
+            ("purge", Some(m)) => {
+                let mut query = Vec::new();
+                if let Some(mountpoint) = m.value_of("mountpoint") {
+                    query.push(format!("mountpoint={}", encode_query(mountpoint)));
+                }
+                if let Some(blob_id) = m.value_of("blob-id") {
+                    query.push(format!("blob_id={}", encode_query(blob_id)));
+                }
+                client.request("DELETE", &format!("/blobcache?{}", query.join("&")), None)?;
+            }
+            ("import", Some(m)) => {
+                // The path is resolved by nydusd, which may run in another directory.
+                let path = std::fs::canonicalize(m.value_of("path").unwrap())
+                    .context("invalid blob path")?;
+                let imported = client.request(
+                    "PUT",
+                    "/blobcache",
+                    Some(&json!({
+                        "mountpoint": m.value_of("mountpoint").unwrap(),
+                        "path": path.to_string_lossy(),
+                    })),
+                )?;
+                if json {
+                    println!("{}", imported);
+                } else {
+                    print_list(
+                        &imported,
+                        &["BLOB_ID", "PATH", "CHUNKS"],
+                        &["blob_id", "path", "chunks"],
+                    );
+                }
+            }
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(encode_query("/sub/a-b_c.d"), "/sub/a-b_c.d");
+        assert_eq!(encode_query("/a b&c"), "/a%20b%26c");
+    }
+
+    #[test]
+    fn test_table() {
+        let mounts = json!([
+            {"mountpoint": "/sub", "backend_type": "rafs", "prefetch": null},
+            {"mountpoint": "/longer", "backend_type": "passthrough_fs", "prefetch": "done"},
+        ]);
+        let rows = object_rows(&mounts, &["mountpoint", "backend_type", "prefetch"]);
+        assert_eq!(
+            table(&["MOUNTPOINT", "TYPE", "PREFETCH"], &rows),
+            "MOUNTPOINT  TYPE            PREFETCH\n\
+             /sub        rafs            -\n\
+             /longer     passthrough_fs  done"
+        );
+
+        let mut rows = Vec::new();
+        kv_rows(
+            "",
+            &json!({"id": "d1", "version": {"git_commit": "abc"}}),
+            &mut rows,
+        );
+        assert_eq!(
+            rows,
+            vec![
+                vec!["id".to_string(), "d1".to_string()],
+                vec!["version.git_commit".to_string(), "abc".to_string()],
+            ]
+        );
+    }
+}
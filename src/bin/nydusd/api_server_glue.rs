@@ -3,7 +3,9 @@
 //
 // SPDX-License-Identifier: (Apache-2.0 AND BSD-3-Clause)
 
+use std::collections::BTreeMap;
 use std::convert::From;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
@@ -14,20 +16,25 @@ use nix::unistd::Pid;
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 use nydus_api::http_endpoint::{
-    ApiError, ApiMountCmd, ApiRequest, ApiResponse, ApiResponsePayload, ApiResult, DaemonConf,
-    DaemonErrorKind, MetricsErrorKind,
+    ApiBulkMountCmd, ApiError, ApiImportBlobsCmd, ApiLogLevel, ApiMountCmd, ApiRequest,
+    ApiResponse, ApiResponsePayload, ApiRestartCmd, ApiResult, DaemonConf, DaemonConfPatch,
+    DaemonConfPatchResult, DaemonErrorKind, MetricsErrorKind, PrefetchFiles,
 };
-use nydus_utils::metrics;
+use nydus_utils::{metrics, LogFilter};
 
 use crate::daemon::{
-    DaemonError, FsBackendMountCmd, FsBackendType, FsBackendUmountCmd, NydusDaemon,
+    validate_mount, DaemonError, DaemonResult, FsBackendMountCmd, FsBackendType,
+    FsBackendUmountCmd, NydusDaemon,
 };
 #[cfg(fusedev)]
 use crate::fusedev::FusedevDaemon;
+use crate::restart;
+use crate::upgrade;
 
 pub struct ApiServer {
     to_http: Sender<ApiResponse>,
-    daemon: Arc<dyn NydusDaemon>,
+    daemon: Arc<dyn NydusDaemon + Send + Sync>,
+    api_sock: PathBuf,
 }
 
 type Result<T> = ApiResult<T>;
@@ -41,17 +48,47 @@ impl From<DaemonError> for DaemonErrorKind {
             Unsupported => DaemonErrorKind::Unsupported,
             Serde(e) => DaemonErrorKind::Serde(e),
             UnexpectedEvent(e) => DaemonErrorKind::UnexpectedEvent(format!("{:?}", e)),
+            InvalidArguments(e) => DaemonErrorKind::InvalidArguments(e),
+            NotFound => DaemonErrorKind::NotFound,
+            Busy(e) => DaemonErrorKind::Busy(e),
             o => DaemonErrorKind::Other(o.to_string()),
         }
     }
 }
 
+/// Change log level of the daemon, leaving levels of modules set by `/daemon/log-level` intact.
+fn set_global_log_level(level: log::LevelFilter) -> DaemonResult<()> {
+    let mut filter = nydus_utils::log_filter();
+    filter.level = level;
+    nydus_utils::set_log_filter(filter).map_err(|e| DaemonError::InvalidArguments(e.to_string()))
+}
+
+/// Mount commands of a batch of filesystems, as posted to `/mounts` or given by `--mounts`.
+pub fn bulk_mount_cmds(cmds: Vec<ApiBulkMountCmd>) -> DaemonResult<Vec<FsBackendMountCmd>> {
+    cmds.into_iter()
+        .map(|c| {
+            Ok(FsBackendMountCmd {
+                fs_type: FsBackendType::from_str(&c.cmd.fs_type)?,
+                mountpoint: c.mountpoint,
+                config: c.cmd.config,
+                source: c.cmd.source,
+                prefetch_files: c.cmd.prefetch_files,
+            })
+        })
+        .collect()
+}
+
 impl ApiServer {
     pub fn new(
         to_http: Sender<ApiResponse>,
-        daemon: Arc<dyn NydusDaemon>,
+        daemon: Arc<dyn NydusDaemon + Send + Sync>,
+        api_sock: &str,
     ) -> std::io::Result<Self> {
-        Ok(ApiServer { to_http, daemon })
+        Ok(ApiServer {
+            to_http,
+            daemon,
+            api_sock: PathBuf::from(api_sock),
+        })
     }
 
     fn process_request(&self, from_http: &Receiver<ApiRequest>) -> std::io::Result<()> {
@@ -62,21 +99,51 @@ impl ApiServer {
         let resp = match request {
             ApiRequest::DaemonInfo => self.daemon_info(),
             ApiRequest::Events => Self::events(),
+            ApiRequest::EventStream(since) => Self::event_stream(since),
             ApiRequest::Mount((mountpoint, info)) => self.do_mount(mountpoint, info),
             ApiRequest::Remount((mountpoint, info)) => self.do_remount(mountpoint, info),
             ApiRequest::Umount(mountpoint) => self.do_umount(mountpoint),
+            ApiRequest::Validate(cmd) => self.validate(cmd),
+            ApiRequest::BulkMount(cmds) => self.do_bulk_mount(cmds),
             ApiRequest::ConfigureDaemon(conf) => self.configure_daemon(conf),
+            ApiRequest::PatchDaemonConf(patch) => self.patch_daemon_conf(patch),
             ApiRequest::ExportGlobalMetrics(id) => Self::export_global_metrics(id),
             ApiRequest::ExportFilesMetrics(id, latest_read_files) => {
                 Self::export_files_metrics(id, latest_read_files)
             }
             ApiRequest::ExportAccessPatterns(id) => Self::export_access_patterns(id),
+            ApiRequest::ExportMountAccessPattern((mountpoint, as_prefetch_list)) => {
+                Self::export_mount_access_pattern(&mountpoint, as_prefetch_list)
+            }
+            ApiRequest::ExportMountHotFiles((mountpoint, top, by_reads)) => {
+                Self::export_mount_hot_files(&mountpoint, top, by_reads)
+            }
             ApiRequest::ExportBackendMetrics(id) => Self::export_backend_metrics(id),
             ApiRequest::ExportBlobcacheMetrics(id) => Self::export_blobcache_metrics(id),
             ApiRequest::ExportInflightMetrics => self.export_inflight_metrics(),
+            ApiRequest::ExportMountsInflight => self.mounts_inflight(),
+            ApiRequest::ExportMemoryUsage => self.memory_usage(),
+            ApiRequest::ExportFsMetrics(mountpoint) => Self::export_fs_metrics(&mountpoint),
             ApiRequest::ExportFsBackendInfo(mountpoint) => self.backend_info(&mountpoint),
+            ApiRequest::ProbeBackend(mountpoint) => self.probe_backend(&mountpoint),
+            ApiRequest::DumpMetadata((mountpoint, path)) => {
+                self.dump_metadata(&mountpoint, path.as_deref())
+            }
+            ApiRequest::ExportMounts => self.mounts(),
+            ApiRequest::PrefetchFiles((mountpoint, files)) => {
+                self.prefetch_files(&mountpoint, files)
+            }
+            ApiRequest::GetLogLevel => self.get_log_level(),
+            ApiRequest::SetLogLevel(level) => self.set_log_level(level),
+            ApiRequest::ExportCachedBlobs => self.cached_blobs(),
+            ApiRequest::PurgeCachedBlobs(mountpoint, blob_id) => {
+                self.purge_cached_blobs(mountpoint, blob_id)
+            }
+            ApiRequest::ImportBlobs(cmd) => self.import_blobs(cmd),
             ApiRequest::SendFuseFd => self.send_fuse_fd(),
+            ApiRequest::SaveState => self.save_state(),
             ApiRequest::Takeover => self.do_takeover(),
+            ApiRequest::Restart(cmd) => self.do_restart(cmd),
             ApiRequest::Exit => self.do_exit(),
         };
 
@@ -104,6 +171,12 @@ impl ApiServer {
         Ok(ApiResponsePayload::Events(events))
     }
 
+    fn event_stream(since: u64) -> ApiResponse {
+        let events = metrics::export_events_since(since)
+            .map_err(|e| ApiError::Events(format!("{:?}", e)))?;
+        Ok(ApiResponsePayload::Events(events))
+    }
+
     fn backend_info(&self, mountpoint: &str) -> ApiResponse {
         let d = self.daemon.as_ref();
         let info = d
@@ -112,6 +185,68 @@ impl ApiServer {
         Ok(ApiResponsePayload::FsBackendInfo(info))
     }
 
+    fn mounts(&self) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let mounts = d
+            .export_mounts()
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Daemon(e.into())))?;
+        Ok(ApiResponsePayload::Mounts(mounts))
+    }
+
+    fn prefetch_files(&self, mountpoint: &str, files: PrefetchFiles) -> ApiResponse {
+        let files = match files {
+            PrefetchFiles::All => None,
+            PrefetchFiles::Paths(paths) => Some(paths),
+        };
+        self.daemon
+            .prefetch_files(mountpoint, files)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))
+    }
+
+    fn probe_backend(&self, mountpoint: &str) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let probe = d
+            .probe_backend(mountpoint)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::BackendHealth(probe))
+    }
+
+    fn dump_metadata(&self, mountpoint: &str, path: Option<&str>) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let dump = d
+            .dump_metadata(mountpoint, path)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::Metadata(dump))
+    }
+
+    fn cached_blobs(&self) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let blobs = d
+            .export_cached_blobs()
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::CachedBlobs(blobs))
+    }
+
+    fn purge_cached_blobs(
+        &self,
+        mountpoint: Option<String>,
+        blob_id: Option<String>,
+    ) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        d.purge_cached_blobs(mountpoint.as_deref(), blob_id.as_deref())
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))
+    }
+
+    fn import_blobs(&self, cmd: ApiImportBlobsCmd) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let imported = d
+            .import_blobs(&cmd.mountpoint, &cmd.path)
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::ImportedBlobs(imported))
+    }
+
     fn configure_daemon(&self, conf: DaemonConf) -> ApiResponse {
         conf.log_level
             .parse::<log::LevelFilter>()
@@ -119,12 +254,145 @@ impl ApiServer {
                 error!("Invalid log level passed, {}", e);
                 ApiError::ResponsePayloadType
             })
-            .map(|v| {
-                log::set_max_level(v);
-                ApiResponsePayload::Empty
+            .and_then(|v| {
+                set_global_log_level(v).map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+                Ok(ApiResponsePayload::Empty)
             })
     }
 
+    fn get_log_level(&self) -> ApiResponse {
+        let filter = nydus_utils::log_filter();
+        let level = ApiLogLevel {
+            level: filter.level.to_string().to_lowercase(),
+            modules: filter
+                .modules
+                .iter()
+                .map(|(m, l)| (m.clone(), l.to_string().to_lowercase()))
+                .collect(),
+        };
+        serde_json::to_string(&level)
+            .map(ApiResponsePayload::LogLevel)
+            .map_err(|e| ApiError::DaemonAbnormal(DaemonError::Serde(e).into()))
+    }
+
+    fn set_log_level(&self, level: ApiLogLevel) -> ApiResponse {
+        let to_api_error = |e: DaemonError| ApiError::DaemonAbnormal(e.into());
+        let parse = |l: &str| {
+            l.parse::<log::LevelFilter>().map_err(|_| {
+                to_api_error(DaemonError::InvalidArguments(format!(
+                    "invalid log level {:?}",
+                    l
+                )))
+            })
+        };
+
+        let mut filter = LogFilter {
+            level: parse(&level.level)?,
+            modules: BTreeMap::new(),
+        };
+        for (module, l) in level.modules.iter() {
+            filter.modules.insert(module.clone(), parse(l)?);
+        }
+        nydus_utils::set_log_filter(filter)
+            .map_err(|e| to_api_error(DaemonError::InvalidArguments(e.to_string())))?;
+        info!("log level is changed to {:?}", level);
+
+        Ok(ApiResponsePayload::Empty)
+    }
+
+    fn patch_daemon_conf(&self, patch: DaemonConfPatch) -> ApiResponse {
+        let to_api_error = |e: DaemonError| ApiError::DaemonAbnormal(e.into());
+
+        // Validate all fields before applying any of them.
+        let log_level = patch
+            .log_level
+            .as_ref()
+            .map(|l| l.parse::<log::LevelFilter>())
+            .transpose()
+            .map_err(|e| {
+                to_api_error(DaemonError::InvalidArguments(format!(
+                    "invalid log level: {}",
+                    e
+                )))
+            })?;
+        if patch.backend_auth.is_some() && patch.backend_registry_token.is_some() {
+            return Err(to_api_error(DaemonError::InvalidArguments(
+                "backend_auth and backend_registry_token are exclusive".to_string(),
+            )));
+        }
+        // Backends are created from mount configs, so backend settings are kept in the mount
+        // state saved for restart and take effect then.
+        let mut backend = serde_json::Map::new();
+        let mut backend_fields = Vec::new();
+        for (name, key, value) in &[
+            (
+                "backend_timeout",
+                "timeout",
+                patch.backend_timeout.map(serde_json::Value::from),
+            ),
+            (
+                "backend_connect_timeout",
+                "connect_timeout",
+                patch.backend_connect_timeout.map(serde_json::Value::from),
+            ),
+            (
+                "backend_retry_limit",
+                "retry_limit",
+                patch.backend_retry_limit.map(serde_json::Value::from),
+            ),
+            (
+                "backend_auth",
+                "auth",
+                patch.backend_auth.clone().map(serde_json::Value::from),
+            ),
+            (
+                "backend_registry_token",
+                "registry_token",
+                patch
+                    .backend_registry_token
+                    .clone()
+                    .map(serde_json::Value::from),
+            ),
+        ] {
+            if let Some(value) = value {
+                backend.insert(key.to_string(), value.clone());
+                backend_fields.push(name.to_string());
+            }
+        }
+        let backend_configs = if backend.is_empty() {
+            None
+        } else {
+            let mgr = self.daemon.upgrade_mgr().ok_or_else(|| {
+                to_api_error(DaemonError::InvalidArguments(
+                    "backend settings are kept for restart, which needs a supervisor".to_string(),
+                ))
+            })?;
+            Some(upgrade::patch_mounts_backend(&mgr, &backend).map_err(to_api_error)?)
+        };
+
+        // Setting prefetch bandwidth is the only step which may fail, so it goes first.
+        let mut result = DaemonConfPatchResult::default();
+        if let Some(rate) = patch.prefetch_bandwidth_rate {
+            self.daemon
+                .set_prefetch_bandwidth(rate)
+                .map_err(to_api_error)?;
+            result.applied.push("prefetch_bandwidth_rate".to_string());
+        }
+        if let Some(level) = log_level {
+            set_global_log_level(level).map_err(to_api_error)?;
+            result.applied.push("log_level".to_string());
+        }
+        if let Some(configs) = backend_configs {
+            // Checked above that the upgrade manager exists.
+            upgrade::set_mounts_config(&mut self.daemon.upgrade_mgr().unwrap(), configs);
+            result.restart_required = backend_fields;
+        }
+
+        let resp =
+            serde_json::to_string(&result).map_err(|e| to_api_error(DaemonError::Serde(e)))?;
+        Ok(ApiResponsePayload::DaemonConfPatched(resp))
+    }
+
     fn export_global_metrics(id: Option<String>) -> ApiResponse {
         metrics::export_global_stats(&id)
             .map(ApiResponsePayload::FsGlobalMetrics)
@@ -156,6 +424,24 @@ impl ApiServer {
             .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
     }
 
+    fn export_mount_access_pattern(mountpoint: &str, as_prefetch_list: bool) -> ApiResponse {
+        metrics::export_mount_access_pattern(mountpoint, as_prefetch_list)
+            .map(ApiResponsePayload::FsFilesPatterns)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
+    }
+
+    fn export_mount_hot_files(mountpoint: &str, top: usize, by_reads: bool) -> ApiResponse {
+        metrics::export_hot_files(mountpoint, top, by_reads)
+            .map(ApiResponsePayload::FsFilesPatterns)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
+    }
+
+    fn export_fs_metrics(mountpoint: &str) -> ApiResponse {
+        metrics::export_fs_summary(mountpoint)
+            .map(ApiResponsePayload::FsMetrics)
+            .map_err(|e| ApiError::Metrics(MetricsErrorKind::Stats(e)))
+    }
+
     /// Detect if there is fop being hang.
     /// `ApiResponsePayload::Empty` will be converted to http status code 204, which means
     /// there is no requests being processed right now.
@@ -195,6 +481,22 @@ impl ApiServer {
         }
     }
 
+    fn mounts_inflight(&self) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let state = d
+            .export_inflight()
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::MountsInflight(state))
+    }
+
+    fn memory_usage(&self) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let usage = d
+            .export_memory_usage()
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::MemoryUsage(usage))
+    }
+
     fn send_fuse_fd(&self) -> ApiResponse {
         let d = self.daemon.as_ref();
 
@@ -203,6 +505,14 @@ impl ApiServer {
             .map_err(|e| ApiError::DaemonAbnormal(e.into()))
     }
 
+    fn save_state(&self) -> ApiResponse {
+        let d = self.daemon.as_ref();
+        let state = d
+            .save_state()
+            .map_err(|e| ApiError::DaemonAbnormal(e.into()))?;
+        Ok(ApiResponsePayload::SavedState(state))
+    }
+
     /// External supervisor wants this instance to fetch `/dev/fuse` fd. Before
     /// invoking this method, supervisor should already listens on a Unix socket and
     /// waits for connection from this instance. Then supervisor should send the *fd*
@@ -216,6 +526,20 @@ impl ApiServer {
             .map_err(|e| ApiError::DaemonAbnormal(e.into()))
     }
 
+    /// Live upgrade to a new binary without an external supervisor driving each step, the
+    /// supervisor is still needed to hold the fuse session. The http response is sent once
+    /// the new Nydusd is started, this instance exits after the new one takes over.
+    fn do_restart(&self, cmd: ApiRestartCmd) -> ApiResponse {
+        restart::restart(
+            self.daemon.clone(),
+            &self.api_sock,
+            Path::new(&cmd.binary),
+            cmd.args,
+        )
+        .map(|_| ApiResponsePayload::Empty)
+        .map_err(|e| ApiError::DaemonAbnormal(e.into()))
+    }
+
     /// External supervisor wants this instance to exit. But it can't just die leave
     /// some pending or in-flight fuse messages un-handled. So this method guarantees
     /// all fuse messages read from kernel are handled and replies are sent back.
@@ -267,6 +591,28 @@ impl ApiServer {
             .map_err(|e| ApiError::MountFailure(e.into()))
     }
 
+    fn validate(&self, cmd: ApiMountCmd) -> ApiResponse {
+        let fs_type =
+            FsBackendType::from_str(&cmd.fs_type).map_err(|e| ApiError::MountFailure(e.into()))?;
+        validate_mount(&FsBackendMountCmd {
+            fs_type,
+            mountpoint: String::new(),
+            config: cmd.config,
+            source: cmd.source,
+            prefetch_files: cmd.prefetch_files,
+        })
+        .map(ApiResponsePayload::Validation)
+        .map_err(|e| ApiError::MountFailure(e.into()))
+    }
+
+    fn do_bulk_mount(&self, cmds: Vec<ApiBulkMountCmd>) -> ApiResponse {
+        let cmds = bulk_mount_cmds(cmds).map_err(|e| ApiError::MountFailure(e.into()))?;
+        self.daemon
+            .mount_all(cmds)
+            .map(|_| ApiResponsePayload::Empty)
+            .map_err(|e| ApiError::MountFailure(e.into()))
+    }
+
     fn do_umount(&self, mountpoint: String) -> ApiResponse {
         self.daemon
             .umount(FsBackendUmountCmd { mountpoint })
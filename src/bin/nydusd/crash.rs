@@ -0,0 +1,119 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Diagnostic dump written when nydusd panics, for post-mortem of rare crashes without relying
+//! on what's left of its logs.
+
+use std::fs::{self, File};
+use std::io::{Result, Write};
+use std::panic::{self, PanicInfo};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use backtrace::Backtrace;
+use nydus_utils::metrics;
+
+use crate::daemon::NydusDaemon;
+
+/// Number of lines logged lately to be dumped.
+const RECENT_LOG_LINES: usize = 200;
+/// How long to wait for daemon state, which may be locked by the panicking thread.
+const COLLECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+static DUMPED: AtomicBool = AtomicBool::new(false);
+
+/// Write a dump into `dir` when any thread panics, before the default panic handling goes on,
+/// which aborts nydusd in release builds.
+pub fn install_panic_hook(dir: &Path, daemon: Arc<dyn NydusDaemon + Send + Sync>) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    nydus_utils::keep_recent_logs(RECENT_LOG_LINES);
+
+    let dir = dir.to_path_buf();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        // Only the first panic is dumped, others may follow as the process goes down.
+        if !DUMPED.swap(true, Ordering::SeqCst) {
+            match write_dump(&dir, daemon.clone(), info) {
+                Ok(path) => eprintln!("crash dump is written to {:?}", path),
+                Err(e) => eprintln!("failed to write crash dump, {}", e),
+            }
+        }
+        default_hook(info);
+    }));
+
+    Ok(())
+}
+
+fn write_dump(
+    dir: &Path,
+    daemon: Arc<dyn NydusDaemon + Send + Sync>,
+    info: &PanicInfo,
+) -> Result<PathBuf> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = dir.join(format!("nydusd-crash-{}-{}.txt", std::process::id(), now));
+    let mut file = File::create(&path)?;
+
+    writeln!(
+        file,
+        "nydusd {} (pid {}) thread '{}' {}",
+        env!("CARGO_PKG_VERSION"),
+        std::process::id(),
+        thread::current().name().unwrap_or("<unnamed>"),
+        info
+    )?;
+    writeln!(file, "time: {}", now)?;
+
+    // Daemon state is collected by another thread, so that a lock held by the panicking
+    // thread doesn't wedge it.
+    let (tx, rx) = channel();
+    let collector = thread::Builder::new()
+        .name("crash-dump".to_string())
+        .spawn(move || tx.send(daemon_state(daemon.as_ref())));
+    let state = match collector {
+        Ok(_) => rx
+            .recv_timeout(COLLECT_TIMEOUT)
+            .unwrap_or_else(|e| format!("unavailable, {}\n", e)),
+        Err(e) => format!("unavailable, {}\n", e),
+    };
+    write!(file, "\n== daemon ==\n{}", state)?;
+
+    writeln!(file, "\n== recent logs ==")?;
+    for line in nydus_utils::recent_logs() {
+        writeln!(file, "{}", line)?;
+    }
+
+    write!(file, "\n== backtrace ==\n{:?}", Backtrace::new())?;
+    file.sync_all()?;
+
+    Ok(path)
+}
+
+/// Daemon state, mounts and outstanding requests.
+fn daemon_state(daemon: &(dyn NydusDaemon + Send + Sync)) -> String {
+    let mut state = format!("state: {}\n", daemon.get_state());
+    match daemon.export_inflight_ops() {
+        Ok(Some(ops)) => state.push_str(&format!("inflight fuse requests: {}\n", ops)),
+        Ok(None) => state.push_str("inflight fuse requests: none\n"),
+        Err(e) => state.push_str(&format!("inflight fuse requests: unavailable, {:?}\n", e)),
+    }
+    match daemon.export_mounts() {
+        Ok(mounts) => state.push_str(&format!("mounts: {}\n", mounts)),
+        Err(e) => state.push_str(&format!("mounts: unavailable, {:?}\n", e)),
+    }
+    for (id, inflight) in metrics::backends_inflight() {
+        state.push_str(&format!(
+            "inflight backend reads of {}: {}, oldest {}ms\n",
+            id, inflight.count, inflight.oldest_age_ms
+        ));
+    }
+
+    state
+}
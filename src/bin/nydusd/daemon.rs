@@ -6,20 +6,22 @@
 
 use std::any::Any;
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::From;
 use std::fmt::{Display, Formatter};
 use std::io::Result;
 use std::ops::Deref;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::process::id;
 use std::str::FromStr;
 use std::sync::{
-    atomic::Ordering,
-    mpsc::{Receiver, Sender},
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{channel, Receiver, Sender},
     Arc, MutexGuard,
 };
 use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{error, fmt, io};
 
 use event_manager::{EventOps, EventSubscriber, Events};
@@ -37,17 +39,22 @@ use serde::{self, Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use serde_with::{serde_as, DisplayFromStr};
 
+use nydus_utils::logger::EventKind;
+use nydus_utils::metrics::{self, InflightStats, MountIoStats};
 use nydus_utils::BuildTimeInfo;
 use rafs::{
-    fs::{Rafs, RafsConfig},
+    fs::{PrefetchStatus, Rafs, RafsConfig, RafsMemoryUsage},
+    signature::SignatureStatus,
     trim_backend_config, RafsError, RafsIoRead,
 };
+use storage::cache::CachedBlob;
 
-use crate::upgrade::{self, UpgradeManager, UpgradeMgrError};
+use crate::upgrade::{self, MountState, UpgradeManager, UpgradeMgrError};
 use crate::EVENT_MANAGER_RUN;
 
 //TODO: Try to public below type from fuse-rs thus no need to redefine it here.
-type BackFileSystem = Box<dyn BackendFileSystem<Inode = u64, Handle = u64> + Send + Sync>;
+pub(crate) type BackFileSystem =
+    Box<dyn BackendFileSystem<Inode = u64, Handle = u64> + Send + Sync>;
 
 #[allow(dead_code)]
 #[derive(Debug, Hash, PartialEq, Eq, Serialize)]
@@ -117,6 +124,8 @@ pub enum DaemonError {
     Common(String),
     NotFound,
     AlreadyExists,
+    /// Resources are in use by others, try again later.
+    Busy(String),
     Serde(SerdeError),
     UpgradeManager(UpgradeMgrError),
     Vfs(VfsError),
@@ -167,7 +176,7 @@ impl From<VfsError> for DaemonError {
 
 pub type DaemonResult<T> = std::result::Result<T, DaemonError>;
 
-#[derive(Clone, Serialize, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub enum FsBackendType {
     Rafs,
     PassthroughFs,
@@ -197,7 +206,7 @@ pub struct DaemonInfo {
     pub backend_collection: FsBackendCollection,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FsBackendMountCmd {
     pub fs_type: FsBackendType,
     pub source: String,
@@ -216,16 +225,172 @@ pub struct FsBackendUmountCmd {
 pub struct FsBackendDesc {
     backend_type: FsBackendType,
     mountpoint: String,
+    source: String,
     #[serde_as(as = "DisplayFromStr")]
     mounted_time: DateTime<Local>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    remounted_time: Option<DateTime<Local>>,
     config: serde_json::Value,
+    #[serde(skip)]
+    vfs_index: u8,
+    /// Cache hits and backend reads, only filled in by `export_info()`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    io_stats: Option<MountIoStats>,
+    /// Signature verified of rafs bootstraps if required, only filled in by `export_info()` and
+    /// `export_mounts()`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<SignatureStatus>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsBackendHealth {
+    Healthy,
+    /// Reads have failed in the last minute, mostly due to storage backend errors.
+    Degraded,
+    /// Recorded as mounted but not found in vfs.
+    Missing,
+}
+
+/// Live state of a mounted filesystem backend.
+#[derive(Serialize)]
+pub struct FsBackendState {
+    #[serde(flatten)]
+    desc: FsBackendDesc,
+    /// Only available for rafs.
+    prefetch: Option<PrefetchStatus>,
+    health: FsBackendHealth,
+}
+
+/// Outstanding fuse requests and backend reads of a mounted filesystem backend.
+#[derive(Serialize)]
+pub struct MountInflightState {
+    mountpoint: String,
+    fuse_requests: InflightStats,
+    /// Only available for rafs.
+    backend_reads: Option<InflightStats>,
+}
+
+/// Outstanding requests of the daemon, fuse requests not attributed to any mount included
+/// in the total.
+#[derive(Serialize)]
+pub struct InflightState {
+    fuse_requests: InflightStats,
+    mounts: Vec<MountInflightState>,
+}
+
+/// A mount as saved for failover, with its index in vfs which inodes of the new daemon must
+/// keep.
+#[derive(Serialize)]
+pub struct SavedMountState {
+    mountpoint: String,
+    backend_type: FsBackendType,
+    source: String,
+    vfs_index: u8,
+    /// Whether the mount is still found in vfs, a mount missing there can't be restored.
+    in_vfs: bool,
+}
+
+/// Summary of state persisted by upgrade manager, to check failover readiness.
+#[derive(Serialize)]
+pub struct SavedState {
+    supervisor: String,
+    /// Fuse session fd to be sent to supervisor, None if the session is not connected.
+    fuse_fd: Option<RawFd>,
+    mounts: Vec<SavedMountState>,
+    /// Problems which would break failover.
+    problems: Vec<String>,
+    /// Seconds since the Unix epoch.
+    saved_at: u64,
+    elapsed_ms: u64,
+}
+
+/// Inodes of vfs carry index of the backend fs in the highest byte.
+pub(crate) const VFS_INDEX_SHIFT: u64 = 56;
+/// Max number of filesystems set up at the same time by `NydusDaemon::mount_all()`.
+const MOUNT_WORKERS: usize = 8;
+
+fn fuse_inflight_stats<'a>(reqs: impl Iterator<Item = &'a (u64, u64)>, now: u64) -> InflightStats {
+    reqs.fold(InflightStats::default(), |mut stats, (_, begin)| {
+        stats.count += 1;
+        stats.oldest_age_ms = std::cmp::max(stats.oldest_age_ms, now.saturating_sub(*begin) * 1000);
+        stats
+    })
+}
+
+/// Approximate memory usage of a rafs mount.
+#[derive(Serialize)]
+pub struct MountMemoryUsage {
+    mountpoint: String,
+    #[serde(flatten)]
+    usage: RafsMemoryUsage,
+}
+
+/// Resident memory of the daemon, and memory taken by subsystems of each rafs mount, which
+/// doesn't add up to the resident memory as mapped files may not be resident and allocator
+/// overhead is not accounted.
+#[derive(Serialize)]
+pub struct MemoryUsage {
+    rss_bytes: Option<u64>,
+    mounts: Vec<MountMemoryUsage>,
+}
+
+/// Resident memory of the process, from `/proc/self/statm`.
+fn process_rss() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+
+    Some(pages * page_size as u64)
+}
+
+/// A blob cache file and the rafs mounts referencing it. Mounts sharing a blobcache work_dir
+/// share the cache file of a common blob, files not used by any mount have no references.
+#[derive(Serialize)]
+pub struct CachedBlobState {
+    #[serde(flatten)]
+    blob: CachedBlob,
+    refs: usize,
+    mountpoints: Vec<String>,
+    /// A mount with the cache file in its work_dir, to purge it through.
+    #[serde(skip)]
+    owner: String,
 }
 
 #[derive(Default, Serialize, Clone)]
 pub struct FsBackendCollection(HashMap<String, FsBackendDesc>);
 
 impl FsBackendCollection {
-    fn add(&mut self, id: &str, cmd: &FsBackendMountCmd) -> DaemonResult<()> {
+    fn add(&mut self, id: &str, cmd: &FsBackendMountCmd, vfs_index: u8) -> DaemonResult<()> {
+        let desc = FsBackendDesc {
+            backend_type: cmd.fs_type.clone(),
+            mountpoint: cmd.mountpoint.clone(),
+            source: cmd.source.clone(),
+            mounted_time: chrono::Local::now(),
+            remounted_time: None,
+            config: Self::wash_config(cmd)?,
+            vfs_index,
+            io_stats: None,
+            signature: None,
+        };
+
+        self.0.insert(id.to_string(), desc);
+
+        Ok(())
+    }
+
+    fn update(&mut self, id: &str, cmd: &FsBackendMountCmd) -> DaemonResult<()> {
+        let config = Self::wash_config(cmd)?;
+        let desc = self.0.get_mut(id).ok_or(DaemonError::NotFound)?;
+
+        desc.source = cmd.source.clone();
+        desc.remounted_time = Some(chrono::Local::now());
+        desc.config = config;
+
+        Ok(())
+    }
+
+    fn wash_config(cmd: &FsBackendMountCmd) -> DaemonResult<serde_json::Value> {
         // We only wash Rafs backend now.
         let fs_config = if cmd.fs_type == FsBackendType::Rafs {
             let mut config: serde_json::Value =
@@ -243,21 +408,58 @@ impl FsBackendCollection {
             serde_json::Value::Null
         };
 
-        let desc = FsBackendDesc {
-            backend_type: cmd.fs_type.clone(),
-            mountpoint: cmd.mountpoint.clone(),
-            mounted_time: chrono::Local::now(),
-            config: fs_config,
-        };
-
-        self.0.insert(id.to_string(), desc);
-
-        Ok(())
+        Ok(fs_config)
     }
 
     fn del(&mut self, id: &str) {
         self.0.remove(id);
     }
+
+    /// Mountpoint of the backend fs at `vfs_index` of vfs.
+    pub(crate) fn mountpoint_of(&self, vfs_index: u8) -> Option<&str> {
+        self.0
+            .values()
+            .find(|desc| desc.vfs_index == vfs_index)
+            .map(|desc| desc.mountpoint.as_str())
+    }
+
+    /// Check that a new mountpoint is a normalized absolute path, and neither nested in nor
+    /// containing an existing mountpoint, which would hide one of them. So that multiple
+    /// bootstraps can be exposed under distinct directories, e.g. `/images/<name>`.
+    ///
+    /// The root mount, e.g. the filesystem given on command line, is the base of vfs which other
+    /// mounts are put on, so it doesn't overlap with any of them.
+    fn check_mountpoint(&self, mountpoint: &str) -> DaemonResult<()> {
+        let normalized = mountpoint == "/"
+            || (mountpoint.starts_with('/')
+                && mountpoint[1..]
+                    .split('/')
+                    .all(|c| !c.is_empty() && c != "." && c != ".."));
+        if !normalized {
+            return Err(DaemonError::InvalidArguments(format!(
+                "mountpoint {} must be a normalized absolute path",
+                mountpoint
+            )));
+        }
+
+        let root = Path::new("/");
+        let path = Path::new(mountpoint);
+        if let Some(existing) = self
+            .0
+            .values()
+            .map(|desc| Path::new(&desc.mountpoint))
+            .filter(|mp| *mp != root && path != root)
+            .find(|mp| path.starts_with(mp) || mp.starts_with(path))
+        {
+            return Err(DaemonError::InvalidArguments(format!(
+                "mountpoint {} overlaps with existing mountpoint {}",
+                mountpoint,
+                existing.display()
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 pub trait NydusDaemon: DaemonStateMachineSubscriber {
@@ -292,16 +494,44 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
     fn backend_collection(&self) -> MutexGuard<FsBackendCollection>;
     fn version(&self) -> BuildTimeInfo;
     fn export_info(&self) -> DaemonResult<String> {
+        let mut backend_collection = self.backend_collection().deref().clone();
+        for desc in backend_collection.0.values_mut() {
+            desc.io_stats = Some(metrics::mount_io_stats(&desc.mountpoint));
+            desc.signature = self
+                .backend_from_mountpoint(&desc.mountpoint)
+                .ok()
+                .flatten()
+                .and_then(|fs| {
+                    let rafs = fs.deref().as_any().downcast_ref::<Rafs>()?;
+                    rafs.signature()
+                });
+        }
         let response = DaemonInfo {
             version: self.version(),
             id: self.id(),
             supervisor: self.supervisor(),
             state: self.get_state(),
-            backend_collection: self.backend_collection().deref().clone(),
+            backend_collection,
         };
 
         serde_json::to_string(&response).map_err(DaemonError::Serde)
     }
+    /// Daemon state and number of mounts followed by metrics of all mounts, in Prometheus
+    /// text format.
+    fn export_prometheus(&self) -> String {
+        let mut text = format!(
+            "# HELP nydusd_state Current state of the daemon.\n\
+             # TYPE nydusd_state gauge\n\
+             nydusd_state{{state=\"{}\"}} 1\n\
+             # HELP nydusd_mounts Filesystems mounted by the daemon.\n\
+             # TYPE nydusd_mounts gauge\n\
+             nydusd_mounts {}\n",
+            self.get_state(),
+            self.backend_collection().0.len()
+        );
+        text.push_str(&metrics::export_prometheus());
+        text
+    }
     fn export_backend_info(&self, mountpoint: &str) -> DaemonResult<String> {
         let fs = self
             .backend_from_mountpoint(mountpoint)?
@@ -310,41 +540,561 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
         let rafs = any_fs
             .downcast_ref::<Rafs>()
             .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
-        let resp = serde_json::to_string(&rafs.sb.meta).map_err(DaemonError::Serde)?;
+        let resp = serde_json::to_string(&rafs.sb().meta).map_err(DaemonError::Serde)?;
         Ok(resp)
     }
 
+    fn export_mounts(&self) -> DaemonResult<String> {
+        let mut descs = self
+            .backend_collection()
+            .0
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        descs.sort_by(|a, b| a.mountpoint.cmp(&b.mountpoint));
+
+        let mounts = descs
+            .into_iter()
+            .map(|mut desc| {
+                let (prefetch, health) = match self.backend_from_mountpoint(&desc.mountpoint) {
+                    Ok(Some(fs)) => match fs.deref().as_any().downcast_ref::<Rafs>() {
+                        Some(rafs) => {
+                            desc.signature = rafs.signature();
+                            let health = if rafs.read_failing() {
+                                FsBackendHealth::Degraded
+                            } else {
+                                FsBackendHealth::Healthy
+                            };
+                            (Some(rafs.prefetch_status()), health)
+                        }
+                        None => (None, FsBackendHealth::Healthy),
+                    },
+                    _ => (None, FsBackendHealth::Missing),
+                };
+                FsBackendState {
+                    desc,
+                    prefetch,
+                    health,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_string(&mounts).map_err(DaemonError::Serde)
+    }
+
+    /// Change prefetch bandwidth limit of all rafs mounts, skipping those not using blobcache.
+    fn set_prefetch_bandwidth(&self, bandwidth_rate: u32) -> DaemonResult<()> {
+        let mountpoints = self
+            .backend_collection()
+            .0
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for mp in mountpoints {
+            let fs = match self.backend_from_mountpoint(&mp)? {
+                Some(fs) => fs,
+                None => continue,
+            };
+            if let Some(rafs) = fs.deref().as_any().downcast_ref::<Rafs>() {
+                match rafs.set_prefetch_bandwidth(bandwidth_rate) {
+                    Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                        debug!("{} can't limit prefetch bandwidth", mp)
+                    }
+                    r => r.map_err(|e| {
+                        DaemonError::Common(format!(
+                            "failed to set prefetch bandwidth of {}: {}",
+                            mp, e
+                        ))
+                    })?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prefetch `files` of a rafs mount in background, or all of its files if it's None.
+    fn prefetch_files(&self, mountpoint: &str, files: Option<Vec<String>>) -> DaemonResult<()> {
+        let files = input_prefetch_files_verify(&files)?;
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let rafs = fs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+
+        rafs.prefetch_files(files.as_deref()).map_err(|e| {
+            if e.raw_os_error() == Some(libc::ENOSYS) {
+                DaemonError::Unsupported
+            } else if e.kind() == io::ErrorKind::NotFound {
+                DaemonError::InvalidArguments(format!("prefetch file not found, {}", e))
+            } else if e.raw_os_error() == Some(libc::EBUSY) {
+                DaemonError::Busy(format!("too many files to prefetch, {}", e))
+            } else {
+                DaemonError::Common(format!("failed to prefetch files, {}", e))
+            }
+        })
+    }
+
+    /// Probe storage backend of a rafs mount, to tell whether it's reachable and accepts
+    /// the configured credentials.
+    fn probe_backend(&self, mountpoint: &str) -> DaemonResult<String> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let rafs = fs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+
+        let probe = rafs
+            .probe_backend()
+            .map_err(|e| DaemonError::Common(format!("failed to probe backend, {}", e)))?;
+        serde_json::to_string(&probe).map_err(DaemonError::Serde)
+    }
+
+    /// Dump superblock and blob table of a rafs mount, and records of the inode at `path` if
+    /// given, which is an absolute path within the mount.
+    fn dump_metadata(&self, mountpoint: &str, path: Option<&str>) -> DaemonResult<String> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let rafs = fs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+
+        let dump = rafs
+            .sb()
+            .dump(path.map(Path::new))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => DaemonError::NotFound,
+                io::ErrorKind::InvalidInput => {
+                    DaemonError::InvalidArguments(format!("invalid path {:?}", path))
+                }
+                _ => DaemonError::Common(format!("failed to dump metadata, {}", e)),
+            })?;
+        serde_json::to_string(&dump).map_err(DaemonError::Serde)
+    }
+
+    /// Collect blobs cached by all rafs mounts, skipping those not using blobcache.
+    fn cached_blobs(&self) -> DaemonResult<Vec<CachedBlobState>> {
+        let mut mountpoints = self
+            .backend_collection()
+            .0
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        mountpoints.sort();
+
+        let mut blobs: BTreeMap<(String, String), CachedBlobState> = BTreeMap::new();
+        for mp in mountpoints {
+            let fs = match self.backend_from_mountpoint(&mp)? {
+                Some(fs) => fs,
+                None => continue,
+            };
+            let rafs = match fs.deref().as_any().downcast_ref::<Rafs>() {
+                Some(rafs) => rafs,
+                None => continue,
+            };
+            let cached = match rafs.cached_blobs() {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => continue,
+                r => r.map_err(|e| {
+                    DaemonError::Common(format!("failed to list cached blobs of {}: {}", mp, e))
+                })?,
+            };
+            for blob in cached {
+                let state = blobs
+                    .entry((blob.work_dir.clone(), blob.blob_id.clone()))
+                    .or_insert_with(|| CachedBlobState {
+                        blob: blob.clone(),
+                        refs: 0,
+                        mountpoints: Vec::new(),
+                        owner: mp.clone(),
+                    });
+                if blob.open {
+                    state.refs += 1;
+                    state.mountpoints.push(mp.clone());
+                }
+            }
+        }
+
+        Ok(blobs.into_iter().map(|(_, v)| v).collect())
+    }
+
+    fn export_cached_blobs(&self) -> DaemonResult<String> {
+        serde_json::to_string(&self.cached_blobs()?).map_err(DaemonError::Serde)
+    }
+
+    /// Purge cached data of all blobs referenced by `mountpoint`, or of blob `blob_id`, or of
+    /// blob `blob_id` referenced by `mountpoint` if both are given.
+    ///
+    /// A cache file is purged for all mounts referencing it, since they share its content.
+    /// Cache files used by other processes are not purged, which fails with `Busy`.
+    fn purge_cached_blobs(
+        &self,
+        mountpoint: Option<&str>,
+        blob_id: Option<&str>,
+    ) -> DaemonResult<()> {
+        if mountpoint.is_none() && blob_id.is_none() {
+            return Err(DaemonError::InvalidArguments(
+                "either mountpoint or blob id should be specified".to_string(),
+            ));
+        }
+        if let Some(mp) = mountpoint {
+            self.backend_from_mountpoint(mp)?
+                .ok_or(DaemonError::NotFound)?;
+        }
+
+        let targets = self
+            .cached_blobs()?
+            .into_iter()
+            .filter(|b| mountpoint.map_or(true, |mp| b.mountpoints.iter().any(|m| m == mp)))
+            .filter(|b| blob_id.map_or(true, |id| b.blob.blob_id == id))
+            .collect::<Vec<_>>();
+        if targets.is_empty() && blob_id.is_some() {
+            return Err(DaemonError::NotFound);
+        }
+
+        let mut busy = Vec::new();
+        for target in targets {
+            let fs = match self.backend_from_mountpoint(&target.owner)? {
+                Some(fs) => fs,
+                None => continue,
+            };
+            if let Some(rafs) = fs.deref().as_any().downcast_ref::<Rafs>() {
+                // Other mounts of the daemon referencing the file are purged along with it.
+                match rafs.purge_blobs(Some(target.blob.blob_id.as_str())) {
+                    Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                        busy.push(target.blob.blob_id)
+                    }
+                    r => {
+                        r.map_err(|e| {
+                            DaemonError::Common(format!(
+                                "failed to purge cached blob {} of {}: {}",
+                                target.blob.blob_id, target.owner, e
+                            ))
+                        })?;
+                    }
+                }
+            }
+        }
+        if !busy.is_empty() {
+            return Err(DaemonError::Busy(format!(
+                "blobs {} are in use by other processes",
+                busy.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Import blob files at `path`, downloaded by an external downloader, into blobcache of
+    /// the rafs mount, see `Rafs::import_blobs()`.
+    fn import_blobs(&self, mountpoint: &str, path: &str) -> DaemonResult<String> {
+        let fs = self
+            .backend_from_mountpoint(mountpoint)?
+            .ok_or(DaemonError::NotFound)?;
+        let rafs = fs
+            .deref()
+            .as_any()
+            .downcast_ref::<Rafs>()
+            .ok_or_else(|| DaemonError::FsTypeMismatch("to rafs".to_string()))?;
+
+        let imported = rafs.import_blobs(Path::new(path)).map_err(|e| {
+            if e.raw_os_error() == Some(libc::ENOSYS) {
+                return DaemonError::Unsupported;
+            }
+            match e.kind() {
+                io::ErrorKind::NotFound => DaemonError::NotFound,
+                io::ErrorKind::InvalidInput => DaemonError::InvalidArguments(format!(
+                    "corrupted or truncated blob file in {}",
+                    path
+                )),
+                _ => DaemonError::Common(format!("failed to import blobs, {}", e)),
+            }
+        })?;
+        serde_json::to_string(&imported).map_err(DaemonError::Serde)
+    }
+
     fn backend_from_mountpoint(&self, mp: &str) -> DaemonResult<Option<Arc<BackFileSystem>>> {
         let r = self.get_vfs().get_rootfs(mp)?;
         Ok(r)
     }
     fn export_inflight_ops(&self) -> DaemonResult<Option<String>>;
 
+    /// Inode and begin time in seconds since epoch of fuse requests being handled.
+    fn inflight_fuse_requests(&self) -> Vec<(u64, u64)> {
+        Vec::new()
+    }
+
+    /// Count and age of outstanding fuse requests and backend reads of each mount, to tell
+    /// which mount hangs and whether it's waiting for storage backend.
+    fn export_inflight(&self) -> DaemonResult<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let reqs = self.inflight_fuse_requests();
+        let mut descs = self
+            .backend_collection()
+            .0
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        descs.sort_by(|a, b| a.mountpoint.cmp(&b.mountpoint));
+
+        let mounts = descs
+            .into_iter()
+            .map(|desc| {
+                let index = desc.vfs_index as u64;
+                let fuse_requests = fuse_inflight_stats(
+                    reqs.iter()
+                        .filter(|(inode, _)| inode >> VFS_INDEX_SHIFT == index),
+                    now,
+                );
+                let backend_reads = match desc.backend_type {
+                    FsBackendType::Rafs => metrics::backend_inflight(&desc.mountpoint).ok(),
+                    FsBackendType::PassthroughFs => None,
+                };
+                MountInflightState {
+                    mountpoint: desc.mountpoint,
+                    fuse_requests,
+                    backend_reads,
+                }
+            })
+            .collect();
+
+        let state = InflightState {
+            fuse_requests: fuse_inflight_stats(reqs.iter(), now),
+            mounts,
+        };
+        serde_json::to_string(&state).map_err(DaemonError::Serde)
+    }
+
+    /// Memory taken by subsystems of each rafs mount, to tell which one is bloating.
+    fn export_memory_usage(&self) -> DaemonResult<String> {
+        let mut mountpoints = self
+            .backend_collection()
+            .0
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        mountpoints.sort();
+
+        let mut mounts = Vec::new();
+        for mp in mountpoints {
+            let fs = match self.backend_from_mountpoint(&mp)? {
+                Some(fs) => fs,
+                None => continue,
+            };
+            if let Some(rafs) = fs.deref().as_any().downcast_ref::<Rafs>() {
+                mounts.push(MountMemoryUsage {
+                    mountpoint: mp,
+                    usage: rafs.memory_usage(),
+                });
+            }
+        }
+
+        let usage = MemoryUsage {
+            rss_bytes: process_rss(),
+            mounts,
+        };
+        serde_json::to_string(&usage).map_err(DaemonError::Serde)
+    }
+
+    /// Fd of the fuse session, which is handed over to the new daemon on failover.
+    fn fuse_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Make upgrade manager persist daemon state right away, and summarize what's saved, so
+    /// that failover readiness can be checked before a real upgrade.
+    fn save_state(&self) -> DaemonResult<String> {
+        let supervisor = match (self.supervisor(), self.upgrade_mgr().is_some()) {
+            (Some(s), true) => s,
+            _ => return Err(DaemonError::Unsupported),
+        };
+        if self.get_state() != DaemonState::RUNNING {
+            return Err(DaemonError::NotReady);
+        }
+
+        let begin = Instant::now();
+        self.save()?;
+        let elapsed_ms = begin.elapsed().as_millis() as u64;
+
+        let mut descs = self
+            .backend_collection()
+            .0
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        descs.sort_by_key(|d| d.vfs_index);
+        let mut problems = Vec::new();
+        let mut mounts = Vec::with_capacity(descs.len());
+        for desc in descs {
+            let in_vfs = self.backend_from_mountpoint(&desc.mountpoint)?.is_some();
+            if !in_vfs {
+                problems.push(format!("{} is not found in vfs", desc.mountpoint));
+            }
+            mounts.push(SavedMountState {
+                mountpoint: desc.mountpoint,
+                backend_type: desc.backend_type,
+                source: desc.source,
+                vfs_index: desc.vfs_index,
+                in_vfs,
+            });
+        }
+        let fuse_fd = self.fuse_fd();
+        if fuse_fd.is_none() {
+            problems.push("fuse session is not connected".to_string());
+        }
+
+        let state = SavedState {
+            supervisor,
+            fuse_fd,
+            mounts,
+            problems,
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            elapsed_ms,
+        };
+        info!(
+            "saved daemon state with {} mounts in {}ms",
+            state.mounts.len(),
+            elapsed_ms
+        );
+        serde_json::to_string(&state).map_err(DaemonError::Serde)
+    }
+
     // NOTE: This method is not thread-safe, however, it is acceptable as
     // mount/umount/remount/restore_mount is invoked from single thread in FSM
     fn mount(&self, cmd: FsBackendMountCmd) -> DaemonResult<()> {
+        self.check_mount(&cmd)?;
+        let backend = fs_backend_factory(&cmd)?;
+        self.mount_backend(cmd, backend)?;
+        Ok(())
+    }
+
+    /// Check that `cmd` can be mounted before setting up its backend.
+    fn check_mount(&self, cmd: &FsBackendMountCmd) -> DaemonResult<()> {
         if self.backend_from_mountpoint(&cmd.mountpoint)?.is_some() {
             return Err(DaemonError::AlreadyExists);
         }
-        let backend = fs_backend_factory(&cmd)?;
+        self.backend_collection().check_mountpoint(&cmd.mountpoint)
+    }
+
+    /// Mount `backend` set up with `cmd` into vfs, returns its index in vfs.
+    fn mount_backend(&self, cmd: FsBackendMountCmd, backend: BackFileSystem) -> DaemonResult<u8> {
         let index = self.get_vfs().mount(backend, &cmd.mountpoint)?;
         info!("rafs mounted at {}", &cmd.mountpoint);
-        self.backend_collection().add(&cmd.mountpoint, &cmd)?;
+        self.backend_collection()
+            .add(&cmd.mountpoint, &cmd, index)?;
+        metrics::record_event(
+            EventKind::Mount,
+            &cmd.mountpoint,
+            format!("mounted {}", cmd.source),
+        );
 
         // Add mounts opaque to UpgradeManager
         if let Some(mut mgr_guard) = self.upgrade_mgr() {
             upgrade::add_mounts_state(&mut mgr_guard, cmd, index)?;
         }
 
+        Ok(index)
+    }
+
+    /// Mount all filesystems in `cmds` or none of them, those already mounted are umounted in
+    /// reverse order if one fails.
+    ///
+    /// Bootstraps are loaded and storage backends initialized for different filesystems in
+    /// parallel, see `fs_backends_factory()`, then they are mounted into vfs in order.
+    fn mount_all(&self, cmds: Vec<FsBackendMountCmd>) -> DaemonResult<()> {
+        if cmds.is_empty() {
+            return Err(DaemonError::InvalidArguments(
+                "no filesystem to mount".to_string(),
+            ));
+        }
+        // Fail fast before setting up any of them.
+        for cmd in cmds.iter() {
+            self.check_mount(cmd)
+                .map_err(|e| mount_error(&cmd.mountpoint, e))?;
+        }
+
+        let begin = Instant::now();
+        let backends = fs_backends_factory(&cmds);
+        info!(
+            "set up {} filesystems in {}ms",
+            cmds.len(),
+            begin.elapsed().as_millis()
+        );
+
+        let mut mounted: Vec<String> = Vec::with_capacity(cmds.len());
+        for (cmd, backend) in cmds.into_iter().zip(backends) {
+            let mountpoint = cmd.mountpoint.clone();
+            // Mountpoints in `cmds` may overlap with each other.
+            let r = self
+                .check_mount(&cmd)
+                .and(backend)
+                .and_then(|backend| self.mount_backend(cmd, backend));
+            if let Err(e) = r {
+                warn!(
+                    "failed to mount {}, rolling back {} mounts",
+                    mountpoint,
+                    mounted.len()
+                );
+                for m in mounted.into_iter().rev() {
+                    self.umount(FsBackendUmountCmd { mountpoint: m })
+                        .unwrap_or_else(|e| error!("failed to roll back mount, {}", e));
+                }
+                return Err(mount_error(&mountpoint, e));
+            }
+            mounted.push(mountpoint);
+        }
+
         Ok(())
     }
 
+    /// Mount filesystems saved by the previous daemon at the same vfs indexes, in a daemon
+    /// with nothing mounted yet.
+    ///
+    /// Bootstraps are loaded in parallel like `mount_all()`. Nothing is rolled back if one
+    /// fails, since the daemon can't take over anyway.
+    fn restore_mounts(&self, mounts: Vec<MountState>) -> DaemonResult<()> {
+        if !self.backend_collection().0.is_empty() {
+            return Err(upgrade::restore_error(
+                "filesystems are mounted before restoring".to_string(),
+            ));
+        }
+
+        let cmds = mounts.iter().map(|m| m.cmd.clone()).collect::<Vec<_>>();
+        let backends = fs_backends_factory(&cmds);
+        upgrade::restore_vfs_indexes(
+            self.get_vfs(),
+            mounts.into_iter().zip(backends).collect(),
+            |state, backend| {
+                let mountpoint = state.cmd.mountpoint.clone();
+                backend
+                    .and_then(|backend| self.mount_backend(state.cmd, backend))
+                    .map_err(|e| mount_error(&mountpoint, e))
+            },
+        )
+    }
+
     fn remount(&self, cmd: FsBackendMountCmd) -> DaemonResult<()> {
         let rootfs = self
             .backend_from_mountpoint(&cmd.mountpoint)?
             .ok_or(DaemonError::NotFound)?;
         let rafs_config = RafsConfig::from_str(&&cmd.config)?;
         let mut bootstrap = RafsIoRead::from_file(&&cmd.source)?;
+        let signature = Rafs::verify_signature(&rafs_config, &cmd.source, &mut bootstrap)?;
         let any_fs = rootfs.deref().as_any();
         let rafs = any_fs
             .downcast_ref::<Rafs>()
@@ -355,6 +1105,14 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
                 RafsError::Unsupported => DaemonError::Unsupported,
                 e => DaemonError::Rafs(e),
             })?;
+        rafs.set_signature(signature);
+
+        self.backend_collection().update(&cmd.mountpoint, &cmd)?;
+        metrics::record_event(
+            EventKind::Remount,
+            &cmd.mountpoint,
+            format!("remounted {}", cmd.source),
+        );
 
         // Update mounts opaque from UpgradeManager
         if let Some(mut mgr_guard) = self.upgrade_mgr() {
@@ -371,6 +1129,7 @@ pub trait NydusDaemon: DaemonStateMachineSubscriber {
         self.get_vfs().umount(&cmd.mountpoint)?;
 
         self.backend_collection().del(&cmd.mountpoint);
+        metrics::record_event(EventKind::Umount, &cmd.mountpoint, "umounted".to_string());
 
         // Remove mount opaque from UpgradeManager
         if let Some(mut mgr_guard) = self.upgrade_mgr() {
@@ -394,20 +1153,109 @@ fn input_prefetch_files_verify(input: &Option<Vec<String>>) -> DaemonResult<Opti
     if let Some(files) = &prefetch_files {
         for f in files.iter() {
             if !f.starts_with(Path::new("/")) {
-                return Err(DaemonError::Common("Illegal prefetch list".to_string()));
+                return Err(DaemonError::InvalidArguments(format!(
+                    "Illegal prefetch list, {:?} is not an absolute path",
+                    f
+                )));
             }
         }
     }
 
     Ok(prefetch_files)
 }
+/// Check whether a filesystem can be mounted with `cmd` without mounting it, the mountpoint
+/// is ignored.
+pub fn validate_mount(cmd: &FsBackendMountCmd) -> DaemonResult<String> {
+    let prefetch_files = input_prefetch_files_verify(&cmd.prefetch_files)?;
+    let validation = match cmd.fs_type {
+        FsBackendType::Rafs => {
+            let v = Rafs::validate(&cmd.config, &cmd.source, prefetch_files.as_deref());
+            serde_json::to_value(&v).map_err(DaemonError::Serde)?
+        }
+        FsBackendType::PassthroughFs => {
+            let error = match Path::new(&cmd.source).metadata() {
+                Ok(m) if m.is_dir() => None,
+                Ok(_) => Some(format!("{} is not a directory", cmd.source)),
+                Err(e) => Some(format!("invalid source {}, {}", cmd.source, e)),
+            };
+            serde_json::json!({
+                "valid": error.is_none(),
+                "checks": [{"name": "source", "passed": error.is_none(), "error": error}],
+            })
+        }
+    };
+
+    Ok(validation.to_string())
+}
+
+/// Error of mounting `mountpoint` as one of bulk mounts.
+fn mount_error(mountpoint: &str, e: DaemonError) -> DaemonError {
+    match e {
+        DaemonError::InvalidArguments(s) => {
+            DaemonError::InvalidArguments(format!("{}, {}", mountpoint, s))
+        }
+        e => DaemonError::DaemonFailure(format!("failed to mount {}, {}", mountpoint, e)),
+    }
+}
+
+/// Set up backends of `cmds` by at most `MOUNT_WORKERS` threads, results are in the order of
+/// `cmds`.
+///
+/// Loading a bootstrap and initializing its storage backend mostly wait for disks and network,
+/// doing that for many filesystems one by one slows down daemons serving lots of images at boot.
+fn fs_backends_factory(cmds: &[FsBackendMountCmd]) -> Vec<DaemonResult<BackFileSystem>> {
+    let workers = std::cmp::min(cmds.len(), MOUNT_WORKERS);
+    if workers < 2 {
+        return cmds.iter().map(fs_backend_factory).collect();
+    }
+
+    let cmds = Arc::new(cmds.to_vec());
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = channel();
+    let mut handles = Vec::with_capacity(workers);
+    for num in 0..workers {
+        let (cmds, next, tx) = (cmds.clone(), next.clone(), tx.clone());
+        let handle = thread::Builder::new()
+            .name(format!("mount_worker_{}", num))
+            .spawn(move || loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                if idx >= cmds.len() || tx.send((idx, fs_backend_factory(&cmds[idx]))).is_err() {
+                    break;
+                }
+            });
+        match handle {
+            Ok(h) => handles.push(h),
+            Err(e) => warn!("failed to start mount worker, {}", e),
+        }
+    }
+    drop(tx);
+
+    let mut results: Vec<Option<DaemonResult<BackFileSystem>>> =
+        (0..cmds.len()).map(|_| None).collect();
+    for (idx, r) in rx.iter() {
+        results[idx] = Some(r);
+    }
+    for h in handles {
+        h.join()
+            .unwrap_or_else(|e| error!("Thread might panic, {:?}", e));
+    }
+    // Left over if no worker is started or one panics.
+    results
+        .into_iter()
+        .enumerate()
+        .map(|(idx, r)| r.unwrap_or_else(|| fs_backend_factory(&cmds[idx])))
+        .collect()
+}
+
 fn fs_backend_factory(cmd: &FsBackendMountCmd) -> DaemonResult<BackFileSystem> {
     let prefetch_files = input_prefetch_files_verify(&cmd.prefetch_files)?;
     match cmd.fs_type {
         FsBackendType::Rafs => {
             let rafs_config = RafsConfig::from_str(cmd.config.as_str())?;
             let mut bootstrap = RafsIoRead::from_file(&cmd.source)?;
+            let signature = Rafs::verify_signature(&rafs_config, &cmd.source, &mut bootstrap)?;
             let mut rafs = Rafs::new(rafs_config, &cmd.mountpoint, &mut bootstrap)?;
+            rafs.set_signature(signature);
             rafs.import(bootstrap, prefetch_files)?;
             info!("Rafs imported");
             Ok(Box::new(rafs))
@@ -583,6 +1431,7 @@ impl DaemonStateMachineContext {
                     "State machine(pid={}): from {:?} to {:?}, input [{:?}], output [{:?}]",
                     &self.pid, last, cur, input, &action
                 );
+                let transition = format!("from {:?} to {:?}, input {:?}", last, cur, input);
                 let r = match action {
                     Some(a) => match a {
                         StartService => d.start().map(|r| {
@@ -618,6 +1467,9 @@ impl DaemonStateMachineContext {
                     self.sm = sm_rollback;
                     e
                 });
+                if r.is_ok() {
+                    metrics::record_event(EventKind::StateChange, "", transition);
+                }
 
                 // Safe to unwrap because channel is never closed
                 self.result_sender.send(r).unwrap();
@@ -635,6 +1487,11 @@ mod tests {
         assert_eq!(stat, DaemonState::INIT);
     }
 
+    #[test]
+    fn it_should_get_process_rss() {
+        assert!(process_rss().unwrap() > 0);
+    }
+
     #[test]
     fn it_should_convert_str_to_fsbackendtype() {
         let backend_type: FsBackendType = "rafs".parse().unwrap();
@@ -654,6 +1511,7 @@ mod tests {
                     source: "testsource".to_string(),
                     prefetch_files: Some(vec!["testfile".to_string()]),
                 },
+                1,
             )
             .is_err()
         {
@@ -665,6 +1523,82 @@ mod tests {
         assert_eq!(col.0.len(), 0);
     }
 
+    #[test]
+    fn it_should_update_backend() {
+        let mut col: FsBackendCollection = Default::default();
+        let mut cmd = FsBackendMountCmd {
+            fs_type: FsBackendType::Rafs,
+            config: "{\"config\": \"test\"}".to_string(),
+            mountpoint: "/test".to_string(),
+            source: "testsource".to_string(),
+            prefetch_files: None,
+        };
+        col.add("/test", &cmd, 1).unwrap();
+        assert!(col.0["/test"].remounted_time.is_none());
+
+        cmd.source = "newsource".to_string();
+        col.update("/test", &cmd).unwrap();
+        assert_eq!(col.0["/test"].source, "newsource");
+        assert!(col.0["/test"].remounted_time.is_some());
+        assert!(col.update("/other", &cmd).is_err());
+    }
+
+    #[test]
+    fn it_should_count_inflight_fuse_requests() {
+        let index = 2u64;
+        let reqs = vec![
+            ((index << VFS_INDEX_SHIFT) | 5, 100),
+            (1, 90),
+            ((index << VFS_INDEX_SHIFT) | 7, 95),
+        ];
+        let stats = fuse_inflight_stats(
+            reqs.iter()
+                .filter(|(inode, _)| inode >> VFS_INDEX_SHIFT == index),
+            110,
+        );
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.oldest_age_ms, 15000);
+        assert_eq!(fuse_inflight_stats(reqs.iter(), 110).oldest_age_ms, 20000);
+        assert_eq!(
+            fuse_inflight_stats([].iter(), 110),
+            InflightStats::default()
+        );
+    }
+
+    #[test]
+    fn it_should_check_mountpoint() {
+        let mut col: FsBackendCollection = Default::default();
+        let cmd = |mountpoint: &str| FsBackendMountCmd {
+            fs_type: FsBackendType::PassthroughFs,
+            config: String::new(),
+            mountpoint: mountpoint.to_string(),
+            source: "testsource".to_string(),
+            prefetch_files: None,
+        };
+
+        for invalid in &["images/a", "/images/../a", "/images/./a", "/images/a/", ""] {
+            assert!(col.check_mountpoint(invalid).is_err(), "{}", invalid);
+        }
+
+        col.check_mountpoint("/images/a").unwrap();
+        col.add("/images/a", &cmd("/images/a"), 1).unwrap();
+        col.check_mountpoint("/images/b").unwrap();
+        col.check_mountpoint("/images/ab").unwrap();
+        col.add("/images/b", &cmd("/images/b"), 2).unwrap();
+
+        assert!(col.check_mountpoint("/images/a/sub").is_err());
+        assert!(col.check_mountpoint("/images").is_err());
+
+        col.del("/images/a");
+        col.check_mountpoint("/images/a/sub").unwrap();
+
+        // Other mounts are put on the root mount.
+        col.check_mountpoint("/").unwrap();
+        col.add("/", &cmd("/"), 0).unwrap();
+        col.check_mountpoint("/images/c").unwrap();
+        assert!(col.check_mountpoint("/images/b/sub").is_err());
+    }
+
     #[test]
     fn it_should_verify_prefetch_files() {
         match input_prefetch_files_verify(&Some(vec!["/etc/passwd".to_string()])) {
@@ -723,4 +1657,28 @@ mod tests {
             panic!("failed to create rafs backend")
         }
     }
+
+    #[test]
+    fn it_should_set_up_backends_in_order() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let cmds = (0..MOUNT_WORKERS * 2 + 1)
+            .map(|i| FsBackendMountCmd {
+                fs_type: FsBackendType::PassthroughFs,
+                config: "".to_string(),
+                mountpoint: format!("/mnt{}", i),
+                source: if i % 3 == 0 {
+                    "/nonexistent/source".to_string()
+                } else {
+                    dir.as_path().to_str().unwrap().to_string()
+                },
+                prefetch_files: None,
+            })
+            .collect::<Vec<_>>();
+
+        let backends = fs_backends_factory(&cmds);
+        assert_eq!(backends.len(), cmds.len());
+        for (i, b) in backends.iter().enumerate() {
+            assert_eq!(b.is_ok(), i % 3 != 0, "backend {}", i);
+        }
+    }
 }
@@ -4,14 +4,19 @@
 // SPDX-License-Identifier: (Apache-2.0 AND BSD-3-Clause)
 
 use std::any::Any;
+use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
-use std::fs::metadata;
+use std::fs::{metadata, File, OpenOptions};
 use std::io::Result;
+use std::mem::size_of;
 use std::ops::Deref;
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::ptr::read_unaligned;
+use std::slice;
 use std::sync::{
     atomic::{AtomicI32, AtomicU64, Ordering},
     mpsc::{channel, Receiver, Sender},
@@ -28,16 +33,22 @@ use fuse_rs::api::{
     Vfs,
 };
 
-use fuse_rs::abi::linux_abi::{InHeader, OutHeader};
+use fuse_rs::abi::linux_abi::{
+    FsOptions, InHeader, InitIn, LkIn, LkOut, Opcode, OutHeader, ReadIn,
+};
+use fuse_rs::transport::{FuseBuf, Reader, Writer};
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::upgrade::{self, FailoverPolicy, UpgradeManager};
+use crate::upgrade::{self, FailoverPolicy, FuseInit, UpgradeManager};
 use crate::{daemon, exit_event_manager};
 use daemon::{
-    DaemonError, DaemonResult, DaemonState, DaemonStateMachineContext, DaemonStateMachineInput,
-    DaemonStateMachineSubscriber, FsBackendCollection, FsBackendMountCmd, NydusDaemon, Trigger,
+    BackFileSystem, DaemonError, DaemonResult, DaemonState, DaemonStateMachineContext,
+    DaemonStateMachineInput, DaemonStateMachineSubscriber, FsBackendCollection, FsBackendMountCmd,
+    NydusDaemon, Trigger, VFS_INDEX_SHIFT,
 };
-use nydus_utils::{BuildTimeInfo, FuseChannel, FuseSession};
+use nydus_utils::request_id::{self, RequestId};
+use nydus_utils::{BuildTimeInfo, FuseChannel, FuseSession, SplicePipe, FUSE_OUT_HEADER_SIZE};
+use rafs::fs::Rafs;
 
 #[derive(Serialize)]
 struct FuseOp {
@@ -45,6 +56,7 @@ struct FuseOp {
     opcode: u32,
     unique: u64,
     timestamp_secs: u64,
+    request_id: Option<RequestId>,
 }
 
 #[derive(Default, Clone, Serialize)]
@@ -63,7 +75,187 @@ impl Default for FuseOp {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            request_id: None,
+        }
+    }
+}
+
+/// How fcntl and flock advisory locks on files of the mounts are handled. It's the same for all
+/// mounts, as it's negotiated once for the fuse session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileLocks {
+    /// Locks are granted by the kernel as on a local filesystem, only visible to processes of
+    /// the same kernel.
+    Local,
+    /// Locks are forwarded to nydusd, which grants all of them at once, even conflicting ones.
+    Emulated,
+    /// Locks are forwarded to nydusd, which refuses them with ENOSYS.
+    Disabled,
+}
+
+impl FileLocks {
+    /// Fuse options to request on INIT, so that the kernel forwards locks if they are handled
+    /// by nydusd.
+    pub fn fuse_options(self) -> FsOptions {
+        match self {
+            FileLocks::Local => FsOptions::empty(),
+            FileLocks::Emulated | FileLocks::Disabled => {
+                FsOptions::POSIX_LOCKS | FsOptions::FLOCK_LOCKS
+            }
+        }
+    }
+}
+
+impl TryFrom<&str> for FileLocks {
+    type Error = std::io::Error;
+
+    fn try_from(l: &str) -> std::result::Result<Self, Self::Error> {
+        match l {
+            "local" => Ok(FileLocks::Local),
+            "emulated" => Ok(FileLocks::Emulated),
+            "disabled" => Ok(FileLocks::Disabled),
+            x => Err(einval!(x)),
+        }
+    }
+}
+
+/// Reply granting lock request `msg`, None if it's not a lock request. `GETLK` is told there
+/// is no conflicting lock, `SETLK` and `SETLKW` for fcntl and flock locks succeed.
+fn grant_lock(msg: &[u8]) -> Option<(InHeader, Vec<u8>)> {
+    if msg.len() < size_of::<InHeader>() + size_of::<LkIn>() {
+        return None;
+    }
+    // Safe because the message is long enough and both are plain old data.
+    let (ih, arg) = unsafe {
+        (
+            read_unaligned(msg.as_ptr() as *const InHeader),
+            read_unaligned(msg[size_of::<InHeader>()..].as_ptr() as *const LkIn),
+        )
+    };
+    if ih.opcode == Opcode::Setlk as u32 || ih.opcode == Opcode::Setlkw as u32 {
+        Some((ih, Vec::new()))
+    } else if ih.opcode == Opcode::Getlk as u32 {
+        let mut out = LkOut { lk: arg.lk };
+        out.lk.type_ = libc::F_UNLCK as u32;
+        // Safe because `LkOut` is plain old data.
+        let data =
+            unsafe { slice::from_raw_parts(&out as *const LkOut as *const u8, size_of::<LkOut>()) };
+        Some((ih, data.to_vec()))
+    } else {
+        None
+    }
+}
+
+/// Replies of rafs reads written into the fuse device without going through the fuse server.
+///
+/// Data of a read reply is otherwise copied from cache files or chunk buffers into a userspace
+/// buffer, then copied again into the fuse device, which caps throughput of large reads.
+/// If splicing is enabled, reads served from uncompressed cache files without validation are
+/// spliced straight from cache files. Other reads spanning chunks are replied with the chunk
+/// buffers gathered by `writev()`, and the rest go through the fuse server as usual.
+struct ReadReplier {
+    vfs: Arc<Vfs>,
+    backends: Arc<Mutex<FsBackendCollection>>,
+    pipe: Option<SplicePipe>,
+}
+
+impl ReadReplier {
+    fn backend(&self, nodeid: u64) -> Option<Arc<BackFileSystem>> {
+        let backends = self.backends.lock().unwrap();
+        let mountpoint = backends.mountpoint_of((nodeid >> VFS_INDEX_SHIFT) as u8)?;
+        self.vfs.get_rootfs(mountpoint).ok().flatten()
+    }
+
+    /// Reply the fuse request `msg` if it's a read whose data can be spliced or gathered from
+    /// chunk buffers, returns false if it's left to the fuse server.
+    fn reply(&self, ch: &FuseChannel, msg: &[u8], metrics_hook: &dyn MetricsHook) -> bool {
+        if msg.len() < size_of::<InHeader>() + size_of::<ReadIn>() {
+            return false;
+        }
+        // Safe because the message is long enough and both are plain old data.
+        let (ih, arg) = unsafe {
+            (
+                read_unaligned(msg.as_ptr() as *const InHeader),
+                read_unaligned(msg[size_of::<InHeader>()..].as_ptr() as *const ReadIn),
+            )
+        };
+        if ih.opcode != Opcode::Read as u32 {
+            return false;
+        }
+        let fs = match self.backend(ih.nodeid) {
+            Some(fs) => fs,
+            None => return false,
+        };
+        let rafs = match fs.deref().as_any().downcast_ref::<Rafs>() {
+            Some(rafs) => rafs,
+            None => return false,
+        };
+        let ino = ih.nodeid & ((1 << VFS_INDEX_SHIFT) - 1);
+
+        metrics_hook.collect(&ih);
+        let mut size = None;
+        if let Some(pipe) = self.pipe.as_ref() {
+            size = splice(ch, pipe, rafs, ino, &ih, &arg).unwrap_or_else(|e| {
+                debug!("failed to splice read of inode {}, {}", ino, e);
+                pipe.clear();
+                None
+            });
         }
+        if size.is_none() {
+            size = gather(ch, rafs, ino, &ih, &arg).unwrap_or_else(|e| {
+                debug!("failed to reply gathered read of inode {}, {}", ino, e);
+                None
+            });
+        }
+        if let Some(size) = size {
+            metrics_hook.release(Some(&OutHeader {
+                len: (FUSE_OUT_HEADER_SIZE + size) as u32,
+                error: 0,
+                unique: ih.unique,
+            }));
+            return true;
+        }
+        metrics_hook.release(None);
+
+        false
+    }
+}
+
+/// Splice data of read `arg` into the pipe then the fuse device, returns size of data or None
+/// if it can't be spliced.
+fn splice(
+    ch: &FuseChannel,
+    pipe: &SplicePipe,
+    rafs: &Rafs,
+    ino: u64,
+    ih: &InHeader,
+    arg: &ReadIn,
+) -> Result<Option<usize>> {
+    let size = rafs.splice_read(ino, arg.fh, arg.size, arg.offset, pipe.fd(), &mut |size| {
+        pipe.write_header(ih.unique, size)
+    })?;
+    match size {
+        Some(size) => ch
+            .splice_reply(pipe, FUSE_OUT_HEADER_SIZE + size)
+            .map(|_| Some(size)),
+        None => Ok(None),
+    }
+}
+
+/// Reply read `arg` with chunk buffers gathered by the kernel, returns size of data or None
+/// if it's within a chunk.
+fn gather(
+    ch: &FuseChannel,
+    rafs: &Rafs,
+    ino: u64,
+    ih: &InHeader,
+    arg: &ReadIn,
+) -> Result<Option<usize>> {
+    match rafs.read_vectored(ino, arg.fh, arg.size, arg.offset)? {
+        Some(data) => ch
+            .reply_vectored(ih.unique, &data.slices())
+            .map(|_| Some(data.len())),
+        None => Ok(None),
     }
 }
 
@@ -72,17 +264,77 @@ pub(crate) struct FuseServer {
     ch: FuseChannel,
     // read buffer for fuse requests
     buf: Vec<u8>,
+    replier: ReadReplier,
+    // max number of pages of a request to negotiate on INIT
+    max_pages: u16,
+    // INIT got from the kernel, to be replayed by a daemon taking over the session
+    fuse_init: Arc<Mutex<Option<FuseInit>>>,
+    file_locks: FileLocks,
 }
 
 impl FuseServer {
-    fn new(server: Arc<Server<Arc<Vfs>>>, se: &FuseSession, evtfd: EventFd) -> Result<FuseServer> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        server: Arc<Server<Arc<Vfs>>>,
+        se: &FuseSession,
+        evtfd: EventFd,
+        vfs: Arc<Vfs>,
+        backends: Arc<Mutex<FsBackendCollection>>,
+        splice: bool,
+        fuse_init: Arc<Mutex<Option<FuseInit>>>,
+        file_locks: FileLocks,
+    ) -> Result<FuseServer> {
+        let ch = se.new_channel(evtfd)?;
+        let pipe = if splice {
+            ch.new_splice_pipe()
+                .map_err(|e| warn!("failed to create splice pipe, reads are copied: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
         Ok(FuseServer {
             server,
-            ch: se.new_channel(evtfd)?,
+            ch,
             buf: Vec::with_capacity(se.bufsize()),
+            replier: ReadReplier {
+                vfs,
+                backends,
+                pipe,
+            },
+            max_pages: se.max_pages(),
+            fuse_init,
+            file_locks,
         })
     }
 
+    /// Grant the lock request `msg` if locks are emulated, returns false if it's left to the
+    /// fuse server.
+    fn reply_lock(&self, msg: &[u8], metrics_hook: &dyn MetricsHook) -> bool {
+        if self.file_locks != FileLocks::Emulated {
+            return false;
+        }
+        let (ih, data) = match grant_lock(msg) {
+            Some(reply) => reply,
+            None => return false,
+        };
+
+        metrics_hook.collect(&ih);
+        match self.ch.reply_vectored(ih.unique, &[&data]) {
+            Ok(()) => metrics_hook.release(Some(&OutHeader {
+                len: (FUSE_OUT_HEADER_SIZE + data.len()) as u32,
+                error: 0,
+                unique: ih.unique,
+            })),
+            Err(e) => {
+                warn!("failed to reply lock request {}, {}", ih.unique, e);
+                metrics_hook.release(None);
+            }
+        }
+
+        true
+    }
+
     fn svc_loop(&mut self, metrics_hook: &dyn MetricsHook) -> Result<()> {
         // Safe because we have already reserved the capacity
         unsafe {
@@ -92,25 +344,65 @@ impl FuseServer {
         // Given error EBADF, it means kernel has shut down this session.
         let _ebadf = std::io::Error::from_raw_os_error(libc::EBADF);
         loop {
-            if let Some(reader) = self.ch.get_reader(&mut self.buf)? {
-                let writer = self.ch.get_writer()?;
-                if let Err(e) = self
-                    .server
-                    .handle_message(reader, writer, None, Some(metrics_hook))
+            let len = match self.ch.read_message(&mut self.buf)? {
+                Some(len) => len,
+                None => {
+                    info!("fuse server exits");
+                    break;
+                }
+            };
+            if self.replier.reply(&self.ch, &self.buf[..len], metrics_hook)
+                || self.reply_lock(&self.buf[..len], metrics_hook)
+            {
+                continue;
+            }
+            if len >= size_of::<InHeader>()
+                // Safe because the message is long enough and the header is plain old data.
+                && unsafe { read_unaligned(self.buf.as_ptr() as *const InHeader) }.opcode
+                    == Opcode::Init as u32
+            {
+                if len >= size_of::<InHeader>() + size_of::<InitIn>() {
+                    // Safe for the same reason as the header.
+                    let arg = unsafe {
+                        read_unaligned(self.buf[size_of::<InHeader>()..].as_ptr() as *const InitIn)
+                    };
+                    *self.fuse_init.lock().unwrap() = Some(FuseInit {
+                        major: arg.major,
+                        minor: arg.minor,
+                        max_readahead: arg.max_readahead,
+                        flags: arg.flags,
+                    });
+                }
+                let server = &self.server;
+                if let Err(e) =
+                    self.ch
+                        .reply_init(&mut self.buf[..len], self.max_pages, |reader, writer| {
+                            server
+                                .handle_message(reader, writer, None, Some(metrics_hook))
+                                .map(|_| ())
+                                .map_err(|e| eother!(format!("{:?}", e)))
+                        })
                 {
-                    match e {
-                        fuse_rs::Error::EncodeMessage(_ebadf) => {
-                            return Err(eio!("fuse session has been shut down"));
-                        }
-                        _ => {
-                            error!("Handling fuse message, {}", DaemonError::ProcessQueue(e));
-                            continue;
-                        }
+                    error!("Handling fuse INIT, {}", e);
+                }
+                continue;
+            }
+
+            let reader = Reader::new(FuseBuf::new(&mut self.buf[..len])).map_err(|e| eother!(e))?;
+            let writer = self.ch.get_writer()?;
+            if let Err(e) = self
+                .server
+                .handle_message(reader, writer, None, Some(metrics_hook))
+            {
+                match e {
+                    fuse_rs::Error::EncodeMessage(_ebadf) => {
+                        return Err(eio!("fuse session has been shut down"));
+                    }
+                    _ => {
+                        error!("Handling fuse message, {}", DaemonError::ProcessQueue(e));
+                        continue;
                     }
                 }
-            } else {
-                info!("fuse server exits");
-                break;
             }
         }
 
@@ -137,9 +429,13 @@ pub struct FusedevDaemon {
     #[allow(dead_code)]
     pub(crate) failover_policy: FailoverPolicy,
     upgrade_mgr: Option<Mutex<UpgradeManager>>,
-    backend_collection: Mutex<FsBackendCollection>,
+    backend_collection: Arc<Mutex<FsBackendCollection>>,
     bti: BuildTimeInfo,
     inflight_ops: Mutex<Vec<FuseOpWrapper>>,
+    // splice replies of reads served from cache files
+    splice: bool,
+    fuse_init: Arc<Mutex<Option<FuseInit>>>,
+    file_locks: FileLocks,
 }
 
 impl MetricsHook for FuseOpWrapper {
@@ -156,10 +452,13 @@ impl MetricsHook for FuseOpWrapper {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            // Stays current in this thread until the request is replied.
+            request_id: Some(request_id::begin()),
         })
     }
 
     fn release(&self, _oh: Option<&OutHeader>) {
+        request_id::end();
         *self.op.lock().expect("Not expect poisoned lock") = None
     }
 }
@@ -171,6 +470,11 @@ impl FusedevDaemon {
             self.session.lock().unwrap().deref(),
             // Clone event fd must succeed, otherwise fusedev daemon should not work.
             self.event_fd.try_clone().unwrap(),
+            self.vfs.clone(),
+            self.backend_collection.clone(),
+            self.splice,
+            self.fuse_init.clone(),
+            self.file_locks,
         )?;
 
         let inflight_op = FuseOpWrapper::default();
@@ -197,6 +501,59 @@ impl FusedevDaemon {
         self.running_threads.fetch_add(1, Ordering::AcqRel);
         Ok(())
     }
+
+    /// Fuse INIT got from the kernel, None if the session is not initialized yet.
+    pub(crate) fn fuse_init(&self) -> Option<FuseInit> {
+        *self.fuse_init.lock().unwrap()
+    }
+
+    /// Initialize the vfs with fuse INIT got by the previous daemon of a session taken over,
+    /// as the kernel doesn't send it again. The reply is dropped.
+    pub(crate) fn replay_init(&self, init: FuseInit) -> Result<()> {
+        let header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<InitIn>()) as u32,
+            opcode: Opcode::Init as u32,
+            ..Default::default()
+        };
+        let arg = InitIn {
+            major: init.major,
+            minor: init.minor,
+            max_readahead: init.max_readahead,
+            flags: init.flags,
+        };
+        let mut msg = Vec::with_capacity(header.len as usize);
+        // Safe because both are plain old data.
+        unsafe {
+            msg.extend_from_slice(slice::from_raw_parts(
+                &header as *const InHeader as *const u8,
+                size_of::<InHeader>(),
+            ));
+            msg.extend_from_slice(slice::from_raw_parts(
+                &arg as *const InitIn as *const u8,
+                size_of::<InitIn>(),
+            ));
+        }
+
+        let null = OpenOptions::new().write(true).open("/dev/null")?;
+        let bufsize = self.session.lock().unwrap().bufsize();
+        let reader = Reader::new(FuseBuf::new(&mut msg)).map_err(|e| eother!(e))?;
+        let writer = Writer::new(null.as_raw_fd(), bufsize).map_err(|e| eother!(e))?;
+        self.server
+            .handle_message(reader, writer, None, None)
+            .map_err(|e| eother!(e))?;
+        *self.fuse_init.lock().unwrap() = Some(init);
+
+        Ok(())
+    }
+
+    /// Serve the fuse session of `file` taken over from the previous daemon.
+    pub(crate) fn set_fuse_fd(&self, file: File) -> Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.set_fuse_fd(file.into_raw_fd());
+        self.conn
+            .store(calc_fuse_conn(session.mountpoint())?, Ordering::Relaxed);
+        Ok(())
+    }
 }
 
 impl DaemonStateMachineSubscriber for FusedevDaemon {
@@ -316,6 +673,24 @@ impl NydusDaemon for FusedevDaemon {
         self.bti.clone()
     }
 
+    fn fuse_fd(&self) -> Option<RawFd> {
+        self.session.lock().unwrap().get_fuse_fd()
+    }
+
+    fn inflight_fuse_requests(&self) -> Vec<(u64, u64)> {
+        self.inflight_ops
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|w| {
+                w.op.lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|op| (op.inode, op.timestamp_secs))
+            })
+            .collect()
+    }
+
     fn export_inflight_ops(&self) -> DaemonResult<Option<String>> {
         let ops = self.inflight_ops.lock().unwrap();
 
@@ -405,11 +780,17 @@ pub fn create_nydus_daemon(
     api_sock: Option<impl AsRef<Path>>,
     upgrade: bool,
     fp: FailoverPolicy,
-    mount_cmd: Option<FsBackendMountCmd>,
+    mount_cmds: Vec<FsBackendMountCmd>,
     bti: BuildTimeInfo,
-) -> Result<Arc<dyn NydusDaemon + Send>> {
+    splice: bool,
+    max_read: Option<usize>,
+    file_locks: FileLocks,
+) -> Result<Arc<dyn NydusDaemon + Send + Sync>> {
     let (trigger, events_rx) = channel::<DaemonStateMachineInput>();
-    let session = FuseSession::new(Path::new(mountpoint), "rafs", "")?;
+    let mut session = FuseSession::new(Path::new(mountpoint), "rafs", "")?;
+    if let Some(size) = max_read {
+        session.set_max_read(size)?;
+    }
 
     // Create upgrade manager
     let upgrade_mgr = if let Some(s) = &supervisor {
@@ -441,6 +822,9 @@ pub fn create_nydus_daemon(
         backend_collection: Default::default(),
         bti,
         inflight_ops: Mutex::new(Vec::new()),
+        splice,
+        fuse_init: Default::default(),
+        file_locks,
     });
 
     let machine = DaemonStateMachineContext::new(daemon.clone(), events_rx, result_sender);
@@ -453,8 +837,8 @@ pub fn create_nydus_daemon(
         && !is_crashed(mountpoint, api_sock.as_ref().unwrap())?)
         || api_sock.is_none()
     {
-        if let Some(cmd) = mount_cmd {
-            daemon.mount(cmd)?;
+        if !mount_cmds.is_empty() {
+            daemon.mount_all(mount_cmds)?;
         }
         daemon.session.lock().unwrap().mount()?;
         daemon
@@ -467,3 +851,77 @@ pub fn create_nydus_daemon(
 
     Ok(daemon)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuse_rs::abi::linux_abi::FileLock;
+    use fuse_rs::api::filesystem::FileSystem;
+    use fuse_rs::api::VfsOptions;
+
+    fn bytes_of<T>(v: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(v as *const T as *const u8, size_of::<T>()) }
+    }
+
+    fn lock_request(opcode: Opcode) -> Vec<u8> {
+        let ih = InHeader {
+            len: (size_of::<InHeader>() + size_of::<LkIn>()) as u32,
+            opcode: opcode as u32,
+            unique: 7,
+            ..Default::default()
+        };
+        let arg = LkIn {
+            lk: FileLock {
+                start: 4096,
+                end: 8191,
+                type_: libc::F_WRLCK as u32,
+                pid: 100,
+            },
+            ..Default::default()
+        };
+        let mut msg = bytes_of(&ih).to_vec();
+        msg.extend_from_slice(bytes_of(&arg));
+        msg
+    }
+
+    #[test]
+    fn test_grant_lock() {
+        let (ih, data) = grant_lock(&lock_request(Opcode::Getlk)).unwrap();
+        assert_eq!(ih.unique, 7);
+        assert_eq!(data.len(), size_of::<LkOut>());
+        let out = unsafe { read_unaligned(data.as_ptr() as *const LkOut) };
+        assert_eq!(out.lk.type_, libc::F_UNLCK as u32);
+        assert_eq!((out.lk.start, out.lk.end), (4096, 8191));
+
+        for op in &[Opcode::Setlk, Opcode::Setlkw] {
+            let (ih, data) = grant_lock(&lock_request(*op)).unwrap();
+            assert_eq!(ih.opcode, *op as u32);
+            assert!(data.is_empty());
+        }
+
+        assert!(grant_lock(&lock_request(Opcode::Read)).is_none());
+        let msg = lock_request(Opcode::Setlk);
+        assert!(grant_lock(&msg[..size_of::<InHeader>()]).is_none());
+    }
+
+    #[test]
+    fn test_file_locks_negotiated() {
+        let locks = FsOptions::POSIX_LOCKS | FsOptions::FLOCK_LOCKS;
+        let negotiate = |file_locks: &str, kernel: FsOptions| {
+            let file_locks = FileLocks::try_from(file_locks).unwrap();
+            Vfs::new(VfsOptions {
+                out_opts: VfsOptions::default().out_opts | file_locks.fuse_options(),
+                ..Default::default()
+            })
+            .init(kernel)
+            .unwrap()
+        };
+
+        assert!(!negotiate("local", FsOptions::all()).intersects(locks));
+        assert!(negotiate("emulated", FsOptions::all()).contains(locks));
+        assert!(negotiate("disabled", FsOptions::all()).contains(locks));
+        // Not requested if the kernel doesn't offer them.
+        assert!(!negotiate("emulated", FsOptions::ASYNC_READ).intersects(locks));
+        assert!(FileLocks::try_from("shared").is_err());
+    }
+}
@@ -20,6 +20,7 @@ use std::convert::TryInto;
 use std::fs::File;
 use std::io::{Read, Result};
 use std::ops::DerefMut;
+use std::path::Path;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     mpsc::channel,
@@ -37,8 +38,13 @@ use fuse_rs::api::{Vfs, VfsOptions};
 use event_manager::{EventManager, EventSubscriber, SubscriberOps};
 use vmm_sys_util::eventfd::EventFd;
 
+use nydus_api::audit;
 use nydus_api::http::start_http_thread;
-use nydus_utils::{dump_program_info, setup_logging, BuildTimeInfo};
+use nydus_api::http_endpoint::ApiBulkMountCmd;
+use nydus_api::prometheus::start_prometheus_thread;
+use nydus_api::push::{MetricsPushConfig, MetricsPusher};
+use nydus_api::tls::{start_tls_thread, TlsListenerConfig};
+use nydus_utils::{dump_program_info, setup_logging, setup_system_logging, BuildTimeInfo};
 
 mod daemon;
 use daemon::{DaemonError, FsBackendMountCmd, FsBackendType, NydusDaemonSubscriber};
@@ -50,11 +56,13 @@ use virtiofs::create_nydus_daemon;
 #[cfg(feature = "fusedev")]
 mod fusedev;
 #[cfg(feature = "fusedev")]
-use fusedev::create_nydus_daemon;
+use fusedev::{create_nydus_daemon, FileLocks};
 
 mod api_server_glue;
+mod crash;
+mod restart;
 mod upgrade;
-use api_server_glue::{ApiServer, ApiSeverSubscriber};
+use api_server_glue::{bulk_mount_cmds, ApiServer, ApiSeverSubscriber};
 
 lazy_static! {
     static ref EVENT_MANAGER_RUN: AtomicBool = AtomicBool::new(true);
@@ -112,6 +120,16 @@ extern "C" fn sig_exit(_sig: std::os::raw::c_int) {
     }
 }
 
+/// Get `metrics_push` of the configuration file, which is shared with the rafs mounted at
+/// startup.
+fn metrics_push_config(path: &str) -> Result<Option<MetricsPushConfig>> {
+    let config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    match config.get("metrics_push") {
+        Some(c) => Ok(Some(serde_json::from_value(c.clone())?)),
+        None => Ok(None),
+    }
+}
+
 fn main() -> Result<()> {
     let (bti_string, bti) = BuildTimeInfo::dump(crate_version!());
 
@@ -127,6 +145,12 @@ fn main() -> Result<()> {
                 .min_values(1)
                 .conflicts_with("shared-dir"),
         )
+        .arg(
+            Arg::with_name("mounts")
+                .long("mounts")
+                .help("JSON file of filesystems to mount on startup, in the format of bulk mount API")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("config")
                 .long("config")
@@ -142,6 +166,59 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .min_values(1),
         )
+        .arg(
+            Arg::with_name("api-tls-address")
+                .long("api-tls-address")
+                .help("Also serve admin api over TLS on this TCP address, e.g. 0.0.0.0:8443")
+                .takes_value(true)
+                .requires_all(&["apisock", "api-tls-cert", "api-tls-key", "api-tls-client-ca"]),
+        )
+        .arg(
+            Arg::with_name("api-tls-cert")
+                .long("api-tls-cert")
+                .help("PEM certificate chain of the admin api TLS server")
+                .takes_value(true)
+                .requires("api-tls-address"),
+        )
+        .arg(
+            Arg::with_name("api-tls-key")
+                .long("api-tls-key")
+                .help("PEM private key of the admin api TLS server")
+                .takes_value(true)
+                .requires("api-tls-address"),
+        )
+        .arg(
+            Arg::with_name("api-tls-client-ca")
+                .long("api-tls-client-ca")
+                .help("PEM CA certificates to verify admin api TLS clients")
+                .takes_value(true)
+                .requires("api-tls-address"),
+        )
+        .arg(
+            Arg::with_name("audit-log")
+                .long("audit-log")
+                .help("Append mutating admin api calls to this file, with clients and outcome")
+                .takes_value(true)
+                .requires("apisock"),
+        )
+        .arg(
+            Arg::with_name("prometheus-address")
+                .long("prometheus-address")
+                .help("Serve metrics in Prometheus format at /metrics on this TCP address, e.g. 0.0.0.0:9100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("crash-dump-dir")
+                .long("crash-dump-dir")
+                .help("Write a diagnostic dump into this directory if nydusd panics")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("otlp-endpoint")
+                .long("otlp-endpoint")
+                .help("Export traces of fuse requests to this OTLP collector, e.g. http://localhost:4317")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("shared-dir")
                 .long("shared-dir")
@@ -168,6 +245,16 @@ fn main() -> Result<()> {
                 .required(false)
                 .global(true),
         )
+        .arg(
+            Arg::with_name("log-target")
+                .long("log-target")
+                .help("Send logs to syslog or the systemd journal instead of stderr or a log file")
+                .takes_value(true)
+                .possible_values(&["syslog", "journald"])
+                .conflicts_with("log-file")
+                .required(false)
+                .global(true),
+        )
         .arg(
             Arg::with_name("rlimit-nofile")
                 .long("rlimit-nofile")
@@ -259,6 +346,34 @@ fn main() -> Result<()> {
                         Err("Input thread number is not legal".to_string())
                     }
                 }),
+        )
+        .arg(
+            Arg::with_name("splice")
+                .long("splice")
+                .help("Splice data of reads served from uncompressed blobcache into fuse device, without copying it through userspace")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-read")
+                .long("max-read")
+                .help("Max size in bytes of fuse read and write requests, a multiple of page size up to 1MB with 4K pages, which is the default")
+                .takes_value(true)
+                .required(false)
+                .validator(|v| {
+                    v.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| "Input max read size is not legal".to_string())
+                }),
+        )
+        .arg(
+            Arg::with_name("file-locks")
+                .long("file-locks")
+                .help("How fcntl and flock locks are handled: `local` by the kernel, `emulated` to grant all of them, or `disabled` to refuse them")
+                .takes_value(true)
+                .default_value("local")
+                .possible_values(&["local", "emulated", "disabled"])
+                .required(false),
         );
 
     #[cfg(feature = "virtiofs")]
@@ -280,10 +395,18 @@ fn main() -> Result<()> {
         .parse()
         .unwrap();
 
-    setup_logging(logging_file, level)?;
+    match cmd_arguments_parsed.value_of("log-target") {
+        // Safe to unwrap because possible values are defined
+        Some(target) => setup_system_logging(target.parse().unwrap(), level)?,
+        None => setup_logging(logging_file, level)?,
+    }
 
     dump_program_info(crate_version!());
 
+    if let Some(endpoint) = cmd_arguments_parsed.value_of("otlp-endpoint") {
+        nydus_utils::trace::init_otlp(endpoint)?;
+    }
+
     // Retrieve arguments
     // shared-dir means fs passthrough
     let shared_dir = cmd_arguments_parsed.value_of("shared-dir");
@@ -299,8 +422,18 @@ fn main() -> Result<()> {
         .map(|n| n.parse().unwrap_or(rlimit_nofile_default))
         .unwrap_or(rlimit_nofile_default);
 
-    let vfs = Vfs::new(VfsOptions::default());
-    let mount_cmd = if let Some(shared_dir) = shared_dir {
+    // Safe to unwrap as it has a default value.
+    #[cfg(feature = "fusedev")]
+    let file_locks: FileLocks = cmd_arguments_parsed
+        .value_of("file-locks")
+        .unwrap()
+        .try_into()?;
+    let vfs = Vfs::new(VfsOptions {
+        #[cfg(feature = "fusedev")]
+        out_opts: VfsOptions::default().out_opts | file_locks.fuse_options(),
+        ..Default::default()
+    });
+    let mut mount_cmds: Vec<FsBackendMountCmd> = if let Some(shared_dir) = shared_dir {
         info!(
             "set rlimit {}, default {}",
             rlimit_nofile, rlimit_nofile_default
@@ -318,7 +451,7 @@ fn main() -> Result<()> {
             prefetch_files: None,
         };
 
-        Some(cmd)
+        vec![cmd]
     } else if let Some(b) = bootstrap {
         let config = cmd_arguments_parsed.value_of("config").ok_or_else(|| {
             DaemonError::InvalidArguments("config file is not provided".to_string())
@@ -339,10 +472,15 @@ fn main() -> Result<()> {
             prefetch_files,
         };
 
-        Some(cmd)
+        vec![cmd]
     } else {
-        None
+        Vec::new()
     };
+    if let Some(f) = cmd_arguments_parsed.value_of("mounts") {
+        let cmds: Vec<ApiBulkMountCmd> = serde_json::from_str(&std::fs::read_to_string(f)?)
+            .map_err(|e| DaemonError::InvalidArguments(format!("invalid mounts {}, {}", f, e)))?;
+        mount_cmds.extend(bulk_mount_cmds(cmds)?);
+    }
 
     let mut event_manager = EventManager::<Arc<dyn EventSubscriber>>::new().unwrap();
 
@@ -364,7 +502,7 @@ fn main() -> Result<()> {
         let vu_sock = cmd_arguments_parsed.value_of("sock").ok_or_else(|| {
             DaemonError::InvalidArguments("vhost socket must be provided!".to_string())
         })?;
-        create_nydus_daemon(daemon_id, supervisor, vu_sock, vfs, mount_cmd, bti)?
+        create_nydus_daemon(daemon_id, supervisor, vu_sock, vfs, mount_cmds, bti)?
     };
     #[cfg(feature = "fusedev")]
     let daemon = {
@@ -397,8 +535,13 @@ fn main() -> Result<()> {
             apisock,
             cmd_arguments_parsed.is_present("upgrade"),
             p,
-            mount_cmd,
+            mount_cmds,
             bti,
+            cmd_arguments_parsed.is_present("splice"),
+            cmd_arguments_parsed
+                .value_of("max-read")
+                .map(|v| v.parse().unwrap()),
+            file_locks,
         )
         .map(|d| {
             info!("Fuse daemon started!");
@@ -406,13 +549,21 @@ fn main() -> Result<()> {
         })?
     };
 
+    if let Some(dir) = cmd_arguments_parsed.value_of("crash-dump-dir") {
+        crash::install_panic_hook(Path::new(dir), daemon.clone())?;
+    }
+
     let mut http_thread: Option<thread::JoinHandle<Result<()>>> = None;
     let http_exit_evtfd = EventFd::new(0).unwrap();
     if let Some(apisock) = apisock {
         let (to_api, from_http) = channel();
         let (to_http, from_api) = channel();
 
-        let api_server = ApiServer::new(to_http, daemon.clone())?;
+        if let Some(path) = cmd_arguments_parsed.value_of("audit-log") {
+            audit::open(Path::new(path))?;
+        }
+
+        let api_server = ApiServer::new(to_http, daemon.clone(), apisock)?;
 
         let api_server_subscriber = Arc::new(ApiSeverSubscriber::new(api_server, from_http)?);
         let evtfd = api_server_subscriber.get_event_fd()?;
@@ -426,8 +577,48 @@ fn main() -> Result<()> {
         )?;
         http_thread = Some(ret);
         info!("api server running at {}", apisock);
+
+        // Safe to unwrap because they are required by `api-tls-address`.
+        if let Some(address) = cmd_arguments_parsed.value_of("api-tls-address") {
+            let config = TlsListenerConfig {
+                address: address.to_string(),
+                cert: cmd_arguments_parsed
+                    .value_of("api-tls-cert")
+                    .unwrap()
+                    .into(),
+                key: cmd_arguments_parsed.value_of("api-tls-key").unwrap().into(),
+                client_ca: cmd_arguments_parsed
+                    .value_of("api-tls-client-ca")
+                    .unwrap()
+                    .into(),
+            };
+            // The listener thread just dies with the process.
+            start_tls_thread(config, apisock)?;
+            info!("api server running at {} over TLS", address);
+        }
+    }
+
+    if let Some(address) = cmd_arguments_parsed.value_of("prometheus-address") {
+        let d = daemon.clone();
+        // The listener thread just dies with the process.
+        start_prometheus_thread(address, move || d.export_prometheus())?;
+        info!("prometheus metrics served at {}", address);
     }
 
+    let metrics_pusher = match cmd_arguments_parsed.value_of("config") {
+        Some(config) => match metrics_push_config(config)? {
+            Some(push_config) => {
+                let d = daemon.clone();
+                info!("metrics pushed to {}", push_config.address);
+                Some(MetricsPusher::start(push_config, move || {
+                    d.export_prometheus()
+                })?)
+            }
+            None => None,
+        },
+        None => None,
+    };
+
     *EXIT_EVTFD.lock().unwrap().deref_mut() = Some(exit_evtfd);
     nydus_utils::signal::register_signal_handler(signal::SIGINT, sig_exit);
     nydus_utils::signal::register_signal_handler(signal::SIGTERM, sig_exit);
@@ -449,6 +640,10 @@ fn main() -> Result<()> {
 
     daemon.stop().unwrap_or_else(|e| error!("{}", e));
     daemon.wait().unwrap_or_else(|e| error!("{}", e));
+    if let Some(pusher) = metrics_pusher {
+        pusher.stop();
+    }
+    nydus_utils::trace::shutdown();
     info!("nydusd quits");
     Ok(())
 }
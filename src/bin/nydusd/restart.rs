@@ -0,0 +1,195 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Live upgrade driven by nydusd itself, see `PUT /api/v1/daemon/restart`.
+//!
+//! The running daemon sends its fuse session to the supervisor, starts the new binary in
+//! upgrade mode, asks it to take over through the API socket, and exits once the new daemon
+//! is running. An external supervisor only has to hold and send back the fuse session.
+
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{kill, SIGTERM};
+use nix::unistd::Pid;
+use nydus_api::client::api_call;
+use nydus_utils::logger::EventKind;
+use nydus_utils::metrics;
+
+use crate::daemon::{DaemonError, DaemonResult, DaemonState, NydusDaemon};
+
+/// How long to wait for the new daemon to take over.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const API_TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+/// Start live upgrade to `binary`, which is run with `args`, or with arguments of this process
+/// in upgrade mode if it's None.
+///
+/// It returns once the new daemon is started, the rest goes on in background. If the new
+/// daemon fails to take over, it's killed and this daemon keeps serving.
+pub fn restart(
+    daemon: Arc<dyn NydusDaemon + Send + Sync>,
+    api_sock: &Path,
+    binary: &Path,
+    args: Option<Vec<String>>,
+) -> DaemonResult<()> {
+    // The fuse session is handed over through the supervisor.
+    if daemon.supervisor().is_none() {
+        return Err(DaemonError::Unsupported);
+    }
+    if daemon.get_state() != DaemonState::RUNNING {
+        return Err(DaemonError::NotReady);
+    }
+
+    let meta = binary.metadata().map_err(|e| {
+        DaemonError::InvalidArguments(format!("invalid binary {:?}, {}", binary, e))
+    })?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(DaemonError::InvalidArguments(format!(
+            "binary {:?} is not executable",
+            binary
+        )));
+    }
+
+    // The new daemon replaces the API socket, tell them apart by inode.
+    let sock_ino = api_sock
+        .metadata()
+        .map_err(|e| DaemonError::Common(format!("failed to stat API socket, {}", e)))?
+        .ino();
+
+    daemon.save()?;
+
+    let args = args.unwrap_or_else(upgrade_args);
+    let child = Command::new(binary)
+        .args(&args)
+        .spawn()
+        .map_err(|e| DaemonError::Common(format!("failed to start {:?}, {}", binary, e)))?;
+    let pid = child.id();
+    metrics::record_event(
+        EventKind::Restart,
+        "",
+        format!("started new daemon {:?} with pid {}", binary, pid),
+    );
+
+    let api_sock = api_sock.to_path_buf();
+    thread::Builder::new()
+        .name("restart".to_string())
+        .spawn(move || finish_restart(daemon, child, &api_sock, sock_ino))
+        .map(|_| ())
+        .map_err(|e| {
+            kill(Pid::from_raw(pid as i32), SIGTERM)
+                .unwrap_or_else(|e| error!("failed to kill new daemon, {}", e));
+            DaemonError::ThreadSpawn(e)
+        })
+}
+
+/// Arguments of this process, for the new daemon to run in upgrade mode.
+fn upgrade_args() -> Vec<String> {
+    let mut args = std::env::args()
+        .skip(1)
+        .filter(|a| a != "--upgrade")
+        .collect::<Vec<_>>();
+    args.push("--upgrade".to_string());
+    args
+}
+
+fn finish_restart(
+    daemon: Arc<dyn NydusDaemon + Send + Sync>,
+    mut child: Child,
+    api_sock: &Path,
+    sock_ino: u64,
+) {
+    if let Err(e) = takeover(api_sock, sock_ino) {
+        error!("new daemon failed to take over, {}", e);
+        metrics::record_event(
+            EventKind::Restart,
+            "",
+            format!("new daemon failed to take over, {}", e),
+        );
+        child
+            .kill()
+            .and_then(|_| child.wait())
+            .map(|_| ())
+            .unwrap_or_else(|e| error!("failed to kill new daemon, {}", e));
+        return;
+    }
+
+    metrics::record_event(
+        EventKind::Restart,
+        "",
+        format!("new daemon with pid {} took over, exiting", child.id()),
+    );
+    // Make sure fuse requests are all handled before exit, just like `/daemon/exit`.
+    daemon
+        .trigger_exit()
+        .unwrap_or_else(|e| error!("failed to stop fuse service, {}", e));
+    kill(Pid::this(), SIGTERM).unwrap_or_else(|e| error!("Send signal error. {}", e));
+}
+
+/// Wait for the new daemon to serve its API, ask it to take over and wait until it's running.
+fn takeover(api_sock: &Path, old_ino: u64) -> io::Result<()> {
+    let deadline = Instant::now() + TAKEOVER_TIMEOUT;
+    let wait = |what: &str| {
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out waiting for {}", what),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+        Ok(())
+    };
+
+    loop {
+        let replaced = api_sock
+            .metadata()
+            .map(|m| m.ino() != old_ino)
+            .unwrap_or(false);
+        if replaced && api_call(api_sock, "GET", "/api/v1/daemon", None, API_TIMEOUT).is_ok() {
+            break;
+        }
+        wait("API server of new daemon")?;
+    }
+
+    let resp = api_call(
+        api_sock,
+        "PUT",
+        "/api/v1/daemon/fuse/takeover",
+        None,
+        API_TIMEOUT,
+    )?;
+    if !resp.is_success() {
+        return Err(eother!(format!(
+            "takeover failed with status {}, {}",
+            resp.status, resp.body
+        )));
+    }
+
+    loop {
+        let resp = api_call(api_sock, "GET", "/api/v1/daemon", None, API_TIMEOUT)?;
+        let info: serde_json::Value = serde_json::from_str(&resp.body)?;
+        if info["state"] == "RUNNING" {
+            return Ok(());
+        }
+        wait("new daemon to be running")?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_args() {
+        let args = upgrade_args();
+        assert_eq!(args.last().map(|a| a.as_str()), Some("--upgrade"));
+        assert_eq!(args.iter().filter(|a| *a == "--upgrade").count(), 1);
+    }
+}
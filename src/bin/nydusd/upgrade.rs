@@ -1,18 +1,226 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Daemon state handed over to a new daemon on live upgrade and failover.
+//!
+//! The state is kept by an external supervisor listening on a Unix socket. To save state, the
+//! daemon connects to the supervisor and sends the state as JSON, with the fuse session fd
+//! attached to the first message by `SCM_RIGHTS`, then shuts down its side of the connection.
+//! A new daemon connects to the supervisor to restore, and the supervisor sends back the last
+//! state saved in the same way.
+//!
+//! Inodes known to the kernel carry the vfs index of the filesystem owning them, so the new
+//! daemon mounts filesystems at the same vfs indexes. The kernel doesn't send fuse INIT again to
+//! a session taken over, so the INIT the previous daemon got is replayed to the vfs.
+
+use std::any::Any;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 #[cfg(feature = "fusedev")]
+use std::fs::File;
+use std::io;
+#[cfg(feature = "fusedev")]
+use std::io::{Read, Write};
+#[cfg(feature = "fusedev")]
+use std::net::Shutdown;
+#[cfg(feature = "fusedev")]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(feature = "fusedev")]
+use std::os::unix::net::UnixStream;
+#[cfg(feature = "fusedev")]
 use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::daemon::{DaemonResult, FsBackendMountCmd, FsBackendUmountCmd};
+use fuse_rs::abi::linux_abi::Attr;
+use fuse_rs::api::filesystem::{Entry, FileSystem, ROOT_ID};
+use fuse_rs::api::{BackendFileSystem, Vfs};
+#[cfg(feature = "fusedev")]
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+#[cfg(feature = "fusedev")]
+use nix::sys::uio::IoVec;
+use serde::{Deserialize, Serialize};
+
+use crate::daemon::{
+    DaemonError, DaemonResult, FsBackendMountCmd, FsBackendType, FsBackendUmountCmd,
+};
+
+/// Version of the saved state, a new daemon refuses state of other versions.
+#[cfg(feature = "fusedev")]
+const STATE_VERSION: u32 = 1;
+/// Size of the first message of saved state, which carries the fuse fd.
+#[cfg(feature = "fusedev")]
+const FIRST_MESSAGE_SIZE: usize = 4096;
 
 #[derive(Debug)]
-pub enum UpgradeMgrError {}
-pub struct UpgradeManager {}
+pub enum UpgradeMgrError {
+    /// Failed to connect to the supervisor.
+    Connect(io::Error),
+    Send(io::Error),
+    Receive(io::Error),
+    Serde(serde_json::Error),
+    /// No fuse fd is received along with the state.
+    NoFuseFd,
+    /// State saved by a daemon of another state version.
+    Version(u32),
+    /// Filesystems can't be mounted as saved.
+    Restore(String),
+}
+
+/// Arguments of fuse INIT from the kernel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FuseInit {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+}
+
+/// A mounted filesystem and its index in vfs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MountState {
+    #[serde(flatten)]
+    pub cmd: FsBackendMountCmd,
+    pub vfs_index: u8,
+}
+
+/// State as sent to the supervisor.
+#[cfg(feature = "fusedev")]
+#[derive(Serialize, Deserialize)]
+struct DaemonStateData {
+    version: u32,
+    /// None if the session is not initialized yet, then the kernel sends INIT to the new daemon.
+    fuse_init: Option<FuseInit>,
+    /// In order of vfs index.
+    mounts: Vec<MountState>,
+}
+
+/// State saved by the previous daemon.
+#[cfg(feature = "fusedev")]
+pub struct RestoredState {
+    pub fuse_fd: File,
+    pub fuse_init: Option<FuseInit>,
+    /// In order of vfs index.
+    pub mounts: Vec<MountState>,
+}
+
+pub struct UpgradeManager {
+    #[cfg(feature = "fusedev")]
+    supervisor: PathBuf,
+    /// Mounted filesystems by mountpoint.
+    mounts: HashMap<String, MountState>,
+}
 
 #[cfg(feature = "fusedev")]
 impl UpgradeManager {
-    pub fn new(_: PathBuf) -> Self {
-        UpgradeManager {}
+    pub fn new(supervisor: PathBuf) -> Self {
+        UpgradeManager {
+            supervisor,
+            mounts: HashMap::new(),
+        }
+    }
+
+    /// Send mounts, the fuse session fd and its INIT to the supervisor.
+    pub fn save(&self, fuse_fd: RawFd, fuse_init: Option<FuseInit>) -> Result<(), UpgradeMgrError> {
+        let mut mounts = self.mounts.values().cloned().collect::<Vec<_>>();
+        mounts.sort_by_key(|m| m.vfs_index);
+        let data = serde_json::to_vec(&DaemonStateData {
+            version: STATE_VERSION,
+            fuse_init,
+            mounts,
+        })
+        .map_err(UpgradeMgrError::Serde)?;
+
+        let stream = UnixStream::connect(&self.supervisor).map_err(UpgradeMgrError::Connect)?;
+        send_state(stream, &data, fuse_fd).map_err(UpgradeMgrError::Send)
     }
+
+    /// Fetch state saved to the supervisor.
+    pub fn restore(&self) -> Result<RestoredState, UpgradeMgrError> {
+        let stream = UnixStream::connect(&self.supervisor).map_err(UpgradeMgrError::Connect)?;
+        let (data, fuse_fd) = recv_state(stream).map_err(UpgradeMgrError::Receive)?;
+        let fuse_fd = fuse_fd.ok_or(UpgradeMgrError::NoFuseFd)?;
+        let state: DaemonStateData =
+            serde_json::from_slice(&data).map_err(UpgradeMgrError::Serde)?;
+        if state.version != STATE_VERSION {
+            return Err(UpgradeMgrError::Version(state.version));
+        }
+
+        Ok(RestoredState {
+            fuse_fd,
+            fuse_init: state.fuse_init,
+            mounts: state.mounts,
+        })
+    }
+}
+
+/// Send `data` with `fd` attached to its first message.
+#[cfg(feature = "fusedev")]
+fn send_state(mut stream: UnixStream, data: &[u8], fd: RawFd) -> io::Result<()> {
+    let first = &data[..std::cmp::min(data.len(), FIRST_MESSAGE_SIZE)];
+    let fds = [fd];
+    let sent = sendmsg(
+        stream.as_raw_fd(),
+        &[IoVec::from_slice(first)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )
+    .map_err(|e| eother!(e))?;
+    stream.write_all(&data[sent..])?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Receive data till the peer shuts down the connection, and the fd attached to the first
+/// message if any.
+#[cfg(feature = "fusedev")]
+fn recv_state(mut stream: UnixStream) -> io::Result<(Vec<u8>, Option<File>)> {
+    let mut data = vec![0u8; FIRST_MESSAGE_SIZE];
+    // Room for more fds than expected, to tell if the peer sends more.
+    let mut cmsg_buf = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // Safe because msghdr is plain old data.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&cmsg_buf) as _;
+    // Not by nix::recvmsg, which always asks for the peer address and can't parse that of an
+    // unnamed unix socket. Safe because buffers of msg outlive the call.
+    let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = ret as usize;
+
+    let mut files = Vec::new();
+    // Safe because the control messages are filled by the kernel within msg_controllen, and
+    // received fds are owned here.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    files.push(File::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 || files.len() > 1 {
+        return Err(eother!("unexpected fds along with state"));
+    }
+
+    data.truncate(len);
+    stream.read_to_end(&mut data)?;
+
+    Ok((data, files.pop()))
 }
 
 #[derive(PartialEq)]
@@ -34,33 +242,370 @@ impl TryFrom<&str> for FailoverPolicy {
 }
 
 pub fn add_mounts_state(
-    _mgr: &mut UpgradeManager,
-    _cmd: FsBackendMountCmd,
-    _vfs_index: u8,
+    mgr: &mut UpgradeManager,
+    cmd: FsBackendMountCmd,
+    vfs_index: u8,
 ) -> DaemonResult<()> {
+    mgr.mounts
+        .insert(cmd.mountpoint.clone(), MountState { cmd, vfs_index });
     Ok(())
 }
 
-pub fn update_mounts_state(_mgr: &mut UpgradeManager, _cmd: FsBackendMountCmd) -> DaemonResult<()> {
+pub fn update_mounts_state(mgr: &mut UpgradeManager, cmd: FsBackendMountCmd) -> DaemonResult<()> {
+    let state = mgr
+        .mounts
+        .get_mut(&cmd.mountpoint)
+        .ok_or(DaemonError::NotFound)?;
+    state.cmd = cmd;
     Ok(())
 }
 
-pub fn remove_mounts_state(
-    _mgr: &mut UpgradeManager,
-    _cmd: FsBackendUmountCmd,
-) -> DaemonResult<()> {
+/// Storage backend settings which only registry backends take.
+const REGISTRY_BACKEND_SETTINGS: &[&str] = &["auth", "registry_token"];
+
+/// Configs of saved rafs mounts with `settings` merged into their storage backend configs, by
+/// mountpoint. Nothing is changed, so that a patch can be checked as a whole before applying.
+pub fn patch_mounts_backend(
+    mgr: &UpgradeManager,
+    settings: &serde_json::Map<String, serde_json::Value>,
+) -> DaemonResult<Vec<(String, String)>> {
+    let mut configs = Vec::new();
+    for (mountpoint, state) in mgr.mounts.iter() {
+        if state.cmd.fs_type != FsBackendType::Rafs {
+            continue;
+        }
+        let mut config: serde_json::Value =
+            serde_json::from_str(&state.cmd.config).map_err(DaemonError::Serde)?;
+        let backend = config
+            .pointer_mut("/device/backend")
+            .and_then(|b| b.as_object_mut())
+            .ok_or_else(|| {
+                DaemonError::InvalidArguments(format!("{} has no storage backend", mountpoint))
+            })?;
+        let registry = backend.get("type").and_then(|t| t.as_str()) == Some("registry");
+        let backend_config = backend
+            .entry("config")
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        let backend_config = backend_config.as_object_mut().ok_or_else(|| {
+            DaemonError::InvalidArguments(format!("bad storage backend config of {}", mountpoint))
+        })?;
+        for key in settings.keys() {
+            if REGISTRY_BACKEND_SETTINGS.contains(&key.as_str()) {
+                if !registry {
+                    return Err(DaemonError::InvalidArguments(format!(
+                        "storage backend of {} takes no {}",
+                        mountpoint, key
+                    )));
+                }
+                // Only one way of authentication is kept.
+                for k in REGISTRY_BACKEND_SETTINGS {
+                    backend_config.remove(*k);
+                }
+            }
+        }
+        for (key, value) in settings {
+            backend_config.insert(key.clone(), value.clone());
+        }
+        configs.push((mountpoint.clone(), config.to_string()));
+    }
+
+    Ok(configs)
+}
+
+/// Replace configs of saved mounts with those from `patch_mounts_backend()`.
+pub fn set_mounts_config(mgr: &mut UpgradeManager, configs: Vec<(String, String)>) {
+    for (mountpoint, config) in configs {
+        // Those umounted in the meantime are gone already.
+        if let Some(state) = mgr.mounts.get_mut(&mountpoint) {
+            state.cmd.config = config;
+        }
+    }
+}
+
+pub fn remove_mounts_state(mgr: &mut UpgradeManager, cmd: FsBackendUmountCmd) -> DaemonResult<()> {
+    mgr.mounts
+        .remove(&cmd.mountpoint)
+        .map(|_| ())
+        .ok_or(DaemonError::NotFound)
+}
+
+pub fn restore_error(msg: String) -> DaemonError {
+    DaemonError::UpgradeManager(UpgradeMgrError::Restore(msg))
+}
+
+/// Takes a vfs index before the filesystem restored at the index is mounted.
+struct PlaceholderFs {}
+
+impl FileSystem for PlaceholderFs {
+    type Inode = u64;
+    type Handle = u64;
+}
+
+impl BackendFileSystem for PlaceholderFs {
+    fn mount(&self) -> io::Result<(Entry, u64)> {
+        let entry = Entry {
+            inode: ROOT_ID,
+            generation: 0,
+            attr: Attr::default().into(),
+            attr_timeout: Duration::new(0, 0),
+            entry_timeout: Duration::new(0, 0),
+        };
+        Ok((entry, ROOT_ID))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Mount filesystems of `mounts`, which are in order of vfs index, at the same vfs indexes as
+/// saved by `mount`, which returns the index a filesystem gets.
+///
+/// A vfs with nothing mounted hands out indexes in order, those not in use by the previous
+/// daemon are taken by placeholders at the mountpoint of the next filesystem, which then
+/// replaces them by over-mounting.
+pub fn restore_vfs_indexes<T, F>(
+    vfs: &Vfs,
+    mounts: Vec<(MountState, T)>,
+    mut mount: F,
+) -> DaemonResult<()>
+where
+    F: FnMut(MountState, T) -> DaemonResult<u8>,
+{
+    let mut next: u16 = 1;
+    for (state, fs) in mounts {
+        let (index, mountpoint) = (state.vfs_index, state.cmd.mountpoint.clone());
+        if (index as u16) < next {
+            return Err(restore_error(format!(
+                "vfs index {} of {} is out of order",
+                index, mountpoint
+            )));
+        }
+        while next < index as u16 {
+            let got = vfs.mount(Box::new(PlaceholderFs {}), &mountpoint)?;
+            if got as u16 != next {
+                return Err(restore_error(format!(
+                    "vfs index {} is allocated while expecting {}",
+                    got, next
+                )));
+            }
+            next += 1;
+        }
+        let got = mount(state, fs)?;
+        if got != index {
+            return Err(restore_error(format!(
+                "{} is mounted at vfs index {} rather than {}",
+                mountpoint, got, index
+            )));
+        }
+        next = index as u16 + 1;
+    }
+
     Ok(())
 }
 
 #[cfg(feature = "fusedev")]
 pub mod fusedev_upgrade {
-    use crate::daemon::DaemonResult;
+    use super::restore_error;
+    use crate::daemon::{DaemonError, DaemonResult, NydusDaemon};
     use crate::fusedev::FusedevDaemon;
-    pub fn save(_daemon: &FusedevDaemon) -> DaemonResult<()> {
-        Ok(())
+
+    /// Send the fuse session and mounts to the supervisor.
+    pub fn save(daemon: &FusedevDaemon) -> DaemonResult<()> {
+        let fd = daemon.fuse_fd().ok_or(DaemonError::NotReady)?;
+        let init = daemon.fuse_init();
+        daemon
+            .upgrade_mgr()
+            .ok_or(DaemonError::Unsupported)?
+            .save(fd, init)
+            .map_err(DaemonError::UpgradeManager)
     }
 
-    pub fn restore(_daemon: &FusedevDaemon) -> DaemonResult<()> {
+    /// Take over the fuse session and mounts saved to the supervisor by the previous daemon.
+    pub fn restore(daemon: &FusedevDaemon) -> DaemonResult<()> {
+        let state = daemon
+            .upgrade_mgr()
+            .ok_or(DaemonError::Unsupported)?
+            .restore()
+            .map_err(DaemonError::UpgradeManager)?;
+
+        let count = state.mounts.len();
+        daemon.restore_mounts(state.mounts)?;
+        if let Some(init) = state.fuse_init {
+            daemon
+                .replay_init(init)
+                .map_err(|e| restore_error(format!("failed to replay fuse INIT, {}", e)))?;
+        }
+        daemon
+            .set_fuse_fd(state.fuse_fd)
+            .map_err(|e| restore_error(format!("failed to take over fuse session, {}", e)))?;
+        info!("restored fuse session and {} mounts", count);
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuse_rs::api::VfsOptions;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn mount_cmd(mountpoint: &str) -> FsBackendMountCmd {
+        FsBackendMountCmd {
+            fs_type: FsBackendType::Rafs,
+            source: "/bootstrap".to_string(),
+            config: "{}".to_string(),
+            mountpoint: mountpoint.to_string(),
+            prefetch_files: None,
+        }
+    }
+
+    #[test]
+    fn test_mounts_state() {
+        let mut mgr = UpgradeManager::new(PathBuf::from("/supervisor"));
+        add_mounts_state(&mut mgr, mount_cmd("/a"), 1).unwrap();
+        add_mounts_state(&mut mgr, mount_cmd("/b"), 2).unwrap();
+
+        let mut cmd = mount_cmd("/b");
+        cmd.source = "/new".to_string();
+        update_mounts_state(&mut mgr, cmd).unwrap();
+        assert_eq!(mgr.mounts["/b"].cmd.source, "/new");
+        assert_eq!(mgr.mounts["/b"].vfs_index, 2);
+        assert!(update_mounts_state(&mut mgr, mount_cmd("/c")).is_err());
+
+        let umount = |mountpoint: &str| FsBackendUmountCmd {
+            mountpoint: mountpoint.to_string(),
+        };
+        remove_mounts_state(&mut mgr, umount("/a")).unwrap();
+        assert!(remove_mounts_state(&mut mgr, umount("/a")).is_err());
+        assert_eq!(mgr.mounts.len(), 1);
+    }
+
+    #[test]
+    fn test_patch_mounts_backend() {
+        let mut mgr = UpgradeManager::new(PathBuf::from("/supervisor"));
+        let mut cmd = mount_cmd("/a");
+        cmd.config =
+            r#"{"device":{"backend":{"type":"registry","config":{"auth":"x","timeout":5}}}}"#
+                .to_string();
+        add_mounts_state(&mut mgr, cmd, 1).unwrap();
+        let mut settings = serde_json::Map::new();
+        settings.insert("timeout".to_string(), 10.into());
+        settings.insert("registry_token".to_string(), "t".into());
+
+        let configs = patch_mounts_backend(&mgr, &settings).unwrap();
+        // Nothing is changed until set.
+        assert!(mgr.mounts["/a"].cmd.config.contains("\"auth\""));
+        set_mounts_config(&mut mgr, configs);
+        let config: serde_json::Value = serde_json::from_str(&mgr.mounts["/a"].cmd.config).unwrap();
+        assert_eq!(
+            config.pointer("/device/backend/config").unwrap(),
+            &serde_json::json!({"timeout": 10, "registry_token": "t"})
+        );
+
+        // Registry settings are refused as a whole if any backend can't take them.
+        let mut cmd = mount_cmd("/b");
+        cmd.config = r#"{"device":{"backend":{"type":"oss","config":{}}}}"#.to_string();
+        add_mounts_state(&mut mgr, cmd, 2).unwrap();
+        assert!(patch_mounts_backend(&mgr, &settings).is_err());
+        settings.remove("registry_token");
+        assert_eq!(patch_mounts_backend(&mgr, &settings).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_save_restore_state() {
+        let dir = TempDir::new().unwrap();
+        let sock = dir.as_path().join("supervisor.sock");
+        let listener = UnixListener::bind(&sock).unwrap();
+        // Hold state from the first connection and send it back to the second.
+        let supervisor = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let (data, fd) = recv_state(stream).unwrap();
+            let fd = fd.unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            send_state(stream, &data, fd.as_raw_fd()).unwrap();
+        });
+
+        let mut mgr = UpgradeManager::new(sock.clone());
+        // Large enough to be sent in more than one message.
+        let mut cmd = mount_cmd("/b");
+        cmd.config = format!("{{\"pad\": \"{}\"}}", "x".repeat(FIRST_MESSAGE_SIZE * 2));
+        add_mounts_state(&mut mgr, cmd, 3).unwrap();
+        add_mounts_state(&mut mgr, mount_cmd("/a"), 1).unwrap();
+        let file = TempFile::new().unwrap();
+        let init = FuseInit {
+            major: 7,
+            minor: 31,
+            max_readahead: 131072,
+            flags: 0x1234,
+        };
+        mgr.save(file.as_file().as_raw_fd(), Some(init)).unwrap();
+
+        let state = UpgradeManager::new(sock).restore().unwrap();
+        supervisor.join().unwrap();
+        assert_eq!(state.fuse_init, Some(init));
+        let mounts = state
+            .mounts
+            .iter()
+            .map(|m| (m.cmd.mountpoint.as_str(), m.vfs_index))
+            .collect::<Vec<_>>();
+        assert_eq!(mounts, vec![("/a", 1), ("/b", 3)]);
+        assert_eq!(
+            state.mounts[1].cmd.config.len(),
+            FIRST_MESSAGE_SIZE * 2 + 11
+        );
+        let (saved, restored) = (
+            file.as_file().metadata().unwrap(),
+            state.fuse_fd.metadata().unwrap(),
+        );
+        assert_eq!((saved.dev(), saved.ino()), (restored.dev(), restored.ino()));
+    }
+
+    #[test]
+    fn test_restore_vfs_indexes() {
+        let vfs = Vfs::new(VfsOptions::default());
+        let mounts = vec![(1, "/a"), (2, "/b"), (5, "/c"), (6, "/d/e")]
+            .into_iter()
+            .map(|(vfs_index, mountpoint)| {
+                let state = MountState {
+                    cmd: mount_cmd(mountpoint),
+                    vfs_index,
+                };
+                (state, Box::new(PlaceholderFs {}))
+            })
+            .collect::<Vec<_>>();
+        let mut mounted = Vec::new();
+        restore_vfs_indexes(&vfs, mounts, |state, fs| {
+            let index = vfs.mount(fs, &state.cmd.mountpoint)?;
+            mounted.push((state.cmd.mountpoint, index));
+            Ok(index)
+        })
+        .unwrap();
+        let indexes = mounted.iter().map(|m| m.1).collect::<Vec<_>>();
+        assert_eq!(indexes, vec![1, 2, 5, 6]);
+        assert!(vfs.get_rootfs("/c").unwrap().is_some());
+
+        // Indexes must be in order.
+        let vfs = Vfs::new(VfsOptions::default());
+        let mounts = vec![(2, "/a"), (1, "/b")]
+            .into_iter()
+            .map(|(vfs_index, mountpoint)| {
+                let state = MountState {
+                    cmd: mount_cmd(mountpoint),
+                    vfs_index,
+                };
+                (state, ())
+            })
+            .collect::<Vec<_>>();
+        let r = restore_vfs_indexes(&vfs, mounts, |state, _| {
+            vfs.mount(Box::new(PlaceholderFs {}), &state.cmd.mountpoint)
+                .map_err(DaemonError::from)
+        });
+        assert!(r.is_err());
+    }
+}
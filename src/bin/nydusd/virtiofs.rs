@@ -295,9 +295,9 @@ pub fn create_nydus_daemon(
     supervisor: Option<String>,
     sock: &str,
     vfs: Arc<Vfs>,
-    mount_cmd: Option<FsBackendMountCmd>,
+    mount_cmds: Vec<FsBackendMountCmd>,
     bti: BuildTimeInfo,
-) -> Result<Arc<dyn NydusDaemon + Send>> {
+) -> Result<Arc<dyn NydusDaemon + Send + Sync>> {
     let vu_daemon = VhostUserDaemon::new(
         String::from("vhost-user-fs-backend"),
         Arc::new(RwLock::new(VhostUserFsBackendHandler::new(vfs.clone())?)),
@@ -323,8 +323,8 @@ pub fn create_nydus_daemon(
     let machine = DaemonStateMachineContext::new(daemon.clone(), events_rx, result_sender);
     machine.kick_state_machine()?;
 
-    if let Some(cmd) = mount_cmd {
-        daemon.mount(cmd)?;
+    if !mount_cmds.is_empty() {
+        daemon.mount_all(mount_cmds)?;
     }
 
     // TODO: In fact, for virtiofs, below event triggers virtio-queue setup and some other
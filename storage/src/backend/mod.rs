@@ -3,10 +3,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::io::Error;
+use std::time::Instant;
 
 use vm_memory::VolatileSlice;
 
-use nydus_utils::metrics::{BackendMetrics, ERROR_HOLDER};
+use nydus_utils::logger::EventKind;
+use nydus_utils::metrics::{self, BackendMetrics, ERROR_HOLDER};
 
 #[cfg(feature = "backend-localfs")]
 use crate::backend::localfs::LocalFsError;
@@ -66,6 +68,8 @@ pub struct CommonConfig {
     timeout: u64,
     connect_timeout: u64,
     retry_limit: u8,
+    /// Max number of idle keep-alive connections kept per host, 0 means unlimited.
+    connection_pool_size: usize,
 }
 
 impl Default for CommonConfig {
@@ -75,6 +79,34 @@ impl Default for CommonConfig {
             timeout: 5,
             connect_timeout: 5,
             retry_limit: 0,
+            connection_pool_size: 0,
+        }
+    }
+}
+
+/// Health of a backend measured by `BlobBackend::probe()`.
+#[derive(Debug, Default, Serialize)]
+pub struct BackendProbe {
+    /// The blob requested by the probe.
+    pub blob_id: String,
+    /// Whether the backend responded to the probe.
+    pub reachable: bool,
+    /// Whether the backend accepted credentials, `None` if it can't be told.
+    pub auth_valid: Option<bool>,
+    /// Status code of the probe response, for HTTP based backends.
+    pub status: Option<u16>,
+    /// Time taken by the probe in milliseconds.
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+impl BackendProbe {
+    /// Start a probe of `blob_id` which began at `begin`, it's unreachable until told otherwise.
+    pub fn new(blob_id: &str, begin: Instant) -> Self {
+        BackendProbe {
+            blob_id: blob_id.to_string(),
+            latency_ms: begin.elapsed().as_millis() as u64,
+            ..Default::default()
         }
     }
 }
@@ -101,10 +133,22 @@ pub trait BlobBackend {
     /// Get whole blob size
     fn blob_size(&self, blob_id: &str) -> BackendResult<u64>;
 
+    /// Check accessibility of blob `blob_id` in a lightweight way, without reading its data.
+    fn probe(&self, blob_id: &str) -> BackendProbe {
+        let begin = Instant::now();
+        let r = self.blob_size(blob_id);
+        let mut probe = BackendProbe::new(blob_id, begin);
+        match r {
+            Ok(_) => probe.reachable = true,
+            Err(e) => probe.error = Some(format!("{:?}", e)),
+        }
+        probe
+    }
+
     /// Read a range of data from blob into the provided slice
     fn read(&self, blob_id: &str, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
         let mut retry_count = self.retry_limit();
-        let begin_time = self.metrics().begin();
+        let begin_time = self.metrics().begin(buf.len());
         loop {
             let ret = self.try_read(blob_id, buf, offset);
             match ret {
@@ -126,6 +170,11 @@ pub trait BlobBackend {
                             .unwrap()
                             .push(&format!("{:?}", err))
                             .unwrap_or_else(|_| error!("Failed when try to hold error"));
+                        metrics::record_event(
+                            EventKind::BackendError,
+                            self.metrics().id(),
+                            format!("failed to read blob {}: {:?}", blob_id, err),
+                        );
                         break Err(err);
                     }
                 }
@@ -4,15 +4,15 @@
 
 use std::io::{Error, Result};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use hmac::{Hmac, Mac, NewMac};
 use reqwest::header::CONTENT_LENGTH;
 use reqwest::Method;
 use sha1::Sha1;
 
-use crate::backend::request::{HeaderMap, Request, RequestError};
-use crate::backend::{default_http_scheme, BackendError, BackendResult};
+use crate::backend::request::{http_probe, HeaderMap, Request, RequestError};
+use crate::backend::{default_http_scheme, BackendError, BackendProbe, BackendResult};
 use crate::backend::{BlobBackend, CommonConfig};
 
 use nydus_utils::metrics::BackendMetrics;
@@ -146,10 +146,17 @@ pub fn new(config: serde_json::value::Value, id: Option<&str>) -> Result<OSS> {
     let common_config: CommonConfig =
         serde_json::from_value(config.clone()).map_err(|e| einval!(e))?;
     let retry_limit = common_config.retry_limit;
-    let request = Request::new(common_config)?;
 
     let config: OssConfig = serde_json::from_value(config).map_err(|e| einval!(e))?;
 
+    let metrics = id.map(|i| BackendMetrics::new(i, "oss"));
+    let request = Request::new(common_config, metrics.clone()).map_err(|e| {
+        if let Some(m) = metrics.as_ref() {
+            m.release().unwrap_or_else(|e| error!("{:?}", e));
+        }
+        e
+    })?;
+
     Ok(OSS {
         scheme: config.scheme,
         object_prefix: config.object_prefix,
@@ -159,7 +166,7 @@ pub fn new(config: serde_json::value::Value, id: Option<&str>) -> Result<OSS> {
         bucket_name: config.bucket_name,
         request,
         retry_limit,
-        metrics: id.map(|i| BackendMetrics::new(i, "oss")),
+        metrics,
         id: id.map(|i| i.to_string()),
     })
 }
@@ -217,6 +224,27 @@ impl BlobBackend for OSS {
             .map_err(|err| OssError::Response(format!("invalid content length: {:?}", err)))?)
     }
 
+    fn probe(&self, blob_id: &str) -> BackendProbe {
+        let begin = Instant::now();
+        let (resource, url) = self.url(blob_id, &[]);
+        let headers = match self.sign(Method::HEAD, HeaderMap::new(), resource.as_str()) {
+            Ok(h) => h,
+            Err(e) => {
+                let mut probe = BackendProbe::new(blob_id, begin);
+                probe.auth_valid = Some(false);
+                probe.error = Some(format!("failed to sign request: {:?}", e));
+                return probe;
+            }
+        };
+
+        let status = self
+            .request
+            .call::<&[u8]>(Method::HEAD, url.as_str(), None, headers, false)
+            .map(|resp| resp.status())
+            .map_err(|e| format!("{:?}", e));
+        http_probe(blob_id, begin, status)
+    }
+
     /// read ranged data from oss object
     fn try_read(&self, blob_id: &str, mut buf: &mut [u8], offset: u64) -> BackendResult<usize> {
         let query = &[];
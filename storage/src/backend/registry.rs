@@ -4,22 +4,29 @@
 
 use std::collections::HashMap;
 use std::io::{Error, Read, Result};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
 use reqwest::blocking::Response;
 pub use reqwest::header::HeaderMap;
-use reqwest::header::{HeaderValue, CONTENT_LENGTH};
+use reqwest::header::{HeaderValue, CONTENT_LENGTH, CONTENT_RANGE};
 use reqwest::{Method, StatusCode};
 use url::{ParseError, Url};
 
-use crate::backend::request::{is_success_status, respond, ReqBody, Request, RequestError};
-use crate::backend::{default_http_scheme, BackendError, BackendResult};
+use crate::backend::request::{
+    http_probe, is_success_status, respond, ReqBody, Request, RequestError,
+};
+use crate::backend::{default_http_scheme, BackendError, BackendProbe, BackendResult};
 use crate::backend::{BlobBackend, CommonConfig};
 use nydus_utils::metrics::BackendMetrics;
 
 const REGISTRY_CLIENT_ID: &str = "nydus-registry-client";
 const HEADER_AUTHORIZATION: &str = "Authorization";
 const HEADER_WWW_AUTHENTICATE: &str = "www-authenticate";
+/// Default size of range requests a large read is split into when pipelined.
+const PIPELINE_SEGMENT_SIZE: usize = 0x10_0000;
 
 #[derive(Default)]
 struct Cache(RwLock<String>);
@@ -108,6 +115,11 @@ pub struct Registry {
     // Example: RwLock<HashMap<"<blob_id>", "<redirected_url>">>
     cached_redirect: HashCache,
     metrics: Option<Arc<BackendMetrics>>,
+    // Max number of range requests in flight for a large read
+    pipeline_depth: usize,
+    pipeline_segment_size: usize,
+    // Workers sending range requests of large reads, if pipelining is enabled
+    pipeline: Option<Pipeline>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -126,6 +138,16 @@ struct RegistryConfig {
     registry_token: Option<String>,
     #[serde(default)]
     blob_url_scheme: String,
+    // Max number of range requests in flight for a read larger than
+    // `pipeline_segment_size`, 0 or 1 disables pipelining.
+    #[serde(default)]
+    pipeline_depth: usize,
+    #[serde(default = "default_pipeline_segment_size")]
+    pipeline_segment_size: usize,
+}
+
+fn default_pipeline_segment_size() -> usize {
+    PIPELINE_SEGMENT_SIZE
 }
 
 #[derive(Clone, Deserialize)]
@@ -171,7 +193,6 @@ pub fn new(config: serde_json::value::Value, id: Option<&str>) -> Result<Registr
     let common_config: CommonConfig =
         serde_json::from_value(config.clone()).map_err(|e| einval!(e))?;
     let retry_limit = common_config.retry_limit;
-    let request = Request::new(common_config)?;
 
     let config: RegistryConfig = serde_json::from_value(config).map_err(|e| einval!(e))?;
 
@@ -208,6 +229,20 @@ pub fn new(config: serde_json::value::Value, id: Option<&str>) -> Result<Registr
         Cache::new(String::new())
     };
 
+    let pipeline = if config.pipeline_depth > 1 {
+        Some(Pipeline::new(config.pipeline_depth)?)
+    } else {
+        None
+    };
+
+    let metrics = id.map(|i| BackendMetrics::new(i, "registry"));
+    let request = Request::new(common_config, metrics.clone()).map_err(|e| {
+        if let Some(m) = metrics.as_ref() {
+            m.release().unwrap_or_else(|e| error!("{:?}", e));
+        }
+        e
+    })?;
+
     Ok(Registry {
         request,
         scheme: config.scheme,
@@ -220,7 +255,10 @@ pub fn new(config: serde_json::value::Value, id: Option<&str>) -> Result<Registr
         retry_limit,
         blob_url_scheme: config.blob_url_scheme,
         cached_redirect: HashCache::new(),
-        metrics: id.map(|i| BackendMetrics::new(i, "registry")),
+        metrics,
+        pipeline_depth: config.pipeline_depth,
+        pipeline_segment_size: std::cmp::max(config.pipeline_segment_size, 0x1000),
+        pipeline,
     })
 }
 
@@ -522,6 +560,259 @@ impl Registry {
             .map_err(RegistryError::Transport)
             .map(|size| size as usize)
     }
+
+    /// Read a large range of blob in segments, keeping up to `pipeline_depth` range requests
+    /// in flight, so that responses of next segments are on the way while the body of the
+    /// current one is received.
+    ///
+    /// The first segment is read by `_try_read()` to follow redirection and get authorized,
+    /// the others are requested from where it ended up by workers of the pipeline. A segment
+    /// failing that way, or not answered with exactly the range asked for, is read again by
+    /// `_try_read()`.
+    fn try_read_pipelined(
+        &self,
+        pipeline: &Pipeline,
+        blob_id: &str,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> RegistryResult<usize> {
+        let size = self.pipeline_segment_size;
+        let len = buf.len();
+        let mut segments = buf.chunks_mut(size).enumerate();
+        let first = match segments.next() {
+            Some((_, first)) => first,
+            None => return Ok(0),
+        };
+        let mut total = self._try_read(blob_id, first, offset, true)?;
+        if total < first.len() {
+            return Ok(total);
+        }
+
+        let (url, mut headers) = match self.cached_redirect.get(blob_id) {
+            Some(location) => (location, HeaderMap::new()),
+            None => {
+                let url = format!("/blobs/sha256:{}", blob_id);
+                let url = self.url(url.as_str(), &[]).map_err(RegistryError::Url)?;
+                let mut headers = HeaderMap::new();
+                let cached_auth = self.cached_auth.get();
+                if !cached_auth.is_empty() {
+                    headers.insert(
+                        HEADER_AUTHORIZATION,
+                        HeaderValue::from_str(cached_auth.as_str()).unwrap(),
+                    );
+                }
+                (url, headers)
+            }
+        };
+
+        // Requests still in flight on return are cancelled and drained along with it.
+        let mut in_flight = InFlight::new();
+        let mut next = 1;
+        for (idx, segment) in segments {
+            while next * size < len && next - idx < self.pipeline_depth {
+                let start = offset + (next * size) as u64;
+                let end = offset + std::cmp::min((next + 1) * size, len) as u64 - 1;
+                headers.insert("Range", format!("bytes={}-{}", start, end).parse().unwrap());
+                in_flight.request(pipeline, next, &self.request, &url, headers.clone());
+                next += 1;
+            }
+
+            let start = offset + (idx * size) as u64;
+            let end = start + segment.len() as u64 - 1;
+            let resp = match in_flight.wait(idx) {
+                Ok(resp) if is_range_response(&resp, start, end) => Some(resp),
+                Ok(resp) => {
+                    debug!(
+                        "pipelined request of blob {} got status {} for range {}-{}",
+                        blob_id,
+                        resp.status(),
+                        start,
+                        end
+                    );
+                    None
+                }
+                Err(e) => {
+                    debug!("pipelined request of blob {} failed: {:?}", blob_id, e);
+                    None
+                }
+            };
+            let read = match resp.map(|mut r| r.copy_to(&mut &mut segment[..])) {
+                Some(Ok(read)) => read as usize,
+                _ => self._try_read(blob_id, segment, start, true)?,
+            };
+
+            total += read;
+            if read < segment.len() {
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Workers sending range requests of pipelined reads, shared by all reads of the backend so
+/// that the number of requests in flight is bounded by the number of workers.
+struct Pipeline {
+    sender: Mutex<Option<spmc::Sender<Job>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Pipeline {
+    fn new(threads: usize) -> Result<Self> {
+        let (sender, receiver) = spmc::channel::<Job>();
+        let mut workers = Vec::with_capacity(threads);
+        for num in 0..threads {
+            let rx = receiver.clone();
+            let worker = thread::Builder::new()
+                .name(format!("registry_pipeline_{}", num))
+                .spawn(move || {
+                    while let Ok(job) = rx.recv() {
+                        job();
+                    }
+                })?;
+            workers.push(worker);
+        }
+
+        Ok(Pipeline {
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(workers),
+        })
+    }
+
+    fn submit(&self, job: Job) {
+        let mut sender = self.sender.lock().unwrap();
+        match sender.as_mut() {
+            Some(s) => {
+                if let Err(e) = s.send(job) {
+                    // The job is handed back, run it right here.
+                    (e.0)();
+                }
+            }
+            None => job(),
+        }
+    }
+
+    /// Stop workers after queued jobs are done.
+    fn stop(&self) {
+        self.sender.lock().unwrap().take();
+        let mut workers = self.workers.lock().unwrap();
+        while let Some(w) = workers.pop() {
+            w.join()
+                .unwrap_or_else(|e| error!("Thread might panic, {:?}", e));
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Range requests of a pipelined read handed over to the pipeline. Requests not sent yet are
+/// cancelled, and responses not waited for are dropped, once the read is over.
+struct InFlight {
+    cancelled: Arc<AtomicBool>,
+    sender: mpsc::Sender<(usize, RegistryResult<Response>)>,
+    receiver: mpsc::Receiver<(usize, RegistryResult<Response>)>,
+    ready: HashMap<usize, RegistryResult<Response>>,
+    pending: usize,
+}
+
+impl InFlight {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        InFlight {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            sender,
+            receiver,
+            ready: HashMap::new(),
+            pending: 0,
+        }
+    }
+
+    /// Request segment `idx` from `url` by the pipeline.
+    fn request(
+        &mut self,
+        pipeline: &Pipeline,
+        idx: usize,
+        request: &Arc<Request>,
+        url: &str,
+        headers: HeaderMap,
+    ) {
+        let (cancelled, tx) = (self.cancelled.clone(), self.sender.clone());
+        let (request, url) = (request.clone(), url.to_string());
+        pipeline.submit(Box::new(move || {
+            let resp = if cancelled.load(Ordering::Acquire) {
+                Err(RegistryError::Common("pipelined read is over".to_string()))
+            } else {
+                request
+                    .call::<&[u8]>(Method::GET, url.as_str(), None, headers, true)
+                    .map_err(RegistryError::Request)
+            };
+            // Dropped along with the response if the read is over.
+            let _ = tx.send((idx, resp));
+        }));
+        self.pending += 1;
+    }
+
+    /// Wait for the response to the request of segment `idx`.
+    fn wait(&mut self, idx: usize) -> RegistryResult<Response> {
+        while !self.ready.contains_key(&idx) && self.pending > 0 {
+            match self.receiver.recv() {
+                Ok((i, resp)) => {
+                    self.pending -= 1;
+                    self.ready.insert(i, resp);
+                }
+                Err(_) => break,
+            }
+        }
+        self.ready
+            .remove(&idx)
+            .unwrap_or_else(|| Err(RegistryError::Common("segment not requested".to_string())))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Release);
+        while self.pending > 0 && self.receiver.recv().is_ok() {
+            self.pending -= 1;
+        }
+    }
+}
+
+/// Whether `resp` is a partial content response of bytes from `start` to `end`, or to the end
+/// of the blob if it ends before `end`.
+fn is_range_response(resp: &Response, start: u64, end: u64) -> bool {
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return false;
+    }
+    // Content-Range: bytes <first>-<last>/<complete length or *>
+    let range = match resp.headers().get(CONTENT_RANGE).map(|v| v.to_str()) {
+        Some(Ok(range)) => range,
+        _ => return false,
+    };
+    let parts: Vec<&str> = range
+        .trim_start_matches("bytes ")
+        .splitn(2, '/')
+        .flat_map(|s| s.splitn(2, '-'))
+        .collect();
+    if !range.starts_with("bytes ") || parts.len() != 3 {
+        return false;
+    }
+    match (
+        parts[0].parse::<u64>(),
+        parts[1].parse::<u64>(),
+        parts[2].parse::<u64>(),
+    ) {
+        (Ok(first), Ok(last), _) if first == start && last == end => true,
+        (Ok(first), Ok(last), Ok(length)) => first == start && last < end && last + 1 == length,
+        _ => false,
+    }
 }
 
 impl BlobBackend for Registry {
@@ -573,12 +864,201 @@ impl BlobBackend for Registry {
             .map_err(|err| RegistryError::Common(format!("invalid content length: {:?}", err)))?)
     }
 
+    fn probe(&self, blob_id: &str) -> BackendProbe {
+        let begin = Instant::now();
+        let r = self
+            .url(&format!("/blobs/sha256:{}", blob_id), &[])
+            .map_err(RegistryError::Url)
+            .and_then(|url| {
+                self.request::<&[u8]>(Method::HEAD, url.as_str(), None, HeaderMap::new(), false)
+            });
+
+        match r {
+            Ok(resp) => http_probe(blob_id, begin, Ok(resp.status())),
+            // Failed to get a token from the authorization server.
+            Err(RegistryError::Common(e)) => {
+                let mut probe = BackendProbe::new(blob_id, begin);
+                probe.reachable = true;
+                probe.auth_valid = Some(false);
+                probe.error = Some(e);
+                probe
+            }
+            Err(e) => http_probe(blob_id, begin, Err(format!("{:?}", e))),
+        }
+    }
+
     fn try_read(&self, blob_id: &str, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
-        self._try_read(blob_id, buf, offset, true)
-            .map_err(BackendError::Registry)
+        match self.pipeline.as_ref() {
+            Some(pipeline) if buf.len() > self.pipeline_segment_size => {
+                self.try_read_pipelined(pipeline, blob_id, buf, offset)
+            }
+            _ => self._try_read(blob_id, buf, offset, true),
+        }
+        .map_err(BackendError::Registry)
     }
 
     fn write(&self, _blob_id: &str, _buf: &[u8], _offset: u64) -> BackendResult<usize> {
         Ok(_buf.len())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    const SEGMENT_SIZE: usize = 0x1000;
+
+    /// Serve range requests of `data` as a blob of any id, a connection per request. The first
+    /// request of a range starting at `ignore_range_at` is answered with the whole blob and
+    /// status 200 instead. Return the address and the number of requests served.
+    fn serve(data: Vec<u8>, ignore_range_at: Option<u64>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let served = Arc::new(AtomicUsize::new(0));
+        let ignored = Arc::new(AtomicBool::new(false));
+        let (data, count) = (Arc::new(data), served.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (data, ignored) = (data.clone(), ignored.clone());
+                count.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut req = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !req.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        req.extend_from_slice(&buf[..n]);
+                    }
+                    let req = String::from_utf8_lossy(&req).to_lowercase();
+                    let range: Vec<u64> = req
+                        .lines()
+                        .find(|l| l.starts_with("range: bytes="))
+                        .unwrap()
+                        .trim_start_matches("range: bytes=")
+                        .split('-')
+                        .map(|n| n.trim().parse().unwrap())
+                        .collect();
+                    let (start, end) = (range[0], std::cmp::min(range[1], data.len() as u64 - 1));
+                    let (head, body) = if ignore_range_at == Some(start)
+                        && !ignored.swap(true, Ordering::SeqCst)
+                    {
+                        ("HTTP/1.1 200 OK\r\n".to_string(), &data[..])
+                    } else if start >= data.len() as u64 {
+                        (
+                            "HTTP/1.1 416 Range Not Satisfiable\r\n".to_string(),
+                            &data[..0],
+                        )
+                    } else {
+                        (
+                            format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                                start,
+                                end,
+                                data.len()
+                            ),
+                            &data[start as usize..end as usize + 1],
+                        )
+                    };
+                    write!(
+                        stream,
+                        "{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                        head,
+                        body.len()
+                    )
+                    .unwrap();
+                    // The client may have given up on the response.
+                    let _ = stream.write_all(body);
+                });
+            }
+        });
+        (addr, served)
+    }
+
+    fn blob(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn registry(addr: &str) -> Registry {
+        new(
+            serde_json::json!({
+                "scheme": "http",
+                "host": addr,
+                "repo": "test/repo",
+                "pipeline_depth": 3,
+                "pipeline_segment_size": SEGMENT_SIZE,
+            }),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pipelined_read() {
+        let data = blob(SEGMENT_SIZE * 10 + 100);
+        let (addr, served) = serve(data.clone(), None);
+        let registry = registry(&addr);
+
+        let mut buf = vec![0u8; SEGMENT_SIZE * 5 + 7];
+        assert_eq!(registry.try_read("blob", &mut buf, 10).unwrap(), buf.len());
+        assert_eq!(buf, &data[10..10 + buf.len()]);
+        assert_eq!(served.load(Ordering::SeqCst), 6);
+
+        // Reads no larger than a segment aren't split.
+        let mut buf = vec![0u8; SEGMENT_SIZE];
+        assert_eq!(registry.try_read("blob", &mut buf, 3).unwrap(), buf.len());
+        assert_eq!(buf, &data[3..3 + SEGMENT_SIZE]);
+        assert_eq!(served.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn test_pipelined_read_ignored_range() {
+        let data = blob(SEGMENT_SIZE * 10);
+        let (addr, served) = serve(data.clone(), Some(SEGMENT_SIZE as u64 * 2));
+        let registry = registry(&addr);
+
+        // The whole blob sent for a segment is not taken, the segment is read again.
+        let mut buf = vec![0u8; SEGMENT_SIZE * 4];
+        assert_eq!(registry.try_read("blob", &mut buf, 0).unwrap(), buf.len());
+        assert_eq!(buf, &data[..buf.len()]);
+        assert_eq!(served.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_pipelined_read_past_end() {
+        let data = blob(SEGMENT_SIZE * 3 + 100);
+        let (addr, _) = serve(data.clone(), None);
+        let registry = registry(&addr);
+
+        // Requests of segments past the end are left behind.
+        let mut buf = vec![0u8; SEGMENT_SIZE * 8];
+        assert_eq!(registry.try_read("blob", &mut buf, 0).unwrap(), data.len());
+        assert_eq!(&buf[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn test_is_range_response() {
+        let data = blob(SEGMENT_SIZE);
+        let (addr, _) = serve(data, None);
+        let client = reqwest::blocking::Client::new();
+        let get = |range: &str| {
+            client
+                .get(&format!("http://{}/", addr))
+                .header("Range", range)
+                .send()
+                .unwrap()
+        };
+
+        assert!(is_range_response(&get("bytes=0-99"), 0, 99));
+        assert!(!is_range_response(&get("bytes=0-99"), 1, 99));
+        assert!(!is_range_response(&get("bytes=0-98"), 0, 99));
+        // Up to the end of the blob.
+        assert!(is_range_response(&get("bytes=4000-4199"), 4000, 4199));
+        assert!(!is_range_response(&get("bytes=5000-5099"), 5000, 5099));
+    }
+}
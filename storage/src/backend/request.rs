@@ -3,13 +3,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashMap;
+use std::error::Error;
 use std::io::Read;
 use std::io::Result;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use reqwest::{
     self,
@@ -18,12 +19,32 @@ use reqwest::{
     Method, StatusCode, Url,
 };
 
-use crate::backend::CommonConfig;
+use nydus_utils::metrics::BackendMetrics;
+use nydus_utils::request_id::{self, REQUEST_ID_HEADER};
+use nydus_utils::trace::TraceSpan;
+
+use crate::backend::{BackendProbe, CommonConfig};
 
 pub use reqwest::header::HeaderMap;
 
 const HEADER_AUTHORIZATION: &str = "Authorization";
 
+/// Settings an HTTP client is built with, backends with the same ones share the client.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ClientKey {
+    proxy: String,
+    timeout: u64,
+    connect_timeout: u64,
+    pool_size: usize,
+}
+
+lazy_static! {
+    /// HTTP clients shared by backends of all mounts in the process. A client keeps a pool of
+    /// keep-alive connections per host, so mounts of images from the same registry reuse
+    /// connections rather than each making its own, and TLS handshakes with them.
+    static ref CLIENTS: Mutex<HashMap<ClientKey, Weak<Client>>> = Mutex::new(HashMap::new());
+}
+
 #[derive(Debug)]
 pub enum RequestError {
     ErrorWithMsg(String),
@@ -94,21 +115,96 @@ impl ProxyHealth {
 
 #[derive(Debug)]
 struct Proxy {
-    client: Client,
+    client: Arc<Client>,
     health: ProxyHealth,
     fallback: bool,
+    // Failures of proxied requests are counted against the proxy.
+    host: String,
 }
 
 #[derive(Debug)]
 pub struct Request {
-    client: Client,
+    client: Arc<Client>,
     proxy: Option<Proxy>,
+    metrics: Option<Arc<BackendMetrics>>,
 }
 
 pub fn is_success_status(status: StatusCode) -> bool {
     status >= StatusCode::OK && status < StatusCode::BAD_REQUEST
 }
 
+/// Build the result of probing a blob with an HTTP request, which got response status `status`.
+pub fn http_probe(
+    blob_id: &str,
+    begin: Instant,
+    status: std::result::Result<StatusCode, String>,
+) -> BackendProbe {
+    let mut probe = BackendProbe::new(blob_id, begin);
+    match status {
+        Ok(status) => {
+            probe.reachable = true;
+            probe.status = Some(status.as_u16());
+            probe.auth_valid = match status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(false),
+                // The request is authorized, just the blob is missing.
+                StatusCode::NOT_FOUND => Some(true),
+                s if is_success_status(s) => Some(true),
+                _ => None,
+            };
+            if !is_success_status(status) {
+                probe.error = Some(format!("unexpected response status {}", status));
+            }
+        }
+        Err(e) => probe.error = Some(e),
+    }
+    probe
+}
+
+/// Class of a request failing without response, to tell e.g. bad certificates from outages.
+fn error_class(err: &reqwest::Error) -> &'static str {
+    if err.is_timeout() {
+        return "timeout";
+    }
+    // Hyper and the TLS library only tell what failed by their messages.
+    let mut connect = false;
+    let mut source = err.source();
+    while let Some(e) = source {
+        let msg = e.to_string().to_lowercase();
+        if msg.contains("dns error") || msg.contains("failed to lookup address") {
+            return "dns";
+        }
+        if msg.contains("certificate") || msg.contains("ssl") || msg.contains("tls") {
+            return "tls";
+        }
+        connect |= msg.contains("error trying to connect");
+        source = e.source();
+    }
+    if connect {
+        "connect"
+    } else {
+        "other"
+    }
+}
+
+/// Class of a failure response, or None if the request succeeded.
+fn status_class(status: StatusCode) -> Option<&'static str> {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some("auth"),
+        StatusCode::NOT_FOUND => Some("not_found"),
+        StatusCode::TOO_MANY_REQUESTS => Some("rate_limited"),
+        s if s.is_server_error() => Some("server_error"),
+        s if s.is_client_error() => Some("client_error"),
+        _ => None,
+    }
+}
+
+fn url_host(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .unwrap_or_default()
+}
+
 pub fn respond(resp: Response) -> RequestResult<Response> {
     if is_success_status(resp.status()) {
         return Ok(resp);
@@ -138,13 +234,38 @@ impl Request {
         if !proxy.is_empty() {
             cb = cb.proxy(reqwest::Proxy::all(proxy).map_err(|e| einval!(e))?)
         }
+        if config.connection_pool_size > 0 {
+            cb = cb.pool_max_idle_per_host(config.connection_pool_size);
+        }
 
         Ok(cb.build().map_err(|e| einval!(e))?)
     }
 
-    pub fn new(config: CommonConfig) -> Result<Arc<Request>> {
+    /// Get the client built with `proxy` and `config` shared by backends, build it if there
+    /// is none. It's dropped along with the last backend using it.
+    fn shared_client(proxy: &str, config: &CommonConfig) -> Result<Arc<Client>> {
+        let key = ClientKey {
+            proxy: proxy.to_string(),
+            timeout: config.timeout,
+            connect_timeout: config.connect_timeout,
+            pool_size: config.connection_pool_size,
+        };
+        let mut clients = CLIENTS.lock().unwrap();
+        if let Some(client) = clients.get(&key).and_then(Weak::upgrade) {
+            return Ok(client);
+        }
+
+        let client = Arc::new(Self::build_client(proxy, config)?);
+        clients.retain(|_, c| c.strong_count() > 0);
+        clients.insert(key, Arc::downgrade(&client));
+
+        Ok(client)
+    }
+
+    /// Failures of requests are counted in `metrics` if given.
+    pub fn new(config: CommonConfig, metrics: Option<Arc<BackendMetrics>>) -> Result<Arc<Request>> {
         info!("backend config: {:?}", config);
-        let client = Self::build_client("", &config)?;
+        let client = Self::shared_client("", &config)?;
         let proxy = if !config.proxy.url.is_empty() {
             let ping_url = if !config.proxy.ping_url.is_empty() {
                 Some(Url::from_str(&config.proxy.ping_url).map_err(|e| einval!(e))?)
@@ -152,15 +273,20 @@ impl Request {
                 None
             };
             Some(Proxy {
-                client: Self::build_client(&config.proxy.url, &config)?,
+                client: Self::shared_client(&config.proxy.url, &config)?,
                 health: ProxyHealth::new(config.proxy.check_interval, ping_url),
                 fallback: config.proxy.fallback,
+                host: url_host(&config.proxy.url),
             })
         } else {
             None
         };
 
-        let request = Arc::new(Request { client, proxy });
+        let request = Arc::new(Request {
+            client,
+            proxy,
+            metrics,
+        });
 
         if let Some(proxy) = &request.proxy {
             let request = request.clone();
@@ -197,10 +323,16 @@ impl Request {
         method: Method,
         url: &str,
         data: Option<ReqBody<R>>,
-        headers: HeaderMap,
+        mut headers: HeaderMap,
         catch_status: bool,
         proxy: bool,
     ) -> RequestResult<Response> {
+        // Correlate with the fuse request it's made for, in access logs of the backend.
+        let request = request_id::current();
+        if let Some(id) = request {
+            // Hex digits are always a valid header value.
+            headers.insert(REQUEST_ID_HEADER, id.to_string().parse().unwrap());
+        }
         debug!(
             "Request: {} {} headers: {:?}, proxy: {}, data: {}",
             method,
@@ -214,6 +346,18 @@ impl Request {
             data.is_some(),
         );
 
+        let span = TraceSpan::start("backend.http");
+        span.set_str("http.method", method.as_str());
+        // Query string may carry signed credentials.
+        span.set_str("http.url", url.splitn(2, '?').next().unwrap_or_default());
+        span.set_bool("proxy", proxy);
+        if let Some(id) = request {
+            span.set_str("nydus.request_id", &id.to_string());
+        }
+
+        // An anonymous request answered with an authentication challenge is retried with a
+        // token by the registry backend, it's not a failure.
+        let anonymous = !headers.contains_key(HEADER_AUTHORIZATION);
         let rb = client.request(method, url).headers(headers);
 
         let ret;
@@ -236,12 +380,36 @@ impl Request {
 
         match ret {
             Ok(resp) => {
+                span.set_u64("http.status_code", resp.status().as_u16() as u64);
+                let challenged = anonymous
+                    && resp.status() == StatusCode::UNAUTHORIZED
+                    && resp
+                        .headers()
+                        .contains_key(reqwest::header::WWW_AUTHENTICATE);
+                if let Some(class) = status_class(resp.status()) {
+                    if !challenged {
+                        self.record_error(url, proxy, class);
+                    }
+                }
                 if !catch_status {
                     return Ok(resp);
                 }
                 respond(resp)
             }
-            Err(err) => Err(RequestError::Common(err)),
+            Err(err) => {
+                self.record_error(url, proxy, error_class(&err));
+                Err(RequestError::Common(err))
+            }
+        }
+    }
+
+    fn record_error(&self, url: &str, proxy: bool, class: &'static str) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            let host = match self.proxy.as_ref() {
+                Some(p) if proxy => p.host.clone(),
+                _ => url_host(url),
+            };
+            metrics.record_error(&host, class);
         }
     }
 
@@ -299,3 +467,60 @@ impl Request {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(status_class(StatusCode::OK), None);
+        assert_eq!(status_class(StatusCode::TEMPORARY_REDIRECT), None);
+        assert_eq!(status_class(StatusCode::FORBIDDEN), Some("auth"));
+        assert_eq!(status_class(StatusCode::NOT_FOUND), Some("not_found"));
+        assert_eq!(
+            status_class(StatusCode::TOO_MANY_REQUESTS),
+            Some("rate_limited")
+        );
+        assert_eq!(status_class(StatusCode::BAD_GATEWAY), Some("server_error"));
+        assert_eq!(status_class(StatusCode::CONFLICT), Some("client_error"));
+
+        assert_eq!(
+            url_host("https://registry.example.com:5000/v2/blobs?x=1"),
+            "registry.example.com"
+        );
+        assert_eq!(url_host("not a url"), "");
+    }
+
+    #[test]
+    fn test_error_class() {
+        // Nothing listens on the port once the listener is gone.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = reqwest::blocking::Client::new()
+            .get(&format!("http://{}/", addr))
+            .send()
+            .unwrap_err();
+        assert_eq!(error_class(&err), "connect");
+    }
+
+    #[test]
+    fn test_shared_client() {
+        let mut config = CommonConfig::default();
+        config.connection_pool_size = 7;
+        let client = Request::shared_client("", &config).unwrap();
+        let same = Request::shared_client("", &config).unwrap();
+        assert!(Arc::ptr_eq(&client, &same));
+
+        config.timeout += 1;
+        let other = Request::shared_client("", &config).unwrap();
+        assert!(!Arc::ptr_eq(&client, &other));
+
+        // Gone along with the last user.
+        drop(other);
+        let other = Request::shared_client("", &config).unwrap();
+        assert_eq!(Arc::strong_count(&other), 1);
+    }
+}
@@ -2,21 +2,27 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
 use std::io::{ErrorKind, Result, Seek, SeekFrom};
+use std::mem::size_of;
 use std::num::NonZeroU32;
 use std::ops::DerefMut;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
 use std::sync::{
-    atomic::{AtomicU32, AtomicU64, Ordering},
-    Arc, Mutex, RwLock,
+    atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex, RwLock, Weak,
 };
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+use nix::fcntl::{flock, FlockArg};
 use nix::sys::uio;
 use nix::unistd::dup;
 
+use arc_swap::ArcSwapOption;
 use futures::executor::block_on;
 use governor::{
     clock::QuantaClock, state::direct::NotKeyed, state::InMemoryState, Quota, RateLimiter,
@@ -25,39 +31,136 @@ use governor::{
 use vm_memory::VolatileSlice;
 
 use crate::backend::BlobBackend;
-use crate::cache::chunkmap::{digested::DigestedChunkMap, indexed::IndexedChunkMap, ChunkMap};
+use crate::cache::chunkmap::{
+    digested::DigestedChunkMap,
+    indexed::{self, IndexedChunkMap},
+    ChunkMap,
+};
+use crate::cache::decompress::{DecompressPool, RawChunk};
+use crate::cache::hybrid::HotChunkCache;
+use crate::cache::inflight::InflightChunks;
+use crate::cache::mmap::CacheMmap;
+use crate::cache::quota::{CacheQuota, QuotaVictim};
+use crate::cache::scheduler::{IoPermit, IoPriority, IoScheduler};
+use crate::cache::watermark::DiskWatermark;
 use crate::cache::RafsCache;
 use crate::cache::*;
+use crate::crypt::KeyProvider;
 use crate::device::{BlobPrefetchControl, RafsBio, RafsBlobEntry};
 use crate::factory::CacheConfig;
-use crate::utils::{alloc_buf, copyv, readv};
+use crate::utils::{
+    alloc_buf, copyv, drop_page_cache, fill_zero, hash_table_bytes, is_zero, open_direct,
+    pread_direct, punch_hole, readv, readv_direct, splice_to_pipe,
+};
 use crate::RAFS_DEFAULT_BLOCK_SIZE;
 
 use nydus_utils::{
-    einval, enoent, enosys, last_error,
-    metrics::{BlobcacheMetrics, Metric},
+    div_round_up, ebusy, einval, enoent, enosys, eother, last_error,
+    logger::EventKind,
+    metrics::{self, BlobcacheMetrics, Metric, ERROR_HOLDER},
+    probe,
+    trace::TraceSpan,
 };
 
+/// How often parked prefetch workers look at free space of the cache filesystem again.
+const FREE_SPACE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Max number of all-zero chunks remembered, forgotten ones are just fetched again.
+const MAX_ZERO_CHUNKS: usize = 65536;
+/// Number of chunks imported under one hold of the cache state lock, chunks evicted to stay
+/// within cache quota are dropped after each batch.
+const IMPORT_BATCH_CHUNKS: usize = 256;
+
+lazy_static! {
+    /// Blob cache instances of the process by work dir, mounts of images with common layers
+    /// on the same work dir share cache files of those blobs.
+    static ref INSTANCES: Mutex<HashMap<String, Vec<Weak<BlobCache>>>> =
+        Mutex::new(HashMap::new());
+    /// Purging takes state locks of all instances sharing a work dir, one purge at a time.
+    static ref PURGE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Descriptors of a blob cache file.
+///
+/// With direct IO enabled, the cache file is read through a separate O_DIRECT descriptor,
+/// while chunks are still written through the buffered one to avoid aligning every write.
+#[derive(Clone, Copy)]
+struct CacheFd {
+    fd: RawFd,
+    direct_fd: Option<RawFd>,
+}
+
+struct BlobCacheEntry {
+    blob_id: String,
+    file: File,
+    direct_file: Option<File>,
+    mmap: Option<CacheMmap>,
+    size: u64,
+    chunk_map: Arc<dyn ChunkMap + Sync + Send>,
+}
+
+impl BlobCacheEntry {
+    /// Take the cache file for exclusive use, e.g. to punch out data of it, return None if
+    /// other nydusd instances sharing the work dir have it open, as they may read or cache any
+    /// chunk of it at any time. Readers of this instance are kept out by the state lock, which
+    /// the caller should hold for write.
+    fn try_exclusive(&self) -> Result<Option<ExclusiveUse>> {
+        let fd = self.file.as_raw_fd();
+        match flock(fd, FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(Some(ExclusiveUse(fd))),
+            Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {
+                // The shared lock is dropped by the failed conversion.
+                flock(fd, FlockArg::LockShared).map_err(|e| eother!(e))?;
+                Ok(None)
+            }
+            Err(e) => Err(eother!(e)),
+        }
+    }
+}
+
+/// Exclusive use of a blob cache file, which goes back to shared use when dropped.
+struct ExclusiveUse(RawFd);
+
+impl Drop for ExclusiveUse {
+    fn drop(&mut self) {
+        if let Err(e) = flock(self.0, FlockArg::LockShared) {
+            warn!("failed to share blob cache file again: {}", e);
+        }
+    }
+}
+
 struct BlobCacheState {
-    /// Index blob info by blob index, HashMap<blob_index, (blob_file, blob_size, Arc<ChunkMap>)>.
-    blob_map: HashMap<u32, (File, u64, Arc<dyn ChunkMap + Sync + Send>)>,
+    /// Index blob info by blob index.
+    blob_map: HashMap<u32, BlobCacheEntry>,
     work_dir: String,
+    direct_io: bool,
+    mmap: bool,
     backend_size_valid: bool,
     metrics: Arc<BlobcacheMetrics>,
     backend: Arc<dyn BlobBackend + Sync + Send>,
+    quota: Option<Arc<CacheQuota>>,
 }
 
 impl BlobCacheState {
-    fn get(&self, blob: &RafsBlobEntry) -> Option<(RawFd, u64, Arc<dyn ChunkMap + Sync + Send>)> {
+    fn get(&self, blob: &RafsBlobEntry) -> Option<(CacheFd, u64, Arc<dyn ChunkMap + Sync + Send>)> {
+        self.blob_map.get(&blob.blob_index).map(|entry| {
+            let fd = CacheFd {
+                fd: entry.file.as_raw_fd(),
+                direct_fd: entry.direct_file.as_ref().map(|f| f.as_raw_fd()),
+            };
+            (fd, entry.size, entry.chunk_map.clone())
+        })
+    }
+
+    fn mmap(&self, blob: &RafsBlobEntry) -> Option<&CacheMmap> {
         self.blob_map
             .get(&blob.blob_index)
-            .map(|(file, size, chunk_map)| (file.as_raw_fd(), *size, chunk_map.clone()))
+            .and_then(|entry| entry.mmap.as_ref())
     }
 
     fn set(
         &mut self,
         blob: &RafsBlobEntry,
-    ) -> Result<(RawFd, u64, Arc<dyn ChunkMap + Sync + Send>)> {
+    ) -> Result<(CacheFd, u64, Arc<dyn ChunkMap + Sync + Send>)> {
         if let Some((fd, size, chunk_map)) = self.get(blob) {
             return Ok((fd, size, chunk_map));
         }
@@ -68,7 +171,26 @@ impl BlobCacheState {
             .write(true)
             .read(true)
             .open(&blob_file_path)?;
-        let fd = file.as_raw_fd();
+        // Held as long as the file is open, to tell other instances that it's in use.
+        flock(file.as_raw_fd(), FlockArg::LockShared).map_err(|e| eother!(e))?;
+        if let Some(quota) = self.quota.as_ref() {
+            let meta = file.metadata()?;
+            quota.add_leftover(&Arc::new(blob.clone()), meta.blocks() * 512);
+        }
+        let direct_file = if self.direct_io {
+            open_direct(&blob_file_path)?
+        } else {
+            None
+        };
+        let mmap = if self.mmap {
+            Some(CacheMmap::new(&file)?)
+        } else {
+            None
+        };
+        let fd = CacheFd {
+            fd: file.as_raw_fd(),
+            direct_fd: direct_file.as_ref().map(|f| f.as_raw_fd()),
+        };
 
         let size = if self.backend_size_valid {
             self.backend
@@ -88,8 +210,17 @@ impl BlobCacheState {
             Arc::new(DigestedChunkMap::new()) as Arc<dyn ChunkMap + Sync + Send>
         };
 
-        self.blob_map
-            .insert(blob.blob_index, (file, size, chunk_map.clone()));
+        self.blob_map.insert(
+            blob.blob_index,
+            BlobCacheEntry {
+                blob_id: blob.blob_id.clone(),
+                file,
+                direct_file,
+                mmap,
+                size,
+                chunk_map: chunk_map.clone(),
+            },
+        );
 
         self.metrics
             .underlying_files
@@ -136,6 +267,8 @@ impl PrefetchContext {
     }
 }
 
+type PrefetchLimiter = RateLimiter<NotKeyed, InMemoryState, QuantaClock>;
+
 pub struct BlobCache {
     cache: Arc<RwLock<BlobCacheState>>,
     validate: bool,
@@ -147,12 +280,37 @@ pub struct BlobCache {
     // TODO: Directly using Governor RateLimiter makes code a little hard to read as
     // some concepts come from GCRA like "cells". GCRA is a sort of improved "Leaky Bucket"
     // firstly invented from ATM network technology. Wrap the limiter into Throttle!
-    limiter: Option<Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>>,
+    limiter: ArcSwapOption<PrefetchLimiter>,
     mr_sender: Arc<Mutex<Option<spmc::Sender<MergedBackendRequest>>>>,
     mr_receiver: Option<spmc::Receiver<MergedBackendRequest>>,
     prefetch_seq: AtomicU64,
     metrics: Arc<BlobcacheMetrics>,
     prefetch_threads: Mutex<Vec<JoinHandle<()>>>,
+    hot_cache: Option<HotChunkCache>,
+    /// Workers decompressing chunks of large reads in parallel, only for compressed cache.
+    decompress_pool: Option<DecompressPool>,
+    quota: Option<Arc<CacheQuota>>,
+    watermark: Option<DiskWatermark>,
+    evict_on_low_space: bool,
+    /// All-zero chunks detected at fetch time, (blob_index, compress_offset), at most
+    /// `MAX_ZERO_CHUNKS` of them.
+    zero_chunks: RwLock<HashSet<(u32, u64)>>,
+    /// Chunks being fetched by readers, so that concurrent readers of a chunk fetch it once.
+    inflight: InflightChunks,
+    /// Backend requests of reads go ahead of prefetch ones.
+    io_scheduler: Option<IoScheduler>,
+    /// Chunks cached by prefetch and not read since, (blob_index, compress_offset) mapped to
+    /// decompressed size of the chunk.
+    prefetched: Mutex<HashMap<(u32, u64), u32>>,
+    /// Number of merged requests and their chunks queued for prefetch workers.
+    prefetch_queued: AtomicUsize,
+    prefetch_queued_chunks: AtomicUsize,
+    /// Number of prefetch workers waiting for free space, they're woken up when prefetch is
+    /// stopped.
+    space_waiters: Mutex<usize>,
+    space_cond: Condvar,
+    /// Keys of encrypted blobs, whose chunks are cached as they are in backend.
+    key_provider: Option<Arc<dyn KeyProvider>>,
 }
 
 impl BlobCache {
@@ -164,15 +322,44 @@ impl BlobCache {
         offset: u64,
         size: usize,
     ) -> Result<(usize, bool)> {
-        let cache_guard = self.cache.read().unwrap();
+        if self.is_zero_chunk(blob, chunk) {
+            self.metrics.zero_hits.inc();
+            return Ok((fill_zero(bufs, size)?, true));
+        }
+
+        // Hold the state lock during the whole read, so that the blob can't be purged while its
+        // chunk is being cached.
+        let mut cache_guard = self.cache.read().unwrap();
         let (fd, _, chunk_map) = match cache_guard.get(blob) {
             Some(entry) => entry,
             None => {
                 drop(cache_guard);
-                self.cache.write().unwrap().set(blob)?
+                self.cache.write().unwrap().set(blob)?;
+                cache_guard = self.cache.read().unwrap();
+                cache_guard
+                    .get(blob)
+                    .ok_or_else(|| enoent!("blob cache entry is gone"))?
+            }
+        };
+        let mut has_ready = chunk_map.has_ready(chunk)?;
+        let _inflight = if has_ready {
+            None
+        } else {
+            // Another reader may be fetching the chunk, wait for it rather than fetching again.
+            let inflight = self
+                .inflight
+                .start(blob.blob_index, chunk.compress_offset());
+            if self.is_zero_chunk(blob, chunk) {
+                self.metrics.zero_hits.inc();
+                return Ok((fill_zero(bufs, size)?, true));
+            }
+            has_ready = chunk_map.has_ready(chunk)?;
+            if has_ready {
+                None
+            } else {
+                inflight
             }
         };
-        let has_ready = chunk_map.has_ready(chunk)?;
         let mut reuse = false;
 
         // Hit cache if cache ready
@@ -183,11 +370,24 @@ impl BlobCache {
                 chunk.compress_size()
             );
             self.metrics.partial_hits.inc();
-            let read_size =
-                self.read_partial_chunk(fd, bufs, offset + chunk.decompress_offset(), size)?;
+            let offset = offset + chunk.decompress_offset();
+            let read_size = match cache_guard.mmap(blob) {
+                Some(mmap) => mmap.read(bufs, offset, size)?,
+                None => self.read_partial_chunk(fd, bufs, offset, size)?,
+            };
             return Ok((read_size, has_ready));
         }
 
+        // Frequently accessed chunks of a compressed cache may have a decompressed copy.
+        if has_ready && !self.need_validate() {
+            if let Some(hot) = self.hot_cache.as_ref() {
+                if let Some(read_size) = hot.read(blob, chunk, bufs, offset, size)? {
+                    self.metrics.hot_hits.inc();
+                    return Ok((read_size, has_ready));
+                }
+            }
+        }
+
         let d_size = chunk.decompress_size() as usize;
         let mut d;
         // one_chunk_buf is the decompressed data buffer
@@ -205,10 +405,28 @@ impl BlobCache {
         // Try to recover cache from blobcache first
         // For gzip, we can only trust ready blobcache because we cannot validate chunks due to
         // stargz format limitations (missing chunk level digest)
+        let mut corrupted = false;
         if (self.compressor() != compress::Algorithm::GZip || has_ready)
-            && self
-                .read_blobcache_chunk(fd, chunk, one_chunk_buf, !has_ready || self.need_validate())
-                .is_ok()
+            && match self.read_blobcache_chunk(
+                fd,
+                blob,
+                chunk,
+                one_chunk_buf,
+                !has_ready || self.need_validate(),
+            ) {
+                Ok(_) => true,
+                Err(e) => {
+                    // A ready chunk failing validation means the cache file is corrupted,
+                    // invalidate it and fall back to backend.
+                    if has_ready {
+                        corrupted = true;
+                        self.metrics.corrupted_chunks.inc();
+                        corruption_event(blob, chunk, &format!("bad cache data, {}", e));
+                        chunk_map.clear_ready(chunk)?;
+                    }
+                    false
+                }
+            }
         {
             self.metrics.whole_hits.inc();
             chunk_map.set_ready(chunk)?;
@@ -221,18 +439,37 @@ impl BlobCache {
                 size,
             );
         } else {
-            self.read_backend_chunk(blob, chunk, one_chunk_buf, |buf| {
-                let offset = if self.is_compressed {
-                    chunk.compress_offset()
+            let _permit = self.start_io(IoPriority::OnDemand);
+            self.read_backend_chunk(blob, chunk, one_chunk_buf, |raw, buf| {
+                // Don't waste cache space on all-zero chunks, serve them from memory.
+                if is_zero(buf) {
+                    self.set_zero_chunk(blob, chunk);
+                    return Ok(());
+                }
+                let (offset, data) = if self.is_compressed {
+                    (chunk.compress_offset(), raw)
                 } else {
-                    chunk.decompress_offset()
+                    (chunk.decompress_offset(), buf)
                 };
                 // TODO: Try to make this as a following asynchronous step writing cache
                 // This should be help to reduce read latency.
-                self.cache(fd, buf, offset)?;
+                self.cache(fd, data, offset)?;
                 chunk_map.set_ready(chunk)?;
                 Ok(())
+            })
+            .map_err(|e| {
+                if corrupted {
+                    corruption_event(blob, chunk, &format!("refetch failed, {}", e));
+                }
+                e
             })?;
+            if corrupted {
+                corruption_event(blob, chunk, "repaired by refetching from backend");
+            }
+        }
+
+        if let Some(hot) = self.hot_cache.as_ref() {
+            hot.record(blob, chunk, one_chunk_buf);
         }
 
         if reuse {
@@ -248,7 +485,8 @@ impl BlobCache {
 
     fn read_blobcache_chunk(
         &self,
-        fd: RawFd,
+        fd: CacheFd,
+        blob: &RafsBlobEntry,
         cki: &dyn RafsChunkInfo,
         chunk: &mut [u8],
         need_validate: bool,
@@ -275,56 +513,176 @@ impl BlobCache {
 
         let mut raw_stream = None;
         if self.compressor() != compress::Algorithm::GZip {
-            debug!(
-                "reading blobcache file fd {} offset {} size {}",
-                fd,
-                offset,
-                raw_chunk.len()
-            );
-            let nr_read = uio::pread(fd, raw_chunk, offset as i64).map_err(|_| last_error!())?;
-            if nr_read == 0 || nr_read != raw_chunk.len() {
-                return Err(einval!());
-            }
+            Self::read_cache_file(fd, raw_chunk, offset)?;
         } else {
-            debug!(
-                "using blobcache file fd {} offset {} as data stream",
-                fd, offset,
-            );
-            // FIXME: In case of multiple threads duplicating the same fd, they still share the same file offset.
-            let fd = dup(fd).map_err(|_| last_error!())?;
-            let mut f = unsafe { File::from_raw_fd(fd) };
-            f.seek(SeekFrom::Start(offset)).map_err(|_| last_error!())?;
-            raw_stream = Some(f)
+            raw_stream = Some(Self::cache_file_stream(fd, offset)?);
         }
 
+        // Uncompressed cache holds decrypted data already.
+        let cipher = if self.is_compressed {
+            self.blob_cipher(blob)?
+        } else {
+            None
+        };
         // Try to validate data just fetched from backend inside.
         self.process_raw_chunk(
             cki,
             raw_chunk,
             raw_stream,
             chunk,
-            self.is_compressed,
+            self.is_compressed && cki.is_compressed(),
             need_validate,
+            cipher.as_deref(),
         )?;
 
         Ok(())
     }
 
+    fn read_cache_file(fd: CacheFd, buf: &mut [u8], offset: u64) -> Result<()> {
+        debug!(
+            "reading blobcache file fd {} offset {} size {}",
+            fd.fd,
+            offset,
+            buf.len()
+        );
+        let nr_read = if let Some(direct_fd) = fd.direct_fd {
+            pread_direct(direct_fd, buf, offset)?
+        } else {
+            uio::pread(fd.fd, buf, offset as i64).map_err(|_| last_error!())?
+        };
+        if nr_read == 0 || nr_read != buf.len() {
+            return Err(einval!());
+        }
+        Ok(())
+    }
+
+    fn cache_file_stream(fd: CacheFd, offset: u64) -> Result<File> {
+        debug!(
+            "using blobcache file fd {} offset {} as data stream",
+            fd.fd, offset,
+        );
+        // FIXME: In case of multiple threads duplicating the same fd, they still share the same file offset.
+        let fd = dup(fd.fd).map_err(|_| last_error!())?;
+        let mut f = unsafe { File::from_raw_fd(fd) };
+        f.seek(SeekFrom::Start(offset)).map_err(|_| last_error!())?;
+        Ok(f)
+    }
+
+    /// Compressed data of a ready chunk of `bio` in a compressed cache, None if the chunk
+    /// should rather be read as usual, e.g. it's not cached yet or kept decompressed already.
+    fn read_compressed_chunk(
+        &self,
+        state: &BlobCacheState,
+        bio: &RafsBio,
+    ) -> Result<Option<RawChunk>> {
+        let chunk = &bio.chunkinfo;
+        if chunk.is_hole() || self.is_zero_chunk(&bio.blob, chunk.as_ref()) {
+            return Ok(None);
+        }
+        if let Some(hot) = self.hot_cache.as_ref() {
+            if hot.contains(&bio.blob, chunk.as_ref()) {
+                return Ok(None);
+            }
+        }
+        let (fd, _, chunk_map) = match state.get(&bio.blob) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if !chunk_map.has_ready(chunk.as_ref())? {
+            return Ok(None);
+        }
+
+        let mut raw = RawChunk {
+            chunk: chunk.clone(),
+            data: Vec::new(),
+            stream: None,
+            cipher: self.blob_cipher(&bio.blob)?,
+        };
+        if self.compressor() == compress::Algorithm::GZip && chunk.is_compressed() {
+            raw.stream = Some(Self::cache_file_stream(fd, chunk.compress_offset())?);
+        } else {
+            raw.data = alloc_buf(chunk.compress_size() as usize);
+            Self::read_cache_file(fd, &mut raw.data, chunk.compress_offset())?;
+        }
+
+        Ok(Some(raw))
+    }
+
+    /// Account a read of `bio` served from cache, or from backend if not `before_ready`.
+    fn account_read(&self, bio: &RafsBio, before_ready: bool) {
+        probe::cache_access(
+            &bio.blob.blob_id,
+            bio.chunkinfo.index(),
+            bio.chunkinfo.compress_offset(),
+            before_ready,
+        );
+
+        // A prefetched chunk is used only if it's still cached when read, rather than evicted
+        // or purged and fetched again.
+        if self.prefetch_ctx.enable {
+            let key = (bio.blob.blob_index, bio.chunkinfo.compress_offset());
+            if let Some(size) = self.prefetched.lock().unwrap().remove(&key) {
+                if before_ready {
+                    self.metrics.prefetch_used_chunks.inc();
+                    self.metrics.prefetch_used_bytes.add(size as usize);
+                }
+            }
+        }
+
+        // The flag means the chunk is not ready before, but now ready,
+        // so increase the entries_count metric.
+        if !before_ready {
+            self.metrics.entries_count.inc();
+        }
+
+        // Only data in cache files takes cache space, zero chunks are served from memory, and
+        // chunks read from backend may not be cached, e.g. of gzip blobs.
+        let quota = self
+            .quota
+            .as_ref()
+            .filter(|_| !self.is_zero_chunk(&bio.blob, bio.chunkinfo.as_ref()));
+        if let Some(quota) = quota {
+            let size = self.cache_range(bio.chunkinfo.as_ref()).1;
+            if before_ready {
+                quota.touch(&bio.blob, &bio.chunkinfo, size);
+            } else if self.is_cached(bio) {
+                let victims = quota.add(&bio.blob, &bio.chunkinfo, size);
+                if !victims.is_empty() {
+                    self.evict(&self.cache.write().unwrap(), victims);
+                }
+            }
+        }
+        self.check_free_space();
+    }
+
+    fn is_cached(&self, bio: &RafsBio) -> bool {
+        match self.cache.read().unwrap().get(&bio.blob) {
+            Some((_, _, chunk_map)) => chunk_map
+                .has_ready(bio.chunkinfo.as_ref())
+                .unwrap_or_default(),
+            None => false,
+        }
+    }
+
     fn read_partial_chunk(
         &self,
-        fd: RawFd,
+        fd: CacheFd,
         bufs: &[VolatileSlice],
         offset: u64,
         max_size: usize,
     ) -> Result<usize> {
-        readv(fd, bufs, offset, max_size)
+        if let Some(direct_fd) = fd.direct_fd {
+            readv_direct(direct_fd, bufs, offset, max_size)
+        } else {
+            readv(fd.fd, bufs, offset, max_size)
+        }
     }
 
     /// Persist a single chunk into local blob cache file. We have to write to the cache
     /// file in unit of chunk size
-    fn cache(&self, fd: RawFd, buf: &[u8], offset: u64) -> Result<()> {
+    fn cache(&self, fd: CacheFd, buf: &[u8], offset: u64) -> Result<()> {
         loop {
-            let ret = uio::pwrite(fd, buf, offset as i64).map_err(|_| last_error!());
+            let ret = uio::pwrite(fd.fd, buf, offset as i64).map_err(|_| last_error!());
 
             match ret {
                 Ok(nr_write) => {
@@ -340,6 +698,377 @@ impl BlobCache {
             }
         }
 
+        if fd.direct_fd.is_some() {
+            drop_page_cache(fd.fd, offset, buf.len());
+        }
+
+        Ok(())
+    }
+
+    fn is_zero_chunk(&self, blob: &RafsBlobEntry, chunk: &dyn RafsChunkInfo) -> bool {
+        self.zero_chunks
+            .read()
+            .unwrap()
+            .contains(&(blob.blob_index, chunk.compress_offset()))
+    }
+
+    fn set_zero_chunk(&self, blob: &RafsBlobEntry, chunk: &dyn RafsChunkInfo) {
+        let mut zero_chunks = self.zero_chunks.write().unwrap();
+        if zero_chunks.len() >= MAX_ZERO_CHUNKS {
+            // Make room by dropping an arbitrary one, it's merely read from backend again.
+            if let Some(victim) = zero_chunks.iter().next().cloned() {
+                zero_chunks.remove(&victim);
+            }
+        }
+        zero_chunks.insert((blob.blob_index, chunk.compress_offset()));
+    }
+
+    /// Check free space of cache filesystem, release cache space of this mount if asked to.
+    ///
+    /// Return true if the cache filesystem is low on free space.
+    fn check_free_space(&self) -> bool {
+        let watermark = match self.watermark.as_ref() {
+            Some(w) => w,
+            None => return false,
+        };
+
+        if let Some(bytes) = watermark.check() {
+            if self.evict_on_low_space {
+                if let Some(quota) = self.quota.as_ref() {
+                    let victims = quota.evict_lru(bytes);
+                    if !victims.is_empty() {
+                        self.evict(&self.cache.write().unwrap(), victims);
+                    }
+                }
+            }
+        }
+
+        watermark.is_low()
+    }
+
+    /// Wait until the cache filesystem is no longer low on free space.
+    ///
+    /// Return false if prefetch is stopped while waiting.
+    fn wait_for_free_space(&self) -> bool {
+        while self.check_free_space() {
+            let mut waiters = self.space_waiters.lock().unwrap();
+            // Checked with `space_waiters` held, so that stopping can't slip in before waiting.
+            if self.mr_sender.lock().unwrap().is_none() {
+                return false;
+            }
+            *waiters += 1;
+            let (mut waiters, _) = self
+                .space_cond
+                .wait_timeout(waiters, FREE_SPACE_POLL_INTERVAL)
+                .unwrap();
+            *waiters -= 1;
+        }
+        true
+    }
+
+    /// Offset and size of a chunk's data in blob cache file.
+    fn cache_range(&self, chunk: &dyn RafsChunkInfo) -> (u64, u64) {
+        if self.is_compressed {
+            (chunk.compress_offset(), chunk.compress_size() as u64)
+        } else {
+            (chunk.decompress_offset(), chunk.decompress_size() as u64)
+        }
+    }
+
+    /// Account a chunk written to cache against cache quota of the mount, return data to
+    /// evict.
+    fn quota_add(
+        &self,
+        blob: &Arc<RafsBlobEntry>,
+        chunk: &Arc<dyn RafsChunkInfo>,
+    ) -> Vec<QuotaVictim> {
+        match self.quota.as_ref() {
+            Some(quota) => quota.add(blob, chunk, self.cache_range(chunk.as_ref()).1),
+            None => Vec::new(),
+        }
+    }
+
+    /// Drop data and ready state of chunks evicted to stay within cache quota.
+    ///
+    /// The caller should hold the cache state lock for write, so that no reader could see
+    /// a chunk still being ready while its data is gone. Data of blobs other nydusd instances
+    /// have open is kept, as it's used by them as well.
+    fn evict(&self, state: &BlobCacheState, victims: Vec<QuotaVictim>) {
+        let mut evicted = 0;
+        let mut released = 0;
+        let mut shared = 0;
+
+        for v in victims {
+            let entry = match state.blob_map.get(&v.blob().blob_index) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let _exclusive = match entry.try_exclusive() {
+                Ok(Some(exclusive)) => exclusive,
+                Ok(None) => {
+                    shared += 1;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("failed to lock blob cache file {}: {}", entry.blob_id, e);
+                    continue;
+                }
+            };
+            match v {
+                QuotaVictim::Chunk { chunk, size, .. } => {
+                    // Not ready before punched, so that no reader takes the data being dropped.
+                    if let Err(e) = entry.chunk_map.clear_ready(chunk.as_ref()) {
+                        warn!("failed to clear ready state of evicted chunk: {}", e);
+                        continue;
+                    }
+                    let offset = self.cache_range(chunk.as_ref()).0;
+                    punch_hole(entry.file.as_raw_fd(), offset, size).unwrap_or_else(|e| {
+                        warn!("failed to punch hole in blob cache file: {}", e)
+                    });
+                    self.metrics.entries_count.sub(1);
+                    self.metrics.evicted_chunks.inc();
+                    evicted += 1;
+                    released += size;
+                }
+                QuotaVictim::Leftover { keep, size, .. } => {
+                    match self.evict_leftover(entry, &keep) {
+                        Ok(()) => released += size,
+                        Err(e) => warn!(
+                            "failed to evict leftover data of blob {}: {}",
+                            entry.blob_id, e
+                        ),
+                    }
+                }
+            }
+        }
+
+        if shared > 0 {
+            debug!(
+                "kept {} chunks of blobs shared with other instances",
+                shared
+            );
+        }
+        if released > 0 {
+            metrics::record_event(
+                EventKind::CacheGc,
+                self.metrics.id(),
+                format!("evicted {} chunks, {} bytes released", evicted, released),
+            );
+        }
+    }
+
+    /// Drop data of a blob left by previous runs, which is all data but chunks in `keep`.
+    fn evict_leftover(
+        &self,
+        entry: &BlobCacheEntry,
+        keep: &[Arc<dyn RafsChunkInfo>],
+    ) -> Result<()> {
+        entry.chunk_map.clear_all()?;
+
+        let mut ranges: Vec<(u64, u64)> =
+            keep.iter().map(|c| self.cache_range(c.as_ref())).collect();
+        ranges.sort_unstable();
+        // Punch whole blocks at the end, the last partial block would be kept otherwise.
+        let meta = entry.file.metadata()?;
+        ranges.push((div_round_up(meta.len(), meta.blksize()) * meta.blksize(), 0));
+        let mut start = 0;
+        for (offset, size) in ranges {
+            if offset > start {
+                punch_hole(entry.file.as_raw_fd(), start, offset - start)?;
+            }
+            start = std::cmp::max(start, offset + size);
+        }
+
+        for c in keep {
+            entry.chunk_map.set_ready(c.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop cached data of blob `blob_id` in the work dir, for all cache instances of the
+    /// process sharing the work dir. Return false if there is no such blob file, and fail
+    /// with EBUSY if other processes have it open, since they may read or cache any chunk of
+    /// it at any time.
+    fn purge_blob(&self, blob_id: &str) -> Result<bool> {
+        if !is_blob_file_name(blob_id) {
+            return Ok(false);
+        }
+
+        let _purging = PURGE_LOCK.lock().unwrap();
+        let work_dir = self.cache.read().unwrap().work_dir.clone();
+        let instances = INSTANCES
+            .lock()
+            .unwrap()
+            .get(&work_dir)
+            .map_or(Vec::new(), |v| {
+                v.iter().filter_map(|i| i.upgrade()).collect()
+            });
+        // Readers of all instances are kept out, so that no chunk could be seen as ready while
+        // its data is punched out.
+        let states = instances
+            .iter()
+            .map(|i| i.cache.write().unwrap())
+            .collect::<Vec<_>>();
+        let users = instances
+            .iter()
+            .zip(states.iter())
+            .filter_map(|(i, s)| {
+                s.blob_map
+                    .iter()
+                    .find(|(_, e)| e.blob_id == blob_id)
+                    .map(|(idx, e)| (i, s, *idx, e))
+            })
+            .collect::<Vec<_>>();
+
+        let path = format!("{}/{}", work_dir, blob_id);
+        let opened;
+        let file = match users.first() {
+            Some((_, _, _, entry)) => &entry.file,
+            None => match OpenOptions::new().write(true).open(&path) {
+                Ok(file) => {
+                    opened = file;
+                    &opened
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            },
+        };
+        // Shared locks of this process are dropped, so that the exclusive lock is only in
+        // the way of other processes.
+        let result = users
+            .iter()
+            .try_for_each(|(_, _, _, e)| flock(e.file.as_raw_fd(), FlockArg::Unlock))
+            .map_err(|e| eother!(e))
+            .and_then(
+                |_| match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+                    // Cleared before others could take the shared lock again.
+                    Ok(()) => Self::purge_file(file, &path, users.is_empty()).and_then(|_| {
+                        users
+                            .iter()
+                            .try_for_each(|(_, _, _, e)| e.chunk_map.clear_all())
+                    }),
+                    Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => Err(ebusy!(blob_id)),
+                    Err(e) => Err(eother!(e)),
+                },
+            );
+        for (_, _, _, entry) in users.iter() {
+            if let Err(e) = flock(entry.file.as_raw_fd(), FlockArg::LockShared) {
+                warn!("failed to share blob cache file again: {}", e);
+            }
+        }
+        result?;
+
+        for (instance, state, blob_index, _) in users.iter() {
+            if let Some(quota) = state.quota.as_ref() {
+                quota.forget_blob(*blob_index);
+            }
+            if let Some(hot) = instance.hot_cache.as_ref() {
+                hot.purge_blob(*blob_index)?;
+            }
+            instance.record_purge(blob_id);
+        }
+        if users.is_empty() {
+            self.record_purge(blob_id);
+        }
+
+        Ok(true)
+    }
+
+    /// Punch out all data of blob cache file `file` at `path`, which should be locked
+    /// exclusively. The chunk map is removed as well if `unused` by this process, others
+    /// opening the blob take the shared lock before the chunk map and so get an empty one.
+    fn purge_file(file: &File, path: &str, unused: bool) -> Result<()> {
+        // Punch whole blocks, the last partial block would be kept otherwise.
+        let meta = file.metadata()?;
+        let len = div_round_up(meta.len(), meta.blksize()) * meta.blksize();
+        if len > 0 {
+            punch_hole(file.as_raw_fd(), 0, len)?;
+        }
+        if unused {
+            match fs::remove_file(format!("{}.{}", path, indexed::FILE_SUFFIX)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn record_purge(&self, blob_id: &str) {
+        metrics::record_event(
+            EventKind::CacheGc,
+            self.metrics.id(),
+            format!("purged cached data of blob {}", blob_id),
+        );
+        self.metrics.purged_blobs.inc();
+    }
+
+    /// Wait for a slot of the IO scheduler to issue a backend request of `priority`.
+    fn start_io(&self, priority: IoPriority) -> Option<IoPermit> {
+        self.io_scheduler.as_ref().map(|s| s.start(priority))
+    }
+
+    /// Read chunks of a merged request from backend in one shot and put them into cache.
+    ///
+    /// Chunks cached by `prefetch` requests are remembered until read, to tell how much
+    /// prefetched data is actually used.
+    fn fetch_merged_request(
+        &self,
+        mr: &MergedBackendRequest,
+        priority: IoPriority,
+        prefetch: bool,
+    ) -> Result<()> {
+        // The slot is released before taking the state lock, which readers holding slots
+        // may be in the way of.
+        let (raw, chunks) = {
+            let _permit = self.start_io(priority);
+            self.read_chunks(
+                &mr.blob_entry,
+                mr.blob_offset,
+                mr.blob_size as usize,
+                &mr.chunks,
+            )?
+        };
+        let mut cache_guard = self.cache.write().expect("Expect cache lock not poisoned");
+        let (fd, _, chunk_map) = cache_guard.set(&mr.blob_entry).map_err(|e| {
+            error!("Set cache index error!");
+            e
+        })?;
+
+        let mut victims = Vec::new();
+        for (i, c) in mr.chunks.iter().enumerate() {
+            if is_zero(chunks[i].as_slice()) {
+                self.set_zero_chunk(&mr.blob_entry, c.as_ref());
+            } else if !chunk_map.has_ready(c.as_ref()).ok().unwrap_or_default() {
+                let (offset, data) = if self.is_compressed {
+                    let start = (c.compress_offset() - mr.blob_offset) as usize;
+                    let end = start + c.compress_size() as usize;
+                    (c.compress_offset(), &raw[start..end])
+                } else {
+                    (c.decompress_offset(), chunks[i].as_slice())
+                };
+                if let Err(err) = self.cache(fd, data, offset) {
+                    error!("Failed to cache chunk: {}", err);
+                } else {
+                    let _ = chunk_map
+                        .set_ready(c.as_ref())
+                        .map_err(|e| error!("Failed to set chunk ready: {:?}", e));
+                    victims.append(&mut self.quota_add(&mr.blob_entry, c));
+                    if prefetch {
+                        self.prefetched.lock().unwrap().insert(
+                            (mr.blob_entry.blob_index, c.compress_offset()),
+                            c.decompress_size(),
+                        );
+                        self.metrics.prefetch_cached_chunks.inc();
+                        self.metrics
+                            .prefetch_cached_bytes
+                            .add(c.decompress_size() as usize);
+                    }
+                }
+            }
+        }
+        self.evict(&cache_guard, victims);
+
         Ok(())
     }
 
@@ -354,6 +1083,24 @@ impl BlobCache {
         false
     }
 
+    /// Wait for the prefetch bandwidth limiter to allow `size` bytes more.
+    fn throttle_prefetch(&self, size: u32) {
+        if let Some(limiter) = self.limiter.load_full() {
+            let cells = match NonZeroU32::new(size) {
+                Some(cells) => cells,
+                None => return,
+            };
+            if let Err(e) = limiter
+                .check_n(cells)
+                .or_else(|_| block_on(limiter.until_n_ready(cells)))
+            {
+                // `InsufficientCapacity` is the only possible error
+                // Have to give up to avoid dead-loop
+                error!("{}: give up rate-limiting", e);
+            }
+        }
+    }
+
     fn generate_merged_requests(
         &self,
         bios: &mut [RafsBio],
@@ -361,19 +1108,7 @@ impl BlobCache {
         merging_size: usize,
         seq: u64,
     ) {
-        let limiter = |merged_size: u32| {
-            if let Some(ref limiter) = self.limiter {
-                let cells = NonZeroU32::new(merged_size).unwrap();
-                if let Err(e) = limiter
-                    .check_n(cells)
-                    .or_else(|_| block_on(limiter.until_n_ready(cells)))
-                {
-                    // `InsufficientCapacity` is the only possible error
-                    // Have to give up to avoid dead-loop
-                    error!("{}: give up rate-limiting", e);
-                }
-            }
-        };
+        let limiter = |merged_size: u32| self.throttle_prefetch(merged_size);
 
         bios.sort_by_key(|entry| entry.chunkinfo.compress_offset());
         let mut index: usize = 1;
@@ -386,7 +1121,7 @@ impl BlobCache {
 
         if bios.len() == 1 {
             limiter(mr.blob_size);
-            tx.send(mr).unwrap();
+            self.queue_merged_request(tx, mr);
             return;
         }
 
@@ -402,18 +1137,29 @@ impl BlobCache {
             } else {
                 // New a MR if a non-continuous chunk is met.
                 limiter(mr.blob_size);
-                tx.send(mr.clone()).unwrap();
+                self.queue_merged_request(tx, mr.clone());
                 mr.reset();
                 mr.merge_begin(Arc::clone(&cki), cur_bio.blob.clone());
             }
             index += 1;
             if index >= bios.len() {
                 limiter(mr.blob_size);
-                tx.send(mr).unwrap();
+                self.queue_merged_request(tx, mr);
                 break;
             }
         }
     }
+
+    fn queue_merged_request(
+        &self,
+        tx: &mut spmc::Sender<MergedBackendRequest>,
+        mr: MergedBackendRequest,
+    ) {
+        self.prefetch_queued.fetch_add(1, Ordering::Relaxed);
+        self.prefetch_queued_chunks
+            .fetch_add(mr.chunks.len(), Ordering::Relaxed);
+        tx.send(mr).unwrap();
+    }
 }
 
 // TODO: This function is too long... :-(
@@ -437,6 +1183,20 @@ fn kick_prefetch_workers(cache: Arc<BlobCache>) {
                     .fetch_add(1, Ordering::Relaxed);
                 // Safe because channel must be established before prefetch workers
                 'wait_mr: while let Ok(mr) = rx.as_ref().unwrap().recv() {
+                    blobcache.prefetch_queued.fetch_sub(1, Ordering::Relaxed);
+                    blobcache
+                        .prefetch_queued_chunks
+                        .fetch_sub(mr.chunks.len(), Ordering::Relaxed);
+                    // Hold prefetch requests back rather than wedging the node with ENOSPC,
+                    // they are dropped only if prefetch is stopped meanwhile.
+                    if !blobcache.wait_for_free_space() {
+                        blobcache
+                            .metrics
+                            .prefetch_skipped_chunks
+                            .add(mr.chunks.len());
+                        continue;
+                    }
+
                     let blob_offset = mr.blob_offset;
                     let blob_size = mr.blob_size;
                     let continuous_chunks = &mr.chunks;
@@ -490,6 +1250,7 @@ fn kick_prefetch_workers(cache: Arc<BlobCache>) {
                             if blobcache
                                 .read_blobcache_chunk(
                                     fd,
+                                    &mr.blob_entry,
                                     c.as_ref(),
                                     alloc_buf(d_size).as_mut_slice(),
                                     true,
@@ -512,40 +1273,15 @@ fn kick_prefetch_workers(cache: Arc<BlobCache>) {
                         continue 'wait_mr;
                     }
 
-                    if let Ok(chunks) = blobcache.read_chunks(
-                        blob_id,
-                        blob_offset,
-                        blob_size as usize,
-                        &continuous_chunks,
-                    ) {
-                        let mut cache_guard = blobcache
-                            .cache
-                            .write()
-                            .expect("Expect cache lock not poisoned");
-                        if let Ok((fd, _, chunk_map)) = cache_guard
-                            .set(&mr.blob_entry)
-                            .map_err(|_| error!("Set cache index error!"))
-                        {
-                            for (i, c) in continuous_chunks.iter().enumerate() {
-                                if !chunk_map.has_ready(c.as_ref()).ok().unwrap_or_default() {
-                                    let offset = if blobcache.is_compressed {
-                                        c.compress_offset()
-                                    } else {
-                                        c.decompress_offset()
-                                    };
-                                    if let Err(err) =
-                                        blobcache.cache(fd, chunks[i].as_slice(), offset)
-                                    {
-                                        error!("Failed to cache chunk: {}", err);
-                                    } else {
-                                        let _ = chunk_map.set_ready(c.as_ref()).map_err(|e| {
-                                            error!("Failed to set chunk ready: {:?}", e)
-                                        });
-                                    }
-                                }
-                            }
-                        }
-                    }
+                    blobcache
+                        .fetch_merged_request(&mr, IoPriority::Prefetch, true)
+                        .unwrap_or_else(|e| {
+                            debug!(
+                                "failed to prefetch {} chunks: {}",
+                                continuous_chunks.len(),
+                                e
+                            )
+                        });
                 }
                 blobcache
                     .metrics
@@ -582,10 +1318,13 @@ impl RafsCache for BlobCache {
 
     fn read(&self, bio: &RafsBio, bufs: &[VolatileSlice], offset: u64) -> Result<usize> {
         self.metrics.total.inc();
+        let span = TraceSpan::start("blobcache.read");
+        span.set_str("blob_id", &bio.blob.blob_id);
+        span.set_u64("chunk_index", bio.chunkinfo.index() as u64);
 
         // Try to get rid of effect from prefetch.
         if self.prefetch_ctx.is_working() {
-            if let Some(ref limiter) = self.limiter {
+            if let Some(limiter) = self.limiter.load_full() {
                 if let Some(v) = NonZeroU32::new(bufs.len() as u32) {
                     // Even fails in getting tokens, continue to read
                     limiter.check_n(v).unwrap_or(());
@@ -595,34 +1334,376 @@ impl RafsCache for BlobCache {
 
         let (size, before_ready) =
             self.entry_read(&bio.blob, bio.chunkinfo.as_ref(), bufs, offset, bio.size)?;
-
-        // The flag means the chunk is not ready before, but now ready,
-        // so increase the entries_count metric.
-        if !before_ready {
-            self.metrics.entries_count.inc();
-        }
+        span.set_bool("hit", before_ready);
+        self.account_read(bio, before_ready);
 
         Ok(size)
     }
 
-    fn write(&self, _blob_id: &str, _blk: &dyn RafsChunkInfo, _buf: &[u8]) -> Result<usize> {
-        Err(enosys!())
-    }
+    fn read_parallel(&self, bios: &[RafsBio]) -> Vec<Option<Vec<u8>>> {
+        let pool = match self.decompress_pool.as_ref() {
+            Some(pool) => pool,
+            None => return Vec::new(),
+        };
 
-    fn blob_size(&self, blob: &RafsBlobEntry) -> Result<u64> {
+        // Hold the state lock until chunks are decompressed, as in `entry_read()`.
         let cache_guard = self.cache.read().unwrap();
-        let (_, size, _) = match cache_guard.get(blob) {
-            Some(entry) => entry,
-            None => {
-                drop(cache_guard);
+        let mut raw_chunks = Vec::new();
+        let mut indexes = Vec::new();
+        for (idx, bio) in bios.iter().enumerate() {
+            match self.read_compressed_chunk(&cache_guard, bio) {
+                Ok(Some(raw)) => {
+                    raw_chunks.push(raw);
+                    indexes.push(idx);
+                }
+                Ok(None) => {}
+                Err(e) => debug!(
+                    "failed to read chunk {} ahead: {}",
+                    bio.chunkinfo.index(),
+                    e
+                ),
+            }
+        }
+        // Not worth handing a single chunk over to workers.
+        if raw_chunks.len() < 2 {
+            return Vec::new();
+        }
+        let decompressed = pool.decompress(raw_chunks, self.need_validate());
+        drop(cache_guard);
+
+        let mut chunks = vec![None; bios.len()];
+        for (idx, data) in indexes.into_iter().zip(decompressed) {
+            let bio = &bios[idx];
+            // Chunks failing here are read as usual, which deals with corrupted cache data.
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    debug!(
+                        "failed to decompress chunk {}: {}",
+                        bio.chunkinfo.index(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            self.metrics.total.inc();
+            self.metrics.whole_hits.inc();
+            if let Some(hot) = self.hot_cache.as_ref() {
+                hot.record(&bio.blob, bio.chunkinfo.as_ref(), &data);
+            }
+            self.account_read(bio, true);
+            chunks[idx] = Some(data);
+        }
+
+        chunks
+    }
+
+    fn splice(
+        &self,
+        bios: &[RafsBio],
+        pipe: RawFd,
+        prepare: &mut dyn FnMut(usize) -> Result<()>,
+    ) -> Result<Option<usize>> {
+        // Compressed or unvalidated data has to go through userspace anyway.
+        if self.is_compressed || self.need_validate() {
+            return Ok(None);
+        }
+
+        // Hold the state lock until data is in the pipe, which keeps referring to pages of
+        // cache files even if the blob is purged afterwards.
+        let cache_guard = self.cache.read().unwrap();
+        // O_DIRECT is there to keep cache files out of page cache, which splice goes through.
+        if cache_guard.direct_io {
+            return Ok(None);
+        }
+        let mut ranges = Vec::with_capacity(bios.len());
+        for bio in bios {
+            let chunk = bio.chunkinfo.as_ref();
+            let (fd, _, chunk_map) = match cache_guard.get(&bio.blob) {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            if self.is_zero_chunk(&bio.blob, chunk) || !chunk_map.has_ready(chunk)? {
+                return Ok(None);
+            }
+            ranges.push((
+                fd.fd,
+                chunk.decompress_offset() + bio.offset as u64,
+                bio.size,
+            ));
+        }
+
+        let size = ranges.iter().map(|(_, _, size)| size).sum();
+        prepare(size)?;
+        for (fd, offset, size) in ranges {
+            splice_to_pipe(fd, offset, pipe, size)?;
+        }
+        drop(cache_guard);
+
+        for bio in bios {
+            self.metrics.total.inc();
+            self.metrics.partial_hits.inc();
+            self.account_read(bio, true);
+        }
+
+        Ok(Some(size))
+    }
+
+    fn write(&self, _blob_id: &str, _blk: &dyn RafsChunkInfo, _buf: &[u8]) -> Result<usize> {
+        Err(enosys!())
+    }
+
+    fn fetch(&self, bios: &[RafsBio], priority: IoPriority) -> Result<usize> {
+        let start = match bios.iter().position(|b| !b.chunkinfo.is_hole()) {
+            Some(start) => start,
+            None => return Ok(0),
+        };
+        let first = &bios[start];
+        // The read is going to hit cache, don't bother backend for its neighbors.
+        if !self.need_fetch(first)? {
+            return Ok(0);
+        }
+
+        let mut mr = MergedBackendRequest::new(0);
+        mr.merge_begin(first.chunkinfo.clone(), first.blob.clone());
+        let mut prior = first;
+        for bio in bios[start + 1..].iter() {
+            if !Self::is_chunk_continuous(prior, bio) {
+                break;
+            }
+            mr.merge_one_chunk(bio.chunkinfo.clone());
+            prior = bio;
+        }
+
+        self.metrics.amplified_chunks.add(mr.chunks.len() - 1);
+        let prefetch = priority == IoPriority::Prefetch;
+        if prefetch {
+            self.throttle_prefetch(mr.blob_size);
+        }
+        self.fetch_merged_request(&mr, priority, prefetch)?;
+
+        Ok(mr.blob_size as usize)
+    }
+
+    fn need_fetch(&self, bio: &RafsBio) -> Result<bool> {
+        if bio.chunkinfo.is_hole() || self.is_zero_chunk(&bio.blob, bio.chunkinfo.as_ref()) {
+            return Ok(false);
+        }
+        let cache_guard = self.cache.read().unwrap();
+        let (_, _, chunk_map) = match cache_guard.get(&bio.blob) {
+            Some(entry) => entry,
+            None => {
+                drop(cache_guard);
+                self.cache.write().unwrap().set(&bio.blob)?
+            }
+        };
+        Ok(!chunk_map.has_ready(bio.chunkinfo.as_ref())?)
+    }
+
+    fn blob_size(&self, blob: &RafsBlobEntry) -> Result<u64> {
+        let cache_guard = self.cache.read().unwrap();
+        let (_, size, _) = match cache_guard.get(blob) {
+            Some(entry) => entry,
+            None => {
+                drop(cache_guard);
                 self.cache.write().unwrap().set(blob)?
             }
         };
         Ok(size)
     }
 
+    fn cached_blobs(&self) -> Result<Vec<CachedBlob>> {
+        let state = self.cache.read().unwrap();
+        let mut blobs = Vec::with_capacity(state.blob_map.len());
+
+        for entry in state.blob_map.values() {
+            blobs.push(CachedBlob {
+                blob_id: entry.blob_id.clone(),
+                work_dir: state.work_dir.clone(),
+                // st_blocks is always in unit of 512 bytes.
+                disk_usage: entry.file.metadata()?.blocks() * 512,
+                open: true,
+            });
+        }
+
+        // Also those left by previous runs or cached by other processes.
+        for dirent in fs::read_dir(&state.work_dir)? {
+            let dirent = dirent?;
+            let name = match dirent.file_name().into_string() {
+                Ok(name) if is_blob_file_name(&name) => name,
+                _ => continue,
+            };
+            if state.blob_map.values().any(|e| e.blob_id == name) {
+                continue;
+            }
+            // Skip files removed since listed.
+            if let Ok(meta) = dirent.metadata() {
+                if meta.is_file() {
+                    blobs.push(CachedBlob {
+                        blob_id: name,
+                        work_dir: state.work_dir.clone(),
+                        disk_usage: meta.blocks() * 512,
+                        open: false,
+                    });
+                }
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    fn purge_blobs(&self, blob_id: Option<&str>) -> Result<usize> {
+        let blob_ids = match blob_id {
+            Some(id) => vec![id.to_string()],
+            None => self
+                .cache
+                .read()
+                .unwrap()
+                .blob_map
+                .values()
+                .map(|e| e.blob_id.clone())
+                .collect(),
+        };
+        let mut purged = 0;
+        let mut busy = 0;
+
+        for id in blob_ids.iter() {
+            match self.purge_blob(id) {
+                Ok(true) => purged += 1,
+                Ok(false) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => busy += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        if busy > 0 {
+            return Err(ebusy!(format!(
+                "{} blobs are in use by other processes",
+                busy
+            )));
+        }
+
+        Ok(purged)
+    }
+
+    fn import_blob(&self, bios: &[RafsBio], src: &Path) -> Result<usize> {
+        // Stargz chunks have no digest to validate the imported data against.
+        if self.compressor() == compress::Algorithm::GZip {
+            return Err(enosys!("gzip compressed blobs can't be imported"));
+        }
+        let blob = match bios.first() {
+            Some(bio) => bio.blob.clone(),
+            None => return Ok(0),
+        };
+        if bios.iter().any(|b| b.blob.blob_index != blob.blob_index) {
+            return Err(einval!("chunks to import are not of the same blob"));
+        }
+
+        let file = File::open(src)?;
+        let mut imported = 0;
+        self.cache.write().unwrap().set(&blob)?;
+        let cipher = self.blob_cipher(&blob)?;
+
+        for batch in bios.chunks(IMPORT_BATCH_CHUNKS) {
+            let mut victims = Vec::new();
+            // Hold the state lock while caching chunks, like readers do.
+            let cache_guard = self.cache.read().unwrap();
+            let (fd, _, chunk_map) = cache_guard
+                .get(&blob)
+                .ok_or_else(|| enoent!("blob cache entry is gone"))?;
+
+            for bio in batch.iter().filter(|b| !b.chunkinfo.is_hole()) {
+                let chunk = bio.chunkinfo.as_ref();
+                if self.is_zero_chunk(&blob, chunk) || chunk_map.has_ready(chunk)? {
+                    continue;
+                }
+
+                let mut raw = alloc_buf(chunk.compress_size() as usize);
+                let nr_read =
+                    uio::pread(file.as_raw_fd(), &mut raw, chunk.compress_offset() as i64)
+                        .map_err(|_| last_error!())?;
+                if nr_read != raw.len() {
+                    return Err(einval!(format!(
+                        "{:?} is too short for chunk {}",
+                        src,
+                        chunk.block_id()
+                    )));
+                }
+                let mut d = alloc_buf(chunk.decompress_size() as usize);
+                self.process_raw_chunk(
+                    chunk,
+                    &raw,
+                    None,
+                    &mut d,
+                    chunk.is_compressed(),
+                    true,
+                    cipher.as_deref(),
+                )
+                .map_err(|e| {
+                    einval!(format!(
+                        "chunk {} of {:?} is corrupted, {}",
+                        chunk.block_id(),
+                        src,
+                        e
+                    ))
+                })?;
+
+                if is_zero(&d) {
+                    self.set_zero_chunk(&blob, chunk);
+                    continue;
+                }
+                let buf = if self.is_compressed { &raw } else { &d };
+                self.cache(fd, buf, self.cache_range(chunk).0)?;
+                chunk_map.set_ready(chunk)?;
+
+                self.metrics.entries_count.inc();
+                imported += 1;
+                victims.append(&mut self.quota_add(&blob, &bio.chunkinfo));
+            }
+            drop(cache_guard);
+
+            if !victims.is_empty() {
+                self.evict(&self.cache.write().unwrap(), victims);
+            }
+        }
+        self.check_free_space();
+
+        Ok(imported)
+    }
+
+    fn memory_usage(&self) -> CacheMemoryUsage {
+        let mut chunk_map_bytes = self
+            .cache
+            .read()
+            .unwrap()
+            .blob_map
+            .values()
+            .map(|e| e.chunk_map.memory_usage())
+            .sum::<usize>();
+        chunk_map_bytes +=
+            hash_table_bytes::<(u32, u64)>(self.zero_chunks.read().unwrap().capacity());
+        chunk_map_bytes +=
+            hash_table_bytes::<((u32, u64), u32)>(self.prefetched.lock().unwrap().capacity());
+        chunk_map_bytes += self.hot_cache.as_ref().map_or(0, |h| h.memory_usage());
+        chunk_map_bytes += self.quota.as_ref().map_or(0, |q| q.memory_usage());
+
+        let prefetch_queue_requests = self.prefetch_queued.load(Ordering::Relaxed);
+        let prefetch_queue_bytes = prefetch_queue_requests * size_of::<MergedBackendRequest>()
+            + self.prefetch_queued_chunks.load(Ordering::Relaxed)
+                * size_of::<Arc<dyn RafsChunkInfo>>();
+
+        CacheMemoryUsage {
+            chunk_map_bytes,
+            inflight_buffer_bytes: self.backend().metrics().inflight_bytes(),
+            prefetch_queue_bytes,
+            prefetch_queue_requests,
+        }
+    }
+
     fn release(&self) {
         self.metrics.release().unwrap_or_else(|e| error!("{:?}", e));
+        if let Some(pool) = self.decompress_pool.as_ref() {
+            pool.stop();
+        }
 
         // TODO: Cache is responsible to release backend's resources
         self.backend().release()
@@ -631,19 +1712,33 @@ impl RafsCache for BlobCache {
         let merging_size = self.prefetch_ctx.merging_size;
         let seq = self.prefetch_seq.fetch_add(1, Ordering::Relaxed);
 
+        // Hole chunks have no data in blob at all.
+        let mut bios: Vec<RafsBio> = bios
+            .iter()
+            .filter(|b| !b.chunkinfo.is_hole())
+            .cloned()
+            .collect();
         self.metrics.prefetch_unmerged_chunks.add(bios.len());
 
         if let Some(mr_sender) = self.mr_sender.lock().unwrap().as_mut() {
-            self.generate_merged_requests(bios, mr_sender, merging_size, seq);
+            self.generate_merged_requests(&mut bios, mr_sender, merging_size, seq);
         }
 
         Ok(0)
     }
 
+    fn set_prefetch_bandwidth(&self, bandwidth_rate: u32) -> Result<()> {
+        self.limiter.store(new_prefetch_limiter(bandwidth_rate));
+        Ok(())
+    }
+
     fn stop_prefetch(&self) -> StorageResult<()> {
         if let Some(s) = self.mr_sender.lock().unwrap().take() {
             drop(s);
         }
+        // Workers having seen the sender are all waiting once `space_waiters` can be taken.
+        drop(self.space_waiters.lock().unwrap());
+        self.space_cond.notify_all();
 
         let mut guard = self
             .prefetch_threads
@@ -673,18 +1768,102 @@ impl RafsCache for BlobCache {
     fn need_validate(&self) -> bool {
         self.validate
     }
+
+    fn key_provider(&self) -> Option<&dyn KeyProvider> {
+        self.key_provider.as_deref()
+    }
+}
+
+/// Record chunk corruption as a daemon event, so that it can be retrieved by the events API.
+fn corruption_event(blob: &RafsBlobEntry, chunk: &dyn RafsChunkInfo, msg: &str) {
+    let event = format!(
+        "blobcache chunk {} of blob {} corrupted: {}",
+        chunk.block_id(),
+        blob.blob_id,
+        msg
+    );
+    warn!("{}", event);
+    ERROR_HOLDER
+        .lock()
+        .unwrap()
+        .push(&event)
+        .unwrap_or_else(|_| error!("Failed when try to hold error"));
 }
 
 #[derive(Clone, Deserialize)]
 struct BlobCacheConfig {
     #[serde(default = "default_work_dir")]
     work_dir: String,
+    /// Max number of chunks kept decompressed aside a compressed cache, 0 to disable.
+    #[serde(default)]
+    hot_chunks: usize,
+    #[serde(default = "default_hot_promote_threshold")]
+    hot_promote_threshold: u32,
+    /// Read cache files with O_DIRECT to avoid duplicating chunk data in host page cache.
+    #[serde(default)]
+    direct_io: bool,
+    /// Read uncompressed cache files through memory mapping rather than `pread()`.
+    #[serde(default)]
+    mmap: bool,
+    /// Max bytes of cache space taken by this mount, 0 means unlimited.
+    #[serde(default)]
+    quota: u64,
+    /// Pause prefetch when free space percentage of cache filesystem drops below this,
+    /// 0 disables the check.
+    #[serde(default)]
+    free_space_low_watermark: u8,
+    /// Resume prefetch when free space percentage gets back to this, defaults to the low one.
+    #[serde(default)]
+    free_space_high_watermark: u8,
+    /// Evict least recently used chunks of this mount when low on free space.
+    #[serde(default)]
+    evict_on_low_space: bool,
+    /// Number of threads decompressing chunks of large reads in parallel, only for compressed
+    /// cache, 0 to decompress in the reading thread.
+    #[serde(default)]
+    decompress_threads: usize,
+    /// Max number of backend requests in flight, scheduled so that reads go ahead of prefetch.
+    /// 0 disables the scheduling.
+    #[serde(default)]
+    io_scheduler_slots: usize,
+    /// Max milliseconds a prefetch request waits for reads before taking a free slot anyway.
+    #[serde(default = "default_io_scheduler_max_wait_ms")]
+    io_scheduler_max_wait_ms: u64,
+    /// Allow chunks of encrypted blobs to be cached decrypted, in uncompressed cache or as hot
+    /// chunks. Only enable it if `work_dir` is as trusted as memory of nydusd.
+    #[serde(default)]
+    plaintext: bool,
 }
 
 fn default_work_dir() -> String {
     ".".to_string()
 }
 
+fn default_hot_promote_threshold() -> u32 {
+    4
+}
+
+fn default_io_scheduler_max_wait_ms() -> u64 {
+    200
+}
+
+fn new_prefetch_limiter(bandwidth_rate: u32) -> Option<Arc<PrefetchLimiter>> {
+    // If the given value is less than blob chunk size, it exceeds burst size of the limiter ending
+    // up with throttling all throughput.
+    // TODO: We get the chunk size by a constant which is the default value and it's not
+    // easy to get real value now. Perhaps we should have a configuration center?
+    let tweaked_bw_limit = if bandwidth_rate != 0 {
+        std::cmp::max(RAFS_DEFAULT_BLOCK_SIZE as u32, bandwidth_rate)
+    } else {
+        0
+    };
+
+    NonZeroU32::new(tweaked_bw_limit).map(|v| {
+        info!("Prefetch bandwidth will be limited at {}Bytes/S", v);
+        Arc::new(RateLimiter::direct(Quota::per_second(v)))
+    })
+}
+
 pub fn new(
     config: CacheConfig,
     backend: Arc<dyn BlobBackend + Sync + Send>,
@@ -716,23 +1895,7 @@ pub fn new(
         }
     }?;
 
-    // If the given value is less than blob chunk size, it exceeds burst size of the limiter ending
-    // up with throttling all throughput.
-    // TODO: We get the chunk size by a constant which is the default value and it's not
-    // easy to get real value now. Perhaps we should have a configuration center?
-    let tweaked_bw_limit = if config.prefetch_worker.bandwidth_rate != 0 {
-        std::cmp::max(
-            RAFS_DEFAULT_BLOCK_SIZE as u32,
-            config.prefetch_worker.bandwidth_rate,
-        )
-    } else {
-        0
-    };
-
-    let limiter = NonZeroU32::new(tweaked_bw_limit).map(|v| {
-        info!("Prefetch bandwidth will be limited at {}Bytes/S", v);
-        Arc::new(RateLimiter::direct(Quota::per_second(v)))
-    });
+    let limiter = new_prefetch_limiter(config.prefetch_worker.bandwidth_rate);
 
     let mut enabled = false;
     let (tx, rx) = if config.prefetch_worker.enable {
@@ -743,14 +1906,90 @@ pub fn new(
         (None, None)
     };
 
+    // Unless allowed by `plaintext`, decrypted data must not go to disk, so encrypted blobs
+    // are cached compressed and encrypted as they are in backend, without decompressed copies
+    // of hot chunks.
+    let plaintext = config.key_provider.is_none() || blob_config.plaintext;
+    if !plaintext && !config.cache_compressed {
+        return Err(einval!(
+            "blobcache has to be compressed to cache encrypted blobs"
+        ));
+    }
+
+    let hot_cache = if config.cache_compressed && plaintext && blob_config.hot_chunks > 0 {
+        info!(
+            "Keep at most {} hot chunks decompressed, promote after {} accesses",
+            blob_config.hot_chunks, blob_config.hot_promote_threshold
+        );
+        Some(HotChunkCache::new(
+            work_dir,
+            blob_config.hot_chunks,
+            blob_config.hot_promote_threshold,
+        ))
+    } else {
+        None
+    };
+
+    let decompress_pool = if config.cache_compressed && blob_config.decompress_threads > 0 {
+        info!(
+            "Decompress chunks of large reads with {} threads",
+            blob_config.decompress_threads
+        );
+        Some(DecompressPool::new(
+            blob_config.decompress_threads,
+            compressor,
+            digester,
+        )?)
+    } else {
+        None
+    };
+
+    let io_scheduler = if blob_config.io_scheduler_slots > 0 {
+        info!(
+            "Schedule backend requests by priority with {} slots",
+            blob_config.io_scheduler_slots
+        );
+        Some(IoScheduler::new(
+            blob_config.io_scheduler_slots,
+            Duration::from_millis(blob_config.io_scheduler_max_wait_ms),
+        ))
+    } else {
+        None
+    };
+
+    let watermark = if blob_config.free_space_low_watermark > 0 {
+        Some(DiskWatermark::new(
+            work_dir,
+            blob_config.free_space_low_watermark,
+            blob_config.free_space_high_watermark,
+        )?)
+    } else {
+        None
+    };
+    let evict_on_low_space = watermark.is_some() && blob_config.evict_on_low_space;
+
+    let quota = if blob_config.quota > 0 {
+        info!("Blob cache quota is {} bytes", blob_config.quota);
+        Some(Arc::new(CacheQuota::new(blob_config.quota)))
+    } else if evict_on_low_space {
+        // Only track least recently used chunks for eviction.
+        Some(Arc::new(CacheQuota::new(u64::MAX)))
+    } else {
+        None
+    };
+
     let metrics = BlobcacheMetrics::new(id, work_dir);
     let cache = Arc::new(BlobCache {
         cache: Arc::new(RwLock::new(BlobCacheState {
             blob_map: HashMap::new(),
             work_dir: work_dir.to_string(),
+            direct_io: blob_config.direct_io,
+            // O_DIRECT is meant to keep cache files out of page cache, which a mapping is.
+            mmap: blob_config.mmap && !config.cache_compressed && !blob_config.direct_io,
             backend_size_valid: compressor == compress::Algorithm::GZip,
             metrics: metrics.clone(),
             backend: backend.clone(),
+            quota: quota.clone(),
         })),
         validate: config.cache_validate,
         is_compressed: config.cache_compressed,
@@ -758,12 +1997,26 @@ pub fn new(
         prefetch_ctx: config.prefetch_worker.into(),
         compressor,
         digester,
-        limiter,
+        limiter: ArcSwapOption::new(limiter),
         mr_sender: Arc::new(Mutex::new(tx)),
         mr_receiver: rx,
         prefetch_seq: AtomicU64::new(0),
         metrics,
         prefetch_threads: Mutex::new(Vec::<_>::new()),
+        hot_cache,
+        decompress_pool,
+        quota,
+        watermark,
+        evict_on_low_space,
+        zero_chunks: RwLock::new(HashSet::new()),
+        inflight: InflightChunks::default(),
+        io_scheduler,
+        prefetched: Mutex::new(HashMap::new()),
+        prefetch_queued: AtomicUsize::new(0),
+        prefetch_queued_chunks: AtomicUsize::new(0),
+        space_waiters: Mutex::new(0),
+        space_cond: Condvar::new(),
+        key_provider: config.key_provider,
     });
 
     cache
@@ -773,6 +2026,12 @@ pub fn new(
         .unwrap()
         .insert("hinted".to_string());
 
+    let mut instances = INSTANCES.lock().unwrap();
+    let shared = instances.entry(work_dir.to_string()).or_default();
+    shared.retain(|i| i.strong_count() > 0);
+    shared.push(Arc::downgrade(&cache));
+    drop(instances);
+
     if enabled {
         kick_prefetch_workers(cache.clone());
     }
@@ -780,28 +2039,42 @@ pub fn new(
     Ok(cache)
 }
 
+/// Blob cache files are named by blob id, other files in the work dir have suffixes.
+fn is_blob_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('.') && !name.contains('/')
+}
+
 #[cfg(test)]
 mod blob_cache_tests {
     use std::alloc::{alloc, Layout};
+    use std::io::Result;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
     use std::slice::from_raw_parts;
-    use std::sync::Arc;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
 
+    use nix::fcntl::{flock, FlockArg};
     use vm_memory::{VolatileMemory, VolatileSlice};
     use vmm_sys_util::tempdir::TempDir;
 
     use crate::backend::{BackendResult, BlobBackend};
-    use crate::cache::blobcache;
+    use crate::cache::blobcache::{self, new_prefetch_limiter, BlobCache};
+    use crate::cache::mock::MockChunkInfo;
+    use crate::cache::scheduler::IoPriority;
     use crate::cache::PrefetchWorker;
     use crate::cache::RafsCache;
     use crate::compress;
-    use crate::device::{RafsBio, RafsBlobEntry, RafsChunkFlags, RafsChunkInfo};
+    use crate::crypt::{BlobCipher, KeyProvider};
+    use crate::device::{RafsBio, RafsBlobEntry, RafsChunkFlags};
     use crate::factory::CacheConfig;
-    use crate::impl_getter;
+    use crate::utils::is_zero;
     use crate::RAFS_DEFAULT_BLOCK_SIZE;
 
     use nydus_utils::{
         digest::{self, RafsDigest},
-        metrics::BackendMetrics,
+        metrics::{BackendMetrics, Metric},
     };
 
     struct MockBackend {
@@ -844,76 +2117,65 @@ mod blob_cache_tests {
         }
     }
 
-    #[derive(Default, Clone)]
-    struct MockChunkInfo {
-        pub block_id: RafsDigest,
-        pub blob_index: u32,
-        pub flags: RafsChunkFlags,
-        pub compress_size: u32,
-        pub decompress_size: u32,
-        pub compress_offset: u64,
-        pub decompress_offset: u64,
-        pub file_offset: u64,
-        pub index: u32,
-        pub reserved: u32,
+    /// Settings of a blob cache under test, those most tests take by default.
+    struct BlobCacheFixture {
+        work_dir: PathBuf,
+        /// More fields of the blobcache config in JSON, e.g. `"quota": 100`.
+        extra: String,
+        validate: bool,
+        compressed: bool,
+        prefetch_worker: PrefetchWorker,
+        key_provider: Option<Arc<dyn KeyProvider>>,
+        compressor: compress::Algorithm,
+        backend: Arc<dyn BlobBackend + Send + Sync>,
     }
 
-    impl MockChunkInfo {
-        fn new() -> Self {
-            MockChunkInfo::default()
+    impl BlobCacheFixture {
+        fn new(work_dir: &Path) -> Self {
+            BlobCacheFixture {
+                work_dir: work_dir.to_path_buf(),
+                extra: String::new(),
+                validate: true,
+                compressed: false,
+                prefetch_worker: PrefetchWorker::default(),
+                key_provider: None,
+                compressor: compress::Algorithm::LZ4Block,
+                backend: Arc::new(MockBackend {
+                    metrics: BackendMetrics::new("id", "mock"),
+                }),
+            }
         }
-    }
 
-    impl RafsChunkInfo for MockChunkInfo {
-        fn block_id(&self) -> &RafsDigest {
-            &self.block_id
-        }
-        fn is_compressed(&self) -> bool {
-            self.flags.contains(RafsChunkFlags::COMPRESSED)
-        }
-        fn is_hole(&self) -> bool {
-            self.flags.contains(RafsChunkFlags::HOLECHUNK)
+        fn build(&self) -> Result<Arc<BlobCache>> {
+            let mut s = format!(r###"{{ "work_dir": {:?}"###, self.work_dir);
+            if !self.extra.is_empty() {
+                s = format!("{}, {}", s, self.extra);
+            }
+            let cache_config = CacheConfig {
+                cache_validate: self.validate,
+                cache_compressed: self.compressed,
+                cache_type: String::from("blobcache"),
+                cache_config: serde_json::from_str(&format!("{} }}", s)).unwrap(),
+                prefetch_worker: self.prefetch_worker.clone(),
+                key_provider: self.key_provider.clone(),
+            };
+            blobcache::new(
+                cache_config,
+                self.backend.clone(),
+                self.compressor,
+                digest::Algorithm::Blake3,
+                "id",
+            )
         }
-        impl_getter!(blob_index, blob_index, u32);
-        impl_getter!(index, index, u32);
-        impl_getter!(compress_offset, compress_offset, u64);
-        impl_getter!(compress_size, compress_size, u32);
-        impl_getter!(decompress_offset, decompress_offset, u64);
-        impl_getter!(decompress_size, decompress_size, u32);
-        impl_getter!(file_offset, file_offset, u64);
-        impl_getter!(flags, flags, RafsChunkFlags);
     }
 
     #[test]
     fn test_add() {
         // new blob cache
         let tmp_dir = TempDir::new().unwrap();
-        let s = format!(
-            r###"
-        {{
-            "work_dir": {:?}
-        }}
-        "###,
-            tmp_dir.as_path().to_path_buf().join("cache"),
-        );
-
-        let cache_config = CacheConfig {
-            cache_validate: true,
-            cache_compressed: false,
-            cache_type: String::from("blobcache"),
-            cache_config: serde_json::from_str(&s).unwrap(),
-            prefetch_worker: PrefetchWorker::default(),
-        };
-        let blob_cache = blobcache::new(
-            cache_config,
-            Arc::new(MockBackend {
-                metrics: BackendMetrics::new("id", "mock"),
-            }) as Arc<dyn BlobBackend + Send + Sync>,
-            compress::Algorithm::LZ4Block,
-            digest::Algorithm::Blake3,
-            "id",
-        )
-        .unwrap();
+        let blob_cache = BlobCacheFixture::new(&tmp_dir.as_path().join("cache"))
+            .build()
+            .unwrap();
 
         // generate backend data
         let mut expect = vec![1u8; 100];
@@ -940,6 +2202,7 @@ mod blob_cache_tests {
                 blob_id: blob_id.to_string(),
                 blob_index: 0,
                 blob_cache_size: 0,
+                key_version: 0,
             }),
             50,
             50,
@@ -972,4 +2235,654 @@ mod blob_cache_tests {
         assert_eq!(r1, &expect[50..]);
         assert_eq!(r2, &expect[50..]);
     }
+
+    #[test]
+    fn test_fetch_continuous_chunks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let blob_cache = BlobCacheFixture::new(&work_dir).build().unwrap();
+
+        // Both chunks come from a single backend read of 200 bytes.
+        let mut expect = vec![0u8; 200];
+        let blob_id = "blobcache";
+        blob_cache
+            .backend
+            .read(blob_id, expect.as_mut(), 0)
+            .unwrap();
+        let blob = Arc::new(RafsBlobEntry {
+            blob_id: blob_id.to_string(),
+            ..Default::default()
+        });
+        let bios = (0..2)
+            .map(|i| {
+                let range = i * 100..(i + 1) * 100;
+                let mut chunk = MockChunkInfo::new();
+                chunk.block_id = RafsDigest::from_buf(&expect[range], digest::Algorithm::Blake3);
+                chunk.compress_offset = i as u64 * 100;
+                chunk.compress_size = 100;
+                chunk.decompress_offset = i as u64 * 100;
+                chunk.decompress_size = 100;
+                RafsBio::new(
+                    Arc::new(chunk),
+                    blob.clone(),
+                    0,
+                    100,
+                    RAFS_DEFAULT_BLOCK_SIZE as u32,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert!(blob_cache.need_fetch(&bios[0]).unwrap());
+        assert_eq!(blob_cache.fetch(&bios, IoPriority::OnDemand).unwrap(), 200);
+        assert_eq!(blob_cache.metrics.amplified_chunks.count(), 1);
+        assert_eq!(std::fs::read(work_dir.join(blob_id)).unwrap(), expect);
+
+        // Chunks already cached are not fetched again.
+        assert!(!blob_cache.need_fetch(&bios[0]).unwrap());
+        assert!(!blob_cache.need_fetch(&bios[1]).unwrap());
+        assert_eq!(blob_cache.fetch(&bios, IoPriority::OnDemand).unwrap(), 0);
+        assert_eq!(blob_cache.metrics.amplified_chunks.count(), 1);
+    }
+
+    #[test]
+    fn test_splice() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let blob_cache = BlobCacheFixture {
+            validate: false,
+            ..BlobCacheFixture::new(&work_dir)
+        }
+        .build()
+        .unwrap();
+
+        let mut expect = vec![0u8; 100];
+        blob_cache
+            .backend
+            .read("blobcache", expect.as_mut(), 0)
+            .unwrap();
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let bio = RafsBio::new(
+            Arc::new(chunk),
+            Arc::new(RafsBlobEntry {
+                blob_id: "blobcache".to_string(),
+                ..Default::default()
+            }),
+            20,
+            50,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        );
+        let (rd, wr) = nix::unistd::pipe().unwrap();
+        let mut prepared = Vec::new();
+
+        // Nothing goes into the pipe before the chunk is cached.
+        let r = blob_cache.splice(&[bio.clone()], wr, &mut |size| {
+            prepared.push(size);
+            Ok(())
+        });
+        assert_eq!(r.unwrap(), None);
+        assert!(prepared.is_empty());
+
+        assert_eq!(
+            blob_cache
+                .fetch(&[bio.clone()], IoPriority::OnDemand)
+                .unwrap(),
+            100
+        );
+        let r = blob_cache.splice(&[bio], wr, &mut |size| {
+            prepared.push(size);
+            Ok(())
+        });
+        assert_eq!(r.unwrap(), Some(50));
+        assert_eq!(prepared, vec![50]);
+        let mut buf = vec![0u8; 100];
+        assert_eq!(nix::unistd::read(rd, &mut buf).unwrap(), 50);
+        assert_eq!(&buf[..50], &expect[20..70]);
+
+        nix::unistd::close(rd).unwrap();
+        nix::unistd::close(wr).unwrap();
+    }
+
+    #[test]
+    fn test_purge_blobs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let blob_cache = BlobCacheFixture::new(&work_dir).build().unwrap();
+
+        let mut expect = vec![0u8; 100];
+        let blob_id = "blobcache";
+        blob_cache
+            .backend
+            .read(blob_id, expect.as_mut(), 0)
+            .unwrap();
+        let blob = Arc::new(RafsBlobEntry {
+            blob_id: blob_id.to_string(),
+            ..Default::default()
+        });
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let bios = vec![RafsBio::new(
+            Arc::new(chunk),
+            blob,
+            0,
+            100,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        )];
+
+        assert_eq!(blob_cache.fetch(&bios, IoPriority::OnDemand).unwrap(), 100);
+        let blobs = blob_cache.cached_blobs().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].blob_id, blob_id);
+        assert!(blobs[0].disk_usage > 0);
+
+        assert_eq!(blob_cache.purge_blobs(Some("no-such-blob")).unwrap(), 0);
+        assert_eq!(blob_cache.purge_blobs(Some(blob_id)).unwrap(), 1);
+        assert_eq!(blob_cache.cached_blobs().unwrap()[0].disk_usage, 0);
+        assert_eq!(blob_cache.metrics.purged_blobs.count(), 1);
+
+        // Purged chunks are fetched from backend again.
+        assert_eq!(blob_cache.fetch(&bios, IoPriority::OnDemand).unwrap(), 100);
+        assert_eq!(std::fs::read(work_dir.join(blob_id)).unwrap(), expect);
+    }
+
+    fn chunk_bio(index: u64, blob: &Arc<RafsBlobEntry>) -> RafsBio {
+        let mut chunk = MockChunkInfo::new();
+        chunk.index = index as u32;
+        chunk.compress_offset = index * 4096;
+        chunk.compress_size = 4096;
+        chunk.decompress_offset = index * 4096;
+        chunk.decompress_size = 4096;
+        RafsBio::new(
+            Arc::new(chunk),
+            blob.clone(),
+            0,
+            4096,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        )
+    }
+
+    #[test]
+    fn test_evict_shared_blob() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let fixture = BlobCacheFixture {
+            extra: r#""quota": 8192"#.to_string(),
+            validate: false,
+            compressor: compress::Algorithm::None,
+            ..BlobCacheFixture::new(&work_dir)
+        };
+        let blob = Arc::new(RafsBlobEntry {
+            chunk_count: 8,
+            blob_id: "blobcache".to_string(),
+            ..Default::default()
+        });
+        let blob_file = work_dir.join("blobcache");
+        let disk_usage = || std::fs::metadata(&blob_file).unwrap().blocks() * 512;
+
+        // Another instance has the blob open, its data is kept beyond the quota.
+        let cache = fixture.build().unwrap();
+        let other = fixture.build().unwrap();
+        other
+            .fetch(&[chunk_bio(0, &blob)], IoPriority::OnDemand)
+            .unwrap();
+        for i in 0..3 {
+            cache
+                .fetch(&[chunk_bio(i, &blob)], IoPriority::OnDemand)
+                .unwrap();
+        }
+        assert_eq!(cache.metrics.evicted_chunks.count(), 0);
+        assert_eq!(disk_usage(), 3 * 4096);
+
+        // Evicted once the blob is no longer shared.
+        drop(other);
+        cache
+            .fetch(&[chunk_bio(3, &blob)], IoPriority::OnDemand)
+            .unwrap();
+        assert_eq!(cache.metrics.evicted_chunks.count(), 1);
+        assert_eq!(disk_usage(), 3 * 4096);
+        drop(cache);
+
+        // Data left by previous runs takes quota, and is evicted before chunks cached since.
+        let cache = fixture.build().unwrap();
+        cache
+            .fetch(&[chunk_bio(4, &blob)], IoPriority::OnDemand)
+            .unwrap();
+        assert_eq!(disk_usage(), 4096);
+        let data = std::fs::read(&blob_file).unwrap();
+        assert!(is_zero(&data[..4 * 4096]));
+        assert_eq!(data[4 * 4096 + 1], 1);
+    }
+
+    #[test]
+    fn test_purge_shared_blobs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let fixture = BlobCacheFixture {
+            validate: false,
+            compressor: compress::Algorithm::None,
+            ..BlobCacheFixture::new(&work_dir)
+        };
+        let blob = Arc::new(RafsBlobEntry {
+            chunk_count: 8,
+            blob_id: "blobcache".to_string(),
+            ..Default::default()
+        });
+        let blob_file = work_dir.join("blobcache");
+        let disk_usage = |path: &Path| std::fs::metadata(path).unwrap().blocks() * 512;
+
+        // Purged for all instances of the process sharing the cache file.
+        let cache = fixture.build().unwrap();
+        let other = fixture.build().unwrap();
+        for c in [&cache, &other].iter() {
+            c.fetch(&[chunk_bio(0, &blob)], IoPriority::OnDemand)
+                .unwrap();
+        }
+        assert_eq!(other.purge_blobs(Some("blobcache")).unwrap(), 1);
+        assert_eq!(disk_usage(&blob_file), 0);
+        assert_eq!(cache.metrics.purged_blobs.count(), 1);
+        assert_eq!(other.metrics.purged_blobs.count(), 1);
+        cache
+            .fetch(&[chunk_bio(0, &blob)], IoPriority::OnDemand)
+            .unwrap();
+        assert_eq!(disk_usage(&blob_file), 4096);
+
+        // Refused while other processes have the cache file open.
+        let file = std::fs::File::open(&blob_file).unwrap();
+        flock(file.as_raw_fd(), FlockArg::LockShared).unwrap();
+        let e = cache.purge_blobs(None).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EBUSY));
+        assert_eq!(disk_usage(&blob_file), 4096);
+        drop(file);
+        assert_eq!(cache.purge_blobs(None).unwrap(), 1);
+
+        // Blob files in the work dir not used by any instance.
+        std::fs::write(work_dir.join("leftover"), vec![1u8; 4096]).unwrap();
+        std::fs::write(work_dir.join("leftover.chunk_map"), vec![0u8; 64]).unwrap();
+        let blobs = cache.cached_blobs().unwrap();
+        assert_eq!(blobs.len(), 2);
+        let leftover = blobs.iter().find(|b| b.blob_id == "leftover").unwrap();
+        assert!(!leftover.open);
+        assert_eq!(leftover.disk_usage, 4096);
+        assert_eq!(cache.purge_blobs(Some("../cache/leftover")).unwrap(), 0);
+        assert_eq!(cache.purge_blobs(Some("leftover")).unwrap(), 1);
+        assert_eq!(disk_usage(&work_dir.join("leftover")), 0);
+        assert!(!work_dir.join("leftover.chunk_map").exists());
+    }
+
+    #[test]
+    fn test_refetch_corrupted_chunk() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let blob_cache = BlobCacheFixture::new(&work_dir).build().unwrap();
+
+        let mut expect = vec![1u8; 100];
+        let blob_id = "blobcache";
+        blob_cache
+            .backend
+            .read(blob_id, expect.as_mut(), 0)
+            .unwrap();
+
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let bio = RafsBio::new(
+            Arc::new(chunk),
+            Arc::new(RafsBlobEntry {
+                blob_id: blob_id.to_string(),
+                ..Default::default()
+            }),
+            0,
+            100,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        );
+        let mut buf = vec![0u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert_eq!(buf, expect);
+
+        // Corrupt the cached chunk, it should be refetched transparently.
+        std::fs::write(work_dir.join(blob_id), vec![0xffu8; 100]).unwrap();
+        buf.iter_mut().for_each(|b| *b = 0);
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert_eq!(buf, expect);
+        assert_eq!(blob_cache.metrics.corrupted_chunks.count(), 1);
+        assert_eq!(std::fs::read(work_dir.join(blob_id)).unwrap(), expect);
+    }
+
+    #[test]
+    fn test_import_blob() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let blob_cache = BlobCacheFixture::new(&work_dir).build().unwrap();
+
+        let blob_id = "blobcache";
+        let expect = vec![2u8; 100];
+        let src = tmp_dir.as_path().join(blob_id);
+        std::fs::write(&src, &expect).unwrap();
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&expect, digest::Algorithm::Blake3);
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let bios = vec![RafsBio::new(
+            Arc::new(chunk),
+            Arc::new(RafsBlobEntry {
+                blob_id: blob_id.to_string(),
+                ..Default::default()
+            }),
+            0,
+            100,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        )];
+
+        assert_eq!(blob_cache.import_blob(&bios, &src).unwrap(), 1);
+        assert_eq!(std::fs::read(work_dir.join(blob_id)).unwrap(), expect);
+        // Ready chunks are skipped.
+        assert_eq!(blob_cache.import_blob(&bios, &src).unwrap(), 0);
+        // Imported chunks are served without going to backend, which returns other data.
+        assert_eq!(blob_cache.fetch(&bios, IoPriority::OnDemand).unwrap(), 0);
+
+        blob_cache.purge_blobs(Some(blob_id)).unwrap();
+        std::fs::write(&src, vec![3u8; 100]).unwrap();
+        assert!(blob_cache.import_blob(&bios, &src).is_err());
+        std::fs::write(&src, vec![2u8; 50]).unwrap();
+        assert!(blob_cache.import_blob(&bios, &src).is_err());
+        assert_eq!(blob_cache.metrics.entries_count.count(), 1);
+
+        // Chunks of other blobs can't be mixed in.
+        let mut other = bios[0].clone();
+        other.blob = Arc::new(RafsBlobEntry {
+            blob_id: "other".to_string(),
+            blob_index: 1,
+            ..Default::default()
+        });
+        let e = blob_cache
+            .import_blob(&[bios[0].clone(), other], &src)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(blob_cache.import_blob(&[], &src).unwrap(), 0);
+    }
+
+    struct DataBackend {
+        data: Vec<u8>,
+        metrics: Arc<BackendMetrics>,
+    }
+
+    impl BlobBackend for DataBackend {
+        fn try_read(&self, _blob_id: &str, buf: &mut [u8], offset: u64) -> BackendResult<usize> {
+            let offset = offset as usize;
+            let end = std::cmp::min(offset + buf.len(), self.data.len());
+            buf[..end - offset].copy_from_slice(&self.data[offset..end]);
+            Ok(end - offset)
+        }
+
+        fn write(&self, _blob_id: &str, _buf: &[u8], _offset: u64) -> BackendResult<usize> {
+            Ok(0)
+        }
+
+        fn blob_size(&self, _blob_id: &str) -> BackendResult<u64> {
+            Ok(self.data.len() as u64)
+        }
+
+        fn release(&self) {}
+
+        fn prefetch_blob(
+            &self,
+            _blob_id: &str,
+            _blob_readahead_offset: u32,
+            _blob_readahead_size: u32,
+        ) -> BackendResult<()> {
+            Ok(())
+        }
+
+        fn metrics(&self) -> &BackendMetrics {
+            &self.metrics
+        }
+    }
+
+    #[test]
+    fn test_zero_chunk() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().join("cache");
+        let blob_cache = BlobCacheFixture {
+            backend: Arc::new(DataBackend {
+                data: vec![0u8; 100],
+                metrics: BackendMetrics::new("id", "mock"),
+            }),
+            ..BlobCacheFixture::new(&work_dir)
+        }
+        .build()
+        .unwrap();
+
+        let blob_id = "blobcache";
+        let blob = Arc::new(RafsBlobEntry {
+            blob_id: blob_id.to_string(),
+            ..Default::default()
+        });
+        let mut chunk = MockChunkInfo::new();
+        chunk.block_id = RafsDigest::from_buf(&[0u8; 100], digest::Algorithm::Blake3);
+        chunk.compress_size = 100;
+        chunk.decompress_size = 100;
+        let chunk = Arc::new(chunk);
+        let bio = RafsBio::new(
+            chunk.clone(),
+            blob.clone(),
+            0,
+            100,
+            RAFS_DEFAULT_BLOCK_SIZE as u32,
+        );
+        let mut buf = vec![1u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+
+        // All-zero chunks are served, but take no cache space.
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        assert!(blob_cache.is_zero_chunk(&blob, chunk.as_ref()));
+        assert_eq!(std::fs::metadata(work_dir.join(blob_id)).unwrap().len(), 0);
+        buf.iter_mut().for_each(|b| *b = 1);
+        blob_cache.read(&bio, &[vs], 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Some zero chunks are forgotten once there are too many of them.
+        let mut other = MockChunkInfo::new();
+        for i in 1..=blobcache::MAX_ZERO_CHUNKS as u64 {
+            other.compress_offset = i * 100;
+            blob_cache.set_zero_chunk(&blob, &other);
+        }
+        assert_eq!(
+            blob_cache.zero_chunks.read().unwrap().len(),
+            blobcache::MAX_ZERO_CHUNKS
+        );
+        assert!(blob_cache.is_zero_chunk(&blob, &other));
+    }
+
+    struct StaticKey(Arc<BlobCipher>);
+
+    impl KeyProvider for StaticKey {
+        fn cipher(&self, version: u32) -> std::io::Result<Arc<BlobCipher>> {
+            if version == 1 {
+                Ok(self.0.clone())
+            } else {
+                Err(enoent!("no such key version"))
+            }
+        }
+    }
+
+    #[test]
+    fn test_encrypted_blob() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().to_path_buf().join("cache");
+        let cipher = Arc::new(BlobCipher::new(&[7u8; 32]).unwrap());
+
+        // A compressible chunk followed by one that isn't.
+        let mut seed = 1u32;
+        let random = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect::<Vec<_>>();
+        let plain = vec![vec![5u8; 4096], random];
+        let mut data = Vec::new();
+        let mut chunks = Vec::new();
+        for (i, p) in plain.iter().enumerate() {
+            let (c, is_compressed) = compress::compress(p, compress::Algorithm::LZ4Block).unwrap();
+            let sealed = cipher.encrypt(&c).unwrap();
+            let mut chunk = MockChunkInfo::new();
+            chunk.block_id = RafsDigest::from_buf(p, digest::Algorithm::Blake3);
+            chunk.flags = RafsChunkFlags::ENCRYPTED;
+            if is_compressed {
+                chunk.flags |= RafsChunkFlags::COMPRESSED;
+            }
+            chunk.compress_offset = data.len() as u64;
+            chunk.compress_size = sealed.len() as u32;
+            chunk.decompress_offset = i as u64 * 4096;
+            chunk.decompress_size = 4096;
+            data.extend_from_slice(&sealed);
+            chunks.push(Arc::new(chunk));
+        }
+
+        let new_cache = |compressed: bool, key: bool, plaintext: bool| {
+            BlobCacheFixture {
+                extra: format!(r#""hot_chunks": 16, "plaintext": {}"#, plaintext),
+                validate: false,
+                compressed,
+                key_provider: if key {
+                    Some(Arc::new(StaticKey(cipher.clone())))
+                } else {
+                    None
+                },
+                backend: Arc::new(DataBackend {
+                    data: data.clone(),
+                    metrics: BackendMetrics::new("id", "mock"),
+                }),
+                ..BlobCacheFixture::new(&work_dir)
+            }
+            .build()
+        };
+        let bios = |blob_id: &str| {
+            let blob = Arc::new(RafsBlobEntry {
+                blob_id: blob_id.to_string(),
+                key_version: 1,
+                ..Default::default()
+            });
+            chunks
+                .iter()
+                .map(|c| {
+                    RafsBio::new(
+                        c.clone(),
+                        blob.clone(),
+                        0,
+                        4096,
+                        RAFS_DEFAULT_BLOCK_SIZE as u32,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Decrypted data would go to disk otherwise.
+        assert!(new_cache(false, true, false).is_err());
+        let blob_cache = new_cache(true, true, false).unwrap();
+        assert!(blob_cache.hot_cache.is_none());
+
+        // One chunk is read on demand, the other is fetched ahead.
+        let encrypted = bios("encrypted");
+        let mut buf = vec![0u8; 4096];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(blob_cache.read(&encrypted[0], &[vs], 0).unwrap(), 4096);
+        assert_eq!(buf, plain[0]);
+        assert_eq!(
+            blob_cache
+                .fetch(&encrypted[1..], IoPriority::OnDemand)
+                .unwrap(),
+            chunks[1].compress_size as usize
+        );
+        // Both are cached as they are in backend.
+        assert_eq!(std::fs::read(work_dir.join("encrypted")).unwrap(), data);
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(blob_cache.read(&encrypted[1], &[vs], 0).unwrap(), 4096);
+        assert_eq!(buf, plain[1]);
+
+        // Encrypted blobs can't be read without keys.
+        let no_key = new_cache(true, false, false).unwrap();
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert!(no_key.read(&bios("other")[0], &[vs], 0).is_err());
+
+        // Decrypted chunks are allowed in cache by `plaintext`.
+        assert!(new_cache(true, true, true).unwrap().hot_cache.is_some());
+        let plain_cache = new_cache(false, true, true).unwrap();
+        let decrypted = bios("decrypted");
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(plain_cache.read(&decrypted[0], &[vs], 0).unwrap(), 4096);
+        assert_eq!(buf, plain[0]);
+        plain_cache
+            .fetch(&decrypted[1..], IoPriority::OnDemand)
+            .unwrap();
+        assert_eq!(
+            std::fs::read(work_dir.join("decrypted")).unwrap(),
+            plain.concat()
+        );
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(plain_cache.read(&decrypted[1], &[vs], 0).unwrap(), 4096);
+        assert_eq!(buf, plain[1]);
+    }
+
+    #[test]
+    fn test_prefetch_waits_for_free_space() {
+        let tmp_dir = TempDir::new().unwrap();
+        let new_cache = |name: &str| {
+            BlobCacheFixture {
+                // Free space is never 100%, so the cache filesystem is always low on space.
+                extra: r#""free_space_low_watermark": 100"#.to_string(),
+                validate: false,
+                prefetch_worker: PrefetchWorker {
+                    enable: true,
+                    threads_count: 1,
+                    merging_size: 0,
+                    bandwidth_rate: 0,
+                },
+                compressor: compress::Algorithm::None,
+                ..BlobCacheFixture::new(&tmp_dir.as_path().join(name))
+            }
+            .build()
+            .unwrap()
+        };
+        // Wait in another thread, and return once it's parked.
+        let wait = |cache: &Arc<blobcache::BlobCache>| {
+            let (tx, rx) = mpsc::channel();
+            let waiter = cache.clone();
+            thread::spawn(move || tx.send(waiter.wait_for_free_space()).unwrap());
+            while *cache.space_waiters.lock().unwrap() == 0 {
+                thread::yield_now();
+            }
+            rx
+        };
+
+        // Parked until prefetch is stopped, free space is always below 100%.
+        let blob_cache = new_cache("stop");
+        let rx = wait(&blob_cache);
+        assert!(rx.try_recv().is_err());
+        blob_cache.stop_prefetch().unwrap();
+        assert_eq!(rx.recv(), Ok(false));
+
+        // Resumed once free space gets back, real checks are held off not to find it low again.
+        let blob_cache = new_cache("resume");
+        let watermark = blob_cache.watermark.as_ref().unwrap();
+        assert!(watermark.assume(100, 0).is_some());
+        let rx = wait(&blob_cache);
+        assert!(rx.try_recv().is_err());
+        assert!(watermark.assume(100, 100).is_none());
+        assert_eq!(rx.recv(), Ok(true));
+    }
+
+    #[test]
+    fn test_new_prefetch_limiter() {
+        assert!(new_prefetch_limiter(0).is_none());
+        assert!(new_prefetch_limiter(1).is_some());
+    }
 }
@@ -10,6 +10,7 @@ use nydus_utils::digest::RafsDigest;
 
 use super::ChunkMap;
 use crate::device::RafsChunkInfo;
+use crate::utils::hash_table_bytes;
 
 /// The DigestedChunkMap is an implementation that uses a hash map
 /// (HashMap<chunk_digest, has_ready>) to records whether a chunk has been
@@ -41,4 +42,18 @@ impl ChunkMap for DigestedChunkMap {
         self.cache.write().unwrap().insert(*chunk.block_id(), true);
         Ok(())
     }
+
+    fn clear_ready(&self, chunk: &dyn RafsChunkInfo) -> Result<()> {
+        self.cache.write().unwrap().remove(chunk.block_id());
+        Ok(())
+    }
+
+    fn clear_all(&self) -> Result<()> {
+        self.cache.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn memory_usage(&self) -> usize {
+        hash_table_bytes::<(RafsDigest, bool)>(self.cache.read().unwrap().capacity())
+    }
 }
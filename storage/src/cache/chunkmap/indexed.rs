@@ -2,33 +2,140 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::fs::OpenOptions;
-use std::io::Result;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Result, Write};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::UNIX_EPOCH;
 
 use nydus_utils::div_round_up;
 
 use super::ChunkMap;
 use crate::device::RafsChunkInfo;
-use crate::utils::readahead;
 
-/// The magic number of blob chunk_map file, it's ASCII hex of string "BMAP".
-const MAGIC: u32 = 0x424D_4150;
+/// The magic number of blob chunk_map file, it's ASCII hex of string "BMP2".
+///
+/// It differs from `PAGED_MAGIC`, so that nydusd knowing only the paged layout refuses the
+/// file instead of reading the bitmap at a wrong offset.
+const MAGIC: u32 = 0x424D_5032;
+/// The magic number of blob chunk_map file with a page sized header, it's ASCII hex of
+/// string "BMAP".
+const PAGED_MAGIC: u32 = 0x424D_4150;
 /// The name suffix of blob chunk_map file, named $blob_id.chunk_map.
-const FILE_SUFFIX: &str = "chunk_map";
+pub const FILE_SUFFIX: &str = "chunk_map";
 /// The header of blob chunk_map file.
-const HEADER_SIZE: usize = 4096;
-const HEADER_RESERVED_SIZE: usize = HEADER_SIZE - 4;
+const HEADER_SIZE: usize = 32;
+const HEADER_RESERVED_SIZE: usize = HEADER_SIZE - 20;
+/// Chunk map files created before generation stamps were introduced have zero here.
+const HEADER_VERSION_LEGACY: u32 = 0;
+/// Chunk map files of `PAGED_MAGIC` with generation stamps, upgraded to the current layout
+/// when opened.
+const HEADER_VERSION_PAGED: u32 = 1;
+const HEADER_VERSION: u32 = 2;
+/// Size of the header of chunk map files of `PAGED_MAGIC`.
+const PAGED_HEADER_SIZE: usize = 4096;
+
+/// The magic number of exported chunk map, it's ASCII hex of string "BMEX".
+const EXPORT_MAGIC: u32 = 0x424D_4558;
+const EXPORT_VERSION: u32 = 1;
+const EXPORT_HEADER_SIZE: usize = 16;
 
-/// The blob chunk map file header, 4096 bytes.
+/// The blob chunk map file header, 32 bytes, followed by the bitmap.
+///
+/// Fields are in native byte order as the header is mapped. Files of `PAGED_MAGIC` have the
+/// first 16 bytes the same but take a whole page.
 #[repr(C)]
 struct Header {
     /// IndexedChunkMap magic number
     magic: u32,
+    version: u32,
+    /// Generation stamp of the blob cache file which the bitmap describes.
+    generation: u64,
+    /// Number of chunks of the blob, so that the file can be checked on its own.
+    chunk_count: u32,
     reserved: [u8; HEADER_RESERVED_SIZE],
 }
 
+impl Header {
+    /// Parse the header of a chunk map file read into `data`, return (magic, version,
+    /// generation, chunk_count). Chunk count is zero for files of `PAGED_MAGIC`.
+    fn parse(data: &[u8]) -> Option<(u32, u32, u64, u32)> {
+        if data.len() < 16 {
+            return None;
+        }
+        let mut word = [0u8; 4];
+        let mut dword = [0u8; 8];
+        word.copy_from_slice(&data[0..4]);
+        let magic = u32::from_ne_bytes(word);
+        word.copy_from_slice(&data[4..8]);
+        let version = u32::from_ne_bytes(word);
+        dword.copy_from_slice(&data[8..16]);
+        let generation = u64::from_ne_bytes(dword);
+        let chunk_count = if magic == MAGIC && data.len() >= 20 {
+            word.copy_from_slice(&data[16..20]);
+            u32::from_ne_bytes(word)
+        } else {
+            0
+        };
+        Some((magic, version, generation, chunk_count))
+    }
+
+    /// Size of the header of chunk map files of `magic`.
+    fn size_of(magic: u32) -> usize {
+        if magic == PAGED_MAGIC {
+            PAGED_HEADER_SIZE
+        } else {
+            HEADER_SIZE
+        }
+    }
+}
+
+/// Generation stamp of a blob cache file, derived from its inode number and birth time.
+///
+/// It changes whenever the cache file gets replaced, for example deleted and created again
+/// or copied from another node, so a stale bitmap won't be trusted for a different file.
+/// Zero means that the stamp is unknown, e.g. the cache file doesn't exist.
+fn blob_generation(blob_path: &str) -> u64 {
+    match fs::metadata(blob_path) {
+        Ok(md) => {
+            let birth = md
+                .created()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default();
+            md.ino() ^ birth.rotate_left(32)
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Why a chunk map file can't be trusted, found by `IndexedChunkMap::check_file()`.
+#[derive(Debug, PartialEq)]
+pub enum ChunkMapFault {
+    /// The file size doesn't match the chunk count of the blob.
+    Size { size: u64, expected: u64 },
+    /// The header is not of a chunk map file.
+    Magic(u32),
+    /// The bitmap was recorded for another blob cache file.
+    Generation,
+}
+
+impl fmt::Display for ChunkMapFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkMapFault::Size { size, expected } => {
+                write!(f, "chunk map size {} doesn't match {}", size, expected)
+            }
+            ChunkMapFault::Magic(magic) => write!(f, "invalid chunk map magic {:#x}", magic),
+            ChunkMapFault::Generation => write!(f, "chunk map doesn't match blob cache file"),
+        }
+    }
+}
+
 /// The IndexedChunkMap is an implementation that uses a file as bitmap
 /// (like HashMap<chunk_index, has_ready>). It creates or opens a file with
 /// the name $blob_id.chunk_map which records whether a chunk has been cached
@@ -55,6 +162,15 @@ impl IndexedChunkMap {
         }
 
         let cache_path = format!("{}.{}", blob_path, FILE_SUFFIX);
+        let bitmap_size = div_round_up(chunk_count as u64, 8u64);
+        let expected_size = HEADER_SIZE as u64 + bitmap_size;
+        let generation = blob_generation(blob_path);
+
+        if let Ok(md) = fs::metadata(&cache_path) {
+            if md.len() == PAGED_HEADER_SIZE as u64 + bitmap_size {
+                Self::upgrade(&cache_path, bitmap_size, generation)?;
+            }
+        }
 
         let file = OpenOptions::new()
             .read(true)
@@ -69,9 +185,6 @@ impl IndexedChunkMap {
             })?;
 
         let file_size = file.metadata()?.len();
-        let bitmap_size = div_round_up(chunk_count as u64, 8u64);
-        let expected_size = HEADER_SIZE as u64 + bitmap_size;
-
         if file_size != expected_size {
             if file_size > 0 {
                 warn!("blob chunk_map file may be corrupted: {:?}", cache_path);
@@ -97,18 +210,29 @@ impl IndexedChunkMap {
             return Err(ebadf!("failed to mmap blob chunk_map"));
         }
 
-        let mut header = unsafe { &mut *(base as *mut Header) };
+        let header = unsafe { &mut *(base as *mut Header) };
         if file_size == 0 {
-            header.magic = MAGIC
+            header.magic = MAGIC;
         } else if header.magic != MAGIC {
             return Err(einval!(format!(
                 "invalid blob chunk_map file header: {:?}",
                 cache_path
             )));
+        } else if generation != 0 && header.generation != generation {
+            // The bitmap was recorded for another blob cache file, nothing in it is trustworthy.
+            warn!(
+                "blob chunk_map file {:?} doesn't match blob cache file, reset it",
+                cache_path
+            );
+            unsafe {
+                std::ptr::write_bytes(base.add(HEADER_SIZE) as *mut u8, 0, bitmap_size as usize)
+            };
         }
+        header.version = HEADER_VERSION;
+        header.generation = generation;
+        header.chunk_count = chunk_count;
 
-        readahead(fd, 0, expected_size);
-
+        // Pages of the bitmap are faulted in as chunks get checked, nothing to read here.
         Ok(Self {
             chunk_count,
             size: expected_size as usize,
@@ -116,6 +240,239 @@ impl IndexedChunkMap {
         })
     }
 
+    /// Rewrite a chunk map file with a page sized header into the current layout, return false
+    /// if it's not one. Generation stamps are kept, files of the legacy version are trusted as
+    /// before and get the stamp of the blob cache file.
+    ///
+    /// The file is replaced by a synced copy in the new layout, so a crash leaves either the old
+    /// or the new one, never a header of one layout over the bitmap of the other.
+    fn upgrade(cache_path: &str, bitmap_size: u64, generation: u64) -> Result<bool> {
+        let mut data = vec![0u8; PAGED_HEADER_SIZE + bitmap_size as usize];
+        File::open(cache_path)?.read_exact(&mut data)?;
+        let (magic, version, old_generation, _) = match Header::parse(&data) {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+        if magic != PAGED_MAGIC {
+            return Ok(false);
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC.to_ne_bytes());
+        header[4..8].copy_from_slice(&HEADER_VERSION.to_ne_bytes());
+        let generation = if version == HEADER_VERSION_PAGED {
+            old_generation
+        } else {
+            generation
+        };
+        header[8..16].copy_from_slice(&generation.to_ne_bytes());
+        // The chunk count is filled in by `new()`.
+        data.splice(..PAGED_HEADER_SIZE, header.iter().cloned());
+
+        // Unique to the process, as nydusd instances sharing the cache may upgrade it together.
+        let tmp = format!("{}.upgrade.{}", cache_path, std::process::id());
+        let r = File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(&data)?;
+                f.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, cache_path));
+        if let Err(e) = r {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        // Make the rename durable too.
+        if let Some(dir) = Path::new(cache_path)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Check the chunk map file of the blob cache file at `blob_path` the way `new()` takes it,
+    /// without changing it. Files recording the chunk count are checked against it if
+    /// `chunk_count` is not known.
+    pub fn check_file(blob_path: &str, chunk_count: Option<u32>) -> Result<Option<ChunkMapFault>> {
+        let cache_path = format!("{}.{}", blob_path, FILE_SUFFIX);
+        let mut file = File::open(&cache_path)?;
+        let size = file.metadata()?.len();
+
+        let mut header = [0u8; HEADER_SIZE];
+        let header = if size >= HEADER_SIZE as u64 {
+            file.read_exact(&mut header)?;
+            Header::parse(&header)
+        } else {
+            None
+        };
+        let (magic, version, stamp, recorded_count) = match header {
+            Some(h) => h,
+            None => {
+                return Ok(Some(ChunkMapFault::Size {
+                    size,
+                    expected: HEADER_SIZE as u64,
+                }))
+            }
+        };
+        if magic != MAGIC && magic != PAGED_MAGIC {
+            return Ok(Some(ChunkMapFault::Magic(magic)));
+        }
+
+        let header_size = Header::size_of(magic) as u64;
+        let count = chunk_count.or_else(|| Some(recorded_count).filter(|c| *c > 0));
+        let expected = match count {
+            Some(c) => header_size + div_round_up(c as u64, 8u64),
+            None => header_size,
+        };
+        if (count.is_some() && size != expected) || size < expected {
+            return Ok(Some(ChunkMapFault::Size { size, expected }));
+        }
+
+        let generation = blob_generation(blob_path);
+        let legacy = magic == PAGED_MAGIC && version == HEADER_VERSION_LEGACY;
+        if !legacy && generation != 0 && stamp != generation {
+            return Ok(Some(ChunkMapFault::Generation));
+        }
+
+        Ok(None)
+    }
+
+    /// Indexes of chunks marked ready in the chunk map file of the blob cache file at
+    /// `blob_path`, read without mapping it.
+    pub fn ready_chunks(blob_path: &str) -> Result<Vec<u32>> {
+        let data = fs::read(format!("{}.{}", blob_path, FILE_SUFFIX))?;
+        let header_size =
+            Header::parse(&data).map_or(HEADER_SIZE, |(m, _, _, _)| Header::size_of(m));
+        let bitmap = data.get(header_size..).unwrap_or_default();
+        let mut ready = Vec::new();
+        for (idx, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8u32 {
+                // The same bit order as `read_u8()`.
+                if byte & (1 << (7 - bit)) != 0 {
+                    ready.push(((idx as u32) << 3) | bit);
+                }
+            }
+        }
+        Ok(ready)
+    }
+
+    fn bitmap(&self) -> &[AtomicU8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self.base.add(HEADER_SIZE) as *const AtomicU8,
+                self.size - HEADER_SIZE,
+            )
+        }
+    }
+
+    /// Export ready state of all chunks, so that another node can import it together
+    /// with a copy of the blob cache file.
+    pub fn export(&self, w: &mut dyn Write) -> Result<()> {
+        let mut header = [0u8; EXPORT_HEADER_SIZE];
+        header[0..4].copy_from_slice(&EXPORT_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&EXPORT_VERSION.to_le_bytes());
+        header[8..12].copy_from_slice(&self.chunk_count.to_le_bytes());
+        w.write_all(&header)?;
+
+        let bitmap: Vec<u8> = self
+            .bitmap()
+            .iter()
+            .map(|b| b.load(Ordering::Acquire))
+            .collect();
+        w.write_all(&bitmap)?;
+        w.flush()
+    }
+
+    /// Import ready state of chunks exported by `export()`.
+    ///
+    /// Chunks already marked as ready are kept, so it's safe to import while the chunk map
+    /// is in use. The caller must make sure that the blob cache file holds data of all the
+    /// imported chunks.
+    pub fn import(&self, r: &mut dyn Read) -> Result<()> {
+        let mut header = [0u8; EXPORT_HEADER_SIZE];
+        r.read_exact(&mut header)?;
+
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&header[0..4]);
+        if u32::from_le_bytes(word) != EXPORT_MAGIC {
+            return Err(einval!("invalid exported chunk_map magic"));
+        }
+        word.copy_from_slice(&header[4..8]);
+        if u32::from_le_bytes(word) != EXPORT_VERSION {
+            return Err(einval!("unsupported exported chunk_map version"));
+        }
+        word.copy_from_slice(&header[8..12]);
+        let chunk_count = u32::from_le_bytes(word);
+        if chunk_count != self.chunk_count {
+            return Err(einval!(format!(
+                "exported chunk_map has {} chunks, expect {}",
+                chunk_count, self.chunk_count
+            )));
+        }
+
+        let bitmap = self.bitmap();
+        let mut data = vec![0u8; bitmap.len()];
+        r.read_exact(&mut data)?;
+        for (cur, new) in bitmap.iter().zip(data.iter()) {
+            if *new != 0 {
+                cur.fetch_or(*new, Ordering::AcqRel);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Export ready state of chunks from the chunk map file of the blob cache file at
+    /// `blob_path`, which must record the chunk count and match the blob cache file.
+    pub fn export_file(blob_path: &str, w: &mut dyn Write) -> Result<()> {
+        if let Some(fault) = Self::check_file(blob_path, None)? {
+            return Err(einval!(format!(
+                "chunk_map of {:?} can't be trusted: {}",
+                blob_path, fault
+            )));
+        }
+        let mut header = [0u8; HEADER_SIZE];
+        File::open(format!("{}.{}", blob_path, FILE_SUFFIX))?.read_exact(&mut header)?;
+        let chunk_count = match Header::parse(&header) {
+            Some((_, _, _, count)) if count > 0 => count,
+            _ => {
+                return Err(einval!(format!(
+                    "chunk_map of {:?} doesn't record chunk count, open it by nydusd first",
+                    blob_path
+                )))
+            }
+        };
+
+        Self::new(blob_path, chunk_count)?.export(w)
+    }
+
+    /// Import ready state of chunks exported by `export()` into the chunk map file of the blob
+    /// cache file at `blob_path`, creating the chunk map file if it doesn't exist.
+    ///
+    /// A chunk map file recorded for another blob cache file is reset first, so a copied blob
+    /// cache file is trusted only for the imported chunks.
+    pub fn import_file(blob_path: &str, r: &mut dyn Read) -> Result<()> {
+        if !Path::new(blob_path).is_file() {
+            return Err(enoent!(format!(
+                "blob cache file {:?} not found",
+                blob_path
+            )));
+        }
+        let mut header = [0u8; EXPORT_HEADER_SIZE];
+        r.read_exact(&mut header)?;
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&header[8..12]);
+        let chunk_count = u32::from_le_bytes(word);
+        if chunk_count == 0 {
+            return Err(einval!("exported chunk_map has no chunks"));
+        }
+
+        // `import()` checks the header again.
+        Self::new(blob_path, chunk_count)?.import(&mut (&header[..]).chain(r))
+    }
+
     fn check_index(&self, idx: u32) -> Result<()> {
         if idx > self.chunk_count - 1 {
             return Err(einval!(format!(
@@ -177,4 +534,23 @@ impl ChunkMap for IndexedChunkMap {
         }
         Ok(())
     }
+
+    fn clear_ready(&self, chunk: &dyn RafsChunkInfo) -> Result<()> {
+        let index = chunk.index();
+        let (_, mask) = self.read_u8(index)?;
+        self.bitmap()[index as usize >> 3].fetch_and(!mask, Ordering::AcqRel);
+        Ok(())
+    }
+
+    fn clear_all(&self) -> Result<()> {
+        for b in self.bitmap() {
+            b.store(0, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// The whole chunk map file is mapped, and shared with page cache.
+    fn memory_usage(&self) -> usize {
+        self.size
+    }
 }
@@ -14,6 +14,12 @@ pub mod indexed;
 pub trait ChunkMap {
     fn has_ready(&self, chunk: &dyn RafsChunkInfo) -> Result<bool>;
     fn set_ready(&self, chunk: &dyn RafsChunkInfo) -> Result<()>;
+    /// Mark a chunk as not cached, e.g. after its data is evicted from blob cache.
+    fn clear_ready(&self, chunk: &dyn RafsChunkInfo) -> Result<()>;
+    /// Mark all chunks as not cached, e.g. after the blob cache file is purged.
+    fn clear_all(&self) -> Result<()>;
+    /// Approximate bytes of memory taken by the chunk map.
+    fn memory_usage(&self) -> usize;
 }
 
 #[cfg(test)]
@@ -147,6 +153,251 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_chunk_map_generation() {
+        let dir = TempDir::new().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.as_os_str().to_str().unwrap().to_string();
+        let chunk = Chunk::new(7);
+
+        std::fs::File::create(&blob_path).unwrap();
+        let chunk_map = IndexedChunkMap::new(&blob_path, 100).unwrap();
+        chunk_map.set_ready(chunk.as_ref()).unwrap();
+        drop(chunk_map);
+
+        // Still the same blob cache file, so the ready state can be trusted.
+        let chunk_map = IndexedChunkMap::new(&blob_path, 100).unwrap();
+        assert!(chunk_map.has_ready(chunk.as_ref()).unwrap());
+        drop(chunk_map);
+
+        // Keep the old file alive so that the new one gets another inode number.
+        let old_path = format!("{}.old", blob_path);
+        std::fs::rename(&blob_path, &old_path).unwrap();
+        std::fs::File::create(&blob_path).unwrap();
+        let chunk_map = IndexedChunkMap::new(&blob_path, 100).unwrap();
+        assert!(!chunk_map.has_ready(chunk.as_ref()).unwrap());
+    }
+
+    #[test]
+    fn test_chunk_map_check_file() {
+        let dir = TempDir::new().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.as_os_str().to_str().unwrap().to_string();
+        let map_path = format!("{}.{}", blob_path, indexed::FILE_SUFFIX);
+
+        std::fs::File::create(&blob_path).unwrap();
+        let chunk_map = IndexedChunkMap::new(&blob_path, 100).unwrap();
+        for idx in &[0, 9, 99] {
+            chunk_map.set_ready(Chunk::new(*idx).as_ref()).unwrap();
+        }
+        drop(chunk_map);
+        assert_eq!(
+            IndexedChunkMap::check_file(&blob_path, Some(100)).unwrap(),
+            None
+        );
+        assert_eq!(IndexedChunkMap::check_file(&blob_path, None).unwrap(), None);
+        assert_eq!(
+            IndexedChunkMap::ready_chunks(&blob_path).unwrap(),
+            vec![0, 9, 99]
+        );
+        assert_eq!(
+            IndexedChunkMap::check_file(&blob_path, Some(200)).unwrap(),
+            Some(indexed::ChunkMapFault::Size {
+                size: 32 + 13,
+                expected: 32 + 25
+            })
+        );
+        // The chunk count is recorded in the file.
+        assert_eq!(IndexedChunkMap::check_file(&blob_path, None).unwrap(), None);
+        let mut data = std::fs::read(&map_path).unwrap();
+        data.push(0);
+        std::fs::write(&map_path, &data).unwrap();
+        assert_eq!(
+            IndexedChunkMap::check_file(&blob_path, None).unwrap(),
+            Some(indexed::ChunkMapFault::Size {
+                size: 32 + 14,
+                expected: 32 + 13
+            })
+        );
+        data.pop();
+        std::fs::write(&map_path, &data).unwrap();
+
+        // Replaced blob cache file.
+        let old_path = format!("{}.old", blob_path);
+        std::fs::rename(&blob_path, &old_path).unwrap();
+        std::fs::File::create(&blob_path).unwrap();
+        assert_eq!(
+            IndexedChunkMap::check_file(&blob_path, Some(100)).unwrap(),
+            Some(indexed::ChunkMapFault::Generation)
+        );
+
+        let mut data = std::fs::read(&map_path).unwrap();
+        data[0] = 0;
+        std::fs::write(&map_path, &data).unwrap();
+        assert!(matches!(
+            IndexedChunkMap::check_file(&blob_path, Some(100)).unwrap(),
+            Some(indexed::ChunkMapFault::Magic(_))
+        ));
+        // Nothing is changed by checking.
+        assert_eq!(std::fs::read(&map_path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_chunk_map_upgrade() {
+        let dir = TempDir::new().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.as_os_str().to_str().unwrap().to_string();
+        let map_path = format!("{}.{}", blob_path, indexed::FILE_SUFFIX);
+        std::fs::File::create(&blob_path).unwrap();
+
+        // A chunk map file of the legacy layout, with a page sized header.
+        let mut data = vec![0u8; 4096 + 13];
+        data[0..4].copy_from_slice(&0x424D_4150u32.to_ne_bytes());
+        data[4096] = 0b1000_0001;
+        data[4096 + 12] = 0b1000_0000;
+        std::fs::write(&map_path, &data).unwrap();
+        assert_eq!(
+            IndexedChunkMap::check_file(&blob_path, Some(100)).unwrap(),
+            None
+        );
+        assert_eq!(
+            IndexedChunkMap::ready_chunks(&blob_path).unwrap(),
+            vec![0, 7, 96]
+        );
+
+        let chunk_map = IndexedChunkMap::new(&blob_path, 100).unwrap();
+        for idx in 0..100 {
+            let ready = chunk_map.has_ready(Chunk::new(idx).as_ref()).unwrap();
+            assert_eq!(ready, idx == 0 || idx == 7 || idx == 96);
+        }
+        drop(chunk_map);
+        assert_eq!(std::fs::metadata(&map_path).unwrap().len(), 32 + 13);
+        // The current layout comes with its own magic, "BMP2".
+        assert_eq!(
+            std::fs::read(&map_path).unwrap()[0..4],
+            0x424D_5032u32.to_ne_bytes()
+        );
+        // Replaced by the upgraded copy, nothing left behind.
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 2);
+        assert_eq!(IndexedChunkMap::check_file(&blob_path, None).unwrap(), None);
+        assert_eq!(
+            IndexedChunkMap::ready_chunks(&blob_path).unwrap(),
+            vec![0, 7, 96]
+        );
+
+        // Opened again as is.
+        let chunk_map = IndexedChunkMap::new(&blob_path, 100).unwrap();
+        assert!(chunk_map.has_ready(Chunk::new(96).as_ref()).unwrap());
+        assert!(!chunk_map.has_ready(Chunk::new(95).as_ref()).unwrap());
+    }
+
+    #[test]
+    fn test_chunk_map_export_import() {
+        let dir = TempDir::new().unwrap();
+        let blob_path1 = dir.as_path().join("blob-1");
+        let blob_path1 = blob_path1.as_os_str().to_str().unwrap().to_string();
+        let blob_path2 = dir.as_path().join("blob-2");
+        let blob_path2 = blob_path2.as_os_str().to_str().unwrap().to_string();
+        let chunk_count = 1000;
+
+        let chunk_map1 = IndexedChunkMap::new(&blob_path1, chunk_count).unwrap();
+        for idx in (0..chunk_count).step_by(3) {
+            chunk_map1.set_ready(Chunk::new(idx).as_ref()).unwrap();
+        }
+        let mut exported = Vec::new();
+        chunk_map1.export(&mut exported).unwrap();
+
+        let chunk_map2 = IndexedChunkMap::new(&blob_path2, chunk_count).unwrap();
+        chunk_map2.set_ready(Chunk::new(1).as_ref()).unwrap();
+        chunk_map2.import(&mut exported.as_slice()).unwrap();
+        for idx in 0..chunk_count {
+            let ready = chunk_map2.has_ready(Chunk::new(idx).as_ref()).unwrap();
+            assert_eq!(ready, idx % 3 == 0 || idx == 1);
+        }
+
+        let blob_path3 = dir.as_path().join("blob-3");
+        let blob_path3 = blob_path3.as_os_str().to_str().unwrap().to_string();
+        let chunk_map3 = IndexedChunkMap::new(&blob_path3, chunk_count + 1).unwrap();
+        assert!(chunk_map3.import(&mut exported.as_slice()).is_err());
+        assert!(chunk_map2.import(&mut &exported[..8]).is_err());
+    }
+
+    #[test]
+    fn test_chunk_map_export_import_file() {
+        let dir = TempDir::new().unwrap();
+        let blob_path1 = dir.as_path().join("blob-1");
+        let blob_path1 = blob_path1.as_os_str().to_str().unwrap().to_string();
+        let blob_path2 = dir.as_path().join("blob-2");
+        let blob_path2 = blob_path2.as_os_str().to_str().unwrap().to_string();
+        let chunk_count = 100;
+
+        std::fs::File::create(&blob_path1).unwrap();
+        let chunk_map1 = IndexedChunkMap::new(&blob_path1, chunk_count).unwrap();
+        for idx in &[0, 9, 99] {
+            chunk_map1.set_ready(Chunk::new(*idx).as_ref()).unwrap();
+        }
+        drop(chunk_map1);
+        let mut exported = Vec::new();
+        IndexedChunkMap::export_file(&blob_path1, &mut exported).unwrap();
+
+        // The blob cache file must be copied first.
+        assert!(IndexedChunkMap::import_file(&blob_path2, &mut exported.as_slice()).is_err());
+        std::fs::copy(&blob_path1, &blob_path2).unwrap();
+        IndexedChunkMap::import_file(&blob_path2, &mut exported.as_slice()).unwrap();
+        assert_eq!(
+            IndexedChunkMap::check_file(&blob_path2, Some(chunk_count)).unwrap(),
+            None
+        );
+        assert_eq!(
+            IndexedChunkMap::ready_chunks(&blob_path2).unwrap(),
+            vec![0, 9, 99]
+        );
+
+        // Chunk map copied together with the blob cache file is reset before importing.
+        std::fs::remove_file(&blob_path2).unwrap();
+        std::fs::copy(&blob_path1, &blob_path2).unwrap();
+        let chunk_map2 = IndexedChunkMap::new(&blob_path2, chunk_count).unwrap();
+        chunk_map2.set_ready(Chunk::new(1).as_ref()).unwrap();
+        drop(chunk_map2);
+        std::fs::remove_file(&blob_path2).unwrap();
+        std::fs::copy(&blob_path1, &blob_path2).unwrap();
+        IndexedChunkMap::import_file(&blob_path2, &mut exported.as_slice()).unwrap();
+        assert_eq!(
+            IndexedChunkMap::ready_chunks(&blob_path2).unwrap(),
+            vec![0, 9, 99]
+        );
+
+        // Nothing to export from a chunk map not matching its blob cache file.
+        std::fs::remove_file(&blob_path1).unwrap();
+        std::fs::File::create(&blob_path1).unwrap();
+        assert!(IndexedChunkMap::export_file(&blob_path1, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_chunk_map_clear_all() {
+        let dir = TempDir::new().unwrap();
+        let blob_path = dir.as_path().join("blob-1");
+        let blob_path = blob_path.as_os_str().to_str().unwrap().to_string();
+        let chunk_count = 100;
+
+        let indexed_chunk_map = IndexedChunkMap::new(&blob_path, chunk_count).unwrap();
+        let digested_chunk_map = DigestedChunkMap::new();
+        for chunk_map in [
+            &indexed_chunk_map as &dyn ChunkMap,
+            &digested_chunk_map as &dyn ChunkMap,
+        ]
+        .iter()
+        {
+            for idx in 0..chunk_count {
+                chunk_map.set_ready(Chunk::new(idx).as_ref()).unwrap();
+            }
+            chunk_map.clear_all().unwrap();
+            for idx in 0..chunk_count {
+                assert!(!chunk_map.has_ready(Chunk::new(idx).as_ref()).unwrap());
+            }
+        }
+    }
+
     fn iterate(chunks: &[Arc<Chunk>], chunk_map: &dyn ChunkMap, chunk_count: u32) {
         for idx in 0..chunk_count {
             chunk_map.set_ready(chunks[idx as usize].as_ref()).unwrap();
@@ -0,0 +1,210 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A pool of workers decompressing chunks in parallel.
+//!
+//! A large read spanning many chunks of a compressed cache would otherwise decompress them one
+//! after another in the thread serving the read. The reader hands raw chunks over to the pool
+//! as a batch and gets them back decompressed in the same order, so the data is copied out as
+//! if it was decompressed in place.
+
+use std::fs::File;
+use std::io::Result;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use nydus_utils::digest;
+
+use crate::compress;
+use crate::crypt::BlobCipher;
+use crate::device::RafsChunkInfo;
+use crate::utils::{alloc_buf, digest_check};
+
+/// Compressed data of a chunk to decompress.
+pub struct RawChunk {
+    pub chunk: Arc<dyn RafsChunkInfo>,
+    pub data: Vec<u8>,
+    /// Stream of gzip compressed data, which doesn't have a known size to read ahead.
+    pub stream: Option<File>,
+    /// Key to decrypt the data with if the chunk is encrypted.
+    pub cipher: Option<Arc<BlobCipher>>,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct DecompressPool {
+    compressor: compress::Algorithm,
+    digester: digest::Algorithm,
+    sender: Mutex<Option<spmc::Sender<Job>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl DecompressPool {
+    pub fn new(
+        threads: usize,
+        compressor: compress::Algorithm,
+        digester: digest::Algorithm,
+    ) -> Result<Self> {
+        let (sender, receiver) = spmc::channel::<Job>();
+        let mut workers = Vec::with_capacity(threads);
+        for num in 0..threads {
+            let rx = receiver.clone();
+            let worker = thread::Builder::new()
+                .name(format!("decompress_thread_{}", num))
+                .spawn(move || {
+                    while let Ok(job) = rx.recv() {
+                        job();
+                    }
+                })?;
+            workers.push(worker);
+        }
+
+        Ok(DecompressPool {
+            compressor,
+            digester,
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(workers),
+        })
+    }
+
+    /// Decompress `chunks` in parallel, validating them by digest with `validate`. Results are
+    /// in the order of `chunks`.
+    pub fn decompress(&self, chunks: Vec<RawChunk>, validate: bool) -> Vec<Result<Vec<u8>>> {
+        let count = chunks.len();
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut sender = self.sender.lock().unwrap();
+            let sender = match sender.as_mut() {
+                Some(s) => s,
+                None => return (0..count).map(|_| Err(eio!("pool is stopped"))).collect(),
+            };
+            for (idx, raw) in chunks.into_iter().enumerate() {
+                let tx = tx.clone();
+                let compressor = self.compressor;
+                let digester = if validate { Some(self.digester) } else { None };
+                let job = Box::new(move || {
+                    let result = decompress_chunk(raw, compressor, digester);
+                    // The reader may have given up waiting, nothing to do then.
+                    let _ = tx.send((idx, result));
+                });
+                if let Err(e) = sender.send(job) {
+                    // The job is handed back, run it right here.
+                    (e.0)();
+                }
+            }
+        }
+        drop(tx);
+
+        let mut results: Vec<Option<Result<Vec<u8>>>> = (0..count).map(|_| None).collect();
+        for (idx, result) in rx.iter() {
+            results[idx] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|| Err(eio!("decompress worker is gone"))))
+            .collect()
+    }
+
+    /// Stop workers after queued jobs are done.
+    pub fn stop(&self) {
+        self.sender.lock().unwrap().take();
+        let mut workers = self.workers.lock().unwrap();
+        while let Some(w) = workers.pop() {
+            w.join()
+                .unwrap_or_else(|e| error!("Thread might panic, {:?}", e));
+        }
+    }
+}
+
+impl Drop for DecompressPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn decompress_chunk(
+    raw: RawChunk,
+    compressor: compress::Algorithm,
+    digester: Option<digest::Algorithm>,
+) -> Result<Vec<u8>> {
+    let decrypted;
+    let data = if raw.chunk.is_encrypted() {
+        let cipher = raw.cipher.ok_or_else(|| eio!("no key to decrypt chunk"))?;
+        decrypted = cipher.decrypt(&raw.data)?;
+        &decrypted
+    } else {
+        &raw.data
+    };
+
+    let mut buf = alloc_buf(raw.chunk.decompress_size() as usize);
+    if raw.chunk.is_compressed() {
+        compress::decompress(data, raw.stream, &mut buf, compressor)?;
+    } else if data.len() == buf.len() {
+        buf.copy_from_slice(data);
+    } else {
+        return Err(eio!("chunk size mismatch"));
+    }
+    if let Some(digester) = digester {
+        if !digest_check(&buf, raw.chunk.block_id(), digester) {
+            return Err(eio!("chunk digest mismatch"));
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::mock::MockChunkInfo;
+    use crate::device::RafsChunkFlags;
+    use nydus_utils::digest::RafsDigest;
+
+    fn raw_chunk(data: &[u8], digester: digest::Algorithm) -> RawChunk {
+        let (compressed, is_compressed) =
+            compress::compress(data, compress::Algorithm::LZ4Block).unwrap();
+        let chunk = MockChunkInfo {
+            block_id: RafsDigest::from_buf(data, digester),
+            flags: if is_compressed {
+                RafsChunkFlags::COMPRESSED
+            } else {
+                RafsChunkFlags::empty()
+            },
+            decompress_size: data.len() as u32,
+            ..Default::default()
+        };
+        RawChunk {
+            chunk: Arc::new(chunk),
+            data: compressed.into_owned(),
+            stream: None,
+            cipher: None,
+        }
+    }
+
+    #[test]
+    fn test_decompress_in_order() {
+        let digester = digest::Algorithm::Blake3;
+        let pool = DecompressPool::new(4, compress::Algorithm::LZ4Block, digester).unwrap();
+        let data = (0..32u8)
+            .map(|i| vec![i; 0x10000 + i as usize])
+            .collect::<Vec<_>>();
+        let chunks = data.iter().map(|d| raw_chunk(d, digester)).collect();
+
+        let results = pool.decompress(chunks, true);
+        assert_eq!(results.len(), data.len());
+        for (result, expected) in results.into_iter().zip(data.iter()) {
+            assert_eq!(&result.unwrap(), expected);
+        }
+
+        // Corrupted data fails validation, others are not affected.
+        let mut chunks = vec![raw_chunk(&data[0], digester), raw_chunk(&data[1], digester)];
+        chunks[1].data = chunks[0].data.clone();
+        let results = pool.decompress(chunks, true);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+
+        pool.stop();
+        let results = pool.decompress(vec![raw_chunk(&data[0], digester)], false);
+        assert!(results[0].is_err());
+    }
+}
@@ -9,6 +9,7 @@ use vm_memory::VolatileSlice;
 
 use crate::backend::BlobBackend;
 use crate::cache::*;
+use crate::crypt::KeyProvider;
 use crate::device::{BlobPrefetchControl, RafsBio, RafsChunkInfo};
 use crate::factory::CacheConfig;
 use crate::utils::{alloc_buf, copyv};
@@ -21,6 +22,7 @@ pub struct DummyCache {
     validate: bool,
     compressor: compress::Algorithm,
     digester: digest::Algorithm,
+    key_provider: Option<Arc<dyn KeyProvider>>,
 }
 
 impl RafsCache for DummyCache {
@@ -51,7 +53,7 @@ impl RafsCache for DummyCache {
             d.as_mut_slice()
         };
 
-        self.read_backend_chunk(&bio.blob, chunk.as_ref(), one_chunk_buf, |_| Ok(()))?;
+        self.read_backend_chunk(&bio.blob, chunk.as_ref(), one_chunk_buf, |_, _| Ok(()))?;
 
         if reuse {
             Ok(one_chunk_buf.len())
@@ -78,6 +80,10 @@ impl RafsCache for DummyCache {
         self.validate
     }
 
+    fn key_provider(&self) -> Option<&dyn KeyProvider> {
+        self.key_provider.as_deref()
+    }
+
     /// Prefetch works when blobcache is enabled
     fn prefetch(&self, _bios: &mut [RafsBio]) -> StorageResult<usize> {
         Err(StorageError::Unsupported)
@@ -117,5 +123,6 @@ pub fn new(
         validate: config.cache_validate,
         compressor,
         digester,
+        key_provider: config.key_provider,
     })
 }
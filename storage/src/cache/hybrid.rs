@@ -0,0 +1,367 @@
+// Copyright 2021 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hot chunk tier for the compressed blob cache.
+//!
+//! A compressed blobcache saves disk space, but every read against it has to decompress the
+//! whole chunk. The hot tier keeps a bounded number of frequently accessed chunks in
+//! decompressed form in a side file per blob, laid out by decompress offset, so reads against
+//! them can be served by a plain `preadv` just like an uncompressed blobcache. Side files are
+//! private to the cache instance: they are removed from the work dir right after creation, so
+//! that mounts sharing the work dir never see each other's, and their space is freed on close.
+//!
+//! A chunk is promoted once its access count reaches the promotion threshold. When the tier is
+//! full, the least frequently used hot chunk gets demoted and its space is punched out of the
+//! side file, but only if it is not hotter than the candidate.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Result;
+use std::os::unix::io::AsRawFd;
+use std::sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Mutex, RwLock,
+};
+
+use nix::sys::uio;
+use vm_memory::VolatileSlice;
+
+use crate::device::{RafsBlobEntry, RafsChunkInfo};
+use crate::utils::{hash_table_bytes, punch_hole, readv};
+
+/// The name suffix of side files, named $blob_id.$pid.$seq.hot while being created.
+pub const HOT_FILE_SUFFIX: &str = "hot";
+
+/// Sequence number of side files created by this process, to keep their names unique.
+static HOT_FILE_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Chunks are identified by (blob index, decompress offset) inside one cache instance.
+type ChunkKey = (u32, u64);
+
+/// Access counters of cold chunks are simply dropped once there are too many of them, which
+/// works as a poor man's aging and keeps memory usage bounded.
+const COLD_COUNTERS_FACTOR: usize = 8;
+
+struct HotChunk {
+    size: u32,
+    hits: AtomicU32,
+}
+
+#[derive(Default)]
+struct HotTierState {
+    files: HashMap<u32, File>,
+    chunks: HashMap<ChunkKey, HotChunk>,
+}
+
+pub struct HotChunkCache {
+    work_dir: String,
+    capacity: usize,
+    threshold: u32,
+    state: RwLock<HotTierState>,
+    cold_counters: Mutex<HashMap<ChunkKey, u32>>,
+}
+
+impl HotChunkCache {
+    pub fn new(work_dir: &str, capacity: usize, threshold: u32) -> Self {
+        HotChunkCache {
+            work_dir: work_dir.to_string(),
+            capacity,
+            threshold: std::cmp::max(threshold, 1),
+            state: RwLock::new(HotTierState::default()),
+            cold_counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[inline]
+    fn key(blob: &RafsBlobEntry, chunk: &dyn RafsChunkInfo) -> ChunkKey {
+        (blob.blob_index, chunk.decompress_offset())
+    }
+
+    pub fn contains(&self, blob: &RafsBlobEntry, chunk: &dyn RafsChunkInfo) -> bool {
+        let state = self.state.read().unwrap();
+        state.chunks.contains_key(&Self::key(blob, chunk))
+    }
+
+    /// Try to serve a read from the hot tier, return None if the chunk is not hot.
+    pub fn read(
+        &self,
+        blob: &RafsBlobEntry,
+        chunk: &dyn RafsChunkInfo,
+        bufs: &[VolatileSlice],
+        offset: u64,
+        size: usize,
+    ) -> Result<Option<usize>> {
+        // Demotion needs the write lock, so hot data can't be punched out while being read.
+        let state = self.state.read().unwrap();
+        let hot = match state.chunks.get(&Self::key(blob, chunk)) {
+            Some(hot) => hot,
+            None => return Ok(None),
+        };
+        let file = match state.files.get(&blob.blob_index) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let expected =
+            std::cmp::min(size as u64, (hot.size as u64).saturating_sub(offset)) as usize;
+        let read_size = readv(
+            file.as_raw_fd(),
+            bufs,
+            chunk.decompress_offset() + offset,
+            size,
+        )?;
+        // Treat it as a miss, the caller then reads the chunk from the compressed cache.
+        if read_size < expected {
+            warn!(
+                "short read of hot chunk {:?}, {} of {} bytes",
+                Self::key(blob, chunk),
+                read_size,
+                expected
+            );
+            return Ok(None);
+        }
+        hot.hits.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Some(read_size))
+    }
+
+    /// Account an access to a chunk which was served from the compressed cache or backend,
+    /// `data` is the whole decompressed and validated chunk.
+    pub fn record(&self, blob: &RafsBlobEntry, chunk: &dyn RafsChunkInfo, data: &[u8]) {
+        if data.len() != chunk.decompress_size() as usize {
+            return;
+        }
+
+        let key = Self::key(blob, chunk);
+        let hits = {
+            let mut counters = self.cold_counters.lock().unwrap();
+            if counters.len() >= self.capacity.saturating_mul(COLD_COUNTERS_FACTOR) {
+                counters.clear();
+            }
+            let hits = counters.entry(key).or_insert(0);
+            *hits += 1;
+            if *hits < self.threshold {
+                return;
+            }
+            counters.remove(&key).unwrap_or_default()
+        };
+
+        match self.promote(blob, key, hits, data) {
+            Ok(true) => {}
+            // Keep counting, so that it takes over once it gets hotter than the coldest one.
+            Ok(false) => {
+                self.cold_counters.lock().unwrap().insert(key, hits);
+            }
+            Err(e) => warn!("failed to promote chunk {:?} to hot tier: {}", key, e),
+        }
+    }
+
+    /// Promote a chunk to the hot tier, return false if all hot chunks are hotter than it.
+    fn promote(&self, blob: &RafsBlobEntry, key: ChunkKey, hits: u32, data: &[u8]) -> Result<bool> {
+        let mut state = self.state.write().unwrap();
+
+        if state.chunks.contains_key(&key) {
+            return Ok(true);
+        }
+
+        if state.chunks.len() >= self.capacity {
+            let victim = state
+                .chunks
+                .iter()
+                .min_by_key(|(_, c)| c.hits.load(Ordering::Relaxed))
+                .map(|(k, c)| (*k, c.hits.load(Ordering::Relaxed), c.size));
+            match victim {
+                Some((victim, victim_hits, victim_size)) if victim_hits <= hits => {
+                    state.chunks.remove(&victim);
+                    if let Some(file) = state.files.get(&victim.0) {
+                        punch_hole(file.as_raw_fd(), victim.1, victim_size as u64).unwrap_or_else(
+                            |e| warn!("failed to punch hole in hot tier file: {}", e),
+                        );
+                    }
+                }
+                _ => return Ok(false),
+            }
+        }
+
+        if !state.files.contains_key(&blob.blob_index) {
+            let file = self.create_file(blob)?;
+            state.files.insert(blob.blob_index, file);
+        }
+
+        // Safe to unwrap because the file was just inserted above.
+        let fd = state.files.get(&blob.blob_index).unwrap().as_raw_fd();
+        let mut written = 0;
+        while written < data.len() {
+            match uio::pwrite(fd, &data[written..], (key.1 + written as u64) as i64) {
+                Ok(n) => written += n,
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(_) => return Err(last_error!()),
+            }
+        }
+
+        state.chunks.insert(
+            key,
+            HotChunk {
+                size: data.len() as u32,
+                hits: AtomicU32::new(hits),
+            },
+        );
+
+        Ok(true)
+    }
+
+    /// Create the side file of a blob, which is only reachable by its descriptor.
+    fn create_file(&self, blob: &RafsBlobEntry) -> Result<File> {
+        let path = format!(
+            "{}/{}.{}.{}.{}",
+            self.work_dir,
+            blob.blob_id,
+            std::process::id(),
+            HOT_FILE_SEQ.fetch_add(1, Ordering::Relaxed),
+            HOT_FILE_SUFFIX
+        );
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .read(true)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+
+        Ok(file)
+    }
+
+    /// Drop all hot chunks of a blob, its side file is closed and so its space is released.
+    pub fn purge_blob(&self, blob_index: u32) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+
+        state.chunks.retain(|k, _| k.0 != blob_index);
+        state.files.remove(&blob_index);
+
+        Ok(())
+    }
+
+    /// Approximate bytes of memory taken to track hot chunks and access counters of cold
+    /// ones, hot chunk data is on disk.
+    pub fn memory_usage(&self) -> usize {
+        let hot = self.state.read().unwrap().chunks.capacity();
+        let cold = self.cold_counters.lock().unwrap().capacity();
+        hash_table_bytes::<(ChunkKey, HotChunk)>(hot) + hash_table_bytes::<(ChunkKey, u32)>(cold)
+    }
+
+    #[cfg(test)]
+    fn is_hot(&self, blob: &RafsBlobEntry, chunk: &dyn RafsChunkInfo) -> bool {
+        self.state
+            .read()
+            .unwrap()
+            .chunks
+            .contains_key(&Self::key(blob, chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm_sys_util::tempdir::TempDir;
+
+    use super::*;
+    use crate::cache::mock::MockChunkInfo;
+
+    #[test]
+    fn test_hot_chunk_promotion() {
+        let tmp_dir = TempDir::new().unwrap();
+        let hot = HotChunkCache::new(tmp_dir.as_path().to_str().unwrap(), 1, 2);
+        let blob = RafsBlobEntry {
+            blob_id: "blob".to_string(),
+            ..Default::default()
+        };
+        let c1 = MockChunkInfo {
+            decompress_offset: 0,
+            decompress_size: 100,
+            ..Default::default()
+        };
+        let c2 = MockChunkInfo {
+            decompress_offset: 100,
+            decompress_size: 100,
+            ..Default::default()
+        };
+        let data = vec![1u8; 100];
+
+        hot.record(&blob, &c1, &data);
+        assert!(!hot.is_hot(&blob, &c1));
+        hot.record(&blob, &c1, &data);
+        assert!(hot.is_hot(&blob, &c1));
+
+        // c1 got more hits since promotion, so c2 can't replace it.
+        let mut buf = vec![0u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(hot.read(&blob, &c1, &[vs], 0, 100).unwrap(), Some(100));
+        assert_eq!(buf, data);
+        hot.record(&blob, &c2, &data);
+        hot.record(&blob, &c2, &data);
+        assert!(hot.is_hot(&blob, &c1));
+        assert!(!hot.is_hot(&blob, &c2));
+
+        // Now c2 is hotter than c1 and takes over the only slot.
+        for _ in 0..4 {
+            hot.record(&blob, &c2, &data);
+        }
+        assert!(!hot.is_hot(&blob, &c1));
+        assert!(hot.is_hot(&blob, &c2));
+    }
+
+    #[test]
+    fn test_hot_files_private_to_instance() {
+        let tmp_dir = TempDir::new().unwrap();
+        let work_dir = tmp_dir.as_path().to_str().unwrap();
+        let hot1 = HotChunkCache::new(work_dir, 4, 1);
+        let hot2 = HotChunkCache::new(work_dir, 4, 1);
+        let blob = RafsBlobEntry {
+            blob_id: "blob".to_string(),
+            ..Default::default()
+        };
+        let chunk = MockChunkInfo {
+            decompress_offset: 0,
+            decompress_size: 100,
+            ..Default::default()
+        };
+
+        hot1.record(&blob, &chunk, &[1u8; 100]);
+        hot2.record(&blob, &chunk, &[2u8; 100]);
+        assert!(hot1.is_hot(&blob, &chunk));
+        assert!(hot2.is_hot(&blob, &chunk));
+        assert_eq!(fs::read_dir(work_dir).unwrap().count(), 0);
+
+        // Promotion and purge by one instance leave hot data of the other alone.
+        hot1.purge_blob(blob.blob_index).unwrap();
+        assert!(!hot1.is_hot(&blob, &chunk));
+        let mut buf = vec![0u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(hot2.read(&blob, &chunk, &[vs], 0, 100).unwrap(), Some(100));
+        assert_eq!(buf, vec![2u8; 100]);
+    }
+
+    #[test]
+    fn test_hot_chunk_short_read() {
+        let tmp_dir = TempDir::new().unwrap();
+        let hot = HotChunkCache::new(tmp_dir.as_path().to_str().unwrap(), 4, 1);
+        let blob = RafsBlobEntry {
+            blob_id: "blob".to_string(),
+            ..Default::default()
+        };
+        let chunk = MockChunkInfo {
+            decompress_offset: 0,
+            decompress_size: 100,
+            ..Default::default()
+        };
+
+        hot.record(&blob, &chunk, &[1u8; 100]);
+        hot.state.read().unwrap().files[&blob.blob_index]
+            .set_len(50)
+            .unwrap();
+        let mut buf = vec![0u8; 100];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(hot.read(&blob, &chunk, &[vs], 0, 100).unwrap(), None);
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(hot.read(&blob, &chunk, &[vs], 10, 40).unwrap(), Some(40));
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Chunks being fetched from backend by readers.
+//!
+//! Concurrent cold reads of a chunk would otherwise all go to the backend for the same data.
+//! The first reader takes the chunk and fetches it, the others park until it's done and then
+//! find it in cache. Chunks are spread over shards each having a lock and a condition variable
+//! of its own, so readers of unrelated chunks hardly ever contend or get woken up in vain.
+
+use std::collections::HashSet;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+const INFLIGHT_SHARDS: usize = 64;
+/// How long a reader waits for another one fetching the same chunk before fetching it itself.
+const INFLIGHT_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// (blob_index, compress_offset) of a chunk.
+type ChunkKey = (u32, u64);
+
+#[derive(Default)]
+struct Shard {
+    chunks: Mutex<HashSet<ChunkKey>>,
+    done: Condvar,
+}
+
+pub struct InflightChunks {
+    shards: Vec<Shard>,
+    timeout: Duration,
+}
+
+/// A chunk taken by a reader to fetch, released on drop.
+pub struct InflightGuard<'a> {
+    shard: &'a Shard,
+    key: ChunkKey,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.shard.chunks.lock().unwrap().remove(&self.key);
+        self.shard.done.notify_all();
+    }
+}
+
+impl Default for InflightChunks {
+    fn default() -> Self {
+        Self::with_timeout(INFLIGHT_WAIT_TIMEOUT)
+    }
+}
+
+impl InflightChunks {
+    fn with_timeout(timeout: Duration) -> Self {
+        InflightChunks {
+            shards: (0..INFLIGHT_SHARDS).map(|_| Shard::default()).collect(),
+            timeout,
+        }
+    }
+
+    fn shard(&self, key: &ChunkKey) -> &Shard {
+        // Chunks are at least 4K apart within a blob.
+        let hash = (key.1 >> 12) ^ (key.0 as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[hash as usize % INFLIGHT_SHARDS]
+    }
+
+    /// Take the chunk at `compress_offset` of blob `blob_index` to fetch it.
+    ///
+    /// If another reader is fetching it, wait until it's done and return `None`, the caller
+    /// should check whether the chunk is cached then. Whoever gets the chunk should check that
+    /// too, as it may have been cached right before.
+    pub fn start(&self, blob_index: u32, compress_offset: u64) -> Option<InflightGuard> {
+        let key = (blob_index, compress_offset);
+        let shard = self.shard(&key);
+        let mut chunks = shard.chunks.lock().unwrap();
+        if chunks.insert(key) {
+            return Some(InflightGuard { shard, key });
+        }
+
+        let begin = Instant::now();
+        while chunks.contains(&key) {
+            let elapsed = begin.elapsed();
+            if elapsed >= self.timeout {
+                warn!(
+                    "Waiting for backend IO expires, blob {} offset {} after {:?}",
+                    blob_index, compress_offset, elapsed
+                );
+                break;
+            }
+            chunks = shard
+                .done
+                .wait_timeout(chunks, self.timeout - elapsed)
+                .unwrap()
+                .0;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    #[test]
+    fn test_wait_for_inflight_chunk() {
+        let inflight = InflightChunks::with_timeout(Duration::from_millis(100));
+        let guard = inflight.start(1, 0x1000).unwrap();
+        // Other chunks are not affected.
+        assert!(inflight.start(1, 0x2000).is_some());
+        assert!(inflight.start(2, 0x1000).is_some());
+
+        let begin = Instant::now();
+        assert!(inflight.start(1, 0x1000).is_none());
+        assert!(begin.elapsed() >= Duration::from_millis(100));
+
+        drop(guard);
+        assert!(inflight.start(1, 0x1000).is_some());
+    }
+
+    #[test]
+    fn test_concurrent_cold_reads() {
+        const CHUNKS: u64 = 512;
+        let inflight = Arc::new(InflightChunks::default());
+        let cached = Arc::new(RwLock::new(HashSet::new()));
+        let fetches = Arc::new((0..CHUNKS).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+
+        let readers = (0..16)
+            .map(|n| {
+                let inflight = inflight.clone();
+                let cached = cached.clone();
+                let fetches = fetches.clone();
+                thread::spawn(move || {
+                    for i in 0..CHUNKS {
+                        // Readers go through chunks from different places.
+                        let idx = (i + n * 37) % CHUNKS;
+                        if cached.read().unwrap().contains(&idx) {
+                            continue;
+                        }
+                        let guard = inflight.start(idx as u32 % 3, idx << 20);
+                        if cached.read().unwrap().contains(&idx) {
+                            continue;
+                        }
+                        assert!(guard.is_some());
+                        fetches[idx as usize].fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_micros(200));
+                        cached.write().unwrap().insert(idx);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        assert_eq!(cached.read().unwrap().len(), CHUNKS as usize);
+        for f in fetches.iter() {
+            assert_eq!(f.load(Ordering::SeqCst), 1);
+        }
+    }
+}
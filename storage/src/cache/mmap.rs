@@ -0,0 +1,143 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only mapping of a blob cache file.
+//!
+//! Chunks cached uncompressed are copied straight from the mapping into request buffers,
+//! which saves a syscall and the kernel copy of `pread()` for every chunk read, and lets the
+//! kernel read ahead on page faults for large sequential reads.
+//!
+//! Cache files only grow as chunks get cached and are never truncated, evicted or purged
+//! ranges are punched out and read as zeros, so the mapping is extended on demand.
+
+use std::fs::File;
+use std::io::Result;
+use std::os::unix::io::AsRawFd;
+use std::sync::RwLock;
+
+use vm_memory::VolatileSlice;
+
+use crate::utils::copyv;
+
+struct Mapping {
+    // address of the mapping, 0 if nothing is mapped
+    addr: usize,
+    len: usize,
+}
+
+pub struct CacheMmap {
+    file: File,
+    mapping: RwLock<Mapping>,
+}
+
+impl CacheMmap {
+    /// Map cache file `file`, a descriptor of its own is taken so that the file can't be
+    /// closed under the mapping.
+    pub fn new(file: &File) -> Result<Self> {
+        let mmap = CacheMmap {
+            file: file.try_clone()?,
+            mapping: RwLock::new(Mapping { addr: 0, len: 0 }),
+        };
+        mmap.remap(0)?;
+
+        Ok(mmap)
+    }
+
+    /// Copy at most `size` bytes of the cache file at `offset` into `bufs`.
+    pub fn read(&self, bufs: &[VolatileSlice], offset: u64, size: usize) -> Result<usize> {
+        let end = offset
+            .checked_add(size as u64)
+            .ok_or_else(|| einval!("invalid read range"))? as usize;
+        if self.mapping.read().unwrap().len < end {
+            self.remap(end)?;
+        }
+
+        let mapping = self.mapping.read().unwrap();
+        if mapping.len < end {
+            return Err(eio!("read beyond end of cache file"));
+        }
+        // Safe because the range is within the mapping, which can't be unmapped while the
+        // lock is held.
+        let data = unsafe {
+            std::slice::from_raw_parts((mapping.addr + offset as usize) as *const u8, size)
+        };
+        copyv(data, bufs, 0, size)
+    }
+
+    /// Map the whole file again if it has grown beyond `end` since mapped.
+    fn remap(&self, end: usize) -> Result<()> {
+        let mut mapping = self.mapping.write().unwrap();
+        if mapping.len >= end && end > 0 {
+            return Ok(());
+        }
+        let len = self.file.metadata()?.len() as usize;
+        if len <= mapping.len {
+            return Ok(());
+        }
+
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(last_error!());
+        }
+        // Chunks are mostly read in order of files, let the kernel read ahead aggressively
+        // on page faults. It's only a hint, failing it is harmless.
+        if unsafe { libc::madvise(addr, len, libc::MADV_SEQUENTIAL) } != 0 {
+            debug!("failed to advise on cache file mapping: {}", last_error!());
+        }
+
+        unmap(&mapping);
+        mapping.addr = addr as usize;
+        mapping.len = len;
+
+        Ok(())
+    }
+}
+
+impl Drop for CacheMmap {
+    fn drop(&mut self) {
+        unmap(&self.mapping.read().unwrap());
+    }
+}
+
+fn unmap(mapping: &Mapping) {
+    if mapping.addr != 0 {
+        unsafe { libc::munmap(mapping.addr as *mut libc::c_void, mapping.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_read_growing_file() {
+        let tmp = TempFile::new().unwrap();
+        let mut file = tmp.into_file();
+        let mmap = CacheMmap::new(&file).unwrap();
+        let mut buf = vec![0u8; 8];
+        let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+
+        assert!(mmap.read(&[vs], 0, 4).is_err());
+
+        file.write_all(b"01234567").unwrap();
+        assert_eq!(mmap.read(&[vs], 2, 4).unwrap(), 4);
+        assert_eq!(&buf[..4], b"2345");
+
+        file.write_all(b"89abcdef").unwrap();
+        assert_eq!(mmap.read(&[vs], 8, 8).unwrap(), 8);
+        assert_eq!(&buf, b"89abcdef");
+        assert!(mmap.read(&[vs], 12, 8).is_err());
+    }
+}
@@ -5,21 +5,42 @@
 use std::cmp;
 use std::fs::File;
 use std::io::Result;
+use std::os::unix::io::RawFd;
+use std::path::Path;
 use std::slice;
 use std::sync::Arc;
 
 use vm_memory::VolatileSlice;
 
-use crate::backend::BlobBackend;
+use crate::backend::{BackendResult, BlobBackend};
+use crate::cache::scheduler::IoPriority;
+use crate::crypt::{BlobCipher, KeyProvider};
 use crate::device::{BlobPrefetchControl, RafsBio, RafsBlobEntry, RafsChunkInfo};
 use crate::utils::{alloc_buf, digest_check};
 use crate::{compress, StorageResult};
 
-use nydus_utils::digest;
+use nydus_utils::{digest, probe};
 
 pub mod blobcache;
 pub mod chunkmap;
+pub mod decompress;
 pub mod dummycache;
+pub mod hybrid;
+pub mod inflight;
+pub mod mmap;
+#[cfg(test)]
+mod mock;
+pub mod quota;
+pub mod scheduler;
+pub mod watermark;
+
+/// Outcome of a backend read for `probe::chunk_fetch_end()`, backend errors don't carry errno.
+fn probe_ret(ret: &BackendResult<usize>) -> i64 {
+    match ret {
+        Ok(n) => *n as i64,
+        Err(_) => -(libc::EIO as i64),
+    }
+}
 
 #[derive(Default, Clone)]
 struct MergedBackendRequest {
@@ -58,6 +79,30 @@ impl MergedBackendRequest {
     }
 }
 
+/// A blob file cached in the work dir of a cache instance.
+#[derive(Clone, Debug, Serialize)]
+pub struct CachedBlob {
+    pub blob_id: String,
+    pub work_dir: String,
+    /// Bytes of disk space actually taken by cached data, not the apparent file size.
+    pub disk_usage: u64,
+    /// Whether the blob is open by the cache instance, rather than only found in its work dir.
+    #[serde(skip)]
+    pub open: bool,
+}
+
+/// Approximate bytes of memory taken by a cache instance.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CacheMemoryUsage {
+    /// Chunk maps and other state tracked per chunk.
+    pub chunk_map_bytes: usize,
+    /// Buffers of backend reads in progress.
+    pub inflight_buffer_bytes: usize,
+    /// Merged requests queued for prefetch workers.
+    pub prefetch_queue_bytes: usize,
+    pub prefetch_queue_requests: usize,
+}
+
 #[derive(Clone, Default)]
 pub struct PrefetchWorker {
     pub enable: bool,
@@ -78,15 +123,82 @@ pub trait RafsCache {
     // storage could benefit the performance.
     fn read(&self, bio: &RafsBio, bufs: &[VolatileSlice], offset: u64) -> Result<usize>;
 
+    /// Read whole chunks of `bios` ahead in parallel, so that a large read doesn't decompress
+    /// them one after another. Returns decompressed data of chunks in the order of `bios`, None
+    /// for those to be read through `read()` as usual, or nothing at all if it's not supported.
+    fn read_parallel(&self, _bios: &[RafsBio]) -> Vec<Option<Vec<u8>>> {
+        Vec::new()
+    }
+
+    /// Splice data of `bios` into the pipe `pipe` straight from cache files, if all of them
+    /// are cached as they are. `prepare` gets the total size before any data goes into the
+    /// pipe. Returns None with nothing spliced if data has to be read through `read()`.
+    fn splice(
+        &self,
+        _bios: &[RafsBio],
+        _pipe: RawFd,
+        _prepare: &mut dyn FnMut(usize) -> Result<()>,
+    ) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
     /// Write a chunk data through cache
     fn write(&self, blob_id: &str, blk: &dyn RafsChunkInfo, buf: &[u8]) -> Result<usize>;
 
+    /// Fetch continuous chunks into cache with a single backend request, ahead of reading
+    /// the first one, at `priority` against other backend requests. Prefetch requests are
+    /// limited by prefetch bandwidth as well. Returns the number of bytes fetched from backend.
+    fn fetch(&self, _bios: &[RafsBio], _priority: IoPriority) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Whether reading `bio` goes to backend, so that fetching it along with its neighbors
+    /// by `fetch()` pays off.
+    fn need_fetch(&self, _bio: &RafsBio) -> Result<bool> {
+        Ok(false)
+    }
+
     /// Get the size of a blob
     fn blob_size(&self, blob: &RafsBlobEntry) -> Result<u64>;
 
     fn prefetch(&self, bio: &mut [RafsBio]) -> StorageResult<usize>;
     fn stop_prefetch(&self) -> StorageResult<()>;
 
+    /// Change prefetch bandwidth limit in bytes per second at runtime, 0 means unlimited.
+    fn set_prefetch_bandwidth(&self, _bandwidth_rate: u32) -> Result<()> {
+        Err(enosys!("prefetch bandwidth limit is not supported"))
+    }
+
+    /// List blob files cached in the work dir of this instance, including those it doesn't
+    /// use.
+    fn cached_blobs(&self) -> Result<Vec<CachedBlob>> {
+        Err(enosys!("cached blobs can't be listed"))
+    }
+
+    /// Drop cached data of the blob `blob_id` in the work dir, or of all blobs used by this
+    /// instance if it's None, so that it gets fetched from backend again on demand. Other
+    /// instances of the process sharing the cache file are purged as well. Fails with EBUSY
+    /// if other processes use the cache file. Returns the number of purged blobs.
+    fn purge_blobs(&self, _blob_id: Option<&str>) -> Result<usize> {
+        Err(enosys!("cached blobs can't be purged"))
+    }
+
+    /// Fill cache with chunks of `bios` read from `src`, a copy of the blob they belong to
+    /// which is downloaded by others. All chunks must be of the same blob, and are validated
+    /// by digest before cached, chunks already ready are skipped. Returns the number of
+    /// imported chunks.
+    fn import_blob(&self, _bios: &[RafsBio], _src: &Path) -> Result<usize> {
+        Err(enosys!("blobs can't be imported"))
+    }
+
+    /// Get approximate memory usage of the cache.
+    fn memory_usage(&self) -> CacheMemoryUsage {
+        CacheMemoryUsage {
+            inflight_buffer_bytes: self.backend().metrics().inflight_bytes(),
+            ..Default::default()
+        }
+    }
+
     /// Release cache
     fn release(&self);
 
@@ -96,11 +208,31 @@ pub trait RafsCache {
     fn compressor(&self) -> compress::Algorithm;
     fn need_validate(&self) -> bool;
 
+    /// Keys of encrypted blobs, None if not configured.
+    fn key_provider(&self) -> Option<&dyn KeyProvider> {
+        None
+    }
+
+    /// Get the cipher to decrypt chunks of `blob`, None if the blob is not encrypted.
+    fn blob_cipher(&self, blob: &RafsBlobEntry) -> Result<Option<Arc<BlobCipher>>> {
+        if blob.key_version == 0 {
+            return Ok(None);
+        }
+        match self.key_provider() {
+            Some(provider) => provider.cipher(blob.key_version).map(Some),
+            None => Err(eio!(format!(
+                "blob {} is encrypted but no key is configured",
+                blob.blob_id
+            ))),
+        }
+    }
+
     /// Read a whole chunk directly from *backend*.
     /// The fetched chunk could be compressed or not by different compressors.
     /// It depends on `cki` how to describe the chunk data.
     /// Moreover, chunk data from backend can be validated as per nydus configuration.
     /// Above is not redundant with blob cache's validation given IO path backend -> blobcache
+    /// `cacher` gets the data as fetched from backend along with the decompressed data.
     fn read_backend_chunk<F>(
         &self,
         blob: &RafsBlobEntry,
@@ -109,13 +241,17 @@ pub trait RafsCache {
         cacher: F,
     ) -> Result<usize>
     where
-        F: FnOnce(&[u8]) -> Result<()>,
+        F: FnOnce(&[u8], &[u8]) -> Result<()>,
         Self: Sized,
     {
         let offset = cki.compress_offset();
+        let cipher = self.blob_cipher(blob)?;
+        if cki.is_encrypted() && cipher.is_none() {
+            return Err(eio!("no key to decrypt chunk"));
+        }
         let mut d;
 
-        let raw_chunk = if cki.is_compressed() {
+        let raw_chunk = if cki.is_compressed() || cki.is_encrypted() {
             // Need to put compressed data into a temporary buffer so as to perform decompression.
             //
             // gzip is special that it doesn't carry compress_size, instead, we can read as much
@@ -173,9 +309,10 @@ pub trait RafsCache {
             unsafe { slice::from_raw_parts_mut(chunk.as_mut_ptr(), chunk.len()) }
         };
 
-        self.backend()
-            .read(&blob.blob_id, raw_chunk, offset)
-            .map_err(|e| eio!(e))?;
+        probe::chunk_fetch_start(&blob.blob_id, offset, raw_chunk.len());
+        let ret = self.backend().read(&blob.blob_id, raw_chunk, offset);
+        probe::chunk_fetch_end(&blob.blob_id, offset, raw_chunk.len(), probe_ret(&ret));
+        ret.map_err(|e| eio!(e))?;
         // Try to validate data just fetched from backend inside.
         self.process_raw_chunk(
             cki,
@@ -184,9 +321,10 @@ pub trait RafsCache {
             chunk,
             cki.is_compressed(),
             self.need_validate(),
+            cipher.as_deref(),
         )
         .map_err(|e| eio!(format!("fail to read from backend: {}", e)))?;
-        cacher(chunk)?;
+        cacher(raw_chunk, chunk)?;
         Ok(chunk.len())
     }
 
@@ -194,6 +332,9 @@ pub trait RafsCache {
     /// backend a bit as per the chunk description as blob cache always saves plain data
     /// into cache file rather than compressed.
     /// An inside trick is that it tries to directly save data into caller's buffer.
+    /// Encrypted chunks are decrypted with `cipher` first, which is None if `raw_chunk` is
+    /// decrypted already, like read from a plaintext cache.
+    #[allow(clippy::too_many_arguments)]
     fn process_raw_chunk(
         &self,
         cki: &dyn RafsChunkInfo,
@@ -202,7 +343,20 @@ pub trait RafsCache {
         chunk: &mut [u8],
         need_decompress: bool,
         need_validate: bool,
+        cipher: Option<&BlobCipher>,
     ) -> Result<usize> {
+        let decrypted;
+        let raw_chunk = match cipher {
+            Some(cipher) if cki.is_encrypted() => {
+                if raw_stream.is_some() {
+                    return Err(eio!("encrypted chunk can't be read as a stream"));
+                }
+                decrypted = cipher.decrypt(raw_chunk)?;
+                decrypted.as_slice()
+            }
+            _ => raw_chunk,
+        };
+
         if need_decompress {
             compress::decompress(raw_chunk, raw_stream, chunk, self.compressor()).map_err(|e| {
                 error!("failed to decompress chunk: {}", e);
@@ -229,21 +383,26 @@ pub trait RafsCache {
     /// range [`blob_offset`..`blob_offset` + `blob_size`] exactly covers more than one
     /// chunks and `cki_set` can correctly describe how to extract chunk from batched buffer.
     /// Afterwards, several chunks are returned, caller does not have to decompress them.
+    /// The data read from backend is returned along with them.
     fn read_chunks(
         &self,
-        blob_id: &str,
+        blob: &RafsBlobEntry,
         blob_offset: u64,
         blob_size: usize,
         cki_set: &[Arc<dyn RafsChunkInfo>],
-    ) -> Result<Vec<Vec<u8>>> {
+    ) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        let blob_id = blob.blob_id.as_str();
+        let cipher = self.blob_cipher(blob)?;
         let mut c_buf = alloc_buf(blob_size);
         let mut chunks: Vec<Vec<u8>> = Vec::new();
         // TODO: Currently, request length to backend may span a whole chunk,
         // Do we need to split it into smaller pieces like 128K or 256K?
-        let nr_read = self
+        probe::chunk_fetch_start(blob_id, blob_offset, blob_size);
+        let ret = self
             .backend()
-            .read(blob_id, c_buf.as_mut_slice(), blob_offset)
-            .map_err(|e| eio!(e))?;
+            .read(blob_id, c_buf.as_mut_slice(), blob_offset);
+        probe::chunk_fetch_end(blob_id, blob_offset, blob_size, probe_ret(&ret));
+        let nr_read = ret.map_err(|e| eio!(e))?;
 
         if nr_read != blob_size {
             return Err(eio!(format!(
@@ -253,6 +412,9 @@ pub trait RafsCache {
         }
 
         for cki in cki_set {
+            if cki.is_encrypted() && cipher.is_none() {
+                return Err(eio!("no key to decrypt chunk"));
+            }
             // TODO: Also check if adjacent here?
             let offset_merged = (cki.compress_offset() - blob_offset) as usize;
             let size_merged = cki.compress_size() as usize;
@@ -264,10 +426,11 @@ pub trait RafsCache {
                 &mut chunk,
                 cki.is_compressed(),
                 self.need_validate(),
+                cipher.as_deref(),
             )?;
             chunks.push(chunk);
         }
 
-        Ok(chunks)
+        Ok((c_buf, chunks))
     }
 }
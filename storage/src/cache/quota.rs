@@ -0,0 +1,399 @@
+// Copyright 2021 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache space quota of a single mount.
+//!
+//! Multiple mounts may share the same blobcache work directory, so a huge image could fill up
+//! the whole cache volume. `CacheQuota` accounts the space taken by chunks cached for one
+//! mount and picks its least recently used chunks as eviction victims once the quota is
+//! exceeded, so a mount only ever evicts its own chunks.
+//!
+//! Chunks are accounted once written to cache by the current instance. Data left in the cache
+//! by previous runs is accounted per blob when the blob is opened, as the least recently used
+//! data of all, until its chunks are read and accounted one by one.
+
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::device::{RafsBlobEntry, RafsChunkInfo};
+use crate::utils::hash_table_bytes;
+
+/// Chunks are identified by (blob index, compress offset), which works for old bootstraps
+/// without chunk indexes too.
+type ChunkKey = (u32, u64);
+
+pub enum QuotaVictim {
+    /// A chunk cached by the current instance.
+    Chunk {
+        blob: Arc<RafsBlobEntry>,
+        chunk: Arc<dyn RafsChunkInfo>,
+        size: u64,
+    },
+    /// Data of a blob left by previous runs, which is all but the chunks in `keep`.
+    Leftover {
+        blob: Arc<RafsBlobEntry>,
+        keep: Vec<Arc<dyn RafsChunkInfo>>,
+        size: u64,
+    },
+}
+
+impl QuotaVictim {
+    pub fn blob(&self) -> &Arc<RafsBlobEntry> {
+        match self {
+            QuotaVictim::Chunk { blob, .. } | QuotaVictim::Leftover { blob, .. } => blob,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            QuotaVictim::Chunk { size, .. } | QuotaVictim::Leftover { size, .. } => *size,
+        }
+    }
+}
+
+struct QuotaEntry {
+    tick: u64,
+    blob: Arc<RafsBlobEntry>,
+    chunk: Arc<dyn RafsChunkInfo>,
+    size: u64,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    tick: u64,
+    usage: u64,
+    entries: HashMap<ChunkKey, QuotaEntry>,
+    /// Access tick to chunk key, the first one is the least recently used.
+    lru: BTreeMap<u64, ChunkKey>,
+    /// Bytes of data left by previous runs of each blob, by blob index.
+    leftover: HashMap<u32, (Arc<RafsBlobEntry>, u64)>,
+}
+
+impl QuotaState {
+    /// Take the least recently used data as a victim, but never the chunk `exclude`.
+    fn pick_victim(&mut self, exclude: Option<ChunkKey>) -> Option<QuotaVictim> {
+        if let Some(blob_index) = self.leftover.keys().next().cloned() {
+            let (blob, size) = self.leftover.remove(&blob_index).unwrap();
+            let keep = self
+                .entries
+                .values()
+                .filter(|e| e.blob.blob_index == blob_index)
+                .map(|e| e.chunk.clone())
+                .collect();
+            self.usage -= size;
+            return Some(QuotaVictim::Leftover { blob, keep, size });
+        }
+
+        let (oldest_tick, oldest_key) = match self.lru.iter().next() {
+            Some((t, k)) if Some(*k) != exclude => (*t, *k),
+            _ => return None,
+        };
+        self.lru.remove(&oldest_tick);
+        let entry = self.entries.remove(&oldest_key)?;
+        self.usage -= entry.size;
+        Some(QuotaVictim::Chunk {
+            blob: entry.blob,
+            chunk: entry.chunk,
+            size: entry.size,
+        })
+    }
+}
+
+pub struct CacheQuota {
+    limit: u64,
+    state: Mutex<QuotaState>,
+}
+
+impl CacheQuota {
+    pub fn new(limit: u64) -> Self {
+        CacheQuota {
+            limit,
+            state: Mutex::new(QuotaState::default()),
+        }
+    }
+
+    /// Account `size` bytes of data of a blob left in cache by previous runs, when the blob
+    /// is opened by the current instance.
+    pub fn add_leftover(&self, blob: &Arc<RafsBlobEntry>, size: u64) {
+        if size == 0 {
+            return;
+        }
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if let Entry::Vacant(e) = state.leftover.entry(blob.blob_index) {
+            e.insert((blob.clone(), size));
+            state.usage += size;
+        }
+    }
+
+    /// Account a chunk written to cache, taking `size` bytes of cache space.
+    ///
+    /// Return chunks to be evicted to get back under the quota, the caller is responsible for
+    /// dropping their data and ready state. The written chunk itself is never a victim.
+    pub fn add(
+        &self,
+        blob: &Arc<RafsBlobEntry>,
+        chunk: &Arc<dyn RafsChunkInfo>,
+        size: u64,
+    ) -> Vec<QuotaVictim> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let key = (blob.blob_index, chunk.compress_offset());
+
+        if !Self::touch_locked(state, key) {
+            Self::insert_locked(state, key, blob, chunk, size);
+        }
+
+        let mut victims = Vec::new();
+        while state.usage > self.limit {
+            match state.pick_victim(Some(key)) {
+                Some(v) => victims.push(v),
+                None => break,
+            }
+        }
+
+        victims
+    }
+
+    /// Record a read of a chunk found in cache. Chunks left by previous runs are accounted
+    /// from now on, as `size` bytes of the leftover data of the blob.
+    pub fn touch(&self, blob: &Arc<RafsBlobEntry>, chunk: &Arc<dyn RafsChunkInfo>, size: u64) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let key = (blob.blob_index, chunk.compress_offset());
+
+        if Self::touch_locked(state, key) {
+            return;
+        }
+        let moved = match state.leftover.get_mut(&blob.blob_index) {
+            Some((_, left)) => {
+                let moved = std::cmp::min(*left, size);
+                *left -= moved;
+                moved
+            }
+            None => return,
+        };
+        if state.leftover[&blob.blob_index].1 == 0 {
+            state.leftover.remove(&blob.blob_index);
+        }
+        state.usage -= moved;
+        Self::insert_locked(state, key, blob, chunk, size);
+    }
+
+    fn touch_locked(state: &mut QuotaState, key: ChunkKey) -> bool {
+        state.tick += 1;
+        let tick = state.tick;
+        match state.entries.get_mut(&key) {
+            Some(entry) => {
+                let old_tick = entry.tick;
+                entry.tick = tick;
+                state.lru.remove(&old_tick);
+                state.lru.insert(tick, key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert_locked(
+        state: &mut QuotaState,
+        key: ChunkKey,
+        blob: &Arc<RafsBlobEntry>,
+        chunk: &Arc<dyn RafsChunkInfo>,
+        size: u64,
+    ) {
+        let tick = state.tick;
+        state.usage += size;
+        state.lru.insert(tick, key);
+        state.entries.insert(
+            key,
+            QuotaEntry {
+                tick,
+                blob: blob.clone(),
+                chunk: chunk.clone(),
+                size,
+            },
+        );
+    }
+
+    /// Pick least recently used data taking at least `bytes` bytes of cache space as
+    /// victims, e.g. to release space when the cache filesystem is running out of space.
+    pub fn evict_lru(&self, bytes: u64) -> Vec<QuotaVictim> {
+        let mut state = self.state.lock().unwrap();
+        let mut released = 0;
+        let mut victims = Vec::new();
+
+        while released < bytes {
+            match state.pick_victim(None) {
+                Some(v) => {
+                    released += v.size();
+                    victims.push(v);
+                }
+                None => break,
+            }
+        }
+
+        victims
+    }
+
+    /// Stop accounting all data of a blob, e.g. after its cached data is purged.
+    ///
+    /// Return the number of chunks no longer accounted.
+    pub fn forget_blob(&self, blob_index: u32) -> usize {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let keys: Vec<ChunkKey> = state
+            .entries
+            .keys()
+            .filter(|k| k.0 == blob_index)
+            .cloned()
+            .collect();
+
+        for key in keys.iter() {
+            if let Some(entry) = state.entries.remove(key) {
+                state.lru.remove(&entry.tick);
+                state.usage -= entry.size;
+            }
+        }
+        if let Some((_, size)) = state.leftover.remove(&blob_index) {
+            state.usage -= size;
+        }
+
+        keys.len()
+    }
+
+    /// Bytes of cache space taken by accounted chunks.
+    pub fn usage(&self) -> u64 {
+        self.state.lock().unwrap().usage
+    }
+
+    /// Approximate bytes of memory taken to account chunks, LRU index included.
+    pub fn memory_usage(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        hash_table_bytes::<(ChunkKey, QuotaEntry)>(state.entries.capacity())
+            + hash_table_bytes::<(u64, ChunkKey)>(state.lru.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::mock::MockChunkInfo;
+
+    fn chunk(index: u32) -> Arc<dyn RafsChunkInfo> {
+        Arc::new(MockChunkInfo {
+            index,
+            compress_offset: index as u64 * 0x1000,
+            ..Default::default()
+        })
+    }
+
+    fn chunk_index(victim: &QuotaVictim) -> u32 {
+        match victim {
+            QuotaVictim::Chunk { chunk, .. } => chunk.index(),
+            QuotaVictim::Leftover { .. } => panic!("unexpected leftover victim"),
+        }
+    }
+
+    #[test]
+    fn test_cache_quota_lru() {
+        let quota = CacheQuota::new(300);
+        let blob = Arc::new(RafsBlobEntry::default());
+        let chunks: Vec<_> = (0..4).map(chunk).collect();
+
+        assert!(quota.add(&blob, &chunks[0], 100).is_empty());
+        assert!(quota.add(&blob, &chunks[1], 100).is_empty());
+        assert!(quota.add(&blob, &chunks[2], 100).is_empty());
+        assert_eq!(quota.usage(), 300);
+
+        // Chunk 0 becomes the most recently used one, so chunk 1 goes first.
+        quota.touch(&blob, &chunks[0], 100);
+        let victims = quota.add(&blob, &chunks[3], 100);
+        assert_eq!(victims.len(), 1);
+        assert_eq!(chunk_index(&victims[0]), 1);
+        assert_eq!(quota.usage(), 300);
+
+        // A chunk bigger than the whole quota evicts everything else but itself.
+        let victims = quota.add(&blob, &chunk(4), 1000);
+        assert_eq!(victims.len(), 3);
+        assert_eq!(quota.usage(), 1000);
+    }
+
+    #[test]
+    fn test_cache_quota_evict_lru() {
+        let quota = CacheQuota::new(u64::MAX);
+        let blob = Arc::new(RafsBlobEntry::default());
+
+        for idx in 0..3 {
+            assert!(quota.add(&blob, &chunk(idx), 100).is_empty());
+        }
+        let victims = quota.evict_lru(150);
+        assert_eq!(victims.len(), 2);
+        assert_eq!(chunk_index(&victims[0]), 0);
+        assert_eq!(chunk_index(&victims[1]), 1);
+        assert_eq!(quota.usage(), 100);
+    }
+
+    #[test]
+    fn test_cache_quota_forget_blob() {
+        let quota = CacheQuota::new(u64::MAX);
+        let blob0 = Arc::new(RafsBlobEntry::default());
+        let blob1 = Arc::new(RafsBlobEntry {
+            blob_index: 1,
+            ..Default::default()
+        });
+
+        for idx in 0..3 {
+            assert!(quota.add(&blob0, &chunk(idx), 100).is_empty());
+        }
+        assert!(quota.add(&blob1, &chunk(0), 100).is_empty());
+
+        assert_eq!(quota.forget_blob(0), 3);
+        assert_eq!(quota.usage(), 100);
+        assert_eq!(quota.forget_blob(0), 0);
+
+        // Only chunks of the remaining blob can be victims.
+        let victims = quota.evict_lru(1000);
+        assert_eq!(victims.len(), 1);
+        assert_eq!(victims[0].blob().blob_index, 1);
+    }
+
+    #[test]
+    fn test_cache_quota_leftover() {
+        let quota = CacheQuota::new(300);
+        let blob = Arc::new(RafsBlobEntry::default());
+
+        quota.add_leftover(&blob, 250);
+        quota.add_leftover(&blob, 250);
+        assert_eq!(quota.usage(), 250);
+
+        // A leftover chunk being read is accounted on its own.
+        quota.touch(&blob, &chunk(0), 100);
+        assert_eq!(quota.usage(), 250);
+        // Reads of blobs without leftover data only refresh chunks already accounted.
+        quota.touch(
+            &Arc::new(RafsBlobEntry {
+                blob_index: 1,
+                ..Default::default()
+            }),
+            &chunk(0),
+            100,
+        );
+        assert_eq!(quota.usage(), 250);
+
+        // Leftover data goes first, except chunks accounted since.
+        let victims = quota.add(&blob, &chunk(1), 100);
+        assert_eq!(victims.len(), 1);
+        match &victims[0] {
+            QuotaVictim::Leftover { keep, size, .. } => {
+                assert_eq!(*size, 150);
+                let mut kept: Vec<u32> = keep.iter().map(|c| c.index()).collect();
+                kept.sort_unstable();
+                assert_eq!(kept, vec![0, 1]);
+            }
+            QuotaVictim::Chunk { .. } => panic!("leftover data should be evicted first"),
+        }
+        assert_eq!(quota.usage(), 200);
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Scheduling of backend requests by priority.
+//!
+//! Prefetch and readahead may keep all backend connections busy fetching data nobody is
+//! waiting for, while a read blocking an application queues up behind them. Backend requests
+//! take one of a limited number of slots, on-demand requests get a slot as soon as one is
+//! free, and background ones only when no on-demand request is running or waiting. So that
+//! background requests are not starved by a steady stream of reads, one that has waited long
+//! enough takes the next free slot regardless.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoPriority {
+    /// Reads an application is waiting for.
+    OnDemand,
+    /// Readahead.
+    Background,
+    /// Prefetch, scheduled as background requests and limited by prefetch bandwidth too.
+    Prefetch,
+}
+
+#[derive(Default)]
+struct State {
+    inflight: usize,
+    // on-demand requests running or waiting for a slot
+    on_demand: usize,
+}
+
+pub struct IoScheduler {
+    slots: usize,
+    max_wait: Duration,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// A slot taken by a backend request, released on drop.
+pub struct IoPermit<'a> {
+    scheduler: &'a IoScheduler,
+    priority: IoPriority,
+}
+
+impl Drop for IoPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.inflight -= 1;
+        if self.priority == IoPriority::OnDemand {
+            state.on_demand -= 1;
+        }
+        self.scheduler.released.notify_all();
+    }
+}
+
+impl IoScheduler {
+    /// Run at most `slots` backend requests at the same time, background requests wait for
+    /// on-demand ones at most `max_wait`.
+    pub fn new(slots: usize, max_wait: Duration) -> Self {
+        IoScheduler {
+            slots,
+            max_wait,
+            state: Mutex::new(State::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a slot to issue a backend request of `priority`.
+    pub fn start(&self, priority: IoPriority) -> IoPermit {
+        let mut state = self.state.lock().unwrap();
+        match priority {
+            IoPriority::OnDemand => {
+                state.on_demand += 1;
+                while state.inflight >= self.slots {
+                    state = self.released.wait(state).unwrap();
+                }
+            }
+            IoPriority::Background | IoPriority::Prefetch => {
+                let begin = Instant::now();
+                loop {
+                    let elapsed = begin.elapsed();
+                    let starving = elapsed >= self.max_wait;
+                    if state.inflight < self.slots && (state.on_demand == 0 || starving) {
+                        break;
+                    }
+                    state = if starving {
+                        self.released.wait(state).unwrap()
+                    } else {
+                        self.released
+                            .wait_timeout(state, self.max_wait - elapsed)
+                            .unwrap()
+                            .0
+                    };
+                }
+            }
+        }
+        state.inflight += 1;
+
+        IoPermit {
+            scheduler: self,
+            priority,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_on_demand_preempts_background() {
+        let scheduler = Arc::new(IoScheduler::new(2, Duration::from_secs(10)));
+        let read = scheduler.start(IoPriority::OnDemand);
+
+        // Background requests wait while a read is in flight, even with free slots.
+        let (tx, rx) = mpsc::channel();
+        let s = scheduler.clone();
+        let background = thread::spawn(move || {
+            let _permit = s.start(IoPriority::Background);
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Reads take free slots at once.
+        let other = scheduler.start(IoPriority::OnDemand);
+        drop(other);
+        drop(read);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        background.join().unwrap();
+    }
+
+    #[test]
+    fn test_background_not_starved() {
+        let scheduler = IoScheduler::new(2, Duration::from_millis(100));
+        let _read = scheduler.start(IoPriority::OnDemand);
+
+        let begin = Instant::now();
+        let permit = scheduler.start(IoPriority::Background);
+        assert!(begin.elapsed() >= Duration::from_millis(100));
+        assert_eq!(scheduler.state.lock().unwrap().inflight, 2);
+        drop(permit);
+        assert_eq!(scheduler.state.lock().unwrap().inflight, 1);
+        assert_eq!(scheduler.state.lock().unwrap().on_demand, 1);
+    }
+}
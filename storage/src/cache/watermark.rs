@@ -0,0 +1,147 @@
+// Copyright 2021 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Free space watermarks of the filesystem hosting blob cache files.
+//!
+//! Prefetch could fill up the cache filesystem and make the whole node fail with ENOSPC. Once
+//! free space drops below the low watermark, the blob cache is considered low on space until
+//! free space climbs back above the high watermark. The gap between the two watermarks avoids
+//! flapping around a single threshold.
+
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use nix::sys::statvfs::statvfs;
+
+/// statvfs(2) is not free, so don't check more frequently than this.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct DiskWatermark {
+    path: String,
+    /// Percentage of free space.
+    low: u8,
+    high: u8,
+    low_space: AtomicBool,
+    next_check: Mutex<Option<Instant>>,
+}
+
+impl DiskWatermark {
+    pub fn new(path: &str, low: u8, high: u8) -> Result<Self> {
+        // Resume as soon as the low watermark is crossed back if no high watermark is given.
+        let high = if high == 0 { low } else { high };
+        if low > high || high > 100 {
+            return Err(einval!(format!(
+                "invalid free space watermarks, low {}% high {}%",
+                low, high
+            )));
+        }
+
+        Ok(DiskWatermark {
+            path: path.to_string(),
+            low,
+            high,
+            low_space: AtomicBool::new(false),
+            next_check: Mutex::new(None),
+        })
+    }
+
+    /// Whether the cache filesystem is considered low on free space.
+    pub fn is_low(&self) -> bool {
+        self.low_space.load(Ordering::Acquire)
+    }
+
+    /// Refresh free space state, at most once per `CHECK_INTERVAL`.
+    ///
+    /// Return number of bytes to be released to get back above the high watermark when the
+    /// cache filesystem is low on free space.
+    pub fn check(&self) -> Option<u64> {
+        {
+            let mut next_check = self.next_check.lock().unwrap();
+            let now = Instant::now();
+            if matches!(*next_check, Some(t) if now < t) {
+                return None;
+            }
+            *next_check = Some(now + CHECK_INTERVAL);
+        }
+
+        let (total, free) = match statvfs(self.path.as_str()) {
+            Ok(st) => (
+                st.blocks() as u64 * st.fragment_size() as u64,
+                st.blocks_available() as u64 * st.fragment_size() as u64,
+            ),
+            Err(e) => {
+                warn!("failed to statvfs blobcache work_dir {}: {}", self.path, e);
+                return None;
+            }
+        };
+        if total == 0 {
+            return None;
+        }
+
+        self.update(total, free)
+    }
+
+    /// Take `free` out of `total` bytes as the latest free space, holding off real checks.
+    #[cfg(test)]
+    pub(crate) fn assume(&self, total: u64, free: u64) -> Option<u64> {
+        *self.next_check.lock().unwrap() = Some(Instant::now() + Duration::from_secs(3600));
+        self.update(total, free)
+    }
+
+    fn update(&self, total: u64, free: u64) -> Option<u64> {
+        let percent = free * 100 / total;
+        if !self.is_low() && percent < self.low as u64 {
+            warn!(
+                "blobcache free space {}% is below low watermark {}%, pause prefetch",
+                percent, self.low
+            );
+            self.low_space.store(true, Ordering::Release);
+        } else if self.is_low() && percent >= self.high as u64 {
+            info!(
+                "blobcache free space {}% is above high watermark {}%, resume prefetch",
+                percent, self.high
+            );
+            self.low_space.store(false, Ordering::Release);
+        }
+
+        if self.is_low() {
+            Some((total * self.high as u64 / 100).saturating_sub(free))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_watermark() {
+        assert!(DiskWatermark::new("/", 20, 10).is_err());
+        assert!(DiskWatermark::new("/", 20, 101).is_err());
+
+        let wm = DiskWatermark::new("/", 10, 20).unwrap();
+        assert_eq!(wm.update(1000, 500), None);
+        assert!(!wm.is_low());
+        assert_eq!(wm.update(1000, 50), Some(150));
+        assert!(wm.is_low());
+        // Still low until getting above the high watermark.
+        assert_eq!(wm.update(1000, 150), Some(50));
+        assert!(wm.is_low());
+        assert_eq!(wm.update(1000, 200), None);
+        assert!(!wm.is_low());
+
+        let wm = DiskWatermark::new("/", 10, 0).unwrap();
+        assert_eq!(wm.update(1000, 50), Some(50));
+        assert_eq!(wm.update(1000, 100), None);
+
+        // The first check always happens.
+        let wm = DiskWatermark::new("/", 0, 0).unwrap();
+        assert_eq!(wm.check(), None);
+        assert!(!wm.is_low());
+    }
+}
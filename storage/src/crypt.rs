@@ -0,0 +1,618 @@
+// Copyright 2021 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption of blob data with keys kept in a KMS.
+//!
+//! The builder encrypts chunks after compressing them, so neither the registry nor blob caches
+//! on nodes ever hold chunk data in plaintext. Chunks are sealed with AES-256-GCM, the nonce is
+//! a keyed digest of the data, which is stored in front of the ciphertext and followed by the
+//! authentication tag. Encryption is deterministic: identical chunks encrypt to identical data
+//! under a key, so they are still deduplicated, at the cost of revealing which chunks are equal.
+//!
+//! Keys are versioned. The builder encrypts a blob with the latest version of the key and
+//! records the version in the blob table, so rotating the key doesn't break images built with
+//! earlier versions as long as the KMS keeps them.
+//!
+//! Instead of a KMS, nydusd can also be given the key in a file or in the kernel keyring.
+
+use std::io::Result;
+use std::ptr;
+use std::sync::Arc;
+
+#[cfg(feature = "encryption")]
+use std::collections::HashMap;
+#[cfg(feature = "encryption")]
+use std::ffi::CString;
+#[cfg(feature = "encryption")]
+use std::fs;
+#[cfg(feature = "encryption")]
+use std::io::Error;
+#[cfg(feature = "encryption")]
+use std::sync::Mutex;
+#[cfg(feature = "encryption")]
+use std::time::{Duration, Instant};
+
+use openssl::sha::Sha256;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+#[cfg(feature = "encryption")]
+use reqwest::blocking::Client;
+#[cfg(feature = "encryption")]
+use serde_json::Value;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Bytes an encrypted chunk takes in addition to its data.
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// An AES-256-GCM key to encrypt and decrypt chunks with.
+pub struct BlobCipher {
+    key: [u8; KEY_LEN],
+}
+
+impl BlobCipher {
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() != KEY_LEN {
+            return Err(einval!(format!(
+                "blob key must be {} bytes, got {}",
+                KEY_LEN,
+                key.len()
+            )));
+        }
+        let mut cipher = BlobCipher { key: [0; KEY_LEN] };
+        cipher.key.copy_from_slice(key);
+        Ok(cipher)
+    }
+
+    /// Encrypt chunk data `data`, returns nonce, ciphertext and tag in a row.
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.key);
+        hasher.update(data);
+        let digest = hasher.finish();
+        let nonce = &digest[..NONCE_LEN];
+
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            &[],
+            data,
+            &mut tag,
+        )
+        .map_err(|e| eother!(format!("failed to encrypt chunk: {}", e)))?;
+
+        let mut sealed = Vec::with_capacity(data.len() + ENCRYPTION_OVERHEAD);
+        sealed.extend_from_slice(nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// Decrypt chunk data sealed by `encrypt()`, which fails if it's been tampered with.
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < ENCRYPTION_OVERHEAD {
+            return Err(eio!("encrypted chunk is truncated"));
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(|e| eio!(format!("failed to decrypt chunk: {}", e)))
+    }
+}
+
+impl Drop for BlobCipher {
+    fn drop(&mut self) {
+        // Don't leave the key behind in freed memory.
+        wipe(&mut self.key);
+    }
+}
+
+fn wipe(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+}
+
+/// Source of keys to decrypt blobs with.
+pub trait KeyProvider: Send + Sync {
+    /// Get the cipher of key version `version`.
+    fn cipher(&self, version: u32) -> Result<Arc<BlobCipher>>;
+}
+
+/// Key of encrypted blobs given to nydusd directly, as either `file` or `keyring`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeyConfig {
+    /// File holding the base64 encoded key.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Description of a `user` key holding the base64 encoded key in the kernel keyring, looked
+    /// up in the thread, process and session keyrings of nydusd, like `nydus:blob-key`.
+    #[serde(default)]
+    pub keyring: Option<String>,
+    /// Version of the key as recorded by the builder, blobs encrypted with other versions are
+    /// refused. Any version is accepted if absent.
+    #[serde(default)]
+    pub version: Option<u32>,
+}
+
+/// Key of encrypted blobs loaded once from a file or the kernel keyring.
+#[cfg(feature = "encryption")]
+pub struct LocalKey {
+    cipher: Arc<BlobCipher>,
+    version: Option<u32>,
+}
+
+#[cfg(feature = "encryption")]
+impl LocalKey {
+    pub fn new(config: &KeyConfig) -> Result<Self> {
+        let mut encoded = match (config.file.as_ref(), config.keyring.as_ref()) {
+            (Some(path), None) => fs::read(path)
+                .map_err(|e| eother!(format!("failed to read blob key {}: {}", path, e)))?,
+            (None, Some(description)) => read_keyring(description)?,
+            _ => {
+                return Err(einval!(
+                    "exactly one of `file` and `keyring` of blob key should be given"
+                ))
+            }
+        };
+
+        let text = std::str::from_utf8(&encoded).unwrap_or("").trim();
+        let key = base64::decode(text).map_err(|_| einval!("blob key is not base64 encoded"));
+        wipe(&mut encoded);
+        let mut key = key?;
+        let cipher = BlobCipher::new(&key);
+        wipe(&mut key);
+
+        Ok(LocalKey {
+            cipher: Arc::new(cipher?),
+            version: config.version,
+        })
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl KeyProvider for LocalKey {
+    fn cipher(&self, version: u32) -> Result<Arc<BlobCipher>> {
+        match self.version {
+            Some(v) if v != version => Err(eio!(format!(
+                "blob is encrypted with key version {}, but version {} is configured",
+                version, v
+            ))),
+            _ => Ok(self.cipher.clone()),
+        }
+    }
+}
+
+/// Read the payload of `user` key `description` from the kernel keyring.
+#[cfg(feature = "encryption")]
+fn read_keyring(description: &str) -> Result<Vec<u8>> {
+    let key_type = CString::new("user").unwrap();
+    let desc = CString::new(description)
+        .map_err(|_| einval!(format!("invalid keyring key {:?}", description)))?;
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_request_key,
+            key_type.as_ptr(),
+            desc.as_ptr(),
+            ptr::null::<libc::c_char>(),
+            0,
+        )
+    };
+    if serial < 0 {
+        return Err(eother!(format!(
+            "failed to find key {} in keyring: {}",
+            description,
+            Error::last_os_error()
+        )));
+    }
+
+    let mut buf = vec![0u8; 256];
+    loop {
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                libc::KEYCTL_READ,
+                serial,
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            wipe(&mut buf);
+            return Err(eother!(format!(
+                "failed to read key {} from keyring: {}",
+                description,
+                Error::last_os_error()
+            )));
+        }
+        // The payload is returned in full only if it fits in the buffer.
+        if len as usize <= buf.len() {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        wipe(&mut buf);
+        buf = vec![0u8; len as usize];
+    }
+}
+
+/// Configuration of a KMS serving keys through the KV version 2 API of HashiCorp Vault.
+#[derive(Clone, Debug, Deserialize)]
+pub struct KmsConfig {
+    /// Address of the KMS, like `https://vault.example.com:8200`.
+    pub endpoint: String,
+    /// Path of the secret holding the key, like `secret/data/nydus/image-key`.
+    pub key_path: String,
+    /// Token to access the KMS with.
+    #[serde(default)]
+    pub token: String,
+    /// File to read the token from before each request instead, so that it can be renewed.
+    #[serde(default)]
+    pub token_file: Option<String>,
+    /// Seconds to use a fetched key before fetching it again, so that revoking a key in the
+    /// KMS takes effect.
+    #[serde(default = "default_key_cache_secs")]
+    pub key_cache_secs: u64,
+    /// Timeout of requests to the KMS in seconds.
+    #[serde(default = "default_kms_timeout")]
+    pub timeout: u64,
+}
+
+fn default_key_cache_secs() -> u64 {
+    300
+}
+
+fn default_kms_timeout() -> u64 {
+    5
+}
+
+#[cfg(feature = "encryption")]
+enum FetchError {
+    /// The KMS can't be reached or fails to serve, keys fetched before remain in use.
+    Unavailable(String),
+    /// The KMS refuses to hand out the key.
+    Rejected(String),
+}
+
+#[cfg(feature = "encryption")]
+struct CachedKey {
+    cipher: Arc<BlobCipher>,
+    fetched: Instant,
+}
+
+/// Client of a KMS fetching keys of blobs, keeping them for `key_cache_secs`.
+#[cfg(feature = "encryption")]
+pub struct KmsClient {
+    config: KmsConfig,
+    client: Client,
+    keys: Mutex<HashMap<u32, CachedKey>>,
+}
+
+#[cfg(feature = "encryption")]
+impl KmsClient {
+    pub fn new(config: KmsConfig) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .build()
+            .map_err(|e| einval!(format!("failed to create KMS client: {}", e)))?;
+        Ok(KmsClient {
+            config,
+            client,
+            keys: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Fetch the latest version of the key, to encrypt new blobs with.
+    pub fn latest(&self) -> Result<(u32, Arc<BlobCipher>)> {
+        let (version, cipher) = self.fetch(None).map_err(|e| match e {
+            FetchError::Unavailable(msg) | FetchError::Rejected(msg) => eio!(msg),
+        })?;
+        self.cache(version, cipher.clone());
+        Ok((version, cipher))
+    }
+
+    fn cache(&self, version: u32, cipher: Arc<BlobCipher>) {
+        self.keys.lock().unwrap().insert(
+            version,
+            CachedKey {
+                cipher,
+                fetched: Instant::now(),
+            },
+        );
+    }
+
+    fn token(&self) -> std::result::Result<String, FetchError> {
+        match self.config.token_file.as_ref() {
+            Some(path) => fs::read_to_string(path)
+                .map(|t| t.trim().to_string())
+                .map_err(|e| {
+                    FetchError::Unavailable(format!("failed to read KMS token {}: {}", path, e))
+                }),
+            None => Ok(self.config.token.clone()),
+        }
+    }
+
+    fn fetch(
+        &self,
+        version: Option<u32>,
+    ) -> std::result::Result<(u32, Arc<BlobCipher>), FetchError> {
+        let mut url = format!(
+            "{}/v1/{}",
+            self.config.endpoint.trim_end_matches('/'),
+            self.config.key_path.trim_start_matches('/')
+        );
+        if let Some(v) = version {
+            url += &format!("?version={}", v);
+        }
+
+        let resp = self
+            .client
+            .get(&url)
+            .header("X-Vault-Token", self.token()?)
+            .send()
+            .map_err(|e| FetchError::Unavailable(format!("failed to request KMS: {}", e)))?;
+        let status = resp.status();
+        if status.is_server_error() {
+            return Err(FetchError::Unavailable(format!("KMS responds {}", status)));
+        } else if !status.is_success() {
+            return Err(FetchError::Rejected(format!(
+                "KMS refuses key {}: {}",
+                self.config.key_path, status
+            )));
+        }
+
+        let body: Value = resp
+            .json()
+            .map_err(|e| FetchError::Unavailable(format!("invalid KMS response: {}", e)))?;
+        let invalid = |what: &str| FetchError::Rejected(format!("invalid key in KMS, {}", what));
+        let key = body["data"]["data"]["key"]
+            .as_str()
+            .ok_or_else(|| invalid("no key"))?;
+        let key = base64::decode(key).map_err(|_| invalid("key is not base64 encoded"))?;
+        let cipher = BlobCipher::new(&key).map_err(|e| invalid(&e.to_string()))?;
+        let fetched = body["data"]["metadata"]["version"]
+            .as_u64()
+            .ok_or_else(|| invalid("no key version"))? as u32;
+        // Version 0 means the blob is not encrypted.
+        if fetched == 0 || version.map_or(false, |v| v != fetched) {
+            return Err(invalid(&format!("unexpected version {}", fetched)));
+        }
+
+        Ok((fetched, Arc::new(cipher)))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl KeyProvider for KmsClient {
+    fn cipher(&self, version: u32) -> Result<Arc<BlobCipher>> {
+        let stale = match self.keys.lock().unwrap().get(&version) {
+            Some(key)
+                if key.fetched.elapsed() < Duration::from_secs(self.config.key_cache_secs) =>
+            {
+                return Ok(key.cipher.clone())
+            }
+            Some(key) => Some(key.cipher.clone()),
+            None => None,
+        };
+
+        match self.fetch(Some(version)) {
+            Ok((_, cipher)) => {
+                self.cache(version, cipher.clone());
+                Ok(cipher)
+            }
+            Err(FetchError::Unavailable(msg)) => match stale {
+                Some(cipher) => {
+                    warn!("{}, keep using key version {}", msg, version);
+                    Ok(cipher)
+                }
+                None => Err(eio!(msg)),
+            },
+            Err(FetchError::Rejected(msg)) => {
+                self.keys.lock().unwrap().remove(&version);
+                Err(eio!(msg))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_cipher() {
+        assert!(BlobCipher::new(&[1u8; 16]).is_err());
+        let cipher = BlobCipher::new(&[1u8; KEY_LEN]).unwrap();
+        let data = vec![0x5au8; 4096];
+
+        let sealed = cipher.encrypt(&data).unwrap();
+        assert_eq!(sealed.len(), data.len() + ENCRYPTION_OVERHEAD);
+        assert!(!sealed.windows(64).any(|w| w == &data[..64]));
+        // Identical chunks are encrypted identically, different ones are not.
+        assert_eq!(cipher.encrypt(&data).unwrap(), sealed);
+        assert_ne!(
+            &cipher.encrypt(&data[1..]).unwrap()[..NONCE_LEN],
+            &sealed[..NONCE_LEN]
+        );
+        assert_eq!(cipher.decrypt(&sealed).unwrap(), data);
+
+        let mut tampered = sealed.clone();
+        tampered[100] ^= 1;
+        assert!(cipher.decrypt(&tampered).is_err());
+        assert!(cipher.decrypt(&sealed[..ENCRYPTION_OVERHEAD - 1]).is_err());
+        let other = BlobCipher::new(&[2u8; KEY_LEN]).unwrap();
+        assert!(other.decrypt(&sealed).is_err());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_local_key() {
+        use vmm_sys_util::tempfile::TempFile;
+
+        let cipher = BlobCipher::new(&[3u8; KEY_LEN]).unwrap();
+        let sealed = cipher.encrypt(b"data").unwrap();
+        let file = TempFile::new().unwrap();
+        fs::write(
+            file.as_path(),
+            format!("{}\n", base64::encode(&[3u8; KEY_LEN])),
+        )
+        .unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+
+        let key = LocalKey::new(&KeyConfig {
+            file: Some(path.clone()),
+            keyring: None,
+            version: None,
+        })
+        .unwrap();
+        assert_eq!(key.cipher(5).unwrap().decrypt(&sealed).unwrap(), b"data");
+
+        let key = LocalKey::new(&KeyConfig {
+            file: Some(path.clone()),
+            keyring: None,
+            version: Some(2),
+        })
+        .unwrap();
+        assert!(key.cipher(2).is_ok());
+        assert!(key.cipher(1).is_err());
+
+        // Only one source can be given.
+        assert!(LocalKey::new(&KeyConfig {
+            file: Some(path.clone()),
+            keyring: Some("nydus:blob-key".to_string()),
+            version: None,
+        })
+        .is_err());
+        assert!(LocalKey::new(&KeyConfig {
+            file: None,
+            keyring: None,
+            version: None,
+        })
+        .is_err());
+
+        fs::write(file.as_path(), "not a key").unwrap();
+        assert!(LocalKey::new(&KeyConfig {
+            file: Some(path),
+            keyring: None,
+            version: None,
+        })
+        .is_err());
+    }
+
+    #[cfg(feature = "encryption")]
+    mod kms {
+        use super::super::*;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+        use std::thread;
+
+        /// Serve `responses` as (status, body) one per request, send request lines to `tx`.
+        fn serve(responses: Vec<(u16, String)>, tx: mpsc::Sender<String>) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                for (status, body) in responses {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut req = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !req.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        req.extend_from_slice(&buf[..n]);
+                    }
+                    let req = String::from_utf8_lossy(&req).to_string();
+                    tx.send(req).unwrap();
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .unwrap();
+                }
+            });
+            format!("http://{}", addr)
+        }
+
+        fn key_response(key: u8, version: u32) -> (u16, String) {
+            let body = format!(
+                r#"{{"data":{{"data":{{"key":"{}"}},"metadata":{{"version":{}}}}}}}"#,
+                base64::encode(&[key; KEY_LEN]),
+                version
+            );
+            (200, body)
+        }
+
+        fn client(endpoint: String, key_cache_secs: u64) -> KmsClient {
+            KmsClient::new(KmsConfig {
+                endpoint,
+                key_path: "secret/data/nydus".to_string(),
+                token: "t0ken".to_string(),
+                token_file: None,
+                key_cache_secs,
+                timeout: 5,
+            })
+            .unwrap()
+        }
+
+        #[test]
+        fn test_kms_key_versions() {
+            let (tx, rx) = mpsc::channel();
+            let endpoint = serve(vec![key_response(2, 2), key_response(1, 1)], tx);
+            let kms = client(endpoint, 300);
+
+            let (version, latest) = kms.latest().unwrap();
+            assert_eq!(version, 2);
+            let req = rx.recv().unwrap();
+            assert!(req.starts_with("GET /v1/secret/data/nydus HTTP/1.1"));
+            assert!(req.to_lowercase().contains("x-vault-token: t0ken"));
+
+            // The latest key is cached, an earlier version is fetched.
+            let sealed = latest.encrypt(b"data").unwrap();
+            assert_eq!(kms.cipher(2).unwrap().decrypt(&sealed).unwrap(), b"data");
+            let old = kms.cipher(1).unwrap();
+            assert!(rx
+                .recv()
+                .unwrap()
+                .starts_with("GET /v1/secret/data/nydus?version=1 "));
+            assert!(old.decrypt(&sealed).is_err());
+            assert!(kms.cipher(1).is_ok());
+        }
+
+        #[test]
+        fn test_kms_key_expiry() {
+            let (tx, _rx) = mpsc::channel();
+            let endpoint = serve(
+                vec![
+                    key_response(1, 1),
+                    (503, "{}".to_string()),
+                    (403, r#"{"errors":["permission denied"]}"#.to_string()),
+                    (503, "{}".to_string()),
+                ],
+                tx,
+            );
+            let kms = client(endpoint, 0);
+
+            assert!(kms.cipher(1).is_ok());
+            // The KMS is out of service, the expired key is still used.
+            assert!(kms.cipher(1).is_ok());
+            // The key is revoked, it's dropped.
+            assert!(kms.cipher(1).is_err());
+            assert!(kms.cipher(1).is_err());
+        }
+    }
+}
@@ -3,22 +3,24 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use arc_swap::ArcSwap;
-use std::cmp;
 use std::io;
 use std::io::Error;
+use std::os::unix::io::RawFd;
+use std::path::Path;
 use std::sync::Arc;
 
 use fuse_rs::api::filesystem::{ZeroCopyReader, ZeroCopyWriter};
 use fuse_rs::transport::FileReadWriteVolatile;
-use vm_memory::{Bytes, VolatileSlice};
+use vm_memory::VolatileSlice;
 
-use crate::cache::RafsCache;
+use crate::backend::BackendProbe;
+use crate::cache::scheduler::IoPriority;
+use crate::cache::{CacheMemoryUsage, CachedBlob, RafsCache};
+use crate::utils::{alloc_buf, copyv, fill_zero};
 use crate::{compress, factory, StorageResult};
 
 use nydus_utils::digest::{self, RafsDigest};
 
-static ZEROS: &[u8] = &[0u8; 4096]; // why 4096? volatile slice default size, unfortunately
-
 // A rafs storage device
 #[derive(Clone)]
 pub struct RafsDevice {
@@ -30,6 +32,8 @@ bitflags! {
         /// chunk is compressed
         const COMPRESSED = 0x0000_0001;
         const HOLECHUNK = 0x0000_0002;
+        /// chunk is encrypted after compressed, see `crypt`
+        const ENCRYPTED = 0x0000_0004;
     }
 }
 
@@ -53,6 +57,9 @@ pub trait RafsChunkInfo: Sync + Send {
     fn is_compressed(&self) -> bool;
     fn is_hole(&self) -> bool;
     fn flags(&self) -> RafsChunkFlags;
+    fn is_encrypted(&self) -> bool {
+        self.flags().contains(RafsChunkFlags::ENCRYPTED)
+    }
 }
 
 impl Default for RafsChunkFlags {
@@ -91,13 +98,13 @@ impl RafsDevice {
         digester: digest::Algorithm,
         id: &str,
     ) -> io::Result<()> {
+        // Set up the new layer first, so a bad config leaves the current one serving.
+        let rw_layer = factory::new_rw_layer(config, compressor, digester, id)?;
         // Stop prefetch if it is running before swapping backend since prefetch
         // threads cloned Arc<Cache>, the swap operation can't drop inner object completely.
         // Otherwise prefetch threads will be leaked.
         self.stop_prefetch().unwrap_or_else(|e| error!("{:?}", e));
-        self.rw_layer.store(Arc::new(factory::new_rw_layer(
-            config, compressor, digester, id,
-        )?));
+        self.rw_layer.store(Arc::new(rw_layer));
         Ok(())
     }
 
@@ -112,14 +119,97 @@ impl RafsDevice {
 
     /// Read a range of data from blob into the provided writer
     pub fn read_to(&self, w: &mut dyn ZeroCopyWriter, desc: RafsBioDesc) -> io::Result<usize> {
+        let mut chunks = self.read_parallel(&desc);
         let mut count: usize = 0;
-        for bio in desc.bi_vec.iter() {
+        for (idx, bio) in desc.bi_vec.iter().enumerate() {
             let mut f = RafsBioDevice::new(bio, &self);
+            f.chunk = chunks.get_mut(idx).and_then(Option::take);
             count += w.write_from(&mut f, bio.size, bio.offset as u64)?;
         }
         Ok(count)
     }
 
+    /// Read a range of data from blob into the provided buffer, for users out of a FUSE context.
+    ///
+    /// The buffer must be large enough to hold `desc.bi_size` bytes. Reading stops at the first
+    /// short read of a bio, returning the number of contiguous bytes read so far.
+    pub fn read_to_buf(&self, buf: &mut [u8], desc: RafsBioDesc) -> io::Result<usize> {
+        if buf.len() < desc.bi_size {
+            return Err(einval!("buffer is too small for the bio desc"));
+        }
+
+        let mut chunks = self.read_parallel(&desc);
+        let mut count: usize = 0;
+        for (idx, bio) in desc.bi_vec.iter().enumerate() {
+            let dst = &mut buf[count..count + bio.size];
+            // It's safe because the slice is borrowed exclusively during the read.
+            let vs = unsafe { VolatileSlice::new(dst.as_mut_ptr(), dst.len()) };
+            let mut f = RafsBioDevice::new(bio, &self);
+            f.chunk = chunks.get_mut(idx).and_then(Option::take);
+            let n = f.read_vectored_at_volatile(&[vs], bio.offset as u64)?;
+            let len = std::cmp::min(n, bio.size);
+            count += len;
+            // Data after a short read would leave a gap in the buffer.
+            if len < bio.size {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Read a range of data from blob as slices of chunk buffers, for replying with a single
+    /// `writev()` instead of copying whole chunks decompressed by `RafsCache::read_parallel()`
+    /// into the reply buffer. Bios without such a chunk are read into buffers of their own.
+    pub fn read_vectored(&self, desc: RafsBioDesc) -> io::Result<RafsReadVec> {
+        let mut chunks = self.read_parallel(&desc);
+        let mut bufs = Vec::with_capacity(desc.bi_vec.len());
+        for (idx, bio) in desc.bi_vec.iter().enumerate() {
+            let start = bio.offset as usize;
+            match chunks.get_mut(idx).and_then(Option::take) {
+                Some(chunk) if start + bio.size <= chunk.len() => {
+                    bufs.push((chunk, start, bio.size));
+                }
+                _ => {
+                    let mut buf = alloc_buf(bio.size);
+                    // It's safe because the buffer is owned here during the read.
+                    let vs = unsafe { VolatileSlice::new(buf.as_mut_ptr(), buf.len()) };
+                    let n = RafsBioDevice::new(bio, &self)
+                        .read_vectored_at_volatile(&[vs], start as u64)?;
+                    let len = std::cmp::min(n, bio.size);
+                    bufs.push((buf, 0, len));
+                    if len < bio.size {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(RafsReadVec { bufs })
+    }
+
+    /// Whole chunks of a read spanning more than one chunk, decompressed in parallel by cache.
+    fn read_parallel(&self, desc: &RafsBioDesc) -> Vec<Option<Vec<u8>>> {
+        if desc.bi_vec.len() < 2 {
+            return Vec::new();
+        }
+        self.rw_layer.load().read_parallel(desc.bi_vec.as_slice())
+    }
+
+    /// Splice a range of data into the pipe `pipe` straight from cache files, see
+    /// `RafsCache::splice()`.
+    pub fn splice_to(
+        &self,
+        desc: &RafsBioDesc,
+        pipe: RawFd,
+        prepare: &mut dyn FnMut(usize) -> io::Result<()>,
+    ) -> io::Result<Option<usize>> {
+        if desc.bi_vec.is_empty() {
+            return Ok(None);
+        }
+        self.rw_layer
+            .load()
+            .splice(desc.bi_vec.as_slice(), pipe, prepare)
+    }
+
     /// Write a range of data to blob from the provided reader
     pub fn write_from(&self, r: &mut dyn ZeroCopyReader, desc: RafsBioDesc) -> io::Result<usize> {
         let mut count: usize = 0;
@@ -131,26 +221,124 @@ impl RafsDevice {
         Ok(count)
     }
 
+    /// Fetch chunks continuous in blob into cache in one shot, to amplify small reads.
+    pub fn fetch(&self, desc: &RafsBioDesc, priority: IoPriority) -> io::Result<usize> {
+        self.rw_layer.load().fetch(desc.bi_vec.as_slice(), priority)
+    }
+
+    /// Whether reading `bio` goes to backend rather than hitting cache.
+    pub fn need_fetch(&self, bio: &RafsBio) -> io::Result<bool> {
+        self.rw_layer.load().need_fetch(bio)
+    }
+
+    /// Fetch all chunks of `desc` into cache, merging continuous chunks into backend requests
+    /// of at most `merging_size` bytes. Unlike `prefetch()`, it works without prefetch workers
+    /// and returns after all chunks are fetched.
+    pub fn fetch_all(
+        &self,
+        desc: &RafsBioDesc,
+        merging_size: u64,
+        priority: IoPriority,
+    ) -> io::Result<usize> {
+        let bios = desc.bi_vec.as_slice();
+        let size = |idx: usize| bios[idx].chunkinfo.compress_size() as u64;
+        let layer = self.rw_layer.load();
+        let mut count = 0;
+        // Chunks in bios[start..end] take `window` bytes.
+        let mut end = 0;
+        let mut window = 0;
+
+        for start in 0..bios.len() {
+            while end < bios.len() && (end == start || window + size(end) <= merging_size) {
+                window += size(end);
+                end += 1;
+            }
+            // Ready chunks are skipped by cache, so a run of continuous chunks gets fetched
+            // by the request starting from its first chunk.
+            count += layer.fetch(&bios[start..end], priority)?;
+            window -= size(start);
+        }
+
+        Ok(count)
+    }
+
     pub fn prefetch(&self, desc: &mut RafsBioDesc) -> StorageResult<usize> {
         self.rw_layer.load().prefetch(desc.bi_vec.as_mut_slice())?;
 
         Ok(desc.bi_size)
     }
 
+    pub fn set_prefetch_bandwidth(&self, bandwidth_rate: u32) -> io::Result<()> {
+        self.rw_layer.load().set_prefetch_bandwidth(bandwidth_rate)
+    }
+
+    pub fn cached_blobs(&self) -> io::Result<Vec<CachedBlob>> {
+        self.rw_layer.load().cached_blobs()
+    }
+
+    pub fn memory_usage(&self) -> CacheMemoryUsage {
+        self.rw_layer.load().memory_usage()
+    }
+
+    pub fn purge_blobs(&self, blob_id: Option<&str>) -> io::Result<usize> {
+        self.rw_layer.load().purge_blobs(blob_id)
+    }
+
+    pub fn import_blob(&self, desc: &RafsBioDesc, src: &Path) -> io::Result<usize> {
+        self.rw_layer
+            .load()
+            .import_blob(desc.bi_vec.as_slice(), src)
+    }
+
+    pub fn probe_backend(&self, blob_id: &str) -> BackendProbe {
+        self.rw_layer.load().backend().probe(blob_id)
+    }
+
     pub fn stop_prefetch(&self) -> StorageResult<()> {
         self.rw_layer.load().stop_prefetch()
     }
 }
 
+/// Data of a read held in chunk buffers, see `RafsDevice::read_vectored()`.
+pub struct RafsReadVec {
+    // (buffer, offset, length) of data in order
+    bufs: Vec<(Vec<u8>, usize, usize)>,
+}
+
+impl RafsReadVec {
+    /// Size of data in bytes.
+    pub fn len(&self) -> usize {
+        self.bufs.iter().map(|b| b.2).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Slices of data in order.
+    pub fn slices(&self) -> Vec<&[u8]> {
+        self.bufs
+            .iter()
+            .map(|(buf, offset, len)| &buf[*offset..*offset + *len])
+            .collect()
+    }
+}
+
 struct RafsBioDevice<'a> {
     bio: &'a RafsBio,
     dev: &'a RafsDevice,
+    /// Whole chunk of `bio` already read by `RafsCache::read_parallel()`.
+    chunk: Option<Vec<u8>>,
 }
 
 impl<'a> RafsBioDevice<'a> {
     fn new(bio: &'a RafsBio, b: &'a RafsDevice) -> Self {
         // FIXME: make sure bio is valid
-        RafsBioDevice { bio, dev: b }
+        RafsBioDevice {
+            bio,
+            dev: b,
+            chunk: None,
+        }
     }
 }
 
@@ -178,6 +366,9 @@ impl FileReadWriteVolatile for RafsBioDevice<'_> {
         if self.bio.chunkinfo.is_hole() {
             return self.fill_hole(bufs);
         }
+        if let Some(chunk) = self.chunk.as_ref() {
+            return copyv(chunk, bufs, offset, self.bio.size);
+        }
 
         self.dev.rw_layer.load().read(&self.bio, bufs, offset)
     }
@@ -195,24 +386,7 @@ impl FileReadWriteVolatile for RafsBioDevice<'_> {
 
 impl RafsBioDevice<'_> {
     fn fill_hole(&self, bufs: &[VolatileSlice]) -> Result<usize, Error> {
-        let mut count: usize = 0;
-        let mut remain: usize = self.bio.size;
-
-        for &buf in bufs.iter() {
-            let mut total = cmp::min(remain, buf.len());
-            let mut offset = 0;
-            while total > 0 {
-                let cnt = cmp::min(total, ZEROS.len());
-                buf.write_slice(&ZEROS[0..cnt], offset)
-                    .map_err(|_| eio!("decompression failed"))?;
-                count += cnt;
-                remain -= cnt;
-                total -= cnt;
-                offset += cnt;
-            }
-        }
-
-        Ok(count)
+        fill_zero(bufs, self.bio.size).map_err(|_| eio!("failed to fill hole chunk"))
     }
 }
 
@@ -229,6 +403,8 @@ pub struct RafsBlobEntry {
     pub blob_index: u32,
     /// The expected decompress size of blob cache file.
     pub blob_cache_size: u64,
+    /// Version of the key chunks are encrypted with, 0 if not encrypted.
+    pub key_version: u32,
 }
 
 // Rafs device blob IO descriptor
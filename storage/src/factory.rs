@@ -13,6 +13,7 @@ use serde_json::value::Value;
 use crate::backend::*;
 use crate::cache::*;
 use crate::compress;
+use crate::crypt::{KeyConfig, KeyProvider, KmsConfig};
 
 use nydus_utils::digest;
 
@@ -67,6 +68,9 @@ pub struct CacheConfig {
     pub cache_validate: bool,
     #[serde(skip_serializing, skip_deserializing)]
     pub prefetch_worker: PrefetchWorker,
+    // Keys of encrypted blobs, set up by Rafs from its `encryption` or `encryption_key` config.
+    #[serde(skip_serializing, skip_deserializing)]
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
 }
 
 pub fn new_backend(
@@ -87,6 +91,34 @@ pub fn new_backend(
     }
 }
 
+pub fn new_key_provider(config: KmsConfig) -> IOResult<Arc<dyn KeyProvider>> {
+    #[cfg(feature = "encryption")]
+    {
+        Ok(Arc::new(crate::crypt::KmsClient::new(config)?))
+    }
+    #[cfg(not(feature = "encryption"))]
+    {
+        Err(einval!(format!(
+            "blob encryption is not supported, can't use KMS {}",
+            config.endpoint
+        )))
+    }
+}
+
+pub fn new_local_key(config: KeyConfig) -> IOResult<Arc<dyn KeyProvider>> {
+    #[cfg(feature = "encryption")]
+    {
+        Ok(Arc::new(crate::crypt::LocalKey::new(&config)?))
+    }
+    #[cfg(not(feature = "encryption"))]
+    {
+        Err(einval!(format!(
+            "blob encryption is not supported, can't use key {:?}",
+            config
+        )))
+    }
+}
+
 pub fn new_rw_layer(
     config: Config,
     compressor: compress::Algorithm,
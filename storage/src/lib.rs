@@ -2,6 +2,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+//! Storage layer of Nydus images: backends fetching blob data from registries, object storage or
+//! local files, caches keeping fetched chunks, and [`device::RafsDevice`] reading file ranges
+//! described by [`device::RafsBioDesc`] through them.
+
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -9,11 +13,14 @@ extern crate serde;
 #[macro_use]
 extern crate bitflags;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate nydus_utils;
 
 pub mod backend;
 pub mod cache;
 pub mod compress;
+pub mod crypt;
 pub mod device;
 pub mod factory;
 pub mod utils;
@@ -2,19 +2,27 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fs::{File, OpenOptions};
 use std::io::{ErrorKind, Result};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::RawFd;
 use std::slice::from_raw_parts_mut;
 
 use libc::off64_t;
-use nix::sys::uio::{preadv, IoVec};
+use nix::sys::uio::{pread, preadv, IoVec};
 use vm_memory::{Bytes, VolatileSlice};
 
 use nydus_utils::{
     digest::{self, RafsDigest},
-    round_down_4k,
+    round_down_4k, try_round_up_4k,
 };
 
+/// A shared page of zeros to serve hole and all-zero chunks.
+static ZEROS: &[u8] = &[0u8; 4096];
+
+/// Alignment of offset, size and memory buffer required by O_DIRECT IO.
+pub const DIRECT_IO_ALIGNMENT: u64 = 4096;
+
 pub fn readv(fd: RawFd, bufs: &[VolatileSlice], offset: u64, max_size: usize) -> Result<usize> {
     if bufs.is_empty() {
         return Ok(0);
@@ -83,6 +91,37 @@ pub fn copyv(src: &[u8], dst: &[VolatileSlice], offset: u64, mut max_size: usize
     Ok(size)
 }
 
+/// Fill at most `max_size` bytes of the buffers with zeros.
+pub fn fill_zero(bufs: &[VolatileSlice], max_size: usize) -> Result<usize> {
+    let mut count: usize = 0;
+    let mut remain = max_size;
+
+    for buf in bufs.iter() {
+        let mut total = std::cmp::min(remain, buf.len());
+        let mut offset = 0;
+        while total > 0 {
+            let cnt = std::cmp::min(total, ZEROS.len());
+            buf.write_slice(&ZEROS[0..cnt], offset)
+                .map_err(|e| einval!(e))?;
+            count += cnt;
+            remain -= cnt;
+            total -= cnt;
+            offset += cnt;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Check whether all bytes of the data are zero.
+pub fn is_zero(data: &[u8]) -> bool {
+    // Compare in unit of u64 for speed, it's safe because any bit pattern is a valid u64.
+    let (prefix, words, suffix) = unsafe { data.align_to::<u64>() };
+    prefix.iter().all(|b| *b == 0)
+        && words.iter().all(|w| *w == 0)
+        && suffix.iter().all(|b| *b == 0)
+}
+
 /// A customized readahead function to ask kernel to fault in all pages from offset to end.
 ///
 /// Call libc::readahead on every 128KB range because otherwise readahead stops at kernel bdi
@@ -101,6 +140,12 @@ pub fn readahead(fd: libc::c_int, mut offset: u64, end: u64) {
     }
 }
 
+/// Approximate bytes of memory taken by a hash table with room for `capacity` entries of `T`,
+/// which takes a control byte per bucket besides the entry itself.
+pub fn hash_table_bytes<T>(capacity: usize) -> usize {
+    capacity * (std::mem::size_of::<T>() + 1)
+}
+
 /// A customized buf allocator that avoids zeroing
 pub fn alloc_buf(size: usize) -> Vec<u8> {
     let mut buf = Vec::with_capacity(size);
@@ -108,7 +153,291 @@ pub fn alloc_buf(size: usize) -> Vec<u8> {
     buf
 }
 
+/// A buffer whose start address is aligned to `DIRECT_IO_ALIGNMENT`, suitable for O_DIRECT IO.
+pub struct AlignedBuf {
+    buf: Vec<u8>,
+    start: usize,
+    size: usize,
+}
+
+impl AlignedBuf {
+    pub fn new(size: usize) -> Self {
+        let align = DIRECT_IO_ALIGNMENT as usize;
+        let buf = alloc_buf(size + align);
+        let start = (align - (buf.as_ptr() as usize) % align) % align;
+        AlignedBuf { buf, start, size }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.size]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + self.size]
+    }
+}
+
+/// Read from a file opened with O_DIRECT at arbitrary offset and size.
+///
+/// The range is expanded to `DIRECT_IO_ALIGNMENT` boundaries and read into an aligned bounce
+/// buffer, then the requested part is copied out. Returns less than `buf.len()` on EOF.
+pub fn pread_direct(fd: RawFd, buf: &mut [u8], offset: u64) -> Result<usize> {
+    let start = round_down_4k(offset);
+    let end: u64 = try_round_up_4k(offset + buf.len() as u64).ok_or_else(|| einval!())?;
+    let mut bounce = AlignedBuf::new((end - start) as usize);
+
+    let mut nr_read = 0;
+    loop {
+        let dst = &mut bounce.as_mut_slice()[nr_read..];
+        if dst.is_empty() {
+            break;
+        }
+        match pread(fd, dst, (start + nr_read as u64) as off64_t) {
+            Ok(0) => break,
+            Ok(n) => nr_read += n,
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(_) => return Err(last_error!()),
+        }
+        // A short read not ending on the alignment boundary means EOF.
+        if nr_read as u64 % DIRECT_IO_ALIGNMENT != 0 {
+            break;
+        }
+    }
+
+    let head = (offset - start) as usize;
+    if nr_read <= head {
+        return Ok(0);
+    }
+    let len = std::cmp::min(nr_read - head, buf.len());
+    buf[..len].copy_from_slice(&bounce.as_slice()[head..head + len]);
+
+    Ok(len)
+}
+
+/// Like `readv()`, but for files opened with O_DIRECT.
+pub fn readv_direct(
+    fd: RawFd,
+    bufs: &[VolatileSlice],
+    offset: u64,
+    max_size: usize,
+) -> Result<usize> {
+    let total = bufs.iter().fold(0usize, |acc, b| acc + b.len());
+    let mut data = alloc_buf(std::cmp::min(total, max_size));
+    let nr_read = pread_direct(fd, &mut data, offset)?;
+
+    copyv(&data[..nr_read], bufs, 0, nr_read)
+}
+
+/// Open `path` for reading with O_DIRECT, or return None if its filesystem doesn't support
+/// O_DIRECT, e.g. tmpfs before Linux 6.6, so that the caller falls back to buffered IO.
+pub fn open_direct(path: &str) -> Result<Option<File>> {
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        Ok(f) => Ok(Some(f)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            warn!(
+                "O_DIRECT is not supported for {}, read it with buffered IO",
+                path
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Write back and drop cached pages of the range, so that data written through a buffered fd
+/// doesn't stay in page cache when the file is read with O_DIRECT.
+pub fn drop_page_cache(fd: RawFd, offset: u64, size: usize) {
+    let ret = unsafe {
+        libc::sync_file_range(
+            fd,
+            offset as off64_t,
+            size as off64_t,
+            libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                | libc::SYNC_FILE_RANGE_WRITE
+                | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+        )
+    };
+    if ret != 0 {
+        warn!("failed to write back range of fd {}: {}", fd, last_error!());
+        return;
+    }
+    let ret = unsafe {
+        libc::posix_fadvise(
+            fd,
+            offset as off64_t,
+            size as off64_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+    if ret != 0 {
+        warn!("failed to drop page cache of fd {}: {}", fd, ret);
+    }
+}
+
+/// Deallocate disk space of a file range while keeping the file size.
+pub fn punch_hole(fd: RawFd, offset: u64, len: u64) -> Result<()> {
+    let ret = unsafe {
+        libc::fallocate(
+            fd,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as off64_t,
+            len as off64_t,
+        )
+    };
+    if ret != 0 {
+        return Err(last_error!());
+    }
+    Ok(())
+}
+
+/// Move `size` bytes of file `fd` at `offset` into the pipe `pipe`, by reference to page cache
+/// rather than copying through userspace. Fails with EAGAIN instead of blocking if the pipe
+/// gets full.
+pub fn splice_to_pipe(fd: RawFd, offset: u64, pipe: RawFd, size: usize) -> Result<()> {
+    let mut off = offset as off64_t;
+    let mut left = size;
+    while left > 0 {
+        let ret = unsafe {
+            libc::splice(
+                fd,
+                &mut off,
+                pipe,
+                std::ptr::null_mut(),
+                left,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if ret < 0 {
+            let e = last_error!();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if ret == 0 {
+            return Err(eio!("unexpected end of file"));
+        }
+        left -= ret as usize;
+    }
+    Ok(())
+}
+
 /// Check hash of data matches provided one
 pub fn digest_check(data: &[u8], digest: &RafsDigest, digester: digest::Algorithm) -> bool {
     digest == &RafsDigest::from_buf(data, digester)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    fn data_file(size: usize) -> (TempFile, Vec<u8>) {
+        let tmp = TempFile::new().unwrap();
+        let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(tmp.as_path(), &data).unwrap();
+        (tmp, data)
+    }
+
+    #[test]
+    fn test_aligned_buf() {
+        for size in &[0, 1, 511, 4096, 4097, 65536 + 3] {
+            let mut buf = AlignedBuf::new(*size);
+            assert_eq!(buf.as_slice().as_ptr() as u64 % DIRECT_IO_ALIGNMENT, 0);
+            assert_eq!(buf.as_slice().len(), *size);
+            assert_eq!(buf.as_mut_slice().as_ptr() as u64 % DIRECT_IO_ALIGNMENT, 0);
+        }
+    }
+
+    #[test]
+    fn test_pread_direct() {
+        let (tmp, data) = data_file(3 * 4096 + 100);
+        let path = tmp.as_path().to_str().unwrap();
+        // Buffered if the filesystem of the temp dir doesn't support O_DIRECT.
+        let file = open_direct(path)
+            .unwrap()
+            .unwrap_or_else(|| File::open(path).unwrap());
+        let fd = file.as_raw_fd();
+
+        // Unaligned offset and size, across alignment boundaries.
+        for (offset, size) in &[
+            (0, 4096),
+            (1, 10),
+            (4000, 200),
+            (4095, 8193),
+            (100, 3 * 4096),
+        ] {
+            let mut buf = vec![0u8; *size];
+            assert_eq!(pread_direct(fd, &mut buf, *offset as u64).unwrap(), *size);
+            assert_eq!(&buf[..], &data[*offset..*offset + *size]);
+        }
+
+        // Short reads at the end of file.
+        let mut buf = vec![0u8; 4096];
+        assert_eq!(pread_direct(fd, &mut buf, 3 * 4096).unwrap(), 100);
+        assert_eq!(&buf[..100], &data[3 * 4096..]);
+        assert_eq!(pread_direct(fd, &mut buf, 3 * 4096 + 100).unwrap(), 0);
+        assert_eq!(pread_direct(fd, &mut buf, 8 * 4096).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_readv_direct() {
+        let (tmp, data) = data_file(2 * 4096);
+        let path = tmp.as_path().to_str().unwrap();
+        let file = open_direct(path)
+            .unwrap()
+            .unwrap_or_else(|| File::open(path).unwrap());
+
+        let mut buf1 = vec![0u8; 100];
+        let mut buf2 = vec![0u8; 5000];
+        let bufs = unsafe {
+            [
+                VolatileSlice::new(buf1.as_mut_ptr(), buf1.len()),
+                VolatileSlice::new(buf2.as_mut_ptr(), buf2.len()),
+            ]
+        };
+        assert_eq!(
+            readv_direct(file.as_raw_fd(), &bufs, 10, 3000).unwrap(),
+            3000
+        );
+        assert_eq!(&buf1[..], &data[10..110]);
+        assert_eq!(&buf2[..2900], &data[110..3010]);
+        assert!(buf2[2900..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_is_zero() {
+        assert!(is_zero(&[]));
+        assert!(is_zero(&[0u8; 4099][1..]));
+        let mut data = vec![0u8; 4099];
+        for i in [0, 1, 2048, 4098].iter() {
+            data[*i] = 1;
+            assert!(!is_zero(&data));
+            data[*i] = 0;
+        }
+    }
+
+    #[test]
+    fn test_fill_zero() {
+        // Hole chunks are filled with zeros only up to the bio size.
+        let mut buf1 = vec![1u8; 100];
+        let mut buf2 = vec![1u8; 5000];
+        let bufs = unsafe {
+            [
+                VolatileSlice::new(buf1.as_mut_ptr(), buf1.len()),
+                VolatileSlice::new(buf2.as_mut_ptr(), buf2.len()),
+            ]
+        };
+        assert_eq!(fill_zero(&bufs, 4196).unwrap(), 4196);
+        assert!(is_zero(&buf1));
+        assert!(is_zero(&buf2[..4096]));
+        assert!(buf2[4096..].iter().all(|b| *b == 1));
+    }
+}
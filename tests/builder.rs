@@ -210,6 +210,25 @@ impl<'a> Builder<'a> {
         ).unwrap();
     }
 
+    /// Build the lower dir into `bootstrap` in the work dir, saving mtime of files if `mtime`.
+    pub fn build_mtime(&mut self, bootstrap: &str, mtime: bool) {
+        self.create_dir(&self.work_dir.join("blobs"));
+
+        exec(
+            format!(
+                "{:?} create --bootstrap {:?} --blob-dir {:?} --log-level info --whiteout-spec {} {} {:?}",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                self.work_dir.join("blobs"),
+                self.whiteout_spec,
+                if mtime { "--inode-mtime" } else { "" },
+                self.work_dir.join("lower"),
+            )
+            .as_str(),
+            false,
+        ).unwrap();
+    }
+
     pub fn build_upper(&mut self, compressor: &str) {
         let upper_dir = self.work_dir.join("upper");
 
@@ -229,6 +248,268 @@ impl<'a> Builder<'a> {
         ).unwrap();
     }
 
+    /// Unpack `bootstrap` with blobs of the blob dir and check that the extracted files are
+    /// the same as those under `expected`.
+    pub fn unpack(&mut self, bootstrap: &str, expected: &str) {
+        let tar = self.work_dir.join(format!("{}.tar", bootstrap));
+        let output = self.work_dir.join(format!("{}-unpacked", bootstrap));
+        self.create_dir(&output);
+
+        exec(
+            format!(
+                "{:?} unpack --bootstrap {:?} --blob-dir {:?} --output {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                self.work_dir.join("blobs"),
+                tar,
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+        // Streamed tar is the same as the written one.
+        exec(
+            format!(
+                "{:?} unpack --bootstrap {:?} --blob-dir {:?} --output - --log-level info | cmp - {:?}",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                self.work_dir.join("blobs"),
+                tar,
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+        exec(
+            format!(
+                "tar --xattrs --xattrs-include='*' -xf {:?} -C {:?}",
+                tar, output
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+        exec(
+            format!(
+                "diff -r --no-dereference {:?} {:?}",
+                self.work_dir.join(expected),
+                output
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+    }
+
+    /// Verify `bootstrap` with blobs of the blob dir, then with a copy of the blobs having a
+    /// byte flipped in each, which must fail.
+    pub fn verify(&mut self, bootstrap: &str) {
+        let report = exec(
+            format!(
+                "{:?} verify --bootstrap {:?} --blob-dir {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                self.work_dir.join("blobs"),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap();
+        assert!(report.contains("\"valid\": true"));
+
+        let corrupted = self.work_dir.join("blobs-corrupted");
+        self.create_dir(&corrupted);
+        for entry in fs::read_dir(self.work_dir.join("blobs")).unwrap() {
+            let entry = entry.unwrap();
+            let mut data = fs::read(entry.path()).unwrap();
+            let mid = data.len() / 2;
+            data[mid] ^= 0xff;
+            fs::write(corrupted.join(entry.file_name()), data).unwrap();
+        }
+        let result = exec(
+            format!(
+                "{:?} verify --bootstrap {:?} --blob-dir {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                corrupted,
+            )
+            .as_str(),
+            true,
+        );
+        assert!(result.is_err());
+        fs::remove_dir_all(corrupted).unwrap();
+    }
+
+    /// Export `bootstraps` of layers, the lowest first, and list entries of each layer.
+    pub fn export(&mut self, bootstraps: &[&str]) -> Vec<Vec<String>> {
+        let output = self.work_dir.join("exported");
+        let args = bootstraps
+            .iter()
+            .map(|b| format!("--bootstrap {:?}", self.work_dir.join(b)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        exec(
+            format!(
+                "{:?} export --format oci {} --blob-dir {:?} --output-dir {:?} --log-level info",
+                self.builder,
+                args,
+                self.work_dir.join("blobs"),
+                output,
+            )
+            .as_str(),
+            false,
+        )
+        .unwrap();
+
+        (0..bootstraps.len())
+            .map(|i| {
+                exec(
+                    format!("tar -tzf {:?}", output.join(format!("layer-{}.tar.gz", i))).as_str(),
+                    true,
+                )
+                .unwrap()
+                .lines()
+                .map(|l| l.to_string())
+                .collect()
+            })
+            .collect()
+    }
+
+    /// Output of `du` on `bootstrap` in the work dir, as `(compressed, uncompressed, path)`.
+    pub fn du(&mut self, bootstrap: &str) -> Vec<(u64, u64, String)> {
+        let output = exec(
+            format!(
+                "{:?} du {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap();
+        output
+            .lines()
+            .map(|line| {
+                let fields = line.splitn(3, '\t').collect::<Vec<_>>();
+                (
+                    fields[0].parse().unwrap(),
+                    fields[1].parse().unwrap(),
+                    fields[2].to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// Output of `bench` on `bootstrap` in the work dir, with rafs configuration `config.json`.
+    pub fn bench(&mut self, bootstrap: &str) -> String {
+        exec(
+            format!(
+                "{:?} bench {:?} --config {:?} --thread-num 2 --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                self.work_dir.join("config.json"),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap()
+    }
+
+    /// Output of `stat` comparing `bootstrap` with the tar archive `source` in the work dir.
+    pub fn stat(&mut self, bootstrap: &str, source: &str) -> String {
+        exec(
+            format!(
+                "{:?} stat {:?} --source {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                self.work_dir.join(source),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap()
+    }
+
+    /// Lines of `dedup` on `bootstraps` in the work dir, the header excluded.
+    pub fn dedup(&mut self, bootstraps: &[&str]) -> Vec<String> {
+        let bootstraps = bootstraps
+            .iter()
+            .map(|b| format!("{:?}", self.work_dir.join(b)))
+            .collect::<Vec<_>>();
+        let output = exec(
+            format!(
+                "{:?} dedup {} --log-level info",
+                self.builder,
+                bootstraps.join(" "),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap();
+        output.lines().skip(1).map(|l| l.to_string()).collect()
+    }
+
+    /// Output of `ls` on `path` of `bootstrap` in the work dir.
+    pub fn ls(&mut self, bootstrap: &str, path: &str) -> String {
+        exec(
+            format!(
+                "{:?} ls {:?} {:?} --long --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                path,
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap()
+    }
+
+    /// Content of `path` of `bootstrap` in the work dir, read from blobs of the blob dir.
+    pub fn cat(&mut self, bootstrap: &str, path: &str) -> String {
+        exec(
+            format!(
+                "{:?} cat {:?} {:?} --blob-dir {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                path,
+                self.work_dir.join("blobs"),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap()
+    }
+
+    /// List changes from bootstrap `old` to `new` in the work dir, one per line.
+    pub fn diff(&mut self, old: &str, new: &str) -> String {
+        exec(
+            format!(
+                "{:?} diff {:?} {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(old),
+                self.work_dir.join(new),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap()
+    }
+
+    /// Upgrade bootstrap `bootstrap` in the work dir to `output` in the work dir.
+    pub fn upgrade_bootstrap(&mut self, bootstrap: &str, output: &str) -> String {
+        exec(
+            format!(
+                "{:?} upgrade-bootstrap {:?} --output {:?} --log-level info",
+                self.builder,
+                self.work_dir.join(bootstrap),
+                self.work_dir.join(output),
+            )
+            .as_str(),
+            true,
+        )
+        .unwrap()
+    }
+
     pub fn build_stargz_lower(&mut self) {
         exec(
             format!(
@@ -7,11 +7,15 @@ extern crate log;
 mod builder;
 mod nydusd;
 
-use std::path::PathBuf;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 
 use vmm_sys_util::tempdir::TempDir;
 
 use nydus_utils::{exec, setup_logging};
+use rafs::metadata::{RafsMode, RafsSuper};
+use rafs::RafsIoReader;
 
 const COMPAT_BOOTSTRAPS: [&str; 2] = [
     "blake3-lz4_block-non_repeatable",
@@ -35,11 +39,41 @@ fn check_compact<'a>(
     );
 
     nydusd.start(Some(bootstrap_name), "mnt");
-    let result_path = format!("repeatable/{}.result", bootstrap_name);
+    // Upgraded bootstraps are under a directory, with the same results.
+    let name = Path::new(bootstrap_name).file_name().unwrap();
+    let result_path = format!("repeatable/{}.result", name.to_string_lossy());
     nydusd.check(result_path.as_str(), "mnt");
     nydusd.umount("mnt");
 }
 
+/// Create a work dir under `TEST_WORKDIR_PREFIX`.
+fn new_work_dir() -> TempDir {
+    // If the smoke test run in container based on overlayfs storage driver,
+    // the test will failed because we can't call `mknod` to create char device file.
+    // So please provide the env `TEST_WORKDIR_PREFIX` to specify a host path, allow
+    // `mknod` to create char device file in the non-overlayfs filesystem.
+    let tmp_dir_prefix =
+        std::env::var("TEST_WORKDIR_PREFIX").expect("Please specify `TEST_WORKDIR_PREFIX` env");
+    let path = if tmp_dir_prefix.ends_with('/') {
+        tmp_dir_prefix
+    } else {
+        format!("{}/", tmp_dir_prefix)
+    };
+    TempDir::new_with_prefix(path).unwrap()
+}
+
+/// Build the lower image, and the overlay image on top of it if `upper`.
+fn build_images(work_dir: &PathBuf, upper: bool) -> builder::Builder<'_> {
+    let mut builder = builder::new(work_dir, "oci");
+    builder.make_lower();
+    builder.build_lower("lz4_block");
+    if upper {
+        builder.make_upper();
+        builder.build_upper("lz4_block");
+    }
+    builder
+}
+
 fn test(
     compressor: &str,
     enable_cache: bool,
@@ -54,20 +88,7 @@ fn test(
         compressor, enable_cache, cache_compressed, rafs_mode
     );
 
-    // If the smoke test run in container based on overlayfs storage driver,
-    // the test will failed because we can't call `mknod` to create char device file.
-    // So please provide the env `TEST_WORKDIR_PREFIX` to specify a host path, allow
-    // `mknod` to create char device file in the non-overlayfs filesystem.
-    let tmp_dir_prefix =
-        std::env::var("TEST_WORKDIR_PREFIX").expect("Please specify `TEST_WORKDIR_PREFIX` env");
-    let tmp_dir = {
-        let path = if tmp_dir_prefix.ends_with('/') {
-            tmp_dir_prefix
-        } else {
-            format!("{}/", tmp_dir_prefix)
-        };
-        TempDir::new_with_prefix(path).unwrap()
-    };
+    let tmp_dir = new_work_dir();
     let work_dir = tmp_dir.as_path().to_path_buf();
     let lower_texture = "directory/lower.result".to_string();
     let overlay_texture = "directory/overlay.result".to_string();
@@ -190,8 +211,19 @@ fn integration_test_compact() {
         false,
     );
 
+    // Bootstraps upgraded to the latest layout work the same way.
+    std::fs::create_dir_all(work_dir.join("upgraded")).unwrap();
+    let mut builder = builder::new(&work_dir, "oci");
+    let mut bootstraps = Vec::new();
+    for bs in COMPAT_BOOTSTRAPS.iter() {
+        let upgraded = format!("upgraded/{}", bs);
+        builder.upgrade_bootstrap(bs, &upgraded);
+        bootstraps.push(bs.to_string());
+        bootstraps.push(upgraded);
+    }
+
     for mode in vec!["direct", "cached"].iter() {
-        for bs in COMPAT_BOOTSTRAPS.iter() {
+        for bs in bootstraps.iter() {
             check_compact(&work_dir, false, bs, mode, false);
             check_compact(&work_dir, false, bs, mode, true);
             check_compact(&work_dir, true, bs, mode, false);
@@ -200,6 +232,47 @@ fn integration_test_compact() {
     }
 }
 
+#[test]
+fn integration_test_mtime() {
+    info!("\n\n==================== testing run: mtime test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+
+    let mut builder = builder::new(&work_dir, "oci");
+    builder.make_lower();
+    let file = work_dir.join("lower/sub/sub-1");
+    exec(
+        format!("touch -m -d @1600000000.5 {:?}", file).as_str(),
+        false,
+    )
+    .unwrap();
+    let meta = std::fs::symlink_metadata(&file).unwrap();
+    builder.build_mtime("bootstrap-mtime", true);
+    builder.build_mtime("bootstrap-no-mtime", false);
+
+    for (bootstrap, has_mtime) in &[("bootstrap-mtime", true), ("bootstrap-no-mtime", false)] {
+        for mode in &[RafsMode::Direct, RafsMode::Cached] {
+            let mut r: RafsIoReader = Box::new(File::open(work_dir.join(bootstrap)).unwrap());
+            let mut rs = RafsSuper {
+                mode: mode.clone(),
+                ..Default::default()
+            };
+            rs.load(&mut r).unwrap();
+            assert_eq!(rs.meta.has_mtime(), *has_mtime);
+
+            let ino = rs.ino_from_path(Path::new("/sub/sub-1")).unwrap();
+            let attr = rs.get_inode(ino, false).unwrap().get_attr();
+            if *has_mtime {
+                assert_eq!((attr.mtime, attr.mtimensec), (1_600_000_000, 500_000_000));
+                assert_eq!(
+                    (attr.ctime, attr.ctimensec),
+                    (meta.ctime() as u64, meta.ctime_nsec() as u32)
+                );
+            }
+        }
+    }
+}
+
 #[test]
 fn integration_test_special_files() {
     info!("\n\n==================== testing run: special file test");
@@ -256,3 +329,183 @@ fn integration_test_stargz() {
     nydusd.check("directory/overlay.result", "mnt");
     nydusd.umount("mnt");
 }
+
+#[test]
+fn integration_test_unpack() {
+    info!("\n\n==================== testing run: unpack test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, true);
+
+    builder.unpack("bootstrap-lower", "lower");
+    let xattrs = exec(
+        format!(
+            "getfattr -d -m user. {:?}",
+            work_dir.join("bootstrap-lower-unpacked/sub/sub-1")
+        )
+        .as_str(),
+        true,
+    )
+    .unwrap();
+    assert!(xattrs.contains("user.key-foo=\"value-foo\""));
+
+    // Overlay image has blobs of both layers in the blob dir.
+    let nydusd = nydusd::new(
+        &work_dir,
+        false,
+        false,
+        "direct".parse().unwrap(),
+        "api.sock".into(),
+        false,
+    );
+    nydusd.start(Some("bootstrap-overlay"), "mnt");
+    builder.unpack("bootstrap-overlay", "mnt");
+    nydusd.umount("mnt");
+}
+
+#[test]
+fn integration_test_verify() {
+    info!("\n\n==================== testing run: verify test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, false);
+
+    builder.verify("bootstrap-lower");
+}
+
+#[test]
+fn integration_test_cat() {
+    info!("\n\n==================== testing run: cat test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, false);
+
+    assert_eq!(builder.cat("bootstrap-lower", "/sub/sub-1"), "lower:sub-1");
+}
+
+#[test]
+fn integration_test_ls() {
+    info!("\n\n==================== testing run: ls test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, false);
+
+    let entries = builder.ls("bootstrap-lower", "/sub");
+    assert!(entries
+        .lines()
+        .any(|l| l.starts_with("-rw") && l.ends_with(" sub-1")));
+}
+
+#[test]
+fn integration_test_du() {
+    info!("\n\n==================== testing run: du test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, false);
+
+    // Each chunk is counted once, so the root takes at most the whole blob.
+    let usages = builder.du("bootstrap-lower");
+    assert_eq!(usages[0].2, "/");
+    let blob_size = std::fs::read_dir(work_dir.join("blobs"))
+        .unwrap()
+        .map(|e| e.unwrap().metadata().unwrap().len())
+        .sum::<u64>();
+    assert!(usages[0].0 > 0 && usages[0].0 <= blob_size);
+    assert!(usages.iter().any(|u| u.2 == "/sub"));
+}
+
+#[test]
+fn integration_test_stat() {
+    info!("\n\n==================== testing run: stat test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, false);
+
+    // The unpacked tar is taken as the source layer, uncompressed.
+    builder.unpack("bootstrap-lower", "lower");
+    let tar_size = std::fs::metadata(work_dir.join("bootstrap-lower.tar"))
+        .unwrap()
+        .len();
+    let stat = builder.stat("bootstrap-lower", "bootstrap-lower.tar");
+    assert!(stat.contains(&format!(
+        "\nsource: 1 layers, {} bytes, {} bytes uncompressed\n",
+        tar_size, tar_size
+    )));
+    assert!(stat.contains("\nsize delta: "));
+}
+
+#[test]
+fn integration_test_bench() {
+    info!("\n\n==================== testing run: bench test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, false);
+
+    for enable_cache in &[false, true] {
+        // Only to write the config file taken by bench.
+        nydusd::new(
+            &work_dir,
+            *enable_cache,
+            false,
+            "direct".parse().unwrap(),
+            "api.sock".into(),
+            true,
+        );
+        let rounds = builder.bench("bootstrap-lower");
+        let rounds = rounds.lines().collect::<Vec<_>>();
+        assert!(rounds[0].starts_with("round 1: ") && rounds[0].contains(", 0 errors, "));
+        if *enable_cache {
+            // All chunks are cached in the first round.
+            let round_2 = rounds
+                .iter()
+                .position(|l| l.starts_with("round 2: "))
+                .unwrap();
+            assert!(rounds[round_2..].contains(&"  backend: 0 reads, 0 bytes, latency avg 0us"));
+        }
+    }
+}
+
+#[test]
+fn integration_test_export() {
+    info!("\n\n==================== testing run: export test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, true);
+
+    let layers = builder.export(&["bootstrap-lower", "bootstrap-overlay"]);
+    assert!(layers[0].contains(&"root-large".to_string()));
+    assert!(layers[1].contains(&".wh.root-large".to_string()));
+    assert!(layers[1].contains(&"sub/sub-1".to_string()));
+    assert!(!layers[1].contains(&"root-1".to_string()));
+}
+
+#[test]
+fn integration_test_diff() {
+    info!("\n\n==================== testing run: diff test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, true);
+
+    let changes = builder.diff("bootstrap-lower", "bootstrap-overlay");
+    let changes = changes.lines().collect::<Vec<_>>();
+    assert!(changes.contains(&"D\t/root-large"));
+    assert!(changes
+        .iter()
+        .any(|c| c.starts_with("M\t/sub/sub-1\t") && c.contains("content")));
+}
+
+#[test]
+fn integration_test_dedup() {
+    info!("\n\n==================== testing run: dedup test");
+    let tmp_dir = new_work_dir();
+    let work_dir = tmp_dir.as_path().to_path_buf();
+    let mut builder = build_images(&work_dir, true);
+
+    // Files kept in the overlay share their chunks with the lower image.
+    let dedup = builder.dedup(&["bootstrap-lower", "bootstrap-overlay"]);
+    let shared = dedup[0].split('\t').nth(2).unwrap();
+    assert!(shared.parse::<u64>().unwrap() > 0);
+    // With two images, chunks shared by the first are all those shared in total.
+    assert!(dedup[2].ends_with("\ttotal"));
+    assert_eq!(dedup[2].split('\t').nth(2), Some(shared));
+}
@@ -1,3 +1,6 @@
 fn main() {
-    built::write_built_file().expect("Failed to acquire build-time information")
+    built::write_built_file().expect("Failed to acquire build-time information");
+
+    println!("cargo:rerun-if-changed=src/probe.c");
+    cc::Build::new().file("src/probe.c").compile("nydus_probe");
 }
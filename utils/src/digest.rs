@@ -39,6 +39,38 @@ impl FromStr for Algorithm {
     }
 }
 
+/// CPU features used to accelerate digest computation, detected at runtime.
+///
+/// sha2 takes SHA-NI on x86 on its own, and blake3 takes the widest x86 SIMD instructions
+/// available. Digests are computed in software without any of them, and always on aarch64,
+/// where blake3 only uses NEON if built with its `neon` feature, and sha2 has no hardware
+/// backend.
+pub fn hw_acceleration() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut features = Vec::new();
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        // Same as what sha2 checks before taking SHA-NI.
+        if is_x86_feature_detected!("sha")
+            && is_x86_feature_detected!("sse2")
+            && is_x86_feature_detected!("ssse3")
+            && is_x86_feature_detected!("sse4.1")
+        {
+            features.push("sha-ni");
+        }
+        if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vl") {
+            features.push("avx512");
+        } else if is_x86_feature_detected!("avx2") {
+            features.push("avx2");
+        } else if is_x86_feature_detected!("sse4.1") {
+            features.push("sse4.1");
+        }
+    }
+
+    features
+}
+
 pub trait DigestHasher {
     fn digest_update(&mut self, buf: &[u8]);
     fn digest_finalize(&mut self) -> RafsDigest;
@@ -126,3 +158,44 @@ impl Into<String> for RafsDigest {
         format!("{}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Known answers guard accelerated code paths, which are picked by the CPU running tests.
+    #[test]
+    fn test_digest_known_answers() {
+        let data = vec![0xa5u8; 0x100000 + 13];
+        let cases = [
+            (
+                Algorithm::Sha256,
+                b"abc".as_ref(),
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                Algorithm::Blake3,
+                b"abc".as_ref(),
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+        ];
+        for (algorithm, input, expected) in cases.iter() {
+            assert_eq!(
+                RafsDigest::from_buf(input, *algorithm).to_string(),
+                *expected
+            );
+        }
+
+        // Incremental hashing in pieces of odd sizes matches the one-shot digest.
+        for algorithm in &[Algorithm::Sha256, Algorithm::Blake3] {
+            let mut hasher = RafsDigest::hasher(*algorithm);
+            for piece in data.chunks(4097) {
+                hasher.digest_update(piece);
+            }
+            assert_eq!(
+                hasher.digest_finalize(),
+                RafsDigest::from_buf(&data, *algorithm)
+            );
+        }
+    }
+}
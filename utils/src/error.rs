@@ -60,6 +60,7 @@ define_libc_error_macro!(ealready, EALREADY);
 define_libc_error_macro!(enosys, ENOSYS);
 define_libc_error_macro!(epipe, EPIPE);
 define_libc_error_macro!(eio, EIO);
+define_libc_error_macro!(ebusy, EBUSY);
 
 // Add more custom error macro here if necessary
 define_error_macro!(last_error, std::io::Error::last_os_error());
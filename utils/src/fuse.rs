@@ -4,7 +4,7 @@
 
 use std::cell::RefCell;
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::io::{self, Read};
 use std::ops::Deref;
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
@@ -14,7 +14,7 @@ use libc::{c_int, sysconf, _SC_PAGESIZE};
 use nix::errno::Errno;
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::poll::{poll, PollFd, PollFlags};
-use nix::unistd::{close, dup, getgid, getuid, read};
+use nix::unistd::{close, dup, getgid, getuid, pipe2, read, write};
 use nix::Error as nixError;
 
 use epoll::{ControlOptions, Event, Events};
@@ -30,6 +30,10 @@ const FUSE_HEADER_SIZE: usize = 0x1000;
 const FUSE_DEVICE: &str = "/dev/fuse";
 const FUSE_FSTYPE: &str = "fuse";
 
+const FUSE_IN_HEADER_SIZE: usize = 40;
+/// Flag of INIT telling that `max_pages` of the reply is valid.
+const FUSE_MAX_PAGES: u32 = 1 << 22;
+
 /// A fuse session representation
 pub struct FuseSession {
     mountpoint: PathBuf,
@@ -37,6 +41,7 @@ pub struct FuseSession {
     subtype: String,
     file: Option<File>,
     bufsize: usize,
+    max_read: usize,
 }
 
 const EXIT_FUSE_SERVICE: u64 = 1;
@@ -54,14 +59,41 @@ impl FuseSession {
             subtype: subtype.to_owned(),
             file: None,
             bufsize: FUSE_KERN_BUF_SIZE * pagesize() + FUSE_HEADER_SIZE,
+            max_read: FUSE_KERN_BUF_SIZE * pagesize(),
         })
     }
 
+    /// Set the max size of fuse read and write requests, a multiple of page size which fits
+    /// in request buffers. It takes effect on mount.
+    pub fn set_max_read(&mut self, size: usize) -> io::Result<()> {
+        let page = pagesize();
+        if size == 0 || size % page != 0 || size > FUSE_KERN_BUF_SIZE * page {
+            return Err(einval!(format!(
+                "max read size should be a multiple of {} up to {}",
+                page,
+                FUSE_KERN_BUF_SIZE * page
+            )));
+        }
+        self.max_read = size;
+        Ok(())
+    }
+
+    /// Max number of pages of a fuse request, to negotiate on INIT.
+    pub fn max_pages(&self) -> u16 {
+        (self.max_read / pagesize()) as u16
+    }
+
     pub fn mount(&mut self) -> io::Result<()> {
         let flags =
             MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOATIME | MsFlags::MS_RDONLY;
 
-        let file = fuse_kern_mount(&self.mountpoint, &self.fsname, &self.subtype, flags)?;
+        let file = fuse_kern_mount(
+            &self.mountpoint,
+            &self.fsname,
+            &self.subtype,
+            flags,
+            self.max_read,
+        )?;
         fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(|e| einval!(e))?;
         self.file = Some(file);
 
@@ -159,6 +191,16 @@ impl FuseChannel {
     }
 
     pub fn get_reader<'b>(&self, buf: &'b mut Vec<u8>) -> io::Result<Option<Reader<'b>>> {
+        match self.read_message(buf.as_mut_slice())? {
+            Some(len) => Ok(Some(
+                Reader::new(FuseBuf::new(&mut buf[..len])).map_err(|e| eother!(e))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a fuse request into `buf`, returns its length, or None if the session is over.
+    pub fn read_message(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
         loop {
             let num_events = epoll::wait(self.epoll_fd, -1, &mut self.events.borrow_mut())?;
 
@@ -183,13 +225,8 @@ impl FuseChannel {
                             return Ok(None);
                         }
 
-                        match read(self.fd, buf.as_mut_slice()) {
-                            Ok(len) => {
-                                return Ok(Some(
-                                    Reader::new(FuseBuf::new(&mut buf[..len]))
-                                        .map_err(|e| eother!(e))?,
-                                ));
-                            }
+                        match read(self.fd, buf) {
+                            Ok(len) => return Ok(Some(len)),
                             Err(nixError::Sys(e)) => match e {
                                 Errno::ENOENT => {
                                     // ENOENT means the operation was interrupted, it's safe
@@ -230,6 +267,193 @@ impl FuseChannel {
     pub fn get_writer(&self) -> io::Result<Writer> {
         Ok(Writer::new(self.fd, self.bufsize).unwrap())
     }
+
+    /// Reply INIT request `msg` by `handler`, raising the max size of requests to `max_pages`
+    /// in the reply if the kernel supports it.
+    ///
+    /// Otherwise the kernel splits reads and writes at its default of 32 pages, however large
+    /// request buffers are. The handler replies into a pipe, from which the reply is patched
+    /// and written to the channel.
+    pub fn reply_init<F>(&self, msg: &mut [u8], max_pages: u16, handler: F) -> io::Result<()>
+    where
+        F: FnOnce(Reader, Writer) -> io::Result<()>,
+    {
+        let mut flags = [0u8; 4];
+        if let Some(f) = msg.get(FUSE_IN_HEADER_SIZE + 12..FUSE_IN_HEADER_SIZE + 16) {
+            flags.copy_from_slice(f);
+        }
+        let kernel_flags = u32::from_ne_bytes(flags);
+
+        let (rfd, wfd) = pipe2(OFlag::O_CLOEXEC).map_err(|e| eother!(e))?;
+        // Safe because both fds are just created and owned here.
+        let (mut pipe_read, pipe_write) =
+            unsafe { (File::from_raw_fd(rfd), File::from_raw_fd(wfd)) };
+        let reader = Reader::new(FuseBuf::new(msg)).map_err(|e| eother!(e))?;
+        let writer = Writer::new(pipe_write.as_raw_fd(), self.bufsize).map_err(|e| eother!(e))?;
+        handler(reader, writer)?;
+        drop(pipe_write);
+
+        let mut reply = Vec::new();
+        pipe_read.read_to_end(&mut reply)?;
+        if kernel_flags & FUSE_MAX_PAGES != 0 && !patch_init_reply(&mut reply, max_pages) {
+            warn!("can't raise max pages of fuse requests in INIT reply");
+        }
+        let n = write(self.fd, &reply).map_err(|e| eother!(e))?;
+        if n != reply.len() {
+            return Err(eio!("short write of INIT reply"));
+        }
+
+        Ok(())
+    }
+
+    /// Create a pipe large enough to hold a reply of the max size to splice into the channel.
+    pub fn new_splice_pipe(&self) -> io::Result<SplicePipe> {
+        SplicePipe::new(self.bufsize)
+    }
+
+    /// Reply with `len` bytes in `pipe`, a header followed by data, without copying data
+    /// through userspace.
+    pub fn splice_reply(&self, pipe: &SplicePipe, len: usize) -> io::Result<()> {
+        let ret = unsafe {
+            libc::splice(
+                pipe.read.as_raw_fd(),
+                std::ptr::null_mut(),
+                self.fd,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if ret < 0 {
+            return Err(last_error!());
+        }
+        // The kernel takes a reply as a whole.
+        if ret as usize != len {
+            return Err(eio!("short splice to fuse device"));
+        }
+
+        Ok(())
+    }
+
+    /// Reply to request `unique` with data gathered from `bufs` by the kernel, which saves
+    /// copying them into the reply buffer. At most 1023 buffers are taken by `writev()`.
+    pub fn reply_vectored(&self, unique: u64, bufs: &[&[u8]]) -> io::Result<()> {
+        write_reply_vectored(self.fd, unique, bufs)
+    }
+}
+
+/// Size of the header of a fuse reply.
+pub const FUSE_OUT_HEADER_SIZE: usize = 16;
+
+/// Header of a successful reply to request `unique` with `size` bytes of data.
+fn reply_header(unique: u64, size: usize) -> [u8; FUSE_OUT_HEADER_SIZE] {
+    let mut header = [0u8; FUSE_OUT_HEADER_SIZE];
+    header[0..4].copy_from_slice(&((FUSE_OUT_HEADER_SIZE + size) as u32).to_ne_bytes());
+    // error is 0
+    header[8..16].copy_from_slice(&unique.to_ne_bytes());
+    header
+}
+
+/// Write a successful reply to request `unique` with data in `bufs` to `fd` in one shot.
+fn write_reply_vectored(fd: RawFd, unique: u64, bufs: &[&[u8]]) -> io::Result<()> {
+    let size = bufs.iter().map(|b| b.len()).sum();
+    let header = reply_header(unique, size);
+    let iovecs = std::iter::once(&header[..])
+        .chain(bufs.iter().copied())
+        .map(|b| libc::iovec {
+            iov_base: b.as_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        })
+        .collect::<Vec<_>>();
+    let ret = unsafe { libc::writev(fd, iovecs.as_ptr(), iovecs.len() as c_int) };
+    if ret < 0 {
+        return Err(last_error!());
+    }
+    // The kernel takes a reply as a whole.
+    if ret as usize != FUSE_OUT_HEADER_SIZE + size {
+        return Err(eio!("short write to fuse device"));
+    }
+
+    Ok(())
+}
+
+/// Set `max_pages` of a successful INIT reply, and `max_write` to match. Return false if it's
+/// not one or too short to have `max_pages`, of a protocol older than 7.28.
+fn patch_init_reply(reply: &mut [u8], max_pages: u16) -> bool {
+    if reply.len() < FUSE_OUT_HEADER_SIZE + 30 {
+        return false;
+    }
+    let mut error = [0u8; 4];
+    error.copy_from_slice(&reply[4..8]);
+    if i32::from_ne_bytes(error) != 0 {
+        return false;
+    }
+
+    // `struct fuse_init_out`: flags at 12, max_write at 20 and max_pages at 28.
+    let out = &mut reply[FUSE_OUT_HEADER_SIZE..];
+    let mut flags = [0u8; 4];
+    flags.copy_from_slice(&out[12..16]);
+    let flags = u32::from_ne_bytes(flags) | FUSE_MAX_PAGES;
+    out[12..16].copy_from_slice(&flags.to_ne_bytes());
+    let max_write = max_pages as u32 * pagesize() as u32;
+    out[20..24].copy_from_slice(&max_write.to_ne_bytes());
+    out[28..30].copy_from_slice(&max_pages.to_ne_bytes());
+
+    true
+}
+
+/// Pipe to build a fuse reply in, whose data is spliced from files by reference to page cache.
+pub struct SplicePipe {
+    read: File,
+    write: File,
+}
+
+impl SplicePipe {
+    fn new(size: usize) -> io::Result<Self> {
+        let mut fds = [0 as c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(last_error!());
+        }
+        // Safe because both fds are just created and owned here.
+        let pipe = unsafe {
+            SplicePipe {
+                read: File::from_raw_fd(fds[0]),
+                write: File::from_raw_fd(fds[1]),
+            }
+        };
+        // Splicing data in fails instead of blocking once the pipe is full, as nobody else
+        // drains it, so it must be able to hold a whole reply.
+        if unsafe { libc::fcntl(fds[1], libc::F_SETPIPE_SZ, size as c_int) } < 0 {
+            return Err(last_error!());
+        }
+
+        Ok(pipe)
+    }
+
+    /// Fd to splice data into.
+    pub fn fd(&self) -> RawFd {
+        self.write.as_raw_fd()
+    }
+
+    /// Put the header of a successful reply to request `unique` with `size` bytes of data.
+    pub fn write_header(&self, unique: u64, size: usize) -> io::Result<()> {
+        let header = reply_header(unique, size);
+        let n = write(self.fd(), &header).map_err(|e| eother!(e))?;
+        if n != header.len() {
+            return Err(eio!("short write to pipe"));
+        }
+        Ok(())
+    }
+
+    /// Drop whatever left in the pipe by a failed reply.
+    pub fn clear(&self) {
+        let mut buf = [0u8; 0x10000];
+        while let Ok(n) = read(self.read.as_raw_fd(), &mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
 }
 
 impl Drop for FuseChannel {
@@ -251,6 +475,7 @@ fn fuse_kern_mount(
     fsname: &str,
     subtype: &str,
     flags: MsFlags,
+    max_read: usize,
 ) -> io::Result<File> {
     let file = OpenOptions::new()
         .create(false)
@@ -259,11 +484,12 @@ fn fuse_kern_mount(
         .open(FUSE_DEVICE)?;
     let meta = mountpoint.metadata()?;
     let opts = format!(
-        "default_permissions,allow_other,fd={},rootmode={:o},user_id={},group_id={}",
+        "default_permissions,allow_other,fd={},rootmode={:o},user_id={},group_id={},max_read={}",
         file.as_raw_fd(),
         meta.permissions().mode() & libc::S_IFMT,
         getuid(),
         getgid(),
+        max_read,
     );
     let mut fstype = String::from(FUSE_FSTYPE);
     if !subtype.is_empty() {
@@ -311,3 +537,54 @@ fn fuse_kern_umount(mountpoint: &str, file: File) -> io::Result<()> {
 
     umount2(mountpoint, MntFlags::MNT_DETACH).map_err(|e| eother!(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_init_reply() {
+        let page = pagesize() as u32;
+        // Out header followed by `struct fuse_init_out` of 64 bytes.
+        let mut reply = vec![0u8; FUSE_OUT_HEADER_SIZE + 64];
+        reply[0..4].copy_from_slice(&(reply.len() as u32).to_ne_bytes());
+        let out = FUSE_OUT_HEADER_SIZE;
+        reply[out + 12..out + 16].copy_from_slice(&0x1u32.to_ne_bytes());
+        reply[out + 20..out + 24].copy_from_slice(&(32 * page).to_ne_bytes());
+
+        assert!(patch_init_reply(&mut reply, 256));
+        let word = |r: &[u8], at: usize| {
+            let mut w = [0u8; 4];
+            w.copy_from_slice(&r[out + at..out + at + 4]);
+            u32::from_ne_bytes(w)
+        };
+        assert_eq!(word(&reply, 12), 0x1 | FUSE_MAX_PAGES);
+        assert_eq!(word(&reply, 20), 256 * page);
+        assert_eq!(word(&reply, 28) & 0xffff, 256);
+
+        // Failed or old replies are left alone.
+        let mut failed = reply.clone();
+        failed[4..8].copy_from_slice(&(-libc::EIO).to_ne_bytes());
+        assert!(!patch_init_reply(&mut failed, 128));
+        let mut old = reply[..FUSE_OUT_HEADER_SIZE + 24].to_vec();
+        assert!(!patch_init_reply(&mut old, 128));
+    }
+
+    #[test]
+    fn test_write_reply_vectored() {
+        let (rfd, wfd) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let (mut pipe_read, pipe_write) =
+            unsafe { (File::from_raw_fd(rfd), File::from_raw_fd(wfd)) };
+        let chunks = [vec![1u8; 0x1000], vec![2u8; 0x1000], vec![3u8; 0x1000]];
+        let bufs = [&chunks[0][0x800..], &chunks[1][..], &chunks[2][..0x10]];
+        write_reply_vectored(pipe_write.as_raw_fd(), 7, &bufs).unwrap();
+        drop(pipe_write);
+
+        let mut reply = Vec::new();
+        pipe_read.read_to_end(&mut reply).unwrap();
+        let size = 0x800 + 0x1000 + 0x10;
+        assert_eq!(reply.len(), FUSE_OUT_HEADER_SIZE + size);
+        assert_eq!(&reply[..FUSE_OUT_HEADER_SIZE], &reply_header(7, size)[..]);
+        assert_eq!(&reply[FUSE_OUT_HEADER_SIZE..], &bufs.concat()[..]);
+    }
+}
@@ -2,21 +2,26 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::convert::{From, Infallible, Into, TryInto};
 use std::env::current_dir;
 use std::fmt::{Debug, Display, Formatter};
-use std::io::Result;
+use std::io::{Result, Write};
 use std::ops::{Add, BitAnd, Mul, Not, Sub};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::RwLock;
-
-use flexi_logger::{self, colored_opt_format, opt_format, Logger};
-use log::LevelFilter;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use flexi_logger::{
+    self, colored_opt_format, opt_format, DeferredNow, LogSpecification, LogTarget, Logger,
+    LoggerHandle,
+};
+use log::{LevelFilter, Record};
 use num_traits::CheckedAdd;
 use serde::Serialize;
 
+use crate::syslog::{SystemLog, SystemLogWriter};
+
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -39,13 +44,17 @@ pub use types::*;
 pub mod fuse;
 
 #[cfg(feature = "fusedev")]
-pub use self::fuse::{FuseChannel, FuseSession};
+pub use self::fuse::{FuseChannel, FuseSession, SplicePipe, FUSE_OUT_HEADER_SIZE};
 
 pub mod digest;
 pub mod logger;
 
 pub mod metrics;
+pub mod probe;
+pub mod request_id;
 pub mod signal;
+pub mod syslog;
+pub mod trace;
 
 pub fn log_level_to_verbosity(level: log::LevelFilter) -> usize {
     level as usize - 1
@@ -97,6 +106,12 @@ pub fn dump_program_info(prog_version: &str) {
         built_info::PROFILE,
         built_info::RUSTC_VERSION,
     );
+    let features = digest::hw_acceleration();
+    if features.is_empty() {
+        info!("Digest acceleration: none");
+    } else {
+        info!("Digest acceleration: {}", features.join(", "));
+    }
 }
 
 #[derive(Serialize, Clone)]
@@ -147,7 +162,7 @@ pub fn setup_logging(log_file_path: Option<PathBuf>, level: LevelFilter) -> Resu
             .log_to_file()
             .suppress_timestamp()
             .append()
-            .format(opt_format);
+            .format(keep_opt_format);
 
         // Parse log file to get the `basename` and `suffix`(extension) because `flexi_logger`
         // will automatically add `.log` suffix if we don't set explicitly, see:
@@ -186,24 +201,192 @@ pub fn setup_logging(log_file_path: Option<PathBuf>, level: LevelFilter) -> Resu
             logger = logger.directory(dir);
         }
 
-        logger.start().map_err(|e| {
+        let handle = logger.start().map_err(|e| {
             eprintln!("{:?}", e);
             eother!(e)
         })?;
+        *LOGGER_HANDLE.lock().unwrap() = Some(handle);
     } else {
         // We rely on rust `log` macro to limit current log level rather than `flexi_logger`
         // So we set `flexi_logger` log level to "trace" which is High enough. Otherwise, we
         // can't change log level to a higher level than what is passed to `flexi_logger`.
-        Logger::with_env_or_str("trace")
-            .format(colored_opt_format)
+        let handle = Logger::with_env_or_str("trace")
+            .format(keep_colored_opt_format)
             .start()
             .map_err(|e| eother!(e))?;
+        *LOGGER_HANDLE.lock().unwrap() = Some(handle);
     }
 
+    init_log_filter(level);
+    Ok(())
+}
+
+/// Send log records to syslog or the systemd journal instead of stderr or a file.
+pub fn setup_system_logging(target: SystemLog, level: LevelFilter) -> Result<()> {
+    let writer = SystemLogWriter::new(target).map_err(|e| {
+        eprintln!("{}", e);
+        e
+    })?;
+    // See `setup_logging()` for why flexi_logger is set to "trace".
+    let handle = Logger::with_env_or_str("trace")
+        .log_target(LogTarget::Writer(Box::new(writer)))
+        .start()
+        .map_err(|e| eother!(e))?;
+    *LOGGER_HANDLE.lock().unwrap() = Some(handle);
+
+    init_log_filter(level);
+    Ok(())
+}
+
+fn init_log_filter(level: LevelFilter) {
     log::set_max_level(level);
+    *LOG_FILTER.lock().unwrap() = LogFilter {
+        level,
+        modules: BTreeMap::new(),
+    };
+}
+
+lazy_static! {
+    static ref LOGGER_HANDLE: Mutex<Option<LoggerHandle>> = Mutex::new(None);
+    static ref RECENT_LOGS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+    static ref LOG_FILTER: Mutex<LogFilter> = Mutex::new(LogFilter {
+        level: log::max_level(),
+        modules: BTreeMap::new(),
+    });
+}
+
+/// Log level of the process, with overrides for modules and their submodules, which are
+/// named by module path like `storage::backend`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFilter {
+    pub level: LevelFilter,
+    pub modules: BTreeMap<String, LevelFilter>,
+}
+
+impl LogFilter {
+    /// Log specification of flexi_logger, e.g. `info, storage::backend = trace`.
+    fn spec(&self) -> String {
+        let mut spec = vec![self.level.to_string().to_lowercase()];
+        for (module, level) in self.modules.iter() {
+            spec.push(format!("{} = {}", module, level.to_string().to_lowercase()));
+        }
+        spec.join(", ")
+    }
+
+    /// The most verbose level of all, below which `log` macros are skipped.
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .values()
+            .fold(self.level, |max, l| std::cmp::max(max, *l))
+    }
+}
+
+/// Change log level of the running process, modules not given in `filter` follow its level.
+pub fn set_log_filter(filter: LogFilter) -> Result<()> {
+    let valid_name = |m: &str| {
+        !m.is_empty()
+            && m.split("::")
+                .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+    };
+    if let Some(m) = filter.modules.keys().find(|m| !valid_name(m)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid module name {:?}", m),
+        ));
+    }
+
+    if let Some(handle) = LOGGER_HANDLE.lock().unwrap().as_mut() {
+        let spec = LogSpecification::parse(&filter.spec()).map_err(|e| einval!(e))?;
+        handle.set_new_spec(spec);
+    }
+    log::set_max_level(filter.max_level());
+    *LOG_FILTER.lock().unwrap() = filter;
+
     Ok(())
 }
 
+/// Get log level of the running process set by `setup_logging()` or `set_log_filter()`.
+pub fn log_filter() -> LogFilter {
+    LOG_FILTER.lock().unwrap().clone()
+}
+
+static RECENT_LOGS_MAX: AtomicUsize = AtomicUsize::new(0);
+
+/// Keep the last `n` lines logged in memory, to be got by `recent_logs()`.
+pub fn keep_recent_logs(n: usize) {
+    RECENT_LOGS_MAX.store(n, Ordering::Relaxed);
+}
+
+/// Lines logged lately, oldest first. It's empty if the lines are being updated, since it may
+/// be called by a thread panicking in the middle of logging.
+pub fn recent_logs() -> Vec<String> {
+    match RECENT_LOGS.try_lock() {
+        Ok(logs) => logs.iter().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn keep_line(line: String) {
+    let max = RECENT_LOGS_MAX.load(Ordering::Relaxed);
+    let mut logs = RECENT_LOGS.lock().unwrap();
+    logs.push_back(line);
+    while logs.len() > max {
+        logs.pop_front();
+    }
+}
+
+/// Call `f` with `record`, whose message is led by ID of the request being handled if any, so
+/// that lines logged on behalf of a fuse request can be told.
+fn with_request_id<F>(record: &Record, f: F) -> Result<()>
+where
+    F: FnOnce(&Record) -> Result<()>,
+{
+    match request_id::current() {
+        Some(id) => f(&Record::builder()
+            .args(format_args!("[req {}] {}", id, record.args()))
+            .metadata(record.metadata().clone())
+            .module_path(record.module_path())
+            .file(record.file())
+            .line(record.line())
+            .build()),
+        None => f(record),
+    }
+}
+
+fn keep_opt_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> Result<()> {
+    with_request_id(record, |record| {
+        if RECENT_LOGS_MAX.load(Ordering::Relaxed) == 0 {
+            return opt_format(w, now, record);
+        }
+        let mut line = Vec::new();
+        opt_format(&mut line, now, record)?;
+        w.write_all(&line)?;
+        keep_line(String::from_utf8_lossy(&line).to_string());
+        Ok(())
+    })
+}
+
+/// Keep `record` as a line of `recent_logs()`, for writers formatting records in their own way.
+fn keep_record(now: &mut DeferredNow, record: &Record) -> Result<()> {
+    if RECENT_LOGS_MAX.load(Ordering::Relaxed) != 0 {
+        let mut line = Vec::new();
+        opt_format(&mut line, now, record)?;
+        keep_line(String::from_utf8_lossy(&line).to_string());
+    }
+    Ok(())
+}
+
+fn keep_colored_opt_format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<()> {
+    with_request_id(record, |record| {
+        keep_record(now, record)?;
+        colored_opt_format(w, now, record)
+    })
+}
+
 pub struct InodeBitmap {
     map: RwLock<BTreeMap<u64, AtomicU64>>,
 }
@@ -346,6 +529,41 @@ impl InodeBitmap {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_log_filter() {
+        let mut filter = LogFilter {
+            level: LevelFilter::Info,
+            modules: BTreeMap::new(),
+        };
+        assert_eq!(filter.spec(), "info");
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+
+        filter
+            .modules
+            .insert("storage::backend".to_string(), LevelFilter::Trace);
+        filter.modules.insert("rafs".to_string(), LevelFilter::Warn);
+        assert_eq!(filter.spec(), "info, rafs = warn, storage::backend = trace");
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+        assert!(LogSpecification::parse(&filter.spec()).is_ok());
+
+        filter
+            .modules
+            .insert("storage::".to_string(), LevelFilter::Debug);
+        assert!(set_log_filter(filter).is_err());
+    }
+
+    #[test]
+    fn test_recent_logs() {
+        keep_recent_logs(2);
+        for i in 0..3 {
+            keep_line(format!("line {}", i));
+        }
+        assert_eq!(recent_logs(), vec!["line 1", "line 2"]);
+        keep_recent_logs(0);
+        keep_line("line 3".to_string());
+        assert!(recent_logs().is_empty());
+    }
+
     #[test]
     fn test_rounders() {
         assert_eq!(round_down_4k(0), 0);
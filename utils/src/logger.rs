@@ -2,6 +2,7 @@ use serde::Serialize;
 use serde_json::Error as SerdeError;
 use std::collections::VecDeque;
 use std::sync::Mutex;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 #[derive(Debug)]
 pub enum ErrorHolderError {
@@ -67,9 +68,120 @@ impl ErrorHolder {
     }
 }
 
+/// Kind of a daemon event, so that clients can react to events without parsing messages.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// The daemon state machine moved to another state.
+    StateChange,
+    Mount,
+    Umount,
+    Remount,
+    /// A backend read failed after all retries.
+    BackendError,
+    /// Cached data was evicted or purged from blob cache.
+    CacheGc,
+    /// Progress of a live upgrade started by the daemon itself.
+    Restart,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// Increases by one for each event since the daemon started, starting from 1.
+    pub seq: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: i64,
+    pub kind: EventKind,
+    /// Mountpoint or backend id the event is about, empty for daemon wide events.
+    pub id: String,
+    pub message: String,
+}
+
+/// Events following a given sequence number.
+#[derive(Debug, Serialize)]
+pub struct EventBatch {
+    /// Sequence number of the latest event, to be passed as the cursor of the next poll.
+    pub last_seq: u64,
+    /// Number of events following the cursor which were dropped before being polled.
+    pub missed: u64,
+    pub events: Vec<Event>,
+}
+
+/// Keep recent daemon events with sequence numbers, so that clients can poll events
+/// following the last one they have seen, rather than polling daemon status.
+pub struct EventLog {
+    max_events: usize,
+    next_seq: u64,
+    events: VecDeque<Event>,
+    // Signaled on each new event, to wake up pollers.
+    notifier: Option<EventFd>,
+}
+
+impl EventLog {
+    pub fn init(max_events: usize) -> Self {
+        Self {
+            max_events,
+            next_seq: 1,
+            events: VecDeque::with_capacity(max_events),
+            notifier: EventFd::new(EFD_NONBLOCK)
+                .map_err(|e| error!("failed to create event notifier, {}", e))
+                .ok(),
+        }
+    }
+
+    pub fn push(&mut self, kind: EventKind, id: &str, message: String) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if self.events.len() >= self.max_events {
+            self.events.pop_front();
+        }
+        self.events.push_back(Event {
+            seq,
+            timestamp: chrono::Local::now().timestamp(),
+            kind,
+            id: id.to_string(),
+            message,
+        });
+
+        if let Some(n) = self.notifier.as_ref() {
+            n.write(1)
+                .unwrap_or_else(|e| warn!("failed to notify new event, {}", e));
+        }
+
+        seq
+    }
+
+    /// Sequence number of the latest event, 0 if there is no event yet.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Get events following the one numbered `seq`.
+    pub fn since(&self, seq: u64) -> EventBatch {
+        let first = self.events.front().map_or(self.next_seq, |e| e.seq);
+
+        EventBatch {
+            last_seq: self.last_seq(),
+            missed: first.saturating_sub(seq + 1),
+            events: self
+                .events
+                .iter()
+                .filter(|e| e.seq > seq)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Get an eventfd signaled on new events, its counter should be reset by the waiter.
+    pub fn notifier(&self) -> Option<EventFd> {
+        self.notifier.as_ref().and_then(|n| n.try_clone().ok())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{ErrorHolder, ErrorHolderError};
+    use super::{ErrorHolder, ErrorHolderError, EventKind, EventLog};
 
     #[test]
     fn test_overflow() {
@@ -98,4 +210,35 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn test_event_log() {
+        let mut log = EventLog::init(3);
+        assert_eq!(log.last_seq(), 0);
+        assert!(log.since(0).events.is_empty());
+
+        for i in 1..=5 {
+            let seq = log.push(EventKind::Mount, "/mnt", format!("mount {}", i));
+            assert_eq!(seq, i);
+        }
+        assert_eq!(log.last_seq(), 5);
+
+        let batch = log.since(0);
+        assert_eq!(batch.last_seq, 5);
+        assert_eq!(batch.missed, 2);
+        assert_eq!(
+            batch.events.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+
+        let batch = log.since(4);
+        assert_eq!(batch.missed, 0);
+        assert_eq!(batch.events.len(), 1);
+        assert_eq!(batch.events[0].message, "mount 5");
+        assert!(log.since(5).events.is_empty());
+
+        let notifier = log.notifier().unwrap();
+        assert_eq!(notifier.read().unwrap(), 5);
+        assert!(notifier.read().is_err());
+    }
 }
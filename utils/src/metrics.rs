@@ -4,16 +4,19 @@
 //
 // Rafs fop stats accounting and exporting.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ops::{Deref, Drop};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use serde_json::Error as SerdeError;
 
-use crate::logger::ErrorHolder;
+use crate::logger::{ErrorHolder, EventKind, EventLog};
+use crate::probe;
+use crate::request_id::{self, RequestScope};
+use crate::trace::TraceSpan;
 use crate::InodeBitmap;
 
 pub type Inode = u64;
@@ -38,9 +41,30 @@ pub enum StatsFop {
     Max,
 }
 
+/// Names of file operations indexed by `StatsFop`.
+const STATS_FOP_NAMES: [&str; StatsFop::Max as usize] = [
+    "getattr",
+    "readlink",
+    "open",
+    "release",
+    "read",
+    "statfs",
+    "getxattr",
+    "listxattr",
+    "opendir",
+    "lookup",
+    "readdir",
+    "readdirplus",
+    "access",
+    "forget",
+    "batch_forget",
+];
+
 #[derive(Debug)]
 pub enum IoStatsError {
     NoCounter,
+    /// The recorder is not enabled by configuration.
+    Disabled,
     Serialize(SerdeError),
 }
 
@@ -81,6 +105,33 @@ fn request_size_index(size: usize) -> usize {
 /// <=200us, <=500us, <=1ms, <=20ms, <=50ms, <=100ms, <=500ms, >500ms
 const READ_LATENCY_RANGE_MAX: usize = 8;
 
+/// Names of read latency ranges, see `latency_range_index()`.
+const READ_LATENCY_RANGE_NAMES: [&str; READ_LATENCY_RANGE_MAX] = [
+    "<=1ms", "<=20ms", "<=50ms", "<=100ms", "<=500ms", "<=1s", "<=2s", ">2s",
+];
+
+/// Latency buckets of file operations, finer than read latency ranges as most metadata
+/// operations take less than 1ms.
+const FOP_LATENCY_BUCKET_MAX: usize = 16;
+
+/// Upper bounds of latency buckets of file operations in micro-seconds, except the last one.
+const FOP_LATENCY_BOUNDS: [usize; FOP_LATENCY_BUCKET_MAX - 1] = [
+    50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000, 200_000, 500_000,
+    1_000_000, 2_000_000,
+];
+
+const FOP_LATENCY_BUCKET_NAMES: [&str; FOP_LATENCY_BUCKET_MAX] = [
+    "<=50us", "<=100us", "<=200us", "<=500us", "<=1ms", "<=2ms", "<=5ms", "<=10ms", "<=20ms",
+    "<=50ms", "<=100ms", "<=200ms", "<=500ms", "<=1s", "<=2s", ">2s",
+];
+
+fn fop_latency_bucket(elapsed: usize) -> usize {
+    FOP_LATENCY_BOUNDS
+        .iter()
+        .position(|b| elapsed <= *b)
+        .unwrap_or(FOP_LATENCY_BUCKET_MAX - 1)
+}
+
 // Defining below global static metrics set so that a specific metrics counter can
 // be found as per the rafs backend mountpoint/id. Remind that nydusd can have
 // multiple backends mounted.
@@ -102,6 +153,10 @@ lazy_static! {
         Arc::new(Mutex::new(ErrorHolder::init(500, 50 * 1024)));
 }
 
+lazy_static! {
+    pub static ref EVENT_LOG: Mutex<EventLog> = Mutex::new(EventLog::init(1024));
+}
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct GlobalIOStats {
     // Whether to enable each file accounting switch.
@@ -127,7 +182,10 @@ pub struct GlobalIOStats {
     //   * @total means io_stats simply adds every fop latency to the counter which is never cleared.
     //     It is useful for other tools to calculate their metrics report.
     fop_cumulative_latency_total: [AtomicUsize; StatsFop::Max as usize],
-    // Record how many times read latency drops to the ranges.
+    // Histograms of latency of each type of file operation, see `FOP_LATENCY_BOUNDS`.
+    fop_latency_dist: [[AtomicUsize; FOP_LATENCY_BUCKET_MAX]; StatsFop::Max as usize],
+    // Record how many times read latency drops to the ranges, which is the time taken to
+    // read from the storage device, with no accounting of the fop.
     // This helps us to understand the io service time stability.
     read_latency_dist: [AtomicIsize; READ_LATENCY_RANGE_MAX],
     // Total number of files that are currently open.
@@ -141,9 +199,47 @@ pub struct GlobalIOStats {
     file_counters: RwLock<HashMap<Inode, Arc<InodeIOStats>>>,
     #[serde(skip_serializing, skip_deserializing)]
     access_patterns: RwLock<HashMap<Inode, Arc<AccessPattern>>>,
+    // Number of files read so far, to order access patterns by first access.
+    #[serde(skip_serializing, skip_deserializing)]
+    nr_accessed_files: AtomicUsize,
     // record regular file read
     #[serde(skip_serializing, skip_deserializing)]
     recent_read_files: InodeBitmap,
+    // Cold start milestones of the filesystem.
+    #[serde(skip_serializing, skip_deserializing)]
+    mounted: Milestone,
+    #[serde(skip_serializing, skip_deserializing)]
+    first_fop: Milestone,
+    #[serde(skip_serializing, skip_deserializing)]
+    prefetch_done: Milestone,
+    // When a read failed for the last time.
+    #[serde(skip_serializing, skip_deserializing)]
+    last_read_error: Mutex<Option<Instant>>,
+}
+
+/// Time something first happens, recorded only once.
+#[derive(Debug, Default)]
+struct Milestone {
+    reached: AtomicBool,
+    at: Mutex<Option<Instant>>,
+}
+
+impl Milestone {
+    fn mark(&self) {
+        // Checked with a load first, not to contend on the cache line once reached.
+        if !self.reached.load(Ordering::Relaxed) && !self.reached.swap(true, Ordering::AcqRel) {
+            *self.at.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    fn set(&self, at: Instant) {
+        self.reached.store(true, Ordering::Release);
+        *self.at.lock().unwrap() = Some(at);
+    }
+
+    fn at(&self) -> Option<Instant> {
+        *self.at.lock().unwrap()
+    }
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -167,6 +263,7 @@ pub struct InodeIOStats {
 ///        And this counter can not be cleared.
 ///     2. First time point at which this file is read. It's wall-time in unit of seconds.
 ///     3. File path relative to current rafs root.
+///     4. How many bytes are read from this file.
 ///
 /// Yes, we now don't have an abundant pattern recorder now. It can be negotiated in the
 /// future about how to enrich it.
@@ -175,8 +272,12 @@ pub struct InodeIOStats {
 pub struct AccessPattern {
     file_path: PathBuf,
     nr_read: AtomicUsize,
+    nr_bytes: AtomicUsize,
     /// In unit of seconds.
     first_access_time: AtomicUsize,
+    /// Order of the first access among files of the filesystem, starting from 1.
+    #[serde(skip_serializing)]
+    first_access_seq: AtomicUsize,
 }
 
 pub trait InodeStatsCounter {
@@ -220,6 +321,7 @@ pub fn new(id: &str) -> Arc<GlobalIOStats> {
         id: id.to_string(),
         ..Default::default()
     });
+    c.mounted.mark();
     IOS_SET.write().unwrap().insert(id.to_string(), c.clone());
     c.init();
     c
@@ -271,20 +373,44 @@ impl GlobalIOStats {
         record_latest_read_files_enabled
     );
 
+    /// Measure cold start milestones from `at`, when mounting the filesystem begins, rather
+    /// than from when the stats are created.
+    pub fn set_mount_time(&self, at: Instant) {
+        self.mounted.set(at);
+    }
+
+    /// Mark the moment prefetch of files hinted on mount is done.
+    pub fn mark_prefetch_done(&self) {
+        self.prefetch_done.mark();
+    }
+
+    /// Get number of failed file operations of type `fop`.
+    pub fn fop_errors(&self, fop: StatsFop) -> usize {
+        self.fop_errors[fop as usize].load(Ordering::Relaxed)
+    }
+
+    /// Get the time a read failed for the last time, None if none has failed.
+    pub fn last_read_error(&self) -> Option<Instant> {
+        *self.last_read_error.lock().unwrap()
+    }
+
     /// For now, each inode has its iostats counter regardless whether it is
     /// enabled per rafs.
     pub fn new_file_counter<F>(&self, ino: Inode, path_getter: F)
     where
         F: Fn(u64) -> PathBuf,
     {
-        if self.files_enabled() {
+        // Counters are created on every lookup, check with the read lock first to not
+        // serialize parallel lookups of known files.
+        if self.files_enabled() && !self.file_counters.read().unwrap().contains_key(&ino) {
             let mut counters = self.file_counters.write().unwrap();
             if counters.get(&ino).is_none() {
                 counters.insert(ino, Arc::new(InodeIOStats::default()));
             }
         }
 
-        if self.access_pattern_enabled() {
+        if self.access_pattern_enabled() && !self.access_patterns.read().unwrap().contains_key(&ino)
+        {
             let mut records = self.access_patterns.write().unwrap();
             if records.get(&ino).is_none() {
                 records.insert(
@@ -317,6 +443,15 @@ impl GlobalIOStats {
             match records.get(&ino) {
                 Some(r) => {
                     r.nr_read.fetch_add(1, Ordering::Relaxed);
+                    if success {
+                        r.nr_bytes.fetch_add(bsize, Ordering::Relaxed);
+                    }
+                    if r.first_access_seq.load(Ordering::Relaxed) == 0 {
+                        let seq = self.nr_accessed_files.fetch_add(1, Ordering::Relaxed) + 1;
+                        r.first_access_seq
+                            .compare_exchange(0, seq, Ordering::Relaxed, Ordering::Relaxed)
+                            .unwrap_or_default();
+                    }
                     if r.first_access_time.load(Ordering::Relaxed) == 0 {
                         // FIXME: Conversion from `u64` to `usize` on 32-bit platform
                         // is not reliable. Fix this by using AtomicU64 instead.
@@ -373,6 +508,9 @@ impl GlobalIOStats {
             };
         } else {
             self.fop_errors[fop as usize].fetch_add(1, Ordering::Relaxed);
+            if fop == StatsFop::Read {
+                *self.last_read_error.lock().unwrap() = Some(Instant::now());
+            }
         }
     }
 
@@ -390,14 +528,24 @@ impl GlobalIOStats {
             if let Ok(d) = SystemTime::elapsed(start) {
                 // Converting u128 to u64 here is safe since it's delta.
                 let elapsed = d.as_micros() as usize;
-                self.read_latency_dist[latency_range_index(elapsed)]
-                    .fetch_add(1, Ordering::Relaxed);
-                self.fop_cumulative_latency_total[fop as usize]
-                    .fetch_add(elapsed as usize, Ordering::Relaxed);
+                if fop == StatsFop::Read {
+                    self.read_latency_dist[latency_range_index(elapsed)]
+                        .fetch_add(1, Ordering::Relaxed);
+                }
             }
         }
     }
 
+    /// Record time taken by a whole file operation, from `start` given by `latency_start()`.
+    fn fop_latency_end(&self, start: &Option<SystemTime>, fop: StatsFop) {
+        if let Some(Ok(d)) = start.as_ref().map(SystemTime::elapsed) {
+            let elapsed = d.as_micros() as usize;
+            self.fop_latency_dist[fop as usize][fop_latency_bucket(elapsed)]
+                .fetch_add(1, Ordering::Relaxed);
+            self.fop_cumulative_latency_total[fop as usize].fetch_add(elapsed, Ordering::Relaxed);
+        }
+    }
+
     fn export_files_stats(&self) -> Result<String, IoStatsError> {
         serde_json::to_string(
             self.file_counters
@@ -426,6 +574,20 @@ impl GlobalIOStats {
         .map_err(IoStatsError::Serialize)
     }
 
+    /// Get access patterns of files read since mount, in the order they're first read.
+    fn accessed_files(&self) -> Vec<Arc<AccessPattern>> {
+        let mut files = self
+            .access_patterns
+            .read()
+            .expect("Not poisoned lock")
+            .values()
+            .filter(|r| r.nr_read.load(Ordering::Relaxed) != 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        files.sort_by_key(|r| r.first_access_seq.load(Ordering::Relaxed));
+        files
+    }
+
     fn export_global_stats(&self) -> Result<String, IoStatsError> {
         serde_json::to_string(self).map_err(IoStatsError::Serialize)
     }
@@ -436,6 +598,7 @@ impl GlobalIOStats {
 /// If the operation succeeds, call `mark_success()` to change the recorder's internal state.
 /// If the operation fails, its internal state will not be changed.
 /// Finally, when the recorder is being destroyed, iostats counter will be updated.
+/// The operation is traced as a span from settled to destroyed, if tracing is enabled.
 pub struct FopRecorder<'a> {
     fop: StatsFop,
     inode: u64,
@@ -443,10 +606,28 @@ pub struct FopRecorder<'a> {
     // Now, the size only makes sense for `Read` FOP.
     size: usize,
     ios: &'a GlobalIOStats,
+    start: Option<SystemTime>,
+    span: TraceSpan,
+    // Latency for probes is measured even if `measure_latency` is off, but only when traced.
+    probe_start: Option<Instant>,
+    // Dropped last, so that the request ID is still current when stats are updated.
+    _request: RequestScope,
 }
 
 impl<'a> Drop for FopRecorder<'a> {
     fn drop(&mut self) {
+        self.span.set_u64("fuse.size", self.size as u64);
+        self.span.set_bool("fuse.success", self.success);
+        if let Some(start) = self.probe_start {
+            probe::fuse_req(
+                STATS_FOP_NAMES[self.fop as usize],
+                self.inode,
+                self.size,
+                start.elapsed().as_micros() as u64,
+                self.success,
+            );
+        }
+        self.ios.fop_latency_end(&self.start, self.fop);
         self.ios
             .file_stats_update(self.inode, self.fop, self.size, self.success);
     }
@@ -458,12 +639,31 @@ impl<'a> FopRecorder<'a> {
         T: AsRef<GlobalIOStats>,
         'b: 'a,
     {
+        let request = RequestScope::enter();
+        let span = TraceSpan::start(STATS_FOP_NAMES[fop as usize]);
+        span.set_str("fuse.opcode", STATS_FOP_NAMES[fop as usize]);
+        span.set_u64("fuse.inode", inode);
+        if crate::trace::enabled() {
+            if let Some(id) = request_id::current() {
+                span.set_str("nydus.request_id", &id.to_string());
+            }
+        }
+        ios.as_ref().first_fop.mark();
+
         FopRecorder {
             fop,
             inode,
             success: false,
             size: 0,
             ios: ios.as_ref(),
+            start: ios.as_ref().latency_start(),
+            span,
+            probe_start: if probe::fuse_req_traced() {
+                Some(Instant::now())
+            } else {
+                None
+            },
+            _request: request,
         }
     }
 
@@ -520,6 +720,78 @@ pub fn export_files_access_pattern(name: &Option<String>) -> Result<String, IoSt
     }
 }
 
+/// Export access patterns of files read since the filesystem `id` is mounted, in the order
+/// they're first read. With `as_prefetch_list`, only file paths are exported line by line,
+/// which can be passed to `nydus-image create --prefetch-policy fs` as is.
+pub fn export_mount_access_pattern(id: &str, as_prefetch_list: bool) -> IoStatsResult<String> {
+    let ios = IOS_SET
+        .read()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or(IoStatsError::NoCounter)?;
+    if !ios.access_pattern_enabled() {
+        return Err(IoStatsError::Disabled);
+    }
+
+    let files = ios.accessed_files();
+    if as_prefetch_list {
+        Ok(files
+            .iter()
+            .map(|r| format!("{}\n", r.file_path.display()))
+            .collect())
+    } else {
+        serde_json::to_string(&files).map_err(IoStatsError::Serialize)
+    }
+}
+
+/// A file ranked by how much it's read since mounted.
+#[derive(Debug, Serialize)]
+pub struct HotFile {
+    path: PathBuf,
+    reads: usize,
+    bytes: usize,
+}
+
+/// Rank files read since mounted by bytes read, or by number of reads with `by_reads`.
+fn rank_hot_files(files: &[Arc<AccessPattern>], top: usize, by_reads: bool) -> Vec<HotFile> {
+    let mut ranked = files
+        .iter()
+        .map(|r| HotFile {
+            path: r.file_path.clone(),
+            reads: r.nr_read.load(Ordering::Relaxed),
+            bytes: r.nr_bytes.load(Ordering::Relaxed),
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by(|a, b| {
+        let (ka, kb) = if by_reads {
+            ((a.reads, a.bytes), (b.reads, b.bytes))
+        } else {
+            ((a.bytes, a.reads), (b.bytes, b.reads))
+        };
+        kb.cmp(&ka).then_with(|| a.path.cmp(&b.path))
+    });
+    ranked.truncate(top);
+    ranked
+}
+
+/// Export the `top` hottest files of filesystem `id`, ranked by bytes read, or by number of
+/// reads with `by_reads`. Hottest files come first.
+pub fn export_hot_files(id: &str, top: usize, by_reads: bool) -> IoStatsResult<String> {
+    let ios = IOS_SET
+        .read()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or(IoStatsError::NoCounter)?;
+    if !ios.access_pattern_enabled() {
+        return Err(IoStatsError::Disabled);
+    }
+
+    serde_json::to_string(&rank_hot_files(&ios.accessed_files(), top, by_reads))
+        .map_err(IoStatsError::Serialize)
+}
+
 pub fn export_global_stats(name: &Option<String>) -> Result<String, IoStatsError> {
     // With only one rafs instance, we allow caller to ask for an unknown ios name.
     let ios_set = IOS_SET.read().unwrap();
@@ -540,6 +812,167 @@ pub fn export_global_stats(name: &Option<String>) -> Result<String, IoStatsError
     }
 }
 
+/// IO summary of a filesystem instance, to tell which one is generating backend load.
+#[derive(Debug, Serialize)]
+pub struct FsIOSummary {
+    id: String,
+    // Successful and failed counts of file operations having been called.
+    fop_hits: HashMap<&'static str, usize>,
+    fop_errors: HashMap<&'static str, usize>,
+    // Bytes read by users of the filesystem.
+    data_read: usize,
+    // Bytes fetched from storage backend and number of requests, absent for filesystems
+    // without a storage backend.
+    backend_read_amount: Option<usize>,
+    backend_read_count: Option<usize>,
+    backend_read_errors: Option<usize>,
+    // Read latency percentiles, as the latency range the percentile falls into.
+    read_latency_percentiles: HashMap<&'static str, &'static str>,
+    // Latency percentiles of each type of file operation having been called.
+    fop_latency_percentiles: HashMap<&'static str, HashMap<&'static str, &'static str>>,
+    // Effectiveness of prefetch, absent for filesystems without a blobcache.
+    prefetch: Option<PrefetchSummary>,
+    readiness: MountReadiness,
+}
+
+/// Cold start milestones of a filesystem, in milliseconds since it began to be mounted, absent
+/// until reached.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct MountReadiness {
+    first_fop_ms: Option<u64>,
+    first_backend_read_ms: Option<u64>,
+    prefetch_done_ms: Option<u64>,
+}
+
+/// How much of the data prefetched into a blobcache is read by users afterwards.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PrefetchSummary {
+    cached_chunks: usize,
+    cached_bytes: usize,
+    used_chunks: usize,
+    used_bytes: usize,
+    // Ratio of prefetched chunks having been read, absent if nothing is prefetched yet.
+    hit_ratio: Option<f64>,
+    // Bytes prefetched but not read so far, or evicted before being read.
+    wasted_bytes: usize,
+}
+
+const PERCENTILES: [(&str, usize); 3] = [("p50", 50), ("p90", 90), ("p99", 99)];
+
+/// Get latency range which `percent` of samples fall into, from a latency histogram whose
+/// ranges are named by `names`.
+fn latency_percentile(
+    dist: &[usize],
+    percent: usize,
+    names: &[&'static str],
+) -> Option<&'static str> {
+    let total: usize = dist.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let target = (total * percent + 99) / 100;
+    let mut accumulated = 0;
+    for (idx, count) in dist.iter().enumerate() {
+        accumulated += count;
+        if accumulated >= target {
+            return Some(names[idx]);
+        }
+    }
+
+    None
+}
+
+fn percentiles(dist: &[usize], names: &[&'static str]) -> HashMap<&'static str, &'static str> {
+    PERCENTILES
+        .iter()
+        .filter_map(|(name, p)| latency_percentile(dist, *p, names).map(|r| (*name, r)))
+        .collect()
+}
+
+impl GlobalIOStats {
+    fn summary(
+        &self,
+        backend: Option<&BackendMetrics>,
+        cache: Option<&BlobcacheMetrics>,
+    ) -> FsIOSummary {
+        let counts = |counters: &[AtomicUsize]| {
+            counters
+                .iter()
+                .enumerate()
+                .map(|(idx, c)| (STATS_FOP_NAMES[idx], c.load(Ordering::Relaxed)))
+                .filter(|(_, c)| *c > 0)
+                .collect::<HashMap<_, _>>()
+        };
+        let dist = self
+            .read_latency_dist
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed) as usize)
+            .collect::<Vec<_>>();
+
+        FsIOSummary {
+            id: self.id.clone(),
+            fop_hits: counts(&self.fop_hits),
+            fop_errors: counts(&self.fop_errors),
+            data_read: self.data_read.load(Ordering::Relaxed),
+            backend_read_amount: backend.map(|b| b.read_amount_total.count()),
+            backend_read_count: backend.map(|b| b.read_count.count()),
+            backend_read_errors: backend.map(|b| b.read_errors.count()),
+            read_latency_percentiles: percentiles(&dist, &READ_LATENCY_RANGE_NAMES),
+            fop_latency_percentiles: self
+                .fop_latency_dist
+                .iter()
+                .enumerate()
+                .map(|(idx, d)| {
+                    let dist = d
+                        .iter()
+                        .map(|c| c.load(Ordering::Relaxed))
+                        .collect::<Vec<_>>();
+                    (
+                        STATS_FOP_NAMES[idx],
+                        percentiles(&dist, &FOP_LATENCY_BUCKET_NAMES),
+                    )
+                })
+                .filter(|(_, p)| !p.is_empty())
+                .collect(),
+            prefetch: cache.map(|c| c.prefetch_summary()),
+            readiness: self.readiness(backend),
+        }
+    }
+
+    fn readiness(&self, backend: Option<&BackendMetrics>) -> MountReadiness {
+        let mounted = match self.mounted.at() {
+            Some(t) => t,
+            None => return MountReadiness::default(),
+        };
+        let since = |m: &Milestone| {
+            m.at()
+                .map(|t| t.saturating_duration_since(mounted).as_millis() as u64)
+        };
+
+        MountReadiness {
+            first_fop_ms: since(&self.first_fop),
+            first_backend_read_ms: backend.and_then(|b| since(&b.first_read)),
+            prefetch_done_ms: since(&self.prefetch_done),
+        }
+    }
+}
+
+/// Export IO summary of filesystem instance `id`, which is the mountpoint of it.
+pub fn export_fs_summary(id: &str) -> IoStatsResult<String> {
+    let ios = IOS_SET
+        .read()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or(IoStatsError::NoCounter)?;
+    let backend = BACKEND_METRICS.read().unwrap().get(id).cloned();
+    let cache = BLOBCACHE_METRICS.read().unwrap().get(id).cloned();
+
+    serde_json::to_string(&ios.summary(backend.as_deref(), cache.as_deref()))
+        .map_err(IoStatsError::Serialize)
+}
+
 pub fn export_backend_metrics(name: &Option<String>) -> IoStatsResult<String> {
     let metrics = BACKEND_METRICS.read().unwrap();
 
@@ -559,6 +992,67 @@ pub fn export_backend_metrics(name: &Option<String>) -> IoStatsResult<String> {
     }
 }
 
+/// Outstanding reads of backend `id`.
+pub fn backend_inflight(id: &str) -> IoStatsResult<InflightStats> {
+    BACKEND_METRICS
+        .read()
+        .unwrap()
+        .get(id)
+        .map(|m| m.inflight())
+        .ok_or(IoStatsError::NoCounter)
+}
+
+/// Basic IO statistics of a filesystem instance, for a health snapshot of the daemon.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct MountIoStats {
+    /// Reads served by the blobcache and all reads to it, absent without blobcache.
+    pub cache_hits: Option<usize>,
+    pub cache_reads: Option<usize>,
+    /// Ratio of reads served by the blobcache, absent without blobcache or any read.
+    pub cache_hit_ratio: Option<f64>,
+    /// Bytes fetched from the storage backend, absent without one.
+    pub backend_read_bytes: Option<usize>,
+    /// Requests to the storage backend and their cumulative latency in microseconds, absent
+    /// without one.
+    pub backend_reads: Option<usize>,
+    pub backend_read_latency_us: Option<usize>,
+}
+
+/// Basic IO statistics of filesystem instance `id`, which is the mountpoint of it.
+pub fn mount_io_stats(id: &str) -> MountIoStats {
+    let mut stats = MountIoStats::default();
+
+    if let Some(c) = BLOBCACHE_METRICS.read().unwrap().get(id) {
+        let hits = c.partial_hits.count()
+            + c.whole_hits.count()
+            + c.hot_hits.count()
+            + c.zero_hits.count();
+        let reads = c.total.count();
+        stats.cache_hits = Some(hits);
+        stats.cache_reads = Some(reads);
+        if reads != 0 {
+            stats.cache_hit_ratio = Some(hits as f64 / reads as f64);
+        }
+    }
+    if let Some(b) = BACKEND_METRICS.read().unwrap().get(id) {
+        stats.backend_read_bytes = Some(b.read_amount_total.count());
+        stats.backend_reads = Some(b.read_count.count());
+        stats.backend_read_latency_us = Some(b.read_cumulative_latency_total.count());
+    }
+
+    stats
+}
+
+/// Outstanding reads of all backends, by backend id.
+pub fn backends_inflight() -> BTreeMap<String, InflightStats> {
+    BACKEND_METRICS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(id, m)| (id.clone(), m.inflight()))
+        .collect()
+}
+
 pub fn export_blobcache_metrics(id: &Option<String>) -> IoStatsResult<String> {
     let metrics = BLOBCACHE_METRICS.read().unwrap();
 
@@ -582,6 +1076,373 @@ pub fn export_events() -> IoStatsResult<String> {
     serde_json::to_string(ERROR_HOLDER.lock().unwrap().deref()).map_err(IoStatsError::Serialize)
 }
 
+/// Record a daemon event, which can be polled by `export_events_since()`.
+pub fn record_event(kind: EventKind, id: &str, message: String) {
+    info!("daemon event {:?} [{}]: {}", kind, id, message);
+    EVENT_LOG.lock().unwrap().push(kind, id, message);
+}
+
+/// Export daemon events following the one numbered `seq`.
+pub fn export_events_since(seq: u64) -> IoStatsResult<String> {
+    serde_json::to_string(&EVENT_LOG.lock().unwrap().since(seq)).map_err(IoStatsError::Serialize)
+}
+
+pub fn last_event_seq() -> u64 {
+    EVENT_LOG.lock().unwrap().last_seq()
+}
+
+/// Upper bounds of read latency ranges in seconds, as Prometheus histogram buckets.
+const READ_LATENCY_BUCKETS: [&str; READ_LATENCY_RANGE_MAX] =
+    ["0.001", "0.02", "0.05", "0.1", "0.5", "1", "2", "+Inf"];
+
+/// Upper bounds of latency buckets of file operations in seconds.
+const FOP_LATENCY_BUCKETS: [&str; FOP_LATENCY_BUCKET_MAX] = [
+    "0.00005", "0.0001", "0.0002", "0.0005", "0.001", "0.002", "0.005", "0.01", "0.02", "0.05",
+    "0.1", "0.2", "0.5", "1", "2", "+Inf",
+];
+
+/// Metrics in Prometheus text exposition format.
+#[derive(Default)]
+struct PromText(String);
+
+impl PromText {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        self.0.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+    }
+
+    fn sample<T: std::fmt::Display>(&mut self, name: &str, labels: &[(&str, &str)], value: T) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.0
+            .push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+    }
+
+    /// Samples of a histogram from counts of latency ranges whose upper bounds are `bounds`,
+    /// `sum_us` in micro-seconds.
+    fn histogram(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        dist: &[usize],
+        bounds: &[&str],
+        sum_us: usize,
+    ) {
+        let mut accumulated = 0;
+        for (idx, count) in dist.iter().enumerate() {
+            accumulated += count;
+            let mut bucket = labels.to_vec();
+            bucket.push(("le", bounds[idx]));
+            self.sample(&format!("{}_bucket", name), &bucket, accumulated);
+        }
+        self.sample(&format!("{}_sum", name), labels, sum_us as f64 / 1e6);
+        self.sample(&format!("{}_count", name), labels, accumulated);
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Clone metrics out of a registry, ordered by id so that the output is stable.
+fn sorted_metrics<T>(set: &RwLock<HashMap<String, Arc<T>>>) -> Vec<(String, Arc<T>)> {
+    let mut metrics = set
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+    metrics.sort_by(|a, b| a.0.cmp(&b.0));
+    metrics
+}
+
+/// Export filesystem, backend and blobcache metrics in Prometheus text format.
+///
+/// Filesystems are labeled by mountpoint, backends and blobcaches by their ids.
+pub fn export_prometheus() -> String {
+    let mut t = PromText::default();
+    let fs = sorted_metrics(&IOS_SET);
+    let backends = sorted_metrics(&BACKEND_METRICS);
+    let caches = sorted_metrics(&BLOBCACHE_METRICS);
+    let load = |c: &AtomicUsize| c.load(Ordering::Relaxed);
+
+    t.family(
+        "nydus_fs_read_bytes_total",
+        "counter",
+        "Bytes read by users of the filesystem.",
+    );
+    for (id, s) in fs.iter() {
+        t.sample(
+            "nydus_fs_read_bytes_total",
+            &[("mountpoint", id.as_str())],
+            load(&s.data_read),
+        );
+    }
+    t.family(
+        "nydus_fs_fop_total",
+        "counter",
+        "File operations handled by the filesystem.",
+    );
+    for (id, s) in fs.iter() {
+        for (idx, fop) in STATS_FOP_NAMES.iter().enumerate() {
+            for (result, counters) in &[("ok", &s.fop_hits), ("error", &s.fop_errors)] {
+                t.sample(
+                    "nydus_fs_fop_total",
+                    &[
+                        ("mountpoint", id.as_str()),
+                        ("fop", *fop),
+                        ("result", *result),
+                    ],
+                    load(&counters[idx]),
+                );
+            }
+        }
+    }
+    t.family(
+        "nydus_fs_open_files",
+        "gauge",
+        "Files currently open in the filesystem.",
+    );
+    for (id, s) in fs.iter() {
+        t.sample(
+            "nydus_fs_open_files",
+            &[("mountpoint", id.as_str())],
+            load(&s.nr_opens),
+        );
+    }
+    let readiness = fs
+        .iter()
+        .map(|(id, s)| {
+            let backend = backends
+                .iter()
+                .find(|(b, _)| b == id)
+                .map(|(_, b)| b.deref());
+            (id, s.readiness(backend))
+        })
+        .collect::<Vec<_>>();
+    let milestones: [(&str, &str, fn(&MountReadiness) -> Option<u64>); 3] = [
+        (
+            "nydus_fs_first_fop_seconds",
+            "Time from mounting the filesystem to its first file operation.",
+            |r| r.first_fop_ms,
+        ),
+        (
+            "nydus_fs_first_backend_read_seconds",
+            "Time from mounting the filesystem to its first successful backend read.",
+            |r| r.first_backend_read_ms,
+        ),
+        (
+            "nydus_fs_prefetch_done_seconds",
+            "Time from mounting the filesystem to the end of prefetch hinted on mount.",
+            |r| r.prefetch_done_ms,
+        ),
+    ];
+    for (name, help, get) in milestones.iter() {
+        t.family(name, "gauge", help);
+        for (id, r) in readiness.iter() {
+            if let Some(ms) = get(r) {
+                t.sample(name, &[("mountpoint", id.as_str())], ms as f64 / 1e3);
+            }
+        }
+    }
+    t.family(
+        "nydus_fs_fop_latency_seconds",
+        "histogram",
+        "Latency of file operations of the filesystem, those never called are omitted.",
+    );
+    for (id, s) in fs.iter() {
+        for (idx, fop) in STATS_FOP_NAMES.iter().enumerate() {
+            let dist = s.fop_latency_dist[idx].iter().map(load).collect::<Vec<_>>();
+            if dist.iter().all(|c| *c == 0) {
+                continue;
+            }
+            t.histogram(
+                "nydus_fs_fop_latency_seconds",
+                &[("mountpoint", id.as_str()), ("fop", *fop)],
+                &dist,
+                &FOP_LATENCY_BUCKETS,
+                load(&s.fop_cumulative_latency_total[idx]),
+            );
+        }
+    }
+
+    t.family(
+        "nydus_backend_read_bytes_total",
+        "counter",
+        "Bytes fetched from the storage backend.",
+    );
+    for (id, b) in backends.iter() {
+        t.sample(
+            "nydus_backend_read_bytes_total",
+            &[("backend", id.as_str()), ("type", b.backend_type.as_str())],
+            b.read_amount_total.count(),
+        );
+    }
+    t.family(
+        "nydus_backend_read_errors_total",
+        "counter",
+        "Failed reads from the storage backend.",
+    );
+    for (id, b) in backends.iter() {
+        t.sample(
+            "nydus_backend_read_errors_total",
+            &[("backend", id.as_str()), ("type", b.backend_type.as_str())],
+            b.read_errors.count(),
+        );
+    }
+    t.family(
+        "nydus_backend_errors_total",
+        "counter",
+        "Failed requests to the storage backend, as per host and class of the failure.",
+    );
+    for (id, b) in backends.iter() {
+        for (host, classes) in b.errors.lock().unwrap().iter() {
+            for (class, count) in classes.iter() {
+                t.sample(
+                    "nydus_backend_errors_total",
+                    &[
+                        ("backend", id.as_str()),
+                        ("type", b.backend_type.as_str()),
+                        ("host", host.as_str()),
+                        ("class", *class),
+                    ],
+                    *count,
+                );
+            }
+        }
+    }
+    t.family(
+        "nydus_backend_inflight_requests",
+        "gauge",
+        "Outstanding reads from the storage backend, retries included.",
+    );
+    for (id, b) in backends.iter() {
+        t.sample(
+            "nydus_backend_inflight_requests",
+            &[("backend", id.as_str()), ("type", b.backend_type.as_str())],
+            b.inflight().count,
+        );
+    }
+    t.family(
+        "nydus_backend_read_latency_seconds",
+        "histogram",
+        "Latency of reads from the storage backend.",
+    );
+    for (id, b) in backends.iter() {
+        let dist = (0..READ_LATENCY_RANGE_MAX)
+            .map(|lat| b.read_latency_dist.iter().map(|d| d[lat].count()).sum())
+            .collect::<Vec<usize>>();
+        t.histogram(
+            "nydus_backend_read_latency_seconds",
+            &[("backend", id.as_str()), ("type", b.backend_type.as_str())],
+            &dist,
+            &READ_LATENCY_BUCKETS,
+            b.read_cumulative_latency_total.count(),
+        );
+    }
+
+    t.family(
+        "nydus_blobcache_reads_total",
+        "counter",
+        "Reads served by the blobcache, as per whether and how it was hit.",
+    );
+    for (id, c) in caches.iter() {
+        let hits = [
+            ("partial", c.partial_hits.count()),
+            ("whole", c.whole_hits.count()),
+            ("hot", c.hot_hits.count()),
+            ("zero", c.zero_hits.count()),
+        ];
+        let missed = c.total.count()
+            - hits
+                .iter()
+                .map(|(_, n)| n)
+                .sum::<usize>()
+                .min(c.total.count());
+        for (hit, n) in hits.iter().chain(&[("miss", missed)]) {
+            t.sample(
+                "nydus_blobcache_reads_total",
+                &[("cache", id.as_str()), ("hit", *hit)],
+                n,
+            );
+        }
+    }
+    t.family(
+        "nydus_blobcache_entries",
+        "gauge",
+        "Chunks ready in the blobcache.",
+    );
+    for (id, c) in caches.iter() {
+        t.sample(
+            "nydus_blobcache_entries",
+            &[("cache", id.as_str())],
+            c.entries_count.count(),
+        );
+    }
+    let counters: [(&str, &str, fn(&BlobcacheMetrics) -> usize); 9] = [
+        (
+            "nydus_blobcache_evicted_chunks_total",
+            "Chunks evicted to stay within cache quota.",
+            |c| c.evicted_chunks.count(),
+        ),
+        (
+            "nydus_blobcache_corrupted_chunks_total",
+            "Cached chunks failing validation and refetched.",
+            |c| c.corrupted_chunks.count(),
+        ),
+        (
+            "nydus_blobcache_purged_blobs_total",
+            "Times cached data of a blob was purged on demand.",
+            |c| c.purged_blobs.count(),
+        ),
+        (
+            "nydus_blobcache_amplified_chunks_total",
+            "Neighboring chunks fetched along with reads.",
+            |c| c.amplified_chunks.count(),
+        ),
+        (
+            "nydus_blobcache_prefetch_bytes_total",
+            "Bytes prefetched into the blobcache.",
+            |c| c.prefetch_data_amount.count(),
+        ),
+        (
+            "nydus_blobcache_prefetch_cached_chunks_total",
+            "Chunks cached by prefetch.",
+            |c| c.prefetch_cached_chunks.count(),
+        ),
+        (
+            "nydus_blobcache_prefetch_cached_bytes_total",
+            "Bytes of chunks cached by prefetch, decompressed.",
+            |c| c.prefetch_cached_bytes.count(),
+        ),
+        (
+            "nydus_blobcache_prefetch_used_chunks_total",
+            "Prefetched chunks read afterwards while still cached.",
+            |c| c.prefetch_used_chunks.count(),
+        ),
+        (
+            "nydus_blobcache_prefetch_used_bytes_total",
+            "Bytes of prefetched chunks read afterwards while still cached, decompressed.",
+            |c| c.prefetch_used_bytes.count(),
+        ),
+    ];
+    for (name, help, value) in counters.iter() {
+        t.family(name, "counter", help);
+        for (id, c) in caches.iter() {
+            t.sample(name, &[("cache", id.as_str())], value(c));
+        }
+    }
+
+    t.0
+}
+
 pub trait Metric {
     /// Adds `value` to the current counter.
     fn add(&self, value: usize);
@@ -589,6 +1450,8 @@ pub trait Metric {
     fn inc(&self) {
         self.add(1);
     }
+    /// Subtracts `value` from the current counter.
+    fn sub(&self, value: usize);
     /// Returns current value of the counter.
     fn count(&self) -> usize;
 }
@@ -626,6 +1489,25 @@ pub struct BackendMetrics {
     read_cumulative_latency_total: BasicMetric,
     // Categorize metrics as per their latency and request size
     read_latency_dist: [[BasicMetric; READ_LATENCY_RANGE_MAX]; BLOCK_READ_COUNT_MAX],
+    // Begin time of outstanding reads, mapped to number of reads begun at the time.
+    #[serde(skip_serializing, skip_deserializing)]
+    inflight: Mutex<BTreeMap<SystemTime, usize>>,
+    // Bytes of buffers of outstanding reads.
+    #[serde(skip_serializing, skip_deserializing)]
+    inflight_bytes: AtomicUsize,
+    // Failed requests per host, as per class of the failure, e.g. "dns" or "rate_limited".
+    errors: Mutex<BTreeMap<String, BTreeMap<&'static str, usize>>>,
+    // Completion of the first successful read.
+    #[serde(skip_serializing)]
+    first_read: Milestone,
+}
+
+/// Number and age of outstanding requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct InflightStats {
+    pub count: usize,
+    /// Age of the oldest request in milliseconds, 0 if there is none.
+    pub oldest_age_ms: u64,
 }
 
 impl Metric for BasicMetric {
@@ -633,12 +1515,20 @@ impl Metric for BasicMetric {
         self.0.fetch_add(value, Ordering::Relaxed);
     }
 
+    fn sub(&self, value: usize) {
+        self.0.fetch_sub(value, Ordering::Relaxed);
+    }
+
     fn count(&self) -> usize {
         self.0.load(Ordering::Relaxed)
     }
 }
 
 impl BackendMetrics {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     pub fn new(id: &str, backend_type: &str) -> Arc<Self> {
         let backend_metrics = Arc::new(Self {
             id: id.to_string(),
@@ -663,11 +1553,26 @@ impl BackendMetrics {
             .ok_or(IoStatsError::NoCounter)
     }
 
-    pub fn begin(&self) -> SystemTime {
-        SystemTime::now()
+    /// Begin a read of `size` bytes, which must be ended by `end()` with the same size.
+    pub fn begin(&self, size: usize) -> SystemTime {
+        let begin = SystemTime::now();
+        *self.inflight.lock().unwrap().entry(begin).or_insert(0) += 1;
+        self.inflight_bytes.fetch_add(size, Ordering::Relaxed);
+        begin
     }
 
     pub fn end(&self, begin: &SystemTime, size: usize, error: bool) {
+        self.inflight_bytes.fetch_sub(size, Ordering::Relaxed);
+        {
+            let mut inflight = self.inflight.lock().unwrap();
+            if let Some(n) = inflight.get_mut(begin) {
+                *n -= 1;
+                if *n == 0 {
+                    inflight.remove(begin);
+                }
+            }
+        }
+
         if let Ok(d) = SystemTime::elapsed(begin) {
             // Below conversion from u128 to usize is acceptable since elapsed
             // is a short duration.
@@ -678,6 +1583,8 @@ impl BackendMetrics {
 
             if error {
                 self.read_errors.inc();
+            } else {
+                self.first_read.mark();
             }
 
             let lat_idx = latency_range_index(elapsed);
@@ -686,6 +1593,37 @@ impl BackendMetrics {
         }
     }
 
+    /// Count a failed request to `host`, whose failure is of `class`.
+    pub fn record_error(&self, host: &str, class: &'static str) {
+        let mut errors = self.errors.lock().unwrap();
+        *errors
+            .entry(host.to_string())
+            .or_default()
+            .entry(class)
+            .or_insert(0) += 1;
+    }
+
+    /// Reads begun but not ended yet, retries included.
+    pub fn inflight(&self) -> InflightStats {
+        let inflight = self.inflight.lock().unwrap();
+        let oldest_age_ms = inflight
+            .keys()
+            .next()
+            .and_then(|t| t.elapsed().ok())
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        InflightStats {
+            count: inflight.values().sum(),
+            oldest_age_ms,
+        }
+    }
+
+    /// Bytes of buffers of reads begun but not ended yet.
+    pub fn inflight_bytes(&self) -> usize {
+        self.inflight_bytes.load(Ordering::Relaxed)
+    }
+
     fn export_metrics(&self) -> IoStatsResult<String> {
         serde_json::to_string(self).map_err(IoStatsError::Serialize)
     }
@@ -699,13 +1637,25 @@ pub struct BlobcacheMetrics {
     // Because stat(2) file may get blocked.
     pub underlying_files: Mutex<HashSet<String>>,
     pub store_path: String,
-    // Cache hit percentage = (partial_hits + whole_hits) / total
+    // Cache hit percentage = (partial_hits + whole_hits + hot_hits + zero_hits) / total
     pub partial_hits: BasicMetric,
     pub whole_hits: BasicMetric,
+    // Reads served from decompressed hot chunks of a compressed blobcache.
+    pub hot_hits: BasicMetric,
+    // Reads of all-zero chunks served from memory.
+    pub zero_hits: BasicMetric,
     pub total: BasicMetric,
-    // Scale of blobcache. Blobcache does not evict entries.
+    // Scale of blobcache. Blobcache only evicts entries when cache quota is set.
     // Means the number of chunks in ready status.
     pub entries_count: BasicMetric,
+    // Number of chunks evicted to stay within cache quota.
+    pub evicted_chunks: BasicMetric,
+    // Number of ready chunks whose cached data failed validation and got refetched.
+    pub corrupted_chunks: BasicMetric,
+    // Number of times cached data of a blob was purged on demand.
+    pub purged_blobs: BasicMetric,
+    // Number of neighboring chunks fetched along with reads as per `amplify_io`.
+    pub amplified_chunks: BasicMetric,
     // In unit of Bytes
     pub prefetch_data_amount: BasicMetric,
     pub prefetch_workers: AtomicUsize,
@@ -715,9 +1665,20 @@ pub struct BlobcacheMetrics {
     pub prefetch_total_size: BasicMetric,
     pub prefetch_mr_count: BasicMetric,
     pub prefetch_unmerged_chunks: BasicMetric,
+    // Chunks not prefetched because prefetch is stopped while cache filesystem is low on free space.
+    pub prefetch_skipped_chunks: BasicMetric,
+    // Chunks cached by prefetch, and those of them read afterwards while still cached.
+    pub prefetch_cached_chunks: BasicMetric,
+    pub prefetch_cached_bytes: BasicMetric,
+    pub prefetch_used_chunks: BasicMetric,
+    pub prefetch_used_bytes: BasicMetric,
 }
 
 impl BlobcacheMetrics {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     pub fn new(id: &str, store_path: &str) -> Arc<Self> {
         let metrics = Arc::new(Self {
             id: id.to_string(),
@@ -748,12 +1709,236 @@ impl BlobcacheMetrics {
     pub fn export_metrics(&self) -> IoStatsResult<String> {
         serde_json::to_string(self).map_err(IoStatsError::Serialize)
     }
+
+    pub fn prefetch_summary(&self) -> PrefetchSummary {
+        let cached_chunks = self.prefetch_cached_chunks.count();
+        let cached_bytes = self.prefetch_cached_bytes.count();
+        let used_chunks = self.prefetch_used_chunks.count();
+        let used_bytes = self.prefetch_used_bytes.count();
+
+        PrefetchSummary {
+            cached_chunks,
+            cached_bytes,
+            used_chunks,
+            used_bytes,
+            hit_ratio: if cached_chunks != 0 {
+                Some(used_chunks as f64 / cached_chunks as f64)
+            } else {
+                None
+            },
+            wasted_bytes: cached_bytes.saturating_sub(used_bytes),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_latency_percentile() {
+        assert_eq!(
+            latency_percentile(&[0; READ_LATENCY_RANGE_MAX], 50, &READ_LATENCY_RANGE_NAMES),
+            None
+        );
+
+        let dist = [90, 5, 4, 0, 0, 0, 0, 1];
+        assert_eq!(
+            latency_percentile(&dist, 50, &READ_LATENCY_RANGE_NAMES),
+            Some("<=1ms")
+        );
+        assert_eq!(
+            latency_percentile(&dist, 90, &READ_LATENCY_RANGE_NAMES),
+            Some("<=1ms")
+        );
+        assert_eq!(
+            latency_percentile(&dist, 95, &READ_LATENCY_RANGE_NAMES),
+            Some("<=20ms")
+        );
+        assert_eq!(
+            latency_percentile(&dist, 99, &READ_LATENCY_RANGE_NAMES),
+            Some("<=50ms")
+        );
+        assert_eq!(
+            latency_percentile(&dist, 100, &READ_LATENCY_RANGE_NAMES),
+            Some(">2s")
+        );
+    }
+
+    #[test]
+    fn test_fs_summary() {
+        let g = GlobalIOStats::default();
+        g.init();
+        g.global_update(StatsFop::Read, 4096, true);
+        g.global_update(StatsFop::Lookup, 0, false);
+
+        let summary = g.summary(None, None);
+        assert_eq!(summary.fop_hits.get("read"), Some(&1));
+        assert_eq!(summary.fop_errors.get("lookup"), Some(&1));
+        assert_eq!(summary.fop_hits.get("lookup"), None);
+        assert_eq!(summary.data_read, 4096);
+        assert!(summary.backend_read_amount.is_none());
+        assert!(summary.prefetch.is_none());
+    }
+
+    #[test]
+    fn test_mount_readiness() {
+        let g = Arc::new(GlobalIOStats::default());
+        assert_eq!(g.readiness(None), MountReadiness::default());
+
+        g.set_mount_time(Instant::now() - std::time::Duration::from_millis(100));
+        let b = BackendMetrics::default();
+        let begin = SystemTime::now();
+        b.begin(4096);
+        b.end(&begin, 4096, true);
+        {
+            let _r = FopRecorder::settle(StatsFop::Lookup, 1, &g);
+        }
+        let r = g.readiness(Some(&b));
+        assert!(r.first_fop_ms.unwrap() >= 100);
+        assert_eq!(r.first_backend_read_ms, None);
+        assert_eq!(r.prefetch_done_ms, None);
+
+        b.begin(4096);
+        b.end(&begin, 4096, false);
+        g.mark_prefetch_done();
+        let first_fop = r.first_fop_ms;
+        {
+            let _r = FopRecorder::settle(StatsFop::Read, 1, &g);
+        }
+        let r = g.readiness(Some(&b));
+        assert_eq!(r.first_fop_ms, first_fop);
+        assert!(r.first_backend_read_ms.unwrap() >= 100);
+        assert!(r.prefetch_done_ms.unwrap() >= 100);
+    }
+
+    #[test]
+    fn test_last_read_error() {
+        let g = Arc::new(GlobalIOStats::default());
+        {
+            let mut r = FopRecorder::settle(StatsFop::Read, 1, &g);
+            r.mark_success(4096);
+        }
+        {
+            let _r = FopRecorder::settle(StatsFop::Getattr, 1, &g);
+        }
+        assert_eq!(g.last_read_error(), None);
+
+        let begin = Instant::now();
+        {
+            let _r = FopRecorder::settle(StatsFop::Read, 1, &g);
+        }
+        assert!(g.last_read_error().unwrap() >= begin);
+        assert_eq!(g.fop_errors(StatsFop::Read), 1);
+    }
+
+    #[test]
+    fn test_prefetch_summary() {
+        let c = BlobcacheMetrics::default();
+        assert_eq!(c.prefetch_summary().hit_ratio, None);
+
+        c.prefetch_cached_chunks.add(4);
+        c.prefetch_cached_bytes.add(4096 * 4);
+        c.prefetch_used_chunks.add(1);
+        c.prefetch_used_bytes.add(4096);
+        let summary = c.prefetch_summary();
+        assert_eq!(summary.hit_ratio, Some(0.25));
+        assert_eq!(summary.wasted_bytes, 4096 * 3);
+
+        let g = GlobalIOStats::default();
+        g.init();
+        assert_eq!(g.summary(None, Some(&c)).prefetch, Some(summary));
+    }
+
+    #[test]
+    fn test_fop_latency() {
+        assert_eq!(fop_latency_bucket(0), 0);
+        assert_eq!(fop_latency_bucket(50), 0);
+        assert_eq!(fop_latency_bucket(51), 1);
+        assert_eq!(fop_latency_bucket(1_000), 4);
+        assert_eq!(fop_latency_bucket(2_000_001), FOP_LATENCY_BUCKET_MAX - 1);
+
+        let g = Arc::new(GlobalIOStats::default());
+        g.init();
+        let start = g.latency_start();
+        assert!(start.is_some());
+        g.fop_latency_end(&start, StatsFop::Lookup);
+        g.fop_latency_end(&None, StatsFop::Getattr);
+        {
+            let _rec = FopRecorder::settle(StatsFop::Getattr, 1, &g);
+        }
+
+        let count = |fop: StatsFop| -> usize {
+            g.fop_latency_dist[fop as usize]
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .sum()
+        };
+        assert_eq!(count(StatsFop::Lookup), 1);
+        assert_eq!(count(StatsFop::Getattr), 1);
+        let summary = g.summary(None, None);
+        assert!(summary.fop_latency_percentiles["lookup"].contains_key("p99"));
+        assert!(summary.fop_latency_percentiles.get("read").is_none());
+    }
+
+    #[test]
+    fn test_accessed_files() {
+        let g = GlobalIOStats::default();
+        g.init();
+        g.toggle_access_pattern(true);
+        for ino in 1..=3 {
+            g.new_file_counter(ino, |i| PathBuf::from(format!("/file{}", i)));
+        }
+
+        g.file_stats_update(3, StatsFop::Read, 100, true);
+        g.file_stats_update(1, StatsFop::Read, 10, true);
+        g.file_stats_update(3, StatsFop::Read, 200, true);
+        g.file_stats_update(1, StatsFop::Read, 10, false);
+
+        let files = g.accessed_files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].file_path, PathBuf::from("/file3"));
+        assert_eq!(files[0].nr_read.load(Ordering::Relaxed), 2);
+        assert_eq!(files[0].nr_bytes.load(Ordering::Relaxed), 300);
+        assert_eq!(files[1].file_path, PathBuf::from("/file1"));
+        assert_eq!(files[1].nr_bytes.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn test_rank_hot_files() {
+        let g = GlobalIOStats::default();
+        g.init();
+        g.toggle_access_pattern(true);
+        for ino in 1..=3 {
+            g.new_file_counter(ino, |i| PathBuf::from(format!("/file{}", i)));
+        }
+
+        // file1: 1 read of 1000 bytes, file2: 3 reads of 30 bytes, file3: 1 read of 1000 bytes.
+        g.file_stats_update(1, StatsFop::Read, 1000, true);
+        for _ in 0..3 {
+            g.file_stats_update(2, StatsFop::Read, 10, true);
+        }
+        g.file_stats_update(3, StatsFop::Read, 1000, true);
+
+        let files = g.accessed_files();
+        let ranked = rank_hot_files(&files, 10, false);
+        let paths = ranked.iter().map(|f| f.path.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/file1"),
+                PathBuf::from("/file3"),
+                PathBuf::from("/file2")
+            ]
+        );
+
+        let ranked = rank_hot_files(&files, 1, true);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].path, PathBuf::from("/file2"));
+        assert_eq!(ranked[0].reads, 3);
+        assert_eq!(ranked[0].bytes, 30);
+    }
+
     #[test]
     fn test_block_read_count() {
         let g = GlobalIOStats::default();
@@ -776,4 +1961,88 @@ mod tests {
         g.global_update(StatsFop::Read, 2015520, true);
         assert_eq!(g.block_count_read[3].load(Ordering::Relaxed), 2);
     }
+
+    #[test]
+    fn test_export_prometheus() {
+        let m = BackendMetrics::new("test_export_prometheus", "registry");
+        let begin = m.begin(4096);
+        m.end(&begin, 4096, true);
+        m.record_error("registry.example.com", "rate_limited");
+        m.record_error("registry.example.com", "rate_limited");
+
+        let text = export_prometheus();
+        let labels = r#"backend="test_export_prometheus",type="registry""#;
+        assert!(text.contains("# TYPE nydus_backend_read_latency_seconds histogram\n"));
+        assert!(text.contains(&format!(
+            "nydus_backend_read_bytes_total{{{}}} 4096\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "nydus_backend_read_errors_total{{{}}} 1\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "nydus_backend_read_latency_seconds_bucket{{{},le=\"+Inf\"}} 1\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "nydus_backend_read_latency_seconds_count{{{}}} 1\n",
+            labels
+        )));
+        assert!(text.contains(&format!(
+            "nydus_backend_errors_total{{{},host=\"registry.example.com\",class=\"rate_limited\"}} 2\n",
+            labels
+        )));
+        m.release().unwrap();
+
+        assert_eq!(escape_label("/a\"b\\c\n"), "/a\\\"b\\\\c\\n");
+    }
+
+    #[test]
+    fn test_mount_io_stats() {
+        assert_eq!(
+            mount_io_stats("/test_mount_io_stats"),
+            MountIoStats::default()
+        );
+
+        let c = BlobcacheMetrics::new("/test_mount_io_stats", "/tmp");
+        let b = BackendMetrics::new("/test_mount_io_stats", "localfs");
+        assert_eq!(mount_io_stats("/test_mount_io_stats").cache_hit_ratio, None);
+
+        c.total.add(4);
+        c.partial_hits.add(2);
+        c.zero_hits.inc();
+        let begin = b.begin(4096);
+        b.end(&begin, 4096, false);
+        let stats = mount_io_stats("/test_mount_io_stats");
+        assert_eq!(stats.cache_hits, Some(3));
+        assert_eq!(stats.cache_reads, Some(4));
+        assert_eq!(stats.cache_hit_ratio, Some(0.75));
+        assert_eq!(stats.backend_read_bytes, Some(4096));
+        assert_eq!(stats.backend_reads, Some(1));
+        assert!(stats.backend_read_latency_us.is_some());
+
+        c.release().unwrap();
+        b.release().unwrap();
+    }
+
+    #[test]
+    fn test_backend_inflight() {
+        let m = BackendMetrics::new("test_backend_inflight", "localfs");
+        assert_eq!(m.inflight(), InflightStats::default());
+
+        let t1 = m.begin(4096);
+        let t2 = m.begin(4096);
+        assert_eq!(backend_inflight("test_backend_inflight").unwrap().count, 2);
+        assert_eq!(m.inflight_bytes(), 8192);
+
+        m.end(&t1, 4096, false);
+        assert_eq!(m.inflight().count, 1);
+        m.end(&t2, 4096, true);
+        assert_eq!(m.inflight(), InflightStats::default());
+        assert_eq!(m.inflight_bytes(), 0);
+
+        m.release().unwrap();
+        assert!(backend_inflight("test_backend_inflight").is_err());
+    }
 }
@@ -0,0 +1,89 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// USDT probes of the `nydus` provider, wrapped by `probe.rs`. Strings are passed with their
+// lengths since they aren't NUL-terminated, use `str(arg0, arg1)` in bpftrace.
+//
+// Probes are described by `.note.stapsdt` notes in the same layout as `sys/sdt.h` from systemtap
+// emits, so that nydus builds without it. Each probe has a semaphore which tracers increment
+// when attaching, callers check it to skip preparing arguments and calling into the probe.
+
+#include <stdint.h>
+
+#if defined(__LP64__)
+#define NYDUS_SDT_ADDR ".8byte"
+#else
+#define NYDUS_SDT_ADDR ".4byte"
+#endif
+
+#define NYDUS_SEMAPHORE(name) \
+	volatile unsigned short nydus_##name##_semaphore __attribute__((section(".probes"))) = 0
+
+// A nop at the probe site, and a note telling where it is and where the arguments are.
+#define NYDUS_PROBE(name, args, ...)                                                          \
+	__asm__ __volatile__("990: nop\n"                                                   \
+			     ".pushsection .note.stapsdt,\"?\",\"note\"\n"                  \
+			     ".balign 4\n"                                                  \
+			     ".4byte 992f-991f, 994f-993f, 3\n"                             \
+			     "991: .asciz \"stapsdt\"\n"                                    \
+			     "992: .balign 4\n"                                             \
+			     "993: " NYDUS_SDT_ADDR " 990b\n"                               \
+			     NYDUS_SDT_ADDR " _.stapsdt.base\n"                             \
+			     NYDUS_SDT_ADDR " nydus_" #name "_semaphore\n"                  \
+			     ".asciz \"nydus\"\n"                                           \
+			     ".asciz \"" #name "\"\n"                                       \
+			     ".asciz \"" args "\"\n"                                        \
+			     "994: .balign 4\n"                                             \
+			     ".popsection\n"                                                \
+			     ".ifndef _.stapsdt.base\n"                                     \
+			     ".pushsection .stapsdt.base,\"aG\",\"progbits\",.stapsdt.base,comdat\n" \
+			     ".weak _.stapsdt.base\n"                                       \
+			     ".hidden _.stapsdt.base\n"                                     \
+			     "_.stapsdt.base: .space 1\n"                                   \
+			     ".size _.stapsdt.base, 1\n"                                    \
+			     ".popsection\n"                                                \
+			     ".endif\n"                                                     \
+			     :                                                              \
+			     : __VA_ARGS__)
+
+NYDUS_SEMAPHORE(chunk_fetch_start);
+NYDUS_SEMAPHORE(chunk_fetch_end);
+NYDUS_SEMAPHORE(cache_hit);
+NYDUS_SEMAPHORE(cache_miss);
+NYDUS_SEMAPHORE(fuse_req);
+
+void nydus_probe_chunk_fetch_start(const char *blob_id, uint64_t blob_id_len, uint64_t offset,
+				   uint64_t size)
+{
+	NYDUS_PROBE(chunk_fetch_start, "8@%0 8@%1 8@%2 8@%3", "r"(blob_id), "r"(blob_id_len),
+		    "r"(offset), "r"(size));
+}
+
+void nydus_probe_chunk_fetch_end(const char *blob_id, uint64_t blob_id_len, uint64_t offset,
+				 uint64_t size, int64_t ret)
+{
+	NYDUS_PROBE(chunk_fetch_end, "8@%0 8@%1 8@%2 8@%3 -8@%4", "r"(blob_id), "r"(blob_id_len),
+		    "r"(offset), "r"(size), "r"(ret));
+}
+
+void nydus_probe_cache_hit(const char *blob_id, uint64_t blob_id_len, uint32_t chunk_index,
+			   uint64_t offset)
+{
+	NYDUS_PROBE(cache_hit, "8@%0 8@%1 4@%2 8@%3", "r"(blob_id), "r"(blob_id_len),
+		    "r"(chunk_index), "r"(offset));
+}
+
+void nydus_probe_cache_miss(const char *blob_id, uint64_t blob_id_len, uint32_t chunk_index,
+			    uint64_t offset)
+{
+	NYDUS_PROBE(cache_miss, "8@%0 8@%1 4@%2 8@%3", "r"(blob_id), "r"(blob_id_len),
+		    "r"(chunk_index), "r"(offset));
+}
+
+void nydus_probe_fuse_req(const char *opcode, uint64_t opcode_len, uint64_t inode, uint64_t size,
+			  uint64_t latency_us, int32_t success)
+{
+	NYDUS_PROBE(fuse_req, "8@%0 8@%1 8@%2 8@%3 8@%4 -4@%5", "r"(opcode), "r"(opcode_len),
+		    "r"(inode), "r"(size), "r"(latency_us), "r"(success));
+}
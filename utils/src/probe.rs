@@ -0,0 +1,213 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! User-level static tracepoints in hot paths, for bpftrace and other eBPF tools to observe a
+//! running nydusd without debug logging.
+//!
+//! The probes belong to provider `nydus` and are always compiled in. Each of them has a
+//! semaphore counting tracers attached, a probe costs a load and a branch until it's traced,
+//! so they are cheap enough to be left in release builds.
+
+mod sys {
+    extern "C" {
+        pub static nydus_chunk_fetch_start_semaphore: u16;
+        pub static nydus_chunk_fetch_end_semaphore: u16;
+        pub static nydus_cache_hit_semaphore: u16;
+        pub static nydus_cache_miss_semaphore: u16;
+        pub static nydus_fuse_req_semaphore: u16;
+
+        pub fn nydus_probe_chunk_fetch_start(
+            blob_id: *const u8,
+            blob_id_len: u64,
+            offset: u64,
+            size: u64,
+        );
+        pub fn nydus_probe_chunk_fetch_end(
+            blob_id: *const u8,
+            blob_id_len: u64,
+            offset: u64,
+            size: u64,
+            ret: i64,
+        );
+        pub fn nydus_probe_cache_hit(
+            blob_id: *const u8,
+            blob_id_len: u64,
+            chunk_index: u32,
+            offset: u64,
+        );
+        pub fn nydus_probe_cache_miss(
+            blob_id: *const u8,
+            blob_id_len: u64,
+            chunk_index: u32,
+            offset: u64,
+        );
+        pub fn nydus_probe_fuse_req(
+            opcode: *const u8,
+            opcode_len: u64,
+            inode: u64,
+            size: u64,
+            latency_us: u64,
+            success: i32,
+        );
+    }
+}
+
+/// Whether a tracer is attached to the probe of `semaphore`, which tracers update behind our
+/// back.
+#[inline]
+fn traced(semaphore: &u16) -> bool {
+    unsafe { std::ptr::read_volatile(semaphore) != 0 }
+}
+
+/// A read of `size` bytes at `offset` of blob `blob_id` is about to be sent to the backend.
+#[inline]
+pub fn chunk_fetch_start(blob_id: &str, offset: u64, size: usize) {
+    unsafe {
+        if traced(&sys::nydus_chunk_fetch_start_semaphore) {
+            sys::nydus_probe_chunk_fetch_start(
+                blob_id.as_ptr(),
+                blob_id.len() as u64,
+                offset,
+                size as u64,
+            )
+        }
+    }
+}
+
+/// The backend read started by `chunk_fetch_start()` is done, `ret` is the number of bytes
+/// read or a negative errno.
+#[inline]
+pub fn chunk_fetch_end(blob_id: &str, offset: u64, size: usize, ret: i64) {
+    unsafe {
+        if traced(&sys::nydus_chunk_fetch_end_semaphore) {
+            sys::nydus_probe_chunk_fetch_end(
+                blob_id.as_ptr(),
+                blob_id.len() as u64,
+                offset,
+                size as u64,
+                ret,
+            )
+        }
+    }
+}
+
+/// A chunk is read from the local cache, or fetched from the backend if `hit` is false.
+#[inline]
+pub fn cache_access(blob_id: &str, chunk_index: u32, offset: u64, hit: bool) {
+    unsafe {
+        if hit && traced(&sys::nydus_cache_hit_semaphore) {
+            sys::nydus_probe_cache_hit(blob_id.as_ptr(), blob_id.len() as u64, chunk_index, offset)
+        } else if !hit && traced(&sys::nydus_cache_miss_semaphore) {
+            sys::nydus_probe_cache_miss(blob_id.as_ptr(), blob_id.len() as u64, chunk_index, offset)
+        }
+    }
+}
+
+/// Whether probe `fuse_req` is traced, so that its latency is worth measuring.
+#[inline]
+pub fn fuse_req_traced() -> bool {
+    unsafe { traced(&sys::nydus_fuse_req_semaphore) }
+}
+
+/// A fuse request on `inode` is done after `latency_us` microseconds.
+#[inline]
+pub fn fuse_req(opcode: &str, inode: u64, size: usize, latency_us: u64, success: bool) {
+    unsafe {
+        if fuse_req_traced() {
+            sys::nydus_probe_fuse_req(
+                opcode.as_ptr(),
+                opcode.len() as u64,
+                inode,
+                size as u64,
+                latency_us,
+                success as i32,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn u16_at(buf: &[u8], off: usize) -> usize {
+        u16::from_le_bytes(buf[off..off + 2].try_into().unwrap()) as usize
+    }
+
+    fn u32_at(buf: &[u8], off: usize) -> usize {
+        u32::from_le_bytes(buf[off..off + 4].try_into().unwrap()) as usize
+    }
+
+    fn u64_at(buf: &[u8], off: usize) -> u64 {
+        u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+    }
+
+    fn cstr(buf: &[u8]) -> (&str, &[u8]) {
+        let end = buf.iter().position(|c| *c == 0).unwrap();
+        (std::str::from_utf8(&buf[..end]).unwrap(), &buf[end + 1..])
+    }
+
+    /// Probes described by `.note.stapsdt` of the running executable, as (provider, name,
+    /// semaphore address).
+    fn probes_in_exe() -> Vec<(String, String, u64)> {
+        let elf = std::fs::read("/proc/self/exe").unwrap();
+        assert_eq!(&elf[..5], b"\x7fELF\x02");
+        let (shoff, shentsize) = (u64_at(&elf, 0x28) as usize, u16_at(&elf, 0x3a));
+        let (shnum, shstrndx) = (u16_at(&elf, 0x3c), u16_at(&elf, 0x3e));
+        let section = |i: usize| {
+            let sh = &elf[shoff + i * shentsize..];
+            let (off, size) = (u64_at(sh, 0x18) as usize, u64_at(sh, 0x20) as usize);
+            (u32_at(sh, 0), &elf[off..off + size])
+        };
+        let strtab = section(shstrndx).1;
+        let notes = (0..shnum)
+            .map(section)
+            .find(|(name, _)| cstr(&strtab[*name..]).0 == ".note.stapsdt")
+            .expect("no probe in executable")
+            .1;
+
+        let mut probes = Vec::new();
+        let align = |n: usize| (n + 3) & !3;
+        let mut off = 0;
+        while off < notes.len() {
+            let (namesz, descsz) = (u32_at(notes, off), u32_at(notes, off + 4));
+            let desc = &notes[off + 12 + align(namesz)..][..descsz];
+            let (provider, rest) = cstr(&desc[24..]);
+            let (name, _) = cstr(rest);
+            probes.push((provider.to_string(), name.to_string(), u64_at(desc, 16)));
+            off += 12 + align(namesz) + align(descsz);
+        }
+
+        probes
+    }
+
+    #[test]
+    fn test_probes_in_executable() {
+        let probes = probes_in_exe();
+        let semaphores = unsafe {
+            [
+                ("chunk_fetch_start", &sys::nydus_chunk_fetch_start_semaphore),
+                ("chunk_fetch_end", &sys::nydus_chunk_fetch_end_semaphore),
+                ("cache_hit", &sys::nydus_cache_hit_semaphore),
+                ("cache_miss", &sys::nydus_cache_miss_semaphore),
+                ("fuse_req", &sys::nydus_fuse_req_semaphore),
+            ]
+        };
+
+        // Every probe is there, and points to the semaphore checked before firing it, at the
+        // same load bias.
+        let mut bias = None;
+        for (name, semaphore) in semaphores.iter() {
+            let (_, _, addr) = probes
+                .iter()
+                .find(|(p, n, _)| p == "nydus" && n == name)
+                .unwrap_or_else(|| panic!("no probe {}", name));
+            let delta = (*semaphore as *const u16 as u64).wrapping_sub(*addr);
+            assert_eq!(*bias.get_or_insert(delta), delta, "probe {}", name);
+            assert!(!traced(semaphore));
+        }
+        assert!(!fuse_req_traced());
+    }
+}
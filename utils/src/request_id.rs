@@ -0,0 +1,123 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Correlation IDs of fuse requests.
+//!
+//! An ID is generated when a fuse request is received and stays current in the handling thread
+//! until the request is replied, so that log lines, backend HTTP requests and trace spans made
+//! on behalf of the request carry it. Work done by other threads, e.g. prefetch workers, has
+//! no ID.
+
+use std::cell::Cell;
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+
+/// HTTP header carrying the ID in requests to storage backends.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+thread_local! {
+    static CURRENT: Cell<Option<RequestId>> = Cell::new(None);
+}
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // Tells IDs of different daemons or different runs apart in registry access logs.
+    static ref PREFIX: u64 = {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        ((nanos ^ std::process::id().rotate_left(16)) as u64) << 32
+    };
+}
+
+/// Correlation ID of a fuse request, shown as 16 hex digits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestId(u64);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl Serialize for RequestId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Start a request in this thread with a new ID.
+pub fn begin() -> RequestId {
+    let id = RequestId(*PREFIX | (NEXT.fetch_add(1, Ordering::Relaxed) & 0xffff_ffff));
+    CURRENT.with(|c| c.set(Some(id)));
+    id
+}
+
+/// End the request being handled by this thread.
+pub fn end() {
+    CURRENT.with(|c| c.set(None));
+}
+
+/// ID of the request being handled by this thread.
+pub fn current() -> Option<RequestId> {
+    CURRENT.with(|c| c.get())
+}
+
+/// Keeps a request current until dropped, if there was none when it's created.
+pub struct RequestScope {
+    owned: bool,
+}
+
+impl RequestScope {
+    /// Join the current request, or start one if the caller doesn't track requests itself.
+    pub fn enter() -> Self {
+        let owned = current().is_none();
+        if owned {
+            begin();
+        }
+        RequestScope { owned }
+    }
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        if self.owned {
+            end();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_scope() {
+        assert_eq!(current(), None);
+        {
+            let _s = RequestScope::enter();
+            let id = current().unwrap();
+            {
+                let _nested = RequestScope::enter();
+                assert_eq!(current(), Some(id));
+            }
+            assert_eq!(current(), Some(id));
+        }
+        assert_eq!(current(), None);
+
+        let a = begin();
+        let b = begin();
+        assert_ne!(a, b);
+        assert_eq!(current(), Some(b));
+        assert_eq!(a.to_string().len(), 16);
+        assert_eq!(serde_json::to_string(&b).unwrap(), format!("\"{}\"", b));
+        end();
+        assert_eq!(current(), None);
+    }
+}
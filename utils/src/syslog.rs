@@ -0,0 +1,236 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Send log records to syslog through `/dev/log`, or to the systemd journal through its native
+//! protocol, so that they are collected along with logs of other services.
+
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::str::FromStr;
+
+use flexi_logger::{writers::LogWriter, DeferredNow};
+use log::{Level, LevelFilter, Record};
+
+use crate::request_id;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// Facility of system daemons.
+const LOG_DAEMON: u8 = 3 << 3;
+const IDENTIFIER: &str = "nydusd";
+
+/// Where to send log records other than stderr or a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SystemLog {
+    Syslog,
+    Journald,
+}
+
+impl FromStr for SystemLog {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "syslog" => Ok(SystemLog::Syslog),
+            "journald" => Ok(SystemLog::Journald),
+            _ => Err(einval!(format!("invalid log target {}", s))),
+        }
+    }
+}
+
+/// Syslog severity of a log level. Trace and debug both map to debug, which is the lowest.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Writes each record as a datagram, the socket is connected once and records are dropped
+/// if syslog or journald is away, rather than blocking threads logging.
+pub struct SystemLogWriter {
+    target: SystemLog,
+    sock: UnixDatagram,
+}
+
+impl SystemLogWriter {
+    pub fn new(target: SystemLog) -> Result<Self> {
+        let path = match target {
+            SystemLog::Syslog => SYSLOG_SOCKET,
+            SystemLog::Journald => JOURNALD_SOCKET,
+        };
+        Self::connect(target, Path::new(path))
+    }
+
+    fn connect(target: SystemLog, path: &Path) -> Result<Self> {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(path).map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!("failed to connect to {:?}, {}", path, e),
+            )
+        })?;
+        sock.set_nonblocking(true)?;
+
+        Ok(SystemLogWriter { target, sock })
+    }
+
+    fn encode(&self, record: &Record) -> Result<Vec<u8>> {
+        match self.target {
+            SystemLog::Syslog => encode_syslog(record),
+            SystemLog::Journald => encode_journald(record),
+        }
+    }
+}
+
+/// RFC 3164 message, syslog stamps the time itself.
+fn encode_syslog(record: &Record) -> Result<Vec<u8>> {
+    let mut msg = Vec::new();
+    write!(
+        msg,
+        "<{}>{}[{}]: {} [{}] {}",
+        LOG_DAEMON | priority(record.level()),
+        IDENTIFIER,
+        std::process::id(),
+        record.level(),
+        record.module_path().unwrap_or("<unnamed>"),
+        record.args()
+    )?;
+    Ok(msg)
+}
+
+/// Fields of the journal native protocol, values having newlines are sized explicitly. Journald
+/// stamps the time and the pid itself.
+fn encode_journald(record: &Record) -> Result<Vec<u8>> {
+    let mut msg = Vec::new();
+    let mut field = |key: &str, value: &[u8]| {
+        msg.extend_from_slice(key.as_bytes());
+        if value.contains(&b'\n') {
+            msg.push(b'\n');
+            msg.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            msg.push(b'=');
+        }
+        msg.extend_from_slice(value);
+        msg.push(b'\n');
+    };
+
+    field("MESSAGE", record.args().to_string().as_bytes());
+    field("PRIORITY", priority(record.level()).to_string().as_bytes());
+    field("SYSLOG_IDENTIFIER", IDENTIFIER.as_bytes());
+    field("TARGET", record.target().as_bytes());
+    if let Some(module) = record.module_path() {
+        field("CODE_MODULE", module.as_bytes());
+    }
+    if let Some(file) = record.file() {
+        field("CODE_FILE", file.as_bytes());
+    }
+    if let Some(line) = record.line() {
+        field("CODE_LINE", line.to_string().as_bytes());
+    }
+    field("NYDUS_LEVEL", record.level().as_str().as_bytes());
+    if let Some(thread) = std::thread::current().name() {
+        field("NYDUS_THREAD", thread.as_bytes());
+    }
+    if let Some(id) = request_id::current() {
+        field("NYDUS_REQUEST_ID", id.to_string().as_bytes());
+    }
+
+    Ok(msg)
+}
+
+impl LogWriter for SystemLogWriter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> Result<()> {
+        crate::with_request_id(record, |record| {
+            crate::keep_record(now, record)?;
+            let msg = self.encode(record)?;
+            match self.sock.send(&msg) {
+                Ok(_) => Ok(()),
+                // Dropped rather than blocking or failing the caller.
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn max_log_level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn record_to<F: FnOnce(&Record)>(level: Level, msg: &str, f: F) {
+        f(&Record::builder()
+            .args(format_args!("{}", msg))
+            .level(level)
+            .target("storage::backend")
+            .module_path(Some("storage::backend"))
+            .file(Some("storage/src/backend/mod.rs"))
+            .line(Some(42))
+            .build())
+    }
+
+    #[test]
+    fn test_priority() {
+        assert_eq!(priority(Level::Error), 3);
+        assert_eq!(priority(Level::Warn), 4);
+        assert_eq!(priority(Level::Info), 6);
+        assert_eq!(priority(Level::Trace), 7);
+        assert_eq!(
+            "journald".parse::<SystemLog>().unwrap(),
+            SystemLog::Journald
+        );
+        assert!("file".parse::<SystemLog>().is_err());
+    }
+
+    #[test]
+    fn test_encode_syslog() {
+        record_to(Level::Warn, "read timeout", |r| {
+            let msg = String::from_utf8(encode_syslog(r).unwrap()).unwrap();
+            assert!(msg.starts_with("<28>nydusd["));
+            assert!(msg.ends_with("]: WARN [storage::backend] read timeout"));
+        });
+    }
+
+    #[test]
+    fn test_encode_journald() {
+        record_to(Level::Error, "failed\nto read", |r| {
+            let msg = encode_journald(r).unwrap();
+            let mut expected = b"MESSAGE\n".to_vec();
+            expected.extend_from_slice(&14u64.to_le_bytes());
+            expected.extend_from_slice(b"failed\nto read\nPRIORITY=3\n");
+            assert!(msg.starts_with(&expected));
+            let text = String::from_utf8_lossy(&msg);
+            assert!(text.contains("\nCODE_LINE=42\n"));
+            assert!(text.contains("\nCODE_MODULE=storage::backend\n"));
+        });
+    }
+
+    #[test]
+    fn test_write_datagram() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("journal.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+        let writer = SystemLogWriter::connect(SystemLog::Journald, &path).unwrap();
+
+        let mut now = DeferredNow::new();
+        record_to(Level::Info, "mounted", |r| {
+            writer.write(&mut now, r).unwrap()
+        });
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"MESSAGE=mounted\nPRIORITY=6\n"));
+    }
+}
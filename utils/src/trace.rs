@@ -0,0 +1,157 @@
+// Copyright 2020 Ant Group. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Opt-in tracing of fuse requests and what they wait for, exported over OTLP.
+//!
+//! A span started by `TraceSpan::start()` becomes the current span of the thread until it's
+//! dropped, so that spans of cache lookups and backend requests done on behalf of a fuse
+//! request are its children. Nothing is recorded unless `init_otlp()` succeeds, which needs
+//! the `otlp` feature.
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "otlp")]
+use opentelemetry::{
+    global,
+    sdk::{self, Resource},
+    trace::{Span, TraceContextExt, Tracer},
+    Context, ContextGuard, KeyValue, Value,
+};
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "otlp")]
+lazy_static! {
+    // Spans are exported in batches by tasks of this runtime, off the fuse threads.
+    static ref RUNTIME: std::sync::Mutex<Option<tokio::runtime::Runtime>> = Default::default();
+}
+
+/// Whether spans are recorded.
+pub fn enabled() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+/// Export spans to the OTLP collector at `endpoint`, e.g. `http://localhost:4317`.
+#[cfg(feature = "otlp")]
+pub fn init_otlp(endpoint: &str) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("otlp-exporter")
+        .enable_all()
+        .build()?;
+    {
+        let _enter = rt.enter();
+        opentelemetry_otlp::new_pipeline()
+            .with_endpoint(endpoint)
+            .with_trace_config(
+                sdk::trace::config()
+                    .with_resource(Resource::new(vec![KeyValue::new("service.name", "nydusd")])),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::Other,
+                    format!("failed to set up OTLP exporter, {}", e),
+                )
+            })?;
+    }
+    *RUNTIME.lock().unwrap() = Some(rt);
+    TRACING.store(true, Ordering::Relaxed);
+    info!("tracing exported to {}", endpoint);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init_otlp(_endpoint: &str) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "tracing requires nydusd built with feature otlp",
+    ))
+}
+
+/// Flush spans not exported yet, before the process exits.
+pub fn shutdown() {
+    if TRACING.swap(false, Ordering::Relaxed) {
+        #[cfg(feature = "otlp")]
+        global::shutdown_tracer_provider();
+    }
+}
+
+/// A span being recorded, which ends when dropped.
+pub struct TraceSpan {
+    #[cfg(feature = "otlp")]
+    scope: Option<(Context, ContextGuard)>,
+}
+
+#[cfg(feature = "otlp")]
+impl TraceSpan {
+    /// Start a span as a child of the current span of this thread, if tracing is enabled.
+    pub fn start(name: &'static str) -> Self {
+        if !enabled() {
+            return TraceSpan { scope: None };
+        }
+        let span = global::tracer("nydus").start(name);
+        let cx = Context::current_with_span(span);
+        let guard = cx.clone().attach();
+
+        TraceSpan {
+            scope: Some((cx, guard)),
+        }
+    }
+
+    fn set(&self, key: &'static str, value: Value) {
+        if let Some((cx, _)) = self.scope.as_ref() {
+            cx.span().set_attribute(KeyValue::new(key, value));
+        }
+    }
+
+    pub fn set_u64(&self, key: &'static str, value: u64) {
+        if self.scope.is_some() {
+            self.set(key, Value::I64(value as i64));
+        }
+    }
+
+    pub fn set_bool(&self, key: &'static str, value: bool) {
+        if self.scope.is_some() {
+            self.set(key, Value::Bool(value));
+        }
+    }
+
+    pub fn set_str(&self, key: &'static str, value: &str) {
+        if self.scope.is_some() {
+            self.set(key, Value::String(value.to_string().into()));
+        }
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+impl TraceSpan {
+    pub fn start(_name: &'static str) -> Self {
+        TraceSpan {}
+    }
+
+    pub fn set_u64(&self, _key: &'static str, _value: u64) {}
+
+    pub fn set_bool(&self, _key: &'static str, _value: bool) {}
+
+    pub fn set_str(&self, _key: &'static str, _value: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_span() {
+        assert!(!enabled());
+        let span = TraceSpan::start("test");
+        span.set_u64("inode", 1);
+        span.set_bool("success", true);
+        span.set_str("blob_id", "test");
+        #[cfg(not(feature = "otlp"))]
+        assert!(init_otlp("http://localhost:4317").is_err());
+    }
+}